@@ -1,6 +1,21 @@
 //! Provides backoff timing control for Kubernetes pod states
 //! such as ImagePullBackoff and CrashLoopBackoff.
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::pod::Pod;
+
+/// The annotation a pod can use to override the number of errors it may have before it enters
+/// CrashLoopBackoff.
+pub const CRASH_LOOP_THRESHOLD_ANNOTATION: &str = "krustlet.dev/crash-loop-threshold";
+/// The annotation a pod can use to override the longest time in seconds it backs off for in
+/// CrashLoopBackoff.
+pub const CRASH_LOOP_BACKOFF_CAP_ANNOTATION: &str = "krustlet.dev/crash-loop-backoff-cap";
+/// The annotation a pod can use to override how long in seconds it has to run without errors
+/// for its error count and backoff to be reset.
+pub const CRASH_LOOP_RESET_AFTER_ANNOTATION: &str = "krustlet.dev/crash-loop-reset-after";
+
+/// The duration of the first backoff, as in the Kubernetes kubelet.
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
 
 /// Determines how long to back off before performing a retry.
 #[async_trait::async_trait]
@@ -35,6 +50,15 @@ impl Default for ExponentialBackoffStrategy {
 }
 
 impl ExponentialBackoffStrategy {
+    /// Gets a backoff strategy that starts at `base_duration` and doubles up to `cap`.
+    pub fn new(base_duration: Duration, cap: Duration) -> Self {
+        Self {
+            base_duration,
+            cap,
+            last_duration: Duration::from_secs(0),
+        }
+    }
+
     fn capped_next_duration(&self) -> Duration {
         let next_duration = if self.last_duration == Duration::from_secs(0) {
             self.base_duration
@@ -62,6 +86,112 @@ impl BackoffStrategy for ExponentialBackoffStrategy {
     }
 }
 
+/// When a pod that keeps failing enters CrashLoopBackoff, and how long it backs off for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashLoopPolicy {
+    /// The number of errors in a row a pod may have before it enters CrashLoopBackoff.
+    pub error_threshold: u32,
+    /// The longest time a pod backs off for.
+    pub backoff_cap: Duration,
+    /// How long a pod has to run without errors for its error count and backoff to be reset.
+    pub reset_after: Duration,
+}
+
+impl Default for CrashLoopPolicy {
+    /// Gets the policy of the Kubernetes kubelet, which resets the backoff after ten minutes.
+    fn default() -> Self {
+        Self {
+            error_threshold: 3,
+            backoff_cap: Duration::from_secs(300),
+            reset_after: Duration::from_secs(600),
+        }
+    }
+}
+
+impl CrashLoopPolicy {
+    /// Gets this policy with the overrides in the pod's annotations applied. This fails if an
+    /// annotation is not a whole number.
+    pub fn for_pod(&self, pod: &Pod) -> anyhow::Result<Self> {
+        let mut policy = self.clone();
+        if let Some(threshold) = pod.annotation_number(CRASH_LOOP_THRESHOLD_ANNOTATION)? {
+            policy.error_threshold = threshold;
+        }
+        if let Some(secs) = pod.annotation_number(CRASH_LOOP_BACKOFF_CAP_ANNOTATION)? {
+            policy.backoff_cap = Duration::from_secs(secs.into());
+        }
+        if let Some(secs) = pod.annotation_number(CRASH_LOOP_RESET_AFTER_ANNOTATION)? {
+            policy.reset_after = Duration::from_secs(secs.into());
+        }
+        Ok(policy)
+    }
+}
+
+/// Tracks the errors of a pod according to a [`CrashLoopPolicy`], and backs off exponentially
+/// while the pod is in CrashLoopBackoff.
+pub struct CrashLoopTracker {
+    policy: CrashLoopPolicy,
+    errors: u32,
+    last_error: Option<Instant>,
+    backoff: ExponentialBackoffStrategy,
+}
+
+impl CrashLoopTracker {
+    /// Gets a tracker for a pod that has had no errors yet.
+    pub fn new(policy: CrashLoopPolicy) -> Self {
+        let backoff = ExponentialBackoffStrategy::new(BASE_BACKOFF, policy.backoff_cap);
+        Self {
+            policy,
+            errors: 0,
+            last_error: None,
+            backoff,
+        }
+    }
+
+    /// Records an error, and returns whether the pod has now had more errors than the policy's
+    /// threshold and should enter CrashLoopBackoff.
+    pub fn record_error(&mut self) -> bool {
+        self.record_error_at(Instant::now())
+    }
+
+    fn record_error_at(&mut self, now: Instant) -> bool {
+        // The pod ran without errors long enough to start over
+        if let Some(last_error) = self.last_error {
+            if now.saturating_duration_since(last_error) >= self.policy.reset_after {
+                self.errors = 0;
+                self.backoff.reset();
+            }
+        }
+        self.last_error = Some(now);
+        self.errors += 1;
+        if self.errors > self.policy.error_threshold {
+            self.errors = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl BackoffStrategy for CrashLoopTracker {
+    fn reset(&mut self) {
+        self.backoff.reset();
+    }
+
+    fn next_duration(&mut self) -> Duration {
+        self.backoff.next_duration()
+    }
+}
+
+/// Formats a backoff the way the Kubernetes kubelet reports it, e.g. `2m40s`.
+pub fn format_backoff(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, s) => format!("{}h{}m{}s", h, m, s),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -103,4 +233,72 @@ mod test {
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
     }
+
+    #[test]
+    fn crash_loop_is_entered_past_the_threshold() {
+        let mut tracker = CrashLoopTracker::new(CrashLoopPolicy {
+            error_threshold: 2,
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert!(!tracker.record_error_at(start));
+        assert!(!tracker.record_error_at(start + Duration::from_secs(1)));
+        assert!(tracker.record_error_at(start + Duration::from_secs(2)));
+        assert!(!tracker.record_error_at(start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn crash_loop_resets_after_running_without_errors() {
+        let mut tracker = CrashLoopTracker::new(CrashLoopPolicy {
+            error_threshold: 1,
+            backoff_cap: Duration::from_secs(60),
+            reset_after: Duration::from_secs(100),
+        });
+        let start = Instant::now();
+        assert!(!tracker.record_error_at(start));
+        assert!(tracker.record_error_at(start + Duration::from_secs(1)));
+        assert_eq!(tracker.next_duration(), Duration::from_secs(10));
+        assert_eq!(tracker.next_duration(), Duration::from_secs(20));
+        assert_eq!(tracker.next_duration(), Duration::from_secs(40));
+        assert_eq!(tracker.next_duration(), Duration::from_secs(60));
+
+        assert!(!tracker.record_error_at(start + Duration::from_secs(200)));
+        assert!(tracker.record_error_at(start + Duration::from_secs(201)));
+        assert_eq!(tracker.next_duration(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn crash_loop_policy_is_overridden_by_annotations() {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "test",
+                "annotations": {
+                    CRASH_LOOP_THRESHOLD_ANNOTATION: "5",
+                    CRASH_LOOP_BACKOFF_CAP_ANNOTATION: "60",
+                },
+            },
+        }))
+        .unwrap();
+        let policy = CrashLoopPolicy::default().for_pod(&Pod::from(pod)).unwrap();
+        assert_eq!(policy.error_threshold, 5);
+        assert_eq!(policy.backoff_cap, Duration::from_secs(60));
+        assert_eq!(policy.reset_after, Duration::from_secs(600));
+
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "test",
+                "annotations": { CRASH_LOOP_RESET_AFTER_ANNOTATION: "10m" },
+            },
+        }))
+        .unwrap();
+        assert!(CrashLoopPolicy::default().for_pod(&Pod::from(pod)).is_err());
+    }
+
+    #[test]
+    fn backoff_is_formatted_like_kubernetes() {
+        assert_eq!(format_backoff(Duration::from_secs(40)), "40s");
+        assert_eq!(format_backoff(Duration::from_secs(160)), "2m40s");
+        assert_eq!(format_backoff(Duration::from_secs(300)), "5m0s");
+        assert_eq!(format_backoff(Duration::from_secs(3660)), "1h1m0s");
+    }
 }
@@ -0,0 +1,516 @@
+//! `bench` measures how quickly the kubelet core moves pods through their state machines, so
+//! that the throughput of the node can be tracked across releases.
+//!
+//! [`run`] creates a number of synthetic pods and runs each of them through the state machine
+//! of a provider, from its `InitialState` until it completes, the same way the pod operator
+//! does but without an API server. Every time a state is entered its status is built, which is
+//! the status the operator would patch to the API server, so the report also gives the rate at
+//! which a node would patch pod statuses. [`StubProvider`] has states that do no work beyond an
+//! optional delay, so that a benchmark of it measures the overhead of the kubelet itself.
+//!
+//! This is only compiled in with the `bench` feature.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::{
+    Container as KubeContainer, EmptyDirVolumeSource, EnvVar, Pod as KubePod, PodSpec, Volume,
+};
+use kube::api::ObjectMeta;
+use serde::Serialize;
+use tokio::sync::{RwLock, Semaphore};
+use tracing::{debug, warn};
+
+use crate::plugin_watcher::PluginRegistry;
+use crate::pod::state::prelude::*;
+use crate::provider::{DevicePluginSupport, PluginSupport, Provider};
+use crate::resources::DeviceManager;
+
+/// The name of the node the synthetic pods are scheduled to.
+pub const BENCH_NODE_NAME: &str = "krustlet-bench";
+
+/// What to benchmark.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// The number of pods to run.
+    pub pods: usize,
+    /// The number of pods whose state machines run at the same time.
+    pub concurrency: usize,
+    /// The number of containers in each pod.
+    pub containers_per_pod: usize,
+    /// The number of environment variables of each container, for measuring how the size of
+    /// pod specs affects the kubelet.
+    pub env_vars_per_container: usize,
+    /// The number of volumes of each pod. They are `emptyDir` volumes, which no container
+    /// mounts.
+    pub volumes_per_pod: usize,
+    /// The namespace of the pods.
+    pub namespace: String,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            pods: 100,
+            concurrency: 10,
+            containers_per_pod: 1,
+            env_vars_per_container: 0,
+            volumes_per_pod: 0,
+            namespace: "default".to_owned(),
+        }
+    }
+}
+
+impl BenchConfig {
+    /// The synthetic pod with the given index. Its containers all use the same image, which the
+    /// provider being benchmarked should be able to run.
+    pub fn synthetic_pod(&self, index: usize) -> Pod {
+        let env: Vec<EnvVar> = (0..self.env_vars_per_container)
+            .map(|n| EnvVar {
+                name: format!("BENCH_VAR_{}", n),
+                value: Some(format!("value-{}", n)),
+                ..Default::default()
+            })
+            .collect();
+        let containers = (0..self.containers_per_pod)
+            .map(|n| KubeContainer {
+                name: format!("container-{}", n),
+                image: Some("krustlet/bench:latest".to_owned()),
+                env: Some(env.clone()),
+                ..Default::default()
+            })
+            .collect();
+        let volumes = (0..self.volumes_per_pod)
+            .map(|n| Volume {
+                name: format!("volume-{}", n),
+                empty_dir: Some(EmptyDirVolumeSource::default()),
+                ..Default::default()
+            })
+            .collect();
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some(format!("bench-pod-{}", index)),
+                namespace: Some(self.namespace.clone()),
+                uid: Some(uuid::Uuid::new_v4().to_string()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers,
+                volumes: Some(volumes),
+                node_name: Some(BENCH_NODE_NAME.to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+/// Latency percentiles of a set of measurements.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Latency {
+    /// The number of measurements.
+    pub count: usize,
+    /// The mean of the measurements.
+    pub mean: Duration,
+    /// The median of the measurements.
+    pub p50: Duration,
+    /// The 99th percentile of the measurements.
+    pub p99: Duration,
+    /// The longest of the measurements.
+    pub max: Duration,
+}
+
+impl Latency {
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        if samples.is_empty() {
+            return Default::default();
+        }
+        samples.sort();
+        let count = samples.len();
+        let percentile = |p: usize| samples[((count - 1) * p) / 100];
+        Latency {
+            count,
+            mean: samples.iter().sum::<Duration>() / count as u32,
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples[count - 1],
+        }
+    }
+}
+
+impl std::fmt::Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n={} mean={:?} p50={:?} p99={:?} max={:?}",
+            self.count, self.mean, self.p50, self.p99, self.max
+        )
+    }
+}
+
+/// The results of a benchmark.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BenchReport {
+    /// The number of pods that were run.
+    pub pods: usize,
+    /// The number of pods whose state machines completed with an error.
+    pub failed_pods: usize,
+    /// How long it took to run all of the pods.
+    pub elapsed: Duration,
+    /// The number of pods run to completion per second.
+    pub pods_per_second: f64,
+    /// How long each pod took from being initialized until its state was dropped.
+    pub pod_latency: Latency,
+    /// How long each state took to transition, by the name of the state.
+    pub state_latency: BTreeMap<String, Latency>,
+    /// The number of statuses that would have been patched to the API server.
+    pub status_updates: usize,
+    /// The number of statuses that would have been patched per second.
+    pub status_updates_per_second: f64,
+    /// How long building each status took.
+    pub status_latency: Latency,
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "pods:           {} ({} failed) in {:?}",
+            self.pods, self.failed_pods, self.elapsed
+        )?;
+        writeln!(f, "throughput:     {:.1} pods/s", self.pods_per_second)?;
+        writeln!(f, "pod latency:    {}", self.pod_latency)?;
+        writeln!(
+            f,
+            "status updates: {} ({:.1}/s)",
+            self.status_updates, self.status_updates_per_second
+        )?;
+        writeln!(f, "status latency: {}", self.status_latency)?;
+        writeln!(f, "states:")?;
+        for (state, latency) in &self.state_latency {
+            writeln!(f, "  {:<24} {}", state, latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// The measurements of a single pod.
+#[derive(Default)]
+struct PodRun {
+    failed: bool,
+    latency: Duration,
+    states: Vec<(String, Duration)>,
+    statuses: Vec<Duration>,
+}
+
+/// Run the configured number of synthetic pods through the state machine of the provider and
+/// report how long it took. Fails if a pod state can't be initialized.
+pub async fn run<P: Provider>(
+    provider: Arc<P>,
+    config: &BenchConfig,
+) -> anyhow::Result<BenchReport> {
+    let provider_state = provider.provider_state();
+    let store = krator::Store::new();
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+
+    let started = Instant::now();
+    let mut tasks = Vec::with_capacity(config.pods);
+    for index in 0..config.pods {
+        let permit = permits.clone().acquire_owned().await?;
+        let pod = config.synthetic_pod(index);
+        let task_provider = provider.clone();
+        let task_provider_state = provider_state.clone();
+        let task_store = store.clone();
+        tasks.push(tokio::spawn(async move {
+            let run = run_pod(task_provider, task_provider_state, pod, task_store).await;
+            drop(permit);
+            run
+        }));
+    }
+
+    let mut runs = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        runs.push(task.await??);
+    }
+    let elapsed = started.elapsed();
+    Ok(report(runs, elapsed))
+}
+
+async fn run_pod<P: Provider>(
+    provider: Arc<P>,
+    provider_state: SharedState<P::ProviderState>,
+    pod: Pod,
+    store: krator::Store,
+) -> anyhow::Result<PodRun> {
+    let started = Instant::now();
+    let mut pod_state = provider.initialize_pod_state(&pod).await?;
+    // Nothing sends updates, but the manifest goes stale if the sender is dropped
+    let (_pod_tx, manifest) = Manifest::new(pod, store);
+    let mut run = PodRun::default();
+
+    let mut state: Box<dyn State<P::PodState>> = Box::new(P::InitialState::default());
+    let result = loop {
+        let status_started = Instant::now();
+        if let Err(e) = state.status(&mut pod_state, &manifest.latest()).await {
+            warn!(error = %e, ?state, "Unable to build status of benchmark pod");
+        }
+        run.statuses.push(status_started.elapsed());
+
+        let name = format!("{:?}", state);
+        let state_started = Instant::now();
+        let transition = state
+            .next(provider_state.clone(), &mut pod_state, manifest.clone())
+            .await;
+        run.states.push((name, state_started.elapsed()));
+
+        state = match transition {
+            Transition::Next(s) => s.into(),
+            Transition::Complete(result) => break result,
+        };
+    };
+    if let Err(e) = result {
+        debug!(error = %e, "Benchmark pod state machine completed with error");
+        run.failed = true;
+    }
+
+    {
+        let mut state_writer = provider_state.write().await;
+        pod_state.async_drop(&mut state_writer).await;
+    }
+    run.latency = started.elapsed();
+    Ok(run)
+}
+
+fn report(runs: Vec<PodRun>, elapsed: Duration) -> BenchReport {
+    let per_second = |count: usize| count as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+    let mut pod_samples = Vec::with_capacity(runs.len());
+    let mut state_samples: BTreeMap<String, Vec<Duration>> = BTreeMap::new();
+    let mut status_samples = vec![];
+    let mut failed_pods = 0;
+    for run in runs {
+        if run.failed {
+            failed_pods += 1;
+        }
+        pod_samples.push(run.latency);
+        for (state, latency) in run.states {
+            state_samples.entry(state).or_default().push(latency);
+        }
+        status_samples.extend(run.statuses);
+    }
+
+    BenchReport {
+        pods: pod_samples.len(),
+        failed_pods,
+        elapsed,
+        pods_per_second: per_second(pod_samples.len()),
+        pod_latency: Latency::from_samples(pod_samples),
+        state_latency: state_samples
+            .into_iter()
+            .map(|(state, samples)| (state, Latency::from_samples(samples)))
+            .collect(),
+        status_updates: status_samples.len(),
+        status_updates_per_second: per_second(status_samples.len()),
+        status_latency: Latency::from_samples(status_samples),
+    }
+}
+
+/// A provider whose pods start and run without doing any work, for measuring the overhead of
+/// the kubelet core.
+#[derive(Debug, Default)]
+pub struct StubProvider {
+    run_time: Duration,
+}
+
+impl StubProvider {
+    /// Create a provider whose pods run for `run_time` before completing.
+    pub fn new(run_time: Duration) -> Self {
+        StubProvider { run_time }
+    }
+}
+
+/// The provider state of [`StubProvider`].
+pub struct StubProviderState {
+    run_time: Duration,
+}
+
+impl PluginSupport for StubProviderState {
+    fn plugin_registry(&self) -> Option<Arc<PluginRegistry>> {
+        None
+    }
+}
+
+impl DevicePluginSupport for StubProviderState {
+    fn device_plugin_manager(&self) -> Option<Arc<DeviceManager>> {
+        None
+    }
+}
+
+/// The pod state of [`StubProvider`].
+pub struct StubPodState;
+
+#[async_trait::async_trait]
+impl ObjectState for StubPodState {
+    type Manifest = Pod;
+    type Status = PodStatus;
+    type SharedState = StubProviderState;
+    async fn async_drop(self, _provider_state: &mut StubProviderState) {}
+}
+
+/// The pod is starting.
+#[derive(Debug, Default)]
+pub struct Starting;
+
+/// The pod is running.
+#[derive(Debug, Default)]
+pub struct Running;
+
+/// The pod has completed.
+#[derive(Debug, Default)]
+pub struct Completed;
+
+impl TransitionTo<Running> for Starting {}
+
+#[async_trait::async_trait]
+impl State<StubPodState> for Starting {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<StubProviderState>,
+        _pod_state: &mut StubPodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<StubPodState> {
+        Transition::next(self, Running)
+    }
+
+    async fn status(&self, _pod_state: &mut StubPodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status(Phase::Pending, "Starting"))
+    }
+}
+
+#[async_trait::async_trait]
+impl State<StubPodState> for Running {
+    async fn next(
+        self: Box<Self>,
+        provider_state: SharedState<StubProviderState>,
+        _pod_state: &mut StubPodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<StubPodState> {
+        let run_time = provider_state.read().await.run_time;
+        if run_time > Duration::from_secs(0) {
+            tokio::time::sleep(run_time).await;
+        }
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut StubPodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status(Phase::Running, "Running"))
+    }
+}
+
+#[async_trait::async_trait]
+impl State<StubPodState> for Completed {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<StubProviderState>,
+        _pod_state: &mut StubPodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<StubPodState> {
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut StubPodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status(Phase::Succeeded, "Completed"))
+    }
+}
+
+#[async_trait::async_trait]
+impl Provider for StubProvider {
+    type ProviderState = StubProviderState;
+    type InitialState = Starting;
+    type TerminatedState = Completed;
+    type PodState = StubPodState;
+
+    const ARCH: &'static str = "bench";
+
+    fn provider_state(&self) -> SharedState<StubProviderState> {
+        Arc::new(RwLock::new(StubProviderState {
+            run_time: self.run_time,
+        }))
+    }
+
+    async fn initialize_pod_state(&self, _pod: &Pod) -> anyhow::Result<StubPodState> {
+        Ok(StubPodState)
+    }
+
+    async fn logs(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _sender: crate::log::Sender,
+    ) -> anyhow::Result<()> {
+        Err(crate::provider::NotImplementedError.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_from_samples() {
+        let samples = (1..=100).map(Duration::from_millis).collect();
+        let latency = Latency::from_samples(samples);
+        assert_eq!(100, latency.count);
+        assert_eq!(Duration::from_micros(50500), latency.mean);
+        assert_eq!(Duration::from_millis(50), latency.p50);
+        assert_eq!(Duration::from_millis(99), latency.p99);
+        assert_eq!(Duration::from_millis(100), latency.max);
+
+        assert_eq!(Latency::default(), Latency::from_samples(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_run_stub_provider() {
+        let config = BenchConfig {
+            pods: 20,
+            concurrency: 4,
+            containers_per_pod: 2,
+            ..Default::default()
+        };
+        let report = run(Arc::new(StubProvider::default()), &config)
+            .await
+            .unwrap();
+
+        assert_eq!(20, report.pods);
+        assert_eq!(0, report.failed_pods);
+        // Each pod enters Starting and Running once, building a status in each
+        assert_eq!(40, report.status_updates);
+        assert_eq!(
+            vec!["Running", "Starting"],
+            report.state_latency.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(20, report.state_latency["Starting"].count);
+    }
+
+    #[tokio::test]
+    async fn test_run_large_pods() {
+        let config = BenchConfig {
+            pods: 4,
+            concurrency: 2,
+            containers_per_pod: 2,
+            env_vars_per_container: 500,
+            volumes_per_pod: 100,
+            ..Default::default()
+        };
+        let pod = config.synthetic_pod(0);
+        assert_eq!(500, pod.containers()[1].env().as_ref().unwrap().len());
+        assert_eq!(100, pod.volumes().unwrap().len());
+
+        let report = run(Arc::new(StubProvider::default()), &config)
+            .await
+            .unwrap();
+        assert_eq!(4, report.pods);
+        assert_eq!(0, report.failed_pods);
+    }
+}
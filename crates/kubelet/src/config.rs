@@ -26,6 +26,7 @@ use serde::Deserialize;
 
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
+const DEFAULT_FAILURE_OUTPUT_LINES: u16 = 10;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
 
 /// The configuration needed for a kubelet to run properly.
@@ -60,12 +61,102 @@ pub struct Config {
     /// Registries that should be accessed using HTTP instead of
     /// HTTPS.
     pub insecure_registries: Option<Vec<String>>,
+    /// Registries whose TLS certificates are not verified, for self-hosted registries with
+    /// self-signed certificates. The certificates of all other registries are verified
+    pub insecure_skip_verify_registries: Option<Vec<String>>,
+    /// A file of PEM encoded CA certificates that the certificates of registries are also
+    /// verified against, for self-hosted registries with certificates of a private CA
+    pub registry_ca_file: Option<PathBuf>,
     /// The directory kubelet should watch for new plugin sockets
     pub plugins_dir: PathBuf,
     /// The directory where kubelet's Registration service for
     /// device plugins lives. This is also where device plugins
     /// should host their services.
     pub device_plugins_dir: PathBuf,
+    /// Where container output should be forwarded to in addition to the local
+    /// log files (e.g. `udp://syslog.local:514` or `https://logs.example.com`).
+    /// Pods can override this with the `krustlet.dev/log-forward` annotation
+    pub log_forward_url: Option<String>,
+    /// The destinations pods may forward their output to with the `krustlet.dev/log-forward`
+    /// annotation, as URLs whose scheme, host and port an annotation has to match. Pods can't
+    /// forward their output anywhere else if this is not set
+    pub log_forward_destinations: Option<Vec<String>>,
+    /// The directory containing executable volume plugins for `flexVolume` volumes. Volumes
+    /// with the driver `vendor/driver` are handled by `<dir>/vendor~driver/driver`. Flex volumes
+    /// are not supported if this is not set
+    pub volume_plugins_dir: Option<PathBuf>,
+    /// The directory OCI image layouts are sideloaded into. Pods may only load modules from
+    /// layouts under it with the `krustlet.dev/oci-layout` annotation, and not at all if this
+    /// is not set
+    pub oci_layout_root: Option<PathBuf>,
+    /// The range of node ports (e.g. `40000-40999`) that container ports of pods are mapped to,
+    /// for providers whose pods don't have their own network namespace. Container ports are not
+    /// mapped if this is not set
+    pub port_mapping_range: Option<String>,
+    /// The maximum number of environment variables across all containers of a pod. Pods with
+    /// more are rejected. There is no limit if this is not set
+    pub max_pod_env_vars: Option<u16>,
+    /// The maximum number of volumes of a pod. Pods with more are rejected. There is no limit if
+    /// this is not set
+    pub max_pod_volumes: Option<u16>,
+    /// The maximum size in bytes of a single environment variable of a container, counting its
+    /// name and value. Containers with a larger variable fail to start. There is no limit if this
+    /// is not set
+    pub max_env_var_size: Option<u32>,
+    /// The number of errors in a row a pod may have before it enters CrashLoopBackoff. Pods can
+    /// override it with an annotation. Defaults to 3 if this is not set
+    pub crash_loop_threshold: Option<u32>,
+    /// The longest time in seconds a pod in CrashLoopBackoff backs off for. Pods can override it
+    /// with an annotation. Defaults to 300 if this is not set
+    pub crash_loop_backoff_cap: Option<u32>,
+    /// How long in seconds a pod has to run without errors for its error count and backoff to be
+    /// reset. Pods can override it with an annotation. Defaults to 600 if this is not set
+    pub crash_loop_reset_after: Option<u32>,
+    /// The number of threads running modules may use between them. Each container takes a share
+    /// of them according to its CPU request, and waits to start until enough are free. There is
+    /// no limit if this is not set
+    pub execution_threads: Option<u16>,
+    /// Whether pods are rejected if their resource requests don't fit in what the node has left
+    /// of the allocatable resources the provider reports, after the requests of the pods already
+    /// running on it
+    pub check_allocatable: bool,
+    /// Registries that modules can also be pulled from when their own registry can't be reached,
+    /// as a map from repository prefixes (e.g. `edge.local:5000/apps`) to the prefixes that
+    /// replace them on the equivalent registries, in the order they are tried
+    pub registry_failover: Option<HashMap<String, Vec<String>>>,
+    /// The largest size in MiB an image layer may decompress to. Pulls of modules with a larger
+    /// layer fail. Defaults to 1024 if this is not set
+    pub max_layer_size: Option<u32>,
+    /// How many times requests for manifests and layers are retried when a registry can't be
+    /// reached or fails with an error that may be temporary, with a delay that doubles after
+    /// every retry. Layer downloads that fail partway are resumed where they stopped. Defaults
+    /// to 3 if this is not set
+    pub registry_max_retries: Option<u32>,
+    /// The size in MiB of the in-memory cache of small modules, which lets pods that restart
+    /// often start without reading their modules from disk. Modules are only read from disk if
+    /// this is not set
+    pub module_cache_size: Option<u32>,
+    /// A WebAssembly module run in place of the commands of `kubectl exec`, with the volumes and
+    /// environment of the container. Running commands in containers fails if this is not set
+    pub diagnostics_module: Option<PathBuf>,
+    /// The number of lines from the end of a failed container's output that are added to the
+    /// message of its terminated status, with the values of Secrets redacted. This only applies
+    /// to containers with the `FallbackToLogsOnError` termination message policy. Set to 0 to
+    /// leave output out of the message
+    pub failure_output_lines: u16,
+    /// The most pods that may be starting at once, from pulling their modules until they are
+    /// running, so that a node that comes back with many pods doesn't start them all at once.
+    /// Pods already running are not held up. There is no limit if this is not set
+    pub max_starting_pods: Option<u16>,
+    /// The most pods of each namespace that may be starting at once, so that a namespace that
+    /// creates many pods at once can't take every place among the starting pods. There is no
+    /// limit if this is not set
+    pub max_starting_pods_per_namespace: Option<u16>,
+    /// The units of fuel a module runs for before it yields its thread to other modules, for
+    /// providers that schedule modules cooperatively so that a CPU-bound module can't keep a
+    /// thread to itself. Modules run until they finish on threads of their own if this is not
+    /// set
+    pub fuel_quantum: Option<u32>,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -78,6 +169,15 @@ pub struct ServerConfig {
     pub cert_file: PathBuf,
     /// Path to kubelet TLS private key.
     pub private_key_file: PathBuf,
+    /// The port of the read-only Kubelet server, which serves `/pods`, `/healthz` and `/metrics`
+    /// over plain HTTP without authentication. It is not started if this is not set
+    pub read_only_port: Option<u16>,
+    /// Path to a file holding the bearer token that requests for `/attestation` must carry.
+    /// Attestations are not served if this is not set
+    pub attestation_token_file: Option<PathBuf>,
+    /// Path to a file holding the bearer token that requests for `/podPreview` must carry. Pod
+    /// previews are not served if this is not set
+    pub pod_preview_token_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -115,18 +215,132 @@ struct ConfigBuilder {
         deserialize_with = "try_deserialize_u16"
     )]
     pub server_port: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "readOnlyPort",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub server_read_only_port: Option<anyhow::Result<u16>>,
     #[serde(default, rename = "tlsCertificateFile")]
     pub server_tls_cert_file: Option<PathBuf>,
     #[serde(default, rename = "tlsPrivateKeyFile")]
     pub server_tls_private_key_file: Option<PathBuf>,
+    #[serde(default, rename = "attestationTokenFile")]
+    pub server_attestation_token_file: Option<PathBuf>,
+    #[serde(default, rename = "podPreviewTokenFile")]
+    pub server_pod_preview_token_file: Option<PathBuf>,
     #[serde(default, rename = "allowLocalModules")]
     pub allow_local_modules: Option<bool>,
     #[serde(default, rename = "insecureRegistries")]
     pub insecure_registries: Option<Vec<String>>,
+    #[serde(default, rename = "insecureSkipVerifyRegistries")]
+    pub insecure_skip_verify_registries: Option<Vec<String>>,
+    #[serde(default, rename = "registryCaFile")]
+    pub registry_ca_file: Option<PathBuf>,
     #[serde(default, rename = "pluginsDir")]
     pub plugins_dir: Option<PathBuf>,
     #[serde(default, rename = "devicePluginsDir")]
     pub device_plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "logForwardUrl")]
+    pub log_forward_url: Option<String>,
+    #[serde(default, rename = "logForwardDestinations")]
+    pub log_forward_destinations: Option<Vec<String>>,
+    #[serde(default, rename = "volumePluginsDir")]
+    pub volume_plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "ociLayoutRoot")]
+    pub oci_layout_root: Option<PathBuf>,
+    #[serde(default, rename = "portMappingRange")]
+    pub port_mapping_range: Option<String>,
+    #[serde(
+        default,
+        rename = "maxPodEnvVars",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_pod_env_vars: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "maxPodVolumes",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_pod_volumes: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "maxEnvVarSize",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub max_env_var_size: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "crashLoopThreshold",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub crash_loop_threshold: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "crashLoopBackoffCap",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub crash_loop_backoff_cap: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "crashLoopResetAfter",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub crash_loop_reset_after: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "executionThreads",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub execution_threads: Option<anyhow::Result<u16>>,
+    #[serde(default, rename = "checkAllocatable")]
+    pub check_allocatable: Option<bool>,
+    #[serde(default, rename = "registryFailover")]
+    pub registry_failover: Option<HashMap<String, Vec<String>>>,
+    #[serde(
+        default,
+        rename = "maxLayerSize",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub max_layer_size: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "registryMaxRetries",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub registry_max_retries: Option<anyhow::Result<u32>>,
+    #[serde(
+        default,
+        rename = "moduleCacheSize",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub module_cache_size: Option<anyhow::Result<u32>>,
+    #[serde(default, rename = "diagnosticsModule")]
+    pub diagnostics_module: Option<PathBuf>,
+    #[serde(
+        default,
+        rename = "failureOutputLines",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub failure_output_lines: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "maxStartingPods",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_starting_pods: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "maxStartingPodsPerNamespace",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub max_starting_pods_per_namespace: Option<anyhow::Result<u16>>,
+    #[serde(
+        default,
+        rename = "fuelQuantum",
+        deserialize_with = "try_deserialize_u32"
+    )]
+    pub fuel_quantum: Option<anyhow::Result<u32>>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -163,20 +377,72 @@ impl Config {
             bootstrap_file: PathBuf::from(BOOTSTRAP_FILE),
             allow_local_modules: false,
             insecure_registries: None,
+            insecure_skip_verify_registries: None,
+            registry_ca_file: None,
             plugins_dir,
             device_plugins_dir,
+            log_forward_url: None,
+            log_forward_destinations: None,
+            volume_plugins_dir: None,
+            oci_layout_root: None,
+            port_mapping_range: None,
+            max_pod_env_vars: None,
+            max_pod_volumes: None,
+            max_env_var_size: None,
+            crash_loop_threshold: None,
+            crash_loop_backoff_cap: None,
+            crash_loop_reset_after: None,
+            execution_threads: None,
+            check_allocatable: false,
+            registry_failover: None,
+            max_layer_size: None,
+            registry_max_retries: None,
+            module_cache_size: None,
+            diagnostics_module: None,
+            failure_output_lines: DEFAULT_FAILURE_OUTPUT_LINES,
+            max_starting_pods: None,
+            max_starting_pods_per_namespace: None,
+            fuel_quantum: None,
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
                     IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
                 },
                 port: DEFAULT_PORT,
+                read_only_port: None,
+                attestation_token_file: None,
+                pod_preview_token_file: None,
                 cert_file,
                 private_key_file,
             },
         })
     }
 
+    /// Returns the limits on the size of the pods this node accepts.
+    pub fn pod_spec_limits(&self) -> crate::pod::PodSpecLimits {
+        crate::pod::PodSpecLimits {
+            max_env_vars: self.max_pod_env_vars.map(usize::from),
+            max_volumes: self.max_pod_volumes.map(usize::from),
+            max_env_var_size: self.max_env_var_size.map(|size| size as usize),
+        }
+    }
+
+    /// Returns the policy for backing off pods that keep failing.
+    pub fn crash_loop_policy(&self) -> crate::backoff::CrashLoopPolicy {
+        let default = crate::backoff::CrashLoopPolicy::default();
+        crate::backoff::CrashLoopPolicy {
+            error_threshold: self.crash_loop_threshold.unwrap_or(default.error_threshold),
+            backoff_cap: self
+                .crash_loop_backoff_cap
+                .map(|secs| std::time::Duration::from_secs(secs.into()))
+                .unwrap_or(default.backoff_cap),
+            reset_after: self
+                .crash_loop_reset_after
+                .map(|secs| std::time::Duration::from_secs(secs.into()))
+                .unwrap_or(default.reset_after),
+        }
+    }
+
     fn new_from_builder(builder: ConfigBuilder) -> Self {
         let fallbacks = ConfigBuilderFallbacks {
             hostname: || default_hostname().expect("unable to get default hostname"),
@@ -287,12 +553,41 @@ impl ConfigBuilder {
             max_pods: ok_result_of(opts.max_pods),
             allow_local_modules: opts.allow_local_modules,
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
+            insecure_skip_verify_registries: opts
+                .insecure_skip_verify_registries
+                .map(parse_comma_separated),
+            registry_ca_file: opts.registry_ca_file,
             plugins_dir: opts.plugins_dir,
             device_plugins_dir: opts.device_plugins_dir,
+            log_forward_url: opts.log_forward_url,
+            log_forward_destinations: opts.log_forward_destinations.map(parse_comma_separated),
+            volume_plugins_dir: opts.volume_plugins_dir,
+            oci_layout_root: opts.oci_layout_root,
+            port_mapping_range: opts.port_mapping_range,
+            max_pod_env_vars: ok_result_of(opts.max_pod_env_vars),
+            max_pod_volumes: ok_result_of(opts.max_pod_volumes),
+            max_env_var_size: ok_result_of(opts.max_env_var_size),
+            crash_loop_threshold: ok_result_of(opts.crash_loop_threshold),
+            crash_loop_backoff_cap: ok_result_of(opts.crash_loop_backoff_cap),
+            crash_loop_reset_after: ok_result_of(opts.crash_loop_reset_after),
+            execution_threads: ok_result_of(opts.execution_threads),
+            check_allocatable: opts.check_allocatable,
+            registry_failover: opts.registry_failover.map(parse_registry_failover),
+            max_layer_size: ok_result_of(opts.max_layer_size),
+            registry_max_retries: ok_result_of(opts.registry_max_retries),
+            module_cache_size: ok_result_of(opts.module_cache_size),
+            diagnostics_module: opts.diagnostics_module,
+            failure_output_lines: ok_result_of(opts.failure_output_lines),
+            max_starting_pods: ok_result_of(opts.max_starting_pods),
+            max_starting_pods_per_namespace: ok_result_of(opts.max_starting_pods_per_namespace),
+            fuel_quantum: ok_result_of(opts.fuel_quantum),
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
+            server_read_only_port: ok_result_of(opts.read_only_port),
             server_tls_cert_file: opts.cert_file,
             server_tls_private_key_file: opts.private_key_file,
+            server_attestation_token_file: opts.attestation_token_file,
+            server_pod_preview_token_file: opts.pod_preview_token_file,
         }
     }
 
@@ -322,12 +617,49 @@ impl ConfigBuilder {
             max_pods: other.max_pods.or(self.max_pods),
             server_addr: other.server_addr.or(self.server_addr),
             server_port: other.server_port.or(self.server_port),
+            server_read_only_port: other.server_read_only_port.or(self.server_read_only_port),
             server_tls_cert_file: other.server_tls_cert_file.or(self.server_tls_cert_file),
+            server_attestation_token_file: other
+                .server_attestation_token_file
+                .or(self.server_attestation_token_file),
+            server_pod_preview_token_file: other
+                .server_pod_preview_token_file
+                .or(self.server_pod_preview_token_file),
             bootstrap_file: other.bootstrap_file.or(self.bootstrap_file),
             allow_local_modules: other.allow_local_modules.or(self.allow_local_modules),
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
+            insecure_skip_verify_registries: other
+                .insecure_skip_verify_registries
+                .or(self.insecure_skip_verify_registries),
+            registry_ca_file: other.registry_ca_file.or(self.registry_ca_file),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
             device_plugins_dir: other.device_plugins_dir.or(self.device_plugins_dir),
+            log_forward_url: other.log_forward_url.or(self.log_forward_url),
+            log_forward_destinations: other
+                .log_forward_destinations
+                .or(self.log_forward_destinations),
+            volume_plugins_dir: other.volume_plugins_dir.or(self.volume_plugins_dir),
+            oci_layout_root: other.oci_layout_root.or(self.oci_layout_root),
+            port_mapping_range: other.port_mapping_range.or(self.port_mapping_range),
+            max_pod_env_vars: other.max_pod_env_vars.or(self.max_pod_env_vars),
+            max_pod_volumes: other.max_pod_volumes.or(self.max_pod_volumes),
+            max_env_var_size: other.max_env_var_size.or(self.max_env_var_size),
+            crash_loop_threshold: other.crash_loop_threshold.or(self.crash_loop_threshold),
+            crash_loop_backoff_cap: other.crash_loop_backoff_cap.or(self.crash_loop_backoff_cap),
+            crash_loop_reset_after: other.crash_loop_reset_after.or(self.crash_loop_reset_after),
+            execution_threads: other.execution_threads.or(self.execution_threads),
+            check_allocatable: other.check_allocatable.or(self.check_allocatable),
+            registry_failover: other.registry_failover.or(self.registry_failover),
+            max_layer_size: other.max_layer_size.or(self.max_layer_size),
+            registry_max_retries: other.registry_max_retries.or(self.registry_max_retries),
+            module_cache_size: other.module_cache_size.or(self.module_cache_size),
+            diagnostics_module: other.diagnostics_module.or(self.diagnostics_module),
+            failure_output_lines: other.failure_output_lines.or(self.failure_output_lines),
+            max_starting_pods: other.max_starting_pods.or(self.max_starting_pods),
+            max_starting_pods_per_namespace: other
+                .max_starting_pods_per_namespace
+                .or(self.max_starting_pods_per_namespace),
+            fuel_quantum: other.fuel_quantum.or(self.fuel_quantum),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
@@ -360,6 +692,10 @@ impl ConfigBuilder {
             .server_port
             .unwrap_or(Ok(DEFAULT_PORT))
             .map_err(|e| invalid_config_value_error(e, "server port"))?;
+        let server_read_only_port = self
+            .server_read_only_port
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "read-only server port"))?;
         let node_ip = self
             .node_ip
             .unwrap_or_else(|| Ok((fallbacks.node_ip)(&mut hostname.clone(), &server_addr)))
@@ -371,6 +707,62 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let max_pod_env_vars = self
+            .max_pod_env_vars
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum pod environment variables"))?;
+        let max_pod_volumes = self
+            .max_pod_volumes
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum pod volumes"))?;
+        let max_env_var_size = self
+            .max_env_var_size
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum environment variable size"))?;
+        let crash_loop_threshold = self
+            .crash_loop_threshold
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "crash loop threshold"))?;
+        let max_layer_size = self
+            .max_layer_size
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum layer size"))?;
+        let registry_max_retries = self
+            .registry_max_retries
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "registry maximum retries"))?;
+        let module_cache_size = self
+            .module_cache_size
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "module cache size"))?;
+        let crash_loop_backoff_cap = self
+            .crash_loop_backoff_cap
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "crash loop backoff cap"))?;
+        let crash_loop_reset_after = self
+            .crash_loop_reset_after
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "crash loop reset window"))?;
+        let execution_threads = self
+            .execution_threads
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "execution threads"))?;
+        let failure_output_lines = self
+            .failure_output_lines
+            .unwrap_or(Ok(DEFAULT_FAILURE_OUTPUT_LINES))
+            .map_err(|e| invalid_config_value_error(e, "failure output lines"))?;
+        let max_starting_pods = self
+            .max_starting_pods
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum starting pods"))?;
+        let max_starting_pods_per_namespace = self
+            .max_starting_pods_per_namespace
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "maximum starting pods per namespace"))?;
+        let fuel_quantum = self
+            .fuel_quantum
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "fuel quantum"))?;
 
         Ok(Config {
             node_ip,
@@ -382,13 +774,40 @@ impl ConfigBuilder {
             bootstrap_file,
             allow_local_modules: self.allow_local_modules.unwrap_or(false),
             insecure_registries: self.insecure_registries,
+            insecure_skip_verify_registries: self.insecure_skip_verify_registries,
+            registry_ca_file: self.registry_ca_file,
             plugins_dir,
             device_plugins_dir,
+            log_forward_url: self.log_forward_url,
+            log_forward_destinations: self.log_forward_destinations,
+            volume_plugins_dir: self.volume_plugins_dir,
+            oci_layout_root: self.oci_layout_root,
+            port_mapping_range: self.port_mapping_range,
+            max_pod_env_vars,
+            max_pod_volumes,
+            max_env_var_size,
+            crash_loop_threshold,
+            crash_loop_backoff_cap,
+            crash_loop_reset_after,
+            execution_threads,
+            check_allocatable: self.check_allocatable.unwrap_or(false),
+            registry_failover: self.registry_failover,
+            max_layer_size,
+            registry_max_retries,
+            module_cache_size,
+            diagnostics_module: self.diagnostics_module,
+            failure_output_lines,
+            max_starting_pods,
+            max_starting_pods_per_namespace,
+            fuel_quantum,
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
                 addr: server_addr,
                 port: server_port,
+                read_only_port: server_read_only_port,
+                attestation_token_file: self.server_attestation_token_file,
+                pod_preview_token_file: self.server_pod_preview_token_file,
             },
         })
     }
@@ -413,6 +832,16 @@ where
     Ok(Some(n))
 }
 
+// This type signature is required by Serde `deserialize_with`.
+#[allow(clippy::unnecessary_wraps)]
+fn try_deserialize_u32<'de, D>(d: D) -> Result<Option<anyhow::Result<u32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let n = u32::deserialize(d).map_err(|e| anyhow::Error::msg(format!("{}", e)));
+    Ok(Some(n))
+}
+
 /// CLI options that can be configured for Kubelet
 ///
 /// These can be parsed from args using `Opts::into_app()`
@@ -440,6 +869,27 @@ pub struct Opts {
     )]
     port: Option<u16>,
 
+    #[structopt(
+        long = "read-only-port",
+        env = "KRUSTLET_READ_ONLY_PORT",
+        help = "The port of the read-only server, which serves /pods, /healthz and /metrics without TLS or authentication. Disabled if not set"
+    )]
+    read_only_port: Option<u16>,
+
+    #[structopt(
+        long = "attestation-token-file",
+        env = "KRUSTLET_ATTESTATION_TOKEN_FILE",
+        help = "The path to a file holding the bearer token that requests for /attestation must carry. Attestations are not served if not set"
+    )]
+    attestation_token_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "pod-preview-token-file",
+        env = "KRUSTLET_POD_PREVIEW_TOKEN_FILE",
+        help = "The path to a file holding the bearer token that requests for /podPreview must carry. Pod previews are not served if not set"
+    )]
+    pod_preview_token_file: Option<PathBuf>,
+
     #[structopt(
         long = "max-pods",
         env = "MAX_PODS",
@@ -541,6 +991,174 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "insecure-skip-verify-registries",
+        env = "KRUSTLET_INSECURE_SKIP_VERIFY_REGISTRIES",
+        help = "Registries whose TLS certificates should not be verified (comma separated)"
+    )]
+    insecure_skip_verify_registries: Option<String>,
+
+    #[structopt(
+        long = "registry-ca-file",
+        env = "KRUSTLET_REGISTRY_CA_FILE",
+        help = "The path to a file of PEM encoded CA certificates to also trust for registries"
+    )]
+    registry_ca_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "log-forward-url",
+        env = "KRUSTLET_LOG_FORWARD_URL",
+        help = "Where to forward container output in addition to the local log files (udp://, tcp://, http:// or https://)"
+    )]
+    log_forward_url: Option<String>,
+
+    #[structopt(
+        long = "log-forward-destinations",
+        env = "KRUSTLET_LOG_FORWARD_DESTINATIONS",
+        help = "The destinations pods may forward their output to with the krustlet.dev/log-forward annotation (comma separated URLs)"
+    )]
+    log_forward_destinations: Option<String>,
+
+    #[structopt(
+        long = "volume-plugins-dir",
+        env = "KRUSTLET_VOLUME_PLUGINS_DIR",
+        help = "The path to the directory containing executable plugins for flexVolume volumes"
+    )]
+    volume_plugins_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "oci-layout-root",
+        env = "KRUSTLET_OCI_LAYOUT_ROOT",
+        help = "The directory OCI image layouts are sideloaded into, which pods may load modules from with the krustlet.dev/oci-layout annotation"
+    )]
+    oci_layout_root: Option<PathBuf>,
+
+    #[structopt(
+        long = "port-mapping-range",
+        env = "KRUSTLET_PORT_MAPPING_RANGE",
+        help = "The range of node ports (e.g. 40000-40999) to map container ports of pods to"
+    )]
+    port_mapping_range: Option<String>,
+
+    #[structopt(
+        long = "max-pod-env-vars",
+        env = "KRUSTLET_MAX_POD_ENV_VARS",
+        help = "The maximum number of environment variables across all containers of a pod. Pods with more are rejected"
+    )]
+    max_pod_env_vars: Option<u16>,
+
+    #[structopt(
+        long = "max-pod-volumes",
+        env = "KRUSTLET_MAX_POD_VOLUMES",
+        help = "The maximum number of volumes of a pod. Pods with more are rejected"
+    )]
+    max_pod_volumes: Option<u16>,
+
+    #[structopt(
+        long = "max-env-var-size",
+        env = "KRUSTLET_MAX_ENV_VAR_SIZE",
+        help = "The maximum size in bytes of an environment variable of a container, counting its name and value. Containers with a larger variable fail to start"
+    )]
+    max_env_var_size: Option<u32>,
+
+    #[structopt(
+        long = "crash-loop-threshold",
+        env = "KRUSTLET_CRASH_LOOP_THRESHOLD",
+        help = "The number of errors in a row a pod may have before it enters CrashLoopBackoff. Defaults to 3"
+    )]
+    crash_loop_threshold: Option<u32>,
+
+    #[structopt(
+        long = "crash-loop-backoff-cap",
+        env = "KRUSTLET_CRASH_LOOP_BACKOFF_CAP",
+        help = "The longest time in seconds a pod in CrashLoopBackoff backs off for. Defaults to 300"
+    )]
+    crash_loop_backoff_cap: Option<u32>,
+
+    #[structopt(
+        long = "crash-loop-reset-after",
+        env = "KRUSTLET_CRASH_LOOP_RESET_AFTER",
+        help = "How long in seconds a pod has to run without errors for its error count and backoff to be reset. Defaults to 600"
+    )]
+    crash_loop_reset_after: Option<u32>,
+
+    #[structopt(
+        long = "execution-threads",
+        env = "KRUSTLET_EXECUTION_THREADS",
+        help = "The number of threads running modules may use between them, shared out by the CPU requests of containers"
+    )]
+    execution_threads: Option<u16>,
+
+    #[structopt(
+        long = "check-allocatable",
+        env = "KRUSTLET_CHECK_ALLOCATABLE",
+        help = "Whether to reject pods whose resource requests don't fit in what is left of the node's allocatable resources"
+    )]
+    check_allocatable: Option<bool>,
+
+    #[structopt(
+        long = "registry-failover",
+        env = "KRUSTLET_REGISTRY_FAILOVER",
+        help = "Registries to pull modules from when their own registry can't be reached, as prefix=replica,replica pairs separated by ';' (e.g. edge.local:5000/apps=backup.local:5000/apps)"
+    )]
+    registry_failover: Option<String>,
+
+    #[structopt(
+        long = "max-layer-size",
+        env = "KRUSTLET_MAX_LAYER_SIZE",
+        help = "The largest size in MiB an image layer may decompress to. Pulls of modules with a larger layer fail. Defaults to 1024"
+    )]
+    max_layer_size: Option<u32>,
+
+    #[structopt(
+        long = "registry-max-retries",
+        env = "KRUSTLET_REGISTRY_MAX_RETRIES",
+        help = "How many times requests for manifests and layers are retried when a registry can't be reached or fails with an error that may be temporary. Layer downloads that fail partway are resumed. Defaults to 3"
+    )]
+    registry_max_retries: Option<u32>,
+
+    #[structopt(
+        long = "module-cache-size",
+        env = "KRUSTLET_MODULE_CACHE_SIZE",
+        help = "The size in MiB of the in-memory cache of small modules, which lets pods that restart often start without reading their modules from disk"
+    )]
+    module_cache_size: Option<u32>,
+
+    #[structopt(
+        long = "diagnostics-module",
+        env = "KRUSTLET_DIAGNOSTICS_MODULE",
+        help = "The path to a WebAssembly module to run in place of the commands of kubectl exec, with the volumes and environment of the container"
+    )]
+    diagnostics_module: Option<PathBuf>,
+
+    #[structopt(
+        long = "failure-output-lines",
+        env = "KRUSTLET_FAILURE_OUTPUT_LINES",
+        help = "The number of lines from the end of a failed container's output to add to its terminated status message, with Secrets redacted, for containers with the FallbackToLogsOnError termination message policy. Defaults to 10, and 0 leaves output out"
+    )]
+    failure_output_lines: Option<u16>,
+
+    #[structopt(
+        long = "max-starting-pods",
+        env = "KRUSTLET_MAX_STARTING_PODS",
+        help = "The most pods that may be starting at once, from pulling their modules until they are running. Pods already running are not held up. There is no limit by default"
+    )]
+    max_starting_pods: Option<u16>,
+
+    #[structopt(
+        long = "max-starting-pods-per-namespace",
+        env = "KRUSTLET_MAX_STARTING_PODS_PER_NAMESPACE",
+        help = "The most pods of each namespace that may be starting at once, so that a namespace that creates many pods can't hold up the pods of others. There is no limit by default"
+    )]
+    max_starting_pods_per_namespace: Option<u16>,
+
+    #[structopt(
+        long = "fuel-quantum",
+        env = "KRUSTLET_FUEL_QUANTUM",
+        help = "The units of fuel a module runs for before it yields its thread to other modules, so that a CPU-bound module can't keep a thread to itself. Modules run on threads of their own by default"
+    )]
+    fuel_quantum: Option<u32>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -643,6 +1261,19 @@ fn parse_comma_separated(source: String) -> Vec<String> {
     source.split(',').map(|s| s.trim().to_owned()).collect()
 }
 
+fn parse_registry_failover(source: String) -> HashMap<String, Vec<String>> {
+    source
+        .split(';')
+        .filter_map(|entry| {
+            let (prefix, replicas) = entry.split_once('=')?;
+            Some((
+                prefix.trim().to_owned(),
+                parse_comma_separated(replicas.to_owned()),
+            ))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -669,6 +1300,9 @@ mod test {
         let config_builder = builder_from_json_string(
             r#"{
             "listenerPort": 1234,
+            "readOnlyPort": 10255,
+            "attestationTokenFile": "/the/attestation/token",
+            "podPreviewTokenFile": "/the/preview/token",
             "listenerAddress": "172.182.192.1",
             "hostname": "krusty-host",
             "dataDir": "/krusty/data/dir",
@@ -687,11 +1321,46 @@ mod test {
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "insecureSkipVerifyRegistries": ["registry.local:5000"],
+            "registryCaFile": "/etc/krustlet/registry-ca.pem",
+            "pluginsDir": "/some/plugins",
+            "logForwardUrl": "udp://syslog.local:514",
+            "logForwardDestinations": ["udp://syslog.local:514"],
+            "volumePluginsDir": "/some/volume/plugins",
+            "ociLayoutRoot": "/mnt/usb",
+            "portMappingRange": "40000-40999",
+            "maxPodEnvVars": 500,
+            "maxPodVolumes": 50,
+            "maxEnvVarSize": 32768,
+            "crashLoopThreshold": 5,
+            "crashLoopBackoffCap": 120,
+            "crashLoopResetAfter": 900,
+            "executionThreads": 4,
+            "checkAllocatable": true,
+            "registryFailover": {
+                "edge.local:5000/apps": ["backup.local:5000/apps", "ghcr.io/acme/apps"]
+            },
+            "maxLayerSize": 256,
+            "registryMaxRetries": 5,
+            "moduleCacheSize": 16,
+            "diagnosticsModule": "/some/diagnostics.wasm",
+            "failureOutputLines": 5,
+            "maxStartingPods": 20,
+            "maxStartingPodsPerNamespace": 4,
+            "fuelQuantum": 1000000
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
         assert_eq!(config.server_config.port, 1234);
+        assert_eq!(config.server_config.read_only_port, Some(10255));
+        assert_eq!(
+            config.server_config.attestation_token_file,
+            Some(PathBuf::from("/the/attestation/token"))
+        );
+        assert_eq!(
+            config.server_config.pod_preview_token_file,
+            Some(PathBuf::from("/the/preview/token"))
+        );
         assert_eq!(format!("{}", config.server_config.addr), "172.182.192.1");
         assert_eq!(
             config.server_config.cert_file.to_string_lossy(),
@@ -716,7 +1385,68 @@ mod test {
         assert_eq!(config.insecure_registries.clone().unwrap().len(), 2);
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
+        assert_eq!(
+            config.insecure_skip_verify_registries,
+            Some(vec!["registry.local:5000".to_owned()])
+        );
+        assert_eq!(
+            config.registry_ca_file,
+            Some(PathBuf::from("/etc/krustlet/registry-ca.pem"))
+        );
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(
+            config.log_forward_url.as_deref(),
+            Some("udp://syslog.local:514")
+        );
+        assert_eq!(
+            config.log_forward_destinations,
+            Some(vec!["udp://syslog.local:514".to_owned()])
+        );
+        assert_eq!(
+            config.volume_plugins_dir,
+            Some(PathBuf::from("/some/volume/plugins"))
+        );
+        assert_eq!(config.oci_layout_root, Some(PathBuf::from("/mnt/usb")));
+        assert_eq!(config.port_mapping_range, Some("40000-40999".to_owned()));
+        assert_eq!(config.max_pod_env_vars, Some(500));
+        assert_eq!(config.max_pod_volumes, Some(50));
+        assert_eq!(config.max_env_var_size, Some(32768));
+        assert_eq!(config.crash_loop_threshold, Some(5));
+        assert_eq!(config.crash_loop_backoff_cap, Some(120));
+        assert_eq!(config.crash_loop_reset_after, Some(900));
+        assert_eq!(config.execution_threads, Some(4));
+        assert_eq!(config.check_allocatable, true);
+        assert_eq!(
+            config.registry_failover.unwrap()["edge.local:5000/apps"],
+            vec!["backup.local:5000/apps", "ghcr.io/acme/apps"]
+        );
+        assert_eq!(config.max_layer_size, Some(256));
+        assert_eq!(config.registry_max_retries, Some(5));
+        assert_eq!(config.module_cache_size, Some(16));
+        assert_eq!(
+            config.diagnostics_module,
+            Some(PathBuf::from("/some/diagnostics.wasm"))
+        );
+        assert_eq!(config.failure_output_lines, 5);
+        assert_eq!(config.max_starting_pods, Some(20));
+        assert_eq!(config.max_starting_pods_per_namespace, Some(4));
+        assert_eq!(config.fuel_quantum, Some(1000000));
+    }
+
+    #[test]
+    fn registry_failover_is_parsed() {
+        let failover = parse_registry_failover(
+            "edge.local:5000/apps=backup.local:5000/apps, ghcr.io/acme/apps;edge.local:5000/tools=ghcr.io/acme/tools".to_owned(),
+        );
+        assert_eq!(failover.len(), 2);
+        assert_eq!(
+            failover["edge.local:5000/apps"],
+            vec!["backup.local:5000/apps", "ghcr.io/acme/apps"]
+        );
+        assert_eq!(
+            failover["edge.local:5000/tools"],
+            vec!["ghcr.io/acme/tools"]
+        );
     }
 
     #[test]
@@ -776,7 +1506,15 @@ mod test {
         assert_eq!(config.data_dir.to_string_lossy(), "/fallback/data/dir");
         assert_eq!(format!("{}", config.node_ip), "4.4.4.4");
         assert_eq!(config.allow_local_modules, false);
+        assert_eq!(config.check_allocatable, false);
+        assert_eq!(config.failure_output_lines, 10);
+        assert_eq!(config.max_starting_pods, None);
+        assert_eq!(config.fuel_quantum, None);
+        assert_eq!(config.server_config.attestation_token_file, None);
+        assert_eq!(config.server_config.pod_preview_token_file, None);
         assert_eq!(config.insecure_registries, None);
+        assert_eq!(config.log_forward_destinations, None);
+        assert_eq!(config.oci_layout_root, None);
         assert_eq!(config.node_labels.len(), 0);
         assert_eq!(
             &config.plugins_dir.to_string_lossy(),
@@ -2,7 +2,10 @@
 //! data in 'sensible' ways.
 
 use crate::config::Config;
-use oci_distribution::client::{ClientConfig, ClientConfigSource, ClientProtocol};
+use oci_distribution::client::{
+    Certificate, CertificateEncoding, ClientConfig, ClientConfigSource, ClientProtocol,
+};
+use tracing::error;
 
 impl ClientConfigSource for Config {
     fn client_config(&self) -> ClientConfig {
@@ -10,13 +13,51 @@ impl ClientConfigSource for Config {
             None => ClientProtocol::default(),
             Some(registries) => ClientProtocol::HttpsExcept(registries.clone()),
         };
+        let extra_root_certificates = match &self.registry_ca_file {
+            None => vec![],
+            Some(path) => match std::fs::read(path) {
+                Ok(bundle) => pem_certificates(&bundle),
+                Err(e) => {
+                    error!(error = %e, path = %path.display(), "Unable to read registry CA file");
+                    vec![]
+                }
+            },
+        };
         ClientConfig {
             protocol,
+            accept_invalid_certificates_from: self
+                .insecure_skip_verify_registries
+                .clone()
+                .unwrap_or_default(),
+            extra_root_certificates,
+            max_decompressed_layer_size: self
+                .max_layer_size
+                .map(|size| u64::from(size) * 1024 * 1024),
+            max_retries: self.registry_max_retries,
             ..Default::default()
         }
     }
 }
 
+/// The certificates of a PEM bundle, each in a PEM document of its own, as only the first
+/// certificate of a document is read.
+fn pem_certificates(bundle: &[u8]) -> Vec<Certificate> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let bundle = String::from_utf8_lossy(bundle);
+    bundle
+        .match_indices(BEGIN)
+        .filter_map(|(start, _)| {
+            let rest = &bundle[start..];
+            rest.find(END).map(|end| &rest[..end + END.len()])
+        })
+        .map(|pem| Certificate {
+            encoding: CertificateEncoding::Pem,
+            data: pem.as_bytes().to_vec(),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -32,8 +73,32 @@ mod test {
             data_dir: std::path::PathBuf::from("/nope"),
             hostname: "nope".to_owned(),
             insecure_registries: None,
+            insecure_skip_verify_registries: None,
+            registry_ca_file: None,
             plugins_dir: std::path::PathBuf::from("/nope"),
             device_plugins_dir: std::path::PathBuf::from("/nope"),
+            log_forward_url: None,
+            log_forward_destinations: None,
+            volume_plugins_dir: None,
+            oci_layout_root: None,
+            port_mapping_range: None,
+            max_pod_env_vars: None,
+            max_pod_volumes: None,
+            max_env_var_size: None,
+            crash_loop_threshold: None,
+            crash_loop_backoff_cap: None,
+            crash_loop_reset_after: None,
+            execution_threads: None,
+            check_allocatable: false,
+            registry_failover: None,
+            max_layer_size: None,
+            registry_max_retries: None,
+            module_cache_size: None,
+            diagnostics_module: None,
+            failure_output_lines: 10,
+            max_starting_pods: None,
+            max_starting_pods_per_namespace: None,
+            fuel_quantum: None,
             max_pods: 0,
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             node_labels: std::collections::HashMap::new(),
@@ -43,6 +108,9 @@ mod test {
                 port: 0,
                 cert_file: std::path::PathBuf::from("/nope"),
                 private_key_file: std::path::PathBuf::from("/nope"),
+                read_only_port: None,
+                attestation_token_file: None,
+                pod_preview_token_file: None,
             },
         }
     }
@@ -67,4 +135,78 @@ mod test {
             ClientProtocol::HttpsExcept(vec!["local".to_owned(), "dev".to_owned()]);
         assert_eq!(expected_protocol, client_config.protocol);
     }
+
+    #[test]
+    fn oci_config_respects_config_insecure_skip_verify_registries() {
+        let config = Config {
+            insecure_skip_verify_registries: Some(vec!["registry.local:5000".to_owned()]),
+            ..empty_config()
+        };
+
+        let client_config = config.client_config();
+
+        assert_eq!(
+            vec!["registry.local:5000".to_owned()],
+            client_config.accept_invalid_certificates_from
+        );
+        assert!(empty_config()
+            .client_config()
+            .accept_invalid_certificates_from
+            .is_empty());
+    }
+
+    #[test]
+    fn oci_config_reads_registry_ca_file() {
+        let first = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----";
+        let second = "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----";
+        let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut ca_file,
+            format!("# Registry CA\n{}\n\n{}\n", first, second).as_bytes(),
+        )
+        .unwrap();
+        let config = Config {
+            registry_ca_file: Some(ca_file.path().to_owned()),
+            ..empty_config()
+        };
+
+        let certificates: Vec<_> = config
+            .client_config()
+            .extra_root_certificates
+            .into_iter()
+            .map(|certificate| String::from_utf8(certificate.data).unwrap())
+            .collect();
+
+        assert_eq!(vec![first, second], certificates);
+    }
+
+    #[test]
+    fn oci_config_respects_config_max_layer_size() {
+        let config = Config {
+            max_layer_size: Some(64),
+            ..empty_config()
+        };
+
+        let client_config = config.client_config();
+
+        assert_eq!(
+            Some(64 * 1024 * 1024),
+            client_config.max_decompressed_layer_size
+        );
+        assert_eq!(
+            None,
+            empty_config().client_config().max_decompressed_layer_size
+        );
+    }
+
+    #[test]
+    fn oci_config_respects_config_registry_max_retries() {
+        let config = Config {
+            registry_max_retries: Some(0),
+            ..empty_config()
+        };
+
+        assert_eq!(Some(0), config.client_config().max_retries);
+        assert_eq!(None, empty_config().client_config().max_retries);
+    }
 }
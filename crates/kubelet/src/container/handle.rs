@@ -1,10 +1,19 @@
-use std::io::SeekFrom;
+use std::time::Duration;
 
-use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::watch;
+
+use futures::future::BoxFuture;
 
 use crate::container::ContainerMap;
-use crate::handle::StopHandler;
-use crate::log::{stream, HandleFactory, Sender};
+use crate::exec;
+use crate::handle::{AttachHandler, ExecHandler, StopHandler};
+use crate::log::{LogBacking, LogStream, Sender};
+use crate::task_group::TaskGroup;
+
+/// How long log streams are given to send the rest of the log once the container has stopped,
+/// before they are cancelled, e.g. because the client isn't reading it.
+const LOG_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Represents a handle to a running "container" (whatever that might be). This
 /// can be used on its own, however, it is generally better to use it as a part
@@ -13,6 +22,11 @@ use crate::log::{stream, HandleFactory, Sender};
 pub struct Handle<H, F> {
     handle: H,
     handle_factory: F,
+    /// The tasks streaming the container's logs to clients.
+    log_streams: TaskGroup,
+    /// Set once the container has stopped, to end followed log streams. Dropping the handle
+    /// ends them as well.
+    stopped: (watch::Sender<bool>, watch::Receiver<bool>),
 }
 
 impl<H, F> std::fmt::Debug for Handle<H, F> {
@@ -21,6 +35,20 @@ impl<H, F> std::fmt::Debug for Handle<H, F> {
     }
 }
 
+impl<H, F> Drop for Handle<H, F> {
+    fn drop(&mut self) {
+        // Followed logs get to send the rest of the log, as when the container stops, before
+        // the streams that are left are cancelled
+        if self.log_streams.is_empty() {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let log_streams = self.log_streams.clone();
+            runtime.spawn(async move { log_streams.shutdown(LOG_CLOSE_TIMEOUT).await });
+        }
+    }
+}
+
 impl<H: StopHandler, F> Handle<H, F> {
     /// Create a new runtime with the given handle for stopping the runtime,
     /// a reader for log output, and a status channel.
@@ -28,6 +56,8 @@ impl<H: StopHandler, F> Handle<H, F> {
         Self {
             handle,
             handle_factory,
+            log_streams: TaskGroup::new("log streams"),
+            stopped: watch::channel(false),
         }
     }
 
@@ -37,24 +67,90 @@ impl<H: StopHandler, F> Handle<H, F> {
         self.handle.stop().await
     }
 
+    /// Force the running instance to stop after it did not stop within its grace period. This
+    /// uses the underlying [`StopHandler`] implementation passed to the constructor
+    pub async fn kill(&mut self) -> anyhow::Result<()> {
+        self.handle.kill().await
+    }
+
     /// Streams output from the running process into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
-    pub(crate) async fn output<R>(&mut self, sender: Sender) -> anyhow::Result<()>
+    /// Output from before the requested `sinceTime` or `sinceSeconds` is skipped, and lines are
+    /// stamped with when they were written, if the [`LogBacking`] keeps a
+    /// [`LogIndex`](crate::log::LogIndex).
+    pub(crate) async fn output(&mut self, sender: Sender) -> anyhow::Result<()>
     where
-        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
-        F: HandleFactory<R>,
+        F: LogBacking,
     {
-        let mut handle = self.handle_factory.new_handle();
-        handle.seek(SeekFrom::Start(0)).await?;
-        tokio::spawn(stream(handle, sender));
+        let index = self.handle_factory.log_index();
+        let since = match (sender.since_start(), &index) {
+            (Some(since), Some(index)) => index.offset_since(since),
+            _ => 0,
+        };
+        // The offset may be in the middle of a line, in which case start at the next one
+        let start = since.saturating_sub(1);
+        let mut handle = tokio::io::BufReader::new(self.handle_factory.open(start).await?);
+        let offset = if since == 0 {
+            0
+        } else {
+            let skipped = handle.read_until(b'\n', &mut Vec::new()).await?;
+            start + skipped as u64
+        };
+        let mut stream = LogStream::new(handle, sender);
+        if let Some(index) = index {
+            stream = stream.with_index(index, offset);
+        }
+        if let Some(path) = self.handle_factory.log_path() {
+            stream = stream.watching(path);
+        }
+        let stream = stream.until_stopped(self.stopped.1.clone());
+
+        self.log_streams.spawn("log stream", stream.run());
         Ok(())
     }
 
     /// Wait for the running process to complete. Generally speaking,
     /// [`Handle::stop`] should be called first. This uses the underlying
-    /// [`StopHandler`] implementation passed to the constructor
+    /// [`StopHandler`] implementation passed to the constructor.
+    ///
+    /// Once the process has completed, followed logs are [closed](Handle::close_logs).
     pub async fn wait(&mut self) -> anyhow::Result<()> {
-        self.handle.wait().await
+        self.handle.wait().await?;
+        self.close_logs().await;
+        Ok(())
+    }
+
+    /// End the streams of the container's logs, once the rest of the log has been sent to their
+    /// clients, or cancel them if that takes more than a few seconds. Logs requested afterwards
+    /// end once what was logged has been sent, even if they are followed.
+    pub async fn close_logs(&mut self) {
+        // The handle keeps a receiver, so this can't fail
+        let _ = self.stopped.0.send(true);
+        self.log_streams.shutdown(LOG_CLOSE_TIMEOUT).await;
+    }
+}
+
+impl<H: AttachHandler, F> Handle<H, F> {
+    /// Attach to the standard streams of the running process. This uses the underlying
+    /// [`AttachHandler`] implementation passed to the constructor
+    pub fn attach(
+        &self,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        self.handle.attach(sender, receiver)
+    }
+}
+
+impl<H: ExecHandler, F> Handle<H, F> {
+    /// Run a command alongside the running process. This uses the underlying [`ExecHandler`]
+    /// implementation passed to the constructor
+    pub fn exec(
+        &self,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> BoxFuture<'static, anyhow::Result<i32>> {
+        self.handle.exec(sender, receiver)
     }
 }
 
@@ -1,16 +1,24 @@
 //! `container` is a collection of utilities surrounding the Kubernetes container API.
 
-use k8s_openapi::api::core::v1::Container as KubeContainer;
+use k8s_openapi::api::core::v1::{Container as KubeContainer, EphemeralContainer};
 use oci_distribution::Reference;
 use std::convert::TryInto;
 use std::fmt::Display;
 
+use crate::pod::{
+    annotation_text, container_annotation, parse_flag_annotation, parse_number_annotation, Pod,
+};
+
 mod handle;
+mod probe;
+mod restart;
 pub mod state;
 mod status;
 
 pub use handle::{Handle, HandleMap};
-pub use status::{make_initial_container_status, patch_container_status, Status};
+pub use probe::{Probe, ProbeAction, Prober};
+pub use restart::RestartPolicy;
+pub use status::{make_initial_container_status, patch_container_status, Status, OOM_KILLED};
 
 /// Specifies how the store should check for module updates
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -68,13 +76,15 @@ pub enum ContainerKey {
     Init(String),
     /// An application container with the given name
     App(String),
+    /// An ephemeral container with the given name, added to a running pod
+    Ephemeral(String),
 }
 
 impl ContainerKey {
     /// Gets the container name
     pub fn name(&self) -> String {
         match self {
-            Self::Init(name) | Self::App(name) => name.to_string(),
+            Self::Init(name) | Self::App(name) | Self::Ephemeral(name) => name.to_string(),
         }
     }
 
@@ -87,6 +97,11 @@ impl ContainerKey {
     pub fn is_init(&self) -> bool {
         matches!(self, Self::Init(_))
     }
+
+    /// Whether the key identifies an ephemeral container
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Ephemeral(_))
+    }
 }
 
 impl Display for ContainerKey {
@@ -111,16 +126,20 @@ impl<V> ContainerMapByName<V> for ContainerMap<V> {
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V> {
         // TODO: borrow checker objected to any of the more natural forms
         let app_key = ContainerKey::App(name.clone());
+        let init_key = ContainerKey::Init(name.clone());
         if self.contains_key(&app_key) {
             self.get_mut(&app_key)
+        } else if self.contains_key(&init_key) {
+            self.get_mut(&init_key)
         } else {
-            self.get_mut(&ContainerKey::Init(name))
+            self.get_mut(&ContainerKey::Ephemeral(name))
         }
     }
 
     fn contains_key_name(&self, name: &str) -> bool {
         self.contains_key(&ContainerKey::App(name.to_owned()))
             || self.contains_key(&ContainerKey::Init(name.to_owned()))
+            || self.contains_key(&ContainerKey::Ephemeral(name.to_owned()))
     }
 }
 
@@ -137,6 +156,62 @@ impl Container {
         Container(container.clone())
     }
 
+    /// Create new Container from an ephemeral container of a pod. Ephemeral containers have
+    /// the same fields as other containers, except that they can't have ports, probes or
+    /// resources, and that they may target another container of the pod, which is ignored.
+    pub fn from_ephemeral(container: &EphemeralContainer) -> Self {
+        let container = container.clone();
+        Container(KubeContainer {
+            args: container.args,
+            command: container.command,
+            env: container.env,
+            env_from: container.env_from,
+            image: container.image,
+            image_pull_policy: container.image_pull_policy,
+            lifecycle: container.lifecycle,
+            name: container.name,
+            security_context: container.security_context,
+            stdin: container.stdin,
+            stdin_once: container.stdin_once,
+            termination_message_path: container.termination_message_path,
+            termination_message_policy: container.termination_message_policy,
+            tty: container.tty,
+            volume_devices: container.volume_devices,
+            volume_mounts: container.volume_mounts,
+            working_dir: container.working_dir,
+            ..Default::default()
+        })
+    }
+
+    /// Get an annotation of the pod for this container: the annotation for this container
+    /// alone, whose key is the annotation's key followed by `.` and the name of the container,
+    /// or else the annotation for all of the pod's containers.
+    pub fn annotation<'a>(&self, pod: &'a Pod, key: &str) -> Option<&'a str> {
+        Some(container_annotation(pod, self.name(), key)?.1)
+    }
+
+    /// Get an annotation of the pod for this container that holds `true` or `false`, failing if
+    /// it holds anything else.
+    pub fn annotation_flag(&self, pod: &Pod, key: &str) -> anyhow::Result<Option<bool>> {
+        container_annotation(pod, self.name(), key)
+            .map(|(key, value)| parse_flag_annotation(&key, value))
+            .transpose()
+    }
+
+    /// Get an annotation of the pod for this container that holds a whole number, failing if it
+    /// holds anything else.
+    pub fn annotation_number(&self, pod: &Pod, key: &str) -> anyhow::Result<Option<u32>> {
+        container_annotation(pod, self.name(), key)
+            .map(|(key, value)| parse_number_annotation(&key, value))
+            .transpose()
+    }
+
+    /// Get an annotation of the pod for this container that holds text, trimmed, or `None` if
+    /// it is blank.
+    pub fn annotation_text<'a>(&self, pod: &'a Pod, key: &str) -> Option<&'a str> {
+        annotation_text(self.annotation(pod, key)?)
+    }
+
     /// Get arguments of container.
     pub fn args(&self) -> &Option<Vec<String>> {
         &self.0.args
@@ -0,0 +1,438 @@
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Probe as KubeProbe;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use super::Container;
+
+/// How often a probe runs if it doesn't say, which is the Kubernetes default.
+const DEFAULT_PERIOD: Duration = Duration::from_secs(10);
+/// How long a probe may take if it doesn't say, which is the Kubernetes default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many times in a row a probe has to fail to count as failed if it doesn't say, which is
+/// the Kubernetes default.
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// What a probe checks.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeAction {
+    /// Sends a GET request to the path on the port. Any status from 200 to 399 is a success.
+    HttpGet {
+        /// `HTTP` or `HTTPS`.
+        scheme: String,
+        /// The host to connect to, if not the container's.
+        host: Option<String>,
+        /// The port to connect to.
+        port: u16,
+        /// The path to request.
+        path: String,
+        /// Headers to send with the request.
+        headers: Vec<(String, String)>,
+    },
+    /// Opens a TCP connection to the port.
+    TcpSocket {
+        /// The host to connect to, if not the container's.
+        host: Option<String>,
+        /// The port to connect to.
+        port: u16,
+    },
+}
+
+/// A startup or liveness probe of a container.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Probe {
+    /// What the probe checks.
+    pub action: ProbeAction,
+    /// How long after the container starts the probe first runs.
+    pub initial_delay: Duration,
+    /// How often the probe runs.
+    pub period: Duration,
+    /// How long each check may take before it counts as failed.
+    pub timeout: Duration,
+    /// How many checks in a row have to succeed for the probe to succeed.
+    pub success_threshold: u32,
+    /// How many checks in a row have to fail for the probe to fail.
+    pub failure_threshold: u32,
+}
+
+impl Probe {
+    /// Parses a probe of the container, resolving named ports from the container's ports.
+    /// Fails for `exec` probes, as there is nothing to run their commands in.
+    pub fn new(probe: &KubeProbe, container: &Container) -> anyhow::Result<Self> {
+        let action = if let Some(http_get) = &probe.http_get {
+            let path = http_get.path.clone().unwrap_or_default();
+            ProbeAction::HttpGet {
+                scheme: http_get.scheme.clone().unwrap_or_else(|| "HTTP".to_owned()),
+                host: http_get.host.clone().filter(|host| !host.is_empty()),
+                port: resolve_port(&http_get.port, container)?,
+                path: if path.starts_with('/') {
+                    path
+                } else {
+                    format!("/{}", path)
+                },
+                headers: http_get
+                    .http_headers
+                    .iter()
+                    .flatten()
+                    .map(|header| (header.name.clone(), header.value.clone()))
+                    .collect(),
+            }
+        } else if let Some(tcp_socket) = &probe.tcp_socket {
+            ProbeAction::TcpSocket {
+                host: tcp_socket.host.clone().filter(|host| !host.is_empty()),
+                port: resolve_port(&tcp_socket.port, container)?,
+            }
+        } else if probe.exec.is_some() {
+            anyhow::bail!("exec probes are not supported");
+        } else {
+            anyhow::bail!("probe has no action");
+        };
+        let seconds = |value: Option<i32>, default: Duration| {
+            value
+                .filter(|seconds| *seconds > 0)
+                .map(|seconds| Duration::from_secs(seconds as u64))
+                .unwrap_or(default)
+        };
+        let threshold = |value: Option<i32>, default: u32| {
+            value
+                .filter(|count| *count > 0)
+                .map(|count| count as u32)
+                .unwrap_or(default)
+        };
+        Ok(Probe {
+            action,
+            initial_delay: seconds(probe.initial_delay_seconds, Duration::from_secs(0)),
+            period: seconds(probe.period_seconds, DEFAULT_PERIOD),
+            timeout: seconds(probe.timeout_seconds, DEFAULT_TIMEOUT),
+            success_threshold: threshold(probe.success_threshold, 1),
+            failure_threshold: threshold(probe.failure_threshold, DEFAULT_FAILURE_THRESHOLD),
+        })
+    }
+
+    /// Runs the probe's check once against `default_host`, unless the probe names a host.
+    pub async fn check(&self, client: &reqwest::Client, default_host: &str) -> anyhow::Result<()> {
+        let check = async {
+            match &self.action {
+                ProbeAction::HttpGet {
+                    scheme,
+                    host,
+                    port,
+                    path,
+                    headers,
+                } => {
+                    let url = format!(
+                        "{}://{}:{}{}",
+                        scheme.to_lowercase(),
+                        host.as_deref().unwrap_or(default_host),
+                        port,
+                        path
+                    );
+                    let mut request = client.get(&url);
+                    for (name, value) in headers {
+                        request = request.header(name.as_str(), value.as_str());
+                    }
+                    let status = request.send().await?.status();
+                    if status.is_success() || status.is_redirection() {
+                        Ok(())
+                    } else {
+                        Err(anyhow::anyhow!("GET {} returned {}", url, status))
+                    }
+                }
+                ProbeAction::TcpSocket { host, port } => {
+                    let host = host.as_deref().unwrap_or(default_host);
+                    tokio::net::TcpStream::connect((host, *port)).await?;
+                    Ok(())
+                }
+            }
+        };
+        match tokio::time::timeout(self.timeout, check).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!(
+                "timed out after {}s",
+                self.timeout.as_secs()
+            )),
+        }
+    }
+}
+
+fn resolve_port(port: &IntOrString, container: &Container) -> anyhow::Result<u16> {
+    let number = match port {
+        IntOrString::Int(number) => *number,
+        IntOrString::String(name) => container
+            .ports()
+            .iter()
+            .flatten()
+            .find(|port| port.name.as_deref() == Some(name.as_str()))
+            .map(|port| port.container_port)
+            .ok_or_else(|| {
+                anyhow::anyhow!("container {} has no port named {}", container.name(), name)
+            })?,
+    };
+    if number <= 0 || number > u16::MAX as i32 {
+        anyhow::bail!("invalid probe port {}", number);
+    }
+    Ok(number as u16)
+}
+
+/// Counts the results of a probe's checks in a row.
+struct ProbeTracker {
+    success_threshold: u32,
+    failure_threshold: u32,
+    successes: u32,
+    failures: u32,
+}
+
+enum ProbeOutcome {
+    Succeeded,
+    Failed(anyhow::Error),
+}
+
+impl ProbeTracker {
+    fn new(probe: &Probe) -> Self {
+        ProbeTracker {
+            success_threshold: probe.success_threshold,
+            failure_threshold: probe.failure_threshold,
+            successes: 0,
+            failures: 0,
+        }
+    }
+
+    /// Records the result of a check, returning the outcome of the probe once enough checks in a
+    /// row have succeeded or failed.
+    fn record(&mut self, result: anyhow::Result<()>) -> Option<ProbeOutcome> {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes += 1;
+                if self.successes >= self.success_threshold {
+                    return Some(ProbeOutcome::Succeeded);
+                }
+            }
+            Err(e) => {
+                self.successes = 0;
+                self.failures += 1;
+                debug!(error = %e, failures = self.failures, "Probe check failed");
+                if self.failures >= self.failure_threshold {
+                    return Some(ProbeOutcome::Failed(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Runs the startup and liveness probes of a running container, to tell when it has to be
+/// restarted.
+///
+/// As in the Kubernetes kubelet, the liveness probe only starts once the startup probe has
+/// succeeded, so that modules that are slow to initialize aren't restarted before they are
+/// ready to answer it. Readiness probes aren't run.
+pub struct Prober {
+    startup: Option<Probe>,
+    liveness: Option<Probe>,
+    host: String,
+    client: reqwest::Client,
+}
+
+impl Prober {
+    /// Create a prober for the probes of the container, which checks them against `host` unless
+    /// a probe names its own. Probes that can't be run, such as `exec` probes, are logged and
+    /// ignored.
+    pub fn new(container: &Container, host: &str) -> Self {
+        let parse = |kind: &str, probe: Option<&KubeProbe>| {
+            let probe = probe?;
+            match Probe::new(probe, container) {
+                Ok(probe) => Some(probe),
+                Err(e) => {
+                    warn!(error = %e, container_name = container.name(), probe = kind, "Ignoring probe that can't be run");
+                    None
+                }
+            }
+        };
+        Prober {
+            startup: parse("startup", container.startup_probe()),
+            liveness: parse("liveness", container.liveness_probe()),
+            host: host.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Whether the container has any probes to run.
+    pub fn is_empty(&self) -> bool {
+        self.startup.is_none() && self.liveness.is_none()
+    }
+
+    /// Runs the probes of a container that has just started. Returns why the container has to be
+    /// restarted once its startup probe or its liveness probe fails, and never returns for a
+    /// container that stays healthy.
+    pub async fn failed(&self) -> String {
+        let started = Instant::now();
+        if let Some(startup) = &self.startup {
+            if let Err(e) = self.run(startup, started, true).await {
+                return format!("Startup probe failed: {:#}", e);
+            }
+            info!("Startup probe succeeded");
+        }
+        if let Some(liveness) = &self.liveness {
+            if let Err(e) = self.run(liveness, started, false).await {
+                return format!("Liveness probe failed: {:#}", e);
+            }
+        }
+        futures::future::pending().await
+    }
+
+    /// Runs the probe every period, starting its initial delay after the container `started`,
+    /// until it fails, or until it succeeds if `until_success` is set.
+    async fn run(
+        &self,
+        probe: &Probe,
+        started: Instant,
+        until_success: bool,
+    ) -> anyhow::Result<()> {
+        tokio::time::sleep_until(started + probe.initial_delay).await;
+        let mut tracker = ProbeTracker::new(probe);
+        loop {
+            match tracker.record(probe.check(&self.client, &self.host).await) {
+                Some(ProbeOutcome::Succeeded) if until_success => return Ok(()),
+                Some(ProbeOutcome::Failed(e)) => return Err(e),
+                _ => (),
+            }
+            tokio::time::sleep(probe.period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, ContainerPort, ExecAction, HTTPGetAction, TCPSocketAction,
+    };
+
+    fn container(startup: Option<KubeProbe>, liveness: Option<KubeProbe>) -> Container {
+        Container::new(&KubeContainer {
+            name: "app".to_owned(),
+            ports: Some(vec![ContainerPort {
+                name: Some("http".to_owned()),
+                container_port: 8080,
+                ..Default::default()
+            }]),
+            startup_probe: startup,
+            liveness_probe: liveness,
+            ..Default::default()
+        })
+    }
+
+    fn tcp_probe(port: u16, failure_threshold: i32) -> KubeProbe {
+        KubeProbe {
+            tcp_socket: Some(TCPSocketAction {
+                port: IntOrString::Int(port as i32),
+                ..Default::default()
+            }),
+            failure_threshold: Some(failure_threshold),
+            period_seconds: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_http_probe() {
+        let kube_probe = KubeProbe {
+            http_get: Some(HTTPGetAction {
+                path: Some("healthz".to_owned()),
+                port: IntOrString::String("http".to_owned()),
+                ..Default::default()
+            }),
+            initial_delay_seconds: Some(5),
+            ..Default::default()
+        };
+        let probe = Probe::new(&kube_probe, &container(None, None)).unwrap();
+        assert_eq!(
+            ProbeAction::HttpGet {
+                scheme: "HTTP".to_owned(),
+                host: None,
+                port: 8080,
+                path: "/healthz".to_owned(),
+                headers: vec![],
+            },
+            probe.action
+        );
+        assert_eq!(Duration::from_secs(5), probe.initial_delay);
+        assert_eq!(DEFAULT_PERIOD, probe.period);
+        assert_eq!(DEFAULT_TIMEOUT, probe.timeout);
+        assert_eq!(1, probe.success_threshold);
+        assert_eq!(DEFAULT_FAILURE_THRESHOLD, probe.failure_threshold);
+    }
+
+    #[test]
+    fn test_parse_unsupported_probes() {
+        let exec = KubeProbe {
+            exec: Some(ExecAction {
+                command: Some(vec!["true".to_owned()]),
+            }),
+            ..Default::default()
+        };
+        assert!(Probe::new(&exec, &container(None, None)).is_err());
+
+        let unknown_port = KubeProbe {
+            tcp_socket: Some(TCPSocketAction {
+                port: IntOrString::String("grpc".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(Probe::new(&unknown_port, &container(None, None)).is_err());
+
+        // Probes that can't be run are ignored rather than failing the container
+        assert!(Prober::new(&container(Some(exec), None), "127.0.0.1").is_empty());
+    }
+
+    #[test]
+    fn test_tracker_thresholds() {
+        let probe = Probe::new(&tcp_probe(8080, 2), &container(None, None)).unwrap();
+        let mut tracker = ProbeTracker::new(&probe);
+        assert!(tracker.record(Err(anyhow::anyhow!("refused"))).is_none());
+        // A success resets the failures
+        assert!(matches!(
+            tracker.record(Ok(())),
+            Some(ProbeOutcome::Succeeded)
+        ));
+        assert!(tracker.record(Err(anyhow::anyhow!("refused"))).is_none());
+        assert!(matches!(
+            tracker.record(Err(anyhow::anyhow!("refused"))),
+            Some(ProbeOutcome::Failed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_liveness_waits_for_startup() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = {
+            let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap().port()
+        };
+
+        // The startup probe succeeds, so the failing liveness probe restarts the container
+        let prober = Prober::new(
+            &container(
+                Some(tcp_probe(open_port, 1)),
+                Some(tcp_probe(closed_port, 1)),
+            ),
+            "127.0.0.1",
+        );
+        assert!(prober.failed().await.starts_with("Liveness probe failed"));
+
+        // The startup probe never succeeds, so the liveness probe never runs
+        let prober = Prober::new(
+            &container(
+                Some(tcp_probe(closed_port, 2)),
+                Some(tcp_probe(open_port, 1)),
+            ),
+            "127.0.0.1",
+        );
+        assert!(prober.failed().await.starts_with("Startup probe failed"));
+    }
+}
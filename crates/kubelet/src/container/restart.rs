@@ -0,0 +1,110 @@
+use super::ContainerKey;
+use crate::pod::Pod;
+
+/// Whether a container is restarted after it exits, from the `restartPolicy` of its pod.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RestartPolicy {
+    /// Restart the container whenever it exits
+    Always,
+    /// Restart the container only if it exits with an error
+    OnFailure,
+    /// Never restart the container
+    Never,
+}
+
+impl RestartPolicy {
+    /// Parses a restart policy from a Kubernetes RestartPolicy string. A pod without one gets
+    /// the Kubernetes default of `Always`, as do values the API server wouldn't accept.
+    pub fn parse(name: Option<&str>) -> Self {
+        match name {
+            Some("OnFailure") => Self::OnFailure,
+            Some("Never") => Self::Never,
+            _ => Self::Always,
+        }
+    }
+
+    /// Gets the policy that applies to the given container of the pod. As in the Kubernetes
+    /// kubelet, init containers are never restarted after they succeed, so `Always` applies to
+    /// them as `OnFailure`, and ephemeral containers are never restarted at all.
+    pub fn for_container(pod: &Pod, key: &ContainerKey) -> Self {
+        let policy = Self::parse(pod.restart_policy());
+        match key {
+            ContainerKey::App(_) => policy,
+            ContainerKey::Init(_) if policy == Self::Always => Self::OnFailure,
+            ContainerKey::Init(_) => policy,
+            ContainerKey::Ephemeral(_) => Self::Never,
+        }
+    }
+
+    /// Whether a container that exited, with an error if `failed` is set, should be restarted.
+    pub fn should_restart(&self, failed: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::OnFailure => failed,
+            Self::Never => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Pod as KubePod, PodSpec};
+
+    fn pod_with_policy(policy: Option<&str>) -> Pod {
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                restart_policy: policy.map(str::to_owned),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_should_restart() {
+        assert!(RestartPolicy::Always.should_restart(false));
+        assert!(RestartPolicy::Always.should_restart(true));
+        assert!(!RestartPolicy::OnFailure.should_restart(false));
+        assert!(RestartPolicy::OnFailure.should_restart(true));
+        assert!(!RestartPolicy::Never.should_restart(false));
+        assert!(!RestartPolicy::Never.should_restart(true));
+    }
+
+    #[test]
+    fn test_policy_for_container() {
+        let app = ContainerKey::App("app".to_owned());
+        let init = ContainerKey::Init("init".to_owned());
+        let debug = ContainerKey::Ephemeral("debug".to_owned());
+
+        let pod = pod_with_policy(None);
+        assert_eq!(
+            RestartPolicy::Always,
+            RestartPolicy::for_container(&pod, &app)
+        );
+        assert_eq!(
+            RestartPolicy::OnFailure,
+            RestartPolicy::for_container(&pod, &init)
+        );
+        assert_eq!(
+            RestartPolicy::Never,
+            RestartPolicy::for_container(&pod, &debug)
+        );
+
+        let pod = pod_with_policy(Some("Never"));
+        assert_eq!(
+            RestartPolicy::Never,
+            RestartPolicy::for_container(&pod, &app)
+        );
+        assert_eq!(
+            RestartPolicy::Never,
+            RestartPolicy::for_container(&pod, &init)
+        );
+
+        let pod = pod_with_policy(Some("OnFailure"));
+        assert_eq!(
+            RestartPolicy::OnFailure,
+            RestartPolicy::for_container(&pod, &app)
+        );
+    }
+}
@@ -1,13 +1,18 @@
 //! Functions for running Container state machines.
+use crate::backoff::{format_backoff, BackoffStrategy, ExponentialBackoffStrategy};
 use crate::container::{patch_container_status, Status};
-use crate::container::{Container, ContainerKey};
-use crate::pod::Pod;
+use crate::container::{Container, ContainerKey, RestartPolicy};
+use crate::pod::{ManifestChanges, Pod};
+use crate::reason::Reason;
+use crate::state::common::{finish_starting_pod, GenericProviderState};
+use crate::task_group::TaskGroup;
 use chrono::Utc;
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::{Manifest, ObjectState, SharedState, State, Transition};
 use kube::api::Api;
-use tracing::{debug, error, instrument, warn};
+use tokio::sync::watch;
+use tracing::{debug, error, info, instrument, warn};
 use tracing_futures::Instrument;
 
 /// Prelude for Pod state machines.
@@ -16,6 +21,10 @@ pub mod prelude {
     pub use krator::{Manifest, ObjectState, SharedState, State, Transition, TransitionTo};
 }
 
+/// How long a container has to run for the backoff between its restarts to be reset, as in the
+/// Kubernetes kubelet.
+const RESTART_BACKOFF_RESET: std::time::Duration = std::time::Duration::from_secs(600);
+
 /// Iteratively evaluate state machine until it returns Complete.
 #[instrument(
     level = "info", 
@@ -30,6 +39,8 @@ pub mod prelude {
     fields(
         pod_name,
         namespace,
+        pod_uid,
+        node_name,
         container = %container_name
     )
 )]
@@ -44,6 +55,17 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
     let initial_pod = pod.latest();
     let namespace = initial_pod.namespace().to_string();
     let pod_name = initial_pod.name().to_string();
+    // Record the same values that are exposed to the container as environment variables so
+    // that node logs can be correlated with module output
+    let span = tracing::Span::current();
+    span.record("pod_name", &pod_name.as_str());
+    span.record("namespace", &namespace.as_str());
+    if let Some(uid) = initial_pod.as_kube_pod().metadata.uid.as_deref() {
+        span.record("pod_uid", &uid);
+    }
+    if let Some(node_name) = initial_pod.node_name() {
+        span.record("node_name", &node_name);
+    }
     let api: Api<KubePod> = Api::namespaced(client.clone(), &namespace);
 
     let mut state: Box<dyn State<S>> = Box::new(initial_state);
@@ -61,9 +83,17 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
     let (container_tx, container_rx) = Manifest::new(initial_container, pod.store.clone());
     let mut task_pod = pod.clone();
     let task_container_name = container_name.clone();
-    tokio::spawn(
+    let mut changes = ManifestChanges::new(&initial_pod);
+    // The updater is aborted once the state machine has finished, when this is dropped
+    let tasks = TaskGroup::new(format!("container {}", container_name));
+    tasks.spawn(
+        "manifest updater",
         async move {
             while let Some(latest_pod) = task_pod.next().await {
+                // Relisting delivers every pod again, don't wake the container up for nothing
+                if !changes.changed(&latest_pod) {
+                    continue;
+                }
                 let latest_container = match latest_pod.find_container(&task_container_name) {
                     Some(container) => container,
                     None => {
@@ -76,10 +106,11 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                     Ok(()) => (),
                     Err(_) => {
                         debug!("Container update receiver hung up, exiting");
-                        return;
+                        break;
                     }
                 }
             }
+            Ok(())
         }
         .instrument(
             tracing::trace_span!("manifest_updater", %pod_name, %namespace, %container_name),
@@ -140,9 +171,14 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
                         message: format!("Container exited with error: {:?}.", e),
                         failed: true,
                     };
-                    patch_container_status(&api, &latest_pod, &container_name, &status)
-                        .await
-                        .unwrap();
+                    if let Err(e) =
+                        patch_container_status(&api, &latest_pod, &container_name, &status).await
+                    {
+                        warn!(
+                            error = %e,
+                            "Pod container status patch returned error"
+                        );
+                    }
 
                     break result;
                 }
@@ -150,3 +186,97 @@ pub async fn run_to_completion<S: ObjectState<Manifest = Container, Status = Sta
         };
     }
 }
+
+/// Runs the container's state machine to completion, as [`run_to_completion`] does, then runs it
+/// again for as long as the container's [`RestartPolicy`] says it should be restarted. Restarts
+/// back off exponentially, as in the Kubernetes kubelet, and the container is reported as
+/// waiting in `CrashLoopBackOff` meanwhile. `new_run` gives the initial state and the container
+/// state of each run. Returns the result of the last run.
+///
+/// Set `stopped` to true, or drop its sender, before stopping the pod's containers, so that
+/// stopping them isn't mistaken for them exiting. Containers of pods that are being deleted
+/// aren't restarted either.
+pub async fn run_with_restarts<S, I, F>(
+    client: &kube::Client,
+    new_run: F,
+    shared: SharedState<S::SharedState>,
+    pod: Manifest<Pod>,
+    container_name: ContainerKey,
+    mut stopped: watch::Receiver<bool>,
+) -> anyhow::Result<()>
+where
+    S: ObjectState<Manifest = Container, Status = Status>,
+    S::SharedState: GenericProviderState,
+    I: State<S>,
+    F: Fn() -> (I, S),
+{
+    let mut backoff = ExponentialBackoffStrategy::default();
+    loop {
+        let started = std::time::Instant::now();
+        let (initial_state, container_state) = new_run();
+        let result = run_to_completion(
+            client,
+            initial_state,
+            shared.clone(),
+            container_state,
+            pod.clone(),
+            container_name.clone(),
+        )
+        .await;
+
+        let latest_pod = pod.latest();
+        let policy = RestartPolicy::for_container(&latest_pod, &container_name);
+        if is_stopped(&mut stopped)
+            || latest_pod.deletion_timestamp().is_some()
+            || !policy.should_restart(result.is_err())
+        {
+            return result;
+        }
+
+        if started.elapsed() >= RESTART_BACKOFF_RESET {
+            backoff.reset();
+        }
+        let delay = backoff.next_duration();
+        info!(
+            container = %container_name,
+            ?policy,
+            delay = %format_backoff(delay),
+            "Restarting container after it exited"
+        );
+        let api: Api<KubePod> = Api::namespaced(client.clone(), latest_pod.namespace());
+        let status = Status::waiting_with_reason(
+            Reason::CrashLoopBackOff,
+            &format!(
+                "back-off {} restarting container {}",
+                format_backoff(delay),
+                container_name
+            ),
+        );
+        if let Err(e) = patch_container_status(&api, &latest_pod, &container_name, &status).await {
+            warn!(error = %e, "Pod container status patch returned error");
+        }
+        // A container that is backing off, such as an init container that keeps failing, gives
+        // up its pod's place among the starting pods, so that it doesn't hold up other pods
+        finish_starting_pod(&shared, &latest_pod).await;
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            _ = wait_until_stopped(&mut stopped) => return result,
+        }
+    }
+}
+
+/// Whether the containers were stopped, either explicitly or by dropping the sender.
+fn is_stopped(stopped: &mut watch::Receiver<bool>) -> bool {
+    match stopped.changed().now_or_never() {
+        Some(Err(_)) => true,
+        _ => *stopped.borrow(),
+    }
+}
+
+async fn wait_until_stopped(stopped: &mut watch::Receiver<bool>) {
+    while !*stopped.borrow() {
+        if stopped.changed().await.is_err() {
+            return;
+        }
+    }
+}
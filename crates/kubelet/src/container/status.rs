@@ -1,13 +1,23 @@
 use crate::container::{Container, ContainerKey};
 use crate::pod::Pod;
+use crate::reason::Reason;
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
     ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStateWaiting,
     ContainerStatus as KubeContainerStatus, Pod as KubePod,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::error::ErrorResponse;
 use tracing::{debug, instrument, warn};
 
+/// The message of a container that was terminated for running out of memory, which is also
+/// reported as the reason it terminated, as the Kubernetes kubelet does.
+pub const OOM_KILLED: &str = "OOMKilled";
+
+/// The exit code reported for a container killed for running out of memory, that of a process
+/// killed with `SIGKILL`.
+const OOM_KILLED_EXIT_CODE: i32 = 137;
+
 /// Status is a simplified version of the Kubernetes container status
 /// for use in providers. It allows for simple creation of the current status of
 /// a "container" (a running wasm process) without worrying about a bunch of
@@ -19,6 +29,8 @@ pub enum Status {
     Waiting {
         /// The timestamp of when this status was reported
         timestamp: DateTime<Utc>,
+        /// Why it is waiting, as Kubernetes reports it
+        reason: Reason,
         /// A human readable string describing the why it is in a waiting status
         message: String,
     },
@@ -39,10 +51,16 @@ pub enum Status {
 }
 
 impl Status {
-    /// Create `Status::Waiting` from message.
+    /// Create `Status::Waiting` from message, for a container waiting to be created.
     pub fn waiting(message: &str) -> Self {
+        Self::waiting_with_reason(Reason::ContainerCreating, message)
+    }
+
+    /// Create `Status::Waiting` from reason and message.
+    pub fn waiting_with_reason(reason: Reason, message: &str) -> Self {
         Status::Waiting {
             timestamp: Utc::now(),
+            reason,
             message: message.to_string(),
         }
     }
@@ -67,10 +85,12 @@ impl Status {
     pub fn to_kubernetes(&self, container_name: &str) -> KubeContainerStatus {
         let mut state = ContainerState::default();
         match self {
-            Self::Waiting { message, .. } => {
+            Self::Waiting {
+                reason, message, ..
+            } => {
                 state.waiting.replace(ContainerStateWaiting {
+                    reason: Some(reason.to_string()),
                     message: Some(message.clone()),
-                    ..Default::default()
                 });
             }
             Self::Running { timestamp } => {
@@ -83,10 +103,23 @@ impl Status {
                 message,
                 failed,
             } => {
+                let oom_killed = *failed && message == OOM_KILLED;
+                let reason = if oom_killed {
+                    Reason::OOMKilled
+                } else if *failed {
+                    Reason::Error
+                } else {
+                    Reason::Completed
+                };
                 state.terminated.replace(ContainerStateTerminated {
                     finished_at: Some(Time(*timestamp)),
                     message: Some(message.clone()),
-                    exit_code: *failed as i32,
+                    reason: Some(reason.to_string()),
+                    exit_code: if oom_killed {
+                        OOM_KILLED_EXIT_CODE
+                    } else {
+                        *failed as i32
+                    },
                     ..Default::default()
                 });
             }
@@ -110,7 +143,21 @@ impl Status {
     }
 }
 
+/// The number of times a container status patch is rebased onto the latest pod and retried
+/// after conflicting with another update.
+const MAX_STATUS_PATCH_RETRIES: usize = 5;
+
 /// Patch a single container's status
+///
+/// Nothing is patched if the pod already reports the status, apart from when it was reported,
+/// so that containers reporting the same status again don't churn the pod's watchers and a
+/// running container keeps the time it started.
+///
+/// The patch addresses the container's entry in the pod's status by index, so it is built
+/// against the given pod. If the API server rejects it because the pod changed in the meantime
+/// (a 409 conflict, or a 422 if the status list no longer matches), the latest pod is fetched and
+/// the patch is rebuilt against it. If it still fails after a few attempts a warning is logged
+/// and the update is dropped, as a later status update will supersede it anyway.
 #[instrument(level = "info", skip(client, pod, key, status), fields(pod_name = %pod.name(), namespace = %pod.namespace(), container_name = %key))]
 pub async fn patch_container_status(
     client: &kube::Api<KubePod>,
@@ -118,63 +165,192 @@ pub async fn patch_container_status(
     key: &ContainerKey,
     status: &Status,
 ) -> anyhow::Result<()> {
-    match pod.find_container(&key) {
-        Some(container) => {
-            let kube_status = status.to_kubernetes(container.name());
-
-            let patches = match pod.container_status_index(&key) {
-                Some(idx) => {
-                    let path_prefix = if key.is_init() {
-                        format!("/status/initContainerStatuses/{}", idx)
-                    } else {
-                        format!("/status/containerStatuses/{}", idx)
-                    };
-
-                    vec![
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/state", path_prefix),
-                            value: serde_json::json!(kube_status.state.unwrap()),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/ready", path_prefix),
-                            value: serde_json::json!(kube_status.ready),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/started", path_prefix),
-                            value: serde_json::json!(true),
-                        }),
-                    ]
-                }
-                None => {
-                    let path = if key.is_init() {
-                        "/status/initContainerStatuses/-".to_string()
-                    } else {
-                        "/status/containerStatuses/-".to_string()
-                    };
-
-                    vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
-                        path,
-                        value: serde_json::json!(kube_status),
-                    })]
-                }
-            };
-
-            let patch = json_patch::Patch(patches);
-            let params = kube::api::PatchParams::default();
-            debug!(?patch, "Patching container status");
-            client
-                .patch_status(pod.name(), &params, &kube::api::Patch::<()>::Json(patch))
-                .await?;
-            Ok(())
+    let mut latest_pod = None;
+    let mut backoff = std::time::Duration::from_millis(100);
+    for attempt in 1..=MAX_STATUS_PATCH_RETRIES {
+        let target = latest_pod.as_ref().unwrap_or(pod);
+        if status_unchanged(target, key, status) {
+            debug!("Container status unchanged, not patching");
+            return Ok(());
         }
-        None => {
-            warn!(
-                "Container status update for unknown container {}.",
-                key.name()
-            );
-            Ok(())
+        let patch = match container_status_patch(target, key, status) {
+            Some(patch) => patch,
+            None => {
+                warn!(
+                    "Container status update for unknown container {}.",
+                    key.name()
+                );
+                return Ok(());
+            }
+        };
+        let params = kube::api::PatchParams::default();
+        debug!(?patch, attempt, "Patching container status");
+        match client
+            .patch_status(pod.name(), &params, &kube::api::Patch::<()>::Json(patch))
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(kube::Error::Api(ErrorResponse { code, .. })) if code == 409 || code == 422 => {
+                debug!(
+                    code,
+                    attempt, "Container status patch conflicted, rebasing onto latest pod"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                latest_pod = Some(Pod::from(client.get(pod.name()).await?));
+            }
+            Err(e) => return Err(e.into()),
         }
     }
+    warn!(
+        attempts = MAX_STATUS_PATCH_RETRIES,
+        "Giving up on container status patch after repeated conflicts"
+    );
+    Ok(())
+}
+
+/// Whether the pod already reports the status for the container, apart from when it was
+/// reported.
+fn status_unchanged(pod: &Pod, key: &ContainerKey, status: &Status) -> bool {
+    let existing = match pod
+        .container_statuses(key)
+        .and_then(|statuses| statuses.iter().find(|s| s.name == key.name()))
+    {
+        Some(existing) => existing,
+        None => return false,
+    };
+    let new = status.to_kubernetes(key.name());
+    existing.ready == new.ready
+        && existing.started == new.started
+        && without_times(&existing.state) == without_times(&new.state)
+}
+
+/// The container state without the times it started and finished.
+fn without_times(state: &Option<ContainerState>) -> Option<ContainerState> {
+    let mut state = state.clone()?;
+    if let Some(running) = state.running.as_mut() {
+        running.started_at = None;
+    }
+    if let Some(terminated) = state.terminated.as_mut() {
+        terminated.started_at = None;
+        terminated.finished_at = None;
+    }
+    Some(state)
+}
+
+/// Builds the JSON patch setting the container's status in the given pod, or `None` if the pod
+/// has no such container.
+///
+/// The patch tests the names of the statuses it relies on the positions of, so that it fails,
+/// and is rebuilt against the latest pod, if the list changed in the meantime rather than
+/// overwriting the status of another container.
+fn container_status_patch(
+    pod: &Pod,
+    key: &ContainerKey,
+    status: &Status,
+) -> Option<json_patch::Patch> {
+    let container = pod.find_container(key)?;
+    let kube_status = status.to_kubernetes(container.name());
+
+    let list_path = format!("/status/{}", status_field(key));
+    let statuses = pod.container_statuses(key);
+    let patches = match (statuses, pod.container_status_index(key)) {
+        (_, Some(idx)) => {
+            let path_prefix = format!("{}/{}", list_path, idx);
+
+            vec![
+                test_name(&path_prefix, container.name()),
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/state", path_prefix),
+                    value: serde_json::json!(kube_status.state),
+                }),
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/ready", path_prefix),
+                    value: serde_json::json!(kube_status.ready),
+                }),
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/started", path_prefix),
+                    value: serde_json::json!(true),
+                }),
+            ]
+        }
+        // Ephemeral containers are added after the pod's statuses were initialized, so the
+        // first one to report has to create the list
+        (None, None) => {
+            vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
+                path: list_path,
+                value: serde_json::json!([kube_status]),
+            })]
+        }
+        // The status is put in the order of the pod's spec rather than the order the containers
+        // happen to report in
+        (Some(statuses), None) => {
+            let mut patches: Vec<_> = statuses
+                .iter()
+                .enumerate()
+                .map(|(idx, s)| test_name(&format!("{}/{}", list_path, idx), &s.name))
+                .collect();
+            patches.push(json_patch::PatchOperation::Replace(
+                json_patch::ReplaceOperation {
+                    value: serde_json::json!(ordered_statuses(pod, key, statuses, kube_status)),
+                    path: list_path,
+                },
+            ));
+            patches
+        }
+    };
+    Some(json_patch::Patch(patches))
+}
+
+/// A JSON patch operation testing that the container status at `path` is for the named
+/// container.
+fn test_name(path: &str, name: &str) -> json_patch::PatchOperation {
+    json_patch::PatchOperation::Test(json_patch::TestOperation {
+        path: format!("{}/name", path),
+        value: serde_json::json!(name),
+    })
+}
+
+/// The statuses of the containers of the same kind as the key with the container's new status
+/// added, in the order of the pod's spec. Statuses of containers that aren't in the spec are kept
+/// after the others.
+fn ordered_statuses(
+    pod: &Pod,
+    key: &ContainerKey,
+    statuses: &[KubeContainerStatus],
+    new_status: KubeContainerStatus,
+) -> Vec<KubeContainerStatus> {
+    let containers = pod.containers_of_kind(key);
+    let mut new_status = Some(new_status);
+    let mut ordered: Vec<KubeContainerStatus> = containers
+        .iter()
+        .filter_map(|container| {
+            if container.name() == key.name() {
+                new_status.take()
+            } else {
+                statuses
+                    .iter()
+                    .find(|s| s.name == container.name())
+                    .cloned()
+            }
+        })
+        .collect();
+    ordered.extend(
+        statuses
+            .iter()
+            .filter(|s| !containers.iter().any(|c| c.name() == s.name))
+            .cloned(),
+    );
+    ordered
+}
+
+/// The field of the pod status holding the statuses of containers of the same kind as the key.
+fn status_field(key: &ContainerKey) -> &'static str {
+    match key {
+        ContainerKey::Init(_) => "initContainerStatuses",
+        ContainerKey::App(_) => "containerStatuses",
+        ContainerKey::Ephemeral(_) => "ephemeralContainerStatuses",
+    }
 }
 
 /// Create inital container status for registering pod.
@@ -182,7 +358,7 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
     let state = ContainerState {
         waiting: Some(ContainerStateWaiting {
             message: Some("Registered".to_string()),
-            reason: Some("Registered".to_string()),
+            reason: Some(Reason::ContainerCreating.to_string()),
         }),
         ..Default::default()
     };
@@ -194,3 +370,126 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, PodSpec, PodStatus as KubePodStatus,
+    };
+
+    fn terminated(status: &Status) -> ContainerStateTerminated {
+        status
+            .to_kubernetes("app")
+            .state
+            .and_then(|state| state.terminated)
+            .expect("status should be terminated")
+    }
+
+    #[test]
+    fn test_terminated_status_reasons() {
+        let state = terminated(&Status::terminated(OOM_KILLED, true));
+        assert_eq!(Some(OOM_KILLED.to_owned()), state.reason);
+        assert_eq!(137, state.exit_code);
+
+        let state = terminated(&Status::terminated("unable to run module", true));
+        assert_eq!(Some("Error".to_owned()), state.reason);
+        assert_eq!(1, state.exit_code);
+
+        let state = terminated(&Status::terminated("Module run completed", false));
+        assert_eq!(Some("Completed".to_owned()), state.reason);
+        assert_eq!(0, state.exit_code);
+    }
+
+    fn pod(containers: &[&str], statuses: Vec<KubeContainerStatus>) -> Pod {
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                containers: containers
+                    .iter()
+                    .map(|name| KubeContainer {
+                        name: (*name).to_owned(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            status: Some(KubePodStatus {
+                container_statuses: Some(statuses),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn apply(pod: &Pod, key: &ContainerKey, status: &Status) -> Pod {
+        let patch = container_status_patch(pod, key, status).unwrap();
+        let mut doc = serde_json::to_value(pod.as_kube_pod()).unwrap();
+        json_patch::patch(&mut doc, &patch).unwrap();
+        Pod::from(serde_json::from_value::<KubePod>(doc).unwrap())
+    }
+
+    fn status_names(pod: &Pod) -> Vec<String> {
+        pod.container_statuses(&ContainerKey::App("a".to_owned()))
+            .unwrap()
+            .iter()
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_status_patch_keeps_spec_order() {
+        let running = Status::running();
+        let pod = pod(&["a", "b", "c"], vec![running.to_kubernetes("c")]);
+        let key = ContainerKey::App("a".to_owned());
+        // The same status gives the same patch
+        assert_eq!(
+            container_status_patch(&pod, &key, &running),
+            container_status_patch(&pod, &key, &running)
+        );
+
+        let pod = apply(&pod, &key, &running);
+        assert_eq!(vec!["a", "c"], status_names(&pod));
+        let pod = apply(&pod, &ContainerKey::App("b".to_owned()), &running);
+        assert_eq!(vec!["a", "b", "c"], status_names(&pod));
+
+        // Statuses that are already in the list are patched where they are
+        let waiting = Status::waiting("CrashLoopBackOff");
+        let pod = apply(&pod, &ContainerKey::App("c".to_owned()), &waiting);
+        assert_eq!(vec!["a", "b", "c"], status_names(&pod));
+        assert!(status_unchanged(
+            &pod,
+            &ContainerKey::App("c".to_owned()),
+            &waiting
+        ));
+    }
+
+    #[test]
+    fn test_status_patch_fails_if_list_changed() {
+        let running = Status::running();
+        let stale = pod(&["a", "b"], vec![running.to_kubernetes("b")]);
+        let latest = pod(
+            &["a", "b"],
+            vec![running.to_kubernetes("a"), running.to_kubernetes("b")],
+        );
+        let patch =
+            container_status_patch(&stale, &ContainerKey::App("b".to_owned()), &running).unwrap();
+        let mut doc = serde_json::to_value(latest.as_kube_pod()).unwrap();
+        assert!(json_patch::patch(&mut doc, &patch).is_err());
+    }
+
+    #[test]
+    fn test_status_unchanged_ignores_times() {
+        let key = ContainerKey::App("app".to_owned());
+        let earlier = Status::Running {
+            timestamp: Utc::now() - chrono::Duration::minutes(5),
+        };
+        let pod = pod(&["app"], vec![earlier.to_kubernetes("app")]);
+        assert!(status_unchanged(&pod, &key, &Status::running()));
+        assert!(!status_unchanged(&pod, &key, &Status::waiting("Stopping")));
+        assert!(!status_unchanged(
+            &pod,
+            &ContainerKey::App("other".to_owned()),
+            &Status::running()
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+//! Events the Kubelet records about the pods it runs.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, ObjectMeta, PostParams};
+use tracing::warn;
+
+use crate::pod::Pod;
+
+/// The component events are reported by.
+const COMPONENT: &str = "krustlet";
+
+/// The type of an event, as Kubernetes reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventType {
+    /// Something that happened as expected.
+    Normal,
+    /// Something that went wrong, which may need attention.
+    Warning,
+}
+
+impl EventType {
+    /// The type as Kubernetes names it.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventType::Normal => "Normal",
+            EventType::Warning => "Warning",
+        }
+    }
+}
+
+/// Something that happened to a pod, which the Kubelet reports through an [`EventRecorder`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct PodEvent {
+    /// Whether the event is expected or a problem.
+    pub type_: EventType,
+    /// A brief CamelCase reason for the event, e.g. `UnexpectedAdmissionError`.
+    pub reason: String,
+    /// A human readable description of the event.
+    pub message: String,
+}
+
+impl PodEvent {
+    /// A `Warning` event with the given reason and message.
+    pub fn warning(reason: &str, message: &str) -> Self {
+        PodEvent {
+            type_: EventType::Warning,
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// Records the events of pods. The Kubelet creates Kubernetes `Event` objects with
+/// [`KubeEventRecorder`] unless it is given another recorder with
+/// [`KubeletBuilder::event_recorder`](crate::KubeletBuilder::event_recorder), e.g. to send the
+/// events to the embedder's own monitoring instead.
+///
+/// Recording an event must not fail the operation it is about, so recorders handle their own
+/// errors.
+#[async_trait]
+pub trait EventRecorder: Send + Sync {
+    /// Record that the event happened to the pod.
+    async fn record(&self, pod: &Pod, event: PodEvent);
+}
+
+/// Records events as Kubernetes `Event` objects in the namespace of their pod, the way the
+/// Kubernetes kubelet reports them, so they show up in `kubectl describe pod`.
+pub struct KubeEventRecorder {
+    client: kube::Client,
+    node_name: String,
+}
+
+impl KubeEventRecorder {
+    /// Create a recorder reporting events as coming from the Kubelet of the given node.
+    pub fn new(client: kube::Client, node_name: String) -> Self {
+        KubeEventRecorder { client, node_name }
+    }
+}
+
+#[async_trait]
+impl EventRecorder for KubeEventRecorder {
+    async fn record(&self, pod: &Pod, event: PodEvent) {
+        let api: Api<Event> = Api::namespaced(self.client.clone(), pod.namespace());
+        let event = kube_event(pod, event, &self.node_name, Utc::now());
+        if let Err(e) = api.create(&PostParams::default(), &event).await {
+            warn!(error = %e, pod = %pod.name(), "Unable to record pod event");
+        }
+    }
+}
+
+/// The Kubernetes `Event` for an event that happened to the pod at the given time.
+fn kube_event(pod: &Pod, event: PodEvent, node_name: &str, now: DateTime<Utc>) -> Event {
+    Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", pod.name())),
+            namespace: Some(pod.namespace().to_owned()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            name: Some(pod.name().to_owned()),
+            namespace: Some(pod.namespace().to_owned()),
+            uid: pod.as_kube_pod().metadata.uid.clone(),
+            ..Default::default()
+        },
+        type_: Some(event.type_.as_str().to_owned()),
+        reason: Some(event.reason),
+        message: Some(event.message),
+        count: Some(1),
+        first_timestamp: Some(Time(now)),
+        last_timestamp: Some(Time(now)),
+        source: Some(EventSource {
+            component: Some(COMPONENT.to_owned()),
+            host: Some(node_name.to_owned()),
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+
+    #[test]
+    fn test_kube_event() {
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("web".to_owned()),
+                namespace: Some("apps".to_owned()),
+                uid: Some("1234".to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let event = PodEvent::warning("UnexpectedAdmissionError", "Unable to add finalizer");
+        let event = kube_event(&pod, event, "node-1", Utc::now());
+        assert_eq!(Some("web.".to_owned()), event.metadata.generate_name);
+        assert_eq!(Some("apps".to_owned()), event.metadata.namespace);
+        assert_eq!(Some("1234".to_owned()), event.involved_object.uid);
+        assert_eq!(Some("Pod".to_owned()), event.involved_object.kind);
+        assert_eq!(Some("Warning".to_owned()), event.type_);
+        assert_eq!(Some("UnexpectedAdmissionError".to_owned()), event.reason);
+        assert_eq!(
+            Some("node-1".to_owned()),
+            event.source.and_then(|source| source.host)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! `exec` contains the channels that stream the input and output of commands run in containers,
+//! such as for `kubectl exec`.
+use tokio::sync::mpsc;
+
+/// How many chunks of output a provider can send before it waits for the client to receive them.
+const OUTPUT_BUFFER: usize = 16;
+
+/// Client options for running a command in a container.
+/// For more details on what the parameters mean please refer to
+/// https://kubernetes.io/docs/reference/generated/kubectl/kubectl-commands#exec
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    /// The command to run and its arguments.
+    pub command: Vec<String>,
+    /// Whether the client sends input to the command.
+    pub stdin: bool,
+    /// Whether the client receives the standard output of the command.
+    pub stdout: bool,
+    /// Whether the client receives the standard error of the command.
+    pub stderr: bool,
+    /// Whether the client asked for a terminal. With a terminal, standard error is sent as
+    /// standard output.
+    pub tty: bool,
+}
+
+impl Options {
+    /// Parse the options from the query string of an exec request, in which `command` is repeated
+    /// for each argument.
+    pub fn from_query(query: &str) -> Self {
+        let mut opts = Options::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let flag = value == "1" || value == "true";
+            match key.as_ref() {
+                "command" => opts.command.push(value.into_owned()),
+                "input" | "stdin" => opts.stdin = flag,
+                "output" | "stdout" => opts.stdout = flag,
+                "error" | "stderr" => opts.stderr = flag,
+                "tty" => opts.tty = flag,
+                _ => (),
+            }
+        }
+        opts
+    }
+}
+
+/// The client has disconnected, so output can no longer be sent.
+#[derive(Debug, thiserror::Error)]
+#[error("Client has disconnected")]
+pub struct ChannelClosed;
+
+/// Output of a command, on its way to the client.
+#[derive(Debug)]
+pub(crate) enum Output {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+}
+
+/// Sender for streaming the output of a command to the client.
+pub struct Sender {
+    output: mpsc::Sender<Output>,
+    opts: Options,
+}
+
+impl Sender {
+    /// The command to run and its arguments.
+    pub fn command(&self) -> &[String] {
+        &self.opts.command
+    }
+
+    /// Whether the client sends input to the command.
+    pub fn stdin(&self) -> bool {
+        self.opts.stdin
+    }
+
+    /// Whether the client asked for a terminal.
+    pub fn tty(&self) -> bool {
+        self.opts.tty
+    }
+
+    /// Send standard output of the command. Output is dropped if the client did not ask for it.
+    pub async fn send_stdout(&mut self, data: impl Into<Vec<u8>>) -> Result<(), ChannelClosed> {
+        if !self.opts.stdout {
+            return Ok(());
+        }
+        self.output
+            .send(Output::Stdout(data.into()))
+            .await
+            .map_err(|_| ChannelClosed)
+    }
+
+    /// Send standard error of the command. Output is dropped if the client did not ask for it.
+    pub async fn send_stderr(&mut self, data: impl Into<Vec<u8>>) -> Result<(), ChannelClosed> {
+        let output = match (self.opts.tty, self.opts.stderr, self.opts.stdout) {
+            (true, _, true) => Output::Stdout(data.into()),
+            (false, true, _) => Output::Stderr(data.into()),
+            _ => return Ok(()),
+        };
+        self.output.send(output).await.map_err(|_| ChannelClosed)
+    }
+}
+
+/// Receiver for the input the client sends to a command.
+pub struct Receiver {
+    input: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl Receiver {
+    /// Receive the next chunk of input. Returns `None` once the client closed its input, or
+    /// straight away if the client did not ask to send input.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.input.recv().await
+    }
+}
+
+/// The ends of the channels that connect to the client.
+pub(crate) struct Session {
+    pub(crate) output: mpsc::Receiver<Output>,
+    /// `None` once the client closed its input.
+    pub(crate) input: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+/// Create the channels for a command run with the given options.
+pub(crate) fn channel(opts: Options) -> (Sender, Receiver, Session) {
+    let (output_tx, output_rx) = mpsc::channel(OUTPUT_BUFFER);
+    let (input_tx, input_rx) = mpsc::unbounded_channel();
+    let input = if opts.stdin { Some(input_tx) } else { None };
+    (
+        Sender {
+            output: output_tx,
+            opts,
+        },
+        Receiver { input: input_rx },
+        Session {
+            output: output_rx,
+            input,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options_from_query() {
+        let opts = Options::from_query("command=sh&command=-c&command=echo%20hi&stdin=1&tty=true");
+        assert_eq!(opts.command, vec!["sh", "-c", "echo hi"]);
+        assert!(opts.stdin);
+        assert!(opts.tty);
+        assert!(!opts.stdout);
+        assert!(!opts.stderr);
+    }
+
+    #[tokio::test]
+    async fn test_stderr_goes_to_stdout_with_tty() {
+        let (mut sender, _receiver, mut session) = channel(Options {
+            stdout: true,
+            stderr: true,
+            tty: true,
+            ..Default::default()
+        });
+        sender.send_stderr("oops").await.unwrap();
+        drop(sender);
+        match session.output.recv().await {
+            Some(Output::Stdout(data)) => assert_eq!(data, b"oops"),
+            other => panic!("unexpected output {:?}", other),
+        }
+        assert!(session.output.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stdin_closed_without_input() {
+        let (_sender, mut receiver, session) = channel(Options::default());
+        drop(session);
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -0,0 +1,109 @@
+//! `failure_injection` lets tests ask for failures at specific points in a pod's lifecycle
+//! through an annotation. This makes it possible to exercise the backoff and restart paths
+//! deterministically against a real cluster without building deliberately broken images.
+//!
+//! This is only compiled in with the `failure-injection` feature and should never be enabled
+//! in production builds.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::pod::Pod;
+
+/// The annotation used to request failures. The value is a JSON object, for example
+/// `{"imagePullFailures": 2, "crashAfterSeconds": 5}`.
+pub const FAILURE_INJECTION_ANNOTATION: &str = "krustlet.dev/inject-failure";
+
+lazy_static! {
+    // Keyed by pod UID so that a recreated pod with the same name starts from scratch
+    static ref IMAGE_PULL_ATTEMPTS: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+}
+
+/// The failures requested for a pod.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailureInjection {
+    /// The number of times pulling the pod's images should fail before succeeding.
+    #[serde(default)]
+    pub image_pull_failures: u32,
+    /// Crash each container this many seconds after it has started.
+    #[serde(default)]
+    pub crash_after_seconds: Option<u64>,
+}
+
+impl FailureInjection {
+    /// Get the failures requested by the pod's annotation, if any. An annotation that can't be
+    /// parsed is logged and ignored.
+    pub fn from_pod(pod: &Pod) -> Option<Self> {
+        let value = pod.get_annotation(FAILURE_INJECTION_ANNOTATION)?;
+        match serde_json::from_str(value) {
+            Ok(injection) => Some(injection),
+            Err(e) => {
+                warn!(error = %e, pod_name = pod.name(), "Ignoring invalid failure injection annotation");
+                None
+            }
+        }
+    }
+
+    /// How long after starting a container should be crashed, if at all.
+    pub fn crash_after(&self) -> Option<Duration> {
+        self.crash_after_seconds.map(Duration::from_secs)
+    }
+}
+
+/// Returns true if this image pull attempt for the pod should fail. Each call counts as one
+/// attempt.
+pub fn should_fail_image_pull(pod: &Pod) -> bool {
+    let failures = match FailureInjection::from_pod(pod) {
+        Some(injection) => injection.image_pull_failures,
+        None => return false,
+    };
+    let mut attempts = IMAGE_PULL_ATTEMPTS.lock().unwrap();
+    let count = attempts.entry(pod.pod_uid().to_owned()).or_insert(0);
+    *count += 1;
+    *count <= failures
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use kube::api::ObjectMeta;
+
+    fn pod_with_annotation(uid: &str, value: &str) -> Pod {
+        let mut annotations = std::collections::BTreeMap::new();
+        annotations.insert(FAILURE_INJECTION_ANNOTATION.to_owned(), value.to_owned());
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("test".to_owned()),
+                uid: Some(uid.to_owned()),
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_parse_annotation() {
+        let pod = pod_with_annotation("parse", r#"{"crashAfterSeconds": 5}"#);
+        let injection = FailureInjection::from_pod(&pod).unwrap();
+        assert_eq!(0, injection.image_pull_failures);
+        assert_eq!(Some(Duration::from_secs(5)), injection.crash_after());
+
+        let pod = pod_with_annotation("invalid", "not json");
+        assert!(FailureInjection::from_pod(&pod).is_none());
+    }
+
+    #[test]
+    fn test_image_pull_fails_requested_times() {
+        let pod = pod_with_annotation("pull", r#"{"imagePullFailures": 2}"#);
+        assert!(should_fail_image_pull(&pod));
+        assert!(should_fail_image_pull(&pod));
+        assert!(!should_fail_image_pull(&pod));
+    }
+}
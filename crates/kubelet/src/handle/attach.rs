@@ -0,0 +1,13 @@
+use futures::future::BoxFuture;
+
+use crate::exec::{Receiver, Sender};
+
+/// An [`AttachHandler`] connects clients to the standard streams of running processes, such as
+/// for `kubectl attach`.
+pub trait AttachHandler {
+    /// Stream the output of the process to `sender` and the input from `receiver` to the
+    /// process. The returned future finishes once the process has exited or the client has
+    /// disconnected. It must not borrow the handler, so that the process can still be stopped
+    /// while a client is attached.
+    fn attach(&self, sender: Sender, receiver: Receiver) -> BoxFuture<'static, anyhow::Result<()>>;
+}
@@ -0,0 +1,13 @@
+use futures::future::BoxFuture;
+
+use crate::exec::{Receiver, Sender};
+
+/// An [`ExecHandler`] runs commands alongside running processes, such as for `kubectl exec`.
+pub trait ExecHandler {
+    /// Run the command given by `sender`, streaming its output to `sender` and its input from
+    /// `receiver`, and return its exit code. Like [`AttachHandler::attach`], the returned future
+    /// must not borrow the handler, so that the process can still be stopped meanwhile.
+    ///
+    /// [`AttachHandler::attach`]: super::AttachHandler::attach
+    fn exec(&self, sender: Sender, receiver: Receiver) -> BoxFuture<'static, anyhow::Result<i32>>;
+}
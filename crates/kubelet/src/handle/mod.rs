@@ -3,6 +3,10 @@
 //! A collection of handle types for use in providers. These are entirely
 //! optional, but abstract away much of the logic around managing logging,
 //! status updates, and stopping pods
+mod attach;
+mod exec;
 mod stopper;
 
-pub use stopper::StopHandler;
+pub use attach::AttachHandler;
+pub use exec::ExecHandler;
+pub use stopper::{StopHandler, StopOutcome};
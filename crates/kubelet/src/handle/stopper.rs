@@ -1,12 +1,102 @@
+use std::time::Duration;
+
+/// How a process that was asked to stop came to stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopOutcome {
+    /// The process finished within its grace period.
+    Graceful,
+    /// The process was killed after its grace period ran out.
+    Forced,
+}
+
 /// A [`StopHandler`] is used to handle stopping running processes.
 #[async_trait::async_trait]
 pub trait StopHandler {
     /// Calling stop should sends a signal for anything running under the implementor to stop.
     ///
-    /// This is considered an ungraceful stop, and the caller should not wait for the
-    /// underlying handle to complete. Instead they should call wait() to wait for anything running
-    /// to stop.
+    /// Implementors that can let what is running shut down gracefully (e.g. by signalling it)
+    /// should do so here, and only force it to stop in [`kill`](StopHandler::kill). The caller
+    /// should not wait for the underlying handle to complete. Instead they should call wait()
+    /// to wait for anything running to stop.
     async fn stop(&mut self) -> anyhow::Result<()>;
     /// Wait for the implementor to stop anything it considers in the running state.
     async fn wait(&mut self) -> anyhow::Result<()>;
+    /// Force anything still running under the implementor to stop, once it has not stopped
+    /// within its grace period after [`stop`](StopHandler::stop). The default implementation
+    /// calls `stop` again.
+    async fn kill(&mut self) -> anyhow::Result<()> {
+        self.stop().await
+    }
+    /// Stop anything running under the implementor, waiting up to `grace_period` for it to
+    /// finish before killing it, and then for it to stop.
+    async fn stop_with_timeout(&mut self, grace_period: Duration) -> anyhow::Result<StopOutcome> {
+        self.stop().await?;
+        match tokio::time::timeout(grace_period, self.wait()).await {
+            Ok(result) => result.map(|()| StopOutcome::Graceful),
+            Err(_) => {
+                self.kill().await?;
+                self.wait().await?;
+                Ok(StopOutcome::Forced)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Stops once it has been asked to as many times as it takes.
+    struct Stubborn {
+        stops_needed: usize,
+        stops: usize,
+        killed: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl StopHandler for Stubborn {
+        async fn stop(&mut self) -> anyhow::Result<()> {
+            self.stops += 1;
+            Ok(())
+        }
+
+        async fn wait(&mut self) -> anyhow::Result<()> {
+            if self.stops < self.stops_needed {
+                futures::future::pending::<()>().await;
+            }
+            Ok(())
+        }
+
+        async fn kill(&mut self) -> anyhow::Result<()> {
+            self.killed = true;
+            self.stop().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_with_timeout() {
+        let mut graceful = Stubborn {
+            stops_needed: 1,
+            stops: 0,
+            killed: false,
+        };
+        let outcome = graceful
+            .stop_with_timeout(Duration::from_secs(30))
+            .await
+            .unwrap();
+        assert_eq!(StopOutcome::Graceful, outcome);
+        assert!(!graceful.killed);
+
+        let mut forced = Stubborn {
+            stops_needed: 2,
+            stops: 0,
+            killed: false,
+        };
+        let outcome = forced
+            .stop_with_timeout(Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(StopOutcome::Forced, outcome);
+        assert!(forced.killed);
+    }
 }
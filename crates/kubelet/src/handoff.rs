@@ -0,0 +1,302 @@
+//! `handoff` lets a new Kubelet process take over a node from a running one, e.g. when upgrading
+//! the provider, without the node's pods being evicted and rescheduled elsewhere.
+//!
+//! The running Kubelet listens on a UNIX socket in its data directory. A new Kubelet starting
+//! with the same data directory connects to it before registering the node and asks for a
+//! handoff. The running Kubelet replies with a [`Checkpoint`] of the pods it is running, then
+//! shuts down *without* draining the node, and removes the socket once it has stopped. The new
+//! Kubelet waits for that before it starts managing the node's pods, so the two processes never
+//! act on the same pods at the same time. If the running Kubelet doesn't stop in time, the new
+//! one gives up rather than manage the node alongside it.
+//!
+//! The new Kubelet gives the checkpoint to its provider, which may adopt the workloads of the
+//! pods in it (see [`Provider::adopt_pods`](crate::provider::Provider::adopt_pods)), and then
+//! picks the pods up from the API server as usual. What the handoff avoids is the pods being
+//! deleted and scheduled onto other nodes.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+use crate::pod::{Pod, PodKey};
+
+/// The name of the handoff socket in the Kubelet's data directory.
+pub const HANDOFF_SOCKET_NAME: &str = "handoff.sock";
+
+/// How long a new Kubelet waits for the running one to stop after handing off the node.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request to take over the node, sent by the new Kubelet.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HandoffRequest {
+    node_name: String,
+}
+
+/// The state handed from the running Kubelet to the one taking over the node.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    /// The name of the node being handed off.
+    pub node_name: String,
+    /// The pods that were running on the node at the time of the handoff.
+    pub pods: Vec<PodCheckpoint>,
+}
+
+/// A pod that was running on the node at the time of the handoff.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodCheckpoint {
+    /// The pod's name.
+    pub name: String,
+    /// The pod's namespace.
+    pub namespace: String,
+    /// The pod's uid, used to tell whether it is still the same pod after the handoff.
+    pub uid: Option<String>,
+    /// The pod's phase at the time of the handoff.
+    pub phase: Option<String>,
+}
+
+impl PodCheckpoint {
+    /// The key of the checkpointed pod.
+    pub fn key(&self) -> PodKey {
+        let key = PodKey::new(&self.namespace, &self.name);
+        match &self.uid {
+            Some(uid) => key.with_uid(uid),
+            None => key,
+        }
+    }
+}
+
+impl From<&Pod> for PodCheckpoint {
+    fn from(pod: &Pod) -> Self {
+        PodCheckpoint {
+            name: pod.name().to_owned(),
+            namespace: pod.namespace().to_owned(),
+            uid: pod.as_kube_pod().metadata.uid.clone(),
+            phase: pod
+                .as_kube_pod()
+                .status
+                .as_ref()
+                .and_then(|s| s.phase.clone()),
+        }
+    }
+}
+
+/// Returns the path of the handoff socket for the given data directory.
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(HANDOFF_SOCKET_NAME)
+}
+
+/// Asks a Kubelet already running with the given socket to hand off the node, and waits for it
+/// to stop.
+///
+/// Returns `None` if no Kubelet is running. A stale socket left behind by a Kubelet that did not
+/// shut down cleanly is removed. Fails if the running Kubelet does not stop within a minute of
+/// handing off the node, as it may still be managing its pods.
+pub async fn request_handoff(path: &Path, node_name: &str) -> anyhow::Result<Option<Checkpoint>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!(error = %e, path = %path.display(), "Removing stale handoff socket");
+            tokio::fs::remove_file(path).await?;
+            return Ok(None);
+        }
+    };
+    info!("Another Kubelet is running for this node, requesting handoff");
+
+    let (read, mut write) = stream.into_split();
+    let mut request = serde_json::to_vec(&HandoffRequest {
+        node_name: node_name.to_owned(),
+    })?;
+    request.push(b'\n');
+    write.write_all(&request).await?;
+
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    if line.is_empty() {
+        anyhow::bail!("running Kubelet closed the handoff connection without a checkpoint");
+    }
+    let checkpoint: Checkpoint = serde_json::from_str(&line)?;
+    if checkpoint.node_name != node_name {
+        anyhow::bail!(
+            "running Kubelet is managing node {}, not {}",
+            checkpoint.node_name,
+            node_name
+        );
+    }
+    info!(
+        num_pods = checkpoint.pods.len(),
+        "Received handoff checkpoint, waiting for running Kubelet to stop"
+    );
+
+    // The running Kubelet removes the socket once it has stopped managing pods.
+    let wait = async {
+        while path.exists() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    };
+    if tokio::time::timeout(HANDOFF_TIMEOUT, wait).await.is_err() {
+        anyhow::bail!(
+            "running Kubelet did not stop within {}s of handing off the node",
+            HANDOFF_TIMEOUT.as_secs()
+        );
+    }
+    Ok(Some(checkpoint))
+}
+
+/// The handoff socket of a running Kubelet, on which it listens for handoff requests from a new
+/// Kubelet.
+///
+/// The socket is removed when this is dropped, which the Kubelet does once it has stopped
+/// managing pods, whether it stopped cleanly or not.
+pub(crate) struct HandoffListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl HandoffListener {
+    /// Listen on the socket at the given path.
+    pub(crate) fn bind(path: &Path) -> anyhow::Result<Self> {
+        Ok(HandoffListener {
+            listener: UnixListener::bind(path)?,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Serve handoff requests until one for this node succeeds. The node's pods are then
+    /// checkpointed and sent back, and this returns so that this Kubelet can shut down without
+    /// draining the node. Failed requests and connections are logged and don't stop serving.
+    pub(crate) async fn serve(&self, client: kube::Client, node_name: &str) {
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = %e, "Unable to accept handoff connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            match handle_request(stream, &client, node_name).await {
+                Ok(()) => {
+                    info!("Handed off node to new Kubelet");
+                    return;
+                }
+                Err(e) => warn!(error = %e, "Failed to hand off node"),
+            }
+        }
+    }
+}
+
+impl Drop for HandoffListener {
+    fn drop(&mut self) {
+        // A Kubelet taking over the node waits for the socket to be removed before it starts
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(error = %e, "Failed to remove handoff socket");
+        }
+    }
+}
+
+async fn handle_request(
+    stream: UnixStream,
+    client: &kube::Client,
+    node_name: &str,
+) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(read).read_line(&mut line).await?;
+    let request: HandoffRequest = serde_json::from_str(&line)?;
+    if request.node_name != node_name {
+        anyhow::bail!(
+            "handoff requested for node {}, but this Kubelet manages {}",
+            request.node_name,
+            node_name
+        );
+    }
+
+    let mut response = serde_json::to_vec(&checkpoint(client, node_name).await?)?;
+    response.push(b'\n');
+    write.write_all(&response).await?;
+    Ok(())
+}
+
+/// Builds a checkpoint of the pods currently scheduled to the node.
+async fn checkpoint(client: &kube::Client, node_name: &str) -> anyhow::Result<Checkpoint> {
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let pods = api.list(&params).await?;
+    Ok(Checkpoint {
+        node_name: node_name.to_owned(),
+        pods: pods
+            .items
+            .into_iter()
+            .map(|p| PodCheckpoint::from(&Pod::from(p)))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn test_request_handoff_without_running_kubelet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(dir.path());
+        assert_eq!(None, request_handoff(&path, "node").await.unwrap());
+
+        // A socket nobody listens on is stale and gets cleaned up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        assert_eq!(None, request_handoff(&path, "node").await.unwrap());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_handoff_listener_removes_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = socket_path(dir.path());
+        let listener = HandoffListener::bind(&path).unwrap();
+        assert!(path.exists());
+
+        // Connections that aren't handoff requests don't stop the listener
+        let client =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:8080".parse().unwrap()))
+                .unwrap();
+        drop(UnixStream::connect(&path).await.unwrap());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), listener.serve(client, "node"))
+                .await
+                .is_err()
+        );
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let checkpoint = Checkpoint {
+            node_name: "node".to_owned(),
+            pods: vec![PodCheckpoint {
+                name: "foo".to_owned(),
+                namespace: "bar".to_owned(),
+                uid: Some("1234".to_owned()),
+                phase: Some("Running".to_owned()),
+            }],
+        };
+        let json = serde_json::to_string(&checkpoint).unwrap();
+        assert!(json.contains("\"nodeName\":\"node\""));
+        assert_eq!(checkpoint, serde_json::from_str(&json).unwrap());
+        assert_eq!(PodKey::new("bar", "foo"), checkpoint.pods[0].key());
+    }
+}
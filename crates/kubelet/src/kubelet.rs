@@ -1,20 +1,23 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
 use crate::config::Config;
-use crate::node;
+use crate::event::{EventRecorder, KubeEventRecorder};
+use crate::metrics::MetricsRegistry;
+use crate::node::{self, ConditionState, NodeConditions};
 use crate::operator::PodOperator;
 use crate::plugin_watcher::PluginRegistry;
 use crate::provider::{DevicePluginSupport, PluginSupport, Provider};
 use crate::resources::device_plugin_manager::{serve_device_registry, DeviceManager};
+use crate::task_group::TaskGroup;
 use crate::webserver::start as start_webserver;
+use crate::webserver::start_read_only as start_read_only_webserver;
 
-use futures::future::{FutureExt, TryFutureExt};
+use futures::future::FutureExt;
 use kube::api::ListParams;
 use std::convert::TryFrom;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::signal::ctrl_c;
-use tokio::task;
 use tracing::{error, info, warn};
 
 use krator::{ControllerBuilder, Manager};
@@ -35,6 +38,12 @@ pub struct Kubelet<P> {
     provider: Arc<P>,
     kube_config: kube::Config,
     config: Box<Config>,
+    register_node: bool,
+    serve_api: bool,
+    pod_list_params: ListParams,
+    node_conditions: NodeConditions,
+    event_recorder: Option<Arc<dyn EventRecorder>>,
+    metrics: MetricsRegistry,
 }
 
 impl<P: Provider> Kubelet<P> {
@@ -45,13 +54,37 @@ impl<P: Provider> Kubelet<P> {
         kube_config: kube::Config,
         config: Config,
     ) -> anyhow::Result<Self> {
-        Ok(Self {
-            provider: Arc::new(provider),
+        Ok(Self::builder(provider, kube_config, config).build())
+    }
+
+    /// Create a [`KubeletBuilder`] for a Kubelet with a provider, a kubernetes
+    /// configuration, and a kubelet configuration, to choose which of its
+    /// subsystems are started.
+    pub fn builder(provider: P, kube_config: kube::Config, config: Config) -> KubeletBuilder<P> {
+        KubeletBuilder {
+            provider,
             kube_config,
-            // The config object can get a little bit for some reason, so put it
-            // on the heap
-            config: Box::new(config),
-        })
+            config,
+            register_node: true,
+            serve_api: true,
+            pod_list_params: ListParams::default(),
+            node_conditions: NodeConditions::default(),
+            event_recorder: None,
+            metrics: MetricsRegistry::default(),
+        }
+    }
+
+    /// The custom conditions reported in the node status. Conditions registered on the
+    /// returned value are reported from the next status update on, even while the Kubelet is
+    /// running.
+    pub fn node_conditions(&self) -> NodeConditions {
+        self.node_conditions.clone()
+    }
+
+    /// The metrics served at `/metrics`. Sources registered on the returned value are served
+    /// from the next scrape on, even while the Kubelet is running.
+    pub fn metrics_registry(&self) -> MetricsRegistry {
+        self.metrics.clone()
     }
 
     /// Begin answering requests for the Kubelet.
@@ -61,8 +94,47 @@ impl<P: Provider> Kubelet<P> {
     pub async fn start(&self) -> anyhow::Result<()> {
         let client = kube::Client::try_from(self.kube_config.clone())?;
 
+        // If another Kubelet is running for this node, take over from it before touching the node
+        #[cfg(target_family = "unix")]
+        let handoff_socket = crate::handoff::socket_path(&self.config.data_dir);
+        #[cfg(target_family = "unix")]
+        if let Some(checkpoint) =
+            crate::handoff::request_handoff(&handoff_socket, &self.config.node_name).await?
+        {
+            info!(
+                num_pods = checkpoint.pods.len(),
+                "Took over node from previous Kubelet"
+            );
+            self.provider.adopt_pods(&checkpoint).await?;
+        }
+        // Listen for a later Kubelet asking to take over the node in turn. The socket is removed
+        // when this is dropped, however this function returns.
+        #[cfg(target_family = "unix")]
+        let handoff_listener = match crate::handoff::HandoffListener::bind(&handoff_socket) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                warn!(error = %e, "Unable to listen for handoff requests");
+                None
+            }
+        };
+
+        // Providers that are still warming caches or starting hosts hold up registering the
+        // node, so that it only becomes Ready once it can run pods
+        info!("Waiting for provider to be ready");
+        self.provider.ready().await?;
+
         // Create the node. If it already exists, this will exit
-        node::create(&client, &self.config, self.provider.clone()).await;
+        if self.register_node {
+            node::create(&client, &self.config, self.provider.clone()).await;
+        }
+
+        // Background tasks that run for as long as the Kubelet does
+        let tasks = TaskGroup::new("kubelet");
+        // Measure how responsive the runtime is for the metrics endpoint
+        tasks.spawn("runtime probe", async {
+            crate::metrics::probe_runtime().await;
+            Ok(())
+        });
 
         // Flag to indicate graceful shutdown has started.
         let signal = Arc::new(AtomicBool::new(false));
@@ -89,25 +161,84 @@ impl<P: Provider> Kubelet<P> {
         .boxed();
 
         // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config)
+        let webserver = if self.serve_api {
+            start_webserver(
+                self.provider.clone(),
+                self.config.node_name.clone(),
+                &self.config.server_config,
+                self.metrics.clone(),
+            )
+            .fuse()
+            .boxed()
+        } else {
+            futures::future::pending().boxed()
+        };
+        let read_only_webserver = match self.config.server_config.read_only_port {
+            Some(port) if self.serve_api => start_read_only_webserver(
+                client.clone(),
+                self.config.node_name.clone(),
+                self.config.server_config.addr,
+                port,
+                self.metrics.clone(),
+            )
             .fuse()
-            .boxed();
+            .boxed(),
+            _ => futures::future::pending().boxed(),
+        };
 
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
+        let node_updater = if self.register_node {
+            start_node_updater(
+                client.clone(),
+                self.config.node_name.clone(),
+                self.provider.clone(),
+                self.node_conditions.clone(),
+            )
             .fuse()
-            .boxed();
+            .boxed()
+        } else {
+            futures::future::pending().boxed()
+        };
+
+        // Drain the node when an external agent asks for it through its annotations
+        let shutdown_watcher = if self.register_node {
+            node::watch_shutdown_requests(client.clone(), self.config.node_name.clone())
+                .fuse()
+                .boxed()
+        } else {
+            futures::future::pending().boxed()
+        };
+
+        // Listen for a new Kubelet asking to take over the node
+        #[cfg(target_family = "unix")]
+        let handoff_task = start_handoff_listener(
+            handoff_listener.as_ref(),
+            client.clone(),
+            &self.config.node_name,
+        )
+        .fuse()
+        .boxed();
+        #[cfg(not(target_family = "unix"))]
+        let handoff_task = futures::future::pending::<()>().boxed();
+
+        // Set when the node has been handed off, in which case it must not be drained on shutdown.
+        let handed_off = Arc::new(AtomicBool::new(false));
 
         // If any of these tasks fail, we can initiate graceful shutdown.
         let services = Box::pin(async {
             tokio::select! {
+                _ = handoff_task => handed_off.store(true, Ordering::Relaxed),
                 res = signal_task => if let Err(e) = res {
                     error!(error = %e, "Signal task completed with error");
                 },
                 res = webserver => error!(result = ?res, "Webserver task completed with result"),
+                res = read_only_webserver => error!(result = ?res, "Read-only webserver task completed with result"),
                 res = node_updater => if let Err(e) = res {
                     error!(error = %e, "Node updater task completed with error");
                 },
+                res = shutdown_watcher => if let Err(e) = res {
+                    error!(error = %e, "Shutdown watcher task completed with error");
+                },
                 res = plugin_registrar => if let Err(e) = res {
                     error!(error = %e, "Plugin registrar task completed with error");
                 },
@@ -123,12 +254,19 @@ impl<P: Provider> Kubelet<P> {
         // Periodically checks for shutdown signal and cleans up resources gracefully if caught.
         let signal_handler = start_signal_handler(Arc::clone(&signal)).fuse().boxed();
 
-        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone());
-        let node_selector = format!("spec.nodeName={}", &self.config.node_name);
-        let params = ListParams {
-            field_selector: Some(node_selector),
-            ..Default::default()
-        };
+        let event_recorder = self.event_recorder.clone().unwrap_or_else(|| {
+            Arc::new(KubeEventRecorder::new(
+                client.clone(),
+                self.config.node_name.clone(),
+            ))
+        });
+        let operator = PodOperator::new(Arc::clone(&self.provider), client.clone(), event_recorder);
+        // Release deleted pods whose finalizer nothing else will remove
+        tasks.spawn(
+            "orphaned pod finalizers",
+            operator.release_orphaned_pods(self.config.node_name.clone()),
+        );
+        let params = pod_list_params(&self.config.node_name, &self.pod_list_params);
 
         let controller_builder = ControllerBuilder::new(operator).with_params(params);
         let mut manager = Manager::new(&self.kube_config);
@@ -139,6 +277,10 @@ impl<P: Provider> Kubelet<P> {
         let core = Box::pin(async {
             tokio::select! {
                 res = signal_handler => match res {
+                    Ok(()) if handed_off.load(Ordering::Relaxed) => {
+                        info!("Node was handed off to a new Kubelet, shutting down without draining");
+                        Ok(())
+                    }
                     Ok(()) => self.provider.shutdown(&self.config.node_name).await,
                     Err(e) => {
                         error!(error = %e, "Signal handler task joined with error");
@@ -156,6 +298,7 @@ impl<P: Provider> Kubelet<P> {
         // return an error. Services will return if signal is set because pod_informer will drop
         // error_sender and error_handler will exit.
         tokio::try_join!(core, services)?;
+        tasks.abort();
         Ok(())
     }
 }
@@ -168,10 +311,118 @@ impl<P> Clone for Kubelet<P> {
             provider: self.provider.clone(),
             kube_config: self.kube_config.clone(),
             config: self.config.clone(),
+            register_node: self.register_node,
+            serve_api: self.serve_api,
+            pod_list_params: self.pod_list_params.clone(),
+            node_conditions: self.node_conditions.clone(),
+            event_recorder: self.event_recorder.clone(),
+            metrics: self.metrics.clone(),
         }
     }
 }
 
+/// A builder for a [`Kubelet`] that controls which of its subsystems are
+/// started, e.g. for tests or for embedding a Kubelet in another process.
+///
+/// By default everything is started, which is what [`Kubelet::new`] does.
+pub struct KubeletBuilder<P> {
+    provider: P,
+    kube_config: kube::Config,
+    config: Config,
+    register_node: bool,
+    serve_api: bool,
+    pod_list_params: ListParams,
+    node_conditions: NodeConditions,
+    event_recorder: Option<Arc<dyn EventRecorder>>,
+    metrics: MetricsRegistry,
+}
+
+impl<P: Provider> KubeletBuilder<P> {
+    /// Whether to create the node and keep its lease and status up to date.
+    /// If this is disabled, the node must be registered by someone else.
+    pub fn register_node(mut self, register_node: bool) -> Self {
+        self.register_node = register_node;
+        self
+    }
+
+    /// Whether to start the Kubelet HTTPS server serving logs, exec, and
+    /// metrics.
+    pub fn serve_api(mut self, serve_api: bool) -> Self {
+        self.serve_api = serve_api;
+        self
+    }
+
+    /// Parameters for listing and watching the pods scheduled to the node,
+    /// e.g. a label selector to only handle some of them. The Kubelet always
+    /// selects its own node's pods and asks for bookmarks on top of these.
+    pub fn pod_list_params(mut self, params: ListParams) -> Self {
+        self.pod_list_params = params;
+        self
+    }
+
+    /// Report a custom condition in the node status, e.g. `GPUAttached`, with the state
+    /// returned by the supplier each time the status is updated. Fails like
+    /// [`NodeConditions::register`]; see [`Kubelet::node_conditions`] for registering
+    /// conditions later.
+    pub fn node_condition<F>(self, type_: &str, supplier: F) -> anyhow::Result<Self>
+    where
+        F: Fn() -> ConditionState + Send + Sync + 'static,
+    {
+        self.node_conditions.register(type_, supplier)?;
+        Ok(self)
+    }
+
+    /// Record the events of pods with the given recorder rather than as Kubernetes `Event`
+    /// objects, e.g. for tests or to send them to the embedder's own monitoring.
+    pub fn event_recorder(mut self, recorder: impl EventRecorder + 'static) -> Self {
+        self.event_recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Serve the metrics of the given registry at `/metrics`, so that the embedder can add
+    /// its own metrics to the Kubelet's; see [`Kubelet::metrics_registry`] for adding them
+    /// later.
+    pub fn metrics_registry(mut self, registry: MetricsRegistry) -> Self {
+        self.metrics = registry;
+        self
+    }
+
+    /// Create the Kubelet.
+    pub fn build(self) -> Kubelet<P> {
+        Kubelet {
+            provider: Arc::new(self.provider),
+            kube_config: self.kube_config,
+            // The config object can get a little bit for some reason, so put it
+            // on the heap
+            config: Box::new(self.config),
+            register_node: self.register_node,
+            serve_api: self.serve_api,
+            pod_list_params: self.pod_list_params,
+            node_conditions: self.node_conditions,
+            event_recorder: self.event_recorder,
+            metrics: self.metrics,
+        }
+    }
+}
+
+/// The parameters for watching the pods of the node, on top of the given ones.
+fn pod_list_params(node_name: &str, params: &ListParams) -> ListParams {
+    let node_selector = format!("spec.nodeName={}", node_name);
+    let field_selector = match &params.field_selector {
+        Some(selector) if !selector.is_empty() => format!("{},{}", node_selector, selector),
+        _ => node_selector,
+    };
+    // The underlying watcher tracks the last seen resourceVersion (including the ones
+    // delivered by bookmark events) and only falls back to a full relist when the API server
+    // responds with 410 Gone, so asking for bookmarks keeps that version fresh on busy
+    // clusters where our pods see few events.
+    ListParams {
+        field_selector: Some(field_selector),
+        allow_bookmarks: true,
+        ..params.clone()
+    }
+}
+
 /// Awaits SIGINT and sets graceful shutdown flag if detected.
 async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
     ctrl_c().await?;
@@ -184,16 +435,7 @@ async fn start_plugin_registry(registrar: Option<Arc<PluginRegistry>>) -> anyhow
     match registrar {
         Some(r) => r.run().await,
         // Do nothing; just poll forever and "pretend" that a plugin watcher is running
-        None => {
-            task::spawn(async {
-                loop {
-                    // We run a delay here so we don't waste time on NOOP CPU cycles
-                    tokio::time::sleep(tokio::time::Duration::from_secs(std::u64::MAX)).await;
-                }
-            })
-            .map_err(anyhow::Error::from)
-            .await
-        }
+        None => futures::future::pending().await,
     }
 }
 
@@ -202,24 +444,34 @@ async fn start_device_manager(device_manager: Option<Arc<DeviceManager>>) -> any
     match device_manager {
         Some(dm) => serve_device_registry(dm).await,
         // Do nothing; just poll forever and "pretend" that a DeviceManager is running
-        None => {
-            task::spawn(async {
-                loop {
-                    // We run a delay here so we don't waste time on NOOP CPU cycles
-                    tokio::time::sleep(tokio::time::Duration::from_secs(std::u64::MAX)).await;
-                }
-            })
-            .map_err(anyhow::Error::from)
-            .await
-        }
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for a new Kubelet to take over the node. Returns once the node has been handed off.
+#[cfg(target_family = "unix")]
+async fn start_handoff_listener(
+    listener: Option<&crate::handoff::HandoffListener>,
+    client: kube::Client,
+    node_name: &str,
+) {
+    match listener {
+        Some(listener) => listener.serve(client, node_name).await,
+        // The node cannot be handed off, so just poll forever
+        None => futures::future::pending().await,
     }
 }
 
 /// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
+async fn start_node_updater<P: Provider>(
+    client: kube::Client,
+    node_name: String,
+    provider: Arc<P>,
+    conditions: NodeConditions,
+) -> anyhow::Result<()> {
     let sleep_interval = std::time::Duration::from_secs(10);
     loop {
-        node::update(&client, &node_name).await;
+        node::update(&client, &node_name, &*provider, &conditions).await;
         tokio::time::sleep(sleep_interval).await;
     }
 }
@@ -325,6 +577,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_pod_list_params() {
+        let params = pod_list_params("node", &ListParams::default());
+        assert_eq!(Some("spec.nodeName=node".to_owned()), params.field_selector);
+        assert!(params.allow_bookmarks);
+
+        let params = pod_list_params(
+            "node",
+            &ListParams::default()
+                .labels("app=foo")
+                .fields("metadata.namespace=bar"),
+        );
+        assert_eq!(
+            Some("spec.nodeName=node,metadata.namespace=bar".to_owned()),
+            params.field_selector
+        );
+        assert_eq!(Some("app=foo".to_owned()), params.label_selector);
+    }
+
     #[tokio::test]
     async fn test_env_vars() {
         let container = Container::new(&KubeContainer {
@@ -451,5 +722,12 @@ mod test {
         );
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
+        assert_eq!("my-name", env.get("POD_NAME").expect("pod name").as_str());
+        assert_eq!(
+            "my-namespace",
+            env.get("POD_NAMESPACE").expect("pod namespace").as_str()
+        );
+        assert_eq!("", env.get("CONTAINER_NAME").expect("container name"));
+        assert!(env.get("POD_UID").is_none());
     }
 }
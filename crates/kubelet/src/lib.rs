@@ -110,22 +110,37 @@ pub(crate) mod grpc_sock;
 #[allow(dead_code, clippy::all)]
 pub(crate) mod mio_uds_windows;
 
+pub mod attestation;
 pub mod backoff;
+#[cfg(any(feature = "bench", feature = "docs"))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "bench")))]
+pub mod bench;
 pub mod config;
 pub mod container;
+pub mod event;
+pub mod exec;
+#[cfg(any(feature = "failure-injection", feature = "docs"))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "failure-injection")))]
+pub mod failure_injection;
 pub mod handle;
+#[cfg(target_family = "unix")]
+pub mod handoff;
 pub mod log;
+pub mod metrics;
 pub mod node;
 pub mod plugin_watcher;
 pub mod pod;
 pub mod provider;
+pub mod reason;
 pub mod resources;
+pub mod runtime_class;
 pub mod secret;
 pub mod state;
 pub mod store;
+pub mod task_group;
 pub mod volume;
 
-pub use self::kubelet::Kubelet;
+pub use self::kubelet::{Kubelet, KubeletBuilder};
 pub use bootstrapping::bootstrap;
 
 #[cfg(feature = "derive")]
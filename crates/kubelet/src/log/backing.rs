@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+
+use super::{HandleFactory, LogIndex};
+
+/// How often the end of a [`MemoryLog`] is recorded in its index while it is written.
+const INDEX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A reader of a log opened by a [`LogBacking`].
+pub type LogReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Where the output of a container is kept, so that
+/// [`container::Handle`](crate::container::Handle) can serve it to `kubectl logs` with the same
+/// `tailLines`, `sinceTime`, `limitBytes`, `timestamps` and `follow` support, whether it is kept
+/// in a file, in memory or by some other service.
+///
+/// Every [`HandleFactory`] of files is a log backing. [`MemoryLog`] keeps the end of the output
+/// in memory instead.
+#[async_trait::async_trait]
+pub trait LogBacking: Send + Sync {
+    /// Open the log for reading from `offset` bytes into it. Once the reader reaches the end of
+    /// the log, it reads nothing until more output is written, like a file that is still being
+    /// written, so that followed logs can be read again for new output.
+    async fn open(&self, offset: u64) -> std::io::Result<LogReader>;
+
+    /// The index of when the output in the log was written, if the backing keeps one. Without
+    /// an index, requests for the logs since a point in time get the whole log.
+    fn log_index(&self) -> Option<LogIndex> {
+        None
+    }
+
+    /// The path of the log file, if the log is a file. Followed logs are watched for changes
+    /// instead of being checked for new output periodically when this is known.
+    fn log_path(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl<F: HandleFactory<tokio::fs::File>> LogBacking for F {
+    async fn open(&self, offset: u64) -> std::io::Result<LogReader> {
+        let mut file = self.new_handle();
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    fn log_index(&self) -> Option<LogIndex> {
+        HandleFactory::log_index(self)
+    }
+
+    fn log_path(&self) -> Option<PathBuf> {
+        HandleFactory::log_path(self)
+    }
+}
+
+/// A log kept in memory, for providers whose output doesn't go to a file. Only the last
+/// `capacity` bytes of the output are kept, so readers that fall behind skip what was dropped
+/// and may start in the middle of a line.
+///
+/// Clones of a `MemoryLog` share the same log, so one can be written by the container while
+/// another is handed to its [`container::Handle`](crate::container::Handle).
+#[derive(Clone)]
+pub struct MemoryLog {
+    ring: Arc<Mutex<Ring>>,
+    index: LogIndex,
+}
+
+struct Ring {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// The offset in the log of the first byte in `buf`.
+    start: u64,
+    /// When the end of the log was last recorded in its index.
+    indexed: Option<Instant>,
+}
+
+impl Ring {
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+}
+
+impl MemoryLog {
+    /// Create an empty log that keeps the last `capacity` bytes written to it.
+    pub fn new(capacity: usize) -> Self {
+        MemoryLog {
+            ring: Arc::new(Mutex::new(Ring {
+                buf: VecDeque::with_capacity(capacity),
+                capacity,
+                start: 0,
+                indexed: None,
+            })),
+            index: LogIndex::default(),
+        }
+    }
+
+    /// Append output to the log, dropping the oldest output once it holds `capacity` bytes.
+    pub fn write(&self, data: &[u8]) {
+        let mut ring = self.ring.lock().unwrap();
+        let kept = &data[data.len().saturating_sub(ring.capacity)..];
+        let overflow = (ring.buf.len() + kept.len()).saturating_sub(ring.capacity);
+        ring.buf.drain(..overflow);
+        ring.buf.extend(kept);
+        ring.start += (overflow + data.len() - kept.len()) as u64;
+
+        if ring
+            .indexed
+            .map_or(true, |indexed| indexed.elapsed() >= INDEX_INTERVAL)
+        {
+            self.index.record(ring.end(), Utc::now());
+            ring.indexed = Some(Instant::now());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl LogBacking for MemoryLog {
+    async fn open(&self, offset: u64) -> std::io::Result<LogReader> {
+        Ok(Box::new(MemoryLogReader {
+            ring: self.ring.clone(),
+            position: offset,
+        }))
+    }
+
+    fn log_index(&self) -> Option<LogIndex> {
+        Some(self.index.clone())
+    }
+}
+
+/// Reads a [`MemoryLog`] from a position in it.
+struct MemoryLogReader {
+    ring: Arc<Mutex<Ring>>,
+    position: u64,
+}
+
+impl AsyncRead for MemoryLogReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let ring = this.ring.lock().unwrap();
+        let position = this.position.max(ring.start).min(ring.end());
+        let skip = (position - ring.start) as usize;
+        let (front, back) = ring.buf.as_slices();
+        let data = if skip < front.len() {
+            &front[skip..]
+        } else {
+            &back[skip - front.len()..]
+        };
+        let read = data.len().min(buf.remaining());
+        buf.put_slice(&data[..read]);
+        this.position = position + read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn read_to_end(reader: &mut LogReader) -> String {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_memory_log_keeps_last_output() {
+        let log = MemoryLog::new(8);
+        log.write(b"first\n");
+        let mut reader = log.open(0).await.unwrap();
+        assert_eq!("first\n", read_to_end(&mut reader).await);
+
+        // Output written after the reader reached the end is read from where it left off
+        log.write(b"second\n");
+        assert_eq!("second\n", read_to_end(&mut reader).await);
+
+        // Readers from before the start of the kept output skip what was dropped
+        let mut reader = log.open(0).await.unwrap();
+        assert_eq!("\nsecond\n", read_to_end(&mut reader).await);
+        let mut reader = log.open(8).await.unwrap();
+        assert_eq!("cond\n", read_to_end(&mut reader).await);
+
+        log.write(b"a long third line\n");
+        let mut reader = log.open(0).await.unwrap();
+        assert_eq!("rd line\n", read_to_end(&mut reader).await);
+        assert_eq!(31, log.ring.lock().unwrap().end());
+    }
+}
@@ -0,0 +1,228 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use tracing::{debug, warn};
+
+use crate::metrics::log_metrics;
+
+/// How often the size of a log file is recorded while it is written.
+const INDEX_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a log file is synced to disk while it is written, so that a node that loses power
+/// loses at most this much of its containers' output.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The line sent to clients ahead of a log that is shorter than the output that was written to
+/// it, so that they aren't misled by a log that was silently truncated.
+pub const INCOMPLETE_LOG_MARKER: &str =
+    "[krustlet] logs may be incomplete: the log file is shorter than the output written to it\n";
+
+/// Records when the output in a log file was written, so that requests for the logs since a
+/// point in time (`sinceTime` or `sinceSeconds`) don't have to read the whole file.
+///
+/// The index holds the size of the file at regular intervals, so the start of the output written
+/// since a point in time is found with the precision of that interval.
+///
+/// The index also holds the sync marker of the file, the size it had when it was last synced to
+/// disk, so that a file that has since lost output, such as one truncated by a crash of the
+/// node or of its storage, is found to be incomplete.
+#[derive(Clone, Debug, Default)]
+pub struct LogIndex {
+    entries: Arc<Mutex<Vec<(u64, DateTime<Utc>)>>>,
+    integrity: Arc<Mutex<Integrity>>,
+}
+
+#[derive(Debug, Default)]
+struct Integrity {
+    /// How much of the file was on disk when it was last synced.
+    synced: u64,
+    /// Whether the file was found to be shorter than the output written to it.
+    incomplete: bool,
+}
+
+impl LogIndex {
+    /// Record that the file was `len` bytes long at the given time.
+    pub fn record(&self, len: u64, time: DateTime<Utc>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.last().map_or(true, |(last, _)| len > *last) {
+            entries.push((len, time));
+        }
+    }
+
+    /// The offset in the file from which the output was written at or after `since`. This may
+    /// include some output from before `since`, but never leaves out later output.
+    pub fn offset_since(&self, since: DateTime<Utc>) -> u64 {
+        let entries = self.entries.lock().unwrap();
+        // Everything up to an offset was written by the time it was recorded, so the output since
+        // starts at the last offset recorded before then
+        let recorded_before = entries.partition_point(|(_, time)| *time < since);
+        match recorded_before {
+            0 => 0,
+            n => entries[n - 1].0,
+        }
+    }
+
+    /// When the output up to `offset` in the file had been written by, with the precision of the
+    /// index, or `None` if it was written after the index was last updated.
+    pub fn written_by(&self, offset: u64) -> Option<DateTime<Utc>> {
+        let entries = self.entries.lock().unwrap();
+        let recorded_before = entries.partition_point(|(len, _)| *len < offset);
+        entries.get(recorded_before).map(|(_, time)| *time)
+    }
+
+    /// Record that the first `len` bytes of the file have been synced to disk.
+    pub fn record_synced(&self, len: u64) {
+        let mut integrity = self.integrity.lock().unwrap();
+        integrity.synced = integrity.synced.max(len);
+    }
+
+    /// How much of the file was on disk when it was last synced.
+    pub fn synced(&self) -> u64 {
+        self.integrity.lock().unwrap().synced
+    }
+
+    /// Check the file, now `len` bytes long, against the sizes recorded for it, returning
+    /// whether it is incomplete. A file that is shorter than it was recorded to be has lost
+    /// output, and stays incomplete from then on.
+    pub fn check(&self, len: u64) -> bool {
+        let recorded = self
+            .entries
+            .lock()
+            .unwrap()
+            .last()
+            .map_or(0, |(len, _)| *len);
+        let mut integrity = self.integrity.lock().unwrap();
+        if !integrity.incomplete && len < recorded.max(integrity.synced) {
+            warn!(
+                len,
+                recorded,
+                synced = integrity.synced,
+                "Log file is shorter than the output written to it"
+            );
+            integrity.incomplete = true;
+            log_metrics().record_incomplete_log();
+        }
+        integrity.incomplete
+    }
+
+    /// Whether the file has been found to be incomplete.
+    pub fn incomplete(&self) -> bool {
+        self.integrity.lock().unwrap().incomplete
+    }
+}
+
+/// Record the size of the file at `path` in the index until `stop` completes, checking that it
+/// hasn't lost any of its output and periodically syncing it to disk. The file is synced once
+/// more when `stop` completes.
+pub async fn index<F>(path: PathBuf, index: LogIndex, stop: F)
+where
+    F: Future<Output = ()>,
+{
+    tokio::pin!(stop);
+    let mut synced_at = Instant::now();
+    loop {
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => {
+                index.check(metadata.len());
+                index.record(metadata.len(), Utc::now());
+            }
+            Err(e) => {
+                debug!(error = %e, path = %path.display(), "Unable to index log file");
+                return;
+            }
+        }
+        if synced_at.elapsed() >= SYNC_INTERVAL {
+            sync(&path, &index).await;
+            synced_at = Instant::now();
+        }
+        tokio::select! {
+            _ = &mut stop => return sync(&path, &index).await,
+            _ = tokio::time::sleep(INDEX_INTERVAL) => (),
+        }
+    }
+}
+
+/// Sync the file at `path` to disk and record how much of it was synced.
+async fn sync(path: &Path, index: &LogIndex) {
+    let synced = async {
+        // Everything up to the size read before the sync is covered by it
+        let len = tokio::fs::metadata(path).await?.len();
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await?;
+        file.sync_data().await?;
+        std::io::Result::Ok(len)
+    };
+    match synced.await {
+        Ok(len) => index.record_synced(len),
+        Err(e) => debug!(error = %e, path = %path.display(), "Unable to sync log file"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_offset_since() {
+        let index = LogIndex::default();
+        let at = |secs| Utc.timestamp(secs, 0);
+        assert_eq!(0, index.offset_since(at(100)));
+
+        index.record(10, at(100));
+        index.record(10, at(101));
+        index.record(25, at(102));
+        index.record(40, at(103));
+
+        assert_eq!(0, index.offset_since(at(50)));
+        assert_eq!(0, index.offset_since(at(100)));
+        assert_eq!(10, index.offset_since(at(102)));
+        assert_eq!(25, index.offset_since(at(103)));
+        assert_eq!(40, index.offset_since(at(200)));
+    }
+
+    #[test]
+    fn test_written_by() {
+        let index = LogIndex::default();
+        let at = |secs| Utc.timestamp(secs, 0);
+        index.record(10, at(100));
+        index.record(25, at(102));
+
+        assert_eq!(Some(at(100)), index.written_by(0));
+        assert_eq!(Some(at(100)), index.written_by(10));
+        assert_eq!(Some(at(102)), index.written_by(11));
+        assert_eq!(None, index.written_by(26));
+    }
+
+    #[test]
+    fn test_check_finds_lost_output() {
+        let index = LogIndex::default();
+        let at = |secs| Utc.timestamp(secs, 0);
+        index.record(10, at(100));
+        index.record_synced(10);
+        index.record(25, at(101));
+        assert!(!index.check(25));
+        assert!(!index.check(40));
+        assert_eq!(10, index.synced());
+
+        // A file that shrinks has lost output, and stays incomplete once it grows again
+        assert!(index.check(12));
+        assert!(index.incomplete());
+        assert!(index.check(50));
+    }
+
+    #[tokio::test]
+    async fn test_index_syncs_when_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        tokio::fs::write(&path, b"first\n").await.unwrap();
+        let log_index = LogIndex::default();
+        index(path, log_index.clone(), async {}).await;
+        assert_eq!(6, log_index.synced());
+        assert!(!log_index.incomplete());
+    }
+}
@@ -1,11 +1,25 @@
 //! `log` contains convenient wrappers around fetching logs from the Kubernetes API.
-use anyhow::bail;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::Deserialize;
+use std::path::PathBuf;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
 use tracing::{debug, error};
 
+mod backing;
+mod index;
+mod sink;
+mod stream;
+mod tail;
+
+pub use backing::{LogBacking, LogReader, MemoryLog};
+pub use index::{index, LogIndex, INCOMPLETE_LOG_MARKER};
+pub use sink::{
+    forward, pod_sink_from_url, sink_from_url, HttpSink, LogRecord, LogSink, LogSource,
+    SyslogTcpSink, SyslogUdpSink, LOG_FORWARD_ANNOTATION,
+};
+pub use stream::{stream, LogStream};
+pub use tail::{message_with_output, tail_lines, MAX_TAIL_BYTES};
+
 /// Possible errors sending log data.
 #[derive(Debug)]
 pub enum SendError {
@@ -13,6 +27,8 @@ pub enum SendError {
     ChannelClosed,
     /// An unexpected error occured.
     Abnormal(anyhow::Error),
+    /// The number of bytes requested by the client (`limitBytes`) has been sent.
+    LimitReached,
 }
 
 impl From<std::io::Error> for SendError {
@@ -26,6 +42,7 @@ impl std::fmt::Display for SendError {
         match self {
             SendError::ChannelClosed => write!(f, "ChannelClosed"),
             SendError::Abnormal(e) => write!(f, "{}", e),
+            SendError::LimitReached => write!(f, "LimitReached"),
         }
     }
 }
@@ -33,7 +50,7 @@ impl std::fmt::Display for SendError {
 impl std::error::Error for SendError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            SendError::ChannelClosed => None,
+            SendError::ChannelClosed | SendError::LimitReached => None,
             SendError::Abnormal(e) => Some(e.root_cause()),
         }
     }
@@ -71,12 +88,17 @@ pub struct Options {
 pub struct Sender {
     sender: hyper::body::Sender,
     opts: Options,
+    sent: u64,
 }
 
 impl Sender {
     /// Create new `Sender` from `hyper::body::Sender`.
     pub fn new(sender: hyper::body::Sender, opts: Options) -> Self {
-        Sender { sender, opts }
+        Sender {
+            sender,
+            opts,
+            sent: 0,
+        }
     }
 
     /// The tail flag indicated by the request if present.
@@ -114,115 +136,94 @@ impl Sender {
         self.opts.limit_bytes
     }
 
-    /// Async send some data to a client.
-    pub async fn send(&mut self, data: String) -> Result<(), SendError> {
-        let b: hyper::body::Bytes = data.into();
-        self.sender.send_data(b).await.map_err(|e| {
-            if e.is_closed() {
-                debug!("channel closed");
-                SendError::ChannelClosed
-            } else {
-                error!(error = %e, "channel error");
-                SendError::Abnormal(anyhow::Error::new(e))
-            }
+    /// The point in time from which logs are requested, from either `sinceTime` or
+    /// `sinceSeconds`, or `None` if the request wants all logs.
+    pub fn since_start(&self) -> Option<DateTime<Utc>> {
+        self.since_time().or_else(|| {
+            self.since()
+                .and_then(|since| chrono::Duration::from_std(since).ok())
+                .map(|since| Utc::now() - since)
         })
     }
-}
 
-/// Stream last `n` lines.
-async fn tail<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
-    sender: &mut Sender,
-    n: usize,
-) -> Result<(), SendError> {
-    let mut line_buf = std::collections::VecDeque::with_capacity(n);
-
-    while let Some(line) = match lines.next_line().await {
-        Ok(line) => line,
-        Err(e) => {
-            error!(error = %e, "Error reading from log");
-            sender
-                .send(format!("Error reading from log: {:?}", e))
-                .await?;
-            return Err(e.into());
+    /// Async send some data to a client.
+    ///
+    /// If the request has a `limitBytes`, the data is cut off at the limit and
+    /// `SendError::LimitReached` is returned once it has been sent.
+    pub async fn send(&mut self, data: String) -> Result<(), SendError> {
+        self.send_chunk(data.into()).await
+    }
+
+    /// Async send a chunk of the log to a client as it is, without copying it.
+    ///
+    /// Like [`send`](Sender::send), the chunk is cut off at the request's `limitBytes`.
+    pub async fn send_chunk(&mut self, mut b: hyper::body::Bytes) -> Result<(), SendError> {
+        let limit_reached = match self.limit_bytes() {
+            Some(limit) => {
+                let remaining = limit.saturating_sub(self.sent);
+                if (b.len() as u64) >= remaining {
+                    b.truncate(remaining as usize);
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        };
+        self.sent += b.len() as u64;
+        if !b.is_empty() {
+            self.send_bytes(b).await?;
         }
-    } {
-        if line_buf.len() == n {
-            line_buf.pop_front();
+        if limit_reached {
+            return Err(SendError::LimitReached);
         }
-        line_buf.push_back(line);
+        Ok(())
     }
 
-    for mut line in line_buf {
-        line.push('\n');
-        sender.send(line).await?;
-    }
-    Ok(())
-}
-
-/// Stream log to end.
-async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
-    lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
-    sender: &mut Sender,
-) -> Result<(), SendError> {
-    while let Some(mut line) = match lines.next_line().await {
-        Ok(line) => line,
-        Err(e) => {
-            error!(error = %e, "Error reading from log");
-            sender
-                .send(format!("Error reading from log: {:?}", e))
-                .await?;
-            return Err(e.into());
+    /// Async send a line of the log, written at the given time. The line is prefixed with the
+    /// time in RFC 3339 format if the request asked for `timestamps`.
+    pub async fn send_line(&mut self, line: String, time: DateTime<Utc>) -> Result<(), SendError> {
+        if self.timestamps() {
+            let time = time.to_rfc3339_opts(SecondsFormat::Nanos, true);
+            self.send(format!("{} {}", time, line)).await
+        } else {
+            self.send(line).await
         }
-    } {
-        line.push('\n');
-        sender.send(line).await?;
     }
-    Ok(())
-}
 
-/// Future that streams logs from provided `AsyncRead` to provided `Sender`.
-pub async fn stream<R: AsyncRead + std::marker::Unpin>(
-    handle: R,
-    mut sender: Sender,
-) -> anyhow::Result<()> {
-    let buf = tokio::io::BufReader::new(handle);
-    let mut lines = buf.lines();
-
-    if let Some(n) = sender.tail() {
-        match tail(&mut lines, &mut sender, n).await {
-            Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
-            Err(SendError::Abnormal(e)) => bail!(e),
-        }
-    } else {
-        match stream_to_end(&mut lines, &mut sender).await {
-            Ok(_) => (),
-            Err(SendError::ChannelClosed) => return Ok(()),
-            Err(SendError::Abnormal(e)) => bail!(e),
-        }
-    }
-
-    if sender.follow() {
-        loop {
-            match stream_to_end(&mut lines, &mut sender).await {
-                Ok(_) => (),
-                Err(SendError::ChannelClosed) => return Ok(()),
-                Err(SendError::Abnormal(e)) => bail!(e),
+    async fn send_bytes(&mut self, b: hyper::body::Bytes) -> Result<(), SendError> {
+        self.sender.send_data(b).await.map_err(|e| {
+            if e.is_closed() {
+                debug!("channel closed");
+                SendError::ChannelClosed
+            } else {
+                error!(error = %e, "channel error");
+                SendError::Abnormal(anyhow::Error::new(e))
             }
-
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-        }
+        })
     }
-
-    Ok(())
 }
 
 // TODO: Both providers make a handle containing a tempfile. If this is a common pattern,
 // it might make sense to provide that implementation here. This would add `tempfile` as a
 // dependency of `kubelet`.
 /// Trait to describe necessary behavior for creating multiple log readers.
+///
+/// Factories of `tokio::fs::File` handles are [`LogBacking`]s, which is how container handles
+/// read their logs.
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// The index of when the output in the log was written, if the factory keeps one. Without
+    /// an index, requests for the logs since a point in time get the whole log.
+    fn log_index(&self) -> Option<LogIndex> {
+        None
+    }
+
+    /// The path of the log file, if the log is a file. Followed logs are watched for changes
+    /// instead of being checked for new output periodically when this is known.
+    fn log_path(&self) -> Option<PathBuf> {
+        None
+    }
 }
@@ -0,0 +1,425 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// The annotation a pod can use to forward the output of its containers somewhere other than
+/// the node wide default. The value uses the same URL format as [`sink_from_url`], and has to
+/// name one of the destinations the node allows (see [`pod_sink_from_url`]).
+pub const LOG_FORWARD_ANNOTATION: &str = "krustlet.dev/log-forward";
+
+/// How often to check for new output while forwarding logs.
+const FORWARD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most lines of output sent to a sink at once.
+const MAX_BATCH_LINES: usize = 100;
+
+/// How long an HTTP sink waits for a batch of records to be accepted.
+const HTTP_SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies the container a forwarded line of output came from.
+#[derive(Clone, Debug)]
+pub struct LogSource {
+    /// The namespace of the pod.
+    pub namespace: String,
+    /// The name of the pod.
+    pub pod: String,
+    /// The name of the container.
+    pub container: String,
+}
+
+impl LogSource {
+    fn record(&self, line: String) -> LogRecord {
+        LogRecord {
+            namespace: self.namespace.clone(),
+            pod: self.pod.clone(),
+            container: self.container.clone(),
+            timestamp: Utc::now(),
+            line,
+        }
+    }
+}
+
+/// A single line of container output sent to a [`LogSink`].
+#[derive(Clone, Debug, Serialize)]
+pub struct LogRecord {
+    /// The namespace of the pod.
+    pub namespace: String,
+    /// The name of the pod.
+    pub pod: String,
+    /// The name of the container.
+    pub container: String,
+    /// When the line was read from the container output.
+    pub timestamp: DateTime<Utc>,
+    /// The line of output, without the trailing newline.
+    pub line: String,
+}
+
+/// A destination that container output is forwarded to in addition to the local log files.
+///
+/// Implementations are provided for syslog (UDP and TCP), HTTP endpoints and
+/// `tokio::sync::mpsc::Sender<LogRecord>` so that embedders can consume the output directly.
+#[async_trait]
+pub trait LogSink: Send + Sync {
+    /// Send a single line of output to the sink.
+    async fn send(&self, record: &LogRecord) -> anyhow::Result<()>;
+
+    /// Send several lines of output to the sink at once. The default implementation sends them
+    /// one at a time.
+    async fn send_batch(&self, records: &[LogRecord]) -> anyhow::Result<()> {
+        for record in records {
+            self.send(record).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LogSink for tokio::sync::mpsc::Sender<LogRecord> {
+    async fn send(&self, record: &LogRecord) -> anyhow::Result<()> {
+        tokio::sync::mpsc::Sender::send(self, record.clone())
+            .await
+            .map_err(|_| anyhow::anyhow!("log record receiver has been dropped"))
+    }
+}
+
+/// Formats a record as an RFC 5424 syslog message with the `user.info` priority.
+fn syslog_message(record: &LogRecord) -> String {
+    format!(
+        "<14>1 {} - {}/{} - {} - {}",
+        record
+            .timestamp
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        record.namespace,
+        record.pod,
+        record.container,
+        record.line
+    )
+}
+
+/// Sends records to a syslog server over UDP.
+pub struct SyslogUdpSink {
+    socket: UdpSocket,
+}
+
+impl SyslogUdpSink {
+    /// Create a sink sending to the given `host:port` address.
+    pub async fn new(addr: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(SyslogUdpSink { socket })
+    }
+}
+
+#[async_trait]
+impl LogSink for SyslogUdpSink {
+    async fn send(&self, record: &LogRecord) -> anyhow::Result<()> {
+        self.socket.send(syslog_message(record).as_bytes()).await?;
+        Ok(())
+    }
+}
+
+/// Sends records to a syslog server over TCP using octet counting framing (RFC 6587).
+///
+/// The connection is established lazily and re-established after a failed write.
+pub struct SyslogTcpSink {
+    addr: String,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl SyslogTcpSink {
+    /// Create a sink sending to the given `host:port` address.
+    pub fn new(addr: &str) -> Self {
+        SyslogTcpSink {
+            addr: addr.to_owned(),
+            stream: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl LogSink for SyslogTcpSink {
+    async fn send(&self, record: &LogRecord) -> anyhow::Result<()> {
+        let message = syslog_message(record);
+        let frame = format!("{} {}", message.len(), message);
+        let mut stream = self.stream.lock().await;
+        let mut connection = match stream.take() {
+            Some(connection) => connection,
+            None => TcpStream::connect(&self.addr).await?,
+        };
+        connection.write_all(frame.as_bytes()).await?;
+        // Only hold on to connections that are still working so a broken one is re-established
+        // on the next line
+        *stream = Some(connection);
+        Ok(())
+    }
+}
+
+/// Sends records as a JSON array in the body of a POST request, one request per batch of lines
+/// read from the container. Requests that aren't answered within 10 seconds fail.
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    /// Create a sink posting to the given URL.
+    pub fn new(url: &str) -> Self {
+        HttpSink {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl LogSink for HttpSink {
+    async fn send(&self, record: &LogRecord) -> anyhow::Result<()> {
+        self.send_batch(std::slice::from_ref(record)).await
+    }
+
+    async fn send_batch(&self, records: &[LogRecord]) -> anyhow::Result<()> {
+        self.client
+            .post(&self.url)
+            .timeout(HTTP_SINK_TIMEOUT)
+            .json(records)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// The scheme, host and port a log forwarding URL sends to.
+fn destination(url: &url::Url) -> Option<(&str, &str, u16)> {
+    let port = match url.scheme() {
+        "udp" | "tcp" => url.port().unwrap_or(514),
+        _ => url.port_or_known_default()?,
+    };
+    Some((url.scheme(), url.host_str()?, port))
+}
+
+/// Create a [`LogSink`] for the URL a pod asked for with the [`LOG_FORWARD_ANNOTATION`]
+/// annotation. The scheme, host and port of the URL have to match one of the `allowed` URLs
+/// the operator configured, so that pods can't make the node connect to arbitrary addresses.
+pub async fn pod_sink_from_url(url: &str, allowed: &[String]) -> anyhow::Result<Arc<dyn LogSink>> {
+    let parsed = url::Url::parse(url)?;
+    let requested = destination(&parsed);
+    let is_allowed = requested.is_some()
+        && allowed.iter().any(|allowed| {
+            url::Url::parse(allowed)
+                .map(|allowed| destination(&allowed) == requested)
+                .unwrap_or(false)
+        });
+    if !is_allowed {
+        anyhow::bail!("forwarding logs to {} is not allowed on this node", url);
+    }
+    sink_from_url(url).await
+}
+
+/// Create a [`LogSink`] from a URL. Supported schemes are `udp` and `tcp` (syslog) and `http`
+/// and `https` (JSON POST).
+pub async fn sink_from_url(url: &str) -> anyhow::Result<Arc<dyn LogSink>> {
+    let parsed = url::Url::parse(url)?;
+    let host_port = || -> anyhow::Result<String> {
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("log forwarding URL {} has no host", url))?;
+        Ok(format!("{}:{}", host, parsed.port().unwrap_or(514)))
+    };
+    let sink: Arc<dyn LogSink> = match parsed.scheme() {
+        "udp" => Arc::new(SyslogUdpSink::new(&host_port()?).await?),
+        "tcp" => Arc::new(SyslogTcpSink::new(&host_port()?)),
+        "http" | "https" => Arc::new(HttpSink::new(url)),
+        other => anyhow::bail!("unsupported log forwarding scheme {}", other),
+    };
+    Ok(sink)
+}
+
+/// Future that forwards each line read from `handle` to `sink` until `stop` completes.
+///
+/// Lines are sent in batches of whatever output is available. A line the container is still
+/// writing is held back until it is complete, and output that isn't valid UTF-8 is forwarded
+/// with the invalid bytes replaced. Once `stop` completes any remaining output is forwarded
+/// before returning. Failures to send are logged and otherwise ignored so that a broken sink
+/// never affects the container.
+pub async fn forward<R, F>(handle: R, source: LogSource, sink: Arc<dyn LogSink>, stop: F)
+where
+    R: AsyncRead + Unpin,
+    F: Future<Output = ()>,
+{
+    let mut reader = tokio::io::BufReader::new(handle);
+    // The start of a line the container hasn't finished writing yet
+    let mut partial = Vec::new();
+    tokio::pin!(stop);
+    let mut stopped = false;
+    loop {
+        let mut batch = Vec::new();
+        loop {
+            match reader.read_until(b'\n', &mut partial).await {
+                Ok(0) => break,
+                Ok(_) if partial.ends_with(b"\n") => {
+                    batch.push(source.record(decode_line(&partial)));
+                    partial.clear();
+                    if batch.len() == MAX_BATCH_LINES {
+                        send_batch(&*sink, &source, &mut batch).await;
+                    }
+                }
+                // The rest of the line hasn't been written yet
+                Ok(_) => break,
+                Err(e) => {
+                    warn!(error = %e, container = %source.container, "Error reading from log");
+                    return;
+                }
+            }
+        }
+        if stopped && !partial.is_empty() {
+            batch.push(source.record(decode_line(&partial)));
+        }
+        send_batch(&*sink, &source, &mut batch).await;
+        if stopped {
+            debug!(container = %source.container, "Finished forwarding logs");
+            return;
+        }
+        tokio::select! {
+            _ = &mut stop => stopped = true,
+            _ = tokio::time::sleep(FORWARD_POLL_INTERVAL) => (),
+        }
+    }
+}
+
+/// A line of output without its line ending, with any bytes that aren't valid UTF-8 replaced.
+fn decode_line(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// Send and clear a batch of records, logging failures.
+async fn send_batch(sink: &dyn LogSink, source: &LogSource, batch: &mut Vec<LogRecord>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = sink.send_batch(batch).await {
+        let lines = batch.len();
+        warn!(error = %e, container = %source.container, lines, "Unable to forward log lines");
+    }
+    batch.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Output that is read in chunks, with an empty chunk wherever the container hasn't written
+    /// any more yet.
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn source() -> LogSource {
+        LogSource {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            container: "bar".to_owned(),
+        }
+    }
+
+    async fn forwarded(output: impl AsyncRead + Unpin) -> Vec<String> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        forward(output, source(), Arc::new(tx), async {}).await;
+        let mut lines = Vec::new();
+        while let Some(record) = rx.recv().await {
+            lines.push(record.line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_forward_sends_all_lines_to_channel() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let output: &[u8] = b"hello\nworld\n";
+        forward(output, source(), Arc::new(tx), async {}).await;
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!("hello", first.line);
+        assert_eq!("bar", first.container);
+        assert_eq!("world", rx.recv().await.unwrap().line);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_waits_for_complete_lines() {
+        let output = Chunks(vec![&b"hel"[..], b"", b"lo\r\nwor", b""].into());
+        assert_eq!(vec!["hello", "wor"], forwarded(output).await);
+    }
+
+    #[tokio::test]
+    async fn test_forward_replaces_invalid_utf8() {
+        let output: &[u8] = b"caf\xff\nnext\n";
+        assert_eq!(vec!["caf\u{FFFD}", "next"], forwarded(output).await);
+    }
+
+    #[tokio::test]
+    async fn test_pod_sink_must_be_allowed() {
+        let allowed = vec![
+            "udp://syslog.local:514".to_owned(),
+            "https://logs.example.com".to_owned(),
+        ];
+        assert!(pod_sink_from_url("tcp://syslog.local", &allowed)
+            .await
+            .is_err());
+        assert!(pod_sink_from_url("udp://10.0.0.1:514", &allowed)
+            .await
+            .is_err());
+        assert!(
+            pod_sink_from_url("https://logs.example.com:8443/", &allowed)
+                .await
+                .is_err()
+        );
+        assert!(
+            pod_sink_from_url("https://logs.example.com/ingest", &allowed)
+                .await
+                .is_ok()
+        );
+        assert!(pod_sink_from_url("https://logs.example.com", &[])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_syslog_message() {
+        let record = LogRecord {
+            namespace: "default".to_owned(),
+            pod: "foo".to_owned(),
+            container: "bar".to_owned(),
+            timestamp: Utc::now(),
+            line: "hello".to_owned(),
+        };
+        let message = syslog_message(&record);
+        assert!(message.starts_with("<14>1 "));
+        assert!(message.ends_with(" - default/foo - bar - hello"));
+    }
+}
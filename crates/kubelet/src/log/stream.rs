@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+use tracing::{debug, error};
+
+use super::{LogIndex, SendError, Sender, INCOMPLETE_LOG_MARKER};
+use crate::fs_watch::FileSystemWatcher;
+
+/// How often a followed log is checked for new output when changes to it aren't watched.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often a watched log is checked for new output anyway, in case a change was missed.
+const WATCH_FALLBACK_INTERVAL: Duration = Duration::from_secs(5);
+/// How much of the log is read at once. Output that is already in the log is sent in chunks of
+/// up to this size when lines don't need timestamps.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams a log to a client as requested by the options of its [`Sender`]: the last
+/// `tailLines` lines or the whole log, optionally prefixed with when they were written and
+/// followed by the output written from then on.
+///
+/// Providers get this behavior through [`container::Handle`](crate::container::Handle) by
+/// keeping their logs in a [`LogBacking`](super::LogBacking), such as a
+/// [`HandleFactory`](super::HandleFactory) of files or a [`MemoryLog`](super::MemoryLog).
+///
+/// Unless the request asks for `timestamps`, the log is sent in chunks of whole lines as it is
+/// read, rather than line by line, so following a busy log costs a read and a send per chunk
+/// instead of per line.
+///
+/// A followed log is streamed until the client disconnects, or until the container is
+/// [stopped](Self::until_stopped), at which point the rest of the log is sent and the response is
+/// ended, so that the client sees the end of the stream rather than a dropped connection.
+///
+/// If the [index](Self::with_index) of the log finds that it has lost output, the client is sent
+/// [`INCOMPLETE_LOG_MARKER`] once, ahead of the rest of the log.
+pub struct LogStream<R> {
+    reader: BufReader<R>,
+    sender: Sender,
+    offset: u64,
+    index: Option<LogIndex>,
+    path: Option<PathBuf>,
+    stopped: Option<watch::Receiver<bool>>,
+    stopping: bool,
+    marked_incomplete: bool,
+}
+
+impl<R: AsyncRead + Unpin> LogStream<R> {
+    /// Stream the log read from `handle` to `sender`.
+    pub fn new(handle: R, sender: Sender) -> Self {
+        LogStream {
+            reader: BufReader::with_capacity(CHUNK_SIZE, handle),
+            sender,
+            offset: 0,
+            index: None,
+            path: None,
+            stopped: None,
+            stopping: false,
+            marked_incomplete: false,
+        }
+    }
+
+    /// Take the times lines were written from `index`, for requests with `timestamps`. The
+    /// handle must be at `offset` in the log. Without an index, lines are stamped with the time
+    /// they are read.
+    pub fn with_index(mut self, index: LogIndex, offset: u64) -> Self {
+        self.index = Some(index);
+        self.offset = offset;
+        self
+    }
+
+    /// Watch the file at `path` for output when following the log, instead of checking it for
+    /// new output every half second.
+    pub fn watching(mut self, path: PathBuf) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Stop following the log once `stopped` is set or its sender is dropped, e.g. because the
+    /// container has exited, after sending the rest of the log.
+    pub fn until_stopped(mut self, stopped: watch::Receiver<bool>) -> Self {
+        self.stopped = Some(stopped);
+        self
+    }
+
+    /// Stream the log until the end, or, when following it, until the client disconnects or the
+    /// container is stopped.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        match self.send_log().await {
+            Ok(()) | Err(SendError::ChannelClosed) | Err(SendError::LimitReached) => Ok(()),
+            Err(SendError::Abnormal(e)) => Err(e),
+        }
+    }
+
+    async fn send_log(&mut self) -> Result<(), SendError> {
+        let mut partial = Vec::new();
+        self.stopping = self
+            .stopped
+            .as_ref()
+            .map_or(false, |stopped| *stopped.borrow());
+        self.mark_incomplete().await?;
+        match self.sender.tail() {
+            Some(n) => self.send_tail(n, &mut partial).await?,
+            None => self.send_to_end(&mut partial).await?,
+        }
+        if !self.following() {
+            return Ok(());
+        }
+
+        let mut changes = Changes::new(self.path.clone());
+        while !self.stopping {
+            self.stopping = self.next_change(&mut changes).await;
+            self.mark_incomplete().await?;
+            // Once stopped, this sends the rest of the log, finishing its last line
+            self.send_to_end(&mut partial).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the end of the log is waited on for more output, rather than ending the stream.
+    fn following(&self) -> bool {
+        self.sender.follow() && !self.stopping
+    }
+
+    /// Send the client [`INCOMPLETE_LOG_MARKER`] if the index of the log has found that it lost
+    /// output, unless it has already been sent.
+    async fn mark_incomplete(&mut self) -> Result<(), SendError> {
+        let index = match &self.index {
+            Some(index) if !self.marked_incomplete => index,
+            _ => return Ok(()),
+        };
+        let incomplete = match &self.path {
+            Some(path) => match tokio::fs::metadata(path).await {
+                Ok(metadata) => index.check(metadata.len()),
+                Err(_) => index.incomplete(),
+            },
+            None => index.incomplete(),
+        };
+        if incomplete {
+            self.marked_incomplete = true;
+            self.sender
+                .send_line(INCOMPLETE_LOG_MARKER.to_owned(), Utc::now())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the log to change, returning whether the container was stopped instead.
+    async fn next_change(&mut self, changes: &mut Changes) -> bool {
+        match self.stopped.as_mut() {
+            Some(stopped) => tokio::select! {
+                _ = changes.next() => false,
+                // An error means the sender was dropped along with the container's handle
+                _ = stopped.changed() => true,
+            },
+            None => {
+                changes.next().await;
+                false
+            }
+        }
+    }
+
+    /// Send the last `n` lines of the log.
+    async fn send_tail(&mut self, n: usize, partial: &mut Vec<u8>) -> Result<(), SendError> {
+        let mut lines = VecDeque::with_capacity(n);
+        while let Some(line) = self.read_line(partial).await? {
+            if lines.len() == n {
+                lines.pop_front();
+            }
+            if n > 0 {
+                lines.push_back(line);
+            }
+        }
+        for (line, time) in lines {
+            self.sender.send_line(line, time).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the lines up to the end of the log.
+    async fn send_to_end(&mut self, partial: &mut Vec<u8>) -> Result<(), SendError> {
+        if !self.sender.timestamps() {
+            return self.send_chunks_to_end(partial).await;
+        }
+        while let Some((line, time)) = self.read_line(partial).await? {
+            self.sender.send_line(line, time).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the lines up to the end of the log, as many at a time as have been read. When
+    /// following the log, a line that isn't finished yet is kept in `partial` until it is.
+    ///
+    /// Each chunk is read into the buffer that is handed to the client, so the log isn't copied
+    /// on its way through.
+    async fn send_chunks_to_end(&mut self, partial: &mut Vec<u8>) -> Result<(), SendError> {
+        loop {
+            let mut chunk = std::mem::take(partial);
+            let start = chunk.len();
+            chunk.resize(start + CHUNK_SIZE, 0);
+            let read = match self.reader.read(&mut chunk[start..]).await {
+                Ok(read) => read,
+                Err(e) => return Err(self.read_error(e).await),
+            };
+            chunk.truncate(start + read);
+            self.offset += read as u64;
+            if read == 0 {
+                if chunk.is_empty() || self.following() {
+                    *partial = chunk;
+                    return Ok(());
+                }
+                chunk.push(b'\n');
+                return self.sender.send_chunk(chunk.into()).await;
+            }
+            // Only the new data can finish a line, as `partial` never has a newline
+            match chunk[start..].iter().rposition(|b| *b == b'\n') {
+                Some(end) => {
+                    *partial = chunk.split_off(start + end + 1);
+                    self.sender.send_chunk(chunk.into()).await?;
+                }
+                None => *partial = chunk,
+            }
+        }
+    }
+
+    /// Read the next line of the log with the time it was written. When following the log, a
+    /// line that isn't finished yet is kept in `partial` until it is.
+    async fn read_line(
+        &mut self,
+        partial: &mut Vec<u8>,
+    ) -> Result<Option<(String, DateTime<Utc>)>, SendError> {
+        loop {
+            let read = match self.reader.read_until(b'\n', partial).await {
+                Ok(read) => read,
+                Err(e) => return Err(self.read_error(e).await),
+            };
+            self.offset += read as u64;
+            if partial.ends_with(b"\n") {
+                break;
+            }
+            if read == 0 {
+                if partial.is_empty() || self.following() {
+                    return Ok(None);
+                }
+                partial.push(b'\n');
+                break;
+            }
+        }
+        let line = String::from_utf8_lossy(&std::mem::take(partial)).into_owned();
+        Ok(Some((line, self.written_by(self.offset))))
+    }
+
+    /// Report an error reading the log to the client.
+    async fn read_error(&mut self, e: std::io::Error) -> SendError {
+        error!(error = %e, "Error reading from log");
+        match self
+            .sender
+            .send(format!("Error reading from log: {:?}", e))
+            .await
+        {
+            Ok(()) => e.into(),
+            Err(send_error) => send_error,
+        }
+    }
+
+    /// When the log up to `offset` had been written by.
+    fn written_by(&self, offset: u64) -> DateTime<Utc> {
+        self.index
+            .as_ref()
+            .and_then(|index| index.written_by(offset))
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+/// Waits for a followed log to change.
+struct Changes {
+    watcher: Option<FileSystemWatcher>,
+}
+
+impl Changes {
+    fn new(path: Option<PathBuf>) -> Self {
+        // The watcher on MacOS only reports files being created and removed in a directory
+        let path = path.filter(|_| cfg!(not(target_os = "macos")));
+        let watcher = path.and_then(|path| match FileSystemWatcher::new(&path) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                debug!(error = %e, path = %path.display(), "Unable to watch log file");
+                None
+            }
+        });
+        Changes { watcher }
+    }
+
+    async fn next(&mut self) {
+        let watcher = match self.watcher.as_mut() {
+            Some(watcher) => watcher,
+            None => return tokio::time::sleep(POLL_INTERVAL).await,
+        };
+        let stopped = tokio::select! {
+            event = watcher.next() => event.is_none(),
+            _ = tokio::time::sleep(WATCH_FALLBACK_INTERVAL) => false,
+        };
+        if stopped {
+            debug!("Log file watcher stopped, checking for output periodically");
+            self.watcher = None;
+            return;
+        }
+        // A busy log changes many times while its output is being sent, and one read covers
+        // them all
+        while let Some(Some(_)) = watcher.next().now_or_never() {}
+    }
+}
+
+/// Future that streams logs from provided `AsyncRead` to provided `Sender`.
+pub async fn stream<R: AsyncRead + Unpin>(handle: R, sender: Sender) -> anyhow::Result<()> {
+    LogStream::new(handle, sender).run().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log::Options;
+    use chrono::TimeZone;
+
+    fn sender(opts: &str) -> (Sender, hyper::Body) {
+        let (tx, body) = hyper::Body::channel();
+        let opts: Options = serde_json::from_str(opts).unwrap();
+        (Sender::new(tx, opts), body)
+    }
+
+    #[tokio::test]
+    async fn test_stream_stops_at_limit_bytes() {
+        let (sender, body) = sender(r#"{"limitBytes": 8}"#);
+        stream(&b"first\nsecond\nthird\n"[..], sender)
+            .await
+            .unwrap();
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(&b"first\nse"[..], &sent[..]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_sends_whole_lines_together() {
+        let (sender, mut body) = sender("{}");
+        tokio::spawn(stream(&b"first\nsecond\nthi"[..], sender));
+        assert_eq!(
+            &b"first\nsecond\n"[..],
+            &body.next().await.unwrap().unwrap()[..]
+        );
+        assert_eq!(&b"thi\n"[..], &body.next().await.unwrap().unwrap()[..]);
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tail_with_timestamps() {
+        let index = LogIndex::default();
+        index.record(6, Utc.timestamp(100, 0));
+        index.record(19, Utc.timestamp(101, 0));
+        let (sender, body) = sender(r#"{"tailLines": 2, "timestamps": true}"#);
+        LogStream::new(&b"first\nsecond\nthird\nfourth"[..], sender)
+            .with_index(index, 0)
+            .run()
+            .await
+            .unwrap();
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        let sent = String::from_utf8_lossy(&sent);
+        let mut lines = sent.lines();
+        assert_eq!(Some("1970-01-01T00:01:41.000000000Z third"), lines.next());
+        assert!(lines.next().unwrap().ends_with("Z fourth"));
+        assert_eq!(None, lines.next());
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_log_is_marked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        tokio::fs::write(&path, b"first\n").await.unwrap();
+        let index = LogIndex::default();
+        index.record(20, Utc.timestamp(100, 0));
+
+        let (sender, body) = sender("{}");
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        LogStream::new(file, sender)
+            .with_index(index.clone(), 0)
+            .watching(path)
+            .run()
+            .await
+            .unwrap();
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(
+            format!("{}first\n", INCOMPLETE_LOG_MARKER).as_bytes(),
+            &sent[..]
+        );
+        assert!(index.incomplete());
+    }
+
+    #[tokio::test]
+    async fn test_follow_waits_for_finished_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        tokio::fs::write(&path, b"first\nsec").await.unwrap();
+
+        let (sender, mut body) = sender(r#"{"follow": true}"#);
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        tokio::spawn(LogStream::new(file, sender).watching(path.clone()).run());
+        assert_eq!(&b"first\n"[..], &body.next().await.unwrap().unwrap()[..]);
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"ond\nthird").unwrap();
+        assert_eq!(&b"second\n"[..], &body.next().await.unwrap().unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn test_follow_ends_when_stopped() {
+        let (tx, mut body) = sender(r#"{"follow": true}"#);
+        let (stop, stopped) = watch::channel(false);
+        let streaming = tokio::spawn(
+            LogStream::new(&b"first\nsec"[..], tx)
+                .until_stopped(stopped)
+                .run(),
+        );
+        assert_eq!(&b"first\n"[..], &body.next().await.unwrap().unwrap()[..]);
+
+        // The rest of the log is sent before the stream ends
+        stop.send(true).unwrap();
+        assert_eq!(&b"sec\n"[..], &body.next().await.unwrap().unwrap()[..]);
+        assert!(body.next().await.is_none());
+        streaming.await.unwrap().unwrap();
+
+        // Streams of stopped containers end once the log has been sent
+        let (tx, body) = sender(r#"{"follow": true}"#);
+        let (_stop, stopped) = watch::channel(true);
+        LogStream::new(&b"first\n"[..], tx)
+            .until_stopped(stopped)
+            .run()
+            .await
+            .unwrap();
+        let sent = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(&b"first\n"[..], &sent[..]);
+    }
+}
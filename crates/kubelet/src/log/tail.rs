@@ -0,0 +1,83 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The most of the end of a log that is read for [`tail_lines`], which is also how much of its
+/// termination message the Kubernetes kubelet reports for a container.
+pub const MAX_TAIL_BYTES: u64 = 4096;
+
+/// The last `n` lines of the log file at `path`, e.g. to report the output of a container that
+/// failed along with its status. Only the last [`MAX_TAIL_BYTES`] of the file are read, so fewer
+/// lines are returned if they are long and the first one may be cut short. Trailing empty lines
+/// are left out.
+pub fn tail_lines(path: &Path, n: usize) -> std::io::Result<Vec<String>> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(MAX_TAIL_BYTES)))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let text = String::from_utf8_lossy(&buf);
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    let start = lines.len().saturating_sub(n);
+    Ok(lines[start..].iter().map(|line| line.to_string()).collect())
+}
+
+/// A status message followed by the output of the container, in the form reported in the
+/// terminated status of a failed container. The message is returned as it is if there is no
+/// output.
+pub fn message_with_output(message: &str, output: &[String]) -> String {
+    match output.len() {
+        0 => message.to_owned(),
+        1 => format!("{}; last line of output:\n{}", message, output[0]),
+        n => format!(
+            "{}; last {} lines of output:\n{}",
+            message,
+            n,
+            output.join("\n")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tail_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.log");
+        std::fs::write(&path, "one\ntwo\nthree\n\n").unwrap();
+        assert_eq!(vec!["two", "three"], tail_lines(&path, 2).unwrap());
+        assert_eq!(vec!["one", "two", "three"], tail_lines(&path, 10).unwrap());
+        assert!(tail_lines(&path, 0).unwrap().is_empty());
+
+        // Only the end of a long log is read
+        let long_line = "x".repeat(MAX_TAIL_BYTES as usize);
+        std::fs::write(&path, format!("first\n{}\nlast\n", long_line)).unwrap();
+        let lines = tail_lines(&path, 3).unwrap();
+        assert_eq!(2, lines.len());
+        assert_eq!("last", lines[1]);
+    }
+
+    #[test]
+    fn test_message_with_output() {
+        assert_eq!(
+            "unable to run module",
+            message_with_output("unable to run module", &[])
+        );
+        assert_eq!(
+            "unable to run module; last line of output:\npanicked",
+            message_with_output("unable to run module", &["panicked".to_owned()])
+        );
+        assert_eq!(
+            "unable to run module; last 2 lines of output:\nstarting\npanicked",
+            message_with_output(
+                "unable to run module",
+                &["starting".to_owned(), "panicked".to_owned()]
+            )
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! `metrics` contains the metrics the Kubelet serves at `/metrics` in the Prometheus text
+//! format.
+//!
+//! Besides the module store metrics, these measure the health of the async runtime: if module
+//! execution starves the runtime, the Kubelet's control loops (such as the node heartbeat) fall
+//! behind, which shows up here as growing scheduling delays before it shows up as a node that
+//! is not ready.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::plugin_watcher::metrics::plugin_metrics;
+use crate::store::metrics::store_metrics;
+
+/// How often the runtime scheduling delay is measured.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref RUNTIME_METRICS: RuntimeMetrics = RuntimeMetrics::default();
+    static ref POD_METRICS: PodMetrics = PodMetrics::default();
+    static ref LOG_METRICS: LogMetrics = LogMetrics::default();
+}
+
+/// The metrics of the async runtime of this process.
+pub fn runtime_metrics() -> &'static RuntimeMetrics {
+    &RUNTIME_METRICS
+}
+
+/// The metrics of the pods running on this node.
+pub fn pod_metrics() -> &'static PodMetrics {
+    &POD_METRICS
+}
+
+/// The metrics of the container logs kept on this node.
+pub fn log_metrics() -> &'static LogMetrics {
+    &LOG_METRICS
+}
+
+/// Render all the metrics of this process in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = store_metrics().render();
+    out.push_str(&runtime_metrics().render());
+    out.push_str(&pod_metrics().render());
+    out.push_str(&log_metrics().render());
+    out.push_str(&plugin_metrics().render());
+    out
+}
+
+type MetricsSource = Box<dyn Fn() -> String + Send + Sync>;
+
+/// The metrics served at `/metrics`: the Kubelet's own, followed by those of the sources
+/// registered by the embedder of the Kubelet, e.g. to export the metrics of its own subsystems
+/// from the same endpoint.
+///
+/// Each source is called whenever the metrics are scraped, and should return quickly. Clones
+/// share the same sources, so sources can be registered while the Kubelet is running.
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    sources: Arc<RwLock<Vec<MetricsSource>>>,
+}
+
+impl std::fmt::Debug for MetricsRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sources = self.sources.read().unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("MetricsRegistry")
+            .field("sources", &sources.len())
+            .finish()
+    }
+}
+
+impl MetricsRegistry {
+    /// Serve the metrics the source renders in the Prometheus text format, after the Kubelet's
+    /// own. The source must not render metrics with the same names as the Kubelet's.
+    pub fn register<F>(&self, source: F)
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.sources
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Box::new(source));
+    }
+
+    /// Render the metrics of this process and of every registered source in the Prometheus
+    /// text format.
+    pub fn render(&self) -> String {
+        let mut out = render();
+        let sources = self.sources.read().unwrap_or_else(PoisonError::into_inner);
+        for source in sources.iter() {
+            let metrics = source();
+            out.push_str(&metrics);
+            if !metrics.is_empty() && !metrics.ends_with('\n') {
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+/// Measurements of how responsive the async runtime is, and of the work providers run on its
+/// blocking thread pool.
+#[derive(Debug, Default)]
+pub struct RuntimeMetrics {
+    probes: AtomicU64,
+    scheduling_delay_micros: AtomicU64,
+    max_scheduling_delay_micros: AtomicU64,
+    blocking_queued: AtomicI64,
+    blocking_running: AtomicI64,
+    blocking_started: AtomicU64,
+    blocking_wait_micros: AtomicU64,
+}
+
+impl RuntimeMetrics {
+    /// Record how much later than asked a task was woken up.
+    pub fn record_scheduling_delay(&self, delay: Duration) {
+        let micros = delay.as_micros() as u64;
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        self.scheduling_delay_micros
+            .fetch_add(micros, Ordering::Relaxed);
+        self.max_scheduling_delay_micros
+            .fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Record a task submitted to the blocking thread pool (e.g. with
+    /// `tokio::task::spawn_blocking`). Call [`QueuedBlockingTask::start`] first thing in the task.
+    pub fn blocking_task_queued(&'static self) -> QueuedBlockingTask {
+        self.blocking_queued.fetch_add(1, Ordering::Relaxed);
+        QueuedBlockingTask {
+            metrics: self,
+            queued_at: Instant::now(),
+            started: false,
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "krustlet_runtime_scheduling_delay_seconds_sum",
+            "counter",
+            "Total time probe tasks were woken up later than scheduled.",
+            micros_to_secs(self.scheduling_delay_micros.load(Ordering::Relaxed)),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_scheduling_delay_seconds_count",
+            "counter",
+            "Number of times the scheduling delay was measured.",
+            self.probes.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_scheduling_delay_seconds_max",
+            "gauge",
+            "Longest time a probe task was woken up later than scheduled.",
+            micros_to_secs(self.max_scheduling_delay_micros.load(Ordering::Relaxed)),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_blocking_tasks_queued",
+            "gauge",
+            "Tasks waiting for a thread of the blocking pool.",
+            self.blocking_queued.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_blocking_tasks_running",
+            "gauge",
+            "Tasks running on the blocking pool, such as WebAssembly modules.",
+            self.blocking_running.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_blocking_wait_seconds_sum",
+            "counter",
+            "Total time tasks waited for a thread of the blocking pool.",
+            micros_to_secs(self.blocking_wait_micros.load(Ordering::Relaxed)),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_runtime_blocking_wait_seconds_count",
+            "counter",
+            "Number of tasks started on the blocking pool.",
+            self.blocking_started.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// A task waiting for a thread of the blocking pool. See
+/// [`RuntimeMetrics::blocking_task_queued`].
+#[derive(Debug)]
+pub struct QueuedBlockingTask {
+    metrics: &'static RuntimeMetrics,
+    queued_at: Instant,
+    started: bool,
+}
+
+impl QueuedBlockingTask {
+    /// Record that the task started running. The task counts as running until the returned
+    /// value is dropped.
+    pub fn start(mut self) -> RunningBlockingTask {
+        self.started = true;
+        let metrics = self.metrics;
+        metrics.blocking_queued.fetch_sub(1, Ordering::Relaxed);
+        metrics.blocking_running.fetch_add(1, Ordering::Relaxed);
+        metrics.blocking_started.fetch_add(1, Ordering::Relaxed);
+        metrics.blocking_wait_micros.fetch_add(
+            self.queued_at.elapsed().as_micros() as u64,
+            Ordering::Relaxed,
+        );
+        RunningBlockingTask { metrics }
+    }
+}
+
+impl Drop for QueuedBlockingTask {
+    fn drop(&mut self) {
+        // The task was cancelled before it started
+        if !self.started {
+            self.metrics.blocking_queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A task running on the blocking pool. See [`QueuedBlockingTask::start`].
+#[derive(Debug)]
+pub struct RunningBlockingTask {
+    metrics: &'static RuntimeMetrics,
+}
+
+impl Drop for RunningBlockingTask {
+    fn drop(&mut self) {
+        self.metrics
+            .blocking_running
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters for the work done by the modules of each pod on the node, and for the updates of
+/// the pods' manifests.
+#[derive(Debug, Default)]
+pub struct PodMetrics {
+    fuel_consumed: Mutex<BTreeMap<(String, String), u64>>,
+    updates_dispatched: AtomicU64,
+    updates_skipped: AtomicU64,
+    startups: Mutex<BTreeMap<String, NamespaceStartups>>,
+}
+
+/// How long the pods of a namespace waited to start and took to start.
+#[derive(Debug, Default)]
+struct NamespaceStartups {
+    waits: u64,
+    wait_micros: u64,
+    startups: u64,
+    startup_micros: u64,
+}
+
+impl PodMetrics {
+    /// Record fuel consumed by a module of the given pod, for providers that meter the
+    /// instructions modules run with fuel.
+    pub fn record_fuel_consumed(&self, namespace: &str, pod: &str, fuel: u64) {
+        *self
+            .fuel_consumed
+            .lock()
+            .unwrap()
+            .entry((namespace.to_owned(), pod.to_owned()))
+            .or_default() += fuel;
+    }
+
+    /// Record an update of a pod's manifest, which was either dispatched to the pod's containers
+    /// or skipped because the manifest hadn't changed. See
+    /// [`ManifestChanges`](crate::pod::ManifestChanges).
+    pub fn record_update(&self, dispatched: bool) {
+        let counter = if dispatched {
+            &self.updates_dispatched
+        } else {
+            &self.updates_skipped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long a pod of the namespace waited for a place among the starting pods. See
+    /// [`StartupLimiter`](crate::state::common::startup::StartupLimiter).
+    pub fn record_startup_wait(&self, namespace: &str, wait: Duration) {
+        let mut startups = self.startups.lock().unwrap();
+        let startups = startups.entry(namespace.to_owned()).or_default();
+        startups.waits += 1;
+        startups.wait_micros += wait.as_micros() as u64;
+    }
+
+    /// Record how long a pod of the namespace held its place among the starting pods.
+    pub fn record_startup(&self, namespace: &str, duration: Duration) {
+        let mut startups = self.startups.lock().unwrap();
+        let startups = startups.entry(namespace.to_owned()).or_default();
+        startups.startups += 1;
+        startups.startup_micros += duration.as_micros() as u64;
+    }
+
+    /// Forget the counters of a pod that has been removed from the node, so that they are no
+    /// longer served.
+    pub fn remove_pod(&self, namespace: &str, pod: &str) {
+        self.fuel_consumed
+            .lock()
+            .unwrap()
+            .remove(&(namespace.to_owned(), pod.to_owned()));
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let name = "krustlet_pod_fuel_consumed_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Fuel consumed by the modules of each pod, roughly one unit per instruction.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for ((namespace, pod), fuel) in self.fuel_consumed.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{namespace=\"{}\",pod=\"{}\"}} {}",
+                name,
+                escape_label(namespace),
+                escape_label(pod),
+                fuel
+            );
+        }
+        write_metric(
+            &mut out,
+            "krustlet_pod_updates_dispatched_total",
+            "counter",
+            "Updates of pod manifests dispatched to the pods' containers.",
+            self.updates_dispatched.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_pod_updates_skipped_total",
+            "counter",
+            "Updates of pod manifests skipped because the manifest was unchanged, e.g. when pods are relisted.",
+            self.updates_skipped.load(Ordering::Relaxed),
+        );
+        let startups = self.startups.lock().unwrap();
+        let per_namespace: [(&str, &str, fn(&NamespaceStartups) -> String); 4] = [
+            (
+                "krustlet_pod_startup_waits_total",
+                "Pods that waited for a place among the starting pods, by namespace.",
+                |s| s.waits.to_string(),
+            ),
+            (
+                "krustlet_pod_startup_wait_seconds_total",
+                "Time pods waited for a place among the starting pods, by namespace.",
+                |s| micros_to_secs(s.wait_micros).to_string(),
+            ),
+            (
+                "krustlet_pod_startups_total",
+                "Pods that gave up their place among the starting pods, by namespace.",
+                |s| s.startups.to_string(),
+            ),
+            (
+                "krustlet_pod_startup_seconds_total",
+                "Time pods held their place among the starting pods, by namespace.",
+                |s| micros_to_secs(s.startup_micros).to_string(),
+            ),
+        ];
+        for (name, help, value) in per_namespace.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (namespace, namespace_startups) in startups.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{namespace=\"{}\"}} {}",
+                    name,
+                    escape_label(namespace),
+                    value(namespace_startups)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Counters for the integrity of container logs.
+#[derive(Debug, Default)]
+pub struct LogMetrics {
+    incomplete_logs: AtomicU64,
+}
+
+impl LogMetrics {
+    /// Record a log that was found to have lost output, e.g. to a crash of the node. See
+    /// [`LogIndex::check`](crate::log::LogIndex::check).
+    pub fn record_incomplete_log(&self) {
+        self.incomplete_logs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "krustlet_logs_incomplete_total",
+            "counter",
+            "Container logs found to be shorter than the output written to them.",
+            self.incomplete_logs.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Measure the scheduling delay of the runtime until the process exits.
+pub(crate) async fn probe_runtime() {
+    loop {
+        let start = Instant::now();
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        let delay = start.elapsed().saturating_sub(PROBE_INTERVAL);
+        runtime_metrics().record_scheduling_delay(delay);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn micros_to_secs(micros: u64) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+/// Write a single metric with its help and type lines.
+pub(crate) fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blocking_tasks() {
+        lazy_static::lazy_static! {
+            static ref METRICS: RuntimeMetrics = RuntimeMetrics::default();
+        }
+        let queued = METRICS.blocking_task_queued();
+        let cancelled = METRICS.blocking_task_queued();
+        assert_eq!(2, METRICS.blocking_queued.load(Ordering::Relaxed));
+
+        drop(cancelled);
+        let running = queued.start();
+        assert_eq!(0, METRICS.blocking_queued.load(Ordering::Relaxed));
+        assert_eq!(1, METRICS.blocking_running.load(Ordering::Relaxed));
+        assert_eq!(1, METRICS.blocking_started.load(Ordering::Relaxed));
+
+        drop(running);
+        assert_eq!(0, METRICS.blocking_running.load(Ordering::Relaxed));
+        assert!(METRICS
+            .render()
+            .contains("\nkrustlet_runtime_blocking_wait_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn test_registry_renders_sources_after_own_metrics() {
+        let registry = MetricsRegistry::default();
+        registry.register(|| "embedder_widgets_total 3".to_owned());
+        // Clones share the sources
+        registry
+            .clone()
+            .register(|| "embedder_gadgets_total 1\n".to_owned());
+        let rendered = registry.render();
+        assert!(rendered.contains("\nkrustlet_store_errors_total "));
+        assert!(rendered.ends_with("\nembedder_widgets_total 3\nembedder_gadgets_total 1\n"));
+    }
+
+    #[test]
+    fn test_scheduling_delay() {
+        let metrics = RuntimeMetrics::default();
+        metrics.record_scheduling_delay(Duration::from_millis(5));
+        metrics.record_scheduling_delay(Duration::from_millis(20));
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nkrustlet_runtime_scheduling_delay_seconds_sum 0.025\n"));
+        assert!(rendered.contains("\nkrustlet_runtime_scheduling_delay_seconds_count 2\n"));
+        assert!(rendered.contains("\nkrustlet_runtime_scheduling_delay_seconds_max 0.02\n"));
+    }
+
+    #[test]
+    fn test_pod_metrics() {
+        let metrics = PodMetrics::default();
+        metrics.record_fuel_consumed("default", "hello", 1000);
+        metrics.record_fuel_consumed("default", "hello", 500);
+        metrics.record_fuel_consumed("kube-system", "dns", 10);
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "\nkrustlet_pod_fuel_consumed_total{namespace=\"default\",pod=\"hello\"} 1500\n"
+        ));
+        assert!(rendered.contains(
+            "\nkrustlet_pod_fuel_consumed_total{namespace=\"kube-system\",pod=\"dns\"} 10\n"
+        ));
+
+        metrics.remove_pod("default", "hello");
+        assert!(!metrics.render().contains("pod=\"hello\""));
+
+        metrics.record_update(true);
+        metrics.record_update(false);
+        metrics.record_update(false);
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nkrustlet_pod_updates_dispatched_total 1\n"));
+        assert!(rendered.contains("\nkrustlet_pod_updates_skipped_total 2\n"));
+
+        metrics.record_startup_wait("default", Duration::from_millis(250));
+        metrics.record_startup("default", Duration::from_secs(2));
+        metrics.record_startup("default", Duration::from_secs(1));
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nkrustlet_pod_startup_waits_total{namespace=\"default\"} 1\n"));
+        assert!(rendered
+            .contains("\nkrustlet_pod_startup_wait_seconds_total{namespace=\"default\"} 0.25\n"));
+        assert!(rendered.contains("\nkrustlet_pod_startups_total{namespace=\"default\"} 2\n"));
+        assert!(
+            rendered.contains("\nkrustlet_pod_startup_seconds_total{namespace=\"default\"} 3\n")
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, PoisonError, RwLock};
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::NodeCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+/// The condition types the Kubelet reports itself or that Kubernetes reserves for the Kubelet.
+const RESERVED_CONDITION_TYPES: &[&str] = &[
+    "Ready",
+    "OutOfDisk",
+    "MemoryPressure",
+    "DiskPressure",
+    "PIDPressure",
+    "NetworkUnavailable",
+];
+
+/// Whether a node condition holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConditionStatus {
+    /// The condition holds.
+    True,
+    /// The condition does not hold.
+    False,
+    /// It is not known whether the condition holds, e.g. because it could not be checked.
+    Unknown,
+}
+
+impl ConditionStatus {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ConditionStatus::True => "True",
+            ConditionStatus::False => "False",
+            ConditionStatus::Unknown => "Unknown",
+        }
+    }
+}
+
+/// The current state of a custom node condition, as reported by its supplier.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConditionState {
+    /// Whether the condition holds.
+    pub status: ConditionStatus,
+    /// A brief CamelCase reason for the status, e.g. `SensorBusResponding`.
+    pub reason: String,
+    /// A human readable explanation of the status.
+    pub message: String,
+}
+
+impl ConditionState {
+    /// A state with the given status, reason and message.
+    pub fn new(status: ConditionStatus, reason: &str, message: &str) -> Self {
+        ConditionState {
+            status,
+            reason: reason.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+type Supplier = Box<dyn Fn() -> ConditionState + Send + Sync>;
+
+/// The status of a condition when it was last reported, and when it last changed.
+type LastReported = Option<(ConditionStatus, DateTime<Utc>)>;
+
+/// The node condition of the given type in the given state, keeping the time it last changed in
+/// `last`.
+fn node_condition(
+    type_: &str,
+    state: ConditionState,
+    last: &mut LastReported,
+    now: DateTime<Utc>,
+) -> NodeCondition {
+    let transitioned_at = match *last {
+        Some((status, at)) if status == state.status => at,
+        _ => now,
+    };
+    *last = Some((state.status, transitioned_at));
+    NodeCondition {
+        type_: type_.to_owned(),
+        status: state.status.as_str().to_owned(),
+        reason: Some(state.reason),
+        message: Some(state.message),
+        last_heartbeat_time: Some(Time(now)),
+        last_transition_time: Some(Time(transitioned_at)),
+    }
+}
+
+struct Entry {
+    type_: String,
+    supplier: Supplier,
+    last: LastReported,
+}
+
+impl Entry {
+    /// The current state from the supplier, or `Unknown` if the supplier panics, so that one
+    /// broken supplier doesn't stop the other conditions or the node status from being reported.
+    fn state(&self) -> ConditionState {
+        catch_unwind(AssertUnwindSafe(|| (self.supplier)())).unwrap_or_else(|_| {
+            ConditionState::new(
+                ConditionStatus::Unknown,
+                "SupplierPanicked",
+                "the supplier of the condition panicked",
+            )
+        })
+    }
+}
+
+/// Custom conditions reported in the status of the node, such as `GPUAttached`, for health that
+/// only the embedder of the Kubelet knows about.
+///
+/// Each condition has a supplier that is called whenever the Kubelet updates the node status,
+/// and should return quickly. Clones share the same conditions, so conditions can be registered
+/// while the Kubelet is running.
+#[derive(Clone, Default)]
+pub struct NodeConditions {
+    entries: Arc<RwLock<Vec<Entry>>>,
+    /// The conditions reported by the provider, by type.
+    provided: Arc<RwLock<HashMap<String, LastReported>>>,
+}
+
+impl std::fmt::Debug for NodeConditions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+        f.debug_list()
+            .entries(entries.iter().map(|entry| &entry.type_))
+            .finish()
+    }
+}
+
+impl NodeConditions {
+    /// Report the condition of the given type with the state returned by the supplier,
+    /// replacing any condition of the same type registered before. Fails for the condition
+    /// types the Kubelet reports itself, such as `Ready`.
+    pub fn register<F>(&self, type_: &str, supplier: F) -> anyhow::Result<()>
+    where
+        F: Fn() -> ConditionState + Send + Sync + 'static,
+    {
+        if type_.is_empty() {
+            anyhow::bail!("node condition type must not be empty");
+        }
+        if RESERVED_CONDITION_TYPES.contains(&type_) {
+            anyhow::bail!("node condition {} is reported by the Kubelet", type_);
+        }
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        entries.retain(|entry| entry.type_ != type_);
+        entries.push(Entry {
+            type_: type_.to_owned(),
+            supplier: Box::new(supplier),
+            last: None,
+        });
+        Ok(())
+    }
+
+    /// Stop reporting the condition of the given type. The condition stays in the node status
+    /// with its last state.
+    pub fn unregister(&self, type_: &str) {
+        self.entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|entry| entry.type_ != type_);
+    }
+
+    /// Ask every supplier for the current state of its condition. Conditions whose supplier
+    /// panics are reported as `Unknown`.
+    pub(crate) fn poll(&self, now: DateTime<Utc>) -> Vec<NodeCondition> {
+        let mut entries = self.entries.write().unwrap_or_else(PoisonError::into_inner);
+        entries
+            .iter_mut()
+            .map(|entry| {
+                let state = entry.state();
+                node_condition(&entry.type_, state, &mut entry.last, now)
+            })
+            .collect()
+    }
+
+    /// The node conditions for the states reported by the provider, keyed by type. Unlike
+    /// registered conditions, these may be the ones the Kubelet reports itself, such as `Ready`.
+    pub(crate) fn provided(
+        &self,
+        states: BTreeMap<String, ConditionState>,
+        now: DateTime<Utc>,
+    ) -> Vec<NodeCondition> {
+        let mut provided = self
+            .provided
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        states
+            .into_iter()
+            .map(|(type_, state)| {
+                let last = provided.entry(type_.clone()).or_default();
+                node_condition(&type_, state, last, now)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_reserved_conditions_are_rejected() {
+        let conditions = NodeConditions::default();
+        let supplier = || ConditionState::new(ConditionStatus::True, "Fine", "all good");
+        assert!(conditions.register("Ready", supplier).is_err());
+        assert!(conditions.register("", supplier).is_err());
+        assert!(conditions.register("SensorBusHealthy", supplier).is_ok());
+    }
+
+    #[test]
+    fn test_poll_tracks_transitions() {
+        let conditions = NodeConditions::default();
+        let attached = Arc::new(AtomicBool::new(true));
+        let gpu = attached.clone();
+        conditions
+            .register("GPUAttached", move || {
+                if gpu.load(Ordering::Relaxed) {
+                    ConditionState::new(ConditionStatus::True, "GPUFound", "GPU is attached")
+                } else {
+                    ConditionState::new(ConditionStatus::False, "GPUMissing", "no GPU found")
+                }
+            })
+            .unwrap();
+
+        let first = Utc::now();
+        let polled = conditions.poll(first);
+        assert_eq!(1, polled.len());
+        assert_eq!("GPUAttached", polled[0].type_);
+        assert_eq!("True", polled[0].status);
+        assert_eq!(Some(Time(first)), polled[0].last_transition_time);
+
+        let second = first + chrono::Duration::seconds(10);
+        let polled = conditions.poll(second);
+        assert_eq!(Some(Time(second)), polled[0].last_heartbeat_time);
+        assert_eq!(Some(Time(first)), polled[0].last_transition_time);
+
+        attached.store(false, Ordering::Relaxed);
+        let third = second + chrono::Duration::seconds(10);
+        let polled = conditions.poll(third);
+        assert_eq!("False", polled[0].status);
+        assert_eq!(Some("GPUMissing".to_owned()), polled[0].reason);
+        assert_eq!(Some(Time(third)), polled[0].last_transition_time);
+
+        conditions.unregister("GPUAttached");
+        assert!(conditions.poll(third).is_empty());
+    }
+
+    #[test]
+    fn test_panicking_supplier_is_reported_unknown() {
+        let conditions = NodeConditions::default();
+        conditions
+            .register("SensorBusHealthy", || panic!("sensor bus went away"))
+            .unwrap();
+        conditions
+            .register("GPUAttached", || {
+                ConditionState::new(ConditionStatus::True, "GPUFound", "GPU is attached")
+            })
+            .unwrap();
+
+        let now = Utc::now();
+        let polled = conditions.poll(now);
+        assert_eq!("Unknown", polled[0].status);
+        assert_eq!(Some("SupplierPanicked".to_owned()), polled[0].reason);
+        assert_eq!("True", polled[1].status);
+
+        // The conditions are still usable afterwards
+        conditions.unregister("SensorBusHealthy");
+        assert_eq!(1, conditions.poll(now).len());
+        assert_eq!("[\"GPUAttached\"]", format!("{:?}", conditions));
+    }
+
+    #[test]
+    fn test_provided_conditions_track_transitions() {
+        let conditions = NodeConditions::default();
+        let states = |status| {
+            let mut states = BTreeMap::new();
+            states.insert(
+                "MemoryPressure".to_owned(),
+                ConditionState::new(status, "KubeletHasSufficientMemory", "enough memory"),
+            );
+            states
+        };
+
+        let first = Utc::now();
+        let provided = conditions.provided(states(ConditionStatus::False), first);
+        assert_eq!("MemoryPressure", provided[0].type_);
+        assert_eq!(Some(Time(first)), provided[0].last_transition_time);
+
+        let second = first + chrono::Duration::seconds(10);
+        let provided = conditions.provided(states(ConditionStatus::False), second);
+        assert_eq!(Some(Time(first)), provided[0].last_transition_time);
+
+        let third = second + chrono::Duration::seconds(10);
+        let provided = conditions.provided(states(ConditionStatus::True), third);
+        assert_eq!("True", provided[0].status);
+        assert_eq!(Some(Time(third)), provided[0].last_transition_time);
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use super::{node_labels_definition, Builder};
+use crate::config::Config;
+
+/// Information about the node the Kubelet registers, for providers that need it e.g. to label
+/// logs, bind addresses or report pod IPs, without deriving it from the [`Config`] themselves.
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    /// The node's name
+    pub name: String,
+    /// The hostname of the node
+    pub hostname: String,
+    /// The IP addresses the node is exposed on
+    pub ips: Vec<IpAddr>,
+    /// The labels the node is registered with, including the ones the Kubelet adds itself
+    pub labels: BTreeMap<String, String>,
+    /// The architecture of the node, as reported in its `kubernetes.io/arch` label
+    pub architecture: String,
+    /// The directory where the Kubelet stores data
+    pub data_dir: PathBuf,
+    /// The directory the Kubelet watches for plugin sockets
+    pub plugins_dir: PathBuf,
+    /// The directory where device plugins register and host their services
+    pub device_plugins_dir: PathBuf,
+}
+
+impl NodeInfo {
+    /// Create the node information for a Kubelet with the given configuration, running a provider
+    /// with the given architecture (usually [`Provider::ARCH`](crate::provider::Provider::ARCH)).
+    pub fn new(config: &Config, arch: &str) -> Self {
+        let mut builder = Builder::default();
+        node_labels_definition(arch, config, &mut builder);
+        NodeInfo {
+            name: config.node_name.clone(),
+            hostname: config.hostname.clone(),
+            ips: vec![config.node_ip],
+            labels: builder.labels,
+            architecture: arch.to_owned(),
+            data_dir: config.data_dir.clone(),
+            plugins_dir: config.plugins_dir.clone(),
+            device_plugins_dir: config.device_plugins_dir.clone(),
+        }
+    }
+}
@@ -2,7 +2,7 @@
 //! nodes operating within the cluster.
 use crate::config::Config;
 use crate::container::Status as ContainerStatus;
-use crate::pod::{Phase, Pod};
+use crate::pod::{node_shutdown_status, Phase, Pod};
 use crate::provider::Provider;
 use chrono::prelude::*;
 use futures::{StreamExt, TryStreamExt};
@@ -11,13 +11,26 @@ use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
-use kube::api::{Api, ListParams, ObjectMeta, PatchParams, PostParams};
+use kube::api::{Api, DeleteParams, EvictParams, ListParams, ObjectMeta, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::Error;
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument, trace, warn};
 
+mod conditions;
+mod info;
+mod shutdown;
+mod status;
+
+pub use conditions::{ConditionState, ConditionStatus, NodeConditions};
+pub use info::NodeInfo;
+pub use shutdown::{
+    watch_shutdown_requests, ShutdownPhase, ShutdownRequest, ShutdownStatus,
+    SHUTDOWN_REQUESTED_ANNOTATION, SHUTDOWN_STATUS_ANNOTATION,
+};
+pub use status::NodeStatus;
+
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 macro_rules! retry {
@@ -97,7 +110,7 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
 
     node_labels_definition(P::ARCH, &config, &mut builder);
 
-    // TODO Do we want to detect this?
+    // These are the defaults for the resources the provider doesn't report
     builder.add_capacity("cpu", "4");
     builder.add_capacity("ephemeral-storage", "61255492Ki");
     builder.add_capacity("hugepages-1Gi", "0");
@@ -112,8 +125,18 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     builder.add_allocatable("memory", "4032800Ki");
     builder.add_allocatable("pods", &config.max_pods.to_string());
 
+    let status = provider_node_status(&*provider).await;
+    for (resource, quantity) in status.capacity() {
+        builder.add_capacity(resource, &quantity.0);
+    }
+    for (resource, quantity) in status.allocatable() {
+        builder.add_allocatable(&resource, &quantity.0);
+    }
+
     let ts = Utc::now();
-    builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
+    if !status.has_condition("Ready") {
+        builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
+    }
     builder.add_condition(
         "OutOfDisk",
         "False",
@@ -121,6 +144,15 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
         "KubeletHasSufficientDisk",
         "kubelet has sufficient disk space available",
     );
+    for (type_, state) in status.into_conditions() {
+        builder.add_condition(
+            &type_,
+            state.status.as_str(),
+            &ts,
+            &state.reason,
+            &state.message,
+        );
+    }
 
     builder.add_address("InternalIP", &format!("{}", config.node_ip));
     builder.add_address("Hostname", &config.hostname);
@@ -184,6 +216,32 @@ pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()>
 /// Fetches list of pods on this node and deletes them.
 #[instrument(level = "info", skip(client))]
 pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let (pods, mut stream) = pods_to_evict(client, node_name).await?;
+
+    info!(num_pods = pods.len(), "Evicting pods");
+
+    for pod in pods {
+        if let Err(e) = evict(client, &pod, &mut stream, None).await {
+            // Absorb the error and attempt to delete other pods with best effort.
+            error!(error = %e, "Error evicting pod")
+        }
+    }
+    Ok(())
+}
+
+type PodStream = std::pin::Pin<
+    Box<
+        dyn futures::Stream<Item = Result<kube::api::WatchEvent<KubePod>, kube::error::Error>>
+            + Send,
+    >,
+>;
+
+/// Lists the pods on this node in the order they are evicted, along with a watch of their
+/// deletions.
+async fn pods_to_evict(
+    client: &kube::Client,
+    node_name: &str,
+) -> anyhow::Result<(Vec<Pod>, PodStream)> {
     let pod_client: Api<KubePod> = Api::all(client.clone());
     let node_selector = format!("spec.nodeName={}", node_name);
     let params = ListParams {
@@ -195,126 +253,241 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
     let lp = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
 
     // The delete call may return a "pending" response, we must watch for the actual delete event.
-    let mut stream = pod_client.watch(&lp, "0").await?.boxed();
-
-    info!(num_pods = pods.len(), "Evicting pods");
+    let stream = pod_client.watch(&lp, "0").await?.boxed();
 
-    for pod in pods {
-        let pod = Pod::from(pod);
-        if pod.is_daemonset() {
-            info!(pod_name = pod.name(), "Skipping eviction of DaemonSet pod");
-            continue;
-        } else if pod.is_static() {
-            let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
-            let patch = serde_json::json!(
-                {
-                    "metadata": {
-                        "resourceVersion": "",
-                    },
-                    "status": {
-                        "phase": Phase::Succeeded,
-                        "reason": "Pod terminated on node shutdown.",
-                        "containerStatuses": pod.all_containers().iter().map(|container| {
-                            ContainerStatus::Terminated {
-                                timestamp: Utc::now(),
-                                message: "Evicted on node shutdown".to_string(),
-                                failed: false
-                            }.to_kubernetes(container.name())
-                        }).collect::<Vec<KubeContainerStatus>>()
-                    }
-                }
-            );
-            api.patch_status(
-                &pod.name(),
-                &PatchParams::default(),
-                &kube::api::Patch::Strategic(patch),
-            )
-            .await?;
+    // Evict the least important pods first, so that Guaranteed pods keep running for as long as
+    // possible.
+    let mut pods: Vec<Pod> = pods.into_iter().map(Pod::from).collect();
+    pods.sort_by_key(|pod| std::cmp::Reverse(pod.qos_class()));
+    Ok((pods, stream))
+}
 
-            info!("Marked static pod as terminated");
-            continue;
-        } else {
-            match evict_pod(&client, pod.name(), pod.namespace(), &mut stream).await {
-                Ok(_) => (),
-                Err(e) => {
-                    // Absorb the error and attempt to delete other pods with best effort.
-                    error!(error = %e, "Error evicting pod")
+/// Evicts a pod from the node. DaemonSet pods are left running, and static pods, which can't be
+/// deleted through the API, are marked as terminated instead. If a grace period is given, pods
+/// are deleted with it in place of their own.
+async fn evict(
+    client: &kube::Client,
+    pod: &Pod,
+    stream: &mut PodStream,
+    grace_period_seconds: Option<u32>,
+) -> anyhow::Result<()> {
+    if pod.is_daemonset() {
+        info!(pod_name = pod.name(), "Skipping eviction of DaemonSet pod");
+    } else if pod.is_static() {
+        let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+        let patch = serde_json::json!(
+            {
+                "metadata": {
+                    "resourceVersion": "",
+                },
+                "status": {
+                    "phase": Phase::Succeeded,
+                    "reason": "Pod terminated on node shutdown.",
+                    "containerStatuses": pod.all_containers().iter().map(|container| {
+                        ContainerStatus::Terminated {
+                            timestamp: Utc::now(),
+                            message: "Evicted on node shutdown".to_string(),
+                            failed: false
+                        }.to_kubernetes(container.name())
+                    }).collect::<Vec<KubeContainerStatus>>()
                 }
             }
-        }
+        );
+        api.patch_status(
+            &pod.name(),
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(patch),
+        )
+        .await?;
+
+        info!("Marked static pod as terminated");
+    } else {
+        evict_pod(
+            client,
+            pod.name(),
+            pod.namespace(),
+            stream,
+            grace_period_seconds,
+        )
+        .await?;
     }
     Ok(())
 }
 
-type PodStream = std::pin::Pin<
-    Box<
-        dyn futures::Stream<Item = Result<kube::api::WatchEvent<KubePod>, kube::error::Error>>
-            + Send,
-    >,
->;
-
 #[instrument(level = "info", skip(client, stream))]
 async fn evict_pod(
     client: &kube::Client,
     name: &str,
     namespace: &str,
     stream: &mut PodStream,
+    grace_period_seconds: Option<u32>,
 ) -> anyhow::Result<()> {
     let ns_client: Api<KubePod> = Api::namespaced(client.clone(), namespace);
+    // Let the pod's state machine know it is terminated because the node is shutting down
+    let patch = serde_json::json!({ "status": node_shutdown_status() });
+    if let Err(e) = ns_client
+        .patch_status(
+            name,
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(patch),
+        )
+        .await
+    {
+        warn!(error = %e, "Unable to mark pod as terminated by node shutdown");
+    }
     info!("Evicting pod");
-    let params = Default::default();
+    let params = DeleteParams {
+        grace_period_seconds,
+        ..Default::default()
+    };
     let response = ns_client.delete(name, &params).await?;
 
     if response.is_left() {
-        // TODO Timeout?
-        info!("Waiting for pod eviction");
-        while let Some(event) = stream.try_next().await? {
-            if let kube::api::WatchEvent::Deleted(s) = event {
-                let pod = Pod::from(s);
-                if name == pod.name() && namespace == pod.namespace() {
-                    info!("Pod evicted");
-                    break;
-                }
-            }
-        }
+        wait_for_deletion(stream, name, namespace).await?;
     } else {
         info!("Pod evicted");
     }
     Ok(())
 }
 
+/// Whether the API server let a pod be evicted through the Eviction API.
+#[derive(Debug)]
+enum EvictionOutcome {
+    /// The pod was evicted and has been deleted.
+    Evicted,
+    /// Evicting the pod would break one of its PodDisruptionBudgets, so it has to be tried again
+    /// later.
+    Refused(String),
+}
+
+/// Evicts a pod through the Eviction API, as `kubectl drain` does, so that its
+/// PodDisruptionBudgets are respected and it is terminated as evicted. DaemonSet and static
+/// pods, which can't be evicted, are handled as by [`evict`].
+async fn evict_with_api(
+    client: &kube::Client,
+    pod: &Pod,
+    stream: &mut PodStream,
+    grace_period_seconds: Option<u32>,
+) -> anyhow::Result<EvictionOutcome> {
+    if pod.is_daemonset() || pod.is_static() {
+        evict(client, pod, stream, grace_period_seconds).await?;
+        return Ok(EvictionOutcome::Evicted);
+    }
+    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let params = EvictParams {
+        delete_options: Some(DeleteParams {
+            grace_period_seconds,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    info!(pod_name = pod.name(), "Evicting pod");
+    match ns_client.evict(pod.name(), &params).await {
+        Ok(_) => (),
+        Err(kube::Error::Api(e)) if e.code == 429 => {
+            return Ok(EvictionOutcome::Refused(e.message))
+        }
+        // The pod is already gone
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(EvictionOutcome::Evicted),
+        Err(e) => return Err(e.into()),
+    }
+    wait_for_deletion(stream, pod.name(), pod.namespace()).await?;
+    Ok(EvictionOutcome::Evicted)
+}
+
+/// Waits for the pod to be deleted, once it has finished terminating.
+async fn wait_for_deletion(
+    stream: &mut PodStream,
+    name: &str,
+    namespace: &str,
+) -> anyhow::Result<()> {
+    // TODO Timeout?
+    info!("Waiting for pod eviction");
+    while let Some(event) = stream.try_next().await? {
+        if let kube::api::WatchEvent::Deleted(s) = event {
+            let pod = Pod::from(s);
+            if name == pod.name() && namespace == pod.namespace() {
+                info!("Pod evicted");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The status of the node reported by the provider, or an empty status if the provider fails to
+/// report it.
+async fn provider_node_status<P: Provider>(provider: &P) -> NodeStatus {
+    provider.node_status().await.unwrap_or_else(|e| {
+        warn!(error = %e, "Unable to get node status from provider");
+        NodeStatus::default()
+    })
+}
+
 /// Update the timestamps on the Node object.
 ///
 /// This is how we report liveness to the upstream.
 /// If we are unable to update the node after several retries we panic, as we could be in an
-/// inconsistent state
-#[instrument(level = "info", skip(client))]
-pub async fn update(client: &kube::Client, node_name: &str) {
+/// inconsistent state. The resources and conditions reported by the provider's
+/// [`node_status`](Provider::node_status) and the custom `conditions` are reported along with
+/// the Kubelet's own.
+#[instrument(level = "info", skip(client, provider, conditions))]
+pub async fn update<P: Provider>(
+    client: &kube::Client,
+    node_name: &str,
+    provider: &P,
+    conditions: &NodeConditions,
+) {
     debug!("Updating node");
     if let Ok(uid) = uid(client, node_name).await {
         trace!("Fetched current node object to update");
         retry!(update_lease(&uid, node_name, client).await, times: 4)
             .expect("Could not update lease");
-        retry!(update_status(node_name, client).await, times: 4)
+        let status = provider_node_status(provider).await;
+        let status_patch = status_patch(status, conditions, Utc::now());
+        retry!(update_status(node_name, &status_patch, client).await, times: 4)
             .expect("Could not update node status");
     }
 }
 
-async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result<()> {
-    // TODO: Update the lastTransitionTime properly
-    let status_patch = serde_json::json!({
-        "status": {
-            "conditions": [
-                {
-                    "lastHeartbeatTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
-                    "message": "kubelet is posting ready status",
-                    "reason": "KubeletReady",
-                    "status": "True",
-                    "type": "Ready"
-                }
-            ],
-        }
+/// The patch to the status of the node with the resources and conditions reported by the
+/// provider, the custom conditions and the Kubelet's `Ready` condition, unless the provider
+/// reports it.
+fn status_patch(
+    status: NodeStatus,
+    conditions: &NodeConditions,
+    now: DateTime<Utc>,
+) -> serde_json::Value {
+    let capacity = status.capacity().clone();
+    let allocatable = status.allocatable();
+    let mut provided = status.into_conditions();
+    provided.entry("Ready".to_owned()).or_insert_with(|| {
+        ConditionState::new(
+            ConditionStatus::True,
+            "KubeletReady",
+            "kubelet is posting ready status",
+        )
     });
+    // Conditions are merged by type, so this leaves the ones of other reporters alone
+    let mut node_conditions = conditions.provided(provided, now);
+    node_conditions.extend(conditions.poll(now));
+
+    let mut status = serde_json::json!({ "conditions": node_conditions });
+    // Only the resources the provider reports are patched, leaving the defaults for the others
+    if !capacity.is_empty() {
+        status["capacity"] = serde_json::json!(capacity);
+    }
+    if !allocatable.is_empty() {
+        status["allocatable"] = serde_json::json!(allocatable);
+    }
+    serde_json::json!({ "status": status })
+}
+
+async fn update_status(
+    node_name: &str,
+    status_patch: &serde_json::Value,
+    client: &kube::Client,
+) -> anyhow::Result<()> {
     let node_client: Api<KubeNode> = Api::all(client.clone());
     let _node = node_client
         .patch_status(
@@ -327,6 +500,142 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
     Ok(())
 }
 
+/// The annotation on the Node object in which pod rejections are aggregated.
+///
+/// The value is a JSON object keyed by rejection reason so that cluster operators and
+/// scheduler extenders can avoid placing similar workloads on this node.
+pub const POD_REJECTIONS_ANNOTATION: &str = "krustlet.dev/pod-rejections";
+
+/// The maximum number of distinct rejection reasons kept in the node annotation. The least
+/// recently seen reasons are dropped first.
+const MAX_POD_REJECTION_REASONS: usize = 16;
+
+/// The number of pod UIDs remembered for each rejection reason, so that a pod that is retried
+/// is only counted once.
+const MAX_POD_REJECTION_UIDS: usize = 16;
+
+/// How many times recording a pod rejection is tried when the Node object changes under it.
+const POD_REJECTION_ATTEMPTS: usize = 5;
+
+/// An aggregated record of pods rejected by this node for a single reason.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodRejection {
+    /// The number of pods rejected for this reason.
+    pub count: u64,
+    /// The `namespace/name` of the most recently rejected pod.
+    pub last_pod: String,
+    /// When a pod was last rejected for this reason.
+    pub last_seen: DateTime<Utc>,
+    /// The UIDs of the most recently rejected pods, which keep retries of a pod from being
+    /// counted again.
+    #[serde(default)]
+    pub recent_pod_uids: Vec<String>,
+}
+
+/// Record that a pod was rejected by this node.
+///
+/// The reason is aggregated into the [`POD_REJECTIONS_ANNOTATION`] annotation on the Node
+/// object rather than creating a new object per rejection.
+#[instrument(level = "info", skip(client, pod, reason), fields(pod_name = pod.name()))]
+pub async fn record_pod_rejection(
+    client: &kube::Client,
+    node_name: &str,
+    pod: &Pod,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let pod_name = format!("{}/{}", pod.namespace(), pod.name());
+    for _ in 0..POD_REJECTION_ATTEMPTS {
+        let node = node_client.get(node_name).await?;
+        let existing = node
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(POD_REJECTIONS_ANNOTATION));
+        let rejections = merge_pod_rejection(
+            existing.map(String::as_str),
+            &pod_name,
+            pod.pod_uid(),
+            reason,
+            Utc::now(),
+        );
+        // The resource version makes the patch fail if another rejection was recorded since
+        // the node was read, rather than overwrite it
+        let patch = serde_json::json!({
+            "metadata": {
+                "resourceVersion": node.metadata.resource_version,
+                "annotations": {
+                    POD_REJECTIONS_ANNOTATION: serde_json::to_string(&rejections)?
+                }
+            }
+        });
+        match node_client
+            .patch(
+                node_name,
+                &PatchParams::default(),
+                &kube::api::Patch::Merge(patch),
+            )
+            .await
+        {
+            Ok(_) => {
+                debug!("Recorded pod rejection on node");
+                return Ok(());
+            }
+            Err(Error::Api(ErrorResponse { code: 409, .. })) => {
+                debug!("Node changed while recording pod rejection, retrying");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Node kept changing while recording pod rejection"
+    ))
+}
+fn merge_pod_rejection(
+    existing: Option<&str>,
+    pod: &str,
+    pod_uid: &str,
+    reason: &str,
+    now: DateTime<Utc>,
+) -> BTreeMap<String, PodRejection> {
+    // If someone has mangled the annotation we just start over rather than failing
+    let mut rejections: BTreeMap<String, PodRejection> = existing
+        .and_then(|value| serde_json::from_str(value).ok())
+        .unwrap_or_default();
+    let rejection = rejections
+        .entry(reason.to_owned())
+        .or_insert_with(|| PodRejection {
+            count: 0,
+            last_pod: String::new(),
+            last_seen: now,
+            recent_pod_uids: vec![],
+        });
+    if !rejection.recent_pod_uids.iter().any(|uid| uid == pod_uid) {
+        rejection.count += 1;
+        rejection.recent_pod_uids.push(pod_uid.to_owned());
+        if rejection.recent_pod_uids.len() > MAX_POD_REJECTION_UIDS {
+            rejection.recent_pod_uids.remove(0);
+        }
+    }
+    rejection.last_pod = pod.to_owned();
+    rejection.last_seen = now;
+
+    while rejections.len() > MAX_POD_REJECTION_REASONS {
+        let oldest = rejections
+            .iter()
+            .min_by_key(|(_, r)| r.last_seen)
+            .map(|(k, _)| k.clone());
+        match oldest {
+            Some(key) => {
+                rejections.remove(&key);
+            }
+            None => break,
+        }
+    }
+    rejections
+}
+
 /// Create a node lease
 ///
 /// These creates a new node lease and claims the node for a set
@@ -761,13 +1070,40 @@ mod test {
                 port: 8080,
                 cert_file: PathBuf::new(),
                 private_key_file: PathBuf::new(),
+                read_only_port: None,
+                attestation_token_file: None,
+                pod_preview_token_file: None,
             },
             bootstrap_file: "doesnt/matter".into(),
             allow_local_modules: false,
             insecure_registries: None,
+            insecure_skip_verify_registries: None,
+            registry_ca_file: None,
             data_dir: PathBuf::new(),
             plugins_dir: PathBuf::new(),
             device_plugins_dir: PathBuf::new(),
+            log_forward_url: None,
+            log_forward_destinations: None,
+            volume_plugins_dir: None,
+            oci_layout_root: None,
+            port_mapping_range: None,
+            max_pod_env_vars: None,
+            max_pod_volumes: None,
+            max_env_var_size: None,
+            crash_loop_threshold: None,
+            crash_loop_backoff_cap: None,
+            crash_loop_reset_after: None,
+            execution_threads: None,
+            check_allocatable: false,
+            registry_failover: None,
+            max_layer_size: None,
+            registry_max_retries: None,
+            module_cache_size: None,
+            diagnostics_module: None,
+            failure_output_lines: 10,
+            max_starting_pods: None,
+            max_starting_pods_per_namespace: None,
+            fuel_quantum: None,
             node_labels,
             max_pods: 110,
         };
@@ -783,5 +1119,84 @@ mod test {
         assert!(result.contains_key("kubernetes.io/instance-type"));
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
+
+        let info = NodeInfo::new(&config, "linux");
+        assert_eq!("bar", info.name);
+        assert_eq!(vec![IpAddr::from(Ipv4Addr::LOCALHOST)], info.ips);
+        assert_eq!(result, info.labels);
+    }
+
+    #[test]
+    fn test_merge_pod_rejection() {
+        let now = Utc::now();
+        let reason = "Cannot run kube-proxy";
+        let first = merge_pod_rejection(None, "default/foo", "uid-foo", reason, now);
+        assert_eq!(1, first.get(reason).unwrap().count);
+
+        let existing = serde_json::to_string(&first).unwrap();
+        let second = merge_pod_rejection(Some(&existing), "default/bar", "uid-bar", reason, now);
+        let rejection = second.get(reason).unwrap();
+        assert_eq!(2, rejection.count);
+        assert_eq!("default/bar", rejection.last_pod);
+
+        // Retrying a pod doesn't count it again
+        let existing = serde_json::to_string(&second).unwrap();
+        let retried = merge_pod_rejection(Some(&existing), "default/foo", "uid-foo", reason, now);
+        let rejection = retried.get(reason).unwrap();
+        assert_eq!(2, rejection.count);
+        assert_eq!("default/foo", rejection.last_pod);
+
+        let garbage =
+            merge_pod_rejection(Some("not json"), "default/foo", "uid-foo", "reason", now);
+        assert_eq!(1, garbage.len());
+    }
+
+    #[test]
+    fn test_merge_pod_rejection_drops_oldest_reasons() {
+        let start = Utc::now();
+        let mut existing: Option<String> = None;
+        for i in 0..=MAX_POD_REJECTION_REASONS {
+            let now = start + chrono::Duration::seconds(i as i64);
+            let merged = merge_pod_rejection(
+                existing.as_deref(),
+                "default/foo",
+                "uid-foo",
+                &format!("reason-{}", i),
+                now,
+            );
+            existing = Some(serde_json::to_string(&merged).unwrap());
+        }
+        let rejections: BTreeMap<String, PodRejection> =
+            serde_json::from_str(&existing.unwrap()).unwrap();
+        assert_eq!(MAX_POD_REJECTION_REASONS, rejections.len());
+        assert!(!rejections.contains_key("reason-0"));
+    }
+
+    #[test]
+    fn test_status_patch() {
+        let conditions = NodeConditions::default();
+        let patch = status_patch(NodeStatus::default(), &conditions, Utc::now());
+        assert_eq!("Ready", patch["status"]["conditions"][0]["type"]);
+        assert_eq!("KubeletReady", patch["status"]["conditions"][0]["reason"]);
+        assert!(patch["status"].get("capacity").is_none());
+
+        let mut status = NodeStatus::default();
+        status.add_capacity("cpu", "8");
+        status.add_condition(
+            "Ready",
+            ConditionState::new(ConditionStatus::False, "RuntimeDown", "runtime is down"),
+        );
+        status.add_condition(
+            "MemoryPressure",
+            ConditionState::new(ConditionStatus::True, "LowMemory", "memory is low"),
+        );
+        let patch = status_patch(status, &conditions, Utc::now());
+        let reported = patch["status"]["conditions"].as_array().unwrap();
+        assert_eq!(2, reported.len());
+        assert_eq!("MemoryPressure", reported[0]["type"]);
+        assert_eq!("Ready", reported[1]["type"]);
+        assert_eq!("False", reported[1]["status"]);
+        assert_eq!("8", patch["status"]["capacity"]["cpu"]);
+        assert_eq!("8", patch["status"]["allocatable"]["cpu"]);
     }
 }
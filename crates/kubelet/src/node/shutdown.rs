@@ -0,0 +1,499 @@
+//! A protocol for external agents, such as fleet managers orchestrating rolling reboots, to
+//! drain the node by annotating its Node object.
+//!
+//! An agent requests a drain by setting [`SHUTDOWN_REQUESTED_ANNOTATION`] to the number of
+//! seconds pods are given to shut down. The Kubelet cordons the node, evicts its pods with that
+//! grace period through the Eviction API and reports its progress in
+//! [`SHUTDOWN_STATUS_ANNOTATION`]. Pods whose PodDisruptionBudgets don't allow them to be evicted
+//! yet are tried again until they do, or the request is removed. Once the status
+//! reaches [`ShutdownPhase::Drained`] the node can be rebooted. Removing the request annotation
+//! clears the status and uncordons the node, unless it was already cordoned before the request.
+use std::str::FromStr;
+
+use chrono::prelude::*;
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::{Api, ListParams, PatchParams, WatchEvent};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use super::{evict_with_api, pods_to_evict, EvictionOutcome};
+
+/// The annotation on the Node object with which an external agent requests the node be drained.
+///
+/// The value is the grace period, in seconds, that each pod is given to shut down.
+pub const SHUTDOWN_REQUESTED_ANNOTATION: &str = "krustlet.dev/shutdown-requested";
+
+/// The annotation on the Node object in which the Kubelet reports the progress of a requested
+/// drain, as a JSON [`ShutdownStatus`].
+pub const SHUTDOWN_STATUS_ANNOTATION: &str = "krustlet.dev/shutdown-status";
+
+/// How long to wait before watching the node again after the watch fails.
+const WATCH_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long to wait before trying again to evict pods whose disruption budgets refused it.
+const EVICTION_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// A request to drain the node, read from the [`SHUTDOWN_REQUESTED_ANNOTATION`] annotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownRequest {
+    /// How long each pod is given to shut down, in seconds.
+    pub grace_period_seconds: u32,
+}
+
+impl FromStr for ShutdownRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let grace_period_seconds = value.trim().parse().map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid shutdown request {:?}: expected a grace period in seconds",
+                value
+            )
+        })?;
+        Ok(Self {
+            grace_period_seconds,
+        })
+    }
+}
+
+/// How far a requested drain has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShutdownPhase {
+    /// Pods are being evicted from the node.
+    Draining,
+    /// All pods, other than DaemonSet pods, have been evicted and the node can be shut down.
+    Drained,
+    /// The request was invalid or some pods could not be evicted. The request has to be removed
+    /// and made again to retry.
+    Failed,
+}
+
+/// The progress of a requested drain, reported in the [`SHUTDOWN_STATUS_ANNOTATION`]
+/// annotation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShutdownStatus {
+    /// How far the drain has got.
+    pub phase: ShutdownPhase,
+    /// The number of pods still to be evicted.
+    pub pods_remaining: usize,
+    /// Why the drain failed, or what it is waiting for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Whether the Kubelet cordoned the node, in which case it uncordons it once the request is
+    /// removed.
+    #[serde(default)]
+    pub cordoned: bool,
+    /// When the status was last updated.
+    pub last_update_time: DateTime<Utc>,
+}
+
+/// What the Kubelet has to do about the shutdown annotations on its node.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// Drain the node. `cordoned` is set if an earlier attempt already cordoned the node.
+    Drain {
+        request: ShutdownRequest,
+        cordoned: bool,
+    },
+    /// Report the request as failed because it is invalid.
+    Reject(String),
+    /// Clear the status of a removed request, uncordoning the node if the Kubelet cordoned it.
+    Withdraw { uncordon: bool },
+}
+
+fn next_action(request: Option<&str>, status: Option<&str>) -> Option<Action> {
+    // If someone has mangled the status we leave the node as it is rather than draining it again
+    let status = status.map(|value| serde_json::from_str::<ShutdownStatus>(value).ok());
+    match (request, status) {
+        (Some(request), None) => Some(match request.parse() {
+            Ok(request) => Action::Drain {
+                request,
+                cordoned: false,
+            },
+            Err(e) => Action::Reject(e.to_string()),
+        }),
+        // A drain that was interrupted, e.g. by the Kubelet restarting, is picked up again
+        (Some(request), Some(Some(status))) if status.phase == ShutdownPhase::Draining => {
+            request.parse().ok().map(|request| Action::Drain {
+                request,
+                cordoned: status.cordoned,
+            })
+        }
+        (Some(_), Some(_)) => None,
+        (None, Some(status)) => Some(Action::Withdraw {
+            uncordon: status.map(|s| s.cordoned).unwrap_or(false),
+        }),
+        (None, None) => None,
+    }
+}
+
+/// Watches the node for shutdown requests from external agents, draining it when one is made.
+///
+/// Errors are logged and the node is watched again, so this only returns if the task is
+/// cancelled.
+#[instrument(level = "info", skip(client))]
+pub async fn watch_shutdown_requests(
+    client: kube::Client,
+    node_name: String,
+) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    loop {
+        if let Err(e) = watch_node(&client, &node_client, &node_name).await {
+            warn!(error = %e, "Error watching node for shutdown requests, retrying");
+            tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+        }
+    }
+}
+
+/// Acts on the current state of the node, then on its changes until the watch ends. As acting
+/// on a request changes the node, the events that were queued up in the meantime are stale, so
+/// this returns after acting to start again from the node's current state.
+async fn watch_node(
+    client: &kube::Client,
+    node_client: &Api<KubeNode>,
+    node_name: &str,
+) -> anyhow::Result<()> {
+    let node = node_client.get(node_name).await?;
+    if handle_node(client, node_client, node_name, &node).await? {
+        return Ok(());
+    }
+
+    let resource_version = node
+        .metadata
+        .resource_version
+        .unwrap_or_else(|| "0".to_owned());
+    let params = ListParams::default().fields(&format!("metadata.name={}", node_name));
+    let mut stream = node_client.watch(&params, &resource_version).await?.boxed();
+    while let Some(event) = stream.try_next().await? {
+        match event {
+            WatchEvent::Added(node) | WatchEvent::Modified(node) => {
+                if handle_node(client, node_client, node_name, &node).await? {
+                    return Ok(());
+                }
+            }
+            WatchEvent::Error(e) => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Acts on the shutdown annotations of the node, returning whether anything was done.
+async fn handle_node(
+    client: &kube::Client,
+    node_client: &Api<KubeNode>,
+    node_name: &str,
+    node: &KubeNode,
+) -> anyhow::Result<bool> {
+    let annotation = |key: &str| {
+        node.metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(key))
+            .map(String::as_str)
+    };
+    let action = match next_action(
+        annotation(SHUTDOWN_REQUESTED_ANNOTATION),
+        annotation(SHUTDOWN_STATUS_ANNOTATION),
+    ) {
+        Some(action) => action,
+        None => return Ok(false),
+    };
+
+    match action {
+        Action::Drain { request, cordoned } => {
+            let unschedulable = node
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.unschedulable)
+                .unwrap_or(false);
+            // A node that someone else cordoned is left cordoned once the request is removed
+            drain_node(
+                client,
+                node_client,
+                node_name,
+                request,
+                cordoned || !unschedulable,
+            )
+            .await?;
+        }
+        Action::Reject(message) => {
+            warn!(%message, "Rejecting shutdown request");
+            set_status(
+                node_client,
+                node_name,
+                Some(&ShutdownStatus {
+                    phase: ShutdownPhase::Failed,
+                    pods_remaining: 0,
+                    message: Some(message),
+                    cordoned: false,
+                    last_update_time: Utc::now(),
+                }),
+            )
+            .await?;
+        }
+        Action::Withdraw { uncordon } => {
+            if uncordon {
+                set_unschedulable(node_client, node_name, false).await?;
+            }
+            set_status(node_client, node_name, None).await?;
+            info!("Shutdown request removed");
+        }
+    }
+    Ok(true)
+}
+
+#[instrument(level = "info", skip(client, node_client))]
+async fn drain_node(
+    client: &kube::Client,
+    node_client: &Api<KubeNode>,
+    node_name: &str,
+    request: ShutdownRequest,
+    cordon: bool,
+) -> anyhow::Result<()> {
+    info!("Draining node on request");
+    if cordon {
+        set_unschedulable(node_client, node_name, true).await?;
+    }
+
+    let (pods, mut stream) = pods_to_evict(client, node_name).await?;
+    // DaemonSet pods are not evicted, so are not waited for either
+    let pods: Vec<_> = pods.into_iter().filter(|pod| !pod.is_daemonset()).collect();
+    let status = |phase, pods_remaining, message| ShutdownStatus {
+        phase,
+        pods_remaining,
+        message,
+        cordoned: cordon,
+        last_update_time: Utc::now(),
+    };
+
+    let mut remaining = pods.len();
+    set_status(
+        node_client,
+        node_name,
+        Some(&status(ShutdownPhase::Draining, remaining, None)),
+    )
+    .await?;
+    let mut pending = pods;
+    loop {
+        let mut refused = Vec::new();
+        for pod in pending {
+            match evict_with_api(
+                client,
+                &pod,
+                &mut stream,
+                Some(request.grace_period_seconds),
+            )
+            .await
+            {
+                Ok(EvictionOutcome::Evicted) => remaining -= 1,
+                Ok(EvictionOutcome::Refused(message)) => {
+                    info!(%message, pod_name = pod.name(), "Pod eviction refused, retrying later");
+                    refused.push(pod);
+                    continue;
+                }
+                Err(e) => {
+                    // Keep evicting the other pods, the failure is reported once they are done
+                    error!(error = %e, pod_name = pod.name(), "Error evicting pod");
+                    continue;
+                }
+            }
+            if let Err(e) = set_status(
+                node_client,
+                node_name,
+                Some(&status(ShutdownPhase::Draining, remaining, None)),
+            )
+            .await
+            {
+                warn!(error = %e, "Unable to report drain progress");
+            }
+        }
+        if refused.is_empty() {
+            break;
+        }
+
+        // Pods whose disruption budgets don't allow them to be evicted yet are tried again
+        // until the budgets allow it, e.g. once replacements are running elsewhere
+        let message = format!(
+            "{} pods waiting for their disruption budgets to allow eviction",
+            refused.len()
+        );
+        if let Err(e) = set_status(
+            node_client,
+            node_name,
+            Some(&status(ShutdownPhase::Draining, remaining, Some(message))),
+        )
+        .await
+        {
+            warn!(error = %e, "Unable to report drain progress");
+        }
+        tokio::time::sleep(EVICTION_RETRY_INTERVAL).await;
+        if !drain_requested(node_client, node_name).await? {
+            info!("Shutdown request removed while draining");
+            return Ok(());
+        }
+        pending = refused;
+    }
+
+    let finished = if remaining == 0 {
+        info!("Node drained on request");
+        status(ShutdownPhase::Drained, 0, None)
+    } else {
+        let message = format!("{} pods could not be evicted", remaining);
+        error!(%message, "Unable to drain node on request");
+        status(ShutdownPhase::Failed, remaining, Some(message))
+    };
+    set_status(node_client, node_name, Some(&finished)).await
+}
+
+/// Whether the [`SHUTDOWN_REQUESTED_ANNOTATION`] annotation is still set on the node.
+async fn drain_requested(node_client: &Api<KubeNode>, node_name: &str) -> anyhow::Result<bool> {
+    let node = node_client.get(node_name).await?;
+    Ok(node.metadata.annotations.map_or(false, |annotations| {
+        annotations.contains_key(SHUTDOWN_REQUESTED_ANNOTATION)
+    }))
+}
+
+/// Sets the [`SHUTDOWN_STATUS_ANNOTATION`] annotation, or removes it if there is no status.
+async fn set_status(
+    node_client: &Api<KubeNode>,
+    node_name: &str,
+    status: Option<&ShutdownStatus>,
+) -> anyhow::Result<()> {
+    let value = match status {
+        Some(status) => serde_json::Value::String(serde_json::to_string(status)?),
+        None => serde_json::Value::Null,
+    };
+    let patch = serde_json::json!({
+        "metadata": {
+            "annotations": {
+                SHUTDOWN_STATUS_ANNOTATION: value
+            }
+        }
+    });
+    node_client
+        .patch(
+            node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Merge(patch),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Cordons or uncordons the node.
+async fn set_unschedulable(
+    node_client: &Api<KubeNode>,
+    node_name: &str,
+    unschedulable: bool,
+) -> anyhow::Result<()> {
+    let patch = serde_json::json!({
+        "spec": {
+            "unschedulable": unschedulable
+        }
+    });
+    node_client
+        .patch(
+            node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Merge(patch),
+        )
+        .await?;
+    info!(unschedulable, "Updated node schedulability");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(phase: ShutdownPhase, cordoned: bool) -> String {
+        serde_json::to_string(&ShutdownStatus {
+            phase,
+            pods_remaining: 2,
+            message: None,
+            cordoned,
+            last_update_time: Utc::now(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_shutdown_request() {
+        assert_eq!(
+            ShutdownRequest {
+                grace_period_seconds: 300
+            },
+            " 300".parse::<ShutdownRequest>().unwrap()
+        );
+        let e = "5m".parse::<ShutdownRequest>().unwrap_err();
+        assert_eq!(
+            "Invalid shutdown request \"5m\": expected a grace period in seconds",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_next_action() {
+        let request = ShutdownRequest {
+            grace_period_seconds: 30,
+        };
+        assert_eq!(None, next_action(None, None));
+        assert_eq!(
+            Some(Action::Drain {
+                request,
+                cordoned: false
+            }),
+            next_action(Some("30"), None)
+        );
+        assert!(matches!(
+            next_action(Some("soon"), None),
+            Some(Action::Reject(_))
+        ));
+
+        // An interrupted drain is resumed, remembering who cordoned the node
+        assert_eq!(
+            Some(Action::Drain {
+                request,
+                cordoned: true
+            }),
+            next_action(Some("30"), Some(&status(ShutdownPhase::Draining, true)))
+        );
+        assert_eq!(
+            None,
+            next_action(Some("30"), Some(&status(ShutdownPhase::Drained, true)))
+        );
+        assert_eq!(
+            None,
+            next_action(Some("30"), Some(&status(ShutdownPhase::Failed, false)))
+        );
+        assert_eq!(None, next_action(Some("30"), Some("mangled")));
+
+        assert_eq!(
+            Some(Action::Withdraw { uncordon: true }),
+            next_action(None, Some(&status(ShutdownPhase::Drained, true)))
+        );
+        assert_eq!(
+            Some(Action::Withdraw { uncordon: false }),
+            next_action(None, Some(&status(ShutdownPhase::Draining, false)))
+        );
+        assert_eq!(
+            Some(Action::Withdraw { uncordon: false }),
+            next_action(None, Some("mangled"))
+        );
+    }
+
+    #[test]
+    fn test_shutdown_status_format() {
+        let status: ShutdownStatus = serde_json::from_str(
+            r#"{"phase":"Draining","podsRemaining":3,"lastUpdateTime":"2021-06-01T12:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(ShutdownPhase::Draining, status.phase);
+        assert_eq!(3, status.pods_remaining);
+        assert!(!status.cordoned);
+        assert_eq!(None, status.message);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+use super::ConditionState;
+
+/// The resources and conditions of the node as the provider sees them, reported through
+/// [`Provider::node_status`](crate::provider::Provider::node_status).
+///
+/// Resources the provider doesn't report keep the Kubelet's defaults, and so does the `Ready`
+/// condition. Other conditions, such as `MemoryPressure`, `DiskPressure` or `PIDPressure`, are
+/// only reported if the provider reports them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NodeStatus {
+    capacity: BTreeMap<String, Quantity>,
+    allocatable: BTreeMap<String, Quantity>,
+    conditions: BTreeMap<String, ConditionState>,
+}
+
+impl NodeStatus {
+    /// Report the total amount of a resource of the node, e.g. `cpu`, `memory` or `pods`. The
+    /// amount allocatable to pods is the same unless it is set with
+    /// [`NodeStatus::add_allocatable`].
+    pub fn add_capacity(&mut self, resource: &str, quantity: &str) {
+        self.capacity
+            .insert(resource.to_owned(), Quantity(quantity.to_owned()));
+    }
+
+    /// Report the amount of a resource of the node that is allocatable to pods.
+    pub fn add_allocatable(&mut self, resource: &str, quantity: &str) {
+        self.allocatable
+            .insert(resource.to_owned(), Quantity(quantity.to_owned()));
+    }
+
+    /// Report a condition of the node, such as `Ready` or `MemoryPressure`.
+    pub fn add_condition(&mut self, type_: &str, state: ConditionState) {
+        self.conditions.insert(type_.to_owned(), state);
+    }
+
+    /// The reported capacity of the node.
+    pub(crate) fn capacity(&self) -> &BTreeMap<String, Quantity> {
+        &self.capacity
+    }
+
+    /// The reported allocatable resources of the node, including those only reported as
+    /// capacity.
+    pub(crate) fn allocatable(&self) -> BTreeMap<String, Quantity> {
+        let mut allocatable = self.capacity.clone();
+        allocatable.extend(self.allocatable.clone());
+        allocatable
+    }
+
+    /// Whether the provider reports the condition of the given type.
+    pub(crate) fn has_condition(&self, type_: &str) -> bool {
+        self.conditions.contains_key(type_)
+    }
+
+    /// The reported conditions, by type.
+    pub(crate) fn into_conditions(self) -> BTreeMap<String, ConditionState> {
+        self.conditions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocatable_defaults_to_capacity() {
+        let mut status = NodeStatus::default();
+        status.add_capacity("cpu", "8");
+        status.add_capacity("memory", "16Gi");
+        status.add_allocatable("memory", "15Gi");
+
+        let allocatable = status.allocatable();
+        assert_eq!(Some(&Quantity("8".to_owned())), allocatable.get("cpu"));
+        assert_eq!(
+            Some(&Quantity("15Gi".to_owned())),
+            allocatable.get("memory")
+        );
+        assert_eq!(2, allocatable.len());
+    }
+}
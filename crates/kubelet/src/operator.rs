@@ -1,21 +1,52 @@
-use crate::pod::initialize_pod_container_statuses;
-use crate::pod::Pod;
+use crate::event::{EventRecorder, PodEvent};
+use crate::pod::state::prelude::StatusBuilder;
+use crate::pod::{add_finalizer, initialize_pod_container_statuses, remove_finalizer};
+use crate::pod::{patch_status, Phase, Pod, PodKey, POD_FINALIZER};
 use crate::provider::Provider;
+use crate::reason::Reason;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use krator::ObjectState;
 use krator::SharedState;
 use krator::{Manifest, Operator};
 use kube::Api;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
+
+/// How long a pod waits for the state machine of a deleted pod with the same name to finish.
+const REPLACED_POD_DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
 
 pub(crate) struct PodOperator<P: Provider> {
     provider: Arc<P>,
     client: kube::Client,
+    events: Arc<dyn EventRecorder>,
+    tracker: Arc<PodTracker>,
 }
 
 impl<P: Provider> PodOperator<P> {
-    pub fn new(provider: Arc<P>, client: kube::Client) -> Self {
-        PodOperator { provider, client }
+    pub fn new(provider: Arc<P>, client: kube::Client, events: Arc<dyn EventRecorder>) -> Self {
+        PodOperator {
+            provider,
+            client,
+            events,
+            tracker: Arc::new(PodTracker::default()),
+        }
+    }
+
+    /// Periodically release the finalizers of the node's deleted pods that no state machine is
+    /// running for, as [`release_orphaned_pods`](crate::pod::release_orphaned_pods) does.
+    pub(crate) fn release_orphaned_pods(
+        &self,
+        node_name: String,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let tracker = self.tracker.clone();
+        crate::pod::release_orphaned_pods(self.client.clone(), node_name, move || {
+            let tracker = tracker.clone();
+            async move { tracker.uids().await }
+        })
     }
 }
 
@@ -41,10 +72,213 @@ impl<P: Provider> Operator for PodOperator<P> {
         let name = initial_manifest.name().to_string();
         let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
 
+        // A pod that was deleted and quickly recreated with the same name must not start until
+        // the state machine of the deleted pod has cleaned up after it.
+        let key = PodKey::from(&initial_manifest);
+        self.tracker
+            .register(key.clone(), REPLACED_POD_DRAIN_TIMEOUT)
+            .await;
+
+        // Keep the pod in the API until its state machine has cleaned up after it, even if it is
+        // force deleted. A pod that can't be protected that way is not run.
+        if let Err(e) = add_finalizer(&api, &key).await {
+            error!(error = %e, pod = %key, "Unable to add finalizer to pod");
+            self.tracker.deregister(&key).await;
+            // No state machine runs for the pod to report why it doesn't start
+            let reason = Reason::UnexpectedAdmissionError.as_str();
+            let message = format!(
+                "Unable to add the {} finalizer to the pod: {}",
+                POD_FINALIZER, e
+            );
+            let status = StatusBuilder::new()
+                .phase(Phase::Failed)
+                .reason(reason)
+                .message(&message)
+                .build();
+            patch_status(&api, &name, status).await;
+            self.events
+                .record(&initial_manifest, PodEvent::warning(reason, &message))
+                .await;
+            return Err(e);
+        }
+
         initialize_pod_container_statuses(name, manifest, &api).await
     }
 
-    async fn deregistration_hook(&self, _manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
-        Ok(())
+    async fn deregistration_hook(&self, manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+        let latest = PodKey::from(&manifest.latest());
+        // The manifest may belong to a replacement by now, so use the uid the pod registered with
+        let key = self.tracker.deregister(&latest).await.unwrap_or(latest);
+
+        // The state machine and the pod state have finished cleaning up by now, so nothing of the
+        // pod is left on the node. The finalizer is only removed from the pod with this uid, and
+        // only if it is being deleted or has finished: if the uid is that of a replacement that
+        // started after the drain timeout, the replacement is still running and keeps it.
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), &key.namespace());
+        remove_finalizer(&api, &key).await
+    }
+}
+
+/// Tracks the pods (by uid) that currently have a state machine.
+///
+/// Only one state machine may run for a given namespace and name at a time. When a pod is
+/// registered while the state machine of a pod with the same name but a different uid is still
+/// running, registration waits until that state machine is deregistered.
+#[derive(Default)]
+struct PodTracker {
+    live: Mutex<HashMap<String, Registration>>,
+}
+
+/// A pod with a state machine.
+struct Registration {
+    key: PodKey,
+    registered_at: tokio::time::Instant,
+    /// Dropped along with the registration, which wakes up pods waiting for it.
+    _drained_tx: watch::Sender<()>,
+    drained: watch::Receiver<()>,
+}
+
+impl Registration {
+    fn new(key: PodKey) -> Self {
+        let (_drained_tx, drained) = watch::channel(());
+        Registration {
+            key,
+            registered_at: tokio::time::Instant::now(),
+            _drained_tx,
+            drained,
+        }
+    }
+}
+
+impl PodTracker {
+    /// Register the pod, first waiting (up to `timeout`) for any other pod with the same name to
+    /// be deregistered.
+    async fn register(&self, key: PodKey, timeout: Duration) {
+        let uid = key.uid().unwrap_or_default().to_owned();
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let mut drained = {
+                let mut live = self.live.lock().await;
+                match live
+                    .values()
+                    .find(|registered| registered.key == key && !registered.key.is_same_pod(&key))
+                {
+                    Some(registered) => {
+                        info!(
+                            pod = %key,
+                            old_uid = registered.key.uid().unwrap_or_default(),
+                            "Waiting for replaced pod to finish before starting"
+                        );
+                        registered.drained.clone()
+                    }
+                    None => {
+                        live.insert(uid, Registration::new(key));
+                        return;
+                    }
+                }
+            };
+            // The sender is dropped when the replaced pod is deregistered
+            if tokio::time::timeout_at(deadline, drained.changed())
+                .await
+                .is_err()
+            {
+                warn!(
+                    pod = %key,
+                    "Timed out waiting for replaced pod to finish, starting anyway"
+                );
+                // The replaced pod stays registered under its own uid until it is deregistered
+                self.live.lock().await.insert(uid, Registration::new(key));
+                return;
+            }
+        }
+    }
+
+    /// The uids of the registered pods.
+    async fn uids(&self) -> HashSet<String> {
+        self.live.lock().await.keys().cloned().collect()
+    }
+
+    /// Deregister the pod with the uid of the given pod, waking up a pod with the same name
+    /// waiting to be registered. Returns the key the pod was registered with.
+    ///
+    /// By the time a state machine is deregistered its manifest may already have been updated
+    /// to a pod that replaced it, which is still waiting to be registered. If no pod is
+    /// registered with the given uid, the pod with the same name that registered first is
+    /// deregistered instead.
+    async fn deregister(&self, key: &PodKey) -> Option<PodKey> {
+        let mut live = self.live.lock().await;
+        let uid = match key.uid() {
+            Some(uid) if live.contains_key(uid) => uid.to_owned(),
+            _ => live
+                .iter()
+                .filter(|(_, registered)| registered.key == *key)
+                .min_by_key(|(_, registered)| registered.registered_at)
+                .map(|(uid, _)| uid.clone())?,
+        };
+        live.remove(&uid).map(|registered| registered.key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(uid: &str) -> PodKey {
+        PodKey::new("default", "foo").with_uid(uid)
+    }
+
+    #[tokio::test]
+    async fn test_replacement_waits_for_deleted_pod() {
+        let tracker = Arc::new(PodTracker::default());
+        tracker.register(key("old"), Duration::from_secs(5)).await;
+
+        let (started_tx, mut started_rx) = tokio::sync::oneshot::channel();
+        let replacement = {
+            let tracker = tracker.clone();
+            tokio::spawn(async move {
+                tracker.register(key("new"), Duration::from_secs(5)).await;
+                started_tx.send(()).unwrap();
+            })
+        };
+
+        // The replacement must not start while the deleted pod is still registered
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(started_rx.try_recv().is_err());
+
+        let deregistered = tracker.deregister(&key("new")).await.unwrap();
+        assert_eq!(Some("old"), deregistered.uid());
+        replacement.await.unwrap();
+        assert!(started_rx.try_recv().is_ok());
+
+        assert!(tracker.live.lock().await.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn test_same_pod_registers_again_immediately() {
+        let tracker = PodTracker::default();
+        tracker.register(key("uid"), Duration::from_secs(5)).await;
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            tracker.register(key("uid"), Duration::from_secs(5)),
+        )
+        .await
+        .expect("registering the same pod should not wait");
+    }
+
+    #[tokio::test]
+    async fn test_replacement_starts_after_timeout() {
+        let tracker = PodTracker::default();
+        tracker.register(key("old"), Duration::from_secs(5)).await;
+        tracker
+            .register(key("new"), Duration::from_millis(50))
+            .await;
+
+        // Deregistering the replaced pod late leaves the replacement registered
+        let deregistered = tracker.deregister(&key("old")).await.unwrap();
+        assert_eq!(Some("old"), deregistered.uid());
+        assert!(tracker.live.lock().await.contains_key("new"));
+        let deregistered = tracker.deregister(&key("new")).await.unwrap();
+        assert_eq!(Some("new"), deregistered.uid());
+        assert!(tracker.deregister(&key("new")).await.is_none());
     }
 }
@@ -0,0 +1,88 @@
+//! Gauges and counters describing the health of the plugins registered with this node.
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use crate::metrics::write_metric;
+
+lazy_static::lazy_static! {
+    static ref PLUGIN_METRICS: PluginMetrics = PluginMetrics::default();
+}
+
+/// The metrics of the plugin registry of this process.
+pub fn plugin_metrics() -> &'static PluginMetrics {
+    &PLUGIN_METRICS
+}
+
+/// Gauges for the registered plugins, and counters for failed liveness probes and the
+/// registrations they removed.
+#[derive(Debug, Default)]
+pub struct PluginMetrics {
+    registered: AtomicI64,
+    unhealthy: AtomicI64,
+    probe_failures: AtomicU64,
+    stale_removed: AtomicU64,
+    reregistrations: AtomicU64,
+}
+
+impl PluginMetrics {
+    /// Set the number of registered plugins, and how many of them failed their last probe.
+    pub fn set_registered(&self, registered: usize, unhealthy: usize) {
+        self.registered.store(registered as i64, Ordering::Relaxed);
+        self.unhealthy.store(unhealthy as i64, Ordering::Relaxed);
+    }
+
+    /// Record a registered plugin that did not answer a liveness probe.
+    pub fn record_probe_failure(&self) {
+        self.probe_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a registration that was removed because its plugin stopped answering.
+    pub fn record_stale_removed(&self) {
+        self.stale_removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a plugin that registered again with different capabilities.
+    pub fn record_reregistration(&self) {
+        self.reregistrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "krustlet_plugins_registered",
+            "gauge",
+            "Plugins registered with this node.",
+            self.registered.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_plugins_unhealthy",
+            "gauge",
+            "Registered plugins that failed their last liveness probe.",
+            self.unhealthy.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_plugin_probe_failures_total",
+            "counter",
+            "Liveness probes of registered plugins that failed.",
+            self.probe_failures.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_plugin_stale_registrations_removed_total",
+            "counter",
+            "Registrations removed because their plugin stopped answering.",
+            self.stale_removed.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_plugin_reregistrations_total",
+            "counter",
+            "Plugins that registered again with different capabilities.",
+            self.reregistrations.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
@@ -5,6 +5,7 @@ use crate::plugin_registration_api::v1::{
     registration_client::RegistrationClient, InfoRequest, PluginInfo, RegistrationStatus,
     API_VERSION,
 };
+use crate::plugin_watcher::metrics::plugin_metrics;
 
 use anyhow::Context;
 use notify::Event;
@@ -13,12 +14,15 @@ use tokio::sync::{RwLock, RwLockWriteGuard};
 use tokio_stream::wrappers::ReadDirStream;
 use tokio_stream::StreamExt;
 use tonic::Request;
-use tracing::{debug, error, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
 use tracing_futures::Instrument;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub mod metrics;
 
 #[cfg(target_family = "unix")]
 const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/plugins_registry/";
@@ -26,6 +30,12 @@ const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/plugins_registry/";
 const DEFAULT_PLUGIN_PATH: &str = "c:\\ProgramData\\kubelet\\plugins_registry";
 
 const SOCKET_EXTENSION: &str = "sock";
+/// How often registered plugins are asked for their info, to find the ones that stopped running
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a plugin has to answer a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The number of probes in a row a plugin may fail before its registration is removed
+const MAX_FAILED_PROBES: u32 = 3;
 const ALLOWED_PLUGIN_TYPES: &[PluginType] = &[PluginType::CsiPlugin];
 
 /// An enum for capturing possible plugin types. This is purely for clarity and capturing this
@@ -56,6 +66,23 @@ impl TryFrom<&str> for PluginType {
 struct PluginEntry {
     plugin_path: PathBuf,
     endpoint: Option<PathBuf>,
+    supported_versions: Vec<String>,
+    /// The number of probes in a row the plugin failed. A plugin that failed its last probe may
+    /// be replaced by another plugin registering with the same name
+    failed_probes: u32,
+}
+
+/// A CSI driver that registered with the node, as seen by the volume subsystem.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsiDriver {
+    /// The name of the driver, which `csi` PersistentVolumes refer to in their `driver` field.
+    pub name: String,
+    /// The socket the driver serves the CSI Node service on.
+    pub endpoint: PathBuf,
+    /// The versions of the plugin registration API the driver supports.
+    pub supported_versions: Vec<String>,
+    /// Whether the driver answered its last probe.
+    pub healthy: bool,
 }
 
 /// An internal storage plugin registry that implements most the same functionality as the [plugin
@@ -85,8 +112,6 @@ impl PluginRegistry {
     }
 
     /// Gets the endpoint for the given plugin name, returning `None` if it doesn't exist
-    // TODO: Remove clippy exception when CSI is completed.
-    #[allow(dead_code)]
     pub async fn get_endpoint(&self, plugin_name: &str) -> Option<PathBuf> {
         let plugins = self.plugins.read().await;
         plugins
@@ -94,11 +119,39 @@ impl PluginRegistry {
             .map(|v| v.endpoint.as_ref().unwrap_or(&v.plugin_path).to_owned())
     }
 
+    /// Gets the registered CSI driver with the given name, returning `None` if no driver with
+    /// that name has registered with the node
+    pub async fn csi_driver(&self, name: &str) -> Option<CsiDriver> {
+        let plugins = self.plugins.read().await;
+        plugins.get(name).map(|entry| csi_driver(name, entry))
+    }
+
+    /// Lists the registered CSI drivers, ordered by name. Only CSI plugins are accepted by the
+    /// registry, so this is every registered plugin
+    pub async fn csi_drivers(&self) -> Vec<CsiDriver> {
+        let plugins = self.plugins.read().await;
+        let mut drivers: Vec<CsiDriver> = plugins
+            .iter()
+            .map(|(name, entry)| csi_driver(name, entry))
+            .collect();
+        drivers.sort_by(|a, b| a.name.cmp(&b.name));
+        drivers
+    }
+
     /// Starts the plugin registrar and runs all automatic plugin discovery and registration loops.
     /// This will block indefinitely or until the underlying watch stops. To stop watching the
     /// filesystem, simply stop polling the future. Underneath the hood this is creating a watch on
-    /// a directory using OS native APIs and then watching that event stream
+    /// a directory using OS native APIs and then watching that event stream. Registered plugins
+    /// are probed while it runs, and the registrations of plugins that stopped answering are
+    /// removed
     pub async fn run(&self) -> anyhow::Result<()> {
+        tokio::select! {
+            res = self.watch() => res,
+            _ = self.probe_plugins() => Ok(()),
+        }
+    }
+
+    async fn watch(&self) -> anyhow::Result<()> {
         // Create plugin directory if it doesn't exist
         create_dir_all(&self.plugin_dir).await?;
 
@@ -189,12 +242,13 @@ impl PluginRegistry {
         for deleted_plugin in plugin_paths(event.paths) {
             remove_plugin(&mut plugins, deleted_plugin);
         }
+        update_metrics(&plugins);
     }
 
     /// Registers the plugin in our HashMap
     async fn register(&self, info: &PluginInfo, discovered_path: &Path) {
         let mut lock = self.plugins.write().await;
-        lock.insert(
+        let previous = lock.insert(
             info.name.clone(),
             PluginEntry {
                 plugin_path: discovered_path.to_owned(),
@@ -202,8 +256,127 @@ impl PluginRegistry {
                     true => None,
                     false => Some(PathBuf::from(&info.endpoint)),
                 },
+                supported_versions: info.supported_versions.clone(),
+                failed_probes: 0,
             },
         );
+        if let Some(previous) = previous {
+            if previous.supported_versions != info.supported_versions {
+                info!(
+                    plugin_name = %info.name,
+                    supported_versions = ?info.supported_versions,
+                    "Plugin registered again with different supported versions"
+                );
+                plugin_metrics().record_reregistration();
+            }
+        }
+        update_metrics(&lock);
+    }
+
+    /// Probes the registered plugins every [`PROBE_INTERVAL`]. This never returns
+    async fn probe_plugins(&self) {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            self.probe_registered_plugins().await;
+        }
+    }
+
+    /// Asks every registered plugin for its info. Plugins that answer with changed info are
+    /// registered again, and the registrations of plugins that failed [`MAX_FAILED_PROBES`]
+    /// probes in a row are removed
+    async fn probe_registered_plugins(&self) {
+        let registered: Vec<(String, PathBuf)> = self
+            .plugins
+            .read()
+            .await
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.plugin_path.clone()))
+            .collect();
+        for (name, plugin_path) in registered {
+            let probe = tokio::time::timeout(PROBE_TIMEOUT, get_plugin_info(&plugin_path))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!(
+                        "GetInfo call to {} timed out",
+                        plugin_path.display()
+                    ))
+                });
+            match probe {
+                Ok(info) => self.handle_probe_success(&name, &plugin_path, info).await,
+                Err(e) => self.handle_probe_failure(&name, &plugin_path, e).await,
+            }
+        }
+    }
+
+    async fn handle_probe_success(&self, name: &str, plugin_path: &Path, info: PluginInfo) {
+        let changed = {
+            let mut plugins = self.plugins.write().await;
+            let entry = match plugins.get_mut(name) {
+                // The plugin was removed or replaced while it was probed
+                Some(entry) if entry.plugin_path == plugin_path => entry,
+                _ => return,
+            };
+            entry.failed_probes = 0;
+            let endpoint = match info.endpoint.is_empty() {
+                true => None,
+                false => Some(PathBuf::from(&info.endpoint)),
+            };
+            let changed = info.name != name
+                || endpoint != entry.endpoint
+                || info.supported_versions != entry.supported_versions;
+            if changed {
+                // Take the plugin through registration again, as if it was just discovered
+                plugins.remove(name);
+            }
+            update_metrics(&plugins);
+            changed
+        };
+        if changed {
+            info!(
+                plugin_name = %name,
+                path = %plugin_path.display(),
+                "Plugin info changed, registering plugin again"
+            );
+            if let Err(e) = self
+                .handle_create(Event {
+                    paths: vec![plugin_path.to_owned()],
+                    ..Default::default()
+                })
+                .await
+            {
+                error!(error = %e, path = %plugin_path.display(), "Unable to register plugin again");
+            }
+        }
+    }
+
+    async fn handle_probe_failure(&self, name: &str, plugin_path: &Path, error: anyhow::Error) {
+        plugin_metrics().record_probe_failure();
+        let mut plugins = self.plugins.write().await;
+        let failed_probes = match plugins.get_mut(name) {
+            Some(entry) if entry.plugin_path == plugin_path => {
+                entry.failed_probes += 1;
+                entry.failed_probes
+            }
+            _ => return,
+        };
+        warn!(
+            error = %error,
+            plugin_name = %name,
+            failed_probes,
+            "Registered plugin did not answer probe"
+        );
+        if failed_probes >= MAX_FAILED_PROBES {
+            warn!(
+                plugin_name = %name,
+                path = %plugin_path.display(),
+                "Removing registration of plugin that stopped answering"
+            );
+            // The socket belongs to the plugin, so it is left alone. A plugin that restarts
+            // creates it again, which registers the plugin again
+            plugins.remove(name);
+            plugin_metrics().record_stale_removed();
+        }
+        update_metrics(&plugins);
     }
 
     /// Validates the given plugin info gathered from a discovered plugin, returning an error with
@@ -213,7 +386,8 @@ impl PluginRegistry {
     ///    iterate and if it is needed
     /// 2. Does the list of supported versions contain the version we expect?
     /// 3. Is the plugin name available? 3a. If the name is already registered, is the endpoint the
-    ///    exact same? If it is, we allow it to reregister
+    ///    exact same? If it is, we allow it to reregister. 3b. If the registered plugin failed its
+    ///    last probe, we allow the new one to replace it
     #[instrument(level = "info", skip(self))]
     async fn validate(&self, info: &PluginInfo, discovered_path: &Path) -> anyhow::Result<()> {
         trace!("Starting validation for plugin");
@@ -265,6 +439,13 @@ impl PluginRegistry {
         let plugins = self.plugins.read().await;
 
         if let Some(current_path) = plugins.get(&info.name) {
+            if current_path.failed_probes > 0 {
+                debug!(
+                    plugin_name = %info.name,
+                    "Replacing registration of plugin that failed its last probe"
+                );
+                return Ok(());
+            }
             // If there is an endpoint set, use that to check, otherwise, use the discovered path
             if !info.endpoint.is_empty()
                 && Some(PathBuf::from(&info.endpoint)) != current_path.endpoint
@@ -305,6 +486,25 @@ fn remove_plugin(
     plugins.remove(&key);
 }
 
+fn csi_driver(name: &str, entry: &PluginEntry) -> CsiDriver {
+    CsiDriver {
+        name: name.to_owned(),
+        endpoint: entry
+            .endpoint
+            .as_ref()
+            .unwrap_or(&entry.plugin_path)
+            .to_owned(),
+        supported_versions: entry.supported_versions.clone(),
+        healthy: entry.failed_probes == 0,
+    }
+}
+
+/// Updates the plugin gauges after the registered plugins changed
+fn update_metrics(plugins: &HashMap<String, PluginEntry>) {
+    let unhealthy = plugins.values().filter(|p| p.failed_probes > 0).count();
+    plugin_metrics().set_registered(plugins.len(), unhealthy);
+}
+
 // An allow list check for currently supported plugin types
 fn is_allowed_plugin_type(t: PluginType) -> bool {
     ALLOWED_PLUGIN_TYPES.iter().any(|item| *item == t)
@@ -783,6 +983,72 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_stale_registration_is_removed() {
+        let (tempdir, registrar) = setup();
+        let info = valid_info();
+
+        // Nothing listens on this socket, like after a plugin crashed
+        let stale_path = tempdir.path().join("stale.sock");
+        std::fs::write(&stale_path, "").expect("Unable to create stale socket");
+        registrar.register(&info, &stale_path).await;
+
+        for _ in 1..MAX_FAILED_PROBES {
+            registrar.probe_registered_plugins().await;
+            assert!(
+                registrar.get_endpoint(&info.name).await.is_some(),
+                "Plugin should stay registered until it failed enough probes"
+            );
+        }
+
+        // A plugin that failed a probe can be replaced
+        let mut replacement = valid_info();
+        replacement.endpoint = "/another/path.sock".to_string();
+        assert!(
+            registrar
+                .validate(&replacement, &PathBuf::from("/tmp/foo/another.sock"))
+                .await
+                .is_ok(),
+            "Plugin that failed its last probe should be replaceable"
+        );
+
+        registrar.probe_registered_plugins().await;
+        assert!(
+            registrar.get_endpoint(&info.name).await.is_none(),
+            "Stale plugin registration should be removed"
+        );
+        assert!(stale_path.exists(), "Plugin socket should be left alone");
+    }
+
+    #[tokio::test]
+    async fn test_csi_drivers() {
+        // This path doesn't matter here
+        let registrar = PluginRegistry::new("/tmp/foo");
+        let mut info = valid_info();
+        registrar
+            .register(&info, &PathBuf::from("/tmp/foo/test.sock"))
+            .await;
+        // Drivers without an endpoint are reached on the socket they registered on
+        info.name = "another".to_string();
+        info.endpoint = String::new();
+        registrar
+            .register(&info, &PathBuf::from("/tmp/foo/another.sock"))
+            .await;
+
+        let drivers = registrar.csi_drivers().await;
+        assert_eq!(
+            vec!["another", "test"],
+            drivers.iter().map(|d| d.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(PathBuf::from("/tmp/foo/another.sock"), drivers[0].endpoint);
+        assert_eq!(
+            Some(PathBuf::from(FAKE_ENDPOINT)),
+            registrar.csi_driver("test").await.map(|d| d.endpoint)
+        );
+        assert!(drivers.iter().all(|d| d.healthy));
+        assert!(registrar.csi_driver("missing").await.is_none());
+    }
+
     #[tokio::test]
     async fn test_reregistration() {
         // This path doesn't matter here
@@ -0,0 +1,302 @@
+use super::Pod;
+use crate::backoff::{
+    CRASH_LOOP_BACKOFF_CAP_ANNOTATION, CRASH_LOOP_RESET_AFTER_ANNOTATION,
+    CRASH_LOOP_THRESHOLD_ANNOTATION,
+};
+use crate::log::LOG_FORWARD_ANNOTATION;
+use crate::pod::KUBECONFIG_ANNOTATION;
+use crate::secret::IMAGE_PULL_SECRET_ANNOTATION;
+use crate::store::oci::{OCI_LAYOUT_ANNOTATION, PLATFORM_ANNOTATION};
+
+/// The kind of value an annotation the Kubelet recognizes holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnotationKind {
+    /// `true` or `false`.
+    Flag,
+    /// A whole number.
+    WholeNumber,
+    /// Any text. Blank text is treated as if the annotation wasn't set.
+    Text,
+    /// `true`, `false` or an absolute path.
+    FlagOrPath,
+}
+
+impl AnnotationKind {
+    /// Describes the values of this kind, as validation errors do.
+    pub fn expected(&self) -> &'static str {
+        match self {
+            AnnotationKind::Flag => "true or false",
+            AnnotationKind::WholeNumber => "a whole number",
+            AnnotationKind::Text => "text",
+            AnnotationKind::FlagOrPath => "true, false or an absolute path",
+        }
+    }
+
+    /// Whether the value is of this kind.
+    pub fn accepts(&self, value: &str) -> bool {
+        let value = value.trim();
+        match self {
+            AnnotationKind::Flag => value.parse::<bool>().is_ok(),
+            AnnotationKind::WholeNumber => value.parse::<u32>().is_ok(),
+            AnnotationKind::Text => true,
+            // Paths in the filesystem of workloads always use forward slashes
+            AnnotationKind::FlagOrPath => value.parse::<bool>().is_ok() || value.starts_with('/'),
+        }
+    }
+}
+
+/// What an annotation the Kubelet recognizes applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnotationScope {
+    /// The pod as a whole.
+    Pod,
+    /// Each of the pod's containers. The annotation can also be set for a single container by
+    /// appending `.` and the name of the container to its key, which takes precedence over the
+    /// annotation for all of the pod's containers.
+    Container,
+}
+
+/// An annotation the Kubelet recognizes on pods.
+#[derive(Clone, Copy, Debug)]
+pub struct KnownAnnotation {
+    /// The key of the annotation.
+    pub key: &'static str,
+    /// The kind of value it holds.
+    pub kind: AnnotationKind,
+    /// What it applies to.
+    pub scope: AnnotationScope,
+}
+
+/// The annotations the Kubelet recognizes on pods, which are checked when pods are admitted by
+/// [`Pod::validate_annotations`]. The `krustlet.dev/inject-failure` annotation of the
+/// `failure-injection` feature is left out, as it is only meant for tests and ignored if it
+/// can't be parsed.
+pub const KNOWN_ANNOTATIONS: &[KnownAnnotation] = &[
+    KnownAnnotation {
+        key: CRASH_LOOP_THRESHOLD_ANNOTATION,
+        kind: AnnotationKind::WholeNumber,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: CRASH_LOOP_BACKOFF_CAP_ANNOTATION,
+        kind: AnnotationKind::WholeNumber,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: CRASH_LOOP_RESET_AFTER_ANNOTATION,
+        kind: AnnotationKind::WholeNumber,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: IMAGE_PULL_SECRET_ANNOTATION,
+        kind: AnnotationKind::Text,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: KUBECONFIG_ANNOTATION,
+        kind: AnnotationKind::FlagOrPath,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: LOG_FORWARD_ANNOTATION,
+        kind: AnnotationKind::Text,
+        scope: AnnotationScope::Container,
+    },
+    KnownAnnotation {
+        key: OCI_LAYOUT_ANNOTATION,
+        kind: AnnotationKind::Text,
+        scope: AnnotationScope::Pod,
+    },
+    KnownAnnotation {
+        key: PLATFORM_ANNOTATION,
+        kind: AnnotationKind::Text,
+        scope: AnnotationScope::Pod,
+    },
+];
+
+/// The known annotation with the given key, including the keys of annotations for single
+/// containers.
+pub fn known_annotation(key: &str) -> Option<&'static KnownAnnotation> {
+    KNOWN_ANNOTATIONS.iter().find(|known| {
+        key == known.key
+            || (known.scope == AnnotationScope::Container
+                && key
+                    .strip_prefix(known.key)
+                    .and_then(|suffix| suffix.strip_prefix('.'))
+                    .map(|container| !container.is_empty() && !container.contains('.'))
+                    .unwrap_or(false))
+    })
+}
+
+/// The key of an annotation for the named container alone.
+pub fn container_annotation_key(key: &str, container_name: &str) -> String {
+    format!("{}.{}", key, container_name)
+}
+
+/// The error for an annotation whose value is not of the kind it should be.
+pub fn invalid_annotation(key: &str, kind: AnnotationKind, value: &str) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Annotation {} must be {}, but is {}",
+        key,
+        kind.expected(),
+        value
+    )
+}
+
+/// Check the known annotations of the pod, failing on the first one whose value is not of the
+/// kind it should be.
+pub(crate) fn validate_annotations(pod: &Pod) -> anyhow::Result<()> {
+    for (key, value) in pod.annotations() {
+        if let Some(known) = known_annotation(key) {
+            if !known.kind.accepts(value) {
+                return Err(invalid_annotation(key, known.kind, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The annotation of the pod for the named container: the annotation for that container alone,
+/// or else the annotation for all of the pod's containers. Returns the key that was found with
+/// the value.
+pub(crate) fn container_annotation<'a>(
+    pod: &'a Pod,
+    container_name: &str,
+    key: &str,
+) -> Option<(String, &'a str)> {
+    let container_key = container_annotation_key(key, container_name);
+    match pod.get_annotation(&container_key) {
+        Some(value) => Some((container_key, value)),
+        None => Some((key.to_owned(), pod.get_annotation(key)?)),
+    }
+}
+
+pub(crate) fn parse_flag_annotation(key: &str, value: &str) -> anyhow::Result<bool> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| invalid_annotation(key, AnnotationKind::Flag, value))
+}
+
+pub(crate) fn parse_number_annotation(key: &str, value: &str) -> anyhow::Result<u32> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| invalid_annotation(key, AnnotationKind::WholeNumber, value))
+}
+
+pub(crate) fn annotation_text(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::container::Container;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, Pod as KubePod};
+    use kube::api::ObjectMeta;
+
+    fn make_pod(annotations: &[(&str, &str)]) -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                annotations: Some(
+                    annotations
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn make_container(name: &str) -> Container {
+        Container::new(&KubeContainer {
+            name: name.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_known_annotation() {
+        assert_eq!(
+            AnnotationKind::WholeNumber,
+            known_annotation(CRASH_LOOP_THRESHOLD_ANNOTATION)
+                .unwrap()
+                .kind
+        );
+        assert!(known_annotation("krustlet.dev/log-forward.app").is_some());
+        assert!(known_annotation("krustlet.dev/log-forward.").is_none());
+        assert!(known_annotation("krustlet.dev/crash-loop-threshold.app").is_none());
+        assert!(known_annotation("krustlet.dev/unknown").is_none());
+    }
+
+    #[test]
+    fn test_pod_annotations() {
+        let pod = make_pod(&[
+            (CRASH_LOOP_THRESHOLD_ANNOTATION, " 5 "),
+            (CRASH_LOOP_BACKOFF_CAP_ANNOTATION, "soon"),
+            (IMAGE_PULL_SECRET_ANNOTATION, "  "),
+            ("example.com/flag", "true"),
+        ]);
+        assert_eq!(
+            Some(5),
+            pod.annotation_number(CRASH_LOOP_THRESHOLD_ANNOTATION)
+                .unwrap()
+        );
+        assert_eq!(
+            "Annotation krustlet.dev/crash-loop-backoff-cap must be a whole number, but is soon",
+            pod.annotation_number(CRASH_LOOP_BACKOFF_CAP_ANNOTATION)
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            None,
+            pod.annotation_number(CRASH_LOOP_RESET_AFTER_ANNOTATION)
+                .unwrap()
+        );
+        assert_eq!(None, pod.annotation_text(IMAGE_PULL_SECRET_ANNOTATION));
+        assert_eq!(Some(true), pod.annotation_flag("example.com/flag").unwrap());
+        assert!(pod.validate_annotations().is_err());
+
+        let pod = make_pod(&[
+            (CRASH_LOOP_THRESHOLD_ANNOTATION, "5"),
+            (KUBECONFIG_ANNOTATION, "/etc/kube"),
+            ("example.com/number", "many"),
+        ]);
+        assert!(pod.validate_annotations().is_ok());
+        assert!(make_pod(&[(KUBECONFIG_ANNOTATION, "etc/kube")])
+            .validate_annotations()
+            .is_err());
+    }
+
+    #[test]
+    fn test_container_annotations() {
+        let pod = make_pod(&[
+            (LOG_FORWARD_ANNOTATION, "udp://logs:514"),
+            ("krustlet.dev/log-forward.app", "tcp://app-logs:514"),
+            ("example.com/debug.app", "yes"),
+        ]);
+        let app = make_container("app");
+        let sidecar = make_container("sidecar");
+        assert_eq!(
+            Some("tcp://app-logs:514"),
+            app.annotation_text(&pod, LOG_FORWARD_ANNOTATION)
+        );
+        assert_eq!(
+            Some("udp://logs:514"),
+            sidecar.annotation_text(&pod, LOG_FORWARD_ANNOTATION)
+        );
+        assert_eq!(
+            "Annotation example.com/debug.app must be true or false, but is yes",
+            app.annotation_flag(&pod, "example.com/debug")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            None,
+            sidecar.annotation_flag(&pod, "example.com/debug").unwrap()
+        );
+    }
+}
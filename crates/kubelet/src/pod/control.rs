@@ -0,0 +1,101 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, Patch, PatchParams};
+use tokio::sync::{mpsc, RwLock};
+use tracing::debug;
+
+use super::{get_same_pod, remove_same_pod, PodKey};
+use crate::provider::ProviderError;
+
+/// A request for a running pod to change what it is doing. These are delivered to the pod's
+/// state machine, which is responsible for acting on them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PodCommand {
+    /// Stop the pod's containers and start the pod again from the beginning.
+    Restart,
+    /// Stop the pod's containers and fail the pod with the given reason.
+    Fail(String),
+}
+
+/// Lets providers restart or fail pods, or annotate them, when they detect external conditions
+/// that require it (e.g. a runtime upgrade), without mutating pods in the API directly.
+///
+/// Running pods register with [`PodControl::register`] and listen for [`PodCommand`]s on the
+/// returned receiver, so that restarts and failures go through the state machine like any
+/// other transition.
+#[derive(Clone)]
+pub struct PodControl {
+    client: kube::Client,
+    senders: Arc<RwLock<HashMap<PodKey, mpsc::Sender<PodCommand>>>>,
+}
+
+impl PodControl {
+    /// Create a new `PodControl` using the given client for annotating pods.
+    pub fn new(client: kube::Client) -> Self {
+        PodControl {
+            client,
+            senders: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Register a running pod, returning the receiver its state machine should listen on for
+    /// commands. Registering a pod again replaces the previous receiver.
+    pub async fn register(&self, key: PodKey) -> mpsc::Receiver<PodCommand> {
+        let (tx, rx) = mpsc::channel(1);
+        let mut senders = self.senders.write().await;
+        // Inserting would keep the existing key, which may carry the uid of a replaced pod
+        senders.remove(&key);
+        senders.insert(key, tx);
+        rx
+    }
+
+    /// Stop delivering commands to the given pod. This should be called when the pod leaves the
+    /// state that registered it, and when the pod state is dropped, as a pod deleted while in
+    /// that state never leaves it. If the pod has since been replaced by another pod with the
+    /// same name, the replacement stays registered.
+    pub async fn deregister(&self, key: &PodKey) {
+        remove_same_pod(&mut *self.senders.write().await, key);
+    }
+
+    /// Ask the given pod to restart.
+    pub async fn restart_pod(&self, key: &PodKey) -> anyhow::Result<()> {
+        self.send(key, PodCommand::Restart).await
+    }
+
+    /// Ask the given pod to stop and fail with the given reason.
+    pub async fn fail_pod(&self, key: &PodKey, reason: &str) -> anyhow::Result<()> {
+        self.send(key, PodCommand::Fail(reason.to_owned())).await
+    }
+
+    /// Add or update annotations on the given pod.
+    pub async fn annotate(
+        &self,
+        key: &PodKey,
+        annotations: BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let api: Api<KubePod> = Api::namespaced(self.client.clone(), &key.namespace());
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": annotations
+            }
+        });
+        api.patch(&key.name(), &PatchParams::default(), &Patch::Merge(patch))
+            .await?;
+        Ok(())
+    }
+
+    async fn send(&self, key: &PodKey, command: PodCommand) -> anyhow::Result<()> {
+        let sender = get_same_pod(&*self.senders.read().await, key)
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: key.name(),
+            })?;
+        debug!(pod_name = %key.name(), ?command, "Sending command to pod");
+        sender
+            .send(command)
+            .await
+            .map_err(|_| anyhow::anyhow!("pod {} is no longer running", key.name()))
+    }
+}
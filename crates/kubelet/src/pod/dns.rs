@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+
+use super::Pod;
+use crate::container::Container;
+
+/// The cluster domain used when building DNS search domains for pods.
+pub const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+
+/// The environment variable providers can use to expose the pod's DNS search domains to
+/// workloads. The value is a space separated list, as in the `search` line of `resolv.conf`.
+pub const DNS_SEARCH_ENV_VAR: &str = "DNS_SEARCH_DOMAINS";
+
+/// The directory of the hosts file in the filesystem of workloads.
+pub const HOSTS_FILE_DIR: &str = "/etc";
+
+/// The directory under the pod's directory the hosts file is written to. Volume names are DNS
+/// labels, so it can't be the directory of one of the pod's volumes.
+const HOSTS_FILE_DIR_NAME: &str = ".etc";
+
+/// Renders the contents of an `/etc/hosts` file for the pod, including any `hostAliases`
+/// from the pod spec. This follows the same layout as the one generated by the kubelet.
+pub fn hosts_file(pod: &Pod) -> String {
+    let mut hosts = String::from("# Kubernetes-managed hosts file.\n");
+    hosts.push_str("127.0.0.1\tlocalhost\n");
+    hosts.push_str("::1\tlocalhost ip6-localhost ip6-loopback\n");
+
+    let spec = pod.as_kube_pod().spec.as_ref();
+    if let Some(ip) = pod.pod_ip() {
+        let hostname = spec
+            .and_then(|s| s.hostname.as_deref())
+            .unwrap_or_else(|| pod.name());
+        match spec.and_then(|s| s.subdomain.as_deref()) {
+            Some(subdomain) => hosts.push_str(&format!(
+                "{}\t{}.{}.{}.svc.{}\t{}\n",
+                ip,
+                hostname,
+                subdomain,
+                pod.namespace(),
+                DEFAULT_CLUSTER_DOMAIN,
+                hostname
+            )),
+            None => hosts.push_str(&format!("{}\t{}\n", ip, hostname)),
+        }
+    }
+
+    let aliases = spec
+        .and_then(|s| s.host_aliases.as_ref())
+        .map(|a| a.as_slice())
+        .unwrap_or_default();
+    if !aliases.is_empty() {
+        hosts.push_str("\n# Entries added by HostAliases.\n");
+        for alias in aliases {
+            let hostnames = alias.hostnames.as_deref().unwrap_or_default();
+            match alias.ip.as_deref() {
+                Some(ip) if !hostnames.is_empty() => {
+                    hosts.push_str(&format!("{}\t{}\n", ip, hostnames.join("\t")))
+                }
+                _ => (),
+            }
+        }
+    }
+    hosts
+}
+
+/// The hosts file of a pod, rendered by [`hosts_file`] into a directory of its own so that
+/// providers can mount or preopen the directory at [`HOSTS_FILE_DIR`] in the filesystem of the
+/// pod's containers.
+#[derive(Clone, Debug)]
+pub struct HostsFile {
+    dir: PathBuf,
+}
+
+impl HostsFile {
+    /// Write the pod's hosts file into a directory of its own under the pod's directory.
+    pub async fn write(pod: &Pod, pod_dir: &Path) -> anyhow::Result<Self> {
+        let dir = pod_dir.join(HOSTS_FILE_DIR_NAME);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join("hosts"), hosts_file(pod)).await?;
+        Ok(HostsFile { dir })
+    }
+
+    /// The directory holding the hosts file, to be supplied to workloads at [`HOSTS_FILE_DIR`].
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path of the hosts file.
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("hosts")
+    }
+
+    /// Remove the hosts file and its directory, once the pod is gone.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        tokio::fs::remove_dir_all(&self.dir).await?;
+        Ok(())
+    }
+}
+
+/// Whether the container should be given the pod's hosts file. Containers that mount a volume
+/// at `/etc/hosts` or over the whole of `/etc` bring their own, as with the Kubernetes kubelet.
+pub fn uses_pod_hosts_file(container: &Container) -> bool {
+    !container.volume_mounts().iter().flatten().any(|mount| {
+        let path = mount.mount_path.trim_end_matches('/');
+        path == HOSTS_FILE_DIR || path == "/etc/hosts"
+    })
+}
+
+/// Returns the DNS search domains for the pod based on its `dnsPolicy` and `dnsConfig`.
+pub fn dns_search_domains(pod: &Pod) -> Vec<String> {
+    let spec = pod.as_kube_pod().spec.as_ref();
+    let mut searches = match spec.and_then(|s| s.dns_policy.as_deref()) {
+        // Neither of these policies use the cluster DNS, so only the dnsConfig applies
+        Some("None") | Some("Default") => vec![],
+        _ => vec![
+            format!("{}.svc.{}", pod.namespace(), DEFAULT_CLUSTER_DOMAIN),
+            format!("svc.{}", DEFAULT_CLUSTER_DOMAIN),
+            DEFAULT_CLUSTER_DOMAIN.to_owned(),
+        ],
+    };
+    for search in spec
+        .and_then(|s| s.dns_config.as_ref())
+        .and_then(|c| c.searches.as_ref())
+        .into_iter()
+        .flatten()
+    {
+        if !searches.contains(search) {
+            searches.push(search.clone());
+        }
+    }
+    searches
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, HostAlias, Pod as KubePod, PodDNSConfig, PodSpec, VolumeMount,
+    };
+    use kube::api::ObjectMeta;
+
+    fn make_pod(spec: PodSpec) -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("foo".to_owned()),
+                namespace: Some("bar".to_owned()),
+                ..Default::default()
+            },
+            spec: Some(spec),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_hosts_file_includes_aliases() {
+        let pod = make_pod(PodSpec {
+            host_aliases: Some(vec![
+                HostAlias {
+                    ip: Some("10.1.2.3".to_owned()),
+                    hostnames: Some(vec!["foo.local".to_owned(), "bar.local".to_owned()]),
+                },
+                HostAlias {
+                    ip: Some("10.1.2.4".to_owned()),
+                    hostnames: None,
+                },
+            ]),
+            ..Default::default()
+        });
+        let hosts = hosts_file(&pod);
+        assert!(hosts.starts_with("# Kubernetes-managed hosts file.\n127.0.0.1\tlocalhost\n"));
+        assert!(hosts.contains("10.1.2.3\tfoo.local\tbar.local\n"));
+        assert!(!hosts.contains("10.1.2.4"));
+    }
+
+    #[tokio::test]
+    async fn test_hosts_file_written_to_pod_dir() {
+        let pod_dir = tempfile::tempdir().unwrap();
+        let pod = make_pod(PodSpec::default());
+        let hosts = HostsFile::write(&pod, pod_dir.path()).await.unwrap();
+        assert_eq!(pod_dir.path().join(".etc"), hosts.dir());
+        assert_eq!(
+            hosts_file(&pod),
+            tokio::fs::read_to_string(hosts.path()).await.unwrap()
+        );
+
+        hosts.remove().await.unwrap();
+        assert!(!hosts.dir().exists());
+    }
+
+    #[test]
+    fn test_uses_pod_hosts_file() {
+        let container = |mount_path: &str| {
+            Container::new(&KubeContainer {
+                name: "app".to_owned(),
+                volume_mounts: Some(vec![VolumeMount {
+                    name: "config".to_owned(),
+                    mount_path: mount_path.to_owned(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            })
+        };
+        assert!(uses_pod_hosts_file(&container("/etc/app")));
+        assert!(!uses_pod_hosts_file(&container("/etc/hosts")));
+        assert!(!uses_pod_hosts_file(&container("/etc/")));
+    }
+
+    #[test]
+    fn test_dns_search_domains() {
+        let pod = make_pod(PodSpec {
+            dns_config: Some(PodDNSConfig {
+                searches: Some(vec!["example.com".to_owned(), "cluster.local".to_owned()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            vec![
+                "bar.svc.cluster.local",
+                "svc.cluster.local",
+                "cluster.local",
+                "example.com"
+            ],
+            dns_search_domains(&pod)
+        );
+
+        let pod = make_pod(PodSpec {
+            dns_policy: Some("None".to_owned()),
+            ..Default::default()
+        });
+        assert!(dns_search_domains(&pod).is_empty());
+    }
+}
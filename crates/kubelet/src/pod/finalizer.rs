@@ -0,0 +1,304 @@
+//! The finalizer the Kubelet puts on the pods it runs.
+
+use super::PodKey;
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::error::ErrorResponse;
+use kube::Api;
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// The finalizer that keeps a pod the Kubelet runs from being removed from the API until the
+/// Kubelet has cleaned up after it on the node (its volumes, logs and runtime handles). This
+/// applies to pods force deleted with a grace period of 0 too.
+pub const POD_FINALIZER: &str = "krustlet.dev/pod-cleanup";
+
+/// The number of times updating the finalizers of a pod is retried after conflicting with another
+/// update.
+const MAX_FINALIZER_PATCH_RETRIES: usize = 5;
+
+/// How often the Kubelet looks for deleted pods of its node whose finalizer nothing will remove.
+const ORPHANED_POD_SWEEP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long, in seconds, a pod has to have been deleted for before its finalizer is released
+/// when no state machine is running for it, so that pods the Kubelet is about to register are
+/// left to their state machines.
+const ORPHANED_POD_GRACE_SECONDS: i64 = 300;
+
+/// The finalizers of a pod with the Kubelet's finalizer added, or `None` if it has it already.
+fn with_finalizer(finalizers: &[String]) -> Option<Vec<String>> {
+    if finalizers.iter().any(|f| f == POD_FINALIZER) {
+        return None;
+    }
+    let mut finalizers = finalizers.to_vec();
+    finalizers.push(POD_FINALIZER.to_owned());
+    Some(finalizers)
+}
+
+/// The finalizers of a pod with the Kubelet's finalizer removed, or `None` if it doesn't have it.
+fn without_finalizer(finalizers: &[String]) -> Option<Vec<String>> {
+    if !finalizers.iter().any(|f| f == POD_FINALIZER) {
+        return None;
+    }
+    Some(
+        finalizers
+            .iter()
+            .filter(|f| *f != POD_FINALIZER)
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Add the Kubelet's finalizer to the pod, unless it is already being deleted.
+pub(crate) async fn add_finalizer(api: &Api<KubePod>, key: &PodKey) -> anyhow::Result<()> {
+    update_finalizers(api, key, |pod| {
+        if pod.metadata.deletion_timestamp.is_some() {
+            // Finalizers can't be added to pods being deleted
+            return None;
+        }
+        with_finalizer(pod.metadata.finalizers.as_deref().unwrap_or_default())
+    })
+    .await
+}
+
+/// Remove the Kubelet's finalizer from the pod, letting it be removed from the API. This must
+/// only be called once nothing of the pod is left on the node.
+///
+/// The finalizer is only removed if the pod has the uid in `key` and is being deleted or has
+/// finished, so that a pod whose state machine is still running keeps it.
+pub(crate) async fn remove_finalizer(api: &Api<KubePod>, key: &PodKey) -> anyhow::Result<()> {
+    if key.uid().is_none() {
+        anyhow::bail!(
+            "refusing to remove the finalizer of pod {} without its uid",
+            key
+        );
+    }
+    update_finalizers(api, key, |pod| {
+        if !is_finished(pod) {
+            debug!(pod = %key, "Pod is still running, keeping its finalizer");
+            return None;
+        }
+        without_finalizer(pod.metadata.finalizers.as_deref().unwrap_or_default())
+    })
+    .await
+}
+
+/// Whether the pod is being deleted or has run to completion, so that no state machine should be
+/// running for it.
+fn is_finished(pod: &KubePod) -> bool {
+    let phase = pod.status.as_ref().and_then(|s| s.phase.as_deref());
+    pod.metadata.deletion_timestamp.is_some() || matches!(phase, Some("Succeeded") | Some("Failed"))
+}
+
+/// Periodically remove the Kubelet's finalizer from deleted pods bound to `node_name` that no
+/// state machine is running for, such as pods whose finalizer couldn't be removed when their
+/// state machine finished. `running` returns the uids of the pods that have a state machine.
+///
+/// Pods bound to other nodes, including nodes removed for good, are left to a controller: the
+/// node authorizer only lets a Kubelet update the pods bound to its own node.
+pub(crate) async fn release_orphaned_pods<F, R>(
+    client: kube::Client,
+    node_name: String,
+    running: F,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> R,
+    R: Future<Output = HashSet<String>>,
+{
+    let pods: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    loop {
+        // The pods the Kubelet finds when it starts are registered in the meantime
+        tokio::time::sleep(ORPHANED_POD_SWEEP_INTERVAL).await;
+        let pods = match pods.list(&params).await {
+            Ok(pods) => pods,
+            Err(e) => {
+                warn!(error = %e, "Unable to list pods to release orphaned finalizers");
+                continue;
+            }
+        };
+        let running = running().await;
+        for pod in pods {
+            if !is_orphaned(&pod, &running, Utc::now()) {
+                continue;
+            }
+            let key = PodKey::from(&pod);
+            info!(pod = %key, "Releasing finalizer of deleted pod without a state machine");
+            let api: Api<KubePod> = Api::namespaced(client.clone(), &key.namespace());
+            if let Err(e) = remove_finalizer(&api, &key).await {
+                warn!(error = %e, pod = %key, "Unable to release finalizer of orphaned pod");
+            }
+        }
+    }
+}
+
+/// Whether the pod has been deleted for a while and still has the Kubelet's finalizer, but no
+/// state machine is running for it to remove it.
+fn is_orphaned(pod: &KubePod, running: &HashSet<String>, now: DateTime<Utc>) -> bool {
+    let finalizers = pod.metadata.finalizers.as_deref().unwrap_or_default();
+    let deleted_at = match &pod.metadata.deletion_timestamp {
+        Some(time) => time.0,
+        None => return false,
+    };
+    let uid = match &pod.metadata.uid {
+        Some(uid) => uid,
+        None => return false,
+    };
+    finalizers.iter().any(|f| f == POD_FINALIZER)
+        && now - deleted_at > chrono::Duration::seconds(ORPHANED_POD_GRACE_SECONDS)
+        && !running.contains(uid)
+}
+
+/// Replace the finalizers of the pod with the list returned by `update`, if it returns one.
+///
+/// Finalizers are replaced as a whole, so the patch is made conditional on the version of the
+/// pod the list was made from and retried on conflicts. A pod that no longer exists, or was
+/// replaced by a pod with the same name, is left alone.
+async fn update_finalizers<F>(api: &Api<KubePod>, key: &PodKey, update: F) -> anyhow::Result<()>
+where
+    F: Fn(&KubePod) -> Option<Vec<String>>,
+{
+    let name = key.name();
+    for attempt in 1..=MAX_FINALIZER_PATCH_RETRIES {
+        let pod = match api.get(&name).await {
+            Ok(pod) => pod,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !key.is_same_pod(&PodKey::from(&pod)) {
+            debug!(pod = %key, "Pod was replaced, leaving its finalizers alone");
+            return Ok(());
+        }
+        let finalizers = match update(&pod) {
+            Some(finalizers) => finalizers,
+            None => return Ok(()),
+        };
+        let patch = serde_json::json!({
+            "metadata": {
+                "finalizers": finalizers,
+                "resourceVersion": pod.metadata.resource_version,
+            }
+        });
+        match api
+            .patch(&name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {
+                debug!(attempt, pod = %key, "Pod finalizer patch conflicted, retrying");
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    warn!(pod = %key, "Giving up on updating pod finalizers after repeated conflicts");
+    anyhow::bail!(
+        "unable to update the finalizers of pod {} after {} conflicting attempts",
+        key,
+        MAX_FINALIZER_PATCH_RETRIES
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn finalizers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_with_finalizer() {
+        assert_eq!(
+            Some(finalizers(&["example.com/other", POD_FINALIZER])),
+            with_finalizer(&finalizers(&["example.com/other"]))
+        );
+        assert_eq!(None, with_finalizer(&finalizers(&[POD_FINALIZER])));
+    }
+
+    #[test]
+    fn test_without_finalizer() {
+        assert_eq!(
+            Some(finalizers(&["example.com/other"])),
+            without_finalizer(&finalizers(&[POD_FINALIZER, "example.com/other"]))
+        );
+        assert_eq!(None, without_finalizer(&finalizers(&["example.com/other"])));
+    }
+
+    #[test]
+    fn test_is_finished() {
+        let running: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "foo", "uid": "1" },
+            "status": { "phase": "Running" }
+        }))
+        .unwrap();
+        assert!(!is_finished(&running));
+
+        let deleting: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "foo",
+                "uid": "1",
+                "deletionTimestamp": "2021-01-01T00:00:00Z"
+            },
+            "status": { "phase": "Running" }
+        }))
+        .unwrap();
+        assert!(is_finished(&deleting));
+
+        let succeeded: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "foo", "uid": "1" },
+            "status": { "phase": "Succeeded" }
+        }))
+        .unwrap();
+        assert!(is_finished(&succeeded));
+    }
+
+    #[test]
+    fn test_is_orphaned() {
+        let deleted: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "foo",
+                "uid": "1",
+                "deletionTimestamp": "2021-01-01T00:00:00Z",
+                "finalizers": [POD_FINALIZER]
+            },
+            "spec": { "nodeName": "node", "containers": [] }
+        }))
+        .unwrap();
+        let later = |seconds| {
+            DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc)
+                + chrono::Duration::seconds(seconds)
+        };
+        let running = HashSet::new();
+        assert!(is_orphaned(&deleted, &running, later(600)));
+        // Recently deleted pods are left to be registered
+        assert!(!is_orphaned(&deleted, &running, later(60)));
+        // Pods with a state machine are left to it
+        let running = vec!["1".to_owned()].into_iter().collect();
+        assert!(!is_orphaned(&deleted, &running, later(600)));
+
+        let running = HashSet::new();
+        let without_finalizer: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "foo",
+                "uid": "1",
+                "deletionTimestamp": "2021-01-01T00:00:00Z"
+            },
+            "spec": { "nodeName": "node", "containers": [] }
+        }))
+        .unwrap();
+        assert!(!is_orphaned(&without_finalizer, &running, later(600)));
+
+        let not_deleted: KubePod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "foo", "uid": "1", "finalizers": [POD_FINALIZER] },
+            "spec": { "nodeName": "node", "containers": [] }
+        }))
+        .unwrap();
+        assert!(!is_orphaned(&not_deleted, &running, later(600)));
+    }
+}
@@ -1,12 +1,12 @@
-use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::container::{
     ContainerKey, ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
 };
-use crate::handle::StopHandler;
-use crate::log::{HandleFactory, Sender};
+use crate::exec;
+use crate::handle::{AttachHandler, ExecHandler, StopHandler};
+use crate::log::{LogBacking, Sender};
 use crate::pod::Pod;
 use crate::provider::ProviderError;
 
@@ -45,10 +45,9 @@ impl<H: StopHandler, F> Handle<H, F> {
 
     /// Streams output from the specified container into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
-    pub async fn output<R>(&self, container_name: &str, sender: Sender) -> anyhow::Result<()>
+    pub async fn output(&self, container_name: &str, sender: Sender) -> anyhow::Result<()>
     where
-        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
-        F: HandleFactory<R>,
+        F: LogBacking,
     {
         let mut handles = self.container_handles.write().await;
         let handle = handles
@@ -81,8 +80,45 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 
-    /// Wait for all containers in the pod to complete
-    pub async fn wait(&mut self) -> anyhow::Result<()> {
+    /// Force the pod's containers to stop, once they have not stopped within the pod's grace
+    /// period after [`stop`](Handle::stop).
+    pub async fn kill(&self) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        for (key, handle) in handles.iter_mut() {
+            warn!(container_name = %key, "Killing container");
+            if let Err(e) = handle.kill().await {
+                // Keep going so that the other containers are killed
+                error!(container_name = %key, error = %e, "Error while trying to kill container")
+            }
+        }
+        Ok(())
+    }
+
+    /// Signal one of the pod's containers to stop, leaving the others running, e.g. so that it
+    /// can be restarted after failing a probe.
+    pub async fn stop_container(&self, key: &ContainerKey) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut(key)
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: key.to_string(),
+            })?;
+        info!(container_name = %key, "Stopping container");
+        handle.stop().await
+    }
+
+    /// End the streams of all the pod's container logs, e.g. once the pod is deleted, so that
+    /// they don't outlive it. See [`ContainerHandle::close_logs`].
+    pub async fn close_logs(&self) {
+        let mut handles = self.container_handles.write().await;
+        for (_, handle) in handles.iter_mut() {
+            handle.close_logs().await;
+        }
+    }
+
+    /// Wait for all containers in the pod to complete, closing their logs as they do
+    pub async fn wait(&self) -> anyhow::Result<()> {
         let mut handles = self.container_handles.write().await;
         for (_, handle) in handles.iter_mut() {
             handle.wait().await?;
@@ -90,3 +126,50 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 }
+
+impl<H: AttachHandler, F> Handle<H, F> {
+    /// Attach to the standard streams of the specified container until it exits or the client
+    /// disconnects.
+    pub async fn attach(
+        &self,
+        container_name: &str,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> anyhow::Result<()> {
+        let attach = {
+            let mut handles = self.container_handles.write().await;
+            let handle = handles
+                .get_mut_by_name(container_name.to_owned())
+                .ok_or_else(|| ProviderError::ContainerNotFound {
+                    pod_name: self.pod.name().to_owned(),
+                    container_name: container_name.to_owned(),
+                })?;
+            handle.attach(sender, receiver)
+        };
+        // Don't hold the lock while attached, so the pod can be stopped meanwhile
+        attach.await
+    }
+}
+
+impl<H: ExecHandler, F> Handle<H, F> {
+    /// Run a command in the specified container and return its exit code.
+    pub async fn exec(
+        &self,
+        container_name: &str,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> anyhow::Result<i32> {
+        let exec = {
+            let mut handles = self.container_handles.write().await;
+            let handle = handles
+                .get_mut_by_name(container_name.to_owned())
+                .ok_or_else(|| ProviderError::ContainerNotFound {
+                    pod_name: self.pod.name().to_owned(),
+                    container_name: container_name.to_owned(),
+                })?;
+            handle.exec(sender, receiver)
+        };
+        // Don't hold the lock while the command runs, so the pod can be stopped meanwhile
+        exec.await
+    }
+}
@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+
+use super::{invalid_annotation, AnnotationKind, Pod};
+
+/// The annotation a pod asks for a kubeconfig to be generated for its containers with. Set it
+/// to `true` to have the kubeconfig supplied in [`KUBECONFIG_DIR`], or to the absolute path of
+/// the directory to supply it in instead.
+pub const KUBECONFIG_ANNOTATION: &str = "krustlet.dev/kubeconfig";
+
+/// The default directory of the generated kubeconfig in the filesystem of workloads.
+pub const KUBECONFIG_DIR: &str = "/var/run/secrets/krustlet.dev/kubeconfig";
+
+/// The name of the generated kubeconfig in its directory.
+pub const KUBECONFIG_FILE_NAME: &str = "config";
+
+/// The directory under the pod's directory the kubeconfig is written to. Volume names are DNS
+/// labels, so it can't be the directory of one of the pod's volumes.
+const KUBECONFIG_DIR_NAME: &str = ".kubeconfig";
+
+/// The directory the pod asks for its generated kubeconfig to be supplied in, in the
+/// filesystem of its containers, or `None` if it doesn't ask for one.
+pub fn kubeconfig_dir(pod: &Pod) -> anyhow::Result<Option<PathBuf>> {
+    match pod.get_annotation(KUBECONFIG_ANNOTATION).map(str::trim) {
+        None | Some("false") => Ok(None),
+        Some("true") => Ok(Some(PathBuf::from(KUBECONFIG_DIR))),
+        // Paths in the filesystem of workloads always use forward slashes
+        Some(dir) if dir.starts_with('/') => Ok(Some(PathBuf::from(dir))),
+        Some(value) => Err(invalid_annotation(
+            KUBECONFIG_ANNOTATION,
+            AnnotationKind::FlagOrPath,
+            value,
+        )),
+    }
+}
+
+/// Renders a kubeconfig for the pod that talks to the API server at `server` as the pod's
+/// service account. Rather than holding credentials itself, it refers to the token and cluster
+/// CA certificate in the pod's service account volume, the projected volume with a
+/// `serviceAccountToken` source, where the pod's containers mount it. The token is replaced
+/// there before it expires, so clients that read it again when they connect keep working.
+pub fn kubeconfig(pod: &Pod, server: &str) -> anyhow::Result<String> {
+    let files = service_account_files(pod).ok_or_else(|| {
+        anyhow::anyhow!("Pod does not mount a service account token for its kubeconfig to use")
+    })?;
+    let mut cluster = json!({ "server": server.trim_end_matches('/') });
+    if let Some(ca) = files.ca {
+        cluster["certificate-authority"] = json!(ca);
+    }
+    let config = json!({
+        "apiVersion": "v1",
+        "kind": "Config",
+        "clusters": [{ "name": "default", "cluster": cluster }],
+        "users": [{ "name": "default", "user": { "tokenFile": files.token } }],
+        "contexts": [{
+            "name": "default",
+            "context": {
+                "cluster": "default",
+                "user": "default",
+                "namespace": pod.namespace(),
+            },
+        }],
+        "current-context": "default",
+    });
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+/// The paths of the service account token and cluster CA certificate in the filesystem of the
+/// pod's containers.
+struct ServiceAccountFiles {
+    token: String,
+    ca: Option<String>,
+}
+
+fn service_account_files(pod: &Pod) -> Option<ServiceAccountFiles> {
+    let containers: Vec<_> = pod
+        .init_containers()
+        .into_iter()
+        .chain(pod.containers())
+        .collect();
+    pod.volumes().into_iter().flatten().find_map(|volume| {
+        let sources = volume.projected.as_ref()?.sources.as_ref()?;
+        let token = sources
+            .iter()
+            .find_map(|source| source.service_account_token.as_ref())?;
+        // Kubernetes mounts the service account volume at the same path in every container
+        let mount_path = containers
+            .iter()
+            .flat_map(|container| container.volume_mounts().iter().flatten())
+            .find(|mount| mount.name == volume.name && mount.sub_path.is_none())?
+            .mount_path
+            .trim_end_matches('/');
+        let ca = sources
+            .iter()
+            .filter_map(|source| source.config_map.as_ref()?.items.as_ref())
+            .flatten()
+            .find(|item| item.key == "ca.crt")
+            .map(|item| format!("{}/{}", mount_path, item.path));
+        Some(ServiceAccountFiles {
+            token: format!("{}/{}", mount_path, token.path),
+            ca,
+        })
+    })
+}
+
+/// The kubeconfig generated for a pod that asks for one with [`KUBECONFIG_ANNOTATION`],
+/// rendered by [`kubeconfig`] into a directory of its own so that providers can mount or
+/// preopen the directory at the path the pod asks for in the filesystem of its containers.
+#[derive(Clone, Debug)]
+pub struct PodKubeconfig {
+    dir: PathBuf,
+    guest_dir: PathBuf,
+}
+
+impl PodKubeconfig {
+    /// Write the pod's kubeconfig into a directory of its own under the pod's directory, if the
+    /// pod asks for one. `server` is the URL of the API server, if the provider lets pods talk
+    /// to it.
+    pub async fn write(
+        pod: &Pod,
+        pod_dir: &Path,
+        server: Option<&str>,
+    ) -> anyhow::Result<Option<Self>> {
+        let guest_dir = match kubeconfig_dir(pod)? {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        let server = server.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Provider does not supply kubeconfigs to pods, so it can't honor the {} annotation",
+                KUBECONFIG_ANNOTATION
+            )
+        })?;
+        let contents = kubeconfig(pod, server)?;
+        let dir = pod_dir.join(KUBECONFIG_DIR_NAME);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(dir.join(KUBECONFIG_FILE_NAME), contents).await?;
+        Ok(Some(PodKubeconfig { dir, guest_dir }))
+    }
+
+    /// The directory holding the kubeconfig, to be supplied to workloads at
+    /// [`guest_dir`](Self::guest_dir).
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The directory the pod asks for the kubeconfig to be supplied in.
+    pub fn guest_dir(&self) -> &Path {
+        &self.guest_dir
+    }
+
+    /// The path of the kubeconfig.
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(KUBECONFIG_FILE_NAME)
+    }
+
+    /// Remove the kubeconfig and its directory, once the pod is gone.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        tokio::fs::remove_dir_all(&self.dir).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        ConfigMapProjection, Container as KubeContainer, KeyToPath, Pod as KubePod, PodSpec,
+        ProjectedVolumeSource, ServiceAccountTokenProjection, Volume, VolumeMount,
+        VolumeProjection,
+    };
+    use kube::api::ObjectMeta;
+
+    fn make_pod(annotation: Option<&str>) -> Pod {
+        let annotations = annotation
+            .map(|value| {
+                std::iter::once((KUBECONFIG_ANNOTATION.to_owned(), value.to_owned())).collect()
+            })
+            .unwrap_or_default();
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("foo".to_owned()),
+                namespace: Some("bar".to_owned()),
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "app".to_owned(),
+                    volume_mounts: Some(vec![VolumeMount {
+                        name: "kube-api-access".to_owned(),
+                        mount_path: "/var/run/secrets/kubernetes.io/serviceaccount/".to_owned(),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                }],
+                volumes: Some(vec![Volume {
+                    name: "kube-api-access".to_owned(),
+                    projected: Some(ProjectedVolumeSource {
+                        sources: Some(vec![
+                            VolumeProjection {
+                                service_account_token: Some(ServiceAccountTokenProjection {
+                                    path: "token".to_owned(),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            },
+                            VolumeProjection {
+                                config_map: Some(ConfigMapProjection {
+                                    name: Some("kube-root-ca.crt".to_owned()),
+                                    items: Some(vec![KeyToPath {
+                                        key: "ca.crt".to_owned(),
+                                        path: "ca.crt".to_owned(),
+                                        ..Default::default()
+                                    }]),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            },
+                        ]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_kubeconfig_dir() {
+        assert_eq!(None, kubeconfig_dir(&make_pod(None)).unwrap());
+        assert_eq!(None, kubeconfig_dir(&make_pod(Some("false"))).unwrap());
+        assert_eq!(
+            Some(PathBuf::from(KUBECONFIG_DIR)),
+            kubeconfig_dir(&make_pod(Some("true"))).unwrap()
+        );
+        assert_eq!(
+            Some(PathBuf::from("/etc/kube")),
+            kubeconfig_dir(&make_pod(Some("/etc/kube"))).unwrap()
+        );
+        assert!(kubeconfig_dir(&make_pod(Some("etc/kube"))).is_err());
+    }
+
+    #[test]
+    fn test_kubeconfig_uses_service_account_volume() {
+        let config: serde_yaml::Value =
+            serde_yaml::from_str(&kubeconfig(&make_pod(None), "https://10.0.0.1:6443/").unwrap())
+                .unwrap();
+        let cluster = &config["clusters"][0]["cluster"];
+        assert_eq!("https://10.0.0.1:6443", cluster["server"]);
+        assert_eq!(
+            "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt",
+            cluster["certificate-authority"]
+        );
+        assert_eq!(
+            "/var/run/secrets/kubernetes.io/serviceaccount/token",
+            config["users"][0]["user"]["tokenFile"]
+        );
+        assert_eq!("bar", config["contexts"][0]["context"]["namespace"]);
+        assert_eq!("default", config["current-context"]);
+    }
+
+    #[test]
+    fn test_kubeconfig_needs_service_account_token() {
+        let mut pod = make_pod(None).into_kube_pod();
+        pod.spec.as_mut().unwrap().containers[0].volume_mounts = None;
+        assert!(kubeconfig(&Pod::from(pod), "https://10.0.0.1:6443").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kubeconfig_written_to_pod_dir() {
+        let pod_dir = tempfile::tempdir().unwrap();
+        let server = Some("https://10.0.0.1:6443");
+        assert!(
+            PodKubeconfig::write(&make_pod(None), pod_dir.path(), server)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let pod = make_pod(Some("true"));
+        assert!(PodKubeconfig::write(&pod, pod_dir.path(), None)
+            .await
+            .is_err());
+
+        let config = PodKubeconfig::write(&pod, pod_dir.path(), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pod_dir.path().join(".kubeconfig"), config.dir());
+        assert_eq!(Path::new(KUBECONFIG_DIR), config.guest_dir());
+        assert_eq!(
+            kubeconfig(&pod, "https://10.0.0.1:6443").unwrap(),
+            tokio::fs::read_to_string(config.path()).await.unwrap()
+        );
+
+        config.remove().await.unwrap();
+        assert!(!config.dir().exists());
+    }
+}
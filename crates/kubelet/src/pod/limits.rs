@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use super::Pod;
+
+/// Node-configured maximums on the size of the pods the Kubelet accepts.
+///
+/// Pods beyond these are rejected when they are registered rather than failing, or slowing down
+/// the node, somewhere further along.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PodSpecLimits {
+    /// The maximum number of environment variables declared across all containers of a pod,
+    /// counting each `envFrom` source as one. `None` means there is no limit.
+    pub max_env_vars: Option<usize>,
+    /// The maximum number of volumes of a pod. `None` means there is no limit.
+    pub max_volumes: Option<usize>,
+    /// The maximum size in bytes of a single environment variable, counting its name and value.
+    /// `None` means there is no limit.
+    pub max_env_var_size: Option<usize>,
+}
+
+impl PodSpecLimits {
+    /// Checks the pod against the limits, returning an error describing the limit it exceeds.
+    pub fn check(&self, pod: &Pod) -> anyhow::Result<()> {
+        if let Some(max) = self.max_env_vars {
+            let env_vars: usize = pod
+                .all_containers()
+                .iter()
+                .map(|c| {
+                    c.env().as_ref().map_or(0, Vec::len) + c.env_from().as_ref().map_or(0, Vec::len)
+                })
+                .sum();
+            if env_vars > max {
+                return Err(anyhow::anyhow!(
+                    "Pod has {} environment variables, but this node accepts at most {}",
+                    env_vars,
+                    max
+                ));
+            }
+        }
+        if let Some(max) = self.max_volumes {
+            let volumes = pod.volumes().map_or(0, Vec::len);
+            if volumes > max {
+                return Err(anyhow::anyhow!(
+                    "Pod has {} volumes, but this node accepts at most {}",
+                    volumes,
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the resolved environment of a container against the size limit, before it is
+    /// handed to a runtime. The error names the variable that exceeds it.
+    ///
+    /// The number of variables is only checked by [`check`](Self::check), against the variables
+    /// declared in the pod spec: the environment here also holds the variables the Kubelet sets
+    /// itself, which the limit doesn't count.
+    pub fn check_env(&self, env: &HashMap<String, String>) -> anyhow::Result<()> {
+        if let Some(max) = self.max_env_var_size {
+            // Report the largest one, so the error doesn't depend on the map order
+            if let Some((name, size)) = env
+                .iter()
+                .map(|(name, value)| (name, name.len() + value.len()))
+                .filter(|(_, size)| *size > max)
+                .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0)))
+            {
+                return Err(anyhow::anyhow!(
+                    "Environment variable {} is {} bytes, but this node accepts at most {}",
+                    name,
+                    size,
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, EnvVar, Pod as KubePod, PodSpec, Volume as KubeVolume,
+    };
+
+    fn pod(env_vars: usize, volumes: usize) -> Pod {
+        let env = (0..env_vars)
+            .map(|i| EnvVar {
+                name: format!("VAR_{}", i),
+                ..Default::default()
+            })
+            .collect();
+        let volumes = (0..volumes)
+            .map(|i| KubeVolume {
+                name: format!("volume-{}", i),
+                ..Default::default()
+            })
+            .collect();
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "app".to_owned(),
+                    env: Some(env),
+                    ..Default::default()
+                }],
+                volumes: Some(volumes),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_no_limits() {
+        assert!(PodSpecLimits::default().check(&pod(1000, 1000)).is_ok());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = PodSpecLimits {
+            max_env_vars: Some(100),
+            max_volumes: Some(10),
+            ..Default::default()
+        };
+        assert!(limits.check(&pod(100, 10)).is_ok());
+
+        let e = limits.check(&pod(101, 10)).unwrap_err();
+        assert_eq!(
+            "Pod has 101 environment variables, but this node accepts at most 100",
+            e.to_string()
+        );
+        let e = limits.check(&pod(0, 11)).unwrap_err();
+        assert_eq!(
+            "Pod has 11 volumes, but this node accepts at most 10",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_env_limits() {
+        let limits = PodSpecLimits {
+            max_env_vars: Some(2),
+            max_env_var_size: Some(10),
+            ..Default::default()
+        };
+        let mut env = HashMap::new();
+        env.insert("SHORT".to_owned(), "12345".to_owned());
+        assert!(limits.check_env(&env).is_ok());
+
+        env.insert("LONG".to_owned(), "1234567".to_owned());
+        let e = limits.check_env(&env).unwrap_err();
+        assert_eq!(
+            "Environment variable LONG is 11 bytes, but this node accepts at most 10",
+            e.to_string()
+        );
+
+        // The number of variables is checked at registration only
+        env.remove("LONG");
+        env.insert("SECOND".to_owned(), String::new());
+        env.insert("THIRD".to_owned(), String::new());
+        assert!(limits.check_env(&env).is_ok());
+    }
+}
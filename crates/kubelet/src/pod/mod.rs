@@ -1,32 +1,76 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+mod annotations;
+mod control;
+mod dns;
+mod finalizer;
 mod handle;
+mod kubeconfig;
+mod limits;
+mod ports;
+mod qos;
+mod related;
+mod resync;
 pub mod state;
 mod status;
+mod termination;
 
+pub(crate) use annotations::{
+    annotation_text, container_annotation, parse_flag_annotation, parse_number_annotation,
+};
+pub use annotations::{
+    container_annotation_key, invalid_annotation, known_annotation, AnnotationKind,
+    AnnotationScope, KnownAnnotation, KNOWN_ANNOTATIONS,
+};
+pub use control::{PodCommand, PodControl};
+pub use dns::{
+    dns_search_domains, hosts_file, uses_pod_hosts_file, HostsFile, DEFAULT_CLUSTER_DOMAIN,
+    DNS_SEARCH_ENV_VAR, HOSTS_FILE_DIR,
+};
+pub use finalizer::POD_FINALIZER;
+pub(crate) use finalizer::{add_finalizer, release_orphaned_pods, remove_finalizer};
 pub use handle::Handle;
+pub use kubeconfig::{
+    kubeconfig, kubeconfig_dir, PodKubeconfig, KUBECONFIG_ANNOTATION, KUBECONFIG_DIR,
+    KUBECONFIG_FILE_NAME,
+};
+pub use limits::PodSpecLimits;
+pub use ports::{parse_port_range, PortMapper, PortMapping};
+pub use qos::QosClass;
+pub use related::{
+    related_objects, volumes_referring_to, RelatedKind, RelatedObject, RelatedObjectUpdate,
+    RelatedObjects, Subscription,
+};
+pub use resync::ManifestChanges;
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
     make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
 };
+pub use termination::TerminationReason;
+pub(crate) use termination::{node_shutdown_status, DISRUPTION_TARGET};
 
 use crate::container::{Container, ContainerKey};
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    Container as KubeContainer, Pod as KubePod, Volume as KubeVolume,
+    Container as KubeContainer, ContainerStatus as KubeContainerStatus, Pod as KubePod,
+    PodCondition as KubePodCondition, Volume as KubeVolume,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::{Resource, ResourceExt};
 use serde::Deserialize;
 use serde::Serialize;
+use std::sync::Arc;
 
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
-/// providing convenient accessor methods
+/// providing convenient accessor methods. The definition is shared
+/// between clones, so handing the pod to every state of its state
+/// machine doesn't copy specs with hundreds of environment variables
+/// or volumes each time.
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct Pod {
     #[serde(flatten)]
-    kube_pod: KubePod,
+    kube_pod: Arc<KubePod>,
 }
 
 impl Pod {
@@ -50,11 +94,26 @@ impl Pod {
             .unwrap_or("default")
     }
 
+    /// Get the name of the node the pod has been scheduled to
+    pub fn node_name(&self) -> Option<&str> {
+        self.kube_pod.spec.as_ref()?.node_name.as_deref()
+    }
+
     /// Get the pod's node_selector map
     pub fn node_selector(&self) -> Option<&std::collections::BTreeMap<String, String>> {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
     }
 
+    /// Get the name of the RuntimeClass the pod asks to be run with
+    pub fn runtime_class_name(&self) -> Option<&str> {
+        self.kube_pod.spec.as_ref()?.runtime_class_name.as_deref()
+    }
+
+    /// Get the pod's restart policy, as it is given in the pod spec
+    pub fn restart_policy(&self) -> Option<&str> {
+        self.kube_pod.spec.as_ref()?.restart_policy.as_deref()
+    }
+
     /// Get the pod's service account name
     pub fn service_account_name(&self) -> Option<&str> {
         let spec = self.kube_pod.spec.as_ref()?;
@@ -67,6 +126,12 @@ impl Pod {
         spec.volumes.as_ref()
     }
 
+    /// Get the fsGroup of the pod's security context, the group that owns its volumes
+    pub fn fs_group(&self) -> Option<i64> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.security_context.as_ref()?.fs_group
+    }
+
     /// Get the pod's host ip
     pub fn host_ip(&self) -> Option<&str> {
         let status = self.kube_pod.status.as_ref()?;
@@ -79,6 +144,12 @@ impl Pod {
         status.pod_ip.as_deref()
     }
 
+    /// Get the pod's QoS class, computed from the resource requests and limits of its
+    /// containers
+    pub fn qos_class(&self) -> QosClass {
+        qos::qos_class(self)
+    }
+
     /// Get the pod's uid
     pub fn pod_uid(&self) -> &str {
         self.kube_pod
@@ -140,6 +211,33 @@ impl Pod {
         Some(self.annotations().get(key)?.as_str())
     }
 
+    /// Get an annotation of the pod that holds `true` or `false`, failing if it holds anything
+    /// else.
+    pub fn annotation_flag(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        self.get_annotation(key)
+            .map(|value| parse_flag_annotation(key, value))
+            .transpose()
+    }
+
+    /// Get an annotation of the pod that holds a whole number, failing if it holds anything
+    /// else.
+    pub fn annotation_number(&self, key: &str) -> anyhow::Result<Option<u32>> {
+        self.get_annotation(key)
+            .map(|value| parse_number_annotation(key, value))
+            .transpose()
+    }
+
+    /// Get an annotation of the pod that holds text, trimmed, or `None` if it is blank.
+    pub fn annotation_text(&self, key: &str) -> Option<&str> {
+        annotation_text(self.get_annotation(key)?)
+    }
+
+    /// Check that the [annotations the Kubelet recognizes](KNOWN_ANNOTATIONS) hold values of
+    /// the kind they should, failing on the first one that doesn't.
+    pub fn validate_annotations(&self) -> anyhow::Result<()> {
+        annotations::validate_annotations(self)
+    }
+
     /// Get the deletionTimestamp if it exists
     pub fn deletion_timestamp(&self) -> Option<&DateTime<Utc>> {
         self.kube_pod
@@ -149,36 +247,71 @@ impl Pod {
             .map(|t| &t.0)
     }
 
+    /// Get the grace period the pod was deleted with, if it is being deleted. Pods that are
+    /// evicted through the Eviction API are deleted like any other pod, so this is also the
+    /// grace period requested for the eviction.
+    pub fn deletion_grace_period(&self) -> Option<std::time::Duration> {
+        self.kube_pod
+            .meta()
+            .deletion_grace_period_seconds
+            .map(|seconds| std::time::Duration::from_secs(seconds.max(0) as u64))
+    }
+
+    /// Get the `terminationGracePeriodSeconds` of the pod spec, if it sets one. This is how long
+    /// the pod is given to shut down when it is deleted without a grace period of its own.
+    pub fn termination_grace_period(&self) -> Option<std::time::Duration> {
+        self.kube_pod
+            .spec
+            .as_ref()?
+            .termination_grace_period_seconds
+            .map(|seconds| std::time::Duration::from_secs(seconds.max(0) as u64))
+    }
+
+    /// Get the pod condition of the given type from the pod status, if it has one.
+    pub fn condition(&self, condition_type: &str) -> Option<&KubePodCondition> {
+        self.kube_pod
+            .status
+            .as_ref()?
+            .conditions
+            .as_ref()?
+            .iter()
+            .find(|condition| condition.type_ == condition_type)
+    }
+
     /// Find container by `ContainerKey` and return it.
     pub fn find_container(&self, key: &ContainerKey) -> Option<Container> {
-        let containers: Vec<Container> = if key.is_init() {
-            self.init_containers()
-        } else {
-            self.containers()
-        };
-        containers
+        self.containers_of_kind(key)
             .into_iter()
             .find(|container| container.name() == key.name())
     }
 
+    /// The containers of the same kind as the key, in the order of the pod's spec.
+    pub(crate) fn containers_of_kind(&self, key: &ContainerKey) -> Vec<Container> {
+        match key {
+            ContainerKey::Init(_) => self.init_containers(),
+            ContainerKey::App(_) => self.containers(),
+            ContainerKey::Ephemeral(_) => self.ephemeral_containers(),
+        }
+    }
+
     /// Finds the index of the container in the Pod's container statuses.
     pub fn container_status_index(&self, key: &ContainerKey) -> Option<usize> {
-        match self.kube_pod.status.as_ref() {
-            Some(status) => {
-                match if key.is_init() {
-                    status.init_container_statuses.as_ref()
-                } else {
-                    status.container_statuses.as_ref()
-                } {
-                    Some(statuses) => statuses
-                        .iter()
-                        .enumerate()
-                        .find(|(_, status)| status.name == key.name())
-                        .map(|(idx, _)| idx),
-                    None => None,
-                }
-            }
-            None => None,
+        self.container_statuses(key)?
+            .iter()
+            .position(|status| status.name == key.name())
+    }
+
+    /// The statuses of the containers of the same kind as the key, `None` if the pod has no
+    /// list of them yet.
+    pub(crate) fn container_statuses(
+        &self,
+        key: &ContainerKey,
+    ) -> Option<&Vec<KubeContainerStatus>> {
+        let status = self.kube_pod.status.as_ref()?;
+        match key {
+            ContainerKey::Init(_) => status.init_container_statuses.as_ref(),
+            ContainerKey::App(_) => status.container_statuses.as_ref(),
+            ContainerKey::Ephemeral(_) => status.ephemeral_container_statuses.as_ref(),
         }
     }
 
@@ -206,6 +339,19 @@ impl Pod {
             .collect()
     }
 
+    /// Get a pod's ephemeral containers, which are added to a running pod, e.g. by
+    /// `kubectl debug`
+    pub fn ephemeral_containers(&self) -> Vec<Container> {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|s| s.ephemeral_containers.as_ref())
+            .into_iter()
+            .flatten()
+            .map(Container::from_ephemeral)
+            .collect()
+    }
+
     /// Gets all of a pod's containers (init and application)
     pub fn all_containers(&self) -> Vec<Container> {
         let mut app_containers = self.containers();
@@ -216,7 +362,7 @@ impl Pod {
 
     /// Turn the Pod into the Kubernetes API version of a Pod
     pub fn into_kube_pod(self) -> KubePod {
-        self.kube_pod
+        Arc::try_unwrap(self.kube_pod).unwrap_or_else(|kube_pod| (*kube_pod).clone())
     }
 
     /// Turn a reference to a Pod into a reference to the Kubernetes API version of a Pod
@@ -233,7 +379,7 @@ impl k8s_openapi::Metadata for Pod {
     }
 
     fn metadata_mut(&mut self) -> &mut ObjectMeta {
-        self.kube_pod.metadata_mut()
+        Arc::make_mut(&mut self.kube_pod).metadata_mut()
     }
 }
 
@@ -246,7 +392,9 @@ impl k8s_openapi::Resource for Pod {
 
 impl std::convert::From<KubePod> for Pod {
     fn from(api_pod: KubePod) -> Self {
-        Self { kube_pod: api_pod }
+        Self {
+            kube_pod: Arc::new(api_pod),
+        }
     }
 }
 
@@ -257,15 +405,21 @@ impl<'a> std::convert::From<&'a Pod> for &'a KubePod {
 }
 impl std::convert::From<Pod> for KubePod {
     fn from(pod: Pod) -> Self {
-        pod.kube_pod
+        pod.into_kube_pod()
     }
 }
 
 /// PodKey is a unique human readable key for storing a handle to a pod in a hash.
-#[derive(Hash, Ord, Eq, PartialOrd, PartialEq, Debug, Clone, Default)]
+///
+/// Keys are compared by namespace and name only, so a key can be used to look up a pod without
+/// knowing its uid (e.g. when serving logs). Keys created from a pod also carry the pod's uid,
+/// which can be checked with [`PodKey::is_same_pod`] to tell a pod apart from another pod with
+/// the same name that replaced it.
+#[derive(Debug, Clone, Default)]
 pub struct PodKey {
     name: String,
     namespace: String,
+    uid: Option<String>,
 }
 
 impl PodKey {
@@ -275,6 +429,7 @@ impl PodKey {
         PodKey {
             name: pod_name.as_ref().to_owned(),
             namespace: namespace.as_ref().to_owned(),
+            uid: None,
         }
     }
 
@@ -287,32 +442,78 @@ impl PodKey {
     pub fn namespace(&self) -> String {
         self.namespace.clone()
     }
+
+    /// Returns the pod key with the given pod uid
+    pub fn with_uid<U: AsRef<str>>(mut self, uid: U) -> Self {
+        self.uid = Some(uid.as_ref().to_owned());
+        self
+    }
+
+    /// Returns the uid of the pod in the pod key, if known
+    pub fn uid(&self) -> Option<&str> {
+        self.uid.as_deref()
+    }
+
+    /// Returns whether both keys refer to the same pod. Unlike `==`, this also compares uids
+    /// when both keys have one, so it is false for a pod that was deleted and recreated with the
+    /// same name.
+    pub fn is_same_pod(&self, other: &PodKey) -> bool {
+        self == other
+            && match (self.uid(), other.uid()) {
+                (Some(uid), Some(other_uid)) => uid == other_uid,
+                _ => true,
+            }
+    }
+}
+
+impl PartialEq for PodKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.namespace == other.namespace && self.name == other.name
+    }
+}
+
+impl Eq for PodKey {}
+
+impl std::hash::Hash for PodKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.namespace.hash(state);
+        self.name.hash(state);
+    }
+}
+
+impl PartialOrd for PodKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PodKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (&self.namespace, &self.name).cmp(&(&other.namespace, &other.name))
+    }
+}
+
+impl std::fmt::Display for PodKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
 }
 
 impl From<Pod> for PodKey {
     fn from(p: Pod) -> Self {
-        PodKey {
-            name: p.name().to_owned(),
-            namespace: p.namespace().to_owned(),
-        }
+        PodKey::from(&p)
     }
 }
 
 impl From<&Pod> for PodKey {
     fn from(p: &Pod) -> Self {
-        PodKey {
-            name: p.name().to_owned(),
-            namespace: p.namespace().to_owned(),
-        }
+        PodKey::from(p.as_kube_pod())
     }
 }
 
 impl From<KubePod> for PodKey {
     fn from(p: KubePod) -> Self {
-        PodKey {
-            name: p.name(),
-            namespace: p.namespace().unwrap_or_else(|| "default".to_string()),
-        }
+        PodKey::from(&p)
     }
 }
 
@@ -321,11 +522,82 @@ impl From<&KubePod> for PodKey {
         PodKey {
             name: p.name(),
             namespace: p.namespace().unwrap_or_else(|| "default".to_string()),
+            uid: p.metadata.uid.clone(),
         }
     }
 }
 
+/// Looks up the value for the given pod in a map keyed by [`PodKey`], only returning it if it
+/// belongs to the same pod and not to another pod with the same name.
+pub fn get_same_pod<'a, V>(
+    map: &'a std::collections::HashMap<PodKey, V>,
+    key: &PodKey,
+) -> Option<&'a V> {
+    map.get_key_value(key)
+        .filter(|(stored, _)| stored.is_same_pod(key))
+        .map(|(_, value)| value)
+}
+
+/// Removes the value for the given pod from a map keyed by [`PodKey`], unless it belongs to
+/// another pod with the same name that has replaced it.
+pub fn remove_same_pod<V>(
+    map: &mut std::collections::HashMap<PodKey, V>,
+    key: &PodKey,
+) -> Option<V> {
+    match map.get_key_value(key) {
+        Some((stored, _)) if stored.is_same_pod(key) => map.remove(key),
+        _ => None,
+    }
+}
+
 lazy_static::lazy_static! {
     static ref EMPTY_MAP: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new();
     static ref EMPTY_VEC: Vec<KubeContainer> = Vec::new();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn key_with_uid(uid: &str) -> PodKey {
+        PodKey::new("default", "foo").with_uid(uid)
+    }
+
+    #[test]
+    fn test_pod_key_ignores_uid_for_lookups() {
+        let mut map = HashMap::new();
+        map.insert(key_with_uid("1"), "first");
+        assert_eq!(Some(&"first"), map.get(&PodKey::new("default", "foo")));
+        assert_eq!(Some(&"first"), get_same_pod(&map, &key_with_uid("1")));
+        assert_eq!(None, get_same_pod(&map, &key_with_uid("2")));
+        assert_eq!("default/foo", key_with_uid("1").to_string());
+    }
+
+    #[test]
+    fn test_deletion_grace_period() {
+        let mut kube_pod = KubePod::default();
+        assert_eq!(None, Pod::from(kube_pod.clone()).deletion_grace_period());
+        kube_pod.metadata.deletion_grace_period_seconds = Some(30);
+        assert_eq!(
+            Some(std::time::Duration::from_secs(30)),
+            Pod::from(kube_pod).deletion_grace_period()
+        );
+    }
+
+    #[test]
+    fn test_remove_same_pod_keeps_replacement() {
+        let mut map = HashMap::new();
+        map.insert(key_with_uid("2"), "replacement");
+
+        // The old pod going away must not remove the pod that replaced it
+        assert_eq!(None, remove_same_pod(&mut map, &key_with_uid("1")));
+        assert_eq!(1, map.len());
+
+        assert_eq!(
+            Some("replacement"),
+            remove_same_pod(&mut map, &key_with_uid("2"))
+        );
+        assert!(map.is_empty());
+    }
+}
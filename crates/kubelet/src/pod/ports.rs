@@ -0,0 +1,379 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::{get_same_pod, remove_same_pod, Pod, PodKey};
+
+/// A `containerPort` of a pod that was mapped to a port on the node.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMapping {
+    /// The namespace of the pod.
+    pub namespace: String,
+    /// The name of the pod.
+    pub pod_name: String,
+    /// The name of the container declaring the port.
+    pub container_name: String,
+    /// The name of the port, if it has one.
+    pub name: Option<String>,
+    /// The protocol of the port.
+    pub protocol: String,
+    /// The port declared by the container.
+    pub container_port: u16,
+    /// The port on the node that the container port is mapped to.
+    pub node_port: u16,
+}
+
+/// Parses a port range in the `<first>-<last>` form, e.g. `40000-40999`.
+pub fn parse_port_range(range: &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let (first, last) = range
+        .split_once('-')
+        .ok_or_else(|| anyhow::anyhow!("invalid port range {}, expected <first>-<last>", range))?;
+    let first: u16 = first.trim().parse()?;
+    let last: u16 = last.trim().parse()?;
+    if first == 0 || first > last {
+        return Err(anyhow::anyhow!("invalid port range {}", range));
+    }
+    Ok(first..=last)
+}
+
+/// Maps the container ports of pods to ports on the node, for runtimes where pods do not have
+/// their own network namespace.
+///
+/// Each TCP `containerPort` that does not ask for a `hostPort` is given a port from the
+/// configured range for as long as the pod is running on the node. The node port is listened on
+/// at every address of the node, and its connections are forwarded to the container port on the
+/// node's loopback address, where the module listens. The mappings can be listed, e.g. to serve
+/// them from the Kubelet API.
+///
+/// Containers declaring the same `containerPort`, in the same pod or not, still clash: each gets
+/// its own node port, but they are all forwarded to the module that listens on the loopback port.
+#[derive(Clone)]
+pub struct PortMapper {
+    range: RangeInclusive<u16>,
+    mappings: Arc<RwLock<HashMap<PodKey, PodPorts>>>,
+}
+
+/// The ports mapped for a pod, with the tasks forwarding their connections, which are stopped
+/// when the ports are released.
+struct PodPorts {
+    mappings: Vec<PortMapping>,
+    forwarders: Vec<JoinHandle<()>>,
+}
+
+impl Drop for PodPorts {
+    fn drop(&mut self) {
+        for forwarder in &self.forwarders {
+            forwarder.abort();
+        }
+    }
+}
+
+impl PortMapper {
+    /// Create a new `PortMapper` allocating node ports from the given range.
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        PortMapper {
+            range,
+            mappings: Default::default(),
+        }
+    }
+
+    /// Map the container ports of the pod and start forwarding connections to them, returning
+    /// its mappings. Mapping a pod again returns its existing mappings. Ports of other protocols
+    /// than TCP are not mapped. This fails without mapping any port if there are not enough
+    /// free ports left in the range. Ports in the range that something else on the node listens
+    /// on are skipped.
+    pub async fn map_pod(&self, pod: &Pod) -> anyhow::Result<Vec<PortMapping>> {
+        let key = PodKey::from(pod);
+        let mut mappings = self.mappings.write().await;
+        if let Some(existing) = get_same_pod(&mappings, &key) {
+            return Ok(existing.mappings.clone());
+        }
+        // A replaced pod with the same name gives its ports up to the new one
+        mappings.remove(&key);
+
+        let used: BTreeSet<u16> = mappings
+            .values()
+            .flat_map(|ports| &ports.mappings)
+            .map(|mapping| mapping.node_port)
+            .collect();
+        let mut free = self.range.clone().filter(|port| !used.contains(port));
+
+        let mut pod_mappings = Vec::new();
+        let mut listeners = Vec::new();
+        for container in pod.containers() {
+            for port in container.ports().iter().flatten() {
+                let protocol = port.protocol.as_deref().unwrap_or("TCP");
+                if port.host_port.is_some() || protocol != "TCP" {
+                    continue;
+                }
+                let (node_port, listener) = loop {
+                    let node_port = free.next().ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "no free node port left in range {}-{} to map port {} of container {}",
+                            self.range.start(),
+                            self.range.end(),
+                            port.container_port,
+                            container.name()
+                        )
+                    })?;
+                    match TcpListener::bind((Ipv4Addr::UNSPECIFIED, node_port)).await {
+                        Ok(listener) => break (node_port, listener),
+                        Err(e) => debug!(error = %e, node_port, "Skipping node port in use"),
+                    }
+                };
+                pod_mappings.push(PortMapping {
+                    namespace: pod.namespace().to_owned(),
+                    pod_name: pod.name().to_owned(),
+                    container_name: container.name().to_owned(),
+                    name: port.name.clone(),
+                    protocol: protocol.to_owned(),
+                    container_port: port.container_port as u16,
+                    node_port,
+                });
+                listeners.push((listener, port.container_port as u16));
+            }
+        }
+        let forwarders = listeners
+            .into_iter()
+            .map(|(listener, container_port)| tokio::spawn(forward(listener, container_port)))
+            .collect();
+        debug!(pod = %key, num_ports = pod_mappings.len(), "Mapped container ports");
+        mappings.insert(
+            key,
+            PodPorts {
+                mappings: pod_mappings.clone(),
+                forwarders,
+            },
+        );
+        Ok(pod_mappings)
+    }
+
+    /// Release the ports mapped for the given pod, which stops forwarding new connections to it.
+    /// If the pod has since been replaced by another pod with the same name, the replacement
+    /// keeps its ports.
+    pub async fn unmap_pod(&self, key: &PodKey) {
+        remove_same_pod(&mut *self.mappings.write().await, key);
+    }
+
+    /// All the current mappings, ordered by node port.
+    pub async fn mappings(&self) -> Vec<PortMapping> {
+        let mut mappings: Vec<PortMapping> = self
+            .mappings
+            .read()
+            .await
+            .values()
+            .flat_map(|ports| &ports.mappings)
+            .cloned()
+            .collect();
+        mappings.sort_by_key(|mapping| mapping.node_port);
+        mappings
+    }
+}
+
+/// Forward the connections to a node port to the container port on the loopback address.
+async fn forward(listener: TcpListener, container_port: u16) {
+    loop {
+        match listener.accept().await {
+            Ok((inbound, _)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = proxy(inbound, container_port).await {
+                        debug!(error = %e, container_port, "Forwarded connection failed");
+                    }
+                });
+            }
+            Err(e) => {
+                // Such as when the node is out of file descriptors, which may not last
+                warn!(error = %e, container_port, "Unable to accept connection to node port");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Copy a connection to a node port to and from the container port until both sides have
+/// finished writing.
+async fn proxy(inbound: TcpStream, container_port: u16) -> std::io::Result<()> {
+    let outbound = TcpStream::connect((Ipv4Addr::LOCALHOST, container_port)).await?;
+    let (mut inbound_read, mut inbound_write) = inbound.into_split();
+    let (mut outbound_read, mut outbound_write) = outbound.into_split();
+    let to_container = async {
+        tokio::io::copy(&mut inbound_read, &mut outbound_write).await?;
+        outbound_write.shutdown().await
+    };
+    let from_container = async {
+        tokio::io::copy(&mut outbound_read, &mut inbound_write).await?;
+        inbound_write.shutdown().await
+    };
+    futures::future::try_join(to_container, from_container).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// A range of `len` ports that are free on the node, so that tests don't depend on fixed
+    /// ports that may be in use.
+    async fn free_ports(len: u16) -> RangeInclusive<u16> {
+        loop {
+            let first = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let last = match first.checked_add(len - 1) {
+                Some(last) => last,
+                None => continue,
+            };
+            let mut free = true;
+            for port in first..=last {
+                if TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+                    .await
+                    .is_err()
+                {
+                    free = false;
+                    break;
+                }
+            }
+            if free {
+                return first..=last;
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(40000..=40999, parse_port_range("40000-40999").unwrap());
+        assert!(parse_port_range("40000").is_err());
+        assert!(parse_port_range("41000-40000").is_err());
+        assert!(parse_port_range("0-10").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_map_pod() {
+        let ports = free_ports(3).await;
+        let first = *ports.start();
+        let mapper = PortMapper::new(ports);
+        let foo: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "foo", "namespace": "default", "uid": "1"},
+            "spec": {"containers": [{"name": "app", "ports": [
+                {"containerPort": 8080},
+                {"containerPort": 9090, "hostPort": 9090},
+                {"containerPort": 5353, "protocol": "UDP"},
+            ]}]},
+        }))
+        .unwrap();
+        let foo = Pod::from(foo);
+        let mappings = mapper.map_pod(&foo).await.unwrap();
+        assert_eq!(1, mappings.len());
+        assert_eq!(8080, mappings[0].container_port);
+        assert_eq!(first, mappings[0].node_port);
+        assert_eq!("TCP", mappings[0].protocol);
+
+        // Mapping the same pod again keeps its ports
+        assert_eq!(mappings, mapper.map_pod(&foo).await.unwrap());
+
+        let bar: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "bar", "namespace": "default", "uid": "2"},
+            "spec": {"containers": [{"name": "app", "ports": [
+                {"containerPort": 80},
+                {"containerPort": 443},
+            ]}]},
+        }))
+        .unwrap();
+        let mappings = mapper.map_pod(&Pod::from(bar)).await.unwrap();
+        assert_eq!(
+            vec![first + 1, first + 2],
+            mappings.iter().map(|m| m.node_port).collect::<Vec<_>>()
+        );
+
+        // The range is exhausted
+        let baz: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "baz", "namespace": "default", "uid": "3"},
+            "spec": {"containers": [{"name": "app", "ports": [{"containerPort": 80}]}]},
+        }))
+        .unwrap();
+        let baz = Pod::from(baz);
+        assert!(mapper.map_pod(&baz).await.is_err());
+
+        mapper.unmap_pod(&PodKey::from(&foo)).await;
+        assert_eq!(first, mapper.map_pod(&baz).await.unwrap()[0].node_port);
+        assert_eq!(3, mapper.mappings().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_replaced_pod_gives_up_ports() {
+        let ports = free_ports(1).await;
+        let first = *ports.start();
+        let mapper = PortMapper::new(ports);
+        let pod = |uid: &str| {
+            let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+                "metadata": {"name": "foo", "namespace": "default", "uid": uid},
+                "spec": {"containers": [{"name": "app", "ports": [{"containerPort": 8080}]}]},
+            }))
+            .unwrap();
+            Pod::from(pod)
+        };
+        let (old, new) = (pod("1"), pod("2"));
+        mapper.map_pod(&old).await.unwrap();
+        assert_eq!(first, mapper.map_pod(&new).await.unwrap()[0].node_port);
+
+        // Unmapping the replaced pod leaves the replacement alone
+        mapper.unmap_pod(&PodKey::from(&old)).await;
+        assert_eq!(1, mapper.mappings().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_node_port_is_forwarded() {
+        let module = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let container_port = module.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = module.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+
+        let mapper = PortMapper::new(free_ports(1).await);
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "foo", "namespace": "default", "uid": "1"},
+            "spec": {"containers": [{"name": "app", "ports": [{"containerPort": container_port}]}]},
+        }))
+        .unwrap();
+        let pod = Pod::from(pod);
+        let node_port = mapper.map_pod(&pod).await.unwrap()[0].node_port;
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, node_port))
+            .await
+            .unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!("hello", greeting);
+
+        // Once the pod's ports are released, the node port is closed as soon as the forwarding
+        // task is stopped
+        mapper.unmap_pod(&PodKey::from(&pod)).await;
+        let mut closed = false;
+        for _ in 0..50 {
+            if TcpListener::bind((Ipv4Addr::UNSPECIFIED, node_port))
+                .await
+                .is_ok()
+            {
+                closed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(closed);
+    }
+}
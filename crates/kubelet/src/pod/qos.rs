@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::Pod;
+use crate::resources::util::parse_quantity;
+
+/// The resources that are taken into account when computing the QoS class of a pod.
+const QOS_RESOURCES: &[&str] = &["cpu", "memory"];
+
+/// The quality of service class of a pod.
+///
+/// This is specified by Kubernetes itself. Classes are ordered from the most to the least
+/// important, so sorting pods by their class puts `Guaranteed` pods first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub enum QosClass {
+    /// Every container has equal CPU and memory requests and limits.
+    Guaranteed,
+    /// At least one container has a CPU or memory request or limit.
+    Burstable,
+    /// No container has any CPU or memory requests or limits.
+    BestEffort,
+}
+
+impl std::fmt::Display for QosClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_json::json!(self).as_str().unwrap())
+    }
+}
+
+/// Computes the QoS class of the pod from the resource requests and limits of its containers.
+/// This follows the same rules as the Kubernetes kubelet.
+pub(crate) fn qos_class(pod: &Pod) -> QosClass {
+    let mut requests: BTreeMap<String, f64> = BTreeMap::new();
+    let mut limits: BTreeMap<String, f64> = BTreeMap::new();
+    let mut is_guaranteed = true;
+
+    for container in pod.all_containers() {
+        let resources = container.resources();
+        for (name, quantity) in resources
+            .and_then(|r| r.requests.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(value) = qos_quantity(name, &quantity.0) {
+                *requests.entry(name.clone()).or_default() += value;
+            }
+        }
+
+        let mut limits_found = BTreeSet::new();
+        for (name, quantity) in resources
+            .and_then(|r| r.limits.as_ref())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(value) = qos_quantity(name, &quantity.0) {
+                limits_found.insert(name.as_str());
+                *limits.entry(name.clone()).or_default() += value;
+            }
+        }
+        if !QOS_RESOURCES.iter().all(|r| limits_found.contains(r)) {
+            is_guaranteed = false;
+        }
+    }
+
+    if requests.is_empty() && limits.is_empty() {
+        return QosClass::BestEffort;
+    }
+    if is_guaranteed
+        && requests.len() == limits.len()
+        && requests
+            .iter()
+            .all(|(name, request)| limits.get(name) == Some(request))
+    {
+        QosClass::Guaranteed
+    } else {
+        QosClass::Burstable
+    }
+}
+
+/// Returns the value of the quantity if it counts towards the QoS class, i.e. it is for a QoS
+/// resource and is not zero.
+fn qos_quantity(name: &str, quantity: &str) -> Option<f64> {
+    if !QOS_RESOURCES.contains(&name) {
+        return None;
+    }
+    parse_quantity(quantity).filter(|value| *value != 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, Pod as KubePod, PodSpec, ResourceRequirements,
+    };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    fn quantities(values: &[(&str, &str)]) -> Option<BTreeMap<String, Quantity>> {
+        Some(
+            values
+                .iter()
+                .map(|(k, v)| (k.to_string(), Quantity(v.to_string())))
+                .collect(),
+        )
+    }
+
+    fn pod_with_resources(requests: &[(&str, &str)], limits: &[(&str, &str)]) -> Pod {
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "test".to_owned(),
+                    resources: Some(ResourceRequirements {
+                        requests: quantities(requests),
+                        limits: quantities(limits),
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_qos_class() {
+        assert_eq!(
+            QosClass::BestEffort,
+            qos_class(&pod_with_resources(&[], &[]))
+        );
+        assert_eq!(
+            QosClass::Burstable,
+            qos_class(&pod_with_resources(&[("cpu", "100m")], &[]))
+        );
+        assert_eq!(
+            QosClass::Guaranteed,
+            qos_class(&pod_with_resources(
+                &[("cpu", "1"), ("memory", "1Gi")],
+                &[("cpu", "1000m"), ("memory", "1Gi")]
+            ))
+        );
+        assert_eq!(
+            QosClass::Burstable,
+            qos_class(&pod_with_resources(
+                &[("cpu", "500m"), ("memory", "1Gi")],
+                &[("cpu", "1"), ("memory", "1Gi")]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_qos_class_ordering() {
+        let mut classes = vec![
+            QosClass::BestEffort,
+            QosClass::Guaranteed,
+            QosClass::Burstable,
+        ];
+        classes.sort();
+        assert_eq!(
+            vec![
+                QosClass::Guaranteed,
+                QosClass::Burstable,
+                QosClass::BestEffort
+            ],
+            classes
+        );
+        assert_eq!("BestEffort", QosClass::BestEffort.to_string());
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret, Volume};
+use kube::api::{Api, ListParams, Resource};
+use kube_runtime::watcher::{watcher, Event};
+use serde::de::DeserializeOwned;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::{Pod, PodKey};
+
+/// How long to wait before watching an object again after the watch failed.
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The kind of an object a pod refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RelatedKind {
+    /// A ConfigMap.
+    ConfigMap,
+    /// A Secret.
+    Secret,
+}
+
+/// An object a pod refers to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RelatedObject {
+    /// The kind of the object.
+    pub kind: RelatedKind,
+    /// The namespace of the object, which is always the namespace of the pod.
+    pub namespace: String,
+    /// The name of the object.
+    pub name: String,
+}
+
+/// A change to an object a pod refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelatedObjectUpdate {
+    /// The object was created or modified.
+    Applied(RelatedObject),
+    /// The object was deleted.
+    Deleted(RelatedObject),
+}
+
+/// Returns the ConfigMaps and Secrets the pod refers to, either as volumes or in the
+/// environment of its containers.
+pub fn related_objects(pod: &Pod) -> BTreeSet<RelatedObject> {
+    let object = |kind, name: &Option<String>| related_object(pod, kind, name);
+    let mut objects: BTreeSet<_> = pod
+        .volumes()
+        .into_iter()
+        .flatten()
+        .flat_map(|volume| volume_objects(pod, volume))
+        .collect();
+
+    for container in pod.all_containers() {
+        for source in container
+            .env()
+            .iter()
+            .flatten()
+            .filter_map(|e| e.value_from.as_ref())
+        {
+            if let Some(cm) = &source.config_map_key_ref {
+                objects.extend(object(RelatedKind::ConfigMap, &cm.name));
+            }
+            if let Some(secret) = &source.secret_key_ref {
+                objects.extend(object(RelatedKind::Secret, &secret.name));
+            }
+        }
+        for source in container.env_from().iter().flatten() {
+            if let Some(cm) = &source.config_map_ref {
+                objects.extend(object(RelatedKind::ConfigMap, &cm.name));
+            }
+            if let Some(secret) = &source.secret_ref {
+                objects.extend(object(RelatedKind::Secret, &secret.name));
+            }
+        }
+    }
+
+    objects
+}
+
+/// Returns the names of the pod's volumes whose files come from the object.
+pub fn volumes_referring_to(pod: &Pod, object: &RelatedObject) -> Vec<String> {
+    pod.volumes()
+        .into_iter()
+        .flatten()
+        .filter(|volume| volume_objects(pod, volume).contains(object))
+        .map(|volume| volume.name.clone())
+        .collect()
+}
+
+fn related_object(pod: &Pod, kind: RelatedKind, name: &Option<String>) -> Option<RelatedObject> {
+    name.as_ref().map(|name| RelatedObject {
+        kind,
+        namespace: pod.namespace().to_owned(),
+        name: name.clone(),
+    })
+}
+
+/// Returns the ConfigMaps and Secrets the files of the volume come from.
+fn volume_objects(pod: &Pod, volume: &Volume) -> BTreeSet<RelatedObject> {
+    let object = |kind, name: &Option<String>| related_object(pod, kind, name);
+    let mut objects = BTreeSet::new();
+    if let Some(cm) = &volume.config_map {
+        objects.extend(object(RelatedKind::ConfigMap, &cm.name));
+    }
+    if let Some(secret) = &volume.secret {
+        objects.extend(object(RelatedKind::Secret, &secret.secret_name));
+    }
+    let projections = volume
+        .projected
+        .as_ref()
+        .and_then(|p| p.sources.as_ref())
+        .into_iter()
+        .flatten();
+    for projection in projections {
+        if let Some(cm) = &projection.config_map {
+            objects.extend(object(RelatedKind::ConfigMap, &cm.name));
+        }
+        if let Some(secret) = &projection.secret {
+            objects.extend(object(RelatedKind::Secret, &secret.name));
+        }
+    }
+    objects
+}
+
+/// The updates to the objects a pod refers to, returned by [`RelatedObjects::subscribe`].
+/// Dropping the subscription stops the delivery of updates.
+pub struct Subscription {
+    objects: BTreeSet<RelatedObject>,
+    updates: mpsc::UnboundedReceiver<RelatedObjectUpdate>,
+}
+
+impl Subscription {
+    /// The objects this subscription delivers updates for.
+    pub fn objects(&self) -> &BTreeSet<RelatedObject> {
+        &self.objects
+    }
+
+    /// Receive the next update. Returns `None` if the subscription was cancelled, or if the pod
+    /// does not refer to any objects.
+    pub async fn recv(&mut self) -> Option<RelatedObjectUpdate> {
+        self.updates.recv().await
+    }
+}
+
+struct Watch {
+    subscribers: Vec<(PodKey, mpsc::UnboundedSender<RelatedObjectUpdate>)>,
+    task: JoinHandle<()>,
+}
+
+type Watches = Arc<Mutex<HashMap<RelatedObject, Watch>>>;
+
+/// Lets pod state machines react to changes of the ConfigMaps and Secrets their pods refer to,
+/// without each state setting up its own watches.
+///
+/// A state subscribes with [`RelatedObjects::subscribe`] and receives [`RelatedObjectUpdate`]s
+/// on the returned [`Subscription`], typically in the same `select!` as its other events. Each
+/// object is watched once however many pods refer to it, and the watch stops once no
+/// subscription needs it anymore.
+#[derive(Clone)]
+pub struct RelatedObjects {
+    client: kube::Client,
+    watches: Watches,
+}
+
+impl RelatedObjects {
+    /// Create a new `RelatedObjects` using the given client for watching objects.
+    pub fn new(client: kube::Client) -> Self {
+        RelatedObjects {
+            client,
+            watches: Default::default(),
+        }
+    }
+
+    /// Subscribe to the changes of the objects the pod refers to. Subscribing a pod again
+    /// replaces its previous subscription.
+    ///
+    /// Only changes made after subscribing are delivered.
+    pub async fn subscribe(&self, pod: &Pod) -> Subscription {
+        let key = PodKey::from(pod);
+        let objects = related_objects(pod);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let mut watches = self.watches.lock().await;
+        remove_subscriber(&mut watches, &key);
+        for object in &objects {
+            let watch = watches.entry(object.clone()).or_insert_with(|| Watch {
+                subscribers: Vec::new(),
+                task: self.spawn_watch(object.clone()),
+            });
+            watch.subscribers.push((key.clone(), tx.clone()));
+        }
+        debug!(pod = %key, num_objects = objects.len(), "Subscribed to related objects");
+
+        Subscription {
+            objects,
+            updates: rx,
+        }
+    }
+
+    /// Stop delivering updates to the given pod.
+    pub async fn unsubscribe(&self, key: &PodKey) {
+        remove_subscriber(&mut *self.watches.lock().await, key);
+    }
+
+    fn spawn_watch(&self, object: RelatedObject) -> JoinHandle<()> {
+        let watches = self.watches.clone();
+        match object.kind {
+            RelatedKind::ConfigMap => {
+                let api: Api<ConfigMap> = Api::namespaced(self.client.clone(), &object.namespace);
+                tokio::spawn(watch_object(api, object, watches))
+            }
+            RelatedKind::Secret => {
+                let api: Api<Secret> = Api::namespaced(self.client.clone(), &object.namespace);
+                tokio::spawn(watch_object(api, object, watches))
+            }
+        }
+    }
+}
+
+/// Removes the pod (by name) from all watches, along with any subscriptions that were dropped,
+/// and stops the watches nobody is subscribed to anymore.
+fn remove_subscriber(watches: &mut HashMap<RelatedObject, Watch>, key: &PodKey) {
+    watches.retain(|_, watch| {
+        watch
+            .subscribers
+            .retain(|(subscriber, tx)| subscriber != key && !tx.is_closed());
+        if watch.subscribers.is_empty() {
+            watch.task.abort();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+async fn watch_object<K>(api: Api<K>, object: RelatedObject, watches: Watches)
+where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + 'static,
+{
+    let params = ListParams::default().fields(&format!("metadata.name={}", object.name));
+    let mut events = watcher(api, params).boxed();
+    let mut last_version = None;
+    while let Some(event) = events.next().await {
+        let current = match event {
+            Ok(Event::Applied(o)) => o.meta().resource_version.clone(),
+            Ok(Event::Deleted(_)) => None,
+            Ok(Event::Restarted(objects)) => objects
+                .first()
+                .and_then(|o| o.meta().resource_version.clone()),
+            Err(e) => {
+                warn!(error = %e, object = %object.name, "Error watching related object");
+                tokio::time::sleep(WATCH_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Some(update) = observe(&object, &mut last_version, current) {
+            if !notify(&watches, &object, update).await {
+                return;
+            }
+        }
+    }
+}
+
+/// Records the current resource version of the object (`None` if it doesn't exist), returning
+/// the update to deliver if it changed. The first version observed is the starting point, so it
+/// is never delivered.
+fn observe(
+    object: &RelatedObject,
+    last_version: &mut Option<Option<String>>,
+    current: Option<String>,
+) -> Option<RelatedObjectUpdate> {
+    match last_version.replace(current.clone()) {
+        None => None,
+        Some(last) if last == current => None,
+        Some(_) => match current {
+            Some(_) => Some(RelatedObjectUpdate::Applied(object.clone())),
+            None => Some(RelatedObjectUpdate::Deleted(object.clone())),
+        },
+    }
+}
+
+/// Delivers the update to the subscribers of the object. Returns false if nobody is subscribed
+/// anymore, in which case the watch should stop.
+async fn notify(watches: &Watches, object: &RelatedObject, update: RelatedObjectUpdate) -> bool {
+    let mut watches = watches.lock().await;
+    let watch = match watches.get_mut(object) {
+        Some(watch) => watch,
+        None => return false,
+    };
+    watch
+        .subscribers
+        .retain(|(_, tx)| tx.send(update.clone()).is_ok());
+    if watch.subscribers.is_empty() {
+        watches.remove(object);
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        ConfigMapKeySelector, ConfigMapVolumeSource, Container as KubeContainer, EnvFromSource,
+        EnvVar, EnvVarSource, Pod as KubePod, PodSpec, SecretEnvSource, SecretVolumeSource,
+        Volume as KubeVolume,
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn related(kind: RelatedKind, name: &str) -> RelatedObject {
+        RelatedObject {
+            kind,
+            namespace: "default".to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_related_objects() {
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("foo".to_owned()),
+                namespace: Some("default".to_owned()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "test".to_owned(),
+                    env: Some(vec![EnvVar {
+                        name: "SETTING".to_owned(),
+                        value_from: Some(EnvVarSource {
+                            config_map_key_ref: Some(ConfigMapKeySelector {
+                                key: "setting".to_owned(),
+                                name: Some("settings".to_owned()),
+                                optional: None,
+                            }),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }]),
+                    env_from: Some(vec![EnvFromSource {
+                        secret_ref: Some(SecretEnvSource {
+                            name: Some("credentials".to_owned()),
+                            optional: None,
+                        }),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                }],
+                volumes: Some(vec![
+                    KubeVolume {
+                        name: "config".to_owned(),
+                        config_map: Some(ConfigMapVolumeSource {
+                            name: Some("settings".to_owned()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    KubeVolume {
+                        name: "certs".to_owned(),
+                        secret: Some(SecretVolumeSource {
+                            secret_name: Some("certs".to_owned()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let objects: Vec<_> = related_objects(&pod).into_iter().collect();
+        assert_eq!(
+            vec![
+                related(RelatedKind::ConfigMap, "settings"),
+                related(RelatedKind::Secret, "certs"),
+                related(RelatedKind::Secret, "credentials"),
+            ],
+            objects
+        );
+        assert_eq!(
+            vec!["config".to_owned()],
+            volumes_referring_to(&pod, &related(RelatedKind::ConfigMap, "settings"))
+        );
+        assert!(
+            volumes_referring_to(&pod, &related(RelatedKind::Secret, "credentials")).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_observe() {
+        let object = related(RelatedKind::ConfigMap, "settings");
+        let mut last = None;
+        // The initial state is never delivered
+        assert_eq!(None, observe(&object, &mut last, Some("1".to_owned())));
+        assert_eq!(None, observe(&object, &mut last, Some("1".to_owned())));
+        assert_eq!(
+            Some(RelatedObjectUpdate::Applied(object.clone())),
+            observe(&object, &mut last, Some("2".to_owned()))
+        );
+        assert_eq!(
+            Some(RelatedObjectUpdate::Deleted(object.clone())),
+            observe(&object, &mut last, None)
+        );
+        assert_eq!(None, observe(&object, &mut last, None));
+
+        // An object that doesn't exist yet is applied when it is created
+        let mut last = None;
+        assert_eq!(None, observe(&object, &mut last, None));
+        assert_eq!(
+            Some(RelatedObjectUpdate::Applied(object.clone())),
+            observe(&object, &mut last, Some("1".to_owned()))
+        );
+    }
+}
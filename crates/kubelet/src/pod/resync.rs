@@ -0,0 +1,105 @@
+use sha2::{Digest, Sha256};
+
+use super::Pod;
+
+/// Tells updates of a pod's manifest that change it apart from the same manifest being
+/// delivered again, as happens for every pod whenever the pods are relisted or the Kubelet
+/// restarts, so that unchanged manifests don't set the pod's containers going again.
+///
+/// An update is unchanged if it has the `resourceVersion` of the last manifest that was
+/// dispatched, or failing that the same hash. The hash leaves out the pod's status and the
+/// metadata the API server changes along with it, so the Kubelet's own status updates count as
+/// unchanged too. Updates are counted in the
+/// [pod metrics](crate::metrics::PodMetrics::record_update).
+#[derive(Debug)]
+pub struct ManifestChanges {
+    resource_version: Option<String>,
+    hash: Vec<u8>,
+}
+
+impl ManifestChanges {
+    /// Track the updates of a pod, starting from the manifest it was dispatched with.
+    pub fn new(pod: &Pod) -> Self {
+        ManifestChanges {
+            resource_version: resource_version(pod),
+            hash: manifest_hash(pod),
+        }
+    }
+
+    /// Whether the update changes the pod's manifest, in which case it is dispatched and later
+    /// updates are compared against it instead.
+    pub fn changed(&mut self, pod: &Pod) -> bool {
+        let resource_version = resource_version(pod);
+        let changed = if resource_version.is_some() && resource_version == self.resource_version {
+            false
+        } else {
+            let hash = manifest_hash(pod);
+            self.resource_version = resource_version;
+            if hash == self.hash {
+                false
+            } else {
+                self.hash = hash;
+                true
+            }
+        };
+        crate::metrics::pod_metrics().record_update(changed);
+        changed
+    }
+}
+
+fn resource_version(pod: &Pod) -> Option<String> {
+    pod.as_kube_pod().metadata.resource_version.clone()
+}
+
+fn manifest_hash(pod: &Pod) -> Vec<u8> {
+    let mut manifest = pod.as_kube_pod().clone();
+    manifest.status = None;
+    manifest.metadata.resource_version = None;
+    manifest.metadata.managed_fields = None;
+    // Serializing a pod can't fail
+    let manifest = serde_json::to_vec(&manifest).unwrap_or_default();
+    Sha256::digest(&manifest).to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, Pod as KubePod, PodSpec, PodStatus,
+    };
+    use kube::api::ObjectMeta;
+
+    fn make_pod(resource_version: &str, image: &str, phase: &str) -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("foo".to_owned()),
+                namespace: Some("default".to_owned()),
+                resource_version: Some(resource_version.to_owned()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "app".to_owned(),
+                    image: Some(image.to_owned()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                phase: Some(phase.to_owned()),
+                ..Default::default()
+            }),
+        })
+    }
+
+    #[test]
+    fn test_unchanged_manifests_are_skipped() {
+        let mut changes = ManifestChanges::new(&make_pod("1", "app:1", "Pending"));
+        // Delivered again on a relist
+        assert!(!changes.changed(&make_pod("1", "app:1", "Pending")));
+        // The Kubelet's own status update
+        assert!(!changes.changed(&make_pod("2", "app:1", "Running")));
+        assert!(changes.changed(&make_pod("3", "app:2", "Running")));
+        assert!(!changes.changed(&make_pod("3", "app:2", "Running")));
+    }
+}
@@ -1,34 +1,56 @@
 //! Container statuses
 
-use super::Pod;
+use super::{Pod, QosClass};
 use crate::container::make_initial_container_status;
+use crate::reason::Reason;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
 use k8s_openapi::api::core::v1::PodStatus as KubePodStatus;
 use krator::{Manifest, ObjectStatus};
 use kube::api::PatchParams;
+use kube::error::ErrorResponse;
 use kube::Api;
 use tracing::{debug, instrument, warn};
 
+/// The number of times a Pod status patch is retried after conflicting with another update.
+const MAX_STATUS_PATCH_RETRIES: usize = 5;
+
 /// Patch Pod status with Kubernetes API.
+///
+/// The patch only contains the fields being set, so if it conflicts with another update to the
+/// pod it is simply reapplied on top of the latest version. Errors are logged rather than
+/// returned, since a later status update will supersede this one.
 #[instrument(level = "info", skip(api, name, status), fields(pod_name = name))]
 pub async fn patch_status(api: &Api<KubePod>, name: &str, status: Status) {
     let patch = status.json_patch();
     debug!(?patch, "Applying status patch to pod");
-    match api
-        .patch_status(
-            &name,
-            &PatchParams::default(),
-            &kube::api::Patch::Strategic(patch),
-        )
-        .await
-    {
-        Ok(_) => (),
-        Err(e) => {
-            warn!(error = %e, "Error patching pod status");
+    let mut backoff = std::time::Duration::from_millis(100);
+    for attempt in 1..=MAX_STATUS_PATCH_RETRIES {
+        match api
+            .patch_status(
+                &name,
+                &PatchParams::default(),
+                &kube::api::Patch::Strategic(&patch),
+            )
+            .await
+        {
+            Ok(_) => return,
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => {
+                debug!(attempt, "Pod status patch conflicted, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                warn!(error = %e, "Error patching pod status");
+                return;
+            }
         }
     }
+    warn!(
+        attempts = MAX_STATUS_PATCH_RETRIES,
+        "Giving up on pod status patch after repeated conflicts"
+    );
 }
 
 const MAX_STATUS_INIT_RETRIES: usize = 5;
@@ -111,12 +133,13 @@ pub fn make_registered_status(pod: &Pod) -> Status {
         .iter()
         .map(make_initial_container_status)
         .collect();
-    make_status_with_containers(
-        Phase::Pending,
-        "Registered",
-        container_statuses,
-        init_container_statuses,
-    )
+    StatusBuilder::new()
+        .phase(Phase::Pending)
+        .reason("Registered")
+        .container_statuses(container_statuses)
+        .init_container_statuses(init_container_statuses)
+        .qos_class(pod.qos_class())
+        .build()
 }
 
 /// Create basic Pod status patch.
@@ -193,6 +216,12 @@ impl StatusBuilder {
         self
     }
 
+    /// Set Pod QoS class.
+    pub fn qos_class(mut self, qos_class: QosClass) -> StatusBuilder {
+        self.0.qos_class = Some(qos_class.to_string());
+        self
+    }
+
     /// Set Pod conditions.
     pub fn conditions(mut self, conditions: Vec<KubePodCondition>) -> StatusBuilder {
         self.0.conditions = Some(conditions);
@@ -257,6 +286,10 @@ impl ObjectStatus for Status {
             status.insert("initContainerStatuses".to_string(), serde_json::json!(s));
         };
 
+        if let Some(s) = self.0.qos_class.clone() {
+            status.insert("qosClass".to_string(), serde_json::Value::String(s));
+        };
+
         if let Some(s) = self.0.conditions.clone() {
             status.insert("conditions".to_string(), serde_json::json!(s));
         };
@@ -275,7 +308,7 @@ impl ObjectStatus for Status {
         StatusBuilder::new()
             .phase(Phase::Failed)
             .message(e)
-            .reason(e)
+            .reason(Reason::Error.as_str())
             .build()
     }
 }
@@ -0,0 +1,188 @@
+use chrono::Utc;
+use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+use super::Pod;
+
+/// The pod condition type Kubernetes uses to mark pods terminated because of a disruption, such
+/// as an eviction through the Eviction API.
+pub(crate) const DISRUPTION_TARGET: &str = "DisruptionTarget";
+
+/// The `DisruptionTarget` reason of pods terminated by a kubelet, for whatever reason. Which
+/// reason it was is told by the pod's status reason.
+const TERMINATION_BY_KUBELET: &str = "TerminationByKubelet";
+
+/// Why a pod is being terminated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminationReason {
+    /// The pod was deleted, by a user or a controller.
+    UserDeleted,
+    /// The pod was evicted, e.g. through the Eviction API when its node was drained.
+    Evicted,
+    /// The pod was preempted by the scheduler to make room for a pod with a higher priority.
+    Preempted,
+    /// The pod was terminated because its node is shutting down.
+    NodeShutdown,
+    /// The pod had already failed when it was deleted.
+    Failure,
+}
+
+impl TerminationReason {
+    /// Works out why a deleted pod is being terminated from the `DisruptionTarget` condition
+    /// the API server or the scheduler gave it, or the status reason the Kubelet gave it when it
+    /// terminated the pod itself, falling back on its phase. Pods without any of these were
+    /// deleted by a user or controller.
+    pub fn from_pod(pod: &Pod) -> Self {
+        let disruption = pod
+            .condition(DISRUPTION_TARGET)
+            .filter(|condition| condition.status == "True")
+            .and_then(|condition| condition.reason.as_deref());
+        let status = pod.as_kube_pod().status.as_ref();
+        match disruption {
+            Some("PreemptionByScheduler") | Some("PreemptionByKubeScheduler") => Self::Preempted,
+            Some("EvictionByEvictionAPI") | Some("DeletionByTaintManager") => Self::Evicted,
+            _ if status.and_then(|s| s.reason.as_deref()) == Some(Self::NodeShutdown.reason()) => {
+                Self::NodeShutdown
+            }
+            _ if status.and_then(|s| s.reason.as_deref()) == Some("Evicted") => Self::Evicted,
+            _ if status.and_then(|s| s.phase.as_deref()) == Some("Failed") => Self::Failure,
+            _ => Self::UserDeleted,
+        }
+    }
+
+    /// The reason a pod terminated for this reason reports in its status.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::UserDeleted => "Terminated",
+            Self::Evicted => "Evicted",
+            Self::Preempted => "Preempted",
+            Self::NodeShutdown => "NodeShutdown",
+            Self::Failure => "Failed",
+        }
+    }
+
+    /// The message a pod terminated for this reason reports in its status.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::UserDeleted => "Pod was deleted",
+            Self::Evicted => "Pod was evicted",
+            Self::Preempted => "Pod was preempted to make room for a higher priority pod",
+            Self::NodeShutdown => "Pod was terminated in response to node shutdown",
+            Self::Failure => "Pod failed before it was deleted",
+        }
+    }
+}
+
+impl std::fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+/// The status the Kubelet gives the pods it evicts when its node shuts down, so that their state
+/// machines terminate them as [`TerminationReason::NodeShutdown`]. Besides the generic
+/// `DisruptionTarget` condition of pods terminated by a kubelet, this sets the pod's status
+/// reason, which is what tells the shutdown apart.
+pub(crate) fn node_shutdown_status() -> serde_json::Value {
+    let reason = TerminationReason::NodeShutdown;
+    let condition = KubePodCondition {
+        type_: DISRUPTION_TARGET.to_owned(),
+        status: "True".to_owned(),
+        reason: Some(TERMINATION_BY_KUBELET.to_owned()),
+        message: Some(reason.message().to_owned()),
+        last_transition_time: Some(Time(Utc::now())),
+        last_probe_time: None,
+    };
+    serde_json::json!({
+        "reason": reason.reason(),
+        "message": reason.message(),
+        "conditions": [condition],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Pod as KubePod, PodStatus as KubePodStatus};
+
+    fn pod_with_status(
+        disruption_reason: Option<&str>,
+        reason: Option<&str>,
+        phase: Option<&str>,
+    ) -> Pod {
+        let conditions = disruption_reason.map(|reason| {
+            vec![KubePodCondition {
+                type_: DISRUPTION_TARGET.to_owned(),
+                status: "True".to_owned(),
+                reason: Some(reason.to_owned()),
+                ..Default::default()
+            }]
+        });
+        Pod::from(KubePod {
+            status: Some(KubePodStatus {
+                conditions,
+                reason: reason.map(str::to_owned),
+                phase: phase.map(str::to_owned),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_reason_from_disruption_condition() {
+        assert_eq!(
+            TerminationReason::Evicted,
+            TerminationReason::from_pod(&pod_with_status(
+                Some("EvictionByEvictionAPI"),
+                None,
+                Some("Running")
+            ))
+        );
+        assert_eq!(
+            TerminationReason::Preempted,
+            TerminationReason::from_pod(&pod_with_status(
+                Some("PreemptionByScheduler"),
+                None,
+                Some("Running")
+            ))
+        );
+        assert_eq!(
+            TerminationReason::NodeShutdown,
+            TerminationReason::from_pod(&pod_with_status(
+                Some(TERMINATION_BY_KUBELET),
+                Some("NodeShutdown"),
+                Some("Running")
+            ))
+        );
+        // The generic kubelet reason alone doesn't mean the node is shutting down
+        assert_eq!(
+            TerminationReason::UserDeleted,
+            TerminationReason::from_pod(&pod_with_status(
+                Some(TERMINATION_BY_KUBELET),
+                None,
+                Some("Running")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_reason_from_status() {
+        assert_eq!(
+            TerminationReason::Evicted,
+            TerminationReason::from_pod(&pod_with_status(None, Some("Evicted"), Some("Failed")))
+        );
+        assert_eq!(
+            TerminationReason::Failure,
+            TerminationReason::from_pod(&pod_with_status(None, None, Some("Failed")))
+        );
+        assert_eq!(
+            TerminationReason::UserDeleted,
+            TerminationReason::from_pod(&pod_with_status(None, None, Some("Running")))
+        );
+        assert_eq!(
+            TerminationReason::UserDeleted,
+            TerminationReason::from_pod(&Pod::from(KubePod::default()))
+        );
+    }
+}
@@ -1,5 +1,6 @@
 //! Traits and types needed to create backend providers for a Kubelet
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use async_trait::async_trait;
 use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
@@ -8,13 +9,16 @@ use std::sync::Arc;
 use thiserror::Error;
 use tracing::{debug, error, info};
 
+use crate::attestation::PodModules;
+use crate::config::Config;
 use crate::container::Container;
 use crate::log::Sender;
-use crate::node::Builder;
+use crate::node::{Builder, NodeInfo, NodeStatus};
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Pod;
 use crate::pod::Status as PodStatus;
+use crate::pod::{Pod, PortMapping};
 use crate::resources::DeviceManager;
+use crate::store::Store;
 use krator::{ObjectState, State};
 
 /// A back-end for a Kubelet.
@@ -108,6 +112,46 @@ pub trait Provider: Sized + Send + Sync + 'static {
     /// Arch returns a string specifying what architecture this provider supports
     const ARCH: &'static str;
 
+    /// Construct the provider from the Kubelet's configuration, the information the node is
+    /// registered with and the clients it shares with the Kubelet. This is the standard way to
+    /// create a provider, so that binaries can set up any provider the same way.
+    ///
+    /// Work that has to finish before the provider can run pods, such as warming caches or
+    /// starting hosts, needn't hold up construction: start it here and wait for it in
+    /// [`ready`](Provider::ready).
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available.
+    async fn initialize(
+        _config: &Config,
+        _node_info: NodeInfo,
+        _clients: ProviderClients,
+    ) -> anyhow::Result<Self> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Wait until the provider is able to run pods. The Kubelet waits for this before it
+    /// registers the node, so the node only becomes Ready once pods can actually run on it,
+    /// and fails to start if this returns an error.
+    ///
+    /// The default implementation is ready straight away.
+    async fn ready(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Take over the workloads of the pods another Kubelet process was running when it handed
+    /// off the node to this one. This is called before [`Provider::ready`], and before any pod
+    /// is picked up from the API server, so a provider whose workloads outlive the Kubelet
+    /// process can adopt them here instead of starting them again. The Kubelet fails to start
+    /// if this returns an error.
+    ///
+    /// The default implementation adopts nothing, so the workloads of the handed off pods are
+    /// started again.
+    #[cfg(target_family = "unix")]
+    async fn adopt_pods(&self, _checkpoint: &crate::handoff::Checkpoint) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Gets the provider state.
     fn provider_state(&self) -> krator::SharedState<Self::ProviderState>;
 
@@ -116,6 +160,13 @@ pub trait Provider: Sized + Send + Sync + 'static {
         Ok(())
     }
 
+    /// The resources and conditions of the node as the provider sees them. This is called when
+    /// the node is created and every time the Kubelet updates the node status, so it should
+    /// return quickly. Resources and conditions that aren't reported keep the Kubelet's defaults.
+    async fn node_status(&self) -> anyhow::Result<NodeStatus> {
+        Ok(NodeStatus::default())
+    }
+
     /// Hook to allow provider to introduced shared state into Pod state.
     // TODO: Is there a way to provide a default implementation of this if Self::PodState: Default?
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState>;
@@ -148,11 +199,81 @@ pub trait Provider: Sized + Send + Sync + 'static {
         sender: Sender,
     ) -> anyhow::Result<()>;
 
-    /// Execute a given command on a workload and then return the result.
+    /// Run the command given by `sender` in a container of a pod, streaming
+    /// its output to `sender` and its input from `receiver` until it exits,
+    /// then return its exit code.
     ///
     /// The default implementation of this returns a message that this feature is
     /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
+    async fn exec(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _sender: crate::exec::Sender,
+        _receiver: crate::exec::Receiver,
+    ) -> anyhow::Result<i32> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Attach to the standard streams of a running container, streaming its
+    /// output to `sender` and its input from `receiver` until it exits or the
+    /// client disconnects.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn attach(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _sender: crate::exec::Sender,
+        _receiver: crate::exec::Receiver,
+    ) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Forward a connection to a port of a pod, copying data between `stream`
+    /// and the workload until either side closes it.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn port_forward(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _port: u16,
+        _stream: tokio::io::DuplexStream,
+    ) -> anyhow::Result<()> {
+        Err(NotImplementedError.into())
+    }
+
+    /// List the container ports of pods that are mapped to node ports.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only for providers that map container ports,
+    /// typically using a [`PortMapper`](crate::pod::PortMapper).
+    async fn port_mappings(&self) -> anyhow::Result<Vec<PortMapping>> {
+        Err(NotImplementedError.into())
+    }
+
+    /// List the modules run by the containers of each pod on the node, with their digests and
+    /// where they are stored, so that they can be attested to.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only for providers that keep track of the modules they
+    /// run, typically using a [`ModuleRegistry`](crate::attestation::ModuleRegistry).
+    async fn running_modules(&self) -> anyhow::Result<Vec<PodModules>> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Preview whether a pod would start on this node, without starting anything, returning
+    /// the reasons it would be rejected. An empty list means the pod would be accepted.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Providers built on the generic states can implement this with
+    /// [`preview_pod`](crate::state::common::preview_pod).
+    async fn preview_pod(&self, _pod: &Pod) -> anyhow::Result<Vec<String>> {
         Err(NotImplementedError.into())
     }
 
@@ -168,27 +289,19 @@ pub trait Provider: Sized + Send + Sync + 'static {
         pod: &Pod,
         client: &kube::Client,
     ) -> HashMap<String, String> {
-        let mut env = HashMap::new();
+        let mut env = default_env_vars(container, pod);
         let vars = match container.env().as_ref() {
             Some(e) => e,
             None => return env,
         };
 
-        for env_var in vars.clone().into_iter() {
-            let key = env_var.name;
-            let value = match env_var.value {
-                Some(v) => v,
-                None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
-                }
+        let mut sources = EnvSources::new(pod, client);
+        for env_var in vars {
+            let value = match &env_var.value {
+                Some(v) => v.clone(),
+                None => sources.value(env_var.value_from.as_ref()).await,
             };
-            env.insert(key, value);
+            env.insert(env_var.name.clone(), value);
         }
         env
     }
@@ -200,6 +313,12 @@ pub trait VolumeSupport {
     fn volume_path(&self) -> Option<&std::path::Path> {
         None
     }
+
+    /// Gets the directory containing executable plugins for `flexVolume` volumes. Defaults to
+    /// `None`, in which case flex volumes are not supported.
+    fn volume_plugins_dir(&self) -> Option<&std::path::Path> {
+        None
+    }
 }
 
 /// A trait for specifying whether plugins are supported. Defaults to `None`
@@ -218,6 +337,43 @@ pub trait DevicePluginSupport {
     }
 }
 
+/// The clients and services a provider shares with the Kubelet, passed to
+/// [`Provider::initialize`].
+#[derive(Clone)]
+pub struct ProviderClients {
+    /// The configuration for talking to the Kubernetes API
+    pub kube_config: kube::Config,
+    /// The store modules are pulled into
+    pub store: Arc<dyn Store + Send + Sync>,
+    /// The plugins registered with the node, such as CSI drivers
+    pub plugin_registry: Arc<PluginRegistry>,
+    /// The device plugins registered with the node and the devices they advertise
+    pub device_plugin_manager: Arc<DeviceManager>,
+}
+
+impl ProviderClients {
+    /// Create the clients for a Kubelet with the given configuration, watching the configured
+    /// plugin and device plugin directories. The store is made by the caller, as the modules
+    /// it pulls depend on the provider.
+    pub fn new(
+        config: &Config,
+        kube_config: kube::Config,
+        store: Arc<dyn Store + Send + Sync>,
+    ) -> anyhow::Result<Self> {
+        let client = kube::Client::try_from(kube_config.clone())?;
+        Ok(ProviderClients {
+            plugin_registry: Arc::new(PluginRegistry::new(&config.plugins_dir)),
+            device_plugin_manager: Arc::new(DeviceManager::new(
+                &config.device_plugins_dir,
+                client,
+                &config.node_name,
+            )),
+            kube_config,
+            store,
+        })
+    }
+}
+
 /// Resolve the environment variables for a container.
 ///
 /// This generally should not be overwritten unless you need to handle
@@ -230,103 +386,163 @@ pub async fn env_vars(
     pod: &Pod,
     client: &kube::Client,
 ) -> HashMap<String, String> {
-    let mut env = HashMap::new();
+    env_vars_with_redactor(container, pod, client).await.0
+}
+
+/// Resolve the environment variables for a container, along with a
+/// [`Redactor`](crate::secret::Redactor) for the values taken from Secrets.
+///
+/// Use the redactor on anything derived from the environment or from running
+/// the container that is logged or reported, such as error messages.
+pub async fn env_vars_with_redactor(
+    container: &Container,
+    pod: &Pod,
+    client: &kube::Client,
+) -> (HashMap<String, String>, crate::secret::Redactor) {
+    let mut env = default_env_vars(container, pod);
+    let mut redactor = crate::secret::Redactor::default();
     let vars = match container.env().as_ref() {
         Some(e) => e,
-        None => return env,
+        None => return (env, redactor),
     };
 
-    for env_var in vars.clone().into_iter() {
-        let key = env_var.name;
-        let value = match env_var.value {
-            Some(v) => v,
-            None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
-                    .await
-            }
+    let mut sources = EnvSources::new(pod, client);
+    for env_var in vars {
+        let from_secret = env_var
+            .value_from
+            .as_ref()
+            .map_or(false, |source| source.secret_key_ref.is_some());
+        let value = match &env_var.value {
+            Some(v) => v.clone(),
+            None => sources.value(env_var.value_from.as_ref()).await,
         };
-        env.insert(key, value);
+        if from_secret {
+            redactor.add_secret(&env_var.name, &value);
+        }
+        env.insert(env_var.name.clone(), value);
+    }
+    (env, redactor)
+}
+
+/// The environment variables set in every container so that module output can be correlated
+/// with the pod it came from. Anything set explicitly in the container spec takes precedence.
+fn default_env_vars(container: &Container, pod: &Pod) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+    env.insert("POD_NAME".to_owned(), pod.name().to_owned());
+    env.insert("POD_NAMESPACE".to_owned(), pod.namespace().to_owned());
+    env.insert("CONTAINER_NAME".to_owned(), container.name().to_owned());
+    if let Some(uid) = pod.as_kube_pod().metadata.uid.as_ref() {
+        env.insert("POD_UID".to_owned(), uid.clone());
+    }
+    if let Some(node_name) = pod.node_name() {
+        env.insert("NODE_NAME".to_owned(), node_name.to_owned());
     }
     env
 }
 
-/// Called when an env var does not have a value associated with.
+/// Resolves the values of environment variables that come from other sources.
 ///
-/// This follows the env_var_source to get the value
-#[doc(hidden)]
-async fn on_missing_env_value(
-    env_var_source: Option<EnvVarSource>,
-    client: &kube::Client,
-    ns: &str,
-    fields: &HashMap<String, String>,
-) -> String {
-    let env_src = match env_var_source {
-        Some(env_src) => env_src,
-        None => return String::new(),
-    };
+/// Sources are only looked at once a variable needs them, and each ConfigMap and Secret is
+/// fetched at most once, so a container with hundreds of variables taken from the same objects
+/// doesn't fetch them, or rebuild the Downward API fields, for each variable.
+struct EnvSources<'a> {
+    pod: &'a Pod,
+    client: &'a kube::Client,
+    fields: Option<HashMap<String, String>>,
+    config_maps: HashMap<String, Option<ConfigMap>>,
+    secrets: HashMap<String, Option<Secret>>,
+}
 
-    // ConfigMaps
-    if let Some(cfkey) = env_src.config_map_key_ref.as_ref() {
-        let name = cfkey.name.as_deref().unwrap_or_default();
-        match Api::<ConfigMap>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(cfgmap) => {
-                // I am not totally clear on what the outcome should
-                // be of a cfgmap key miss. So for now just return an
-                // empty default.
-                return cfgmap
-                    .data
-                    .unwrap_or_default()
-                    .get(&cfkey.key)
-                    .cloned()
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                error!(error = %e, name, "Error fetching config map");
-                return "".to_string();
-            }
+impl<'a> EnvSources<'a> {
+    fn new(pod: &'a Pod, client: &'a kube::Client) -> Self {
+        EnvSources {
+            pod,
+            client,
+            fields: None,
+            config_maps: HashMap::new(),
+            secrets: HashMap::new(),
         }
     }
-    // Secrets
-    if let Some(seckey) = env_src.secret_key_ref.as_ref() {
-        let name = seckey.name.as_deref().unwrap_or_default();
-        match Api::<Secret>::namespaced(client.clone(), ns)
-            .get(name)
-            .await
-        {
-            Ok(secret) => {
-                // I am not totally clear on what the outcome should
-                // be of a secret key miss. So for now just return an
-                // empty default.
-                return secret
-                    .data
-                    .unwrap_or_default()
-                    .remove(&seckey.key)
-                    .map(|s| String::from_utf8(s.0).unwrap_or_default())
-                    .unwrap_or_default();
+
+    /// The value of a variable without a value of its own, following its source.
+    async fn value(&mut self, env_src: Option<&EnvVarSource>) -> String {
+        let env_src = match env_src {
+            Some(env_src) => env_src,
+            None => return String::new(),
+        };
+
+        // ConfigMaps
+        if let Some(cfkey) = env_src.config_map_key_ref.as_ref() {
+            let name = cfkey.name.as_deref().unwrap_or_default();
+            if !self.config_maps.contains_key(name) {
+                let config_map =
+                    match Api::<ConfigMap>::namespaced(self.client.clone(), self.pod.namespace())
+                        .get(name)
+                        .await
+                    {
+                        Ok(cfgmap) => Some(cfgmap),
+                        Err(e) => {
+                            error!(error = %e, name, "Error fetching config map");
+                            None
+                        }
+                    };
+                self.config_maps.insert(name.to_owned(), config_map);
             }
-            Err(e) => {
-                error!(error = %e, name, "Error fetching secret");
-                return String::new();
+            // I am not totally clear on what the outcome should
+            // be of a cfgmap key miss. So for now just return an
+            // empty default.
+            return self.config_maps[name]
+                .as_ref()
+                .and_then(|cfgmap| cfgmap.data.as_ref()?.get(&cfkey.key).cloned())
+                .unwrap_or_default();
+        }
+        // Secrets
+        if let Some(seckey) = env_src.secret_key_ref.as_ref() {
+            let name = seckey.name.as_deref().unwrap_or_default();
+            if !self.secrets.contains_key(name) {
+                let secret =
+                    match Api::<Secret>::namespaced(self.client.clone(), self.pod.namespace())
+                        .get(name)
+                        .await
+                    {
+                        Ok(secret) => Some(secret),
+                        Err(e) => {
+                            error!(error = %e, name, "Error fetching secret");
+                            None
+                        }
+                    };
+                self.secrets.insert(name.to_owned(), secret);
             }
+            // I am not totally clear on what the outcome should
+            // be of a secret key miss. So for now just return an
+            // empty default.
+            return self.secrets[name]
+                .as_ref()
+                .and_then(|secret| secret.data.as_ref()?.get(&seckey.key))
+                .map(|s| String::from_utf8(s.0.clone()).unwrap_or_default())
+                .unwrap_or_default();
         }
-    }
-    // Downward API (Field Refs)
-    if let Some(cfkey) = env_src.field_ref.as_ref() {
-        return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
-    }
-    // Reource Fields (Not implementable just yet... need more of a model.)
+        // Downward API (Field Refs)
+        if let Some(cfkey) = env_src.field_ref.as_ref() {
+            let pod = self.pod;
+            return self
+                .fields
+                .get_or_insert_with(|| field_map(pod))
+                .get(&cfkey.field_path)
+                .cloned()
+                .unwrap_or_default();
+        }
+        // Reource Fields (Not implementable just yet... need more of a model.)
 
-    String::new()
+        String::new()
+    }
 }
 
 /// Build the map of allowable field_ref values.
 ///
 /// The Downward API only supports a small selection of fields. This
 /// provides those fields.
-fn field_map(pod: &Pod) -> HashMap<String, String> {
+pub(crate) fn field_map(pod: &Pod) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     map.insert("metadata.name".into(), pod.name().to_owned());
     map.insert("metadata.namespace".into(), pod.namespace().to_owned());
@@ -375,3 +591,77 @@ pub enum ProviderError {
 #[derive(Error, Debug)]
 #[error("Operation not supported")]
 pub struct NotImplementedError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::pin_mut;
+    use http::{Request as HttpRequest, Response as HttpResponse};
+    use hyper::Body;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, Pod as KubePod};
+    use serde_json::json;
+    use tower_test::mock;
+
+    #[tokio::test]
+    async fn test_env_sources_are_fetched_once() {
+        let (mock_service, handle) = mock::pair::<HttpRequest<Body>, HttpResponse<Body>>();
+        let requests = tokio::spawn(async move {
+            pin_mut!(handle);
+            let mut requests = vec![];
+            while let Some((request, send)) = handle.next_request().await {
+                requests.push(request.uri().path().to_owned());
+                let config_map = json!({
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": { "name": "settings", "namespace": "default" },
+                    "data": { "VAR_0": "zero", "VAR_1": "one", "VAR_2": "two" }
+                });
+                send.send_response(
+                    HttpResponse::builder()
+                        .body(Body::from(serde_json::to_vec(&config_map).unwrap()))
+                        .unwrap(),
+                );
+            }
+            requests
+        });
+
+        let mut env: Vec<serde_json::Value> = (0..3)
+            .map(|i| {
+                json!({
+                    "name": format!("VAR_{}", i),
+                    "valueFrom": {
+                        "configMapKeyRef": { "name": "settings", "key": format!("VAR_{}", i) }
+                    }
+                })
+            })
+            .collect();
+        env.push(json!({
+            "name": "MY_POD_NAME",
+            "valueFrom": { "fieldRef": { "fieldPath": "metadata.name" } }
+        }));
+        let kube_container: KubeContainer = serde_json::from_value(json!({
+            "name": "app",
+            "env": env
+        }))
+        .unwrap();
+        let pod = Pod::from(
+            serde_json::from_value::<KubePod>(json!({
+                "metadata": { "name": "big", "namespace": "default" },
+                "spec": { "containers": [kube_container.clone()] }
+            }))
+            .unwrap(),
+        );
+
+        let client = kube::Client::new(mock_service);
+        let env = env_vars(&Container::new(&kube_container), &pod, &client).await;
+        drop(client);
+
+        assert_eq!("zero", env["VAR_0"]);
+        assert_eq!("two", env["VAR_2"]);
+        assert_eq!("big", env["MY_POD_NAME"]);
+        assert_eq!(
+            vec!["/api/v1/namespaces/default/configmaps/settings"],
+            requests.await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! The reasons the Kubelet reports in pod and container statuses.
+//!
+//! Krustlet tells failures apart by the step of running a pod they happen in, while dashboards
+//! and controllers key off the reasons the Kubernetes kubelet reports, such as `ErrImagePull` or
+//! `CrashLoopBackOff`. [`Reason`] holds those reasons, and [`Failure`] maps the Kubelet's
+//! failures onto them, so that the states and status patches of every provider report the same
+//! reason for the same failure.
+use std::fmt;
+
+/// A reason the Kubernetes kubelet reports for pods and containers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// A container is waiting for its pod to be set up, or for its module to start.
+    ContainerCreating,
+    /// A container is waiting for the init containers of its pod to finish.
+    PodInitializing,
+    /// The module of a container couldn't be pulled.
+    ErrImagePull,
+    /// The pod is waiting to pull modules again after failing to.
+    ImagePullBackOff,
+    /// The configuration of a container, such as its volumes, environment or devices, couldn't
+    /// be prepared.
+    CreateContainerConfigError,
+    /// A container couldn't be started.
+    RunContainerError,
+    /// A container or pod that keeps failing is waiting to be restarted.
+    CrashLoopBackOff,
+    /// A container exited with an error.
+    Error,
+    /// A container exited successfully.
+    Completed,
+    /// A container was killed for using more memory than it is allowed.
+    OOMKilled,
+    /// The pod wasn't admitted to the node, such as for asking for an unknown runtime class.
+    UnexpectedAdmissionError,
+}
+
+impl Reason {
+    /// The reason as it is reported in statuses.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::ContainerCreating => "ContainerCreating",
+            Reason::PodInitializing => "PodInitializing",
+            Reason::ErrImagePull => "ErrImagePull",
+            Reason::ImagePullBackOff => "ImagePullBackOff",
+            Reason::CreateContainerConfigError => "CreateContainerConfigError",
+            Reason::RunContainerError => "RunContainerError",
+            Reason::CrashLoopBackOff => "CrashLoopBackOff",
+            Reason::Error => "Error",
+            Reason::Completed => "Completed",
+            Reason::OOMKilled => "OOMKilled",
+            Reason::UnexpectedAdmissionError => "UnexpectedAdmissionError",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The step of running a pod that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Admitting the pod to the node.
+    Admission,
+    /// Pulling the modules of the pod's containers.
+    ImagePull,
+    /// Preparing the volumes, devices and other configuration of the pod's containers.
+    ContainerConfig,
+    /// Starting the pod's containers. Failures that aren't told apart are reported as this.
+    ContainerStart,
+    /// Running a container, which exited with an error.
+    ContainerExit,
+}
+
+impl Failure {
+    /// The reason reported for the failure.
+    pub fn reason(&self) -> Reason {
+        match self {
+            Failure::Admission => Reason::UnexpectedAdmissionError,
+            Failure::ImagePull => Reason::ErrImagePull,
+            Failure::ContainerConfig => Reason::CreateContainerConfigError,
+            Failure::ContainerStart => Reason::RunContainerError,
+            Failure::ContainerExit => Reason::Error,
+        }
+    }
+}
+
+impl Default for Failure {
+    fn default() -> Self {
+        Failure::ContainerStart
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_failure_reasons() {
+        assert_eq!("ErrImagePull", Failure::ImagePull.reason().as_str());
+        assert_eq!(
+            "CreateContainerConfigError",
+            Failure::ContainerConfig.reason().to_string()
+        );
+        assert_eq!(Reason::RunContainerError, Failure::default().reason());
+        assert_eq!("Error", Failure::ContainerExit.reason().as_str());
+    }
+}
@@ -0,0 +1,189 @@
+//! Admission of pods by the resources the node has left to allocate.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use tracing::debug;
+
+use super::requirements::ResourceRequirements;
+use super::util::parse_quantity;
+use crate::node::NodeStatus;
+use crate::pod::Pod;
+
+/// Keeps track of the resources requested by the pods admitted to the node, and rejects pods
+/// whose requests don't fit in what is left of the node's allocatable resources.
+///
+/// The scheduler already places pods so that they fit, but pods bound to the node directly
+/// (e.g. with `nodeName`) bypass it. Only the resources the node reports as allocatable are
+/// checked, so requests for other resources are let through.
+#[derive(Debug)]
+pub struct ResourceAdmission {
+    allocatable: BTreeMap<String, f64>,
+    admitted: Mutex<HashMap<String, BTreeMap<String, f64>>>,
+}
+
+impl ResourceAdmission {
+    /// Admit pods by the given allocatable resources, e.g. `cpu` in cores and `memory` in bytes.
+    pub fn new(allocatable: BTreeMap<String, f64>) -> Self {
+        Self {
+            allocatable,
+            admitted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admit pods by the allocatable resources of the node status a provider reports. Resources
+    /// with invalid quantities are not checked.
+    pub fn from_node_status(status: &NodeStatus) -> Self {
+        let allocatable = status
+            .allocatable()
+            .into_iter()
+            .filter_map(|(resource, quantity)| Some((resource, parse_quantity(&quantity.0)?)))
+            .collect();
+        Self::new(allocatable)
+    }
+
+    /// Checks that the pod's requests fit in the resources not requested by the other admitted
+    /// pods, without admitting it.
+    pub fn check(&self, pod: &Pod) -> anyhow::Result<()> {
+        let requests = ResourceRequirements::for_pod(pod)?.requests;
+        self.check_requests(
+            &*self.admitted.lock().unwrap(),
+            &admission_key(pod),
+            &requests,
+        )
+    }
+
+    /// Admits the pod if its requests fit, setting them aside until it is
+    /// [released](Self::release). Admitting a pod that was already admitted updates its requests.
+    pub fn admit(&self, pod: &Pod) -> anyhow::Result<()> {
+        let requests = ResourceRequirements::for_pod(pod)?.requests;
+        let key = admission_key(pod);
+        let mut admitted = self.admitted.lock().unwrap();
+        self.check_requests(&admitted, &key, &requests)?;
+        admitted.insert(key, requests);
+        Ok(())
+    }
+
+    /// Gives back the resources set aside for the pod, once it has finished or been deleted.
+    pub fn release(&self, pod: &Pod) {
+        if self
+            .admitted
+            .lock()
+            .unwrap()
+            .remove(&admission_key(pod))
+            .is_some()
+        {
+            debug!(pod = %pod.name(), "Released resources of pod");
+        }
+    }
+
+    fn check_requests(
+        &self,
+        admitted: &HashMap<String, BTreeMap<String, f64>>,
+        key: &str,
+        requests: &BTreeMap<String, f64>,
+    ) -> anyhow::Result<()> {
+        for (resource, request) in requests {
+            let allocatable = match self.allocatable.get(resource) {
+                Some(allocatable) => *allocatable,
+                None => continue,
+            };
+            let in_use: f64 = admitted
+                .iter()
+                .filter(|(admitted_key, _)| admitted_key.as_str() != key)
+                .filter_map(|(_, requests)| requests.get(resource))
+                .sum();
+            // Compare in thousandths, the precision of Kubernetes quantities, so that rounding
+            // errors don't reject pods that exactly fill the node
+            if ((in_use + request) * 1000.0).round() > (allocatable * 1000.0).round() {
+                return Err(anyhow::anyhow!(
+                    "Pod requests {} of {}, but only {} of the node's {} is not requested by other pods",
+                    request,
+                    resource,
+                    (allocatable - in_use).max(0.0),
+                    allocatable
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pods are told apart by their UID, so that a pod recreated with the same name while the old
+/// one is still terminating is counted separately. Pods being previewed may not have one yet.
+fn admission_key(pod: &Pod) -> String {
+    match &pod.as_kube_pod().metadata.uid {
+        Some(uid) => uid.clone(),
+        None => format!("{}/{}", pod.namespace(), pod.name()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, Pod as KubePod, PodSpec,
+        ResourceRequirements as KubeResourceRequirements,
+    };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn pod(uid: &str, cpu: &str) -> Pod {
+        let mut requests = BTreeMap::new();
+        requests.insert("cpu".to_owned(), Quantity(cpu.to_owned()));
+        requests.insert("example.com/dongle".to_owned(), Quantity("1".to_owned()));
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some(uid.to_owned()),
+                uid: Some(uid.to_owned()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "app".to_owned(),
+                    resources: Some(KubeResourceRequirements {
+                        requests: Some(requests),
+                        limits: None,
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn admission(cpu: &str) -> ResourceAdmission {
+        let mut status = NodeStatus::default();
+        status.add_capacity("cpu", cpu);
+        ResourceAdmission::from_node_status(&status)
+    }
+
+    #[test]
+    fn test_admits_pods_that_fit() {
+        let admission = admission("2");
+        admission.admit(&pod("a", "1")).unwrap();
+        admission.admit(&pod("b", "500m")).unwrap();
+        // Admitting a pod again doesn't count its requests twice
+        admission.admit(&pod("b", "1")).unwrap();
+
+        let e = admission.check(&pod("c", "100m")).unwrap_err();
+        assert_eq!(
+            "Pod requests 0.1 of cpu, but only 0 of the node's 2 is not requested by other pods",
+            e.to_string()
+        );
+        assert!(admission.admit(&pod("c", "100m")).is_err());
+
+        admission.release(&pod("a", "1"));
+        admission.check(&pod("c", "100m")).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_pods_larger_than_the_node() {
+        let admission = admission("1");
+        let e = admission.admit(&pod("a", "1500m")).unwrap_err();
+        assert_eq!(
+            "Pod requests 1.5 of cpu, but only 1 of the node's 1 is not requested by other pods",
+            e.to_string()
+        );
+    }
+}
@@ -9,7 +9,8 @@ use super::pod_devices::{ContainerDevices, DeviceAllocateInfo, PodDevices};
 use super::{DeviceIdMap, DeviceMap, PluginDevicesMap, PodResourceRequests, HEALTHY};
 use crate::device_plugin_api::v1beta1::{
     device_plugin_client::DevicePluginClient, AllocateRequest, ContainerAllocateRequest,
-    ContainerAllocateResponse, RegisterRequest, API_VERSION,
+    ContainerAllocateResponse, ContainerPreferredAllocationRequest, PreferredAllocationRequest,
+    RegisterRequest, API_VERSION,
 };
 use crate::grpc_sock;
 use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
@@ -19,7 +20,7 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 
 use tokio::sync::broadcast;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 #[cfg(target_family = "unix")]
 const DEFAULT_PLUGIN_PATH: &str = "/var/lib/kubelet/device-plugins/";
@@ -28,6 +29,10 @@ const DEFAULT_PLUGIN_PATH: &str = "c:\\ProgramData\\kubelet\\device-plugins";
 
 const UPDATE_NODE_STATUS_CHANNEL_SIZE: usize = 15;
 
+/// How long a device plugin is given to suggest which devices to allocate, before any of the
+/// available devices are allocated instead.
+const PREFERRED_ALLOCATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 /// ContainerAllocateInfo pairs an allocate request to with the requesting container
 #[derive(Clone)]
 pub struct ContainerAllocateInfo {
@@ -455,19 +460,15 @@ impl DeviceManager {
             ));
         }
 
-        // let plugin_connection = self.plugins.lock().unwrap().get(resource_name).unwrap().clone();
-        // let get_preferred_allocation_available = match
-        // &plugin_connection.register_request.options {None => false, Some(options) =>
-        // options.get_preferred_allocation_available,
-        // };
-
-        // if get_preferred_allocation_available {
-        //
-        // }
-
-        // TODO: support preferred allocation For now, reserve first N devices where N = quantity by
-        // adding them to allocated map
-        let devices_to_allocate: Vec<String> = available_devices[..quantity].to_vec();
+        // Reserve the devices the plugin prefers, or else the first N devices where N = quantity,
+        // by adding them to allocated map
+        let devices_to_allocate = match self
+            .preferred_devices(resource_name, &available_devices, quantity)
+            .await
+        {
+            Some(devices) => devices,
+            None => available_devices[..quantity].to_vec(),
+        };
         devices_to_allocate.iter().for_each(|dev| {
             allocated_devices.insert(dev.clone());
         });
@@ -475,6 +476,63 @@ impl DeviceManager {
 
         Ok(devices_to_allocate)
     }
+
+    /// Asks the resource's device plugin which `quantity` of the available devices it would
+    /// prefer to allocate, e.g. to keep them on the same NUMA node, if it supports
+    /// `GetPreferredAllocation`. Returns `None` if it doesn't, or if its answer can't be used, in
+    /// which case any of the available devices can be allocated.
+    async fn preferred_devices(
+        &self,
+        resource_name: &str,
+        available_devices: &[String],
+        quantity: usize,
+    ) -> Option<Vec<String>> {
+        let plugin_connection = self.plugins.read().await.get(resource_name).cloned()?;
+        if !plugin_connection.get_preferred_allocation_available() {
+            return None;
+        }
+        let request = PreferredAllocationRequest {
+            container_requests: vec![ContainerPreferredAllocationRequest {
+                available_device_i_ds: available_devices.to_vec(),
+                must_include_device_i_ds: Vec::new(),
+                allocation_size: quantity as i32,
+            }],
+        };
+        let response = match tokio::time::timeout(
+            PREFERRED_ALLOCATION_TIMEOUT,
+            plugin_connection.get_preferred_allocation(request),
+        )
+        .await
+        {
+            Ok(Ok(response)) => response.into_inner(),
+            Ok(Err(e)) => {
+                warn!(error = %e, resource = %resource_name, "Unable to get preferred allocation from device plugin");
+                return None;
+            }
+            Err(_) => {
+                warn!(resource = %resource_name, "Timed out getting preferred allocation from device plugin");
+                return None;
+            }
+        };
+        let preferred = response
+            .container_responses
+            .into_iter()
+            .next()
+            .map(|container_response| container_response.device_i_ds)
+            .unwrap_or_default();
+
+        // The plugin has to choose as many distinct devices as were asked for, among the
+        // available ones
+        let distinct: HashSet<&String> = preferred.iter().collect();
+        if preferred.len() != quantity
+            || distinct.len() != quantity
+            || !preferred.iter().all(|id| available_devices.contains(id))
+        {
+            warn!(resource = %resource_name, ?preferred, "Ignoring invalid preferred allocation from device plugin");
+            return None;
+        }
+        Some(preferred)
+    }
 }
 
 /// Returns the device IDs of all healthy devices that have yet to be allocated.
@@ -544,7 +602,8 @@ mod tests {
     use super::*;
     use crate::device_plugin_api::v1beta1::{
         device_plugin_server::{DevicePlugin, DevicePluginServer},
-        registration_client, AllocateRequest, AllocateResponse, Device, DevicePluginOptions, Empty,
+        registration_client, AllocateRequest, AllocateResponse,
+        ContainerPreferredAllocationResponse, Device, DevicePluginOptions, Empty,
         ListAndWatchResponse, PreStartContainerRequest, PreStartContainerResponse,
         PreferredAllocationRequest, PreferredAllocationResponse, API_VERSION,
     };
@@ -599,11 +658,26 @@ mod tests {
             )))
         }
 
+        /// Prefers the devices with the highest IDs
         async fn get_preferred_allocation(
             &self,
-            _request: Request<PreferredAllocationRequest>,
+            request: Request<PreferredAllocationRequest>,
         ) -> Result<Response<PreferredAllocationResponse>, Status> {
-            unimplemented!();
+            let container_responses = request
+                .into_inner()
+                .container_requests
+                .into_iter()
+                .map(|container_request| {
+                    let mut device_i_ds = container_request.available_device_i_ds;
+                    device_i_ds.sort();
+                    device_i_ds.reverse();
+                    device_i_ds.truncate(container_request.allocation_size as usize);
+                    ContainerPreferredAllocationResponse { device_i_ds }
+                })
+                .collect();
+            Ok(Response::new(PreferredAllocationResponse {
+                container_responses,
+            }))
         }
 
         async fn allocate(
@@ -839,4 +913,31 @@ mod tests {
         // ContainerAllocateRequest
         assert_eq!(dm.get_pod_allocate_responses("pod_uid").unwrap().len(), 2);
     }
+
+    // The devices the device plugin prefers are allocated when it supports preferred allocation
+    #[tokio::test]
+    async fn test_devices_to_allocate_uses_preferred_allocation() {
+        let resource_name = "example.com/r1";
+        let (_devices_sender, devices_receiver) = watch::channel(Vec::new());
+        let dp_socket = run_mock_device_plugin(devices_receiver).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let mut dm = create_device_manager("some_node");
+        dm.devices = test_utils::create_mock_healthy_devices(resource_name, "example.com/r2");
+        dm.create_plugin_connection(RegisterRequest {
+            endpoint: dp_socket,
+            resource_name: resource_name.to_string(),
+            options: Some(DevicePluginOptions {
+                get_preferred_allocation_available: true,
+                pre_start_required: false,
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let devices = dm
+            .devices_to_allocate(resource_name, "pod_uid", "containerA", 2)
+            .await
+            .unwrap();
+        assert_eq!(vec!["example.com/r1-id2", "example.com/r1-id1"], devices);
+    }
 }
@@ -1,7 +1,7 @@
 use super::DeviceMap;
 use crate::device_plugin_api::v1beta1::{
     device_plugin_client::DevicePluginClient, AllocateRequest, AllocateResponse, Device, Empty,
-    ListAndWatchResponse, RegisterRequest,
+    ListAndWatchResponse, PreferredAllocationRequest, PreferredAllocationResponse, RegisterRequest,
 };
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -83,6 +83,24 @@ impl PluginConnection {
         Ok(())
     }
 
+    /// Whether the device plugin can suggest which of the available devices to allocate.
+    pub fn get_preferred_allocation_available(&self) -> bool {
+        self.register_request
+            .options
+            .as_ref()
+            .map_or(false, |options| options.get_preferred_allocation_available)
+    }
+
+    pub async fn get_preferred_allocation(
+        &self,
+        request: PreferredAllocationRequest,
+    ) -> Result<tonic::Response<PreferredAllocationResponse>, tonic::Status> {
+        self.client
+            .clone()
+            .get_preferred_allocation(Request::new(request))
+            .await
+    }
+
     pub async fn allocate(
         &self,
         allocate_request: AllocateRequest,
@@ -1,5 +1,9 @@
 //! `resources` contains utilities and managers for container resources.
 
+mod admission;
 pub(crate) mod device_plugin_manager;
+mod requirements;
+pub use admission::ResourceAdmission;
 pub use device_plugin_manager::manager::DeviceManager;
+pub use requirements::{ResourceRequirements, CPU, MEMORY};
 pub mod util;
@@ -0,0 +1,220 @@
+//! The resource requests and limits of containers and pods, parsed so that providers can act on
+//! them, e.g. to cap the memory of a container's runtime at its memory limit.
+use std::collections::BTreeMap;
+
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+use super::util::parse_quantity;
+use crate::container::Container;
+use crate::pod::Pod;
+
+/// The CPU resource, measured in cores.
+pub const CPU: &str = "cpu";
+/// The memory resource, measured in bytes.
+pub const MEMORY: &str = "memory";
+
+/// The resource requests and limits of a container, or the effective ones of a pod.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceRequirements {
+    /// The amount of each resource that is set aside. A resource with a limit but no request
+    /// requests its limit, as the API server defaults it to.
+    pub requests: BTreeMap<String, f64>,
+    /// The most of each resource that may be used.
+    pub limits: BTreeMap<String, f64>,
+}
+
+impl ResourceRequirements {
+    /// The requests and limits of the container, failing if any of its quantities is invalid.
+    pub fn for_container(container: &Container) -> anyhow::Result<Self> {
+        let resources = container.resources();
+        let limits = parse_quantities(resources.and_then(|r| r.limits.as_ref())).map_err(|e| {
+            anyhow::anyhow!("Container {} has an invalid limit: {}", container.name(), e)
+        })?;
+        let mut requests =
+            parse_quantities(resources.and_then(|r| r.requests.as_ref())).map_err(|e| {
+                anyhow::anyhow!(
+                    "Container {} has an invalid request: {}",
+                    container.name(),
+                    e
+                )
+            })?;
+        for (resource, limit) in &limits {
+            requests.entry(resource.clone()).or_insert(*limit);
+        }
+        Ok(Self { requests, limits })
+    }
+
+    /// The effective requests and limits of the pod, worked out as the Kubernetes kubelet does.
+    /// These are the sums of those of its app containers, or those of its largest init
+    /// container if that is larger, as init containers run one at a time before the app
+    /// containers start. The pod only has a limit on a resource if all of its containers do.
+    pub fn for_pod(pod: &Pod) -> anyhow::Result<Self> {
+        let containers = pod
+            .containers()
+            .iter()
+            .map(Self::for_container)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let init_containers = pod
+            .init_containers()
+            .iter()
+            .map(Self::for_container)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut effective = Self::default();
+        for container in &containers {
+            for (resource, request) in &container.requests {
+                *effective.requests.entry(resource.clone()).or_default() += request;
+            }
+            for (resource, limit) in &container.limits {
+                *effective.limits.entry(resource.clone()).or_default() += limit;
+            }
+        }
+        for init_container in &init_containers {
+            max_into(&mut effective.requests, &init_container.requests);
+            max_into(&mut effective.limits, &init_container.limits);
+        }
+        effective.limits.retain(|resource, _| {
+            containers
+                .iter()
+                .chain(init_containers.iter())
+                .all(|c| c.limits.contains_key(resource))
+        });
+        Ok(effective)
+    }
+
+    /// The request for the resource, if there is one.
+    pub fn request(&self, resource: &str) -> Option<f64> {
+        self.requests.get(resource).copied()
+    }
+
+    /// The limit on the resource, if there is one.
+    pub fn limit(&self, resource: &str) -> Option<f64> {
+        self.limits.get(resource).copied()
+    }
+
+    /// The CPU request in cores, if there is one.
+    pub fn cpu_request(&self) -> Option<f64> {
+        self.request(CPU)
+    }
+
+    /// The memory limit in bytes, if there is one.
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.limit(MEMORY).map(|bytes| bytes.ceil() as u64)
+    }
+}
+
+fn parse_quantities(
+    quantities: Option<&BTreeMap<String, Quantity>>,
+) -> anyhow::Result<BTreeMap<String, f64>> {
+    quantities
+        .into_iter()
+        .flatten()
+        .map(|(resource, quantity)| {
+            let value = parse_quantity(&quantity.0).ok_or_else(|| {
+                anyhow::anyhow!("{} is not a quantity of {}", quantity.0, resource)
+            })?;
+            Ok((resource.clone(), value))
+        })
+        .collect()
+}
+
+fn max_into(totals: &mut BTreeMap<String, f64>, values: &BTreeMap<String, f64>) {
+    for (resource, value) in values {
+        let total = totals.entry(resource.clone()).or_default();
+        *total = total.max(*value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, Pod as KubePod, PodSpec,
+        ResourceRequirements as KubeResourceRequirements,
+    };
+
+    fn container(name: &str, requests: &[(&str, &str)], limits: &[(&str, &str)]) -> KubeContainer {
+        let quantities = |values: &[(&str, &str)]| {
+            Some(
+                values
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), Quantity(v.to_string())))
+                    .collect(),
+            )
+        };
+        KubeContainer {
+            name: name.to_owned(),
+            resources: Some(KubeResourceRequirements {
+                requests: quantities(requests),
+                limits: quantities(limits),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn pod(containers: Vec<KubeContainer>, init_containers: Vec<KubeContainer>) -> Pod {
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                containers,
+                init_containers: Some(init_containers),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_container_requests_default_to_limits() {
+        let requirements = ResourceRequirements::for_container(&Container::new(&container(
+            "app",
+            &[("cpu", "250m")],
+            &[("cpu", "1"), ("memory", "64Mi")],
+        )))
+        .unwrap();
+        assert_eq!(Some(0.25), requirements.cpu_request());
+        assert_eq!(Some(64.0 * 1024.0 * 1024.0), requirements.request(MEMORY));
+        assert_eq!(Some(64 * 1024 * 1024), requirements.memory_limit());
+    }
+
+    #[test]
+    fn test_invalid_quantity() {
+        let e = ResourceRequirements::for_container(&Container::new(&container(
+            "app",
+            &[("memory", "lots")],
+            &[],
+        )))
+        .unwrap_err();
+        assert_eq!(
+            "Container app has an invalid request: lots is not a quantity of memory",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pod_requirements() {
+        let requirements = ResourceRequirements::for_pod(&pod(
+            vec![
+                container("a", &[("cpu", "500m")], &[("memory", "64Mi")]),
+                container("b", &[("cpu", "500m"), ("memory", "32Mi")], &[]),
+            ],
+            vec![container("init", &[("cpu", "2")], &[("memory", "16Mi")])],
+        ))
+        .unwrap();
+        assert_eq!(Some(2.0), requirements.cpu_request());
+        // The init container's request defaults to its limit, which is less than the sum
+        assert_eq!(Some(96.0 * 1024.0 * 1024.0), requirements.request(MEMORY));
+        // Container b has no memory limit, so neither does the pod
+        assert_eq!(None, requirements.memory_limit());
+
+        let requirements = ResourceRequirements::for_pod(&pod(
+            vec![
+                container("a", &[], &[("memory", "64Mi")]),
+                container("b", &[], &[("memory", "32Mi")]),
+            ],
+            vec![container("init", &[], &[("memory", "128Mi")])],
+        ))
+        .unwrap();
+        assert_eq!(Some(128 * 1024 * 1024), requirements.memory_limit());
+        assert_eq!(Some(128.0 * 1024.0 * 1024.0), requirements.request(MEMORY));
+    }
+}
@@ -125,6 +125,40 @@ fn is_dns_1123_subdomain(value: &str) -> anyhow::Result<()> {
     }
 }
 
+/// Parses a Kubernetes resource quantity (e.g. `100m`, `1.5Gi` or `2e3`) into its numeric value.
+/// Returns `None` if the quantity is not valid.
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let split = quantity
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-'))
+        .unwrap_or(quantity.len());
+    let (number, suffix) = quantity.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let value = match suffix {
+        "" => number,
+        "n" => number / 1e9,
+        "u" => number / 1e6,
+        "m" => number / 1e3,
+        "k" => number * 1e3,
+        "M" => number * 1e6,
+        "G" => number * 1e9,
+        "T" => number * 1e12,
+        "P" => number * 1e15,
+        "E" => number * 1e18,
+        "Ki" => number * 1024.0,
+        "Mi" => number * 1024f64.powi(2),
+        "Gi" => number * 1024f64.powi(3),
+        "Ti" => number * 1024f64.powi(4),
+        "Pi" => number * 1024f64.powi(5),
+        "Ei" => number * 1024f64.powi(6),
+        exponent if exponent.starts_with('e') || exponent.starts_with('E') => {
+            number * 10f64.powi(exponent[1..].parse().ok()?)
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,4 +174,19 @@ mod tests {
         let no_prefix_name = "dongle";
         assert!(!is_extended_resource_name(no_prefix_name));
     }
+
+    #[test]
+    fn test_parse_quantity() {
+        assert_eq!(Some(0.1), parse_quantity("100m"));
+        assert_eq!(Some(2.0), parse_quantity("2"));
+        assert_eq!(
+            Some(1.5 * 1024.0 * 1024.0 * 1024.0),
+            parse_quantity("1.5Gi")
+        );
+        assert_eq!(Some(128e6), parse_quantity("128M"));
+        assert_eq!(Some(2000.0), parse_quantity("2e3"));
+        assert_eq!(parse_quantity("1"), parse_quantity("1000m"));
+        assert_eq!(None, parse_quantity("lots"));
+        assert_eq!(None, parse_quantity("1Xi"));
+    }
 }
@@ -0,0 +1,108 @@
+//! Resolves the [RuntimeClass](https://kubernetes.io/docs/concepts/containers/runtime-class/)
+//! a pod asks for, so that providers can run it with the execution profile its handler
+//! selects.
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::node::v1::RuntimeClass as KubeRuntimeClass;
+use kube::api::Api;
+use kube::error::ErrorResponse;
+
+use crate::pod::Pod;
+
+/// The prefix of the annotations on a RuntimeClass that are passed to providers as the
+/// parameters of its handler, e.g. `parameters.krustlet.dev/fuel: "1000000"`. RuntimeClasses
+/// have no field for handler parameters, so Krustlet reads them from annotations.
+pub const RUNTIME_PARAMETER_ANNOTATION_PREFIX: &str = "parameters.krustlet.dev/";
+
+/// The RuntimeClass of a pod, as it is handed to providers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuntimeClass {
+    /// The name of the RuntimeClass.
+    pub name: String,
+    /// The handler the RuntimeClass selects, which names the configuration providers run the
+    /// pod with.
+    pub handler: String,
+    /// The parameters of the handler, from the RuntimeClass's annotations with the
+    /// [`RUNTIME_PARAMETER_ANNOTATION_PREFIX`], without the prefix.
+    pub parameters: BTreeMap<String, String>,
+    /// The labels of the nodes the RuntimeClass is supported on. The API server adds these to
+    /// the node selector of pods that use the RuntimeClass.
+    pub node_selector: BTreeMap<String, String>,
+}
+
+impl RuntimeClass {
+    /// Gets the RuntimeClass the pod asks for, or `None` if it doesn't ask for one. This fails
+    /// if the RuntimeClass doesn't exist, as the pod can't be run the way it asks to be.
+    pub async fn for_pod(client: &kube::Client, pod: &Pod) -> anyhow::Result<Option<Self>> {
+        let name = match pod.runtime_class_name() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let api: Api<KubeRuntimeClass> = Api::all(client.clone());
+        match api.get(name).await {
+            Ok(runtime_class) => Ok(Some(RuntimeClass::from(runtime_class))),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                Err(anyhow::anyhow!("RuntimeClass {} not found", name))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl From<KubeRuntimeClass> for RuntimeClass {
+    fn from(runtime_class: KubeRuntimeClass) -> Self {
+        let parameters = runtime_class
+            .metadata
+            .annotations
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix(RUNTIME_PARAMETER_ANNOTATION_PREFIX)
+                    .map(|key| (key.to_owned(), value))
+            })
+            .collect();
+        RuntimeClass {
+            name: runtime_class.metadata.name.unwrap_or_default(),
+            handler: runtime_class.handler,
+            parameters,
+            node_selector: runtime_class
+                .scheduling
+                .and_then(|s| s.node_selector)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::node::v1::Scheduling;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    #[test]
+    fn test_parameters_from_annotations() {
+        let mut annotations = BTreeMap::new();
+        annotations.insert("parameters.krustlet.dev/fuel".to_owned(), "1000".to_owned());
+        annotations.insert("example.com/owner".to_owned(), "team".to_owned());
+        let mut node_selector = BTreeMap::new();
+        node_selector.insert("kubernetes.io/arch".to_owned(), "wasm32-wasi".to_owned());
+        let runtime_class = RuntimeClass::from(KubeRuntimeClass {
+            metadata: ObjectMeta {
+                name: Some("metered".to_owned()),
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            handler: "wasmtime".to_owned(),
+            scheduling: Some(Scheduling {
+                node_selector: Some(node_selector.clone()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!("metered", runtime_class.name);
+        assert_eq!("wasmtime", runtime_class.handler);
+        assert_eq!(1, runtime_class.parameters.len());
+        assert_eq!("1000", runtime_class.parameters["fuel"]);
+        assert_eq!(node_selector, runtime_class.node_selector);
+    }
+}
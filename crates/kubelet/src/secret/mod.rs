@@ -1,14 +1,36 @@
-//! Resolves image pull secrets
+//! Resolves image pull secrets, and keeps secret values out of output
 
-use k8s_openapi::api::core::v1::Secret;
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::{Secret, ServiceAccount};
 use kube::api::Api;
 use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use tracing::{debug, warn};
+
+mod redact;
+
+pub use redact::{Redactor, REDACTED};
+
+/// The annotation a pod can use to name a docker-registry secret in its namespace whose
+/// credentials take precedence over those of its `imagePullSecrets`, so that pods on a shared
+/// node can pull with a registry identity of their own.
+pub const IMAGE_PULL_SECRET_ANNOTATION: &str = "krustlet.dev/image-pull-secret";
 
-/// Resolves registry authentication from image pull secrets
+/// Resolves registry authentication from image pull secrets.
+///
+/// The secrets are those named by a pod's [`IMAGE_PULL_SECRET_ANNOTATION`], its
+/// `imagePullSecrets` and the `imagePullSecrets` of its service account, in that order. Secrets
+/// may be of the `kubernetes.io/dockerconfigjson` type or of the legacy `kubernetes.io/dockercfg`
+/// type, and their credentials are merged: of the entries that match an image, the most
+/// specific one is used, i.e. one for the image's registry rather than a wildcard (such as
+/// `*.azurecr.io`), then one whose path matches more of the image's repository. Of equally
+/// specific entries, the one from the secret that comes first is used.
 pub struct RegistryAuthResolver {
     kube_client: kube::Client,
     pod_namespace: String,
     image_pull_secret_names: Vec<String>,
+    service_account_name: String,
 }
 
 impl RegistryAuthResolver {
@@ -19,7 +41,8 @@ impl RegistryAuthResolver {
         RegistryAuthResolver {
             kube_client: client,
             pod_namespace: pod.namespace().to_owned(),
-            image_pull_secret_names: pod.image_pull_secrets(),
+            image_pull_secret_names: image_pull_secret_names(pod),
+            service_account_name: pod.service_account_name().unwrap_or("default").to_owned(),
         }
     }
 
@@ -28,6 +51,12 @@ impl RegistryAuthResolver {
         &self,
         reference: &oci_distribution::Reference,
     ) -> anyhow::Result<RegistryAuth> {
+        Ok(self.resolve_credentials().await?.for_reference(reference))
+    }
+
+    /// Get the credentials of the pod's image pull secrets, so that an image can be pulled with
+    /// the credentials for whichever registry it is pulled from.
+    pub async fn resolve_credentials(&self) -> anyhow::Result<RegistryCredentials> {
         let secrets_api: Api<Secret> =
             Api::namespaced(self.kube_client.clone(), &self.pod_namespace);
 
@@ -36,77 +65,397 @@ impl RegistryAuthResolver {
             .iter()
             .map(|name| secrets_api.get(name))
             .collect();
-        let secret_results = futures::future::join_all(secret_futures).await;
+        let (secret_results, service_account_secrets) = futures::future::join(
+            futures::future::join_all(secret_futures),
+            self.service_account_secrets(&secrets_api),
+        )
+        .await;
 
+        let mut secrets = Vec::with_capacity(secret_results.len());
         for secret_result in secret_results {
-            match secret_result {
-                Err(e) => return Err(e.into()),
-                Ok(secret) => {
-                    if let Some(auth) = parse_auth(&secret, reference.registry()) {
-                        return Ok(auth);
-                    }
-                }
+            secrets.push(secret_result?);
+        }
+        secrets.extend(service_account_secrets);
+        Ok(RegistryCredentials::from_secrets(&secrets))
+    }
+
+    /// The image pull secrets of the pod's service account that the pod doesn't name itself.
+    /// Unlike the pod's own secrets, ones that can't be read are skipped, as the pod didn't ask
+    /// for them.
+    async fn service_account_secrets(&self, secrets_api: &Api<Secret>) -> Vec<Secret> {
+        let service_accounts: Api<ServiceAccount> =
+            Api::namespaced(self.kube_client.clone(), &self.pod_namespace);
+        let names: Vec<String> = match service_accounts.get(&self.service_account_name).await {
+            Ok(service_account) => service_account
+                .image_pull_secrets
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|objref| objref.name)
+                .filter(|name| !self.image_pull_secret_names.contains(name))
+                .collect(),
+            Err(e) => {
+                debug!(
+                    error = %e,
+                    service_account = %self.service_account_name,
+                    "Unable to get image pull secrets of service account"
+                );
+                return vec![];
             }
+        };
+        let secrets = futures::future::join_all(names.iter().map(|name| secrets_api.get(name)));
+        names
+            .iter()
+            .zip(secrets.await)
+            .filter_map(|(name, secret)| match secret {
+                Ok(secret) => Some(secret),
+                Err(e) => {
+                    warn!(
+                        error = %e,
+                        secret = %name,
+                        service_account = %self.service_account_name,
+                        "Unable to get image pull secret of service account"
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The registry credentials of a pod's image pull secrets, as resolved by
+/// [`RegistryAuthResolver::resolve_credentials`].
+///
+/// Stores are given the credentials rather than the authentication for a single registry, as
+/// they may pull an image from registries other than its own, such as the replicas a
+/// [`FailoverClient`](crate::store::oci::FailoverClient) fails over to. Each registry is only
+/// ever sent the credentials the secrets have for it.
+#[derive(Clone, Default)]
+pub struct RegistryCredentials {
+    keyring: Arc<Keyring>,
+}
+
+impl RegistryCredentials {
+    /// No credentials, so that every registry is accessed anonymously.
+    pub fn anonymous() -> Self {
+        RegistryCredentials::default()
+    }
+
+    /// The credentials of the given docker-registry secrets, which take precedence in the order
+    /// they are given for equally specific entries.
+    pub fn from_secrets(secrets: &[Secret]) -> Self {
+        let mut keyring = Keyring::default();
+        for secret in secrets {
+            keyring.add(secret);
+        }
+        RegistryCredentials {
+            keyring: Arc::new(keyring),
         }
+    }
 
-        Ok(RegistryAuth::Anonymous)
+    /// The authentication for pulling the given image reference, or anonymous access if none of
+    /// the secrets has credentials for it.
+    pub fn for_reference(&self, reference: &Reference) -> RegistryAuth {
+        self.keyring
+            .lookup(reference.registry(), reference.repository())
+            .unwrap_or(RegistryAuth::Anonymous)
     }
 }
 
-fn parse_auth(secret: &Secret, registry_name: &str) -> Option<RegistryAuth> {
-    if let Some(data) = secret.data.as_ref() {
-        parse_auth_from_secret_data(data, registry_name)
-    } else {
-        None
+/// The names of the secrets to resolve the pod's registry authentication from, in the order they
+/// are tried.
+fn image_pull_secret_names(pod: &crate::pod::Pod) -> Vec<String> {
+    let mut names = pod.image_pull_secrets();
+    if let Some(annotated) = pod.annotation_text(IMAGE_PULL_SECRET_ANNOTATION) {
+        names.retain(|name| name != annotated);
+        names.insert(0, annotated.to_owned());
     }
+    names
+}
+
+/// The registry credentials of a set of image pull secrets, in the order they were added.
+#[derive(Default)]
+struct Keyring {
+    entries: Vec<KeyringEntry>,
 }
 
-fn parse_auth_from_secret_data(
-    secret_data: &std::collections::BTreeMap<String, k8s_openapi::ByteString>,
-    registry_name: &str,
-) -> Option<RegistryAuth> {
-    secret_data
-        .values()
-        .find_map(|v| parse_auth_from_secret_value(v, registry_name))
+struct KeyringEntry {
+    /// The registry, which may start with a `*.` wildcard for any one subdomain.
+    host: String,
+    /// The repositories the credentials are for, or empty for the whole registry.
+    path: String,
+    auth: RegistryAuth,
+}
+
+impl Keyring {
+    /// Add the credentials in a `kubernetes.io/dockerconfigjson` or `kubernetes.io/dockercfg`
+    /// secret.
+    fn add(&mut self, secret: &Secret) {
+        let data = match secret.data.as_ref() {
+            Some(data) => data,
+            None => return,
+        };
+        for (key, value) in data {
+            let value: serde_json::Value = match serde_json::from_slice(&value.0) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            // The legacy format is the map of registries that is under `auths` in the current
+            // one
+            let auths = if key == ".dockercfg" {
+                Some(&value)
+            } else {
+                value.get("auths")
+            };
+            if let Some(auths) = auths.and_then(serde_json::Value::as_object) {
+                for (registry, creds) in auths {
+                    self.add_entry(registry, creds);
+                }
+            }
+        }
+    }
+
+    fn add_entry(&mut self, registry: &str, creds: &serde_json::Value) {
+        let auth = match parse_creds(creds) {
+            Some(creds) => creds,
+            None => return,
+        };
+        // Registries may be given as URLs of their API, e.g. `https://index.docker.io/v1/`
+        let registry = registry
+            .trim_start_matches("https://")
+            .trim_start_matches("http://");
+        let (host, path) = registry.split_at(registry.find('/').unwrap_or(registry.len()));
+        let path = path.trim_matches('/');
+        let path = match path {
+            "v1" | "v2" => "",
+            path => path,
+        };
+        self.entries.push(KeyringEntry {
+            host: normalize_registry(host),
+            path: path.to_owned(),
+            auth,
+        });
+    }
+
+    /// The credentials for the repository in the registry, from the most specific entry that
+    /// matches it.
+    fn lookup(&self, registry: &str, repository: &str) -> Option<RegistryAuth> {
+        let registry = normalize_registry(registry);
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.matches(&registry, repository))
+            // The first of the entries that are most specific
+            .min_by_key(|(i, entry)| {
+                (
+                    entry.host.starts_with("*."),
+                    std::cmp::Reverse(entry.path.len()),
+                    *i,
+                )
+            })
+            .map(|(_, entry)| entry.auth.clone())
+    }
 }
 
-fn parse_auth_from_secret_value(
-    secret_value: &k8s_openapi::ByteString,
-    registry_name: &str,
-) -> Option<RegistryAuth> {
-    // We are intereted in secret_value if it is of the form
-    // {
-    //   "auths": {
-    //     "reg1": { ... },
-    //     "reg2": { ... }
-    //   }
-    // }
-    parse_byte_string_json(secret_value)
-        .and_then(|value| parse_auth_from_json_value(&value, registry_name))
+impl KeyringEntry {
+    fn matches(&self, registry: &str, repository: &str) -> bool {
+        let host_matches = match self.host.strip_prefix('*') {
+            Some(domain) => registry.strip_suffix(domain).map_or(false, |subdomain| {
+                !subdomain.is_empty() && !subdomain.contains('.')
+            }),
+            None => self.host == registry,
+        };
+        let path_matches = self.path.is_empty()
+            || repository
+                .strip_prefix(self.path.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'));
+        host_matches && path_matches
+    }
 }
 
-fn parse_byte_string_json(byte_string: &k8s_openapi::ByteString) -> Option<serde_json::Value> {
-    serde_json::from_slice(&byte_string.0).ok()
+/// The registry named by `host`, with the hosts of Docker Hub's API named as Docker Hub is in
+/// image references.
+fn normalize_registry(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    match host.as_str() {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io".to_owned(),
+        _ => host,
+    }
 }
 
-fn parse_auth_from_json_value(
-    json_value: &serde_json::Value,
-    registry_name: &str,
-) -> Option<RegistryAuth> {
-    json_value
-        .get("auths")
-        .and_then(|auths| auths.get(registry_name))
-        .and_then(|creds| parse_auth_from_json_creds(creds))
+/// The credentials of an entry of a docker config: its `identitytoken`, which `docker login`
+/// stores for registries that hand one out, or else the user name and password from its
+/// `username` and `password`, or else from its `auth`, which holds both base64 encoded as
+/// `username:password`.
+fn parse_creds(creds: &serde_json::Value) -> Option<RegistryAuth> {
+    let identity_token = creds
+        .get("identitytoken")
+        .and_then(serde_json::Value::as_str);
+    if let Some(identity_token) = identity_token.filter(|token| !token.is_empty()) {
+        return Some(RegistryAuth::IdentityToken(identity_token.to_owned()));
+    }
+    let username = creds.get("username").and_then(serde_json::Value::as_str);
+    let password = creds.get("password").and_then(serde_json::Value::as_str);
+    if let (Some(username), Some(password)) = (username, password) {
+        return Some(RegistryAuth::Basic(
+            username.to_owned(),
+            password.to_owned(),
+        ));
+    }
+    let auth = creds.get("auth").and_then(serde_json::Value::as_str)?;
+    let auth = String::from_utf8(base64::decode(auth).ok()?).ok()?;
+    let (username, password) = auth.split_at(auth.find(':')?);
+    Some(RegistryAuth::Basic(
+        username.to_owned(),
+        password[1..].to_owned(),
+    ))
 }
 
-fn parse_auth_from_json_creds(json_creds: &serde_json::Value) -> Option<RegistryAuth> {
-    let username = json_creds.get("username");
-    let password = json_creds.get("password");
-    // TODO: my test creds also included an entry "auth" - should we return this? (e.g. bearer auth?)
-    match (username, password) {
-        (Some(serde_json::Value::String(u)), Some(serde_json::Value::String(p))) => {
-            Some(RegistryAuth::Basic(u.to_owned(), p.to_owned()))
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{LocalObjectReference, Pod as KubePod, PodSpec};
+    use kube::api::ObjectMeta;
+
+    fn pod(annotation: Option<&str>, image_pull_secrets: &[&str]) -> crate::pod::Pod {
+        crate::pod::Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("hello".to_owned()),
+                annotations: annotation.map(|name| {
+                    std::iter::once((IMAGE_PULL_SECRET_ANNOTATION.to_owned(), name.to_owned()))
+                        .collect()
+                }),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                image_pull_secrets: Some(
+                    image_pull_secrets
+                        .iter()
+                        .map(|name| LocalObjectReference {
+                            name: Some((*name).to_owned()),
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_annotated_secret_is_tried_first() {
+        assert_eq!(
+            vec!["first", "second"],
+            image_pull_secret_names(&pod(None, &["first", "second"]))
+        );
+        assert_eq!(
+            vec!["tenant", "first", "second"],
+            image_pull_secret_names(&pod(Some("tenant"), &["first", "second"]))
+        );
+        assert_eq!(
+            vec!["second", "first"],
+            image_pull_secret_names(&pod(Some(" second "), &["first", "second"]))
+        );
+        assert_eq!(
+            vec!["first"],
+            image_pull_secret_names(&pod(Some(""), &["first"]))
+        );
+    }
+
+    fn secret(key: &str, value: serde_json::Value) -> Secret {
+        Secret {
+            data: Some(
+                std::iter::once((
+                    key.to_owned(),
+                    k8s_openapi::ByteString(serde_json::to_vec(&value).unwrap()),
+                ))
+                .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn user(auth: Option<RegistryAuth>) -> Option<String> {
+        match auth {
+            Some(RegistryAuth::Basic(username, _)) => Some(username),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_keyring_formats() {
+        let mut keyring = Keyring::default();
+        keyring.add(&secret(
+            ".dockerconfigjson",
+            serde_json::json!({"auths": {"example.com": {"username": "json", "password": "p"}}}),
+        ));
+        keyring.add(&secret(
+            ".dockercfg",
+            serde_json::json!({"https://index.docker.io/v1/": {
+                "auth": base64::encode("legacy:secret:password"),
+            }}),
+        ));
+        assert_eq!(
+            Some("json".to_owned()),
+            user(keyring.lookup("example.com", "app"))
+        );
+        match keyring.lookup("docker.io", "library/hello") {
+            Some(RegistryAuth::Basic(username, password)) => {
+                assert_eq!("legacy", username);
+                assert_eq!("secret:password", password);
+            }
+            _ => panic!("expected credentials for Docker Hub"),
         }
-        _ => None,
+        assert!(keyring.lookup("other.com", "app").is_none());
+    }
+
+    #[test]
+    fn test_keyring_identity_token() {
+        let mut keyring = Keyring::default();
+        keyring.add(&secret(
+            ".dockerconfigjson",
+            serde_json::json!({"auths": {"team.azurecr.io": {
+                "auth": base64::encode("00000000-0000-0000-0000-000000000000:"),
+                "identitytoken": "refresh",
+            }}}),
+        ));
+        match keyring.lookup("team.azurecr.io", "app") {
+            Some(RegistryAuth::IdentityToken(token)) => assert_eq!("refresh", token),
+            _ => panic!("expected the identity token"),
+        }
+    }
+
+    #[test]
+    fn test_keyring_prefers_most_specific_entry() {
+        let creds = |username: &str| serde_json::json!({"username": username, "password": "p"});
+        let mut keyring = Keyring::default();
+        keyring.add(&secret(
+            ".dockerconfigjson",
+            serde_json::json!({"auths": {
+                "*.azurecr.io": creds("wildcard"),
+                "team.azurecr.io/app": creds("first"),
+            }}),
+        ));
+        keyring.add(&secret(
+            ".dockerconfigjson",
+            serde_json::json!({"auths": {
+                "team.azurecr.io": creds("registry"),
+                "team.azurecr.io/app": creds("second"),
+            }}),
+        ));
+        let lookup = |registry, repository| user(keyring.lookup(registry, repository));
+        assert_eq!(Some("first".to_owned()), lookup("team.azurecr.io", "app"));
+        assert_eq!(
+            Some("first".to_owned()),
+            lookup("team.azurecr.io", "app/web")
+        );
+        assert_eq!(
+            Some("registry".to_owned()),
+            lookup("team.azurecr.io", "application")
+        );
+        assert_eq!(
+            Some("wildcard".to_owned()),
+            lookup("other.azurecr.io", "app")
+        );
+        assert_eq!(None, lookup("a.b.azurecr.io", "app"));
     }
 }
@@ -1,12 +1,15 @@
 //! The pod is backing off after repeated failures and retries.
 
 use super::registered::Registered;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use super::GenericProvider;
+use crate::backoff::format_backoff;
 use crate::pod::state::prelude::*;
+use crate::reason::Reason;
 
 /// The pod is backing off after repeated failures and retries.
 pub struct CrashLoopBackoff<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    delay: std::time::Duration,
 }
 
 impl<P: GenericProvider> std::fmt::Debug for CrashLoopBackoff<P> {
@@ -15,10 +18,13 @@ impl<P: GenericProvider> std::fmt::Debug for CrashLoopBackoff<P> {
     }
 }
 
-impl<P: GenericProvider> Default for CrashLoopBackoff<P> {
-    fn default() -> Self {
+impl<P: GenericProvider> CrashLoopBackoff<P> {
+    /// Creates an instance of the CrashLoopBackoff state that backs off for
+    /// the given time before the pod is retried.
+    pub fn new(delay: std::time::Duration) -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            delay,
         }
     }
 }
@@ -28,16 +34,23 @@ impl<P: GenericProvider> State<P::PodState> for CrashLoopBackoff<P> {
     async fn next(
         self: Box<Self>,
         _provider_state: SharedState<P::ProviderState>,
-        pod_state: &mut P::PodState,
+        _pod_state: &mut P::PodState,
         _pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
-        pod_state.backoff(BackoffSequence::CrashLoop).await;
+        tokio::time::sleep(self.delay).await;
         let next = Registered::<P>::default();
         Transition::next(self, next)
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "CrashLoopBackoff"))
+        Ok(StatusBuilder::new()
+            .phase(Phase::Pending)
+            .reason(Reason::CrashLoopBackOff.as_str())
+            .message(&format!(
+                "back-off {} restarting failed container",
+                format_backoff(self.delay)
+            ))
+            .build())
     }
 }
 
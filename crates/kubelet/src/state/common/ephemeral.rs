@@ -0,0 +1,106 @@
+//! Starts the ephemeral containers that are added to running pods.
+
+use std::collections::HashSet;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use krator::{Manifest, SharedState};
+use kube::api::Api;
+use tracing::{error, info, warn};
+
+use super::{GenericPodState, GenericProvider, GenericProviderState};
+use crate::container::{patch_container_status, Container, ContainerKey, Status};
+use crate::pod::Pod;
+use crate::store::Store;
+
+/// Starts the ephemeral containers of a running pod, each of them once. Ephemeral containers
+/// are added to running pods through the pod's `ephemeralcontainers` subresource, e.g. by
+/// `kubectl debug`, and are never restarted.
+///
+/// Providers opt in by implementing [`GenericPodState::start_ephemeral_container`] and calling
+/// [`start_new`](Self::start_new) from their `RunState` when it starts and whenever the pod
+/// changes. The module of each container is pulled from the provider's store before it is
+/// started. A container that can't be started is reported as terminated with the reason, and
+/// doesn't affect the pod.
+#[derive(Debug, Default)]
+pub struct EphemeralContainers {
+    started: HashSet<String>,
+}
+
+impl EphemeralContainers {
+    /// Create a tracker for a pod that hasn't started any ephemeral containers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Starts the ephemeral containers of the latest version of the pod that haven't been
+    /// started yet. Containers that already have a status, such as those that ran before the
+    /// pod was restarted, aren't started again.
+    pub async fn start_new<P: GenericProvider>(
+        &mut self,
+        provider_state: SharedState<P::ProviderState>,
+        pod_state: &mut P::PodState,
+        pod: &Manifest<Pod>,
+    ) {
+        let latest_pod = pod.latest();
+        for container in latest_pod.ephemeral_containers() {
+            let key = ContainerKey::Ephemeral(container.name().to_owned());
+            if !self.started.insert(container.name().to_owned())
+                || latest_pod.container_status_index(&key).is_some()
+            {
+                continue;
+            }
+
+            info!(
+                container_name = container.name(),
+                "Starting ephemeral container for pod"
+            );
+            if let Err(e) =
+                start::<P>(provider_state.clone(), pod_state, pod.clone(), &container).await
+            {
+                error!(error = %e, container_name = container.name(), "Unable to start ephemeral container");
+                let client = provider_state.read().await.client();
+                let api: Api<KubePod> = Api::namespaced(client, latest_pod.namespace());
+                let status = Status::terminated(
+                    &format!("Unable to start ephemeral container: {:#}", e),
+                    true,
+                );
+                if let Err(e) = patch_container_status(&api, &latest_pod, &key, &status).await {
+                    warn!(error = %e, container_name = container.name(), "Unable to report ephemeral container failure");
+                }
+            }
+        }
+    }
+}
+
+async fn start<P: GenericProvider>(
+    provider_state: SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    pod: Manifest<Pod>,
+    container: &Container,
+) -> anyhow::Result<()> {
+    P::validate_container_runnable(container)?;
+    let (client, store, platform, layout_root) = {
+        let state_reader = provider_state.read().await;
+        (
+            state_reader.client(),
+            state_reader.store(),
+            state_reader.platform(),
+            state_reader.oci_layout_root().map(ToOwned::to_owned),
+        )
+    };
+    let image = container
+        .image()?
+        .ok_or_else(|| anyhow::anyhow!("ephemeral container {} has no image", container.name()))?;
+    let pull_policy = container.effective_pull_policy()?;
+    let credentials = crate::secret::RegistryAuthResolver::new(client, &pod.latest())
+        .resolve_credentials()
+        .await?;
+    let module =
+        crate::store::oci::pod_store(store, &pod.latest(), platform, layout_root.as_deref())
+            .await?
+            .get(&image, pull_policy, &credentials)
+            .await?;
+    pod_state
+        .start_ephemeral_container(provider_state, pod, container, module)
+        .await
+}
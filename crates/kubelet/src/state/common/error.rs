@@ -2,12 +2,16 @@
 
 use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
-use super::{GenericPodState, GenericProvider, ThresholdTrigger};
+use super::{
+    finish_starting_pod, BackoffSequence, GenericPodState, GenericProvider, ThresholdTrigger,
+};
 use crate::pod::state::prelude::*;
+use crate::reason::Failure;
 
 /// The Pod failed to run.
 pub struct Error<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    failure: Failure,
     message: String,
 }
 
@@ -19,10 +23,18 @@ impl<P: GenericProvider> std::fmt::Debug for Error<P> {
 }
 
 impl<P: GenericProvider> Error<P> {
-    /// Creates an instance of the Error state.
+    /// Creates an instance of the Error state, reported as a failure to start the pod's
+    /// containers.
     pub fn new(message: String) -> Self {
+        Self::with_failure(Failure::default(), message)
+    }
+
+    /// Creates an instance of the Error state for a failure of the given step, which decides
+    /// the reason reported in the pod status.
+    pub fn with_failure(failure: Failure, message: String) -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            failure,
             message,
         }
     }
@@ -32,13 +44,15 @@ impl<P: GenericProvider> Error<P> {
 impl<P: GenericProvider> State<P::PodState> for Error<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
+        finish_starting_pod(&provider_state, &pod.latest()).await;
         match pod_state.record_error().await {
             ThresholdTrigger::Triggered => {
-                let next = CrashLoopBackoff::<P>::default();
+                let delay = pod_state.next_backoff(BackoffSequence::CrashLoop).await;
+                let next = CrashLoopBackoff::<P>::new(delay);
                 Transition::next(self, next)
             }
             ThresholdTrigger::Untriggered => {
@@ -50,7 +64,11 @@ impl<P: GenericProvider> State<P::PodState> for Error<P> {
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, &self.message))
+        Ok(StatusBuilder::new()
+            .phase(Phase::Pending)
+            .reason(self.failure.reason().as_str())
+            .message(&self.message)
+            .build())
     }
 }
 
@@ -1,15 +1,104 @@
 //! Kubelet is pulling container images.
 
 use super::image_pull_backoff::ImagePullBackoff;
-use super::volume_mount::VolumeMount;
-use super::{BackoffSequence, GenericPodState, GenericProvider, GenericProviderState};
+use super::initializing::Initializing;
+use super::{
+    record_pod_modules, start_pod, BackoffSequence, GenericPodState, GenericProvider,
+    GenericProviderState,
+};
 use crate::pod::state::prelude::*;
+use crate::store::oci::pod_store;
+use crate::store::{ContainerModules, Store};
+use oci_distribution::manifest::Platform;
 
-use tracing::{error, instrument};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{debug, error, instrument, warn};
+
+/// How long a single attempt at pulling a pod's images may take. Layers that were downloaded
+/// before the deadline are cached, so the next attempt continues where this one stopped.
+const IMAGE_PULL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// Modules being pulled for a pod before it reaches the [`ImagePull`] state, so that the pull
+/// overlaps with allocating its resources and mounting its volumes. Dropping the prefetch, for instance because the pod was
+/// deleted, cancels the pull.
+pub(crate) struct ModulePrefetch {
+    images: Vec<Option<String>>,
+    pull: tokio::task::JoinHandle<anyhow::Result<ContainerModules>>,
+}
+
+impl ModulePrefetch {
+    /// Start pulling the modules of all of the pod's containers in the background.
+    pub(crate) fn start(
+        client: kube::Client,
+        store: Arc<dyn Store + Sync + Send>,
+        platform: Option<Platform>,
+        layout_root: Option<PathBuf>,
+        pod: Pod,
+    ) -> Self {
+        let images = pod_images(&pod);
+        let pull = tokio::spawn(async move {
+            let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
+            let store = pod_store(store, &pod, platform, layout_root.as_deref()).await?;
+            Ok(store.fetch_pod_modules(&pod, &auth_resolver).await)
+        });
+        ModulePrefetch { images, pull }
+    }
+
+    /// Wait for the pull to complete.
+    async fn join(mut self) -> anyhow::Result<ContainerModules> {
+        (&mut self.pull)
+            .await
+            .map_err(|e| anyhow::anyhow!("Module pull did not complete: {}", e))?
+    }
+}
+
+impl Drop for ModulePrefetch {
+    fn drop(&mut self) {
+        self.pull.abort();
+    }
+}
+
+/// Modules that were pulled for a pod whose other modules failed to pull, so that only the
+/// modules that failed are pulled again.
+pub(crate) struct PulledModules {
+    images: Vec<Option<String>>,
+    modules: HashMap<String, Vec<u8>>,
+}
+
+fn pod_images(pod: &Pod) -> Vec<Option<String>> {
+    pod.all_containers()
+        .iter()
+        .map(|c| c.image().ok().flatten().map(|r| r.whole()))
+        .collect()
+}
 
 /// Kubelet is pulling container images.
 pub struct ImagePull<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    prefetch: Option<ModulePrefetch>,
+    pulled: Option<PulledModules>,
+}
+
+impl<P: GenericProvider> ImagePull<P> {
+    /// Pull the modules by waiting for a pull that was already started.
+    pub(crate) fn with_prefetch(prefetch: Option<ModulePrefetch>) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            prefetch,
+            pulled: None,
+        }
+    }
+
+    /// Pull the modules that weren't pulled by an earlier attempt.
+    pub(crate) fn with_pulled(pulled: Option<PulledModules>) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            prefetch: None,
+            pulled,
+        }
+    }
 }
 
 impl<P: GenericProvider> std::fmt::Debug for ImagePull<P> {
@@ -22,6 +111,8 @@ impl<P: GenericProvider> Default for ImagePull<P> {
     fn default() -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            prefetch: None,
+            pulled: None,
         }
     }
 }
@@ -34,7 +125,7 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
         fields(pod_name)
     )]
     async fn next(
-        self: Box<Self>,
+        mut self: Box<Self>,
         provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
@@ -43,22 +134,113 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
-        let (client, store) = {
+        #[cfg(feature = "failure-injection")]
+        {
+            if crate::failure_injection::should_fail_image_pull(&pod) {
+                error!("Injected image pull failure");
+                return Transition::next(self, ImagePullBackoff::<P>::default());
+            }
+        }
+
+        // A pod that backed off gave up its place among the starting pods
+        start_pod(&provider_state, &pod).await;
+        let (client, store, platform, layout_root) = {
             // Minimise the amount of time we hold any locks
             let state_reader = provider_state.read().await;
-            (state_reader.client(), state_reader.store())
+            (
+                state_reader.client(),
+                state_reader.store(),
+                state_reader.platform(),
+                state_reader.oci_layout_root().map(ToOwned::to_owned),
+            )
         };
         let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
-        let modules = match store.fetch_pod_modules(&pod, &auth_resolver).await {
-            Ok(m) => m,
-            Err(e) => {
-                error!(error = %e);
-                return Transition::next(self, ImagePullBackoff::<P>::default());
+        // The images may have changed since the prefetch started, or since the modules of an
+        // earlier attempt were pulled
+        let images = pod_images(&pod);
+        let prefetch = self
+            .prefetch
+            .take()
+            .filter(|prefetch| prefetch.images == images);
+        let mut modules = self
+            .pulled
+            .take()
+            .filter(|pulled| pulled.images == images)
+            .map(|pulled| pulled.modules)
+            .unwrap_or_default();
+        let missing: Vec<_> = pod
+            .all_containers()
+            .into_iter()
+            .filter(|container| !modules.contains_key(container.name()))
+            .collect();
+        let pull = async {
+            match prefetch {
+                Some(prefetch) => {
+                    debug!("Waiting for module prefetch");
+                    prefetch.join().await
+                }
+                None => {
+                    debug!(containers = missing.len(), "Pulling modules");
+                    let store = pod_store(store, &pod, platform, layout_root.as_deref()).await?;
+                    Ok(store
+                        .fetch_container_modules(&missing, &auth_resolver)
+                        .await)
+                }
+            }
+        };
+        let pulled = match tokio::time::timeout(IMAGE_PULL_TIMEOUT, pull).await {
+            Ok(Ok(pulled)) => pulled,
+            Ok(Err(e)) => {
+                let message = format!("{:#}", e);
+                error!(error = %message);
+                return Transition::next(self, ImagePullBackoff::<P>::new(message));
+            }
+            Err(_) => {
+                let message = format!(
+                    "Image pull did not complete within {} seconds",
+                    IMAGE_PULL_TIMEOUT.as_secs()
+                );
+                error!(error = %message);
+                let pulled = PulledModules { images, modules };
+                return Transition::next(
+                    self,
+                    ImagePullBackoff::<P>::new(message).with_pulled(pulled),
+                );
             }
         };
+
+        let mut failures = BTreeMap::new();
+        for (container_name, module) in pulled {
+            match module {
+                Ok(module) => {
+                    modules.insert(container_name, module);
+                }
+                Err(e) => {
+                    let message = format!("{:#}", e);
+                    warn!(
+                        container_name = %container_name,
+                        error = %message,
+                        "Unable to pull module"
+                    );
+                    failures.insert(container_name, message);
+                }
+            }
+        }
+        if !failures.is_empty() {
+            error!(
+                containers = ?failures.keys().collect::<Vec<_>>(),
+                "Unable to pull modules"
+            );
+            let pulled = PulledModules { images, modules };
+            return Transition::next(
+                self,
+                ImagePullBackoff::<P>::with_failures(failures).with_pulled(pulled),
+            );
+        }
+        record_pod_modules(&provider_state, &pod, &modules).await;
         pod_state.set_modules(modules).await;
         pod_state.reset_backoff(BackoffSequence::ImagePull).await;
-        Transition::next(self, VolumeMount::<P>::default())
+        Transition::next(self, Initializing::<P>::default())
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
@@ -67,4 +249,4 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<ImagePullBackoff<P>> for ImagePull<P> {}
-impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for ImagePull<P> {}
+impl<P: GenericProvider> TransitionTo<Initializing<P>> for ImagePull<P> {}
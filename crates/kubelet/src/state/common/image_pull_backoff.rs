@@ -1,12 +1,24 @@
 //! Kubelet encountered an error when pulling container image.
 
-use super::image_pull::ImagePull;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{
+    ContainerState, ContainerStateWaiting, ContainerStatus as KubeContainerStatus,
+};
+
+use super::image_pull::{ImagePull, PulledModules};
+use super::{finish_starting_pod, BackoffSequence, GenericPodState, GenericProvider};
+use crate::container::Container;
 use crate::pod::state::prelude::*;
+use crate::reason::{Failure, Reason};
 
 /// Kubelet encountered an error when pulling container image.
 pub struct ImagePullBackoff<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    message: Option<String>,
+    /// Why the module of each container that couldn't be pulled wasn't, by container name
+    failures: BTreeMap<String, String>,
+    pulled: Option<PulledModules>,
 }
 
 impl<P: GenericProvider> std::fmt::Debug for ImagePullBackoff<P> {
@@ -19,25 +31,172 @@ impl<P: GenericProvider> Default for ImagePullBackoff<P> {
     fn default() -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            message: None,
+            failures: BTreeMap::new(),
+            pulled: None,
+        }
+    }
+}
+
+impl<P: GenericProvider> ImagePullBackoff<P> {
+    /// Creates an instance of the ImagePullBackoff state, reporting why the pull failed in the
+    /// pod status.
+    pub fn new(message: String) -> Self {
+        Self {
+            message: Some(message),
+            ..Default::default()
         }
     }
+
+    /// Creates an instance of the ImagePullBackoff state for the containers whose modules
+    /// couldn't be pulled, reporting why in their statuses as `ErrImagePull`. The failures are
+    /// keyed by container name.
+    pub fn with_failures(failures: BTreeMap<String, String>) -> Self {
+        let names: Vec<&str> = failures.keys().map(String::as_str).collect();
+        Self {
+            message: Some(format!(
+                "Unable to pull the modules of containers: {}",
+                names.join(", ")
+            )),
+            failures,
+            ..Default::default()
+        }
+    }
+
+    /// Keep the modules that were pulled, so that only the others are pulled again.
+    pub(crate) fn with_pulled(mut self, pulled: PulledModules) -> Self {
+        self.pulled = Some(pulled);
+        self
+    }
 }
 
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::PodState> for ImagePullBackoff<P> {
     async fn next(
-        self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        mut self: Box<Self>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
+        // Other pods can start while this one backs off
+        finish_starting_pod(&provider_state, &pod.latest()).await;
         pod_state.backoff(BackoffSequence::ImagePull).await;
-        Transition::next(self, ImagePull::<P>::default())
+        let pulled = self.pulled.take();
+        Transition::next(self, ImagePull::<P>::with_pulled(pulled))
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "ImagePullBackoff"))
+    async fn status(&self, _pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(image_pull_backoff_status(
+            pod,
+            self.message
+                .as_deref()
+                .unwrap_or_else(|| Reason::ImagePullBackOff.as_str()),
+            &self.failures,
+        ))
+    }
+}
+
+fn image_pull_backoff_status(
+    pod: &Pod,
+    message: &str,
+    failures: &BTreeMap<String, String>,
+) -> PodStatus {
+    let builder = StatusBuilder::new()
+        .phase(Phase::Pending)
+        .reason(Reason::ImagePullBackOff.as_str())
+        .message(message);
+    if failures.is_empty() {
+        return builder.build();
+    }
+    let statuses = |containers: Vec<Container>| -> Vec<KubeContainerStatus> {
+        containers
+            .iter()
+            .map(|container| container_status(container, failures))
+            .collect()
+    };
+    builder
+        .container_statuses(statuses(pod.containers()))
+        .init_container_statuses(statuses(pod.init_containers()))
+        .build()
+}
+
+/// The status of a container while the pod waits to pull modules again: `ErrImagePull` if its
+/// module couldn't be pulled, or waiting for the others otherwise.
+fn container_status(
+    container: &Container,
+    failures: &BTreeMap<String, String>,
+) -> KubeContainerStatus {
+    let waiting = match failures.get(container.name()) {
+        Some(message) => ContainerStateWaiting {
+            reason: Some(Failure::ImagePull.reason().to_string()),
+            message: Some(message.clone()),
+        },
+        None => ContainerStateWaiting {
+            reason: Some(Reason::ContainerCreating.to_string()),
+            message: Some("Waiting for the modules of other containers".to_owned()),
+        },
+    };
+    KubeContainerStatus {
+        name: container.name().to_owned(),
+        ready: false,
+        started: Some(false),
+        state: Some(ContainerState {
+            waiting: Some(waiting),
+            ..Default::default()
+        }),
+        ..Default::default()
     }
 }
 
 impl<P: GenericProvider> TransitionTo<ImagePull<P>> for ImagePullBackoff<P> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Container as KubeContainer, Pod as KubePod, PodSpec};
+    use krator::ObjectStatus;
+
+    fn container(name: &str) -> KubeContainer {
+        KubeContainer {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_image_pull_backoff_status_reports_failed_containers() {
+        let pod = Pod::from(KubePod {
+            spec: Some(PodSpec {
+                containers: vec![container("app"), container("sidecar")],
+                init_containers: Some(vec![container("setup")]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let mut failures = BTreeMap::new();
+        failures.insert("sidecar".to_owned(), "manifest unknown".to_owned());
+        let status = image_pull_backoff_status(&pod, "Unable to pull", &failures).json_patch();
+        assert_eq!("ImagePullBackOff", status["status"]["reason"]);
+        let statuses = &status["status"]["containerStatuses"];
+        assert_eq!("app", statuses[0]["name"]);
+        assert_eq!(
+            "ContainerCreating",
+            statuses[0]["state"]["waiting"]["reason"]
+        );
+        assert_eq!("sidecar", statuses[1]["name"]);
+        assert_eq!("ErrImagePull", statuses[1]["state"]["waiting"]["reason"]);
+        assert_eq!(
+            "manifest unknown",
+            statuses[1]["state"]["waiting"]["message"]
+        );
+        assert_eq!(
+            "setup",
+            status["status"]["initContainerStatuses"][0]["name"]
+        );
+
+        let status = image_pull_backoff_status(&pod, "Unable to pull", &BTreeMap::new());
+        assert!(status.json_patch()["status"]
+            .get("containerStatuses")
+            .is_none());
+    }
+}
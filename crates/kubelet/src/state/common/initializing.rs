@@ -0,0 +1,76 @@
+//! Kubelet is running the pod's init containers.
+
+use tracing::{error, info, instrument};
+
+use super::{finish_starting_pod, BackoffSequence, GenericPodState, GenericProvider};
+use crate::pod::state::prelude::*;
+
+/// Kubelet is running the pod's init containers. They are run one at a time, in the order of
+/// the pod spec, and each must exit successfully before the next is started. The pod fails if
+/// any of them fails. Once they have all run, the pod moves on to the provider's `RunState`.
+pub struct Initializing<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for Initializing<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "Initializing".fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> Default for Initializing<P> {
+    fn default() -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for Initializing<P> {
+    #[instrument(
+        level = "info",
+        skip(self, provider_state, pod_state, pod),
+        fields(pod_name)
+    )]
+    async fn next(
+        self: Box<Self>,
+        provider_state: SharedState<P::ProviderState>,
+        pod_state: &mut P::PodState,
+        pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        let latest_pod = pod.latest();
+
+        tracing::Span::current().record("pod_name", &latest_pod.name());
+
+        for init_container in latest_pod.init_containers() {
+            info!(
+                container_name = init_container.name(),
+                "Starting init container for pod"
+            );
+
+            // Each new init container resets the CrashLoopBackoff timer.
+            pod_state.reset_backoff(BackoffSequence::CrashLoop).await;
+
+            if let Err(e) = pod_state
+                .run_init_container(provider_state.clone(), pod.clone(), &init_container)
+                .await
+            {
+                error!(error = %e, container_name = init_container.name(), "Init container failed");
+                finish_starting_pod(&provider_state, &latest_pod).await;
+                return Transition::Complete(Err(anyhow::anyhow!(
+                    "Init container {} failed: {}",
+                    init_container.name(),
+                    e
+                )));
+            }
+        }
+        info!("Finished init containers for pod");
+        pod_state.reset_backoff(BackoffSequence::CrashLoop).await;
+        Transition::next_unchecked(self, P::RunState::default())
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(make_status(Phase::Pending, "Initializing"))
+    }
+}
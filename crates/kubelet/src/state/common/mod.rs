@@ -3,18 +3,23 @@
 //! states in many providers; instead, the provider need only implement the
 //! GenericProviderState and GenericPodState traits for its state types.
 
+use crate::attestation::ContainerModule;
 use crate::pod::state::prelude::PodStatus;
 use crate::pod::Pod;
 use crate::provider::{DevicePluginSupport, PluginSupport, VolumeSupport};
-use krator::{ObjectState, State};
+use krator::{Manifest, ObjectState, SharedState, State};
 use std::collections::HashMap;
 
 pub mod crash_loop_backoff;
+pub mod ephemeral;
 pub mod error;
 pub mod image_pull;
 pub mod image_pull_backoff;
+pub mod initializing;
 pub mod registered;
+pub mod rejected;
 pub mod resources;
+pub mod startup;
 pub mod terminated;
 pub mod volume_mount;
 
@@ -46,6 +51,78 @@ pub trait GenericProviderState: 'static + Send + Sync {
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Waits for the specified pod to finish after it was stopped. The
+    /// caller bounds the wait with the pod's termination grace period. The
+    /// default implementation returns immediately.
+    async fn wait(&self, _pod: &crate::pod::Pod) -> anyhow::Result<()> {
+        Ok(())
+    }
+    /// Forces the specified pod to stop, once it has not finished within its
+    /// termination grace period after it was stopped. The default
+    /// implementation stops it again.
+    async fn kill(&self, pod: &crate::pod::Pod) -> anyhow::Result<()> {
+        self.stop(pod).await
+    }
+    /// Gets the limits on the size of the pods the provider accepts. Pods
+    /// beyond them are rejected when they are registered. The default
+    /// implementation has no limits.
+    fn pod_spec_limits(&self) -> crate::pod::PodSpecLimits {
+        crate::pod::PodSpecLimits::default()
+    }
+    /// Gets information about the node the provider runs pods on, if the
+    /// provider keeps it. The default implementation returns `None`.
+    fn node_info(&self) -> Option<&crate::node::NodeInfo> {
+        None
+    }
+    /// Gets the policy for backing off pods that keep failing, before
+    /// the overrides in a pod's annotations are applied. The default
+    /// implementation returns the Kubernetes defaults.
+    fn crash_loop_policy(&self) -> crate::backoff::CrashLoopPolicy {
+        crate::backoff::CrashLoopPolicy::default()
+    }
+    /// Gets the admission of pods by the resources the node has left to
+    /// allocate, if the provider checks pods against them. Pods whose
+    /// requests don't fit are rejected when they are registered. The
+    /// default implementation returns `None`, which admits every pod.
+    fn resource_admission(&self) -> Option<&crate::resources::ResourceAdmission> {
+        None
+    }
+    /// Gets the limit on how many pods may be starting at once, if the
+    /// provider limits them. Pods wait for a place among the starting pods
+    /// before their modules are pulled, and give it up once they are
+    /// running. The default implementation returns `None`, which lets every
+    /// pod start right away.
+    fn startup_limiter(&self) -> Option<&startup::StartupLimiter> {
+        None
+    }
+    /// Gets the registry the modules of each pod are recorded in once
+    /// they have been pulled, if the provider attests to the modules it
+    /// runs. The default implementation returns `None`, which records
+    /// nothing.
+    fn module_registry(&self) -> Option<&crate::attestation::ModuleRegistry> {
+        None
+    }
+    /// Gets the URL of the API server that the kubeconfigs generated for
+    /// pods, on their request, talk to. The default implementation returns
+    /// `None`, for providers whose workloads can't be given one; pods that
+    /// ask for a kubeconfig then fail to start.
+    fn api_server_url(&self) -> Option<&str> {
+        None
+    }
+    /// Gets the platform whose manifest is loaded from images that are
+    /// image indexes, in the OCI layouts pods load their modules from. The
+    /// default implementation derives it from the architecture in
+    /// `node_info`, if the provider keeps it.
+    fn platform(&self) -> Option<oci_distribution::manifest::Platform> {
+        self.node_info()
+            .and_then(|info| crate::store::oci::platform_for_arch(&info.architecture))
+    }
+    /// Gets the directory the OCI layouts pods load their modules from
+    /// have to be in. The default implementation returns `None`, so that
+    /// pods can't load modules from layouts.
+    fn oci_layout_root(&self) -> Option<&std::path::Path> {
+        None
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -62,8 +139,71 @@ pub trait GenericPodState: ObjectState<Manifest = Pod, Status = PodStatus> {
     /// the provider's execution environment. Typically your
     /// implementation can just move the volumes map into a member field.
     async fn set_volumes(&mut self, volumes: HashMap<String, crate::volume::VolumeRef>);
-    /// Backs off (waits) after an error of the specified kind.
-    async fn backoff(&mut self, sequence: BackoffSequence);
+    /// Stores the RuntimeClass the pod asks to be run with, once it has been
+    /// validated with `validate_runtime_class`. This is called when the pod
+    /// is registered, before any containers are run. The default
+    /// implementation ignores it.
+    async fn set_runtime_class(
+        &mut self,
+        _runtime_class: Option<crate::runtime_class::RuntimeClass>,
+    ) {
+    }
+    /// Stores the hosts file generated for the pod, with its `hostAliases`,
+    /// so that it can be supplied to the pod's containers at
+    /// [`HOSTS_FILE_DIR`](crate::pod::HOSTS_FILE_DIR). Providers that keep it
+    /// should remove it once the pod is gone. The default implementation
+    /// removes it straight away, for providers that can't supply it.
+    async fn set_hosts_file(&mut self, hosts_file: crate::pod::HostsFile) {
+        let _ = hosts_file.remove().await;
+    }
+    /// Stores the kubeconfig generated for the pod, if it asks for one with
+    /// the [`KUBECONFIG_ANNOTATION`](crate::pod::KUBECONFIG_ANNOTATION)
+    /// annotation, so that it can be supplied to the pod's containers at
+    /// [`PodKubeconfig::guest_dir`](crate::pod::PodKubeconfig::guest_dir).
+    /// Providers that keep it should remove it once the pod is gone. The
+    /// default implementation removes it straight away.
+    async fn set_kubeconfig(&mut self, kubeconfig: crate::pod::PodKubeconfig) {
+        let _ = kubeconfig.remove().await;
+    }
+    /// Runs an init container of the pod to completion, returning an error
+    /// if it could not be run or exited unsuccessfully. This is called by
+    /// the `Initializing` state for each init container in turn.
+    async fn run_init_container(
+        &mut self,
+        provider_state: SharedState<Self::SharedState>,
+        pod: Manifest<Pod>,
+        container: &crate::container::Container,
+    ) -> anyhow::Result<()>;
+    /// Starts an ephemeral container that was added to the running pod,
+    /// e.g. by `kubectl debug`, with the module of its image. This should
+    /// return once the container has been started, without waiting for it
+    /// to exit. The container runs until it exits or the pod is stopped,
+    /// and its exit doesn't affect the pod. This is called by
+    /// [`EphemeralContainers`](ephemeral::EphemeralContainers) for each new
+    /// ephemeral container, for providers whose `RunState` uses it. The
+    /// default implementation returns an error that ephemeral containers
+    /// are not supported.
+    async fn start_ephemeral_container(
+        &mut self,
+        _provider_state: SharedState<Self::SharedState>,
+        _pod: Manifest<Pod>,
+        _container: &crate::container::Container,
+        _module: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        Err(crate::provider::NotImplementedError.into())
+    }
+    /// Stores why the pod is being terminated. This is called by the
+    /// `Terminated` state before the pod is stopped, so that the provider
+    /// can clean up according to the reason, e.g. keep the logs of evicted
+    /// pods. The default implementation ignores it.
+    async fn set_termination_reason(&mut self, _reason: crate::pod::TerminationReason) {}
+    /// Gets how long to back off after an error of the specified kind.
+    async fn next_backoff(&mut self, sequence: BackoffSequence) -> std::time::Duration;
+    /// Backs off (waits) after an error of the specified kind. The default
+    /// implementation waits for the duration given by `next_backoff`.
+    async fn backoff(&mut self, sequence: BackoffSequence) {
+        tokio::time::sleep(self.next_backoff(sequence).await).await
+    }
     /// Resets the backoff time for the specified kind of error.
     async fn reset_backoff(&mut self, sequence: BackoffSequence);
     /// Increments an error count and returns whether the number of errors
@@ -79,8 +219,8 @@ pub trait GenericProvider: 'static + Send + Sync {
     /// The state that is passed between Pod state handlers.
     type PodState: GenericPodState + ObjectState<SharedState = Self::ProviderState>;
     /// The state to which pods should transition after they have completed
-    /// all generic states. Typically this is the state which first runs
-    /// any pod binary (for example, the state which runs init containers).
+    /// all generic states, including running their init containers.
+    /// Typically this is the state which starts the pod's containers.
     type RunState: Default + State<Self::PodState>;
 
     /// Validates that the pod specification is compatible with the provider.
@@ -96,6 +236,17 @@ pub trait GenericProvider: 'static + Send + Sync {
     /// a description of why the pod cannot be run.
     fn validate_container_runnable(container: &crate::container::Container) -> anyhow::Result<()>;
 
+    /// Validates that the provider can run pods with the RuntimeClass: that
+    /// it knows the handler, and that the handler's parameters are valid.
+    /// If not, implementations should return an Err value with a
+    /// description of why the pod cannot be run. The default implementation
+    /// accepts every RuntimeClass, ignoring its handler.
+    fn validate_runtime_class(
+        _runtime_class: &crate::runtime_class::RuntimeClass,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     /// Validates that the pod specification, including all containers, is
     /// compatible with the provider. The default implementation calls
     /// `validate_pod_runnable`, then `validate_container_runnable` for each
@@ -108,3 +259,172 @@ pub trait GenericProvider: 'static + Send + Sync {
         Ok(())
     }
 }
+
+/// Checks whether the provider accepts the pod: that it is runnable, within the provider's
+/// pod spec limits, has a valid crash loop policy and, if the provider admits pods by their
+/// resources, fits in what the node has left. This is what pods are checked against when they
+/// are registered.
+pub fn check_pod_admission<P: GenericProvider>(
+    provider_state: &P::ProviderState,
+    pod: &Pod,
+) -> anyhow::Result<()> {
+    P::validate_pod_and_containers_runnable(pod)?;
+    pod.validate_annotations()?;
+    provider_state.pod_spec_limits().check(pod)?;
+    provider_state.crash_loop_policy().for_pod(pod)?;
+    if let Some(resource_admission) = provider_state.resource_admission() {
+        resource_admission.check(pod)?;
+    }
+    Ok(())
+}
+
+/// Admits the pod, setting aside the resources it requests if the provider admits pods by their
+/// resources. They are given back when the pod is terminated.
+pub fn admit_pod<P: GenericProvider>(
+    provider_state: &P::ProviderState,
+    pod: &Pod,
+) -> anyhow::Result<()> {
+    check_pod_admission::<P>(provider_state, pod)?;
+    if let Some(resource_admission) = provider_state.resource_admission() {
+        resource_admission.admit(pod)?;
+    }
+    Ok(())
+}
+
+/// Waits for the pod to have a place among the starting pods, if the provider limits how many
+/// pods may be starting at once.
+pub async fn start_pod<S: GenericProviderState>(provider_state: &SharedState<S>, pod: &Pod) {
+    let startup_limiter = provider_state.read().await.startup_limiter().cloned();
+    if let Some(startup_limiter) = startup_limiter {
+        startup_limiter.start(pod).await;
+    }
+}
+
+/// Takes a place among the starting pods for the pod if one is free, without waiting for one.
+/// Returns whether the pod has a place, which it always does if the provider doesn't limit how
+/// many pods may be starting at once.
+pub async fn try_start_pod<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+) -> bool {
+    match provider_state.read().await.startup_limiter() {
+        Some(startup_limiter) => startup_limiter.try_start(pod),
+        None => true,
+    }
+}
+
+/// Gives up the pod's place among the starting pods, once it is running or has stopped starting.
+/// Providers whose `RunState` doesn't use the generic states call this once the pod is running.
+pub async fn finish_starting_pod<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+) {
+    if let Some(startup_limiter) = provider_state.read().await.startup_limiter() {
+        startup_limiter.finish(pod);
+    }
+}
+
+/// Records the modules pulled for the pod, with their digests and where they are stored, if the
+/// provider attests to the modules it runs.
+pub async fn record_pod_modules<S: GenericProviderState>(
+    provider_state: &SharedState<S>,
+    pod: &Pod,
+    modules: &HashMap<String, Vec<u8>>,
+) {
+    let (module_registry, store) = {
+        let state_reader = provider_state.read().await;
+        match state_reader.module_registry() {
+            Some(module_registry) => (module_registry.clone(), state_reader.store()),
+            None => return,
+        }
+    };
+    let mut containers = Vec::new();
+    for container in pod.all_containers() {
+        let (module, image) = match (modules.get(container.name()), container.image()) {
+            (Some(module), Ok(Some(image))) => (module, image),
+            _ => continue,
+        };
+        let path = store.module_path(&image).await;
+        containers.push(ContainerModule::new(
+            container.name(),
+            &image.whole(),
+            module,
+            path,
+        ));
+    }
+    module_registry.record(pod, containers).await;
+}
+
+/// Gets the RuntimeClass the pod asks for, if any, and checks that the provider can run pods
+/// with it.
+pub async fn resolve_runtime_class<P: GenericProvider>(
+    client: &kube::Client,
+    pod: &Pod,
+) -> anyhow::Result<Option<crate::runtime_class::RuntimeClass>> {
+    let runtime_class = crate::runtime_class::RuntimeClass::for_pod(client, pod).await?;
+    if let Some(runtime_class) = &runtime_class {
+        P::validate_runtime_class(runtime_class).map_err(|e| {
+            anyhow::anyhow!(
+                "RuntimeClass {} with handler {} is not supported: {}",
+                runtime_class.name,
+                runtime_class.handler,
+                e
+            )
+        })?;
+    }
+    Ok(runtime_class)
+}
+
+/// Previews whether the pod would start on this node, without starting anything. This runs
+/// the admission checks the pod would go through when it is registered, resolves its
+/// RuntimeClass, and checks that the store could get the modules of all its containers with
+/// their pull policies, which includes resolving their image pull secrets.
+///
+/// Returns the reasons the pod would be rejected, which is empty if it would start. Failures
+/// later on, such as missing volumes or resources the node has run out of, are not predicted.
+pub async fn preview_pod<P: GenericProvider>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod: &Pod,
+) -> Vec<String> {
+    let (admission, client, store, platform, layout_root) = {
+        let state_reader = provider_state.read().await;
+        (
+            check_pod_admission::<P>(&state_reader, pod),
+            state_reader.client(),
+            state_reader.store(),
+            state_reader.platform(),
+            state_reader.oci_layout_root().map(ToOwned::to_owned),
+        )
+    };
+    let mut reasons = Vec::new();
+    if let Err(e) = admission {
+        reasons.push(e.to_string());
+    }
+    if let Err(e) = resolve_runtime_class::<P>(&client, pod).await {
+        reasons.push(e.to_string());
+    }
+
+    let auth_resolver = crate::secret::RegistryAuthResolver::new(client, pod);
+    let store =
+        match crate::store::oci::pod_store(store, pod, platform, layout_root.as_deref()).await {
+            Ok(store) => store,
+            Err(e) => {
+                reasons.push(format!("{:#}", e));
+                return reasons;
+            }
+        };
+    for container in pod.all_containers() {
+        let resolution = async {
+            let image = container
+                .image()?
+                .ok_or_else(|| anyhow::anyhow!("Container has no image"))?;
+            let pull_policy = container.effective_pull_policy()?;
+            let credentials = auth_resolver.resolve_credentials().await?;
+            store.resolve(&image, pull_policy, &credentials).await
+        };
+        if let Err(e) = resolution.await {
+            reasons.push(format!("Container {}: {:#}", container.name(), e));
+        }
+    }
+    reasons
+}
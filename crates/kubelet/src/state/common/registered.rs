@@ -1,11 +1,17 @@
 //! The Kubelet is aware of the Pod.
 
 use crate::pod::state::prelude::*;
-use tracing::{debug, error, info, instrument};
+use crate::reason::Failure;
+use tracing::{debug, error, info, instrument, warn};
 
 use super::error::Error;
+use super::image_pull::ModulePrefetch;
+use super::rejected::Rejected;
 use super::resources::Resources;
-use super::GenericProvider;
+use super::{
+    admit_pod, resolve_runtime_class, try_start_pod, GenericPodState, GenericProvider,
+    GenericProviderState,
+};
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,13 +36,13 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     #[instrument(
         level = "info",
-        skip(self, _provider_state, _pod_state, pod),
+        skip(self, provider_state, pod_state, pod),
         fields(pod_name)
     )]
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
-        _pod_state: &mut P::PodState,
+        provider_state: SharedState<P::ProviderState>,
+        pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
@@ -44,16 +50,52 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
         tracing::Span::current().record("pod_name", &pod.name());
 
         debug!("Preparing to register pod");
-        match P::validate_pod_and_containers_runnable(&pod) {
-            Ok(_) => (),
+        let (limits, client) = {
+            let state_reader = provider_state.read().await;
+            (state_reader.pod_spec_limits(), state_reader.client())
+        };
+        // The spec of a pod can't change, so a pod beyond the limits would never be admitted
+        if let Err(e) = limits.check(&pod) {
+            error!(error = %e, "Pod exceeds the limits of the node");
+            record_rejection(&client, &pod, &e).await;
+            let next = Rejected::<P>::new(Failure::Admission, e.to_string());
+            return Transition::next(self, next);
+        }
+        let admission = admit_pod::<P>(&*provider_state.read().await, &pod);
+        let validation = match admission {
+            Ok(()) => resolve_runtime_class::<P>(&client, &pod).await,
+            Err(e) => Err(e),
+        };
+        let runtime_class = match validation {
+            Ok(runtime_class) => runtime_class,
             Err(e) => {
                 error!(error = %e);
-                let next = Error::<P>::new(e.to_string());
+                record_rejection(&client, &pod, &e).await;
+                let next = Error::<P>::with_failure(Failure::Admission, e.to_string());
                 return Transition::next(self, next);
             }
-        }
+        };
+        pod_state.set_runtime_class(runtime_class).await;
         info!("Pod registered");
-        let next = Resources::<P>::default();
+        // Start pulling the modules right away, so that they download while resources are
+        // allocated and volumes are mounted. Pods that have to wait for a place among the
+        // starting pods wait for it when they pull their modules instead, so that waiting doesn't
+        // hold up registration.
+        if !try_start_pod(&provider_state, &pod).await {
+            debug!("Waiting for other pods to start before pulling modules");
+            return Transition::next(self, Resources::<P>::default());
+        }
+        let (client, store, platform, layout_root) = {
+            let state_reader = provider_state.read().await;
+            (
+                state_reader.client(),
+                state_reader.store(),
+                state_reader.platform(),
+                state_reader.oci_layout_root().map(ToOwned::to_owned),
+            )
+        };
+        let prefetch = ModulePrefetch::start(client, store, platform, layout_root, pod);
+        let next = Resources::<P>::with_prefetch(prefetch);
         Transition::next(self, next)
     }
 
@@ -62,5 +104,17 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     }
 }
 
+/// Records on the node that the pod was rejected.
+async fn record_rejection(client: &kube::Client, pod: &Pod, error: &anyhow::Error) {
+    if let Some(node_name) = pod.node_name() {
+        if let Err(e) =
+            crate::node::record_pod_rejection(client, node_name, pod, &error.to_string()).await
+        {
+            warn!(error = %e, "Unable to record pod rejection on node");
+        }
+    }
+}
+
 impl<P: GenericProvider> TransitionTo<Error<P>> for Registered<P> {}
+impl<P: GenericProvider> TransitionTo<Rejected<P>> for Registered<P> {}
 impl<P: GenericProvider> TransitionTo<Resources<P>> for Registered<P> {}
@@ -0,0 +1,53 @@
+//! The Pod was rejected by the node and will never run.
+
+use super::GenericProvider;
+use crate::pod::state::prelude::*;
+use crate::reason::Failure;
+
+/// The Pod was rejected by the node and will never run, e.g. because its spec exceeds the limits
+/// of the node. Unlike [`Error`](super::error::Error), the pod is not retried: it is reported as
+/// `Failed` and its state machine completes.
+pub struct Rejected<P: GenericProvider> {
+    phantom: std::marker::PhantomData<P>,
+    failure: Failure,
+    message: String,
+}
+
+impl<P: GenericProvider> std::fmt::Debug for Rejected<P> {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = format!("Rejected: {}", self.message);
+        text.fmt(formatter)
+    }
+}
+
+impl<P: GenericProvider> Rejected<P> {
+    /// Creates an instance of the Rejected state for a failure of the given step, which decides
+    /// the reason reported in the pod status.
+    pub fn new(failure: Failure, message: String) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            failure,
+            message,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P: GenericProvider> State<P::PodState> for Rejected<P> {
+    async fn next(
+        self: Box<Self>,
+        _provider_state: SharedState<P::ProviderState>,
+        _pod_state: &mut P::PodState,
+        _pod: Manifest<Pod>,
+    ) -> Transition<P::PodState> {
+        Transition::Complete(Ok(()))
+    }
+
+    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(StatusBuilder::new()
+            .phase(Phase::Failed)
+            .reason(self.failure.reason().as_str())
+            .message(&self.message)
+            .build())
+    }
+}
@@ -1,6 +1,7 @@
 //! Resources can be successfully allocated to the Pod.
 use crate::pod::state::prelude::*;
 use crate::provider::DevicePluginSupport;
+use crate::reason::Failure;
 use crate::resources::device_plugin_manager::PodResourceRequests;
 use crate::resources::util;
 use crate::volume::{HostPathVolume, VolumeRef};
@@ -11,12 +12,24 @@ use std::collections::HashMap;
 use tracing::{debug, error, info};
 
 use super::error::Error;
-use super::image_pull::ImagePull;
+use super::image_pull::ModulePrefetch;
+use super::volume_mount::VolumeMount;
 use super::{GenericPodState, GenericProvider};
 
 /// Resources can be successfully allocated to the Pod
 pub struct Resources<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    prefetch: Option<ModulePrefetch>,
+}
+
+impl<P: GenericProvider> Resources<P> {
+    /// Allocate resources while the pod's modules are pulled in the background.
+    pub(crate) fn with_prefetch(prefetch: ModulePrefetch) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            prefetch: Some(prefetch),
+        }
+    }
 }
 
 impl<P: GenericProvider> std::fmt::Debug for Resources<P> {
@@ -29,6 +42,7 @@ impl<P: GenericProvider> Default for Resources<P> {
     fn default() -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            prefetch: None,
         }
     }
 }
@@ -36,7 +50,7 @@ impl<P: GenericProvider> Default for Resources<P> {
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::PodState> for Resources<P> {
     async fn next(
-        self: Box<Self>,
+        mut self: Box<Self>,
         provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
@@ -69,7 +83,7 @@ impl<P: GenericProvider> State<P::PodState> for Resources<P> {
                 .await
             {
                 error!(error = %e);
-                let next = Error::<P>::new(e.to_string());
+                let next = Error::<P>::with_failure(Failure::ContainerConfig, e.to_string());
                 return Transition::next(self, next);
             }
 
@@ -116,7 +130,7 @@ impl<P: GenericProvider> State<P::PodState> for Resources<P> {
             info!("Resources allocated to Pod: {}", pod.name());
         }
 
-        let next = ImagePull::<P>::default();
+        let next = VolumeMount::<P>::with_prefetch(self.prefetch.take());
         Transition::next(self, next)
     }
 
@@ -126,4 +140,4 @@ impl<P: GenericProvider> State<P::PodState> for Resources<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for Resources<P> {}
-impl<P: GenericProvider> TransitionTo<ImagePull<P>> for Resources<P> {}
+impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for Resources<P> {}
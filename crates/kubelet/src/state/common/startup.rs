@@ -0,0 +1,382 @@
+//! Limits on how many pods start at once.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::metrics::pod_metrics;
+use crate::pod::{Pod, QosClass};
+
+/// Limits how many pods may be starting at once, from pulling their modules until they are
+/// running, so that a node that comes back with many pods doesn't pull and compile all of their
+/// modules at the same time. Pods that are already running are not limited.
+///
+/// A pod holds its place from when it [starts](Self::start) until it has
+/// [finished](Self::finish) starting, whether it is running, backing off or has been deleted.
+/// Pods waiting for a place are given one by their QoS class, `Guaranteed` pods first, and then
+/// namespace by namespace in turn, so that a namespace that creates many pods at once doesn't hold
+/// up the pods of other namespaces. Each namespace may be limited to fewer starting pods than the
+/// node. Clones share the same places.
+#[derive(Clone, Debug)]
+pub struct StartupLimiter {
+    places: Arc<Mutex<Places>>,
+}
+
+#[derive(Debug)]
+struct Places {
+    free: usize,
+    max_per_namespace: Option<usize>,
+    starting: HashMap<String, Starting>,
+    starting_per_namespace: HashMap<String, usize>,
+    waiting: BTreeMap<String, VecDeque<Waiter>>,
+    /// The namespace that was last given a place, which namespaces are taken in turn after.
+    last_namespace: Option<String>,
+}
+
+#[derive(Debug)]
+struct Starting {
+    namespace: String,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    key: String,
+    qos_class: QosClass,
+    place: oneshot::Sender<()>,
+}
+
+impl StartupLimiter {
+    /// Let up to `max_starting_pods` pods start at once.
+    pub fn new(max_starting_pods: usize) -> Self {
+        Self {
+            places: Arc::new(Mutex::new(Places {
+                free: max_starting_pods,
+                max_per_namespace: None,
+                starting: HashMap::new(),
+                starting_per_namespace: HashMap::new(),
+                waiting: BTreeMap::new(),
+                last_namespace: None,
+            })),
+        }
+    }
+
+    /// Let up to `max_starting_pods` pods of each namespace start at once, as well as limiting
+    /// the pods of the node.
+    pub fn with_namespace_limit(self, max_starting_pods: usize) -> Self {
+        self.places.lock().unwrap().max_per_namespace = Some(max_starting_pods);
+        self
+    }
+
+    /// The limiter for the `maxStartingPods` and `maxStartingPodsPerNamespace` of the
+    /// configuration, if either is set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let per_namespace = config.max_starting_pods_per_namespace.map(usize::from);
+        let limiter = match (config.max_starting_pods, per_namespace) {
+            (None, None) => return None,
+            (Some(max), _) => StartupLimiter::new(usize::from(max)),
+            (None, Some(_)) => StartupLimiter::new(usize::MAX),
+        };
+        Some(match per_namespace {
+            Some(max) => limiter.with_namespace_limit(max),
+            None => limiter,
+        })
+    }
+
+    /// Waits for the pod to have a place among the starting pods. Pods that already have one
+    /// keep it.
+    pub async fn start(&self, pod: &Pod) {
+        let key = startup_key(pod);
+        let namespace = pod.namespace().to_owned();
+        let waiting = {
+            let mut places = self.places.lock().unwrap();
+            if places.starting.contains_key(&key) {
+                return;
+            }
+            if places.has_place_for(&namespace) {
+                places.take(key, namespace);
+                return;
+            }
+            debug!(pod = %pod.name(), %namespace, "Waiting for other pods to start");
+            let (place, waiting) = oneshot::channel();
+            places
+                .waiting
+                .entry(namespace.clone())
+                .or_default()
+                .push_back(Waiter {
+                    key,
+                    qos_class: pod.qos_class(),
+                    place,
+                });
+            waiting
+        };
+        let since = Instant::now();
+        // The sender is only dropped once the place is given
+        let _ = waiting.await;
+        pod_metrics().record_startup_wait(&namespace, since.elapsed());
+    }
+
+    /// Takes a place among the starting pods for the pod if one is free, without waiting for
+    /// one. Returns whether the pod has a place.
+    pub fn try_start(&self, pod: &Pod) -> bool {
+        let key = startup_key(pod);
+        let namespace = pod.namespace().to_owned();
+        let mut places = self.places.lock().unwrap();
+        if places.starting.contains_key(&key) {
+            return true;
+        }
+        if !places.has_place_for(&namespace) {
+            return false;
+        }
+        places.take(key, namespace);
+        true
+    }
+
+    /// Gives up the pod's place among the starting pods, once it is running or has stopped
+    /// starting. Pods without a place are ignored.
+    pub fn finish(&self, pod: &Pod) {
+        let mut places = self.places.lock().unwrap();
+        let starting = match places.starting.remove(&startup_key(pod)) {
+            Some(starting) => starting,
+            None => return,
+        };
+        debug!(pod = %pod.name(), "Pod finished starting");
+        pod_metrics().record_startup(&starting.namespace, starting.since.elapsed());
+        places.free += 1;
+        if let Some(count) = places.starting_per_namespace.get_mut(&starting.namespace) {
+            *count -= 1;
+            if *count == 0 {
+                places.starting_per_namespace.remove(&starting.namespace);
+            }
+        }
+        places.give_places();
+    }
+
+    /// The number of pods that are starting.
+    pub fn starting(&self) -> usize {
+        self.places.lock().unwrap().starting.len()
+    }
+}
+
+impl Places {
+    fn has_place_for(&self, namespace: &str) -> bool {
+        let starting = self
+            .starting_per_namespace
+            .get(namespace)
+            .copied()
+            .unwrap_or(0);
+        self.free > 0 && self.max_per_namespace.map_or(true, |max| starting < max)
+    }
+
+    fn take(&mut self, key: String, namespace: String) {
+        self.free -= 1;
+        *self
+            .starting_per_namespace
+            .entry(namespace.clone())
+            .or_default() += 1;
+        self.last_namespace = Some(namespace.clone());
+        self.starting.insert(
+            key,
+            Starting {
+                namespace,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    /// Give the free places to waiting pods, the most important QoS class first and taking the
+    /// namespaces in turn.
+    fn give_places(&mut self) {
+        while self.free > 0 {
+            let (namespace, qos_class) = match self.next_namespace() {
+                Some(next) => next,
+                None => return,
+            };
+            let waiters = self
+                .waiting
+                .get_mut(&namespace)
+                .expect("next namespace has waiting pods");
+            let position = waiters
+                .iter()
+                .position(|waiter| waiter.qos_class == qos_class)
+                .expect("next namespace has waiting pods of the class");
+            let waiter = waiters.remove(position).expect("position is in the queue");
+            if waiters.is_empty() {
+                self.waiting.remove(&namespace);
+            }
+            // Pods that stopped waiting, e.g. because they were deleted, are passed over
+            if self.starting.contains_key(&waiter.key) || waiter.place.send(()).is_err() {
+                continue;
+            }
+            self.take(waiter.key, namespace);
+        }
+    }
+
+    /// The most important QoS class of the pods waiting in namespaces that may start another
+    /// pod, and the first of those namespaces with a pod of that class after the one that was
+    /// last given a place, wrapping around.
+    fn next_namespace(&self) -> Option<(String, QosClass)> {
+        let qos_class = self
+            .waiting
+            .iter()
+            .filter(|(namespace, _)| self.has_place_for(namespace))
+            .flat_map(|(_, waiters)| waiters.iter().map(|waiter| waiter.qos_class))
+            .min()?;
+        let eligible = |namespace: &&String| {
+            self.has_place_for(namespace)
+                && self.waiting[*namespace]
+                    .iter()
+                    .any(|waiter| waiter.qos_class == qos_class)
+        };
+        let after = match &self.last_namespace {
+            Some(last) => self
+                .waiting
+                .keys()
+                .filter(|namespace| *namespace > last)
+                .find(eligible),
+            None => None,
+        };
+        after
+            .or_else(|| self.waiting.keys().find(eligible))
+            .map(|namespace| (namespace.clone(), qos_class))
+    }
+}
+
+fn startup_key(pod: &Pod) -> String {
+    match &pod.as_kube_pod().metadata.uid {
+        Some(uid) => uid.clone(),
+        None => format!("{}/{}", pod.namespace(), pod.name()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use kube::api::ObjectMeta;
+
+    fn pod(name: &str) -> Pod {
+        namespaced_pod("default", name)
+    }
+
+    fn namespaced_pod(namespace: &str, name: &str) -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                namespace: Some(namespace.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_startup_limiter() {
+        let limiter = StartupLimiter::new(1);
+        let first = pod("first");
+        let second = pod("second");
+        limiter.start(&first).await;
+        // A pod that is already starting keeps its place
+        limiter.start(&first).await;
+        assert_eq!(1, limiter.starting());
+
+        let waiting = {
+            let limiter = limiter.clone();
+            let second = second.clone();
+            tokio::spawn(async move { limiter.start(&second).await })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(1, limiter.starting());
+
+        assert!(limiter.try_start(&first));
+        assert!(!limiter.try_start(&second));
+        limiter.finish(&first);
+        waiting.await.unwrap();
+        assert_eq!(1, limiter.starting());
+        limiter.finish(&second);
+        limiter.finish(&second);
+        assert_eq!(0, limiter.starting());
+    }
+
+    #[tokio::test]
+    async fn test_startup_limiter_takes_namespaces_in_turn() {
+        let limiter = StartupLimiter::new(1);
+        let busy = ["busy-1", "busy-2", "busy-3"]
+            .iter()
+            .map(|name| namespaced_pod("busy", name))
+            .collect::<Vec<_>>();
+        let quiet = namespaced_pod("quiet", "quiet-1");
+        limiter.start(&busy[0]).await;
+        for pod in busy[1..].iter().chain(std::iter::once(&quiet)) {
+            let limiter = limiter.clone();
+            let pod = pod.clone();
+            tokio::spawn(async move { limiter.start(&pod).await });
+        }
+        tokio::task::yield_now().await;
+
+        // The quiet namespace is next, although its pod came after the busy ones
+        limiter.finish(&busy[0]);
+        assert!(limiter.try_start(&quiet));
+        assert!(!limiter.try_start(&busy[1]));
+        limiter.finish(&quiet);
+        assert!(limiter.try_start(&busy[1]));
+        assert!(!limiter.try_start(&busy[2]));
+    }
+
+    #[tokio::test]
+    async fn test_startup_limiter_starts_guaranteed_pods_first() {
+        let limiter = StartupLimiter::new(1);
+        let guaranteed = |namespace: &str, name: &str| {
+            let mut pod = namespaced_pod(namespace, name).as_kube_pod().clone();
+            pod.spec = Some(
+                serde_json::from_value(serde_json::json!({
+                    "containers": [{
+                        "name": "app",
+                        "resources": {
+                            "requests": {"cpu": "100m", "memory": "64Mi"},
+                            "limits": {"cpu": "100m", "memory": "64Mi"},
+                        },
+                    }],
+                }))
+                .unwrap(),
+            );
+            Pod::from(pod)
+        };
+        let first = namespaced_pod("busy", "first");
+        let best_effort = namespaced_pod("busy", "best-effort");
+        let guaranteed_pods = [
+            guaranteed("busy", "guaranteed"),
+            guaranteed("quiet", "guaranteed"),
+        ];
+        limiter.start(&first).await;
+        for pod in std::iter::once(&best_effort).chain(&guaranteed_pods) {
+            let limiter = limiter.clone();
+            let pod = pod.clone();
+            tokio::spawn(async move { limiter.start(&pod).await });
+            tokio::task::yield_now().await;
+        }
+
+        // The guaranteed pods go first, even the one that waits in the same namespace behind a
+        // best effort pod, and the namespaces are still taken in turn between them
+        limiter.finish(&first);
+        assert!(limiter.try_start(&guaranteed_pods[1]));
+        assert!(!limiter.try_start(&best_effort));
+        limiter.finish(&guaranteed_pods[1]);
+        assert!(limiter.try_start(&guaranteed_pods[0]));
+        limiter.finish(&guaranteed_pods[0]);
+        assert!(limiter.try_start(&best_effort));
+    }
+
+    #[tokio::test]
+    async fn test_startup_limiter_limits_namespaces() {
+        let limiter = StartupLimiter::new(2).with_namespace_limit(1);
+        limiter.start(&namespaced_pod("busy", "busy-1")).await;
+        assert!(!limiter.try_start(&namespaced_pod("busy", "busy-2")));
+        assert!(limiter.try_start(&namespaced_pod("quiet", "quiet-1")));
+        assert_eq!(2, limiter.starting());
+    }
+}
@@ -1,9 +1,23 @@
 //! Pod was deleted.
 
-use super::{GenericProvider, GenericProviderState};
+use super::{GenericPodState, GenericProvider, GenericProviderState};
 use crate::pod::state::prelude::*;
+use crate::pod::{TerminationReason, DISRUPTION_TARGET};
+use chrono::Utc;
+use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use tracing::{debug, info, warn};
 
-/// Pod was deleted.
+/// The grace period for pods deleted without one whose spec doesn't set one either, which is the
+/// Kubernetes default.
+const DEFAULT_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long a pod that was killed after its grace period ran out is waited for.
+const KILL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Pod was deleted. The provider is told why with
+/// [`GenericPodState::set_termination_reason`] before the pod is stopped. Pods that don't finish
+/// within their grace period are then killed with [`GenericProviderState::kill`].
 pub struct Terminated<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
 }
@@ -27,20 +41,186 @@ impl<P: GenericProvider> State<P::PodState> for Terminated<P> {
     async fn next(
         self: Box<Self>,
         provider_state: SharedState<P::ProviderState>,
-        _pod_state: &mut P::PodState,
+        pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
         let pod = pod.latest();
+        let reason = TerminationReason::from_pod(&pod);
+        info!(%reason, "Terminating pod");
+        pod_state.set_termination_reason(reason).await;
 
         let state_reader = provider_state.read().await;
+        if let Some(startup_limiter) = state_reader.startup_limiter() {
+            startup_limiter.finish(&pod);
+        }
         // TODO: In original code, pod key was stored in state rather than
         // re-derived.  Is this important e.g. could pod mutate in ways
         // that invalidate the key assigned on startup?
-        let stop_result = state_reader.stop(&pod).await;
+        let mut stop_result = state_reader.stop(&pod).await;
+        if stop_result.is_ok() {
+            let grace_period = grace_period(&pod);
+            match tokio::time::timeout(grace_period, state_reader.wait(&pod)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!(error = %e, "Pod exited with an error after being stopped"),
+                Err(_) => {
+                    warn!(
+                        grace_period = grace_period.as_secs(),
+                        "Pod did not finish within its termination grace period, killing it"
+                    );
+                    stop_result = state_reader.kill(&pod).await;
+                    if stop_result.is_ok()
+                        && tokio::time::timeout(KILL_TIMEOUT, state_reader.wait(&pod))
+                            .await
+                            .is_err()
+                    {
+                        warn!("Pod did not finish after it was killed");
+                    }
+                }
+            }
+        }
+        if let Some(resource_admission) = state_reader.resource_admission() {
+            resource_admission.release(&pod);
+        }
         Transition::Complete(stop_result)
     }
 
-    async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Succeeded, "Terminated"))
+    async fn status(&self, _pod_state: &mut P::PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(terminated_status(pod))
+    }
+}
+
+/// How long the pod is given to finish once it has been stopped: the grace period it was deleted
+/// or evicted with, or else the `terminationGracePeriodSeconds` of its spec.
+fn grace_period(pod: &Pod) -> std::time::Duration {
+    pod.deletion_grace_period()
+        .or_else(|| pod.termination_grace_period())
+        .unwrap_or(DEFAULT_GRACE_PERIOD)
+}
+
+/// The status of a deleted pod, with the reason it was terminated for. Pods that had already
+/// failed stay failed.
+///
+/// Pods terminated because of a disruption keep reporting the `DisruptionTarget` condition the
+/// API server, the scheduler or the Kubelet gave them (e.g. `EvictionByEvictionAPI` for a pod
+/// evicted when draining the node). Pods that were simply deleted don't get one.
+fn terminated_status(pod: &Pod) -> PodStatus {
+    let reason = TerminationReason::from_pod(pod);
+    let phase = match reason {
+        TerminationReason::Failure => Phase::Failed,
+        _ => Phase::Succeeded,
+    };
+    let builder = StatusBuilder::new()
+        .phase(phase)
+        .reason(reason.reason())
+        .message(reason.message());
+    match disruption_condition(pod) {
+        Some(condition) => builder.conditions(vec![condition]).build(),
+        None => builder.build(),
+    }
+}
+
+/// The `DisruptionTarget` condition of a pod that was given one, with a transition time.
+fn disruption_condition(pod: &Pod) -> Option<KubePodCondition> {
+    let existing = pod
+        .condition(DISRUPTION_TARGET)
+        .filter(|condition| condition.status == "True")?;
+    Some(KubePodCondition {
+        last_transition_time: existing
+            .last_transition_time
+            .clone()
+            .or_else(|| Some(Time(Utc::now()))),
+        ..existing.clone()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Pod as KubePod, PodSpec, PodStatus as KubePodStatus};
+    use krator::ObjectStatus;
+
+    fn conditions(status: &PodStatus) -> serde_json::Value {
+        status.json_patch()["status"]["conditions"].clone()
+    }
+
+    #[test]
+    fn test_terminated_status_keeps_eviction_reason() {
+        let pod = Pod::from(KubePod {
+            status: Some(KubePodStatus {
+                conditions: Some(vec![KubePodCondition {
+                    type_: DISRUPTION_TARGET.to_owned(),
+                    status: "True".to_owned(),
+                    reason: Some("EvictionByEvictionAPI".to_owned()),
+                    message: Some("Eviction API: evicting".to_owned()),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let conditions = conditions(&terminated_status(&pod));
+        assert_eq!("DisruptionTarget", conditions[0]["type"]);
+        assert_eq!("EvictionByEvictionAPI", conditions[0]["reason"]);
+        assert_eq!("Eviction API: evicting", conditions[0]["message"]);
+    }
+
+    #[test]
+    fn test_terminated_status_reason() {
+        let pod = Pod::from(KubePod {
+            status: Some(KubePodStatus {
+                phase: Some("Failed".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let status = terminated_status(&pod).json_patch();
+        assert_eq!("Failed", status["status"]["phase"]);
+        assert_eq!("Failed", status["status"]["reason"]);
+
+        let status = terminated_status(&Pod::from(KubePod::default())).json_patch();
+        assert_eq!("Succeeded", status["status"]["phase"]);
+        assert_eq!("Terminated", status["status"]["reason"]);
+    }
+
+    #[test]
+    fn test_deleted_pod_is_not_a_disruption_target() {
+        let status = terminated_status(&Pod::from(KubePod::default()));
+        assert!(conditions(&status)
+            .as_array()
+            .map(|conditions| conditions.is_empty())
+            .unwrap_or(true));
+    }
+
+    #[test]
+    fn test_grace_period() {
+        assert_eq!(
+            DEFAULT_GRACE_PERIOD,
+            grace_period(&Pod::from(KubePod::default()))
+        );
+
+        let mut kube_pod = KubePod {
+            spec: Some(PodSpec {
+                termination_grace_period_seconds: Some(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            std::time::Duration::from_secs(60),
+            grace_period(&Pod::from(kube_pod.clone()))
+        );
+
+        kube_pod.metadata.deletion_grace_period_seconds = Some(5);
+        assert_eq!(
+            std::time::Duration::from_secs(5),
+            grace_period(&Pod::from(kube_pod))
+        );
+    }
+
+    #[test]
+    fn test_terminated_status_without_eviction() {
+        let conditions = conditions(&terminated_status(&Pod::from(KubePod::default())));
+        assert_eq!("True", conditions[0]["status"]);
+        assert_eq!("TerminationByKubelet", conditions[0]["reason"]);
     }
 }
@@ -1,16 +1,31 @@
-//! Kubelet is pulling container images.
+//! Kubelet is mounting the pod's volumes.
 
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
+use super::image_pull::{ImagePull, ModulePrefetch};
 use super::{GenericPodState, GenericProvider, GenericProviderState};
 use crate::pod::state::prelude::*;
+use crate::pod::{HostsFile, PodKubeconfig};
 use crate::provider::{PluginSupport, VolumeSupport};
+use crate::reason::Failure;
 use crate::state::common::error::Error;
-use crate::volume::VolumeRef;
+use crate::volume::{VolumeOwnership, VolumeRef};
 
-/// Kubelet is pulling container images.
+/// Kubelet is mounting the pod's volumes. Modules that started pulling when the pod was
+/// registered keep pulling meanwhile, and are waited for in the [`ImagePull`] state.
 pub struct VolumeMount<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    prefetch: Option<ModulePrefetch>,
+}
+
+impl<P: GenericProvider> VolumeMount<P> {
+    /// Mount the volumes while the pod's modules are pulled by `prefetch`.
+    pub(crate) fn with_prefetch(prefetch: Option<ModulePrefetch>) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            prefetch,
+        }
+    }
 }
 
 impl<P: GenericProvider> std::fmt::Debug for VolumeMount<P> {
@@ -23,6 +38,7 @@ impl<P: GenericProvider> Default for VolumeMount<P> {
     fn default() -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            prefetch: None,
         }
     }
 }
@@ -35,7 +51,7 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
         fields(pod_name)
     )]
     async fn next(
-        self: Box<Self>,
+        mut self: Box<Self>,
         provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
@@ -44,28 +60,46 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
-        let (client, volume_path, plugin_registry) = {
+        let (client, volume_path, plugin_registry, volume_plugins_dir, api_server_url) = {
             let state_reader = provider_state.read().await;
             let vol_path = match state_reader.volume_path() {
                 Some(p) => p.to_owned(),
                 None => {
                     info!("No volume directory found for pod. Assuming no volume support");
-                    return Transition::next_unchecked(self, P::RunState::default());
+                    let next = ImagePull::<P>::with_prefetch(self.prefetch.take());
+                    return Transition::next(self, next);
                 }
             };
             (
                 state_reader.client(),
                 vol_path,
                 state_reader.plugin_registry(),
+                state_reader.volume_plugins_dir().map(|p| p.to_owned()),
+                state_reader.api_server_url().map(|u| u.to_owned()),
             )
         };
 
         // Get the map of VolumeRefs
-        let mut volumes = match VolumeRef::volumes_from_pod(&pod, &client, plugin_registry).await {
+        let mut volumes = match VolumeRef::volumes_from_pod(
+            &pod,
+            &client,
+            plugin_registry,
+            volume_plugins_dir.as_deref(),
+        )
+        .await
+        {
             Ok(v) => v,
             Err(e) => {
                 error!(error = %e);
-                let next = Error::<P>::new(e.to_string());
+                let next = Error::<P>::with_failure(Failure::ContainerConfig, e.to_string());
+                return Transition::next(self, next);
+            }
+        };
+        let ownership = match VolumeOwnership::for_pod(&pod) {
+            Ok(ownership) => ownership,
+            Err(e) => {
+                error!(error = %e);
+                let next = Error::<P>::with_failure(Failure::ContainerConfig, e.to_string());
                 return Transition::next(self, next);
             }
         };
@@ -75,9 +109,11 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
             .iter_mut()
             .map(|(k, v)| (k, v, base_path.clone()))
             .map(|(k, v, p)| async move {
-                v.mount(p)
-                    .await
-                    .map_err(|e| anyhow::anyhow!("Unable to mount volume {}: {}", k, e))
+                let mut mounted = v.mount(p).await;
+                if let (Ok(()), Some(ownership)) = (&mounted, ownership) {
+                    mounted = v.apply_ownership(&ownership).await;
+                }
+                mounted.map_err(|e| anyhow::anyhow!("Unable to mount volume {}: {}", k, e))
             });
         if let Err(e) = futures::future::join_all(mounts)
             .await
@@ -85,11 +121,31 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
             .collect::<anyhow::Result<()>>()
         {
             error!(error = %e);
-            let next = Error::<P>::new(e.to_string());
+            let next = Error::<P>::with_failure(Failure::ContainerConfig, e.to_string());
             return Transition::next(self, next);
         }
         pod_state.set_volumes(volumes).await;
-        Transition::next_unchecked(self, P::RunState::default())
+        // Written alongside the volumes, so that providers can supply it like one
+        match HostsFile::write(&pod, &base_path).await {
+            Ok(hosts_file) => pod_state.set_hosts_file(hosts_file).await,
+            // Workloads can still run without it, they just can't resolve the host aliases
+            Err(e) => warn!(error = %e, "Unable to write hosts file for pod"),
+        }
+        // Unlike the hosts file, the pod asked for it, so it can't run without it
+        match PodKubeconfig::write(&pod, &base_path, api_server_url.as_deref()).await {
+            Ok(Some(kubeconfig)) => pod_state.set_kubeconfig(kubeconfig).await,
+            Ok(None) => (),
+            Err(e) => {
+                error!(error = %e, "Unable to write kubeconfig for pod");
+                let next = Error::<P>::with_failure(
+                    Failure::ContainerConfig,
+                    format!("Unable to write kubeconfig: {}", e),
+                );
+                return Transition::next(self, next);
+            }
+        }
+        let next = ImagePull::<P>::with_prefetch(self.prefetch.take());
+        Transition::next(self, next)
     }
 
     async fn status(&self, _pod_state: &mut P::PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
@@ -98,6 +154,7 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for VolumeMount<P> {}
+impl<P: GenericProvider> TransitionTo<ImagePull<P>> for VolumeMount<P> {}
 
 fn pod_dir_name(pod: &Pod) -> String {
     format!("{}-{}", pod.name(), pod.namespace())
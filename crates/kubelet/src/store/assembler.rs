@@ -0,0 +1,119 @@
+//! `assembler` composes the layers of a pulled image into the module that is stored for it.
+use oci_distribution::client::ImageLayer;
+use oci_distribution::manifest::WASM_LAYER_MEDIA_TYPE;
+use oci_distribution::Reference;
+
+/// Composes the layers of an image, in the order of its manifest and already decompressed,
+/// into a module.
+///
+/// [`LocalStore`](crate::store::LocalStore)s assemble every image they pull before storing it,
+/// so providers whose modules are made up of several layers, or are carried next to other
+/// artifacts in the same image, can decide how their modules are put together. Any
+/// `Fn(&Reference, Vec<ImageLayer>) -> anyhow::Result<Vec<u8>>` is an assembler.
+pub trait Assembler {
+    /// Compose the image's layers into its module.
+    fn assemble(&self, image_ref: &Reference, layers: Vec<ImageLayer>) -> anyhow::Result<Vec<u8>>;
+}
+
+impl<F> Assembler for F
+where
+    F: Fn(&Reference, Vec<ImageLayer>) -> anyhow::Result<Vec<u8>>,
+{
+    fn assemble(&self, image_ref: &Reference, layers: Vec<ImageLayer>) -> anyhow::Result<Vec<u8>> {
+        self(image_ref, layers)
+    }
+}
+
+/// An [`Assembler`] that takes the one layer of an image with a given media type as its module,
+/// ignoring the image's other layers. By default it picks the WebAssembly layer.
+#[derive(Clone, Debug)]
+pub struct MediaTypeAssembler {
+    media_type: String,
+}
+
+impl MediaTypeAssembler {
+    /// Create an assembler that picks the layer with the given media type.
+    pub fn new(media_type: impl Into<String>) -> Self {
+        MediaTypeAssembler {
+            media_type: media_type.into(),
+        }
+    }
+}
+
+impl Default for MediaTypeAssembler {
+    fn default() -> Self {
+        Self::new(WASM_LAYER_MEDIA_TYPE)
+    }
+}
+
+impl Assembler for MediaTypeAssembler {
+    fn assemble(&self, image_ref: &Reference, layers: Vec<ImageLayer>) -> anyhow::Result<Vec<u8>> {
+        let mut matching = layers
+            .into_iter()
+            .filter(|layer| layer.media_type == self.media_type);
+        match (matching.next(), matching.next()) {
+            (Some(layer), None) => Ok(layer.data),
+            (None, _) => Err(anyhow::anyhow!(
+                "Image {} has no layer of media type {}",
+                image_ref,
+                self.media_type
+            )),
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "Image {} has more than one layer of media type {}",
+                image_ref,
+                self.media_type
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const DATA_MEDIA_TYPE: &str = "application/vnd.example.data.v1";
+
+    fn layers() -> Vec<ImageLayer> {
+        vec![
+            ImageLayer::oci_v1(vec![1]),
+            ImageLayer::new(vec![2, 3], WASM_LAYER_MEDIA_TYPE.to_owned()),
+            ImageLayer::new(vec![4], DATA_MEDIA_TYPE.to_owned()),
+        ]
+    }
+
+    #[test]
+    fn test_media_type_assembler_picks_layer() {
+        let image_ref = Reference::try_from("foo/bar:1.0").unwrap();
+        let assembler = MediaTypeAssembler::default();
+        assert_eq!(
+            vec![2, 3],
+            assembler.assemble(&image_ref, layers()).unwrap()
+        );
+        assert_eq!(
+            vec![4],
+            MediaTypeAssembler::new(DATA_MEDIA_TYPE)
+                .assemble(&image_ref, layers())
+                .unwrap()
+        );
+
+        assert!(assembler
+            .assemble(&image_ref, vec![ImageLayer::oci_v1(vec![1])])
+            .is_err());
+        let mut twice = layers();
+        twice.push(ImageLayer::new(vec![5], WASM_LAYER_MEDIA_TYPE.to_owned()));
+        assert!(assembler.assemble(&image_ref, twice).is_err());
+    }
+
+    #[test]
+    fn test_closure_assembler() {
+        let image_ref = Reference::try_from("foo/bar:1.0").unwrap();
+        let concatenate = |_: &Reference, layers: Vec<ImageLayer>| -> anyhow::Result<Vec<u8>> {
+            Ok(layers.into_iter().flat_map(|layer| layer.data).collect())
+        };
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            concatenate.assemble(&image_ref, layers()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use oci_distribution::manifest::Platform;
+use oci_distribution::Reference;
+use tracing::{debug, warn};
+
+use crate::secret::RegistryCredentials;
+use crate::store::metrics::store_metrics;
+use crate::store::PullPolicy;
+use crate::store::Store;
+
+/// A `Store` that modules can also be written to, so that a `ChainedStore` can populate it with
+/// modules that were got from a later layer. Every [`LocalStore`](crate::store::LocalStore),
+/// such as the node's [`FileStore`](crate::store::oci::FileStore) module cache, is writable.
+#[async_trait]
+pub trait WritableStore: Store {
+    /// Save a module's data under its image `Reference`.
+    async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()>;
+}
+
+enum LayerStore {
+    ReadOnly(Arc<dyn Store + Send + Sync>),
+    Writable(Arc<dyn WritableStore + Send + Sync>),
+}
+
+struct Layer {
+    name: String,
+    priority: u32,
+    store: LayerStore,
+}
+
+impl Layer {
+    fn store(&self) -> &(dyn Store + Send + Sync) {
+        match &self.store {
+            LayerStore::ReadOnly(store) => store.as_ref(),
+            LayerStore::Writable(store) => store.as_ref(),
+        }
+    }
+}
+
+/// A `Store` that tries a chain of stores in turn, such as a directory of modules shipped with
+/// the node, then the node's module cache, then the remote registries. Layers with a lower
+/// priority are tried first, and layers with the same priority in the order they were added.
+///
+/// A module is got from the first layer that has it, and is then written back to the writable
+/// layers before that one, so that the next lookup stops earlier in the chain. Failing to write
+/// a module back only logs a warning. Each layer gets the pull policy of the request, so a
+/// layer that can't satisfy it falls through to the next one.
+///
+/// Lookups of the same reference are made one at a time, so that concurrent pods using the same
+/// module fetch it from a slow layer once and then find it in the layers it was written back to.
+/// Lookups of different references run in parallel.
+///
+/// The hits and misses of each layer, and the modules written back to it, are counted in the
+/// [store metrics](crate::store::metrics::StoreMetrics) under the layer's name.
+#[derive(Default)]
+pub struct ChainedStore {
+    layers: Vec<Layer>,
+    in_flight: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl ChainedStore {
+    /// Create a chain with no layers. A chain with no layers fails every lookup.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a layer that modules are only read from.
+    pub fn with_layer(
+        self,
+        name: &str,
+        priority: u32,
+        store: Arc<dyn Store + Send + Sync>,
+    ) -> Self {
+        self.add_layer(name, priority, LayerStore::ReadOnly(store))
+    }
+
+    /// Add a layer that modules found in later layers are written back to.
+    pub fn with_writable_layer(
+        self,
+        name: &str,
+        priority: u32,
+        store: Arc<dyn WritableStore + Send + Sync>,
+    ) -> Self {
+        self.add_layer(name, priority, LayerStore::Writable(store))
+    }
+
+    fn add_layer(mut self, name: &str, priority: u32, store: LayerStore) -> Self {
+        self.layers.push(Layer {
+            name: name.to_owned(),
+            priority,
+            store,
+        });
+        // A stable sort keeps layers with the same priority in the order they were added
+        self.layers.sort_by_key(|layer| layer.priority);
+        self
+    }
+
+    fn reference_lock(&self, image_ref: &Reference) -> Arc<tokio::sync::Mutex<()>> {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .entry(image_ref.whole())
+            .or_default()
+            .clone()
+    }
+
+    fn release_reference_lock(&self, image_ref: &Reference, lock: Arc<tokio::sync::Mutex<()>>) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Only the map and this lookup hold the lock, so no other lookup is waiting on it
+        if Arc::strong_count(&lock) == 2 {
+            in_flight.remove(&image_ref.whole());
+        }
+    }
+
+    async fn get_from_layers(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut errors = vec![];
+        for (index, layer) in self.layers.iter().enumerate() {
+            match layer.store().get(image_ref, pull_policy, auth).await {
+                Ok(module) => {
+                    store_metrics().record_layer_hit(&layer.name);
+                    debug!(%image_ref, layer = %layer.name, "Got module from store layer");
+                    self.write_back(image_ref, &module, &self.layers[..index])
+                        .await;
+                    return Ok(module);
+                }
+                Err(e) => {
+                    store_metrics().record_layer_miss(&layer.name);
+                    debug!(%image_ref, layer = %layer.name, error = %e, "Store layer did not have module");
+                    errors.push(format!("{}: {}", layer.name, e));
+                }
+            }
+        }
+        Err(no_layer_error(image_ref, errors))
+    }
+
+    async fn write_back(&self, image_ref: &Reference, module: &[u8], layers: &[Layer]) {
+        for layer in layers {
+            if let LayerStore::Writable(store) = &layer.store {
+                match store.put(image_ref, module).await {
+                    Ok(()) => store_metrics().record_layer_write_back(&layer.name),
+                    Err(e) => warn!(
+                        %image_ref,
+                        layer = %layer.name,
+                        error = %e,
+                        "Unable to write module back to store layer"
+                    ),
+                }
+            }
+        }
+    }
+}
+
+fn no_layer_error(image_ref: &Reference, errors: Vec<String>) -> anyhow::Error {
+    if errors.is_empty() {
+        anyhow::anyhow!("Module {} not found: the store has no layers", image_ref)
+    } else {
+        anyhow::anyhow!(
+            "Module {} not found in any store layer: {}",
+            image_ref,
+            errors.join("; ")
+        )
+    }
+}
+
+#[async_trait]
+impl Store for ChainedStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let lock = self.reference_lock(image_ref);
+        let result = {
+            let _guard = lock.lock().await;
+            self.get_from_layers(image_ref, pull_policy, auth).await
+        };
+        self.release_reference_lock(image_ref, lock);
+        result
+    }
+
+    async fn resolve(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        let mut errors = vec![];
+        for layer in &self.layers {
+            match layer.store().resolve(image_ref, pull_policy, auth).await {
+                Ok(()) => return Ok(()),
+                Err(e) => errors.push(format!("{}: {}", layer.name, e)),
+            }
+        }
+        Err(no_layer_error(image_ref, errors))
+    }
+
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        for layer in &self.layers {
+            if layer.store().is_present(image_ref).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn platform_reference(
+        &self,
+        image_ref: &Reference,
+        platform: &Platform,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Option<Reference>> {
+        // Layers that don't know the image's manifests return `None`, so the first layer that
+        // does, such as the one pulling from the registries, decides
+        let mut errors = vec![];
+        for layer in &self.layers {
+            match layer
+                .store()
+                .platform_reference(image_ref, platform, auth)
+                .await
+            {
+                Ok(Some(reference)) => return Ok(Some(reference)),
+                Ok(None) => (),
+                Err(e) => errors.push(format!("{}: {}", layer.name, e)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(None)
+        } else {
+            Err(anyhow::anyhow!(
+                "Unable to find the manifest of {} for platform {}: {}",
+                image_ref,
+                platform,
+                errors.join("; ")
+            ))
+        }
+    }
+
+    async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        // Layers keep files for modules they don't have yet, so only report a file that exists
+        for layer in &self.layers {
+            if let Some(path) = layer.store().module_path(image_ref).await {
+                if tokio::fs::metadata(&path).await.is_ok() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct FakeLayer {
+        modules: Mutex<HashMap<String, Vec<u8>>>,
+        gets: AtomicUsize,
+    }
+
+    impl FakeLayer {
+        fn with_module(image_ref: &str, module: &[u8]) -> Self {
+            let layer = FakeLayer::default();
+            layer
+                .modules
+                .lock()
+                .unwrap()
+                .insert(image_ref.to_owned(), module.to_vec());
+            layer
+        }
+
+        fn has(&self, image_ref: &str) -> bool {
+            self.modules.lock().unwrap().contains_key(image_ref)
+        }
+    }
+
+    #[async_trait]
+    impl Store for FakeLayer {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryCredentials,
+        ) -> anyhow::Result<Vec<u8>> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            // Give concurrent lookups the chance to overlap
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.modules
+                .lock()
+                .unwrap()
+                .get(&image_ref.whole())
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no module"))
+        }
+    }
+
+    #[async_trait]
+    impl WritableStore for FakeLayer {
+        async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()> {
+            self.modules
+                .lock()
+                .unwrap()
+                .insert(image_ref.whole(), module.to_vec());
+            Ok(())
+        }
+    }
+
+    fn reference() -> Reference {
+        Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap()
+    }
+
+    #[tokio::test]
+    async fn chained_store_falls_through_and_writes_back() {
+        let image_ref = reference();
+        let offline = Arc::new(FakeLayer::default());
+        let cache = Arc::new(FakeLayer::default());
+        let registry = Arc::new(FakeLayer::with_module(&image_ref.whole(), &[1, 2, 3]));
+        // Added out of order, the priorities decide the order the layers are tried in
+        let store = ChainedStore::new()
+            .with_layer("registry", 20, registry.clone())
+            .with_layer("offline", 0, offline.clone())
+            .with_writable_layer("cache", 10, cache.clone());
+
+        let module = store
+            .get(
+                &image_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], module);
+        assert!(cache.has(&image_ref.whole()));
+        assert!(!offline.has(&image_ref.whole()));
+
+        store
+            .get(
+                &image_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, offline.gets.load(Ordering::SeqCst));
+        assert_eq!(2, cache.gets.load(Ordering::SeqCst));
+        assert_eq!(1, registry.gets.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn chained_store_gets_a_reference_once_at_a_time() {
+        let image_ref = reference();
+        let cache = Arc::new(FakeLayer::default());
+        let registry = Arc::new(FakeLayer::with_module(&image_ref.whole(), &[1]));
+        let store = Arc::new(
+            ChainedStore::new()
+                .with_writable_layer("cache", 0, cache.clone())
+                .with_layer("registry", 1, registry.clone()),
+        );
+
+        let gets = (0..4).map(|_| {
+            let store = store.clone();
+            let image_ref = image_ref.clone();
+            async move {
+                store
+                    .get(
+                        &image_ref,
+                        PullPolicy::IfNotPresent,
+                        &RegistryCredentials::anonymous(),
+                    )
+                    .await
+            }
+        });
+        for result in futures::future::join_all(gets).await {
+            assert_eq!(vec![1], result.unwrap());
+        }
+        assert_eq!(1, registry.gets.load(Ordering::SeqCst));
+        assert!(store.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chained_store_reports_every_layer_when_no_layer_has_the_module() {
+        let store = ChainedStore::new()
+            .with_layer("offline", 0, Arc::new(FakeLayer::default()))
+            .with_layer("registry", 1, Arc::new(FakeLayer::default()));
+        let message = store
+            .get(
+                &reference(),
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("offline: no module"));
+        assert!(message.contains("registry: no module"));
+    }
+}
@@ -1,10 +1,16 @@
 //! `composite` implements building complex stores from simpler ones.
 
+mod chained;
+
+pub use chained::{ChainedStore, WritableStore};
+
+use crate::secret::RegistryCredentials;
 use crate::store::PullPolicy;
 use crate::store::Store;
 use async_trait::async_trait;
-use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::manifest::Platform;
 use oci_distribution::Reference;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 /// A `Store` that has additional logic to determine if it can satisfy
@@ -65,7 +71,7 @@ impl Store for CompositeStore {
         &self,
         image_ref: &Reference,
         pull_policy: PullPolicy,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<Vec<u8>> {
         if self.interceptor.intercepts(image_ref) {
             self.interceptor.get(image_ref, pull_policy, auth).await
@@ -73,12 +79,58 @@ impl Store for CompositeStore {
             self.base.get(image_ref, pull_policy, auth).await
         }
     }
+
+    async fn resolve(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.resolve(image_ref, pull_policy, auth).await
+        } else {
+            self.base.resolve(image_ref, pull_policy, auth).await
+        }
+    }
+
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.is_present(image_ref).await
+        } else {
+            self.base.is_present(image_ref).await
+        }
+    }
+
+    async fn platform_reference(
+        &self,
+        image_ref: &Reference,
+        platform: &Platform,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Option<Reference>> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor
+                .platform_reference(image_ref, platform, auth)
+                .await
+        } else {
+            self.base
+                .platform_reference(image_ref, platform, auth)
+                .await
+        }
+    }
+
+    async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.module_path(image_ref).await
+        } else {
+            self.base.module_path(image_ref).await
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use oci_distribution::secrets::RegistryAuth;
+    use crate::secret::RegistryCredentials;
     use std::convert::TryFrom;
 
     struct FakeBase {}
@@ -90,7 +142,7 @@ mod test {
             &self,
             _image_ref: &Reference,
             _pull_policy: PullPolicy,
-            _auth: &RegistryAuth,
+            _auth: &RegistryCredentials,
         ) -> anyhow::Result<Vec<u8>> {
             Ok(vec![11, 10, 5, 14])
         }
@@ -102,10 +154,19 @@ mod test {
             &self,
             _image_ref: &Reference,
             _pull_policy: PullPolicy,
-            _auth: &RegistryAuth,
+            _auth: &RegistryCredentials,
         ) -> anyhow::Result<Vec<u8>> {
             Ok(vec![1, 2, 3])
         }
+
+        async fn resolve(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryCredentials,
+        ) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("intercepted {}", image_ref))
+        }
     }
 
     impl InterceptingStore for FakeInterceptor {
@@ -121,7 +182,7 @@ mod test {
             .get(
                 &Reference::try_from("int/foo").unwrap(),
                 PullPolicy::Never,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await
             .unwrap();
@@ -136,11 +197,32 @@ mod test {
             .get(
                 &Reference::try_from("mint/foo").unwrap(),
                 PullPolicy::Never,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await
             .unwrap();
         assert_eq!(4, result.len());
         assert_eq!(11, result[0]);
     }
+
+    #[tokio::test]
+    async fn composite_store_resolves_with_the_store_that_would_get() {
+        let store = Arc::new(FakeBase {}).with_override(Arc::new(FakeInterceptor {}));
+        assert!(store
+            .resolve(
+                &Reference::try_from("int/foo").unwrap(),
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .is_err());
+        assert!(store
+            .resolve(
+                &Reference::try_from("mint/foo").unwrap(),
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .is_ok());
+    }
 }
@@ -1,9 +1,9 @@
 //! `fs` implements fetching modules from the local file system.
 
+use crate::secret::RegistryCredentials;
 use crate::store::composite::InterceptingStore;
 use crate::store::{PullPolicy, Store};
 use async_trait::async_trait;
-use oci_distribution::secrets::RegistryAuth;
 use oci_distribution::Reference;
 use std::path::PathBuf;
 
@@ -24,11 +24,31 @@ impl Store for FileSystemStore {
         &self,
         image_ref: &Reference,
         _pull_policy: PullPolicy,
-        _auth: &RegistryAuth,
+        _auth: &RegistryCredentials,
     ) -> anyhow::Result<Vec<u8>> {
         let path = PathBuf::from(image_ref.repository());
         Ok(tokio::fs::read(&path).await?)
     }
+
+    async fn resolve(
+        &self,
+        image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        let path = PathBuf::from(image_ref.repository());
+        tokio::fs::metadata(&path).await?;
+        Ok(())
+    }
+
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        let path = PathBuf::from(image_ref.repository());
+        tokio::fs::metadata(&path).await.is_ok()
+    }
+
+    async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        Some(PathBuf::from(image_ref.repository()))
+    }
 }
 
 impl InterceptingStore for FileSystemStore {
@@ -0,0 +1,165 @@
+//! An in-memory cache of small modules, so that pods which restart often (e.g. crash looping or
+//! frequently scaled ones) don't read their modules from disk every time they start.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use oci_distribution::Reference;
+
+use crate::store::metrics::store_metrics;
+
+/// The largest module that is kept in memory. Larger modules are read from disk each time, as
+/// reading them costs little next to compiling them.
+pub const MAX_CACHED_MODULE_SIZE: usize = 1024 * 1024;
+
+/// A least recently used cache of module bytes, keyed by image reference, that holds no more
+/// than a fixed number of bytes.
+#[derive(Debug)]
+pub struct ModuleCache {
+    budget: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<Reference, CachedModule>,
+    used: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CachedModule {
+    module: Vec<u8>,
+    digest: Option<String>,
+    last_used: u64,
+}
+
+impl ModuleCache {
+    /// Create a cache that holds up to `budget` bytes of modules.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Get a module from the cache. If a digest is given, the module is only returned if it was
+    /// cached with that digest, so that modules updated in their registry are not served stale.
+    pub fn get(&self, image_ref: &Reference, digest: Option<&str>) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let module = match state.entries.get_mut(image_ref) {
+            Some(entry) if digest.is_none() || entry.digest.as_deref() == digest => {
+                entry.last_used = clock;
+                Some(entry.module.clone())
+            }
+            _ => None,
+        };
+        match module {
+            Some(_) => store_metrics().record_memory_cache_hit(),
+            None => store_metrics().record_memory_cache_miss(),
+        }
+        module
+    }
+
+    /// Add a module to the cache, evicting the least recently used modules to make room for it.
+    /// Modules larger than [`MAX_CACHED_MODULE_SIZE`] or the cache's budget are not cached.
+    pub fn insert(&self, image_ref: &Reference, module: &[u8], digest: Option<String>) {
+        if module.len() > MAX_CACHED_MODULE_SIZE || module.len() > self.budget {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(image_ref);
+        while state.used + module.len() > self.budget {
+            let oldest = match state.entries.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((oldest, _)) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+        }
+        state.clock += 1;
+        let last_used = state.clock;
+        state.used += module.len();
+        state.entries.insert(
+            image_ref.clone(),
+            CachedModule {
+                module: module.to_vec(),
+                digest,
+                last_used,
+            },
+        );
+    }
+
+    /// Remove a module from the cache, e.g. because a newer version of it has been stored.
+    pub fn remove(&self, image_ref: &Reference) {
+        self.state.lock().unwrap().remove(image_ref);
+    }
+
+    /// The number of bytes of modules in the cache.
+    pub fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, image_ref: &Reference) {
+        if let Some(entry) = self.entries.remove(image_ref) {
+            self.used -= entry.module.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn reference(name: &str) -> Reference {
+        Reference::try_from(name).expect("reference should parse")
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = ModuleCache::new(10);
+        cache.insert(&reference("foo/a:1.0"), &[1; 4], None);
+        cache.insert(&reference("foo/b:1.0"), &[2; 4], None);
+        assert!(cache.get(&reference("foo/a:1.0"), None).is_some());
+        cache.insert(&reference("foo/c:1.0"), &[3; 4], None);
+
+        assert_eq!(Some(vec![1; 4]), cache.get(&reference("foo/a:1.0"), None));
+        assert_eq!(None, cache.get(&reference("foo/b:1.0"), None));
+        assert_eq!(Some(vec![3; 4]), cache.get(&reference("foo/c:1.0"), None));
+        assert_eq!(8, cache.used());
+    }
+
+    #[test]
+    fn test_skips_modules_over_budget() {
+        let cache = ModuleCache::new(10);
+        cache.insert(&reference("foo/a:1.0"), &[1; 11], None);
+        assert_eq!(None, cache.get(&reference("foo/a:1.0"), None));
+
+        let cache = ModuleCache::new(4 * MAX_CACHED_MODULE_SIZE);
+        cache.insert(
+            &reference("foo/a:1.0"),
+            &vec![1; MAX_CACHED_MODULE_SIZE + 1],
+            None,
+        );
+        assert_eq!(0, cache.used());
+    }
+
+    #[test]
+    fn test_checks_digest() {
+        let cache = ModuleCache::new(10);
+        let image_ref = reference("foo/a:1.0");
+        cache.insert(&image_ref, &[1, 2], Some("sha256:12".to_owned()));
+        assert!(cache.get(&image_ref, Some("sha256:12")).is_some());
+        assert!(cache.get(&image_ref, Some("sha256:34")).is_none());
+        assert!(cache.get(&image_ref, None).is_some());
+
+        cache.insert(&image_ref, &[3, 4, 5], None);
+        assert!(cache.get(&image_ref, Some("sha256:12")).is_none());
+        assert_eq!(3, cache.used());
+        cache.remove(&image_ref);
+        assert_eq!(0, cache.used());
+    }
+}
@@ -0,0 +1,260 @@
+//! Counters describing how the module store is used, so that operators can size caches and
+//! registry mirrors.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::metrics::write_metric;
+
+lazy_static::lazy_static! {
+    static ref STORE_METRICS: StoreMetrics = StoreMetrics::default();
+}
+
+/// The metrics of the module stores of this process.
+pub fn store_metrics() -> &'static StoreMetrics {
+    &STORE_METRICS
+}
+
+/// Counters for module store lookups, image pulls and the layer cache.
+#[derive(Debug, Default)]
+pub struct StoreMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    memory_cache_hits: AtomicU64,
+    memory_cache_misses: AtomicU64,
+    layers_reused: AtomicU64,
+    bytes_reused: AtomicU64,
+    verification_failures: AtomicU64,
+    bytes_pulled: Mutex<BTreeMap<String, u64>>,
+    layer_hits: Mutex<BTreeMap<String, u64>>,
+    layer_misses: Mutex<BTreeMap<String, u64>>,
+    layer_write_backs: Mutex<BTreeMap<String, u64>>,
+}
+
+impl StoreMetrics {
+    /// Record a module that was served from the local store without pulling it.
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a module that had to be pulled from its registry.
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a lookup that failed, such as for a module that couldn't be pulled or that isn't
+    /// in the store with the `Never` pull policy.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a module that was served from the in-memory module cache.
+    pub fn record_memory_cache_hit(&self) {
+        self.memory_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a lookup that was not in the in-memory module cache, or was out of date there.
+    pub fn record_memory_cache_miss(&self) {
+        self.memory_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an image layer that was taken from the layer cache instead of being downloaded.
+    pub fn record_layer_reused(&self, bytes: usize) {
+        self.layers_reused.fetch_add(1, Ordering::Relaxed);
+        self.bytes_reused.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record bytes downloaded from the given registry.
+    pub fn record_bytes_pulled(&self, registry: &str, bytes: usize) {
+        increment(&self.bytes_pulled, registry, bytes as u64);
+    }
+
+    /// Record downloaded or cached data that did not match its digest.
+    pub fn record_verification_failure(&self) {
+        self.verification_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a module got from the given layer of a chained store.
+    pub fn record_layer_hit(&self, layer: &str) {
+        increment(&self.layer_hits, layer, 1);
+    }
+
+    /// Record a lookup that fell through the given layer of a chained store.
+    pub fn record_layer_miss(&self, layer: &str) {
+        increment(&self.layer_misses, layer, 1);
+    }
+
+    /// Record a module written back to the given layer of a chained store.
+    pub fn record_layer_write_back(&self, layer: &str) {
+        increment(&self.layer_write_backs, layer, 1);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_metric(
+            &mut out,
+            "krustlet_store_hits_total",
+            "counter",
+            "Modules served from the local store without pulling.",
+            self.hits.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_misses_total",
+            "counter",
+            "Modules pulled from their registry.",
+            self.misses.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_errors_total",
+            "counter",
+            "Module lookups that failed, such as for absent modules that may not be pulled.",
+            self.errors.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_memory_cache_hits_total",
+            "counter",
+            "Modules served from the in-memory module cache without reading them from disk.",
+            self.memory_cache_hits.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_memory_cache_misses_total",
+            "counter",
+            "Lookups that were not served from the in-memory module cache.",
+            self.memory_cache_misses.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_layers_reused_total",
+            "counter",
+            "Image layers taken from the layer cache instead of being downloaded.",
+            self.layers_reused.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_bytes_reused_total",
+            "counter",
+            "Bytes of image layers taken from the layer cache instead of being downloaded.",
+            self.bytes_reused.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "krustlet_store_verification_failures_total",
+            "counter",
+            "Image layers that did not match their digest.",
+            self.verification_failures.load(Ordering::Relaxed),
+        );
+        write_labelled_counter(
+            &mut out,
+            "krustlet_store_bytes_pulled_total",
+            "Bytes of image layers downloaded, by registry.",
+            "registry",
+            &self.bytes_pulled,
+        );
+        write_labelled_counter(
+            &mut out,
+            "krustlet_store_layer_hits_total",
+            "Modules got from each layer of a chained store.",
+            "layer",
+            &self.layer_hits,
+        );
+        write_labelled_counter(
+            &mut out,
+            "krustlet_store_layer_misses_total",
+            "Lookups that fell through each layer of a chained store.",
+            "layer",
+            &self.layer_misses,
+        );
+        write_labelled_counter(
+            &mut out,
+            "krustlet_store_layer_write_backs_total",
+            "Modules written back to each layer of a chained store.",
+            "layer",
+            &self.layer_write_backs,
+        );
+        out
+    }
+}
+
+fn increment(counters: &Mutex<BTreeMap<String, u64>>, label: &str, by: u64) {
+    *counters
+        .lock()
+        .unwrap()
+        .entry(label.to_owned())
+        .or_default() += by;
+}
+
+/// Write a counter with a value for each of its label's values.
+fn write_labelled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counters: &Mutex<BTreeMap<String, u64>>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (value, count) in counters.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            value.replace('\\', "\\\\").replace('"', "\\\""),
+            count
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = StoreMetrics::default();
+        metrics.record_hit();
+        metrics.record_hit();
+        metrics.record_miss();
+        metrics.record_error();
+        metrics.record_memory_cache_hit();
+        metrics.record_layer_reused(100);
+        metrics.record_bytes_pulled("webassembly.azurecr.io", 10);
+        metrics.record_bytes_pulled("webassembly.azurecr.io", 20);
+        metrics.record_bytes_pulled("ghcr.io", 5);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nkrustlet_store_hits_total 2\n"));
+        assert!(rendered.contains("\nkrustlet_store_misses_total 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_errors_total 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_memory_cache_hits_total 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_memory_cache_misses_total 0\n"));
+        assert!(rendered.contains("\nkrustlet_store_bytes_reused_total 100\n"));
+        assert!(rendered.contains("\nkrustlet_store_verification_failures_total 0\n"));
+        assert!(rendered.contains(
+            "\nkrustlet_store_bytes_pulled_total{registry=\"webassembly.azurecr.io\"} 30\n"
+        ));
+        assert!(rendered.contains("\nkrustlet_store_bytes_pulled_total{registry=\"ghcr.io\"} 5\n"));
+    }
+
+    #[test]
+    fn test_render_layers() {
+        let metrics = StoreMetrics::default();
+        metrics.record_layer_miss("cache");
+        metrics.record_layer_hit("registry");
+        metrics.record_layer_write_back("cache");
+        metrics.record_layer_hit("cache");
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nkrustlet_store_layer_hits_total{layer=\"cache\"} 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_layer_hits_total{layer=\"registry\"} 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_layer_misses_total{layer=\"cache\"} 1\n"));
+        assert!(rendered.contains("\nkrustlet_store_layer_write_backs_total{layer=\"cache\"} 1\n"));
+    }
+}
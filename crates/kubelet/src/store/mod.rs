@@ -1,23 +1,56 @@
 //! `store` contains logic around fetching and storing modules.
+pub mod assembler;
 pub mod composite;
 pub mod fs;
+pub mod memory;
+pub mod metrics;
 pub mod oci;
 
-use oci_distribution::client::ImageData;
-use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::client::{ImageData, ImageLayer};
+use oci_distribution::manifest::Platform;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
 use async_trait::async_trait;
 use oci_distribution::Reference;
-use tracing::{debug, instrument};
+use thiserror::Error;
+use tracing::{debug, info, instrument, warn};
 
-use crate::container::PullPolicy;
+use crate::container::{Container, PullPolicy};
 use crate::pod::Pod;
+use crate::secret::RegistryCredentials;
+use crate::store::assembler::Assembler;
+use crate::store::composite::WritableStore;
+use crate::store::memory::ModuleCache;
+use crate::store::metrics::store_metrics;
 use crate::store::oci::Client;
 
+/// A module in local storage no longer matches the digest it was stored under, for example
+/// because the disk was corrupted. The `Storer` removes such a module rather than return it, so
+/// that it is pulled again.
+#[derive(Error, Debug)]
+#[error("Stored module {image_ref} does not match its digest {digest}")]
+pub struct CorruptModuleError {
+    /// The image the module was stored for.
+    pub image_ref: String,
+    /// The digest of the module when it was stored.
+    pub digest: String,
+}
+
+/// A module that isn't in the store was asked for with the `Never` pull policy.
+#[derive(Error, Debug)]
+#[error("Module {image_ref} is not in the store and its pull policy is Never")]
+pub struct NeverPullError {
+    /// The image of the module.
+    pub image_ref: String,
+}
+
+/// The modules of a pod's containers by container name, or why each one couldn't be fetched.
+pub type ContainerModules = HashMap<String, anyhow::Result<Vec<u8>>>;
+
 /// A store of container modules.
 ///
 /// This provides the ability to get a module's bytes given an image [`Reference`].
@@ -26,8 +59,8 @@ use crate::store::oci::Client;
 ///  ```rust
 /// use async_trait::async_trait;
 /// use oci_distribution::Reference;
-/// use oci_distribution::secrets::RegistryAuth;
 /// use kubelet::container::PullPolicy;
+/// use kubelet::secret::RegistryCredentials;
 /// use kubelet::store::Store;
 /// use std::collections::HashMap;
 ///
@@ -37,7 +70,7 @@ use crate::store::oci::Client;
 ///
 /// #[async_trait]
 /// impl Store for InMemoryStore {
-///     async fn get(&self, image_ref: &Reference, pull_policy: PullPolicy, _auth: &RegistryAuth) -> anyhow::Result<Vec<u8>> {
+///     async fn get(&self, image_ref: &Reference, pull_policy: PullPolicy, _auth: &RegistryCredentials) -> anyhow::Result<Vec<u8>> {
 ///         match pull_policy {
 ///             PullPolicy::Never => (),
 ///             _ => todo!("Implement support for pull policies"),
@@ -56,42 +89,101 @@ pub trait Store: Sync {
         &self,
         image_ref: &Reference,
         pull_policy: PullPolicy,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<Vec<u8>>;
 
-    /// Fetch all container modules for a given `Pod` storing the name of the
-    /// container and the module's data as key/value pairs in a hashmap.
+    /// Check that a module could be got with the given pull policy, without getting it. This
+    /// is used to preview whether a pod would start, so it should not download modules.
+    ///
+    /// The default implementation accepts every reference.
+    async fn resolve(
+        &self,
+        _image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the store has the module for the given reference, so that getting it with the
+    /// `IfNotPresent` pull policy doesn't contact its registry. Fetching pod modules only
+    /// resolves registry credentials for modules that may be pulled.
+    ///
+    /// The default implementation reports every module as missing.
+    async fn is_present(&self, _image_ref: &Reference) -> bool {
+        false
+    }
+
+    /// The reference by digest of the module for `platform`, if the given reference is an
+    /// image index, so that pods that ask for a platform with the
+    /// [`PLATFORM_ANNOTATION`](crate::store::oci::PLATFORM_ANNOTATION) get the module for it.
+    /// This contacts the module's registry.
     ///
-    /// This will fetch all of the container modules in parallel.
+    /// The default implementation returns `None`, so that the reference is got as is.
+    async fn platform_reference(
+        &self,
+        _image_ref: &Reference,
+        _platform: &Platform,
+        _auth: &RegistryCredentials,
+    ) -> anyhow::Result<Option<Reference>> {
+        Ok(None)
+    }
+
+    /// Where the module for the given reference is stored on the node, if the store keeps it
+    /// in a file. This is reported in [attestations](crate::attestation) of the modules
+    /// running on the node.
     ///
-    /// # Panics
+    /// The default implementation does not know of any file.
+    async fn module_path(&self, _image_ref: &Reference) -> Option<PathBuf> {
+        None
+    }
+
+    /// Fetch all container modules for a given `Pod` storing the name of the
+    /// container and the module's data, or why it couldn't be fetched, as key/value
+    /// pairs in a hashmap.
     ///
-    /// This panics if any of the pod's containers do not have an image associated with them
+    /// This will fetch all of the container modules in parallel. A module that can't be
+    /// fetched doesn't stop the others from being fetched.
     #[instrument(level = "info", skip(self, pod, auth), fields(pod_name = pod.name()))]
     async fn fetch_pod_modules(
         &self,
         pod: &Pod,
         auth: &crate::secret::RegistryAuthResolver,
-    ) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    ) -> ContainerModules {
         debug!("Fetching all the container modules for pod");
-        // Fetch all of the container modules in parallel
-        let all_containers = pod.all_containers();
-        let container_module_futures = all_containers.iter().map(move |container| {
-            let reference = container
-                .image()
-                .expect("Could not parse image.")
-                .expect("FATAL ERROR: container must have an image");
-            let pull_policy = container
-                .effective_pull_policy()
-                .expect("Could not identify pull policy.");
-            async move {
-                let registry_authentication = auth.resolve_registry_auth(&reference).await?;
-                Ok((
-                    container.name().to_string(),
-                    self.get(&reference, pull_policy, &registry_authentication)
-                        .await?,
-                ))
-            }
+        self.fetch_container_modules(&pod.all_containers(), auth)
+            .await
+    }
+
+    /// Fetch the modules of the given containers in parallel, as for
+    /// [`fetch_pod_modules`](Store::fetch_pod_modules). This is used to fetch again only the
+    /// modules that failed to be fetched before.
+    async fn fetch_container_modules(
+        &self,
+        containers: &[Container],
+        auth: &crate::secret::RegistryAuthResolver,
+    ) -> ContainerModules {
+        let container_module_futures = containers.iter().map(move |container| async move {
+            let module = async {
+                let reference = container
+                    .image()?
+                    .ok_or_else(|| anyhow::anyhow!("container must have an image"))?;
+                let pull_policy = container.effective_pull_policy()?;
+                // Reading the pod's image pull secrets is skipped for modules that won't be
+                // pulled
+                let may_pull = match pull_policy {
+                    PullPolicy::Always => true,
+                    PullPolicy::IfNotPresent => !self.is_present(&reference).await,
+                    PullPolicy::Never => false,
+                };
+                let credentials = if may_pull {
+                    auth.resolve_credentials().await?
+                } else {
+                    RegistryCredentials::anonymous()
+                };
+                self.get(&reference, pull_policy, &credentials).await
+            };
+            (container.name().to_string(), module.await)
         });
 
         // Collect the container modules into a HashMap for quick lookup
@@ -107,57 +199,235 @@ pub trait Store: Sync {
 pub struct LocalStore<S: Storer, C: Client> {
     storer: Arc<RwLock<S>>,
     client: Arc<Mutex<C>>,
+    module_cache: Option<Arc<ModuleCache>>,
+    assembler: Arc<dyn Assembler + Send + Sync>,
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
+    /// Keep up to `budget` bytes of small modules in memory as well as in local storage, so
+    /// that getting them again doesn't read local storage. Pull policies are still applied.
+    pub fn with_module_cache(mut self, budget: usize) -> Self {
+        self.module_cache = Some(Arc::new(ModuleCache::new(budget)));
+        self
+    }
+
+    /// Compose the modules of pulled images with `assembler`, rather than taking the image's
+    /// WebAssembly layer as its module.
+    pub fn with_assembler(mut self, assembler: impl Assembler + Send + Sync + 'static) -> Self {
+        self.assembler = Arc::new(assembler);
+        self
+    }
+}
+
+impl<S: Storer + BlobCache + Sync + Send, C: Client> LocalStore<S, C> {
     #[instrument(level = "info", skip(self, auth))]
-    async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+    async fn pull(&self, image_ref: &Reference, auth: &RegistryCredentials) -> anyhow::Result<()> {
         debug!("Pulling image ref from registry");
-        let image_data = self.client.lock().await.pull(image_ref, auth).await?;
+        let image_data = {
+            let storer = self.storer.read().await;
+            self.client
+                .lock()
+                .await
+                .pull_cached(image_ref, auth, &*storer)
+                .await?
+        };
+        let module = self.assembler.assemble(image_ref, image_data.layers)?;
+        let image_data = ImageData {
+            layers: vec![ImageLayer::new(
+                module,
+                oci_distribution::manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            digest: image_data.digest,
+        };
         self.storer
             .write()
             .await
             .store(image_ref, image_data)
             .await?;
+        self.evict_cached(image_ref);
         Ok(())
     }
-}
 
-#[async_trait]
-impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C> {
-    async fn get(
+    /// Get a module, counting it as a hit or a miss if it is found. Failed lookups are counted
+    /// by the caller.
+    async fn get_module(
         &self,
         image_ref: &Reference,
         pull_policy: PullPolicy,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<Vec<u8>> {
-        match pull_policy {
-            PullPolicy::IfNotPresent => {
-                if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
-                }
-            }
-            PullPolicy::Always => {
-                let digest = self
-                    .client
+        // The `Always` pull policy needs the registry's digest of the module to tell whether
+        // the copy in memory or in local storage is up to date
+        let digest = match pull_policy {
+            PullPolicy::Always => Some(
+                self.client
                     .lock()
                     .await
                     .fetch_digest(image_ref, auth)
-                    .await?;
+                    .await?,
+            ),
+            PullPolicy::IfNotPresent | PullPolicy::Never => None,
+        };
+        if let Some(module) = self
+            .module_cache
+            .as_ref()
+            .and_then(|cache| cache.get(image_ref, digest.as_deref()))
+        {
+            store_metrics().record_hit();
+            info!(%image_ref, ?pull_policy, "Resolved module from memory");
+            return Ok(module);
+        }
+
+        // Only the `Always` pull policy has a digest
+        let pulled = match (pull_policy, &digest) {
+            (PullPolicy::Never, _) => {
+                if !self.storer.read().await.is_present(image_ref).await {
+                    return Err(NeverPullError {
+                        image_ref: image_ref.whole(),
+                    }
+                    .into());
+                }
+                false
+            }
+            (_, Some(digest)) => {
                 let already_got_with_digest = self
                     .storer
                     .read()
                     .await
-                    .is_present_with_digest(image_ref, digest)
+                    .is_present_with_digest(image_ref, digest.clone())
                     .await;
                 if !already_got_with_digest {
                     self.pull(image_ref, auth).await?
                 }
+                !already_got_with_digest
+            }
+            (_, None) => {
+                let present = self.storer.read().await.is_present(image_ref).await;
+                if !present {
+                    self.pull(image_ref, auth).await?
+                }
+                !present
+            }
+        };
+        info!(%image_ref, ?pull_policy, pulled, "Resolved module from store");
+
+        let local = self.storer.read().await.get_local(image_ref).await;
+        let local = match local {
+            Err(e) if e.is::<CorruptModuleError>() && pull_policy != PullPolicy::Never => {
+                warn!(error = %e, %image_ref, "Pulling corrupted module again");
+                match self.pull(image_ref, auth).await {
+                    Ok(()) => self.storer.read().await.get_local(image_ref).await,
+                    Err(e) => Err(e),
+                }
             }
-            PullPolicy::Never => (),
+            local => local,
         };
+        // Only lookups that end with the module count as hits or misses
+        let module = local?;
+        if pulled {
+            store_metrics().record_miss();
+        } else {
+            store_metrics().record_hit();
+        }
+        if let Some(cache) = &self.module_cache {
+            cache.insert(image_ref, &module, digest);
+        }
+        Ok(module)
+    }
+
+    fn evict_cached(&self, image_ref: &Reference) {
+        if let Some(cache) = &self.module_cache {
+            cache.remove(image_ref);
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storer + BlobCache + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C> {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let module = self.get_module(image_ref, pull_policy, auth).await;
+        if module.is_err() {
+            store_metrics().record_error();
+        }
+        module
+    }
+
+    async fn resolve(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        let present = self.storer.read().await.is_present(image_ref).await;
+        match pull_policy {
+            PullPolicy::Never if !present => Err(NeverPullError {
+                image_ref: image_ref.whole(),
+            }
+            .into()),
+            PullPolicy::Never | PullPolicy::IfNotPresent if present => Ok(()),
+            // Fetching the digest checks that the registry has the module and that the
+            // credentials are accepted, without downloading any layers
+            _ => {
+                self.client
+                    .lock()
+                    .await
+                    .fetch_digest(image_ref, auth)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
 
-        self.storer.read().await.get_local(image_ref).await
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        self.storer.read().await.is_present(image_ref).await
+    }
+
+    async fn platform_reference(
+        &self,
+        image_ref: &Reference,
+        platform: &Platform,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Option<Reference>> {
+        let digest = self
+            .client
+            .lock()
+            .await
+            .fetch_platform_digest(image_ref, auth, platform)
+            .await?;
+        Ok(digest.map(|digest| image_ref.clone_with_digest(digest)))
+    }
+
+    async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        self.storer.read().await.module_path(image_ref)
+    }
+}
+
+#[async_trait]
+impl<S: Storer + BlobCache + Sync + Send, C: Client + Sync + Send> WritableStore
+    for LocalStore<S, C>
+{
+    async fn put(&self, image_ref: &Reference, module: &[u8]) -> anyhow::Result<()> {
+        // The registry digest of the module isn't known, so a module written back here is
+        // pulled again by the `Always` pull policy
+        let image_data = ImageData {
+            layers: vec![ImageLayer::new(
+                module.to_vec(),
+                oci_distribution::manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            digest: None,
+        };
+        self.storer
+            .write()
+            .await
+            .store(image_ref, image_data)
+            .await?;
+        self.evict_cached(image_ref);
+        Ok(())
     }
 }
 
@@ -165,14 +435,18 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
 /// handles local I/O for module data and acts as a cache implementation.
 #[async_trait]
 pub trait Storer {
-    /// Saves a module's data into the backing store indexed by its image `Reference`.
+    /// Saves a module's data into the backing store indexed by its image `Reference`. The
+    /// module is the only layer of `image_data`, having been assembled from the layers of the
+    /// image it was pulled from.
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()>;
 
     /// Get a module's data from the backing store given its image `Reference`.
     ///
     /// The implementation must fail if the image is not present
     /// locally. `Storer` handles only reading and writing its own backing store;
-    /// remote fetch is handled at the `Store` level.
+    /// remote fetch is handled at the `Store` level. Implementations that can tell that the
+    /// module was corrupted in storage should remove it and fail with a
+    /// [`CorruptModuleError`], which makes the `LocalStore` pull it again.
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>>;
 
     /// Whether the specified module is already present in the backing store.
@@ -180,4 +454,25 @@ pub trait Storer {
 
     /// Whether the specified module is already present in the backing store with the specified digest.
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool;
+
+    /// The file the specified module is stored in, if the backing store keeps modules in
+    /// files. The file need not exist.
+    fn module_path(&self, _image_ref: &Reference) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// A content-addressed cache of image blobs.
+///
+/// Layers are added to the cache as soon as they have been downloaded and verified, so that a
+/// pull that fails part way through (for example on a flaky connection) only has to fetch the
+/// missing layers when it is retried.
+#[async_trait]
+pub trait BlobCache {
+    /// Get the blob with the given digest, if it is in the cache.
+    async fn get_blob(&self, digest: &str) -> Option<Vec<u8>>;
+
+    /// Add a blob to the cache. Callers are responsible for verifying that the data matches the
+    /// digest.
+    async fn put_blob(&self, digest: &str, data: &[u8]) -> anyhow::Result<()>;
 }
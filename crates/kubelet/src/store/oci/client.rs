@@ -1,11 +1,19 @@
 //! Client for fetching container modules from OCI
 use async_trait::async_trait;
-use oci_distribution::client::ImageData;
+use oci_distribution::client::{ImageData, ImageLayer};
+use oci_distribution::compression::decompressed_media_type;
+use oci_distribution::errors::DigestMismatchError;
 use oci_distribution::manifest;
-use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::manifest::Platform;
+use sha2::Digest;
+use tracing::{debug, info, warn};
 
 use oci_distribution::Reference;
 
+use crate::secret::RegistryCredentials;
+use crate::store::metrics::store_metrics;
+use crate::store::BlobCache;
+
 /// An image client capable of fetching images from a storage location
 #[async_trait]
 pub trait Client {
@@ -18,13 +26,13 @@ pub trait Client {
     /// use kubelet::store::oci::Client;
     /// use oci_distribution::Reference;
     /// use oci_distribution::client::ImageData;
-    /// use oci_distribution::secrets::RegistryAuth;
+    /// use kubelet::secret::RegistryCredentials;
     ///
     /// struct InMemoryClient(std::collections::HashMap<Reference, ImageData>);
     ///
     /// #[async_trait]
     /// impl Client for InMemoryClient {
-    ///     async fn pull(&mut self, image_ref: &Reference, _auth: &RegistryAuth) -> anyhow::Result<ImageData> {
+    ///     async fn pull(&mut self, image_ref: &Reference, _auth: &RegistryCredentials) -> anyhow::Result<ImageData> {
     ///         let image_data = self
     ///             .0
     ///             .get(image_ref)
@@ -36,9 +44,22 @@ pub trait Client {
     async fn pull(
         &mut self,
         image_ref: &Reference,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<ImageData>;
 
+    /// Fetch the image data for the given image reference, reusing the layers that
+    /// are already in `cache` and adding the layers it downloads to it.
+    ///
+    /// The default implementation ignores the cache and calls `pull`.
+    async fn pull_cached(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryCredentials,
+        _cache: &(dyn BlobCache + Send + Sync),
+    ) -> anyhow::Result<ImageData> {
+        self.pull(image_ref, auth).await
+    }
+
     /// Fetch the digest for the given image reference from a storage location.
     ///
     /// The default implementation pulls the image data and digest, and returns
@@ -47,27 +68,170 @@ pub trait Client {
     async fn fetch_digest(
         &mut self,
         image_ref: &Reference,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<String> {
         let image_data = self.pull(image_ref, auth).await?;
         image_data
             .digest
             .ok_or_else(|| anyhow::anyhow!("image {} does not have a digest", image_ref))
     }
+
+    /// Fetch the digest of the manifest for `platform`, if the given image reference is an
+    /// image index, so that the module for that platform can be pulled by its digest.
+    ///
+    /// The default implementation returns `None`, so that the reference is pulled as is.
+    async fn fetch_platform_digest(
+        &mut self,
+        _image_ref: &Reference,
+        _auth: &RegistryCredentials,
+        _platform: &Platform,
+    ) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
 impl Client for oci_distribution::Client {
-    async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
-        self.pull(image, auth, vec![manifest::WASM_LAYER_MEDIA_TYPE])
-            .await
+    async fn pull(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<ImageData> {
+        self.pull(
+            image,
+            &auth.for_reference(image),
+            vec![manifest::WASM_LAYER_MEDIA_TYPE],
+        )
+        .await
     }
 
     async fn fetch_digest(
         &mut self,
         image: &Reference,
-        auth: &RegistryAuth,
+        auth: &RegistryCredentials,
     ) -> anyhow::Result<String> {
-        self.fetch_manifest_digest(image, auth).await
+        self.fetch_manifest_digest(image, &auth.for_reference(image))
+            .await
+    }
+
+    async fn fetch_platform_digest(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryCredentials,
+        platform: &Platform,
+    ) -> anyhow::Result<Option<String>> {
+        self.fetch_platform_manifest_digest(image, &auth.for_reference(image), platform)
+            .await
+    }
+
+    async fn pull_cached(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryCredentials,
+        cache: &(dyn BlobCache + Send + Sync),
+    ) -> anyhow::Result<ImageData> {
+        let auth = auth.for_reference(image);
+        let (manifest, digest) = self.pull_manifest(image, &auth).await?;
+        // Every layer is pulled, whatever its media type, for the store's assembler to compose
+        // the module from
+        if manifest.layers.is_empty() {
+            return Err(anyhow::anyhow!("no layers to pull"));
+        }
+
+        let total = manifest.layers.len();
+        let mut layers = Vec::with_capacity(total);
+        let mut bytes_reused = 0;
+        for layer in &manifest.layers {
+            let cached = match cache.get_blob(&layer.digest).await {
+                Some(data) if is_verified(&layer.digest, &data) => {
+                    store_metrics().record_layer_reused(data.len());
+                    bytes_reused += data.len();
+                    Some(data)
+                }
+                Some(_) => {
+                    warn!(digest = %layer.digest, "Cached image layer does not match its digest");
+                    store_metrics().record_verification_failure();
+                    None
+                }
+                None => None,
+            };
+            layers.push(cached);
+        }
+        let cached = layers.iter().filter(|l| l.is_some()).count();
+        info!(%image, bytes_reused, "{} of {} layers cached", cached, total);
+
+        // Download all the missing layers, even if some of them fail, so that as many layers as
+        // possible are cached for the next attempt.
+        let this = &*self;
+        let missing: Vec<usize> = (0..total).filter(|i| layers[*i].is_none()).collect();
+        let downloads = missing.into_iter().map(|index| {
+            let layer = &manifest.layers[index];
+            async move {
+                debug!(digest = %layer.digest, "Pulling image layer");
+                let mut data = Vec::new();
+                // The layer is checked against its digest as it is pulled
+                if let Err(e) = this.pull_layer(image, &layer.digest, &mut data).await {
+                    if e.is::<DigestMismatchError>() {
+                        store_metrics().record_verification_failure();
+                    }
+                    return Err(e);
+                }
+                store_metrics().record_bytes_pulled(image.registry(), data.len());
+                debug!(digest = %layer.digest, registry = image.registry(), bytes = data.len(), "Pulled image layer");
+                if let Err(e) = cache.put_blob(&layer.digest, &data).await {
+                    warn!(error = %e, digest = %layer.digest, "Unable to cache image layer");
+                }
+                Ok::<_, anyhow::Error>((index, data))
+            }
+        });
+        let mut error = None;
+        for result in futures::future::join_all(downloads).await {
+            match result {
+                Ok((index, data)) => layers[index] = Some(data),
+                Err(e) => error = Some(e),
+            }
+        }
+        if let Some(e) = error {
+            let cached = layers.iter().filter(|l| l.is_some()).count();
+            return Err(e.context(format!("{} of {} layers cached", cached, total)));
+        }
+
+        // Layers are cached as they are stored in the registry, and only decompressed for use
+        let layers = manifest
+            .layers
+            .into_iter()
+            .zip(layers)
+            .map(|(layer, data)| {
+                let data = self.decompress_layer(&layer, &data.expect("all layers are pulled"))?;
+                let media_type = decompressed_media_type(&layer.media_type).to_owned();
+                Ok(ImageLayer::new(data, media_type))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ImageData {
+            layers,
+            digest: Some(digest),
+        })
+    }
+}
+
+/// Whether the data matches the digest. Only SHA256 digests can be verified, data for any other
+/// kind of digest is never considered verified.
+pub(super) fn is_verified(digest: &str, data: &[u8]) -> bool {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) => format!("{:x}", sha2::Sha256::digest(data)) == hex,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_verified() {
+        let digest = "sha256:039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81";
+        assert!(is_verified(digest, &[1, 2, 3]));
+        assert!(!is_verified(digest, &[1, 2]));
+        assert!(!is_verified("sha512:039058c6", &[1, 2, 3]));
     }
 }
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use oci_distribution::client::ImageData;
+use oci_distribution::errors::ServerError;
+use oci_distribution::manifest::Platform;
+use oci_distribution::Reference;
+use tracing::{info, warn};
+
+use super::client::Client;
+use crate::secret::RegistryCredentials;
+use crate::store::BlobCache;
+
+/// The number of failures in a row after which a registry is skipped.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long a registry is skipped for before it is tried again.
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Tracks the failures of a registry, so that one that is down is only tried once the others
+/// have failed too instead of holding up every pull.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self, now: Instant) -> bool {
+        matches!(self.open_until, Some(until) if now < until)
+    }
+
+    fn record_success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        // Once the circuit has been open, a single failed retry opens it again
+        if self.failures >= FAILURE_THRESHOLD {
+            self.open_until = Some(now + OPEN_DURATION);
+        }
+    }
+}
+
+/// Repositories that can be pulled from several equivalent registries.
+#[derive(Debug)]
+struct FailoverGroup {
+    prefix: String,
+    replicas: Vec<String>,
+}
+
+/// A [`Client`] that pulls images from equivalent registries when their own registry can't be
+/// reached, such as a local registry on an edge node backed by a central one.
+///
+/// Failover is configured with repository prefixes (e.g. `edge.local:5000/apps`) and the
+/// prefixes that replace them in the same repositories on the other registries, in the order
+/// they are tried. An image is always tried at its own registry first. Registries that couldn't
+/// be reached or answered with a server error several pulls in a row are tried last for a while. Images are stored under the reference
+/// the pod asked for, whichever registry they came from.
+///
+/// Each registry is sent the credentials the pod's image pull secrets have for it, or none at
+/// all, never those for the image's own registry. The replicas are configured statically; they
+/// are not discovered.
+pub struct FailoverClient<C> {
+    inner: C,
+    groups: Vec<FailoverGroup>,
+    breakers: HashMap<String, CircuitBreaker>,
+}
+
+impl<C: Client + Send> FailoverClient<C> {
+    /// Create a client that pulls with `inner`, failing over from each repository prefix in
+    /// `failover` to the replacement prefixes it maps to. Fails if any of the prefixes is not a
+    /// valid image reference.
+    pub fn new(inner: C, failover: HashMap<String, Vec<String>>) -> anyhow::Result<Self> {
+        let mut groups = Vec::with_capacity(failover.len());
+        for (prefix, replicas) in failover {
+            for name in std::iter::once(&prefix).chain(&replicas) {
+                Reference::try_from(name.as_str()).map_err(|e| {
+                    anyhow::anyhow!("invalid registry failover prefix {}: {}", name, e)
+                })?;
+            }
+            groups.push(FailoverGroup { prefix, replicas });
+        }
+        Ok(FailoverClient {
+            inner,
+            groups,
+            breakers: HashMap::new(),
+        })
+    }
+
+    /// The references to try for the image, in order.
+    fn candidates(&self, image_ref: &Reference, now: Instant) -> Vec<Reference> {
+        let group = self
+            .groups
+            .iter()
+            .filter(|group| rewrite(image_ref, &group.prefix, &group.prefix).is_some())
+            .max_by_key(|group| group.prefix.len());
+        let mut candidates = vec![image_ref.clone()];
+        if let Some(group) = group {
+            candidates.extend(
+                group
+                    .replicas
+                    .iter()
+                    .filter_map(|replica| rewrite(image_ref, &group.prefix, replica)),
+            );
+        }
+        // The sort is stable, so the configured order is kept otherwise
+        candidates.sort_by_key(|candidate| {
+            self.breakers
+                .get(candidate.registry())
+                .map_or(false, |breaker| breaker.is_open(now))
+        });
+        candidates
+    }
+
+    fn record<T>(&mut self, candidate: &Reference, result: &anyhow::Result<T>, now: Instant) {
+        let breaker = self
+            .breakers
+            .entry(candidate.registry().to_owned())
+            .or_default();
+        match result {
+            Ok(_) => breaker.record_success(),
+            Err(e) if is_registry_failure(e) => {
+                warn!(error = %e, image = %candidate, "Unable to pull from registry");
+                breaker.record_failure(now);
+            }
+            // The registry answered, e.g. that the image doesn't exist or the pod's credentials
+            // aren't accepted, so it isn't down
+            Err(e) => warn!(error = %e, image = %candidate, "Registry refused pull"),
+        }
+    }
+}
+
+/// Whether the error means the registry couldn't be reached or failed to answer: the connection
+/// failed, the request timed out or the registry answered with a server error.
+fn is_registry_failure(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<ServerError>()
+            || cause
+                .downcast_ref::<reqwest::Error>()
+                .map_or(false, |e| e.is_connect() || e.is_timeout())
+    })
+}
+
+/// Replace `prefix` in the reference, if the reference starts with it. Prefixes only match whole
+/// path components, so `edge.local/app` doesn't match `edge.local/apps/web`.
+fn rewrite(image_ref: &Reference, prefix: &str, replacement: &str) -> Option<Reference> {
+    let whole = image_ref.whole();
+    let rest = whole.strip_prefix(prefix)?;
+    if !(rest.is_empty() || rest.starts_with(&['/', ':', '@'][..])) {
+        return None;
+    }
+    Reference::try_from(format!("{}{}", replacement, rest)).ok()
+}
+
+/// The error for an image that none of the registries could provide.
+fn all_failed(image_ref: &Reference, errors: Vec<(Reference, anyhow::Error)>) -> anyhow::Error {
+    let mut errors = errors.into_iter();
+    match (errors.next(), errors.len()) {
+        // Without failover, keep the error as it is
+        (Some((_, e)), 0) => e,
+        (first, _) => {
+            let details: Vec<String> = first
+                .into_iter()
+                .chain(errors)
+                .map(|(candidate, e)| format!("{}: {}", candidate.registry(), e))
+                .collect();
+            anyhow::anyhow!(
+                "unable to pull {} from any of its registries ({})",
+                image_ref,
+                details.join("; ")
+            )
+        }
+    }
+}
+
+/// Try the candidates for the image in order with the given call to the inner client, until one
+/// of them succeeds. The inner client authenticates to each candidate's registry with the
+/// credentials for that candidate.
+macro_rules! with_failover {
+    ($self:ident, $image_ref:expr, |$client:ident, $candidate:ident| $call:expr) => {{
+        let mut errors = Vec::new();
+        for $candidate in $self.candidates($image_ref, Instant::now()) {
+            let $client = &mut $self.inner;
+            let result = $call.await;
+            $self.record(&$candidate, &result, Instant::now());
+            match result {
+                Ok(value) => {
+                    if !errors.is_empty() {
+                        info!(
+                            image = %$image_ref,
+                            registry = $candidate.registry(),
+                            "Pulled from failover registry"
+                        );
+                    }
+                    return Ok(value);
+                }
+                Err(e) => errors.push(($candidate, e)),
+            }
+        }
+        Err(all_failed($image_ref, errors))
+    }};
+}
+
+#[async_trait]
+impl<C: Client + Send> Client for FailoverClient<C> {
+    async fn pull(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<ImageData> {
+        with_failover!(self, image_ref, |client, candidate| client
+            .pull(&candidate, auth))
+    }
+
+    async fn pull_cached(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryCredentials,
+        cache: &(dyn BlobCache + Send + Sync),
+    ) -> anyhow::Result<ImageData> {
+        with_failover!(self, image_ref, |client, candidate| client
+            .pull_cached(&candidate, auth, cache))
+    }
+
+    async fn fetch_digest(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<String> {
+        with_failover!(self, image_ref, |client, candidate| client
+            .fetch_digest(&candidate, auth))
+    }
+
+    async fn fetch_platform_digest(
+        &mut self,
+        image_ref: &Reference,
+        auth: &RegistryCredentials,
+        platform: &Platform,
+    ) -> anyhow::Result<Option<String>> {
+        with_failover!(self, image_ref, |client, candidate| client
+            .fetch_platform_digest(&candidate, auth, platform))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Secret;
+    use oci_distribution::client::ImageLayer;
+    use oci_distribution::secrets::RegistryAuth;
+    use std::collections::HashSet;
+
+    /// Serves every image but those named `missing` from the registries that are up.
+    struct FakeRegistries {
+        up: HashSet<&'static str>,
+        pulls: Vec<String>,
+        /// The user each pull authenticated as, if any.
+        users: Vec<Option<String>>,
+    }
+
+    #[async_trait]
+    impl Client for FakeRegistries {
+        async fn pull(
+            &mut self,
+            image_ref: &Reference,
+            auth: &RegistryCredentials,
+        ) -> anyhow::Result<ImageData> {
+            self.pulls.push(image_ref.whole());
+            self.users.push(match auth.for_reference(image_ref) {
+                RegistryAuth::Basic(username, _) => Some(username),
+                _ => None,
+            });
+            if !self.up.contains(image_ref.registry()) {
+                return Err(ServerError {
+                    url: format!("https://{}/v2/", image_ref.registry()),
+                    status: 503,
+                }
+                .into());
+            }
+            if image_ref.repository().ends_with("/missing") {
+                anyhow::bail!("OCI API error: manifest unknown");
+            }
+            Ok(ImageData {
+                layers: vec![ImageLayer::oci_v1(image_ref.whole().into_bytes())],
+                digest: Some("sha256:123".to_owned()),
+            })
+        }
+    }
+
+    fn client(up: &[&'static str]) -> FailoverClient<FakeRegistries> {
+        let mut failover = HashMap::new();
+        failover.insert(
+            "edge.local:5000/apps".to_owned(),
+            vec![
+                "backup.local:5000/apps".to_owned(),
+                "registry.example.com/edge/apps".to_owned(),
+            ],
+        );
+        FailoverClient::new(
+            FakeRegistries {
+                up: up.iter().cloned().collect(),
+                pulls: Vec::new(),
+                users: Vec::new(),
+            },
+            failover,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let image = Reference::try_from("edge.local:5000/apps/web:v1").unwrap();
+        assert_eq!(
+            "registry.example.com/edge/apps/web:v1",
+            rewrite(
+                &image,
+                "edge.local:5000/apps",
+                "registry.example.com/edge/apps"
+            )
+            .unwrap()
+            .whole()
+        );
+        assert!(rewrite(&image, "edge.local:5000/app", "backup.local:5000/app").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pull_fails_over() {
+        let mut client = client(&["registry.example.com"]);
+        let image = Reference::try_from("edge.local:5000/apps/web:v1").unwrap();
+        let data = client
+            .pull(&image, &RegistryCredentials::anonymous())
+            .await
+            .unwrap();
+        assert_eq!(
+            b"registry.example.com/edge/apps/web:v1".to_vec(),
+            data.layers[0].data
+        );
+        assert_eq!(3, client.inner.pulls.len());
+
+        let other = Reference::try_from("other.local/web:v1").unwrap();
+        let e = client
+            .pull(&other, &RegistryCredentials::anonymous())
+            .await
+            .unwrap_err();
+        assert_eq!("Server error at https://other.local/v2/", e.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_replicas_get_their_own_credentials() {
+        let secret: Secret = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "edge"},
+            "type": "kubernetes.io/dockerconfigjson",
+            "data": {
+                ".dockerconfigjson": base64::encode(serde_json::json!({"auths": {
+                    "edge.local:5000": {"username": "edge", "password": "p"},
+                    "registry.example.com": {"username": "central", "password": "p"},
+                }}).to_string()),
+            },
+        }))
+        .unwrap();
+        let credentials = RegistryCredentials::from_secrets(&[secret]);
+        let mut client = client(&["registry.example.com"]);
+        let image = Reference::try_from("edge.local:5000/apps/web:v1").unwrap();
+        client.pull(&image, &credentials).await.unwrap();
+        assert_eq!(
+            vec![Some("edge".to_owned()), None, Some("central".to_owned())],
+            client.inner.users
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_registry_is_tried_last() {
+        let mut client = client(&["backup.local:5000"]);
+        let image = Reference::try_from("edge.local:5000/apps/web:v1").unwrap();
+        for _ in 0..FAILURE_THRESHOLD {
+            client
+                .pull(&image, &RegistryCredentials::anonymous())
+                .await
+                .unwrap();
+        }
+        client.inner.pulls.clear();
+        client
+            .pull(&image, &RegistryCredentials::anonymous())
+            .await
+            .unwrap();
+        assert_eq!(vec!["backup.local:5000/apps/web:v1"], client.inner.pulls);
+
+        // Once the circuit closes again, the image's own registry is tried first
+        let later = Instant::now() + OPEN_DURATION;
+        let candidates = client.candidates(&image, later);
+        assert_eq!("edge.local:5000", candidates[0].registry());
+    }
+
+    #[tokio::test]
+    async fn test_missing_image_is_not_a_registry_failure() {
+        let mut client = client(&["edge.local:5000", "backup.local:5000"]);
+        let image = Reference::try_from("edge.local:5000/apps/missing:v1").unwrap();
+        for _ in 0..FAILURE_THRESHOLD {
+            client
+                .pull(&image, &RegistryCredentials::anonymous())
+                .await
+                .unwrap_err();
+        }
+        let candidates = client.candidates(&image, Instant::now());
+        assert_eq!("edge.local:5000", candidates[0].registry());
+    }
+}
@@ -1,17 +1,30 @@
-use crate::store::Storer;
+use crate::store::{BlobCache, CorruptModuleError, Storer};
 use oci_distribution::client::ImageData;
+use std::collections::HashSet;
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use oci_distribution::Reference;
+use sha2::Digest;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use super::client::Client;
+use super::client::{is_verified, Client};
+use crate::store::assembler::MediaTypeAssembler;
+use crate::store::metrics::store_metrics;
 use crate::store::LocalStore;
 
+/// How long a blob that no module reference points to is kept before it is garbage collected.
+/// Layers are only referenced while their image is being pulled, and are kept this long so that
+/// a pull that is retried doesn't have to download them again.
+const BLOB_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// How often [`FileStore::collect_garbage_periodically`] garbage collects the store.
+const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 /// A module store that keeps modules cached on the file system
 ///
 /// This type is generic over the type of client used
@@ -27,10 +40,42 @@ impl<C: Client + Send> FileStore<C> {
                 root_dir: root_dir.as_ref().into(),
             })),
             client: Arc::new(Mutex::new(client)),
+            module_cache: None,
+            assembler: Arc::new(MediaTypeAssembler::default()),
+        }
+    }
+
+    /// Garbage collect the store when the returned future is first polled and every 10 minutes
+    /// after that, for as long as it runs. Walking the whole store is too slow to do on every
+    /// pull, so embedders spawn this alongside the Kubelet.
+    pub fn collect_garbage_periodically(&self) -> impl Future<Output = ()> + Send + 'static {
+        let storer = self.storer.clone();
+        async move {
+            let root_dir = storer.read().await.root_dir.clone();
+            // Collecting doesn't need the store lock: blobs are only removed once they have
+            // been unreferenced for a while, so pulls in progress keep theirs
+            let storer = FileStorer::new(root_dir);
+            let mut interval = tokio::time::interval(GARBAGE_COLLECTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                match storer.collect_garbage(BLOB_GRACE_PERIOD).await {
+                    Ok(removed) => debug!(removed, "Garbage collected module store"),
+                    Err(e) => warn!(error = %e, "Unable to garbage collect module store"),
+                }
+            }
         }
     }
 }
 
+/// Stores modules and image layers on the file system, content-addressed by their digest.
+///
+/// Blobs are kept under `blobs/<algorithm>/<hex>`, and each image reference has a directory
+/// under `<registry>/<repository>/<tag>` holding the digest of its module and the digest of the
+/// image it was pulled from. Images that share a module share its blob. Modules are checked
+/// against their digest whenever they are read, and blobs that no reference points to are
+/// removed by [`collect_garbage`](Self::collect_garbage). Modules stored as
+/// `<registry>/<repository>/<tag>/module.wasm`, as they were before, are moved into the blob
+/// store when they are first looked up.
 pub struct FileStorer {
     root_dir: PathBuf,
 }
@@ -50,46 +95,149 @@ impl FileStorer {
         path
     }
 
-    fn pull_file_path(&self, r: &Reference) -> PathBuf {
+    fn module_digest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("module.txt")
+    }
+
+    /// Where the module of the reference was stored before modules were stored as blobs.
+    fn legacy_module_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("module.wasm")
     }
 
     fn digest_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("digest.txt")
     }
+
+    fn blobs_path(&self) -> PathBuf {
+        self.root_dir.join("blobs")
+    }
+
+    fn blob_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        match digest.split_once(':') {
+            Some((algorithm, hex))
+                if !algorithm.is_empty()
+                    && !hex.is_empty()
+                    && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+                    && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                let mut path = self.blobs_path();
+                path.push(algorithm);
+                path.push(hex);
+                Ok(path)
+            }
+            _ => Err(anyhow::anyhow!("Invalid blob digest {}", digest)),
+        }
+    }
+
+    /// The digest of the module stored for the reference, if there is one.
+    async fn module_digest(&self, image_ref: &Reference) -> Option<String> {
+        if let Ok(digest) = tokio::fs::read_to_string(self.module_digest_file_path(image_ref)).await
+        {
+            return Some(digest);
+        }
+        match self.migrate_legacy_module(image_ref).await {
+            Ok(digest) => digest,
+            Err(e) => {
+                warn!(?image_ref, error = %e, "Unable to move module into the blob store");
+                None
+            }
+        }
+    }
+
+    /// Move the module stored for the reference the way modules were stored before into the
+    /// blob store, returning its digest, or `None` if there is no such module.
+    async fn migrate_legacy_module(&self, image_ref: &Reference) -> anyhow::Result<Option<String>> {
+        let legacy_module_path = self.legacy_module_path(image_ref);
+        let module = match tokio::fs::read(&legacy_module_path).await {
+            Ok(module) => module,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let digest = format!("sha256:{:x}", sha2::Sha256::digest(&module));
+        debug!(?image_ref, %digest, "Moving module into the blob store");
+        self.put_blob(&digest, &module).await?;
+        write_atomically(&self.module_digest_file_path(image_ref), digest.as_bytes()).await?;
+        match tokio::fs::remove_file(&legacy_module_path).await {
+            // Another lookup of the reference may have moved it at the same time
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        Ok(Some(digest))
+    }
+
+    /// Remove the blobs that no image reference points to and that haven't been written for at
+    /// least `grace_period`, along with any partly written blobs as old as that, returning how
+    /// many were removed.
+    pub async fn collect_garbage(&self, grace_period: Duration) -> anyhow::Result<usize> {
+        let root_dir = self.root_dir.clone();
+        let blobs_dir = self.blobs_path();
+        tokio::task::spawn_blocking(move || {
+            let mut referenced = HashSet::new();
+            find_module_digests(&root_dir, &blobs_dir, &mut referenced)?;
+            remove_unreferenced_blobs(&blobs_dir, &referenced, grace_period)
+        })
+        .await?
+    }
 }
 
 #[async_trait]
 impl Storer for FileStorer {
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
-        let path = self.pull_file_path(image_ref);
+        let not_available = || anyhow::anyhow!("Image ref {} not available locally", image_ref);
+        let digest = self
+            .module_digest(image_ref)
+            .await
+            .ok_or_else(not_available)?;
+        let path = self.blob_path(&digest)?;
         if !path.exists() {
-            return Err(anyhow::anyhow!(
-                "Image ref {} not available locally",
-                image_ref
-            ));
+            return Err(not_available());
         }
 
-        debug!(?image_ref, "Fetching image ref from disk");
-        Ok(tokio::fs::read(path).await?)
+        debug!(?image_ref, %digest, "Fetching image ref from disk");
+        let module = tokio::fs::read(&path).await?;
+        if !is_verified(&digest, &module) {
+            warn!(?image_ref, %digest, "Stored module does not match its digest, removing it");
+            store_metrics().record_verification_failure();
+            // Other references to the module find it missing and pull it again
+            tokio::fs::remove_file(&path).await?;
+            tokio::fs::remove_file(self.module_digest_file_path(image_ref)).await?;
+            return Err(CorruptModuleError {
+                image_ref: image_ref.whole(),
+                digest,
+            }
+            .into());
+        }
+        Ok(module)
     }
+
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()> {
         tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
         let digest_path = self.digest_file_path(image_ref);
-        // We delete the digest file before writing the image file, rather
-        // than simply overwriting the digest file after writing the image file.
-        // This addresses failure modes where, for example, the image file
-        // gets updated but the digest file write fails and the store ends
-        // up associating the wrong digest with the file on disk.
+        // We delete the digest file before writing the module digest file, rather
+        // than simply overwriting the digest file after writing the module digest.
+        // This addresses failure modes where, for example, the module gets
+        // updated but the digest file write fails and the store ends
+        // up associating the wrong digest with the module on disk.
         if digest_path.exists() {
             tokio::fs::remove_file(&digest_path).await?;
         }
-        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
-        let module_path = self.pull_file_path(image_ref);
-        if image_data.layers.is_empty() {
-            return Err(anyhow::anyhow!("No module layer present in image data"));
+        // The store has already assembled the image's layers into the module
+        let module = match image_data.layers.first() {
+            Some(layer) => &layer.data,
+            None => return Err(anyhow::anyhow!("No module layer present in image data")),
+        };
+        let module_digest = format!("sha256:{:x}", sha2::Sha256::digest(module));
+        self.put_blob(&module_digest, module).await?;
+        write_atomically(
+            &self.module_digest_file_path(image_ref),
+            module_digest.as_bytes(),
+        )
+        .await?;
+        // Modules used to be stored in the reference's directory
+        let legacy_module_path = self.legacy_module_path(image_ref);
+        if legacy_module_path.exists() {
+            tokio::fs::remove_file(&legacy_module_path).await?;
         }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
         if let Some(d) = image_data.digest {
             tokio::fs::write(&digest_path, d).await?;
         }
@@ -97,13 +245,36 @@ impl Storer for FileStorer {
     }
 
     async fn is_present(&self, image_ref: &Reference) -> bool {
-        let path = self.pull_file_path(image_ref);
-        path.exists()
+        match self.module_digest(image_ref).await {
+            Some(digest) => matches!(self.blob_path(&digest), Ok(path) if path.exists()),
+            None => false,
+        }
     }
 
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool {
         let path = self.digest_file_path(image_ref);
-        path.exists() && file_content_is(path, digest).await
+        path.exists() && file_content_is(path, digest).await && self.is_present(image_ref).await
+    }
+
+    fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        let digest = std::fs::read_to_string(self.module_digest_file_path(image_ref)).ok()?;
+        self.blob_path(&digest).ok()
+    }
+}
+
+#[async_trait]
+impl BlobCache for FileStorer {
+    async fn get_blob(&self, digest: &str) -> Option<Vec<u8>> {
+        let path = self.blob_path(digest).ok()?;
+        tokio::fs::read(path).await.ok()
+    }
+
+    async fn put_blob(&self, digest: &str, data: &[u8]) -> anyhow::Result<()> {
+        let path = self.blob_path(digest)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomically(&path, data).await
     }
 }
 
@@ -112,10 +283,21 @@ impl<C: Client + Send> Clone for FileStore<C> {
         Self {
             storer: self.storer.clone(),
             client: self.client.clone(),
+            module_cache: self.module_cache.clone(),
+            assembler: self.assembler.clone(),
         }
     }
 }
 
+/// Write to a temporary file first so that an interrupted write never leaves a partial file
+/// under its final name.
+async fn write_atomically(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("partial");
+    tokio::fs::write(&temp_path, data).await?;
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
 async fn file_content_is(path: PathBuf, text: String) -> bool {
     match tokio::fs::read(path).await {
         Err(_) => false,
@@ -126,13 +308,68 @@ async fn file_content_is(path: PathBuf, text: String) -> bool {
     }
 }
 
+/// Collect the module digests of the image references under `dir`, skipping the blobs.
+fn find_module_digests(
+    dir: &Path,
+    blobs_dir: &Path,
+    digests: &mut HashSet<String>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path == blobs_dir {
+            continue;
+        }
+        if path.is_dir() {
+            find_module_digests(&path, blobs_dir, digests)?;
+        } else if path.file_name().map_or(false, |name| name == "module.txt") {
+            digests.insert(std::fs::read_to_string(&path)?);
+        }
+    }
+    Ok(())
+}
+
+fn remove_unreferenced_blobs(
+    blobs_dir: &Path,
+    referenced: &HashSet<String>,
+    grace_period: Duration,
+) -> anyhow::Result<usize> {
+    if !blobs_dir.exists() {
+        return Ok(0);
+    }
+    let mut removed = 0;
+    for algorithm in std::fs::read_dir(blobs_dir)? {
+        let algorithm = algorithm?;
+        if !algorithm.file_type()?.is_dir() {
+            continue;
+        }
+        for blob in std::fs::read_dir(algorithm.path())? {
+            let blob = blob?;
+            let digest = format!(
+                "{}:{}",
+                algorithm.file_name().to_string_lossy(),
+                blob.file_name().to_string_lossy()
+            );
+            let age = SystemTime::now()
+                .duration_since(blob.metadata()?.modified()?)
+                .unwrap_or_default();
+            if !referenced.contains(&digest) && age >= grace_period {
+                debug!(%digest, "Removing unreferenced blob");
+                std::fs::remove_file(blob.path())?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::container::PullPolicy;
-    use crate::store::Store;
+    use crate::secret::RegistryCredentials;
+    use crate::store::composite::ChainedStore;
+    use crate::store::{NeverPullError, Store};
     use oci_distribution::client::{ImageData, ImageLayer};
-    use oci_distribution::secrets::RegistryAuth;
     use std::collections::HashMap;
     use std::convert::TryFrom;
     use std::sync::RwLock;
@@ -169,22 +406,16 @@ mod test {
                 images: Default::default(),
             };
             for (name, content, digest) in entries {
-                let mut images = client
-                    .images
-                    .write()
-                    .expect("should be able to write to images");
-                images.insert(
-                    name.to_owned(),
-                    ImageData {
-                        layers: vec![ImageLayer::oci_v1(content)],
-                        digest: Some(digest.to_owned()),
-                    },
-                );
+                client.insert(name, vec![wasm_layer(content)], digest);
             }
             client
         }
 
         fn update(&mut self, key: &str, content: Vec<u8>, digest: &str) {
+            self.insert(key, vec![wasm_layer(content)], digest);
+        }
+
+        fn insert(&self, key: &str, layers: Vec<ImageLayer>, digest: &str) {
             let mut images = self
                 .images
                 .write()
@@ -192,18 +423,25 @@ mod test {
             images.insert(
                 key.to_owned(),
                 ImageData {
-                    layers: vec![ImageLayer::oci_v1(content)],
+                    layers,
                     digest: Some(digest.to_owned()),
                 },
             );
         }
     }
+
+    fn wasm_layer(content: Vec<u8>) -> ImageLayer {
+        ImageLayer::new(
+            content,
+            oci_distribution::manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+        )
+    }
     #[async_trait]
     impl Client for FakeImageClient {
         async fn pull(
             &mut self,
             image_ref: &Reference,
-            _auth: &RegistryAuth,
+            _auth: &RegistryCredentials,
         ) -> anyhow::Result<ImageData> {
             let images = self
                 .images
@@ -244,7 +482,7 @@ mod test {
             .get(
                 &fake_ref,
                 PullPolicy::IfNotPresent,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await?;
         assert_eq!(3, module_bytes.len());
@@ -259,7 +497,11 @@ mod test {
         let scratch_dir = create_temp_dir();
         let store = FileStore::new(fake_client, &scratch_dir.path);
         let module_bytes = store
-            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
             .await?;
         assert_eq!(3, module_bytes.len());
         assert_eq!(2, module_bytes[1]);
@@ -273,12 +515,17 @@ mod test {
         let scratch_dir = create_temp_dir();
         let store = FileStore::new(fake_client, &scratch_dir.path);
         let module_bytes = store
-            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
             .await;
         assert!(
             module_bytes.is_err(),
             "expected get with pull policy Never to fail but it worked"
         );
+        assert!(module_bytes.unwrap_err().is::<NeverPullError>());
         Ok(())
     }
 
@@ -288,12 +535,22 @@ mod test {
         let fake_ref = Reference::try_from("foo/bar:1.0")?;
         let scratch_dir = create_temp_dir();
         let store = FileStore::new(fake_client, &scratch_dir.path);
+        assert!(!store.is_present(&fake_ref).await);
         let prime_cache = store
-            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
             .await;
         assert!(prime_cache.is_ok());
+        assert!(store.is_present(&fake_ref).await);
         let module_bytes = store
-            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
             .await?;
         assert_eq!(3, module_bytes.len());
         assert_eq!(2, module_bytes[1]);
@@ -311,7 +568,7 @@ mod test {
             .get(
                 &fake_ref,
                 PullPolicy::IfNotPresent,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await?;
         assert_eq!(3, module_bytes_orig.len());
@@ -321,7 +578,7 @@ mod test {
             .get(
                 &fake_ref,
                 PullPolicy::IfNotPresent,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await?;
         assert_eq!(3, module_bytes_after.len());
@@ -340,20 +597,164 @@ mod test {
             .get(
                 &fake_ref,
                 PullPolicy::IfNotPresent,
-                &RegistryAuth::Anonymous,
+                &RegistryCredentials::anonymous(),
             )
             .await?;
         assert_eq!(3, module_bytes_orig.len());
         assert_eq!(2, module_bytes_orig[1]);
         fake_client.update("foo/bar:1.0", vec![4, 5, 6, 7], "sha256:4567");
         let module_bytes_after = store
-            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
             .await?;
         assert_eq!(4, module_bytes_after.len());
         assert_eq!(5, module_bytes_after[1]);
         Ok(())
     }
 
+    #[tokio::test]
+    async fn file_module_store_does_not_serve_stale_modules_from_memory() -> anyhow::Result<()> {
+        let mut fake_client =
+            FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path).with_module_cache(1024);
+        let module_bytes_orig = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes_orig);
+        fake_client.update("foo/bar:1.0", vec![4, 5, 6, 7], "sha256:4567");
+        let module_bytes_always = store
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![4, 5, 6, 7], module_bytes_always);
+        let module_bytes_after = store
+            .get(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![4, 5, 6, 7], module_bytes_after);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_storer_caches_blobs() -> anyhow::Result<()> {
+        let scratch_dir = create_temp_dir();
+        let storer = FileStorer::new(&scratch_dir.path);
+        let digest = "sha256:039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81";
+        assert_eq!(None, storer.get_blob(digest).await);
+        storer.put_blob(digest, &[1, 2, 3]).await?;
+        assert_eq!(Some(vec![1, 2, 3]), storer.get_blob(digest).await);
+        assert!(storer.put_blob("sha256:../../etc", &[1]).await.is_err());
+        assert!(storer.put_blob("no-algorithm", &[1]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_pulls_corrupted_modules_again() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        store
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        let path = store
+            .module_path(&fake_ref)
+            .await
+            .expect("module should be stored in a file");
+        assert!(path.starts_with(scratch_dir.path.join("blobs").join("sha256")));
+
+        std::fs::write(&path, &[6u8, 6, 6])?;
+        let module_bytes = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+
+        std::fs::write(&path, &[6u8, 6, 6])?;
+        let error = store
+            .get(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .expect_err("corrupted module should not be returned");
+        assert!(error.is::<CorruptModuleError>());
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_storer_collects_unreferenced_blobs() -> anyhow::Result<()> {
+        let scratch_dir = create_temp_dir();
+        let mut storer = FileStorer::new(&scratch_dir.path);
+        let module = |data: Vec<u8>| ImageData {
+            layers: vec![wasm_layer(data)],
+            digest: None,
+        };
+        let foo = Reference::try_from("foo/bar:1.0")?;
+        let baz = Reference::try_from("foo/baz:1.0")?;
+        storer.store(&foo, module(vec![1, 2, 3])).await?;
+        storer.store(&baz, module(vec![1, 2, 3])).await?;
+        let layer = "sha256:2fa1b377bf67309f65e5e7bc9d924345ca648dec4e601a398a9cb497dcba3765";
+        storer.put_blob(layer, &[4, 5]).await?;
+        assert_eq!(storer.module_path(&foo), storer.module_path(&baz));
+
+        storer.store(&foo, module(vec![6])).await?;
+        assert_eq!(1, storer.collect_garbage(Duration::from_secs(0)).await?);
+        assert_eq!(None, storer.get_blob(layer).await);
+        assert_eq!(vec![1, 2, 3], storer.get_local(&baz).await?);
+        assert_eq!(vec![6], storer.get_local(&foo).await?);
+
+        storer.store(&baz, module(vec![7])).await?;
+        assert_eq!(1, storer.collect_garbage(Duration::from_secs(0)).await?);
+        assert_eq!(0, storer.collect_garbage(Duration::from_secs(0)).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_storer_moves_legacy_modules_into_blob_store() -> anyhow::Result<()> {
+        let scratch_dir = create_temp_dir();
+        let storer = FileStorer::new(&scratch_dir.path);
+        let foo = Reference::try_from("foo/bar:1.0")?;
+        let legacy_module_path = storer.legacy_module_path(&foo);
+        std::fs::create_dir_all(storer.pull_path(&foo))?;
+        std::fs::write(&legacy_module_path, &[1u8, 2, 3])?;
+
+        assert!(storer.is_present(&foo).await);
+        assert!(!legacy_module_path.exists());
+        assert_eq!(vec![1, 2, 3], storer.get_local(&foo).await?);
+        let path = storer
+            .module_path(&foo)
+            .expect("module should be stored in a blob");
+        assert!(path.starts_with(scratch_dir.path.join("blobs").join("sha256")));
+        // The moved module is referenced, so it isn't garbage collected
+        assert_eq!(0, storer.collect_garbage(Duration::from_secs(0)).await?);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_copes_with_no_tag() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar", vec![2, 3], "sha256:23")]);
@@ -361,7 +762,11 @@ mod test {
         let scratch_dir = create_temp_dir();
         let store = FileStore::new(fake_client, &scratch_dir.path);
         let module_bytes = store
-            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .get(
+                &fake_ref,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
             .await?;
         assert_eq!(2, module_bytes.len());
         assert_eq!(3, module_bytes[1]);
@@ -377,14 +782,14 @@ mod test {
         let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
         let policy = PullPolicy::parse_effective(None, Some(fake_ref.clone()))?;
         let module_bytes_orig = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(3, module_bytes_orig.len());
         assert_eq!(7, module_bytes_orig[1]);
         fake_client.update("foo/bar:2.0", vec![8, 9], "sha256:89");
         // But with no policy it should *not* re-fetch a tag that's in cache
         let module_bytes_after = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(3, module_bytes_after.len());
         assert_eq!(7, module_bytes_after[1]);
@@ -401,13 +806,13 @@ mod test {
         let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
         let policy = PullPolicy::parse_effective(None, Some(fake_ref.clone()))?;
         let module_bytes_orig = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(2, module_bytes_orig.len());
         assert_eq!(4, module_bytes_orig[1]);
         fake_client.update("foo/bar:latest", vec![5, 6, 7], "sha256:567");
         let module_bytes_after = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(3, module_bytes_after.len());
         assert_eq!(6, module_bytes_after[1]);
@@ -422,16 +827,102 @@ mod test {
         let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
         let policy = PullPolicy::parse_effective(None, Some(fake_ref.clone()))?;
         let module_bytes_orig = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(2, module_bytes_orig.len());
         assert_eq!(4, module_bytes_orig[1]);
         fake_client.update("foo/bar", vec![5, 6, 7], "sha256:567");
         let module_bytes_after = store
-            .get(&fake_ref, policy, &RegistryAuth::Anonymous)
+            .get(&fake_ref, policy, &RegistryCredentials::anonymous())
             .await?;
         assert_eq!(3, module_bytes_after.len());
         assert_eq!(6, module_bytes_after[1]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn file_module_store_assembles_multi_layer_images() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![]);
+        fake_client.insert(
+            "foo/bar:1.0",
+            vec![
+                ImageLayer::oci_v1(vec![1]),
+                wasm_layer(vec![2, 3]),
+                ImageLayer::oci_v1(vec![4]),
+            ],
+            "sha256:1234",
+        );
+        fake_client.insert("foo/baz:1.0", vec![ImageLayer::oci_v1(vec![1])], "sha256:1");
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
+        let module_bytes = store
+            .get(
+                &Reference::try_from("foo/bar:1.0")?,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![2, 3], module_bytes);
+        assert!(store
+            .get(
+                &Reference::try_from("foo/baz:1.0")?,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+            .is_err());
+
+        let other_dir = create_temp_dir();
+        let concatenate = |_: &Reference, layers: Vec<ImageLayer>| -> anyhow::Result<Vec<u8>> {
+            Ok(layers.into_iter().flat_map(|layer| layer.data).collect())
+        };
+        let store = FileStore::new(fake_client, &other_dir.path).with_assembler(concatenate);
+        let module_bytes = store
+            .get(
+                &Reference::try_from("foo/bar:1.0")?,
+                PullPolicy::Always,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3, 4], module_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_is_written_back_by_chained_store() -> anyhow::Result<()> {
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let upper_dir = create_temp_dir();
+        let lower_dir = create_temp_dir();
+        // The upper store's registry doesn't have the module, so it can only get it written back
+        let upper = Arc::new(FileStore::new(
+            FakeImageClient::new(vec![]),
+            &upper_dir.path,
+        ));
+        let lower = Arc::new(FileStore::new(
+            FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]),
+            &lower_dir.path,
+        ));
+        let chain = ChainedStore::new()
+            .with_writable_layer("upper", 0, upper.clone())
+            .with_layer("lower", 1, lower);
+        let module_bytes = chain
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+
+        assert!(upper.is_present(&fake_ref).await);
+        let module_bytes = upper
+            .get(
+                &fake_ref,
+                PullPolicy::Never,
+                &RegistryCredentials::anonymous(),
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        Ok(())
+    }
 }
@@ -0,0 +1,706 @@
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use oci_distribution::compression::{
+    decompress, decompressed_media_type, DEFAULT_MAX_DECOMPRESSED_LAYER_SIZE,
+};
+use oci_distribution::errors::DigestMismatchError;
+use oci_distribution::manifest::{
+    ImageIndexEntry, OciDescriptor, OciImageIndex, OciManifest, Platform,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+use oci_distribution::Reference;
+use sha2::Digest;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::debug;
+
+use super::platform::PlatformStore;
+use crate::container::PullPolicy;
+use crate::pod::Pod;
+use crate::secret::RegistryCredentials;
+use crate::store::composite::{ComposableStore, InterceptingStore};
+use crate::store::Store;
+
+/// The annotation a pod names an OCI image layout on the node with, to load the modules of its
+/// containers from instead of pulling them from registries. The layout is given as
+/// `oci-dir:<path>` for a layout directory, or `oci-archive:<path>` for a layout in a tar
+/// archive, and has to be under the directory the node loads layouts from (see [`pod_store`]).
+pub const OCI_LAYOUT_ANNOTATION: &str = "krustlet.dev/oci-layout";
+
+/// The annotation of the images in a layout's index that gives their reference.
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Where an OCI image layout is on the node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OciLayoutSource {
+    /// A layout directory, given as `oci-dir:<path>`.
+    Dir(PathBuf),
+    /// A layout in an uncompressed tar archive, given as `oci-archive:<path>`.
+    Archive(PathBuf),
+}
+
+impl OciLayoutSource {
+    /// Parse an `oci-dir:<path>` or `oci-archive:<path>` layout reference.
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let source = source.trim();
+        let (kind, path) = source.split_once(':').unwrap_or(("", source));
+        if path.is_empty() {
+            anyhow::bail!("OCI layout {:?} has no path", source);
+        }
+        match kind {
+            "oci-dir" => Ok(OciLayoutSource::Dir(PathBuf::from(path))),
+            "oci-archive" => Ok(OciLayoutSource::Archive(PathBuf::from(path))),
+            _ => anyhow::bail!(
+                "Invalid OCI layout {:?}: expected oci-dir:<path> or oci-archive:<path>",
+                source
+            ),
+        }
+    }
+
+    /// The layout the pod names with the [`OCI_LAYOUT_ANNOTATION`], if any.
+    pub fn from_pod(pod: &Pod) -> anyhow::Result<Option<Self>> {
+        pod.annotation_text(OCI_LAYOUT_ANNOTATION)
+            .map(Self::parse)
+            .transpose()
+    }
+
+    /// The layout, with its path canonicalized, if it is under `root`. Pods may only load
+    /// modules from layouts under the directory the node loads them from, so that they can't
+    /// read other workloads' layouts or probe the node's file system.
+    pub async fn within(self, root: &Path) -> anyhow::Result<Self> {
+        let root = tokio::fs::canonicalize(root).await?;
+        let within = |path: PathBuf| async move {
+            match tokio::fs::canonicalize(&path).await {
+                Ok(path) if path.starts_with(&root) => Ok(path),
+                _ => Err(anyhow::anyhow!(
+                    "OCI layout {} is not in {}",
+                    path.display(),
+                    root.display()
+                )),
+            }
+        };
+        match self {
+            OciLayoutSource::Dir(path) => Ok(OciLayoutSource::Dir(within(path).await?)),
+            OciLayoutSource::Archive(path) => Ok(OciLayoutSource::Archive(within(path).await?)),
+        }
+    }
+
+    /// Read a file of the layout, by its path relative to the root of the layout. Files of a
+    /// layout directory that link to outside of it are refused.
+    async fn read(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        match self {
+            OciLayoutSource::Dir(dir) => {
+                let dir = tokio::fs::canonicalize(dir).await?;
+                let path = tokio::fs::canonicalize(dir.join(name)).await?;
+                if !path.starts_with(&dir) {
+                    anyhow::bail!("{} links to outside of OCI layout {}", name, dir.display());
+                }
+                Ok(tokio::fs::read(path).await?)
+            }
+            OciLayoutSource::Archive(archive) => read_archive_entry(archive, name).await,
+        }
+    }
+}
+
+impl std::fmt::Display for OciLayoutSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OciLayoutSource::Dir(path) => write!(f, "oci-dir:{}", path.display()),
+            OciLayoutSource::Archive(path) => write!(f, "oci-archive:{}", path.display()),
+        }
+    }
+}
+
+/// A store of the modules of the images in an OCI image layout on the node, such as one
+/// delivered to a disconnected site on removable media.
+///
+/// Images are found by the `org.opencontainers.image.ref.name` annotation of the layout's
+/// index, which may be the whole reference of an image or just its tag, or by the digest of
+/// their manifest. Every blob read from the layout is checked against the digest and size it
+/// is referenced by, starting from the index, so a module is only loaded if it is exactly the
+/// one the index names. Pull policies don't apply, as the layout is already on the node.
+///
+/// Images that are image indexes (or Docker manifest lists) themselves, as multi-platform
+/// images are, are loaded from the manifest for the store's [platform](Self::with_platform).
+pub struct OciLayoutStore {
+    source: OciLayoutSource,
+    images: Vec<ImageIndexEntry>,
+    platform: Option<Platform>,
+}
+
+impl OciLayoutStore {
+    /// Open the layout, reading its index.
+    pub async fn open(source: OciLayoutSource) -> anyhow::Result<Self> {
+        let index = source
+            .read("index.json")
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to read index of OCI layout {}: {}", source, e))?;
+        let index: OciImageIndex = serde_json::from_slice(&index)
+            .map_err(|e| anyhow::anyhow!("Invalid index in OCI layout {}: {}", source, e))?;
+        debug!(layout = %source, images = index.manifests.len(), "Opened OCI layout");
+        Ok(OciLayoutStore {
+            source,
+            images: index.manifests,
+            platform: None,
+        })
+    }
+
+    /// Load images that are image indexes from their manifest for the given platform. Without
+    /// a platform, such images can't be loaded.
+    pub fn with_platform(mut self, platform: Option<Platform>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// The image of the layout's index that the reference names.
+    fn find(&self, image_ref: &Reference) -> Option<&ImageIndexEntry> {
+        self.images.iter().find(|image| {
+            if Some(image.digest.as_str()) == image_ref.digest() {
+                return true;
+            }
+            match image
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(REF_NAME_ANNOTATION))
+            {
+                Some(name) => {
+                    Some(name.as_str()) == image_ref.tag()
+                        || Reference::try_from(name.as_str()).ok().as_ref() == Some(image_ref)
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Read a blob of the layout, checking it against its digest and size.
+    async fn read_blob(&self, digest: &str, size: i64) -> anyhow::Result<Vec<u8>> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .filter(|(algorithm, hex)| {
+                !algorithm.is_empty()
+                    && !hex.is_empty()
+                    && is_alphanumeric(algorithm)
+                    && is_alphanumeric(hex)
+            })
+            .ok_or_else(|| anyhow::anyhow!("Invalid digest {:?}", digest))?;
+        let data = self
+            .source
+            .read(&format!("blobs/{}/{}", algorithm, hex))
+            .await?;
+        if data.len() as i64 != size {
+            anyhow::bail!(
+                "blob {} is {} bytes rather than its size of {} bytes",
+                digest,
+                data.len(),
+                size
+            );
+        }
+        let computed = match algorithm {
+            "sha256" => format!("sha256:{:x}", sha2::Sha256::digest(&data)),
+            "sha512" => format!("sha512:{:x}", sha2::Sha512::digest(&data)),
+            _ => anyhow::bail!("unsupported digest algorithm: {}", digest),
+        };
+        if computed != digest {
+            return Err(DigestMismatchError {
+                digest: digest.to_owned(),
+                computed,
+            }
+            .into());
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl Store for OciLayoutStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let image = self.find(image_ref).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Image {} not found in OCI layout {}",
+                image_ref,
+                self.source
+            )
+        })?;
+        let manifest = if is_index(&image.media_type) {
+            let index = self.read_blob(&image.digest, image.size).await?;
+            let index: OciImageIndex = serde_json::from_slice(&index)?;
+            let platform = self.platform.as_ref().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Image {} in OCI layout {} is an image index, and there is no platform to select from it",
+                    image_ref,
+                    self.source
+                )
+            })?;
+            let entry = index.select(platform).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No manifest for platform {} in image index of {} in OCI layout {}",
+                    platform,
+                    image_ref,
+                    self.source
+                )
+            })?;
+            if is_index(&entry.media_type) {
+                anyhow::bail!(
+                    "Image index entry {} in OCI layout {} is not an image manifest",
+                    entry.digest,
+                    self.source
+                );
+            }
+            debug!(image = %image_ref, %platform, digest = %entry.digest, "Selected manifest from image index");
+            self.read_blob(&entry.digest, entry.size).await?
+        } else {
+            self.read_blob(&image.digest, image.size).await?
+        };
+        let manifest: OciManifest = serde_json::from_slice(&manifest)?;
+        let layer: &OciDescriptor = manifest
+            .layers
+            .iter()
+            .find(|l| decompressed_media_type(&l.media_type) == WASM_LAYER_MEDIA_TYPE)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Image {} in OCI layout {} has no module layer",
+                    image_ref,
+                    self.source
+                )
+            })?;
+        let data = self.read_blob(&layer.digest, layer.size).await?;
+        debug!(image = %image_ref, layout = %self.source, digest = %layer.digest, "Loaded module from OCI layout");
+        decompress(
+            &layer.media_type,
+            &data,
+            DEFAULT_MAX_DECOMPRESSED_LAYER_SIZE,
+        )
+    }
+
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        self.find(image_ref).is_some()
+    }
+}
+
+impl InterceptingStore for OciLayoutStore {
+    fn intercepts(&self, image_ref: &Reference) -> bool {
+        self.find(image_ref).is_some()
+    }
+}
+
+/// The store to fetch the modules of the pod's containers from. If the pod names an OCI layout
+/// with the [`OCI_LAYOUT_ANNOTATION`], the images in the layout are loaded from it and the
+/// others from the given store. Images in the layout that are image indexes are loaded from
+/// their manifest for `platform`, or for the platform the pod asks for with the
+/// [`PLATFORM_ANNOTATION`](super::PLATFORM_ANNOTATION), which images from the given store are
+/// got for too.
+///
+/// The layout has to be under `layout_root`, the directory the operator sideloads layouts into.
+/// Pods can't load modules from layouts if it is `None`.
+pub async fn pod_store(
+    store: Arc<dyn Store + Send + Sync>,
+    pod: &Pod,
+    platform: Option<Platform>,
+    layout_root: Option<&Path>,
+) -> anyhow::Result<Arc<dyn Store + Send + Sync>> {
+    let (store, platform) = match super::pod_platform(pod)? {
+        Some(pod_platform) => {
+            let store: Arc<dyn Store + Send + Sync> =
+                Arc::new(PlatformStore::new(store, pod_platform.clone()));
+            (store, Some(pod_platform))
+        }
+        None => (store, platform),
+    };
+    match OciLayoutSource::from_pod(pod)? {
+        Some(source) => {
+            let root = layout_root.ok_or_else(|| {
+                anyhow::anyhow!("loading modules from OCI layouts is not enabled on this node")
+            })?;
+            let source = source.within(root).await?;
+            let layout = OciLayoutStore::open(source).await?.with_platform(platform);
+            Ok(store.with_override(Arc::new(layout)))
+        }
+        None => Ok(store),
+    }
+}
+
+fn is_index(media_type: &str) -> bool {
+    media_type == OCI_IMAGE_INDEX_MEDIA_TYPE || media_type == IMAGE_MANIFEST_LIST_MEDIA_TYPE
+}
+
+fn is_alphanumeric(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Read the contents of the named file in a tar archive, skipping over the other entries.
+/// Long names in GNU and PAX extended headers are supported.
+async fn read_archive_entry(archive: &Path, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(archive).await?;
+    let mut long_name = None;
+    loop {
+        let mut header = [0; 512];
+        match file.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        // The archive ends with empty blocks
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = entry_size(&header[124..136])?;
+        let padding = (512 - size % 512) % 512;
+        let entry_name = long_name.take().unwrap_or_else(|| header_name(&header));
+        match header[156] {
+            // GNU long name of the next entry
+            b'L' => {
+                let data = read_entry(&mut file, size, padding).await?;
+                long_name = Some(
+                    String::from_utf8_lossy(&data)
+                        .trim_end_matches('\0')
+                        .to_owned(),
+                );
+            }
+            // PAX extended header of the next entry
+            b'x' => {
+                let data = read_entry(&mut file, size, padding).await?;
+                long_name = pax_path(&data);
+            }
+            b'0' | b'\0' if entry_name.trim_start_matches("./") == name => {
+                return read_entry(&mut file, size, padding).await;
+            }
+            _ => {
+                file.seek(SeekFrom::Current((size + padding) as i64))
+                    .await?;
+            }
+        }
+    }
+    anyhow::bail!("{} not found in archive {}", name, archive.display())
+}
+
+/// Read the contents of the entry whose header was just read, and skip its padding.
+async fn read_entry(
+    file: &mut tokio::fs::File,
+    size: u64,
+    padding: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data).await?;
+    file.seek(SeekFrom::Current(padding as i64)).await?;
+    Ok(data)
+}
+
+/// The name of an entry from its header, including the prefix of ustar headers.
+fn header_name(header: &[u8; 512]) -> String {
+    let field = |bytes: &[u8]| {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    let name = field(&header[..100]);
+    if &header[257..262] == b"ustar" {
+        let prefix = field(&header[345..500]);
+        if !prefix.is_empty() {
+            return format!("{}/{}", prefix, name);
+        }
+    }
+    name
+}
+
+/// The size of an entry, in octal or, for large entries, in base-256.
+fn entry_size(field: &[u8]) -> anyhow::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |size, b| {
+                (size << 8) | u64::from(*b)
+            }));
+    }
+    let octal = String::from_utf8_lossy(field);
+    let octal = octal.trim_matches(|c: char| c == '\0' || c == ' ');
+    if octal.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(octal, 8)
+        .map_err(|_| anyhow::anyhow!("Invalid entry size {:?} in tar archive", octal))
+}
+
+/// The path in a PAX extended header, made of `<length> <key>=<value>\n` records.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let data = String::from_utf8_lossy(data);
+    data.lines().find_map(|record| {
+        let (_, field) = record.split_once(' ')?;
+        field.strip_prefix("path=").map(|path| path.to_owned())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use kube::api::ObjectMeta;
+    use oci_distribution::manifest::OCI_IMAGE_MEDIA_TYPE;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", sha2::Sha256::digest(data))
+    }
+
+    /// The files of a layout with a single image, by path.
+    fn layout_files(ref_name: &str, module: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let blob = |data: &[u8]| {
+            (
+                format!("blobs/sha256/{}", &sha256(data)[7..]),
+                data.to_vec(),
+            )
+        };
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.wasm.config.v1+json",
+                "digest": sha256(b"{}"),
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": WASM_LAYER_MEDIA_TYPE,
+                "digest": sha256(MODULE),
+                "size": MODULE.len(),
+            }],
+        }))
+        .unwrap();
+        let index = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": OCI_IMAGE_MEDIA_TYPE,
+                "digest": sha256(&manifest),
+                "size": manifest.len(),
+                "annotations": { REF_NAME_ANNOTATION: ref_name },
+            }],
+        }))
+        .unwrap();
+        vec![
+            (
+                "oci-layout".to_owned(),
+                br#"{"imageLayoutVersion":"1.0.0"}"#.to_vec(),
+            ),
+            ("index.json".to_owned(), index),
+            blob(&manifest),
+            blob(b"{}"),
+            // Stored under the digest of the expected module, whatever it contains
+            (
+                format!("blobs/sha256/{}", &sha256(MODULE)[7..]),
+                module.to_vec(),
+            ),
+        ]
+    }
+
+    fn write_dir(dir: &Path, files: &[(String, Vec<u8>)]) {
+        for (name, data) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+    }
+
+    fn tar(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, data) in files {
+            // Long names go in a PAX extended header, as Go's archive/tar writes them
+            let name = format!("./{}", name);
+            if name.len() > 100 {
+                let record = format!(" path={}\n", name);
+                let record = format!("{}{}", record.len() + 3, record);
+                archive.extend_from_slice(&tar_header("PaxHeaders/entry", record.len(), b'x'));
+                archive.extend(record.as_bytes());
+                archive.resize((archive.len() + 511) / 512 * 512, 0);
+                archive.extend_from_slice(&tar_header("", data.len(), b'0'));
+            } else {
+                archive.extend_from_slice(&tar_header(&name, data.len(), b'0'));
+            }
+            archive.extend(data);
+            archive.resize((archive.len() + 511) / 512 * 512, 0);
+        }
+        archive.extend(&[0; 1024][..]);
+        archive
+    }
+
+    fn tar_header(name: &str, size: usize, typeflag: u8) -> [u8; 512] {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    fn reference(image: &str) -> Reference {
+        Reference::try_from(image).unwrap()
+    }
+
+    async fn get(store: &OciLayoutStore, image: &str) -> anyhow::Result<Vec<u8>> {
+        store
+            .get(
+                &reference(image),
+                PullPolicy::IfNotPresent,
+                &RegistryCredentials::anonymous(),
+            )
+            .await
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            OciLayoutSource::Dir(PathBuf::from("/mnt/usb/apps")),
+            OciLayoutSource::parse("oci-dir:/mnt/usb/apps").unwrap()
+        );
+        assert_eq!(
+            OciLayoutSource::Archive(PathBuf::from("/mnt/usb/apps.tar")),
+            OciLayoutSource::parse(" oci-archive:/mnt/usb/apps.tar").unwrap()
+        );
+        assert!(OciLayoutSource::parse("/mnt/usb/apps").is_err());
+        assert!(OciLayoutSource::parse("oci-dir:").is_err());
+
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                annotations: Some(
+                    vec![(
+                        OCI_LAYOUT_ANNOTATION.to_owned(),
+                        "oci-dir:/mnt/usb/apps".to_owned(),
+                    )]
+                    .into_iter()
+                    .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert!(OciLayoutSource::from_pod(&pod).unwrap().is_some());
+        assert!(OciLayoutSource::from_pod(&Pod::from(KubePod::default()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_layout_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        write_dir(dir.path(), &layout_files("example.com/app:v1", MODULE));
+        let store = OciLayoutStore::open(OciLayoutSource::Dir(dir.path().to_owned()))
+            .await
+            .unwrap();
+
+        assert_eq!(MODULE, get(&store, "example.com/app:v1").await.unwrap());
+        assert!(store.intercepts(&reference("example.com/app:v1")));
+        assert!(!store.intercepts(&reference("example.com/app:v2")));
+        assert!(get(&store, "example.com/app:v2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_layout_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("apps.tar");
+        // Just the tag, as skopeo names images, and a path long enough for a PAX header
+        let mut files = layout_files("v1", MODULE);
+        files.push((format!("blobs/sha256/{}", "0".repeat(100)), vec![1; 600]));
+        files.rotate_right(1);
+        std::fs::write(&archive, tar(&files)).unwrap();
+        let store = OciLayoutStore::open(OciLayoutSource::Archive(archive))
+            .await
+            .unwrap();
+
+        assert_eq!(MODULE, get(&store, "example.com/app:v1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_layout_blob_must_match_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        write_dir(dir.path(), &layout_files("v1", b"\0asm\x01\0\0\x01"));
+        let store = OciLayoutStore::open(OciLayoutSource::Dir(dir.path().to_owned()))
+            .await
+            .unwrap();
+
+        let error = get(&store, "example.com/app:v1").await.unwrap_err();
+        assert!(error.is::<DigestMismatchError>());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_layout_must_be_within_root() {
+        let root = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        let layout = root.path().join("apps");
+        write_dir(&layout, &layout_files("v1", MODULE));
+        std::fs::create_dir(root.path().join("other")).unwrap();
+
+        let source = OciLayoutSource::Dir(root.path().join("other/../apps"))
+            .within(root.path())
+            .await
+            .unwrap();
+        assert_eq!(
+            OciLayoutSource::Dir(tokio::fs::canonicalize(&layout).await.unwrap()),
+            source
+        );
+        assert!(OciLayoutSource::Dir(root.path().join("apps/../.."))
+            .within(root.path())
+            .await
+            .is_err());
+        assert!(OciLayoutSource::Archive(other.path().join("apps.tar"))
+            .within(root.path())
+            .await
+            .is_err());
+
+        // Nor may the layout link to files outside of it
+        let secret = other.path().join("secret");
+        std::fs::write(&secret, b"secret").unwrap();
+        std::os::unix::fs::symlink(&secret, layout.join("blobs/sha256/link")).unwrap();
+        assert!(source.read("blobs/sha256/link").await.is_err());
+        assert!(source.read("index.json").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_layout_image_index() {
+        // Move the image into an image index for several platforms
+        let mut files = layout_files("v1", MODULE);
+        let index: serde_json::Value = serde_json::from_slice(&files[1].1).unwrap();
+        let mut image = index["manifests"][0].clone();
+        image["annotations"].take();
+        let mut other = image.clone();
+        image["platform"] = serde_json::json!({ "os": "wasi", "architecture": "wasm" });
+        other["platform"] = serde_json::json!({ "os": "linux", "architecture": "amd64" });
+        other["digest"] = serde_json::json!(sha256(b"missing"));
+        let image_index = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [other, image],
+        }))
+        .unwrap();
+        files[1].1 = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": OCI_IMAGE_INDEX_MEDIA_TYPE,
+                "digest": sha256(&image_index),
+                "size": image_index.len(),
+                "annotations": { REF_NAME_ANNOTATION: "v1" },
+            }],
+        }))
+        .unwrap();
+        files.push((
+            format!("blobs/sha256/{}", &sha256(&image_index)[7..]),
+            image_index,
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        write_dir(dir.path(), &files);
+        let source = OciLayoutSource::Dir(dir.path().to_owned());
+
+        let store = OciLayoutStore::open(source.clone()).await.unwrap();
+        assert!(get(&store, "example.com/app:v1").await.is_err());
+        let store = store.with_platform(Some(Platform::new("wasi", "wasm")));
+        assert_eq!(MODULE, get(&store, "example.com/app:v1").await.unwrap());
+        let store = OciLayoutStore::open(source)
+            .await
+            .unwrap()
+            .with_platform(Some(Platform::new("linux", "arm64")));
+        assert!(get(&store, "example.com/app:v1").await.is_err());
+    }
+}
@@ -1,6 +1,52 @@
 //! `oci` implements different storage methods for fetching modules from an OCI registry.
 mod client;
+mod failover;
 mod file;
+mod layout;
+mod platform;
 
 pub use client::Client;
+pub use failover::FailoverClient;
 pub use file::FileStore;
+pub use layout::{pod_store, OciLayoutSource, OciLayoutStore, OCI_LAYOUT_ANNOTATION};
+pub use platform::{pod_platform, PLATFORM_ANNOTATION};
+
+use oci_distribution::manifest::Platform;
+
+/// Returns the platform to select from image indexes for a provider with the given
+/// [`ARCH`](crate::provider::Provider::ARCH), if it can be determined.
+///
+/// `ARCH` is expected to be in the `<cpu>-<os>` form of a target triple such as
+/// `wasm32-wasi`, which is translated to the `os/architecture` naming used in OCI
+/// image indexes (`wasi/wasm`).
+pub fn platform_for_arch(arch: &str) -> Option<Platform> {
+    let (cpu, os) = arch.split_once('-')?;
+    let architecture = match cpu {
+        "wasm32" => "wasm",
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        cpu => cpu,
+    };
+    if os.is_empty() {
+        return None;
+    }
+    Some(Platform::new(os, architecture))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_platform_for_arch() {
+        assert_eq!(
+            Some(Platform::new("wasi", "wasm")),
+            platform_for_arch("wasm32-wasi")
+        );
+        assert_eq!(
+            Some(Platform::new("linux", "amd64")),
+            platform_for_arch("x86_64-linux")
+        );
+        assert_eq!(None, platform_for_arch("mock"));
+    }
+}
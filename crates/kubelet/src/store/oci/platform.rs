@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use oci_distribution::manifest::Platform;
+use oci_distribution::Reference;
+
+use crate::container::PullPolicy;
+use crate::pod::Pod;
+use crate::secret::RegistryCredentials;
+use crate::store::Store;
+
+/// The annotation a pod asks for the modules of another platform than the node's with, when its
+/// images are image indexes. The platform is given in the `os/architecture[/variant]` form, such
+/// as `wasi/wasm`.
+pub const PLATFORM_ANNOTATION: &str = "krustlet.dev/platform";
+
+/// The platform the pod asks for with the [`PLATFORM_ANNOTATION`], if any.
+pub fn pod_platform(pod: &Pod) -> anyhow::Result<Option<Platform>> {
+    pod.annotation_text(PLATFORM_ANNOTATION)
+        .map(Platform::parse)
+        .transpose()
+}
+
+lazy_static::lazy_static! {
+    /// The references by digest the platforms' modules of images were last got by, keyed by
+    /// the image reference and the platform, so that modules that are already on the node are
+    /// found again without contacting their registry.
+    static ref PLATFORM_REFERENCES: Mutex<HashMap<(String, String), Reference>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A store that gets the modules of images that are image indexes for the given platform, by
+/// getting them by the digest of the platform's manifest, which the base store looks up with
+/// [`Store::platform_reference`]. The lookup is skipped for modules that were got for the
+/// platform before and are still in the base store, unless the pull policy is `Always`.
+/// Modules with the `Never` pull policy that weren't got for the platform before are got by
+/// their reference as is, as looking up the manifest contacts the registry.
+pub(super) struct PlatformStore {
+    base: Arc<dyn Store + Send + Sync>,
+    platform: Platform,
+}
+
+impl PlatformStore {
+    pub(super) fn new(base: Arc<dyn Store + Send + Sync>, platform: Platform) -> Self {
+        PlatformStore { base, platform }
+    }
+
+    fn key(&self, image_ref: &Reference) -> (String, String) {
+        (image_ref.whole(), self.platform.to_string())
+    }
+
+    /// The reference the platform's module was last got by, if any.
+    fn known_reference(&self, image_ref: &Reference) -> Option<Reference> {
+        let references = PLATFORM_REFERENCES
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        references.get(&self.key(image_ref)).cloned()
+    }
+
+    async fn reference(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Reference> {
+        match (pull_policy, self.known_reference(image_ref)) {
+            (PullPolicy::Never, known) => return Ok(known.unwrap_or_else(|| image_ref.clone())),
+            (PullPolicy::IfNotPresent, Some(known)) => {
+                if self.base.is_present(&known).await {
+                    return Ok(known);
+                }
+            }
+            _ => (),
+        }
+        // Images that aren't image indexes are got by their reference as is
+        let reference = self
+            .base
+            .platform_reference(image_ref, &self.platform, auth)
+            .await?
+            .unwrap_or_else(|| image_ref.clone());
+        PLATFORM_REFERENCES
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(self.key(image_ref), reference.clone());
+        Ok(reference)
+    }
+}
+
+#[async_trait]
+impl Store for PlatformStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let image_ref = self.reference(image_ref, pull_policy, auth).await?;
+        self.base.get(&image_ref, pull_policy, auth).await
+    }
+
+    async fn resolve(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<()> {
+        let image_ref = self.reference(image_ref, pull_policy, auth).await?;
+        self.base.resolve(&image_ref, pull_policy, auth).await
+    }
+
+    async fn is_present(&self, image_ref: &Reference) -> bool {
+        match self.known_reference(image_ref) {
+            Some(known) => self.base.is_present(&known).await,
+            None => false,
+        }
+    }
+
+    async fn platform_reference(
+        &self,
+        image_ref: &Reference,
+        platform: &Platform,
+        auth: &RegistryCredentials,
+    ) -> anyhow::Result<Option<Reference>> {
+        self.base
+            .platform_reference(image_ref, platform, auth)
+            .await
+    }
+
+    async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+        let known = self.known_reference(image_ref)?;
+        self.base.module_path(&known).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::Pod as KubePod;
+    use serde_json::json;
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    const ARM64_DIGEST: &str =
+        "sha256:0000000000000000000000000000000000000000000000000000000000000001";
+
+    /// Has an image index with a manifest for `linux/arm64`, keeps the modules it is asked for
+    /// and counts the manifests it looks up.
+    #[derive(Default)]
+    struct FakeRegistry {
+        got: Mutex<Vec<String>>,
+        lookups: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl Store for FakeRegistry {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryCredentials,
+        ) -> anyhow::Result<Vec<u8>> {
+            self.got.lock().unwrap().push(image_ref.whole());
+            Ok(vec![1, 2, 3])
+        }
+
+        async fn is_present(&self, image_ref: &Reference) -> bool {
+            self.got.lock().unwrap().contains(&image_ref.whole())
+        }
+
+        async fn platform_reference(
+            &self,
+            image_ref: &Reference,
+            platform: &Platform,
+            _auth: &RegistryCredentials,
+        ) -> anyhow::Result<Option<Reference>> {
+            *self.lookups.lock().unwrap() += 1;
+            if *platform == Platform::new("linux", "arm64") {
+                Ok(Some(image_ref.clone_with_digest(ARM64_DIGEST.to_owned())))
+            } else {
+                Err(anyhow::anyhow!("no manifest for platform {}", platform))
+            }
+        }
+
+        async fn module_path(&self, image_ref: &Reference) -> Option<PathBuf> {
+            Some(PathBuf::from(image_ref.whole()))
+        }
+    }
+
+    #[test]
+    fn test_pod_platform() {
+        let pod = |annotations: serde_json::Value| {
+            Pod::from(
+                serde_json::from_value::<KubePod>(json!({
+                    "metadata": { "name": "app", "annotations": annotations }
+                }))
+                .unwrap(),
+            )
+        };
+        assert_eq!(
+            Some(Platform::new("linux", "arm64")),
+            pod_platform(&pod(json!({ PLATFORM_ANNOTATION: "linux/arm64" }))).unwrap()
+        );
+        assert_eq!(None, pod_platform(&pod(json!({}))).unwrap());
+        assert!(pod_platform(&pod(json!({ PLATFORM_ANNOTATION: "arm64" }))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_modules_are_got_for_the_platform() {
+        let registry = Arc::new(FakeRegistry::default());
+        let image_ref = Reference::try_from("webassembly.azurecr.io/hello:v1").unwrap();
+        let by_digest = format!("webassembly.azurecr.io/hello@{}", ARM64_DIGEST);
+        let auth = RegistryCredentials::anonymous();
+
+        let store = PlatformStore::new(registry.clone(), Platform::new("linux", "arm64"));
+        // Modules that may not be pulled and weren't got for the platform before are got by
+        // their reference as is
+        store
+            .get(&image_ref, PullPolicy::Never, &auth)
+            .await
+            .unwrap();
+        assert!(!store.is_present(&image_ref).await);
+        assert_eq!(None, store.module_path(&image_ref).await);
+
+        store
+            .get(&image_ref, PullPolicy::IfNotPresent, &auth)
+            .await
+            .unwrap();
+        assert!(store.is_present(&image_ref).await);
+        assert_eq!(
+            Some(PathBuf::from(&by_digest)),
+            store.module_path(&image_ref).await
+        );
+        // The manifest isn't looked up again for modules that are present
+        store
+            .get(&image_ref, PullPolicy::IfNotPresent, &auth)
+            .await
+            .unwrap();
+        store
+            .get(&image_ref, PullPolicy::Never, &auth)
+            .await
+            .unwrap();
+        assert_eq!(1, *registry.lookups.lock().unwrap());
+        store
+            .get(&image_ref, PullPolicy::Always, &auth)
+            .await
+            .unwrap();
+        assert_eq!(2, *registry.lookups.lock().unwrap());
+        assert_eq!(
+            *registry.got.lock().unwrap(),
+            vec![
+                "webassembly.azurecr.io/hello:v1".to_owned(),
+                by_digest.clone(),
+                by_digest.clone(),
+                by_digest.clone(),
+                by_digest,
+            ]
+        );
+
+        let store = PlatformStore::new(registry, Platform::new("wasi", "wasm"));
+        assert!(store
+            .get(&image_ref, PullPolicy::Always, &auth)
+            .await
+            .is_err());
+    }
+}
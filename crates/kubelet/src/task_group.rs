@@ -0,0 +1,215 @@
+//! `task_group` tracks the tasks spawned on behalf of something, such as a pod or a container, so
+//! that none of them is left running detached once their owner is gone.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// A group of tasks owned by whoever spawned them.
+///
+/// Tasks that fail or panic are logged with the names of the group and the task, rather than
+/// their errors being lost with a dropped `JoinHandle`. The owner can [join](Self::join) the
+/// tasks, or [shut them down](Self::shutdown) by waiting for them for a while before aborting
+/// the rest. Tasks still running when the last clone of the group is dropped are aborted, so
+/// tasks never outlive their owner. Clones share the same tasks.
+#[derive(Clone)]
+pub struct TaskGroup {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    name: String,
+    tasks: Mutex<HashMap<u64, JoinHandle<()>>>,
+    next_id: AtomicU64,
+    /// The number of tasks in the group, for waiting on them to finish.
+    count: (watch::Sender<usize>, watch::Receiver<usize>),
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for (_, task) in self.tasks.get_mut().unwrap().drain() {
+            task.abort();
+        }
+    }
+}
+
+impl std::fmt::Debug for TaskGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TaskGroup")
+            .field("name", &self.inner.name)
+            .field("tasks", &self.len())
+            .finish()
+    }
+}
+
+impl TaskGroup {
+    /// Create an empty group. The name identifies its tasks in logs.
+    pub fn new(name: impl Into<String>) -> Self {
+        TaskGroup {
+            inner: Arc::new(Inner {
+                name: name.into(),
+                tasks: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                count: watch::channel(0),
+            }),
+        }
+    }
+
+    /// Spawn a task in the group. An error returned by the task is logged along with the name
+    /// of the task.
+    pub fn spawn<F>(&self, name: impl Into<String>, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let membership = Membership {
+            group: Arc::downgrade(&self.inner),
+            group_name: self.inner.name.clone(),
+            task_name: name.into(),
+            id,
+        };
+        // The task can't remove itself from the group before it has been added
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = task.await {
+                error!(
+                    group = %membership.group_name,
+                    task = %membership.task_name,
+                    error = %e,
+                    "Task failed"
+                );
+            }
+            drop(membership);
+        });
+        tasks.insert(id, handle);
+        self.inner.count.0.send(tasks.len()).ok();
+    }
+
+    /// The number of tasks in the group that haven't finished.
+    pub fn len(&self) -> usize {
+        self.inner.tasks.lock().unwrap().len()
+    }
+
+    /// Whether every task in the group has finished.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait for every task in the group to finish, including tasks spawned while waiting.
+    pub async fn join(&self) {
+        let mut count = self.inner.count.1.clone();
+        while *count.borrow() > 0 {
+            // The group keeps a sender, so this can't fail
+            if count.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for every task in the group to finish, then abort the tasks that
+    /// haven't.
+    pub async fn shutdown(&self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.join()).await.is_err() {
+            warn!(
+                group = %self.inner.name,
+                tasks = self.len(),
+                "Tasks did not finish in time, aborting them"
+            );
+            self.abort();
+        }
+    }
+
+    /// Abort every task in the group.
+    pub fn abort(&self) {
+        let tasks = std::mem::take(&mut *self.inner.tasks.lock().unwrap());
+        self.inner.count.0.send(0).ok();
+        for (_, task) in tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Removes a task from its group once it has finished, panicked or been aborted.
+struct Membership {
+    group: Weak<Inner>,
+    group_name: String,
+    task_name: String,
+    id: u64,
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            error!(group = %self.group_name, task = %self.task_name, "Task panicked");
+        }
+        if let Some(group) = self.group.upgrade() {
+            let mut tasks = group.tasks.lock().unwrap();
+            if tasks.remove(&self.id).is_some() {
+                group.count.0.send(tasks.len()).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn panics() -> anyhow::Result<()> {
+        panic!("panicked")
+    }
+
+    #[tokio::test]
+    async fn test_join_waits_for_tasks() {
+        let group = TaskGroup::new("test");
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        group.spawn("waiting", async move {
+            rx.await?;
+            Ok(())
+        });
+        group.spawn("failing", async { Err(anyhow::anyhow!("failed")) });
+        group.spawn("panicking", panics());
+
+        // Failed and panicked tasks leave the group, the one still running holds up joining it
+        let joined = tokio::time::timeout(Duration::from_millis(50), group.join()).await;
+        assert!(joined.is_err());
+        assert_eq!(1, group.len());
+
+        tx.send(()).unwrap();
+        group.join().await;
+        assert!(group.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_unfinished_tasks() {
+        let group = TaskGroup::new("test");
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        group.spawn("stuck", async move {
+            let _tx = tx;
+            futures::future::pending::<()>().await;
+            Ok(())
+        });
+        group.shutdown(Duration::from_millis(10)).await;
+        assert!(group.is_empty());
+        // The aborted task drops its sender
+        assert!(rx.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_group_aborts_tasks() {
+        let group = TaskGroup::new("test");
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        group.spawn("stuck", async move {
+            let _tx = tx;
+            futures::future::pending::<()>().await;
+            Ok(())
+        });
+        drop(group);
+        assert!(rx.await.is_err());
+    }
+}
@@ -12,6 +12,7 @@ pub struct ConfigMapVolume {
     client: kube::Api<ConfigMap>,
     items: Option<Vec<KeyToPath>>,
     mounted_path: Option<PathBuf>,
+    checksums: FileChecksums,
 }
 
 impl ConfigMapVolume {
@@ -30,6 +31,7 @@ impl ConfigMapVolume {
             client: Api::namespaced(client, namespace),
             items: cm_source.items.clone(),
             mounted_path: None,
+            checksums: FileChecksums::default(),
         })
     }
 
@@ -39,6 +41,11 @@ impl ConfigMapVolume {
         self.mounted_path.as_deref()
     }
 
+    /// Returns the checksums of the files written when the volume was mounted
+    pub fn checksums(&self) -> &FileChecksums {
+        &self.checksums
+    }
+
     /// Mounts the ConfigMap volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
@@ -46,33 +53,24 @@ impl ConfigMapVolume {
         let path = base_path.as_ref().join(&self.vol_name);
         tokio::fs::create_dir_all(&path).await?;
 
-        let binary_data = config_map.binary_data.unwrap_or_default();
-        let binary_data = binary_data
+        let binary_data = config_map
+            .binary_data
+            .unwrap_or_default()
             .into_iter()
-            .filter_map(
-                |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
-                    ItemMount::DoNotMount => None,
-                },
-            )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        let binary_data = futures::future::join_all(binary_data);
-
-        let data = config_map.data.unwrap_or_default();
-        let data = data
+            .map(|(key, ByteString(data))| (key, data));
+        let data = config_map
+            .data
+            .unwrap_or_default()
             .into_iter()
+            .map(|(key, data)| (key, data.into_bytes()));
+        let files = binary_data
+            .chain(data)
             .filter_map(|(key, data)| match mount_setting_for(&key, &self.items) {
-                ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
+                ItemMount::MountAt(mount_path) => Some((PathBuf::from(mount_path), data)),
                 ItemMount::DoNotMount => None,
             })
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        let data = futures::future::join_all(data);
-
-        let (binary_data, data) = futures::future::join(binary_data, data).await;
-        binary_data
-            .into_iter()
-            .chain(data)
-            .collect::<tokio::io::Result<_>>()?;
+            .collect();
+        self.checksums = files::write_files(&path, files).await?;
 
         // Set configmap directory to read-only.
         let mut perms = tokio::fs::metadata(&path).await?.permissions();
@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::{
+    DownwardAPIVolumeFile, Node as KubeNode, ResourceFieldSelector, Volume as KubeVolume,
+};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::Api;
+use tracing::warn;
+
+use super::*;
+use crate::resources::util::parse_quantity;
+
+/// A type that can manage a downwardAPI volume with mounting and unmounting support. The volume
+/// has a file for each of its items, holding a field of the pod or a resource limit or request
+/// of one of its containers.
+pub struct DownwardApiVolume {
+    vol_name: String,
+    pod: Pod,
+    client: kube::Client,
+    items: Vec<DownwardAPIVolumeFile>,
+    mounted_path: Option<PathBuf>,
+    checksums: FileChecksums,
+}
+
+impl DownwardApiVolume {
+    /// Creates a new downwardAPI volume from a Kubernetes volume object. Passing a
+    /// non-downwardAPI volume type will result in an error. The client is used to look up the
+    /// node's allocatable resources for limits the pod's containers don't set.
+    pub fn new(vol: &KubeVolume, pod: &Pod, client: kube::Client) -> anyhow::Result<Self> {
+        let source = vol.downward_api.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called a downwardAPI volume constructor with a non-downwardAPI volume")
+        })?;
+        Ok(DownwardApiVolume {
+            vol_name: vol.name.clone(),
+            pod: pod.clone(),
+            client,
+            items: source.items.clone().unwrap_or_default(),
+            mounted_path: None,
+            checksums: FileChecksums::default(),
+        })
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Returns the checksums of the files written when the volume was mounted
+    pub fn checksums(&self) -> &FileChecksums {
+        &self.checksums
+    }
+
+    /// Mounts the downwardAPI volume in the given directory. The actual path will be
+    /// $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let files = downward_api_files(&self.pod, &self.items, &self.client).await?;
+        let path = base_path.as_ref().join(&self.vol_name);
+        tokio::fs::create_dir_all(&path).await?;
+        self.checksums = files::write_files(&path, files).await?;
+
+        // Set downwardAPI directory to read-only.
+        let mut perms = tokio::fs::metadata(&path).await?.permissions();
+        perms.set_readonly(true);
+        tokio::fs::set_permissions(&path, perms).await?;
+
+        self.mounted_path = Some(path);
+
+        Ok(())
+    }
+
+    /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
+    /// hasn't been mounted will log a warning, but otherwise not error
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        match self.mounted_path.take() {
+            Some(p) => {
+                //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
+                #[cfg(target_family = "windows")]
+                tokio::task::spawn_blocking(|| remove_dir_all::remove_dir_all(p)).await??;
+
+                #[cfg(target_family = "unix")]
+                tokio::fs::remove_dir_all(p).await?;
+            }
+            None => {
+                warn!("Attempted to unmount downwardAPI directory that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files for the given downward API items of the pod, as paths relative to the volume and
+/// their contents. The node's allocatable resources are only looked up if a container doesn't
+/// set a limit an item asks for.
+pub(crate) async fn downward_api_files(
+    pod: &Pod,
+    items: &[DownwardAPIVolumeFile],
+    client: &kube::Client,
+) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let allocatable = if needs_node_allocatable(pod, items) {
+        node_allocatable(client, pod).await?
+    } else {
+        BTreeMap::new()
+    };
+    files_for_items(pod, items, &allocatable)
+}
+
+/// The files for the given downward API items of the pod, with limits that the pod's containers
+/// don't set taken from the node's `allocatable` resources.
+fn files_for_items(
+    pod: &Pod,
+    items: &[DownwardAPIVolumeFile],
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    items
+        .iter()
+        .map(|item| {
+            let value = match (&item.field_ref, &item.resource_field_ref) {
+                (Some(field), None) => field_value(pod, &field.field_path)?,
+                (None, Some(resource)) => resource_value(pod, resource, allocatable)?,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                    "downward API item {} must have exactly one of fieldRef and resourceFieldRef",
+                    item.path
+                ))
+                }
+            };
+            Ok((PathBuf::from(&item.path), value.into_bytes()))
+        })
+        .collect()
+}
+
+/// The value of a field of the pod, e.g. `metadata.name` or `metadata.labels`.
+fn field_value(pod: &Pod, field_path: &str) -> anyhow::Result<String> {
+    let subscript = |prefix: &str| {
+        field_path
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix("['"))
+            .and_then(|rest| rest.strip_suffix("']"))
+    };
+    let value = match field_path {
+        "metadata.labels" => format_map(pod.labels()),
+        "metadata.annotations" => format_map(pod.annotations()),
+        "metadata.uid" => pod.pod_uid().to_owned(),
+        _ => {
+            if let Some(key) = subscript("metadata.labels") {
+                pod.labels().get(key).cloned().unwrap_or_default()
+            } else if let Some(key) = subscript("metadata.annotations") {
+                pod.annotations().get(key).cloned().unwrap_or_default()
+            } else {
+                crate::provider::field_map(pod)
+                    .remove(field_path)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("unsupported downward API field {}", field_path)
+                    })?
+            }
+        }
+    };
+    Ok(value)
+}
+
+/// Labels and annotations are written one per line, as `key="value"` with the value quoted and
+/// escaped.
+fn format_map(map: &BTreeMap<String, String>) -> String {
+    map.iter()
+        .map(|(key, value)| format!("{}={:?}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether any of the items asks for a limit that its container doesn't set.
+fn needs_node_allocatable(pod: &Pod, items: &[DownwardAPIVolumeFile]) -> bool {
+    items
+        .iter()
+        .filter_map(|item| item.resource_field_ref.as_ref())
+        .any(|selector| {
+            let resource = match selector.resource.strip_prefix("limits.") {
+                Some(resource) => resource,
+                None => return false,
+            };
+            pod.all_containers()
+                .into_iter()
+                .find(|c| Some(c.name()) == selector.container_name.as_deref())
+                .map_or(false, |container| {
+                    container
+                        .resources()
+                        .and_then(|r| r.limits.as_ref())
+                        .map_or(true, |limits| !limits.contains_key(resource))
+                })
+        })
+}
+
+/// The allocatable resources of the node the pod is scheduled to.
+async fn node_allocatable(
+    client: &kube::Client,
+    pod: &Pod,
+) -> anyhow::Result<BTreeMap<String, Quantity>> {
+    let node_name = pod
+        .node_name()
+        .ok_or_else(|| anyhow::anyhow!("pod is not scheduled to a node"))?;
+    let node = Api::<KubeNode>::all(client.clone()).get(node_name).await?;
+    Ok(node
+        .status
+        .and_then(|status| status.allocatable)
+        .unwrap_or_default())
+}
+
+/// The value of a resource limit or request of a container of the pod, e.g. `limits.memory`, in
+/// units of the selector's divisor and rounded up. As in Kubernetes, a limit the container
+/// doesn't set is the node's allocatable amount of the resource.
+fn resource_value(
+    pod: &Pod,
+    selector: &ResourceFieldSelector,
+    allocatable: &BTreeMap<String, Quantity>,
+) -> anyhow::Result<String> {
+    let container_name = selector.container_name.as_deref().ok_or_else(|| {
+        anyhow::anyhow!(
+            "downward API resource {} does not name a container",
+            selector.resource
+        )
+    })?;
+    let container = pod
+        .all_containers()
+        .into_iter()
+        .find(|c| c.name() == container_name)
+        .ok_or_else(|| anyhow::anyhow!("pod has no container named {}", container_name))?;
+    let mut parts = selector.resource.splitn(2, '.');
+    let (kind, resource) = match (parts.next(), parts.next()) {
+        (Some(kind), Some(resource)) if kind == "limits" || kind == "requests" => (kind, resource),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "unsupported downward API resource {}",
+                selector.resource
+            ))
+        }
+    };
+
+    let resources = container.resources();
+    let limits = resources.and_then(|r| r.limits.as_ref());
+    let requests = resources.and_then(|r| r.requests.as_ref());
+    let quantity = match kind {
+        // Requests default to the limit when only a limit is set
+        "requests" => requests
+            .and_then(|r| r.get(resource))
+            .or_else(|| limits.and_then(|l| l.get(resource)))
+            .map_or("0", |q| q.0.as_str()),
+        _ => limits
+            .and_then(|l| l.get(resource))
+            .or_else(|| allocatable.get(resource))
+            .map(|q| q.0.as_str())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "container {} has no {} limit, and the node has no allocatable {}",
+                    container_name,
+                    resource,
+                    resource
+                )
+            })?,
+    };
+    let value = parse_quantity(quantity)
+        .ok_or_else(|| anyhow::anyhow!("invalid quantity {} for {}", quantity, resource))?;
+    let divisor = match &selector.divisor {
+        Some(divisor) => parse_quantity(&divisor.0)
+            .filter(|d| *d > 0.0)
+            .ok_or_else(|| anyhow::anyhow!("invalid divisor {}", divisor.0))?,
+        None => 1.0,
+    };
+    Ok(format!("{}", (value / divisor).ceil()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{
+        Container as KubeContainer, ObjectFieldSelector, Pod as KubePod, PodSpec,
+        ResourceRequirements,
+    };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    fn pod() -> Pod {
+        let mut labels = BTreeMap::new();
+        labels.insert("app".to_owned(), "web".to_owned());
+        labels.insert("tier".to_owned(), "front \"end\"".to_owned());
+        let mut limits = BTreeMap::new();
+        limits.insert("memory".to_owned(), Quantity("64Mi".to_owned()));
+        limits.insert("cpu".to_owned(), Quantity("1500m".to_owned()));
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("web-1".to_owned()),
+                namespace: Some("apps".to_owned()),
+                labels: Some(labels),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![KubeContainer {
+                    name: "main".to_owned(),
+                    resources: Some(ResourceRequirements {
+                        limits: Some(limits),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn field(path: &str, field_path: &str) -> DownwardAPIVolumeFile {
+        DownwardAPIVolumeFile {
+            path: path.to_owned(),
+            field_ref: Some(ObjectFieldSelector {
+                field_path: field_path.to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn resource(path: &str, resource: &str, divisor: Option<&str>) -> DownwardAPIVolumeFile {
+        DownwardAPIVolumeFile {
+            path: path.to_owned(),
+            resource_field_ref: Some(ResourceFieldSelector {
+                container_name: Some("main".to_owned()),
+                resource: resource.to_owned(),
+                divisor: divisor.map(|d| Quantity(d.to_owned())),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn contents(files: &[(PathBuf, Vec<u8>)], path: &str) -> String {
+        let (_, data) = files
+            .iter()
+            .find(|(p, _)| p == Path::new(path))
+            .expect("file exists");
+        String::from_utf8(data.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_downward_api_fields() {
+        let files = files_for_items(
+            &pod(),
+            &[
+                field("name", "metadata.name"),
+                field("namespace", "metadata.namespace"),
+                field("labels", "metadata.labels"),
+                field("app", "metadata.labels['app']"),
+            ],
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        assert_eq!("web-1", contents(&files, "name"));
+        assert_eq!("apps", contents(&files, "namespace"));
+        assert_eq!(
+            "app=\"web\"\ntier=\"front \\\"end\\\"\"",
+            contents(&files, "labels")
+        );
+        assert_eq!("web", contents(&files, "app"));
+
+        assert!(files_for_items(&pod(), &[field("x", "spec.nodeName")], &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_downward_api_resources() {
+        let files = files_for_items(
+            &pod(),
+            &[
+                resource("memory", "limits.memory", Some("1Mi")),
+                resource("cpu", "limits.cpu", None),
+                resource("millicores", "requests.cpu", Some("1m")),
+            ],
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        assert_eq!("64", contents(&files, "memory"));
+        assert_eq!("2", contents(&files, "cpu"));
+        assert_eq!("1500", contents(&files, "millicores"));
+        assert!(!needs_node_allocatable(
+            &pod(),
+            &[resource("memory", "limits.memory", None)]
+        ));
+
+        let no_limit = resource("storage", "limits.ephemeral-storage", Some("1Gi"));
+        assert!(needs_node_allocatable(&pod(), &[no_limit.clone()]));
+        assert!(files_for_items(&pod(), &[no_limit.clone()], &BTreeMap::new()).is_err());
+        let mut allocatable = BTreeMap::new();
+        allocatable.insert("ephemeral-storage".to_owned(), Quantity("20Gi".to_owned()));
+        let files = files_for_items(&pod(), &[no_limit], &allocatable).unwrap();
+        assert_eq!("20", contents(&files, "storage"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::path::Path;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{Pod as KubePod, Volume as KubeVolume};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+use super::*;
+use crate::resources::util::parse_quantity;
+
+/// How often the size of a disk backed volume with a size limit is checked.
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What backs an emptyDir volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Medium {
+    /// A directory on the node's disk
+    Disk,
+    /// A tmpfs, where the node supports mounting one
+    Memory,
+}
+
+/// A type that can manage an emptyDir volume with mounting and unmounting support. The volume
+/// starts out as an empty, writable directory that lives as long as the pod.
+///
+/// Volumes with `medium: Memory` are backed by a tmpfs on Linux, sized to the volume's size
+/// limit. Where a tmpfs can't be mounted, for example because the Kubelet isn't running as
+/// root, the volume falls back to a directory on disk. The size of disk backed volumes with a
+/// size limit is checked periodically while they are mounted, and the pod is deleted if the
+/// volume grows past the limit.
+pub struct EmptyDirVolume {
+    vol_name: String,
+    medium: Medium,
+    size_limit: Option<u64>,
+    pod_name: String,
+    pods: kube::Api<KubePod>,
+    mounted_path: Option<PathBuf>,
+    tmpfs: bool,
+    size_monitor: Option<JoinHandle<()>>,
+}
+
+impl EmptyDirVolume {
+    /// Creates a new emptyDir volume from a Kubernetes volume object. Passing a non-emptyDir
+    /// volume type will result in an error
+    pub fn new(vol: &KubeVolume, pod: &Pod, client: kube::Client) -> anyhow::Result<Self> {
+        let source = vol.empty_dir.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called an emptyDir volume constructor with a non-emptyDir volume")
+        })?;
+        let medium = match source.medium.as_deref().unwrap_or_default() {
+            "" => Medium::Disk,
+            "Memory" => Medium::Memory,
+            other => {
+                return Err(anyhow::anyhow!(
+                    "emptyDir medium {} is not supported: only the default and Memory are",
+                    other
+                ))
+            }
+        };
+        let size_limit = match &source.size_limit {
+            Some(quantity) => Some(
+                parse_quantity(&quantity.0)
+                    .filter(|size| *size >= 0.0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid emptyDir sizeLimit {}", quantity.0))?
+                    .ceil() as u64,
+            ),
+            None => None,
+        };
+        Ok(EmptyDirVolume {
+            vol_name: vol.name.clone(),
+            medium,
+            size_limit,
+            pod_name: pod.name().to_owned(),
+            pods: Api::namespaced(client, pod.namespace()),
+            mounted_path: None,
+            tmpfs: false,
+            size_monitor: None,
+        })
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Mounts the emptyDir volume in the given directory. The actual path will be
+    /// $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = base_path.as_ref().join(&self.vol_name);
+        tokio::fs::create_dir_all(&path).await?;
+
+        if self.medium == Medium::Memory {
+            match mount_tmpfs(&path, self.size_limit).await {
+                Ok(()) => self.tmpfs = true,
+                Err(e) => warn!(
+                    error = %e,
+                    volume = %self.vol_name,
+                    "Unable to mount tmpfs for emptyDir volume, using a directory on disk instead"
+                ),
+            }
+        }
+
+        // Any user the module runs as must be able to write to the volume
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o777)).await?;
+        }
+
+        if let (Some(limit), false) = (self.size_limit, self.tmpfs) {
+            self.size_monitor = Some(tokio::spawn(monitor_size(
+                path.clone(),
+                limit,
+                self.vol_name.clone(),
+                self.pod_name.clone(),
+                self.pods.clone(),
+            )));
+        }
+
+        self.mounted_path = Some(path);
+
+        Ok(())
+    }
+
+    /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
+    /// hasn't been mounted will log a warning, but otherwise not error
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        if let Some(monitor) = self.size_monitor.take() {
+            monitor.abort();
+        }
+        match self.mounted_path.take() {
+            Some(p) => {
+                if self.tmpfs {
+                    if let Err(e) = unmount_tmpfs(&p).await {
+                        self.mounted_path = Some(p);
+                        return Err(e);
+                    }
+                    self.tmpfs = false;
+                }
+
+                //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
+                #[cfg(target_family = "windows")]
+                tokio::task::spawn_blocking(|| remove_dir_all::remove_dir_all(p)).await??;
+
+                #[cfg(target_family = "unix")]
+                tokio::fs::remove_dir_all(p).await?;
+            }
+            None => {
+                warn!("Attempted to unmount emptyDir directory that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EmptyDirVolume {
+    fn drop(&mut self) {
+        if let Some(monitor) = &self.size_monitor {
+            monitor.abort();
+        }
+    }
+}
+
+/// Mount a tmpfs at the given path, limited to the given size if there is one.
+#[cfg(target_os = "linux")]
+async fn mount_tmpfs(path: &Path, size_limit: Option<u64>) -> anyhow::Result<()> {
+    let mut options = "mode=0777".to_owned();
+    if let Some(limit) = size_limit {
+        // A size of 0 means no limit to tmpfs, so the smallest limit is a page
+        options.push_str(&format!(",size={}", limit.max(1)));
+    }
+    run_mount_command(
+        tokio::process::Command::new("mount")
+            .args(&["-t", "tmpfs", "-o", &options, "tmpfs"])
+            .arg(path),
+    )
+    .await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn mount_tmpfs(_path: &Path, _size_limit: Option<u64>) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("tmpfs is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+async fn unmount_tmpfs(path: &Path) -> anyhow::Result<()> {
+    run_mount_command(tokio::process::Command::new("umount").arg(path)).await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn unmount_tmpfs(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn run_mount_command(command: &mut tokio::process::Command) -> anyhow::Result<()> {
+    let output = command.output().await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Check the size of the volume at `path` every `SIZE_CHECK_INTERVAL`, deleting the pod once it
+/// is larger than `limit` bytes.
+async fn monitor_size(
+    path: PathBuf,
+    limit: u64,
+    vol_name: String,
+    pod_name: String,
+    pods: kube::Api<KubePod>,
+) {
+    loop {
+        tokio::time::sleep(SIZE_CHECK_INTERVAL).await;
+        let size = match dir_size(&path).await {
+            Ok(size) => size,
+            Err(e) => {
+                debug!(error = %e, volume = %vol_name, "Unable to measure emptyDir volume");
+                continue;
+            }
+        };
+        if size > limit {
+            warn!(
+                volume = %vol_name,
+                pod_name = %pod_name,
+                size,
+                limit,
+                "emptyDir volume exceeds its size limit, deleting pod"
+            );
+            if let Err(e) = pods.delete(&pod_name, &Default::default()).await {
+                error!(error = %e, pod_name = %pod_name, "Unable to delete pod");
+                continue;
+            }
+            return;
+        }
+    }
+}
+
+/// The total size of the files under `path`, not following symlinks.
+async fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dir_size() {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join("a"), vec![0; 100])
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join("b/c"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("b/c/d"), vec![0; 50])
+            .await
+            .unwrap();
+        assert_eq!(150, dir_size(dir.path()).await.unwrap());
+    }
+}
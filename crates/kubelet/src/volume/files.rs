@@ -0,0 +1,198 @@
+//! Writes the files of volumes whose contents come from the API, such as ConfigMap, Secret,
+//! downward API and projected volumes.
+//!
+//! Modules often read these files as soon as they start, so each file is written to a
+//! temporary file, flushed to disk and checked against the checksum of its contents before it
+//! is renamed into place. A crash part way through a write leaves either the old file or the
+//! new one, never a partial one.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// The suffix of the temporary files that volume files are written to before they are renamed
+/// into place.
+const TEMP_SUFFIX: &str = ".krustlet-tmp";
+
+/// The SHA256 checksums of the files written to a volume, by their paths relative to the
+/// volume. Comparing them with the files a volume would have now tells which files need to be
+/// written again to bring the volume up to date.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileChecksums(BTreeMap<PathBuf, String>);
+
+impl FileChecksums {
+    /// The checksum of the file at `path`, relative to the volume, or `None` if no file was
+    /// written there.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&str> {
+        self.0.get(path.as_ref()).map(String::as_str)
+    }
+
+    /// The paths of the given files whose contents differ from the files that were written,
+    /// including files that weren't written at all.
+    pub fn changed<'a>(&self, files: &'a [(PathBuf, Vec<u8>)]) -> Vec<&'a Path> {
+        files
+            .iter()
+            .filter(|(path, data)| self.get(path) != Some(checksum(data).as_str()))
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// The paths of the files that were written but aren't among the given files.
+    pub fn removed(&self, files: &[(PathBuf, Vec<u8>)]) -> Vec<&Path> {
+        self.0
+            .keys()
+            .filter(|path| !files.iter().any(|(file, _)| file == *path))
+            .map(PathBuf::as_path)
+            .collect()
+    }
+
+    /// Iterate over the paths of the files and their checksums.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.0
+            .iter()
+            .map(|(path, checksum)| (path.as_path(), checksum.as_str()))
+    }
+}
+
+/// The hex encoded SHA256 checksum of the data.
+fn checksum(data: &[u8]) -> String {
+    format!("{:x}", sha2::Sha256::digest(data))
+}
+
+/// Write the files to `dir`, at their paths relative to it, creating any directories they are
+/// in. Returns the checksums of the files that were written.
+pub(crate) async fn write_files(
+    dir: &Path,
+    files: Vec<(PathBuf, Vec<u8>)>,
+) -> anyhow::Result<FileChecksums> {
+    let mut checksums = BTreeMap::new();
+    for (file, data) in files {
+        let checksum = write_file(&dir.join(&file), &data).await?;
+        checksums.insert(file, checksum);
+    }
+    Ok(FileChecksums(checksums))
+}
+
+/// Atomically replace the file at `path` with the data, returning its checksum. The data is
+/// written to a temporary file next to it, which is flushed to disk and verified against the
+/// checksum before it is renamed over `path`.
+async fn write_file(path: &Path, data: &[u8]) -> anyhow::Result<String> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{} is not in a directory", path.display()))?;
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    tokio::fs::create_dir_all(parent).await?;
+
+    let mut temp_name = file_name.to_owned();
+    temp_name.push(TEMP_SUFFIX);
+    let temp_path = parent.join(temp_name);
+    let expected = checksum(data);
+    if let Err(e) = write_verified(&temp_path, data, &expected).await {
+        if let Err(e) = tokio::fs::remove_file(&temp_path).await {
+            warn!(error = %e, path = %temp_path.display(), "Unable to remove temporary volume file");
+        }
+        return Err(e);
+    }
+    tokio::fs::rename(&temp_path, path).await?;
+    sync_dir(parent).await?;
+    Ok(expected)
+}
+
+async fn write_verified(path: &Path, data: &[u8], expected: &str) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    let written = checksum(&tokio::fs::read(path).await?);
+    if written != expected {
+        return Err(anyhow::anyhow!(
+            "{} has checksum {} after writing, expected {}",
+            path.display(),
+            written,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Flush the directory to disk, so that a file renamed into it is still there after a crash.
+#[cfg(target_family = "unix")]
+async fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Directories can't be opened to flush them on Windows, where renames are flushed with the
+/// file system's journal instead.
+#[cfg(target_family = "windows")]
+async fn sync_dir(_dir: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn files() -> Vec<(PathBuf, Vec<u8>)> {
+        vec![
+            (PathBuf::from("config.toml"), b"level = 1".to_vec()),
+            (PathBuf::from("nested/key"), b"secret".to_vec()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_write_files() {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join("config.toml"), b"old contents")
+            .await
+            .unwrap();
+
+        let checksums = write_files(dir.path(), files()).await.unwrap();
+
+        assert_eq!(
+            b"level = 1".to_vec(),
+            tokio::fs::read(dir.path().join("config.toml"))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            b"secret".to_vec(),
+            tokio::fs::read(dir.path().join("nested/key"))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            Some("2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"),
+            checksums.get("nested/key")
+        );
+        // No temporary files are left behind
+        let mut entries = tokio::fs::read_dir(dir.path()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checksums_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let checksums = write_files(dir.path(), files()).await.unwrap();
+
+        assert!(checksums.changed(&files()).is_empty());
+        assert!(checksums.removed(&files()).is_empty());
+
+        let updated = vec![
+            (PathBuf::from("config.toml"), b"level = 2".to_vec()),
+            (PathBuf::from("extra"), b"new".to_vec()),
+        ];
+        assert_eq!(
+            vec![Path::new("config.toml"), Path::new("extra")],
+            checksums.changed(&updated)
+        );
+        assert_eq!(vec![Path::new("nested/key")], checksums.removed(&updated));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::{Secret, Volume as KubeVolume};
+use tracing::{debug, warn};
+
+use super::*;
+
+/// A type that can manage a `flexVolume` volume by calling out to an executable plugin.
+///
+/// Plugins follow the Kubernetes FlexVolume calling convention: the plugin for the driver
+/// `vendor/driver` lives at `<plugins dir>/vendor~driver/driver` and is invoked as
+/// `<plugin> mount <mount dir> <json options>` and `<plugin> unmount <mount dir>`. It must print a
+/// JSON object with a `status` of `Success`, `Failure` or `Not supported`, and optionally a
+/// `message`, to stdout.
+pub struct FlexVolume {
+    vol_name: String,
+    driver: String,
+    plugin: PathBuf,
+    options: BTreeMap<String, String>,
+    secret: Option<(String, kube::Api<Secret>)>,
+    mounted_path: Option<PathBuf>,
+}
+
+/// The output of a FlexVolume plugin call.
+#[derive(Debug, serde::Deserialize)]
+struct DriverStatus {
+    status: String,
+    message: Option<String>,
+}
+
+impl FlexVolume {
+    /// Creates a new flex volume from a Kubernetes volume object, using the plugins in the given
+    /// directory. Passing a non-flexVolume volume type will result in an error
+    pub fn new(
+        vol: &KubeVolume,
+        pod: &Pod,
+        client: kube::Client,
+        plugins_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        let source = vol.flex_volume.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called a flex volume constructor with a non-flexVolume volume")
+        })?;
+        let plugin = plugin_path(plugins_dir, &source.driver)?;
+
+        let mut options = source.options.clone().unwrap_or_default();
+        if let Some(fs_type) = &source.fs_type {
+            options.insert("kubernetes.io/fsType".to_owned(), fs_type.clone());
+        }
+        let read_only = source.read_only.unwrap_or(false);
+        options.insert(
+            "kubernetes.io/readwrite".to_owned(),
+            if read_only { "ro" } else { "rw" }.to_owned(),
+        );
+        options.insert("kubernetes.io/pod.name".to_owned(), pod.name().to_owned());
+        options.insert(
+            "kubernetes.io/pod.namespace".to_owned(),
+            pod.namespace().to_owned(),
+        );
+        if let Some(uid) = &pod.as_kube_pod().metadata.uid {
+            options.insert("kubernetes.io/pod.uid".to_owned(), uid.clone());
+        }
+        options.insert("kubernetes.io/pvOrVolumeName".to_owned(), vol.name.clone());
+        if let Some(service_account) = pod.service_account_name() {
+            options.insert(
+                "kubernetes.io/serviceAccount.name".to_owned(),
+                service_account.to_owned(),
+            );
+        }
+
+        let secret = source
+            .secret_ref
+            .as_ref()
+            .and_then(|s| s.name.clone())
+            .map(|name| (name, Api::namespaced(client, pod.namespace())));
+
+        Ok(FlexVolume {
+            vol_name: vol.name.clone(),
+            driver: source.driver.clone(),
+            plugin,
+            options,
+            secret,
+            mounted_path: None,
+        })
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Mounts the flex volume in the given directory by calling the plugin. The actual path will
+    /// be $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = base_path.as_ref().join(&self.vol_name);
+        tokio::fs::create_dir_all(&path).await?;
+
+        let mut options = self.options.clone();
+        if let Some((name, client)) = &self.secret {
+            // Secret data is passed to the plugin base64 encoded, as the API returns it
+            let secret = client.get(name).await?;
+            for (key, value) in secret.data.unwrap_or_default() {
+                options.insert(
+                    format!("kubernetes.io/secret/{}", key),
+                    base64::encode(&value.0),
+                );
+            }
+        }
+
+        let path_arg = path.to_string_lossy().into_owned();
+        let options = serde_json::to_string(&options)?;
+        self.call(&["mount", &path_arg, &options]).await?;
+
+        self.mounted_path = Some(path);
+        Ok(())
+    }
+
+    /// Unmounts the volume by calling the plugin and removes the mount directory
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        match self.mounted_path.take() {
+            Some(p) => {
+                let path_arg = p.to_string_lossy().into_owned();
+                if let Err(e) = self.call(&["unmount", &path_arg]).await {
+                    self.mounted_path = Some(p);
+                    return Err(e);
+                }
+                // The plugin is responsible for cleaning up what it mounted, so only remove the
+                // directory if it is empty
+                if let Err(e) = tokio::fs::remove_dir(&p).await {
+                    warn!(error = %e, path = %p.display(), "Unable to remove flex volume directory");
+                }
+            }
+            None => {
+                warn!("Attempted to unmount flex volume that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+
+    async fn call(&self, args: &[&str]) -> anyhow::Result<()> {
+        debug!(driver = %self.driver, command = args[0], "Calling flex volume plugin");
+        let output = tokio::process::Command::new(&self.plugin)
+            .args(args)
+            .output()
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Unable to run flex volume plugin {}: {}",
+                    self.plugin.display(),
+                    e
+                )
+            })?;
+        let status: DriverStatus = serde_json::from_slice(&output.stdout).map_err(|e| {
+            anyhow::anyhow!(
+                "Flex volume driver {} returned invalid output ({}): {}",
+                self.driver,
+                e,
+                String::from_utf8_lossy(&output.stdout)
+            )
+        })?;
+        match status.status.as_str() {
+            "Success" => Ok(()),
+            other => Err(anyhow::anyhow!(
+                "Flex volume driver {} {} returned {}: {}",
+                self.driver,
+                args[0],
+                other,
+                status.message.unwrap_or_default()
+            )),
+        }
+    }
+}
+
+/// Returns the path of the plugin executable for the given driver.
+fn plugin_path(plugins_dir: &Path, driver: &str) -> anyhow::Result<PathBuf> {
+    let name = driver
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty() && !driver.contains(".."))
+        .ok_or_else(|| anyhow::anyhow!("Invalid flex volume driver name {}", driver))?;
+    Ok(plugins_dir.join(driver.replace('/', "~")).join(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plugin_path() {
+        let dir = Path::new("/plugins");
+        assert_eq!(
+            PathBuf::from("/plugins/example.com~s3/s3"),
+            plugin_path(dir, "example.com/s3").unwrap()
+        );
+        assert_eq!(
+            PathBuf::from("/plugins/local/local"),
+            plugin_path(dir, "local").unwrap()
+        );
+        assert!(plugin_path(dir, "example.com/").is_err());
+        assert!(plugin_path(dir, "../../bin/sh").is_err());
+    }
+}
@@ -8,19 +8,33 @@ use std::sync::Arc;
 use k8s_openapi::api::core::v1::KeyToPath;
 use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Secret, Volume as KubeVolume};
 use kube::api::Api;
-use tracing::error;
+use tracing::{error, info, warn};
 
 use crate::plugin_watcher::PluginRegistry;
-use crate::pod::Pod;
+use crate::pod::{Pod, RelatedObject};
 
 mod configmap;
+mod downwardapi;
+mod emptydir;
+mod files;
+mod flex;
 mod hostpath;
+mod ownership;
+mod path;
 mod persistentvolumeclaim;
+mod projected;
 mod secret;
 
 pub use configmap::ConfigMapVolume;
+pub use downwardapi::DownwardApiVolume;
+pub use emptydir::EmptyDirVolume;
+pub use files::FileChecksums;
+pub use flex::FlexVolume;
 pub use hostpath::HostPathVolume;
+pub use ownership::VolumeOwnership;
+pub use path::{guest_path, host_join, host_path, same_host_path, HostPathStyle};
 pub use persistentvolumeclaim::PvcVolume;
+pub use projected::ProjectedVolume;
 pub use secret::SecretVolume;
 
 /// type of volume
@@ -34,6 +48,15 @@ pub enum VolumeType {
     PersistentVolumeClaim(Option<PathBuf>),
     /// hostpath volume
     HostPath,
+    /// flexVolume volume handled by an executable plugin
+    Flex,
+    /// downwardAPI volume exposing fields and resources of the pod
+    DownwardApi,
+    /// emptyDir volume, on disk or in memory
+    EmptyDir,
+    /// projected volume combining ConfigMaps, Secrets, downward API fields and service account
+    /// tokens
+    Projected,
 }
 
 /// A reference to a volume that can be mounted and unmounted. A `VolumeRef` should be stored
@@ -48,14 +71,24 @@ pub enum VolumeRef {
     PersistentVolumeClaim(PvcVolume),
     /// hostpath volume
     HostPath(HostPathVolume),
+    /// flexVolume volume
+    Flex(FlexVolume),
+    /// projected volume
+    Projected(ProjectedVolume),
+    /// downwardAPI volume
+    DownwardApi(DownwardApiVolume),
+    /// emptyDir volume
+    EmptyDir(EmptyDirVolume),
 }
 
 impl VolumeRef {
-    /// Resolves the volumes for a pod.
+    /// Resolves the volumes for a pod. Flex volumes are only supported if a directory of volume
+    /// plugins is given.
     pub async fn volumes_from_pod(
         pod: &Pod,
         client: &kube::Client,
         plugin_registry: Option<Arc<PluginRegistry>>,
+        volume_plugins_dir: Option<&Path>,
     ) -> anyhow::Result<HashMap<String, Self>> {
         let zero_vec = Vec::with_capacity(0);
         let vols = pod
@@ -66,7 +99,7 @@ impl VolumeRef {
             .map(|(vol, pr)| async move {
                 Ok((
                     vol.name.clone(),
-                    to_volume_ref(vol, pod.namespace(), client, pr).await?,
+                    to_volume_ref(vol, pod, client, pr, volume_plugins_dir).await?,
                 ))
             });
         futures::future::join_all(vols).await.into_iter().collect()
@@ -80,6 +113,25 @@ impl VolumeRef {
             VolumeRef::Secret(sec) => sec.get_path(),
             VolumeRef::PersistentVolumeClaim(pv) => pv.get_path(),
             VolumeRef::HostPath(host) => host.get_path(),
+            VolumeRef::Flex(flex) => flex.get_path(),
+            VolumeRef::Projected(projected) => projected.get_path(),
+            VolumeRef::DownwardApi(downward) => downward.get_path(),
+            VolumeRef::EmptyDir(empty) => empty.get_path(),
+        }
+    }
+
+    /// Returns the checksums of the files written to the volume when it was mounted, for the
+    /// variants whose files Krustlet writes itself. Returns `None` for the other variants
+    pub fn checksums(&self) -> Option<&FileChecksums> {
+        match self {
+            VolumeRef::ConfigMap(cm) => Some(cm.checksums()),
+            VolumeRef::Secret(sec) => Some(sec.checksums()),
+            VolumeRef::Projected(projected) => Some(projected.checksums()),
+            VolumeRef::DownwardApi(downward) => Some(downward.checksums()),
+            VolumeRef::PersistentVolumeClaim(_)
+            | VolumeRef::HostPath(_)
+            | VolumeRef::Flex(_)
+            | VolumeRef::EmptyDir(_) => None,
         }
     }
 
@@ -90,6 +142,73 @@ impl VolumeRef {
             VolumeRef::Secret(sec) => sec.mount(path).await,
             VolumeRef::PersistentVolumeClaim(pv) => pv.mount(path).await,
             VolumeRef::HostPath(host) => host.mount().await,
+            VolumeRef::Flex(flex) => flex.mount(path).await,
+            VolumeRef::Projected(projected) => projected.mount(path).await,
+            VolumeRef::DownwardApi(downward) => downward.mount(path).await,
+            VolumeRef::EmptyDir(empty) => empty.mount(path).await,
+        }
+    }
+
+    /// Gives the volume the ownership of the pod's `fsGroup`, for the variants whose files
+    /// Krustlet manages itself. The ownership of the other variants is left to whatever
+    /// provides them. Must be called after the volume is mounted
+    pub async fn apply_ownership(&self, ownership: &VolumeOwnership) -> anyhow::Result<()> {
+        let read_only = match self {
+            VolumeRef::ConfigMap(_)
+            | VolumeRef::Secret(_)
+            | VolumeRef::Projected(_)
+            | VolumeRef::DownwardApi(_) => true,
+            VolumeRef::EmptyDir(_) => false,
+            VolumeRef::PersistentVolumeClaim(_) | VolumeRef::HostPath(_) | VolumeRef::Flex(_) => {
+                return Ok(())
+            }
+        };
+        match self.get_path() {
+            Some(path) => ownership.apply(path, read_only).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the files of a mounted ConfigMap, Secret or projected volume again from the current
+    /// contents of the objects they come from, and removes the files of keys that are gone from
+    /// them. Other variants, and volumes that haven't been mounted, are left as they are
+    pub async fn update(&mut self, ownership: Option<VolumeOwnership>) -> anyhow::Result<()> {
+        let path = match self {
+            VolumeRef::ConfigMap(_) | VolumeRef::Secret(_) | VolumeRef::Projected(_) => {
+                match self.get_path() {
+                    Some(path) => path.to_owned(),
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+        let base_path = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("{} is not in a directory", path.display()))?
+            .to_owned();
+        let previous = self.checksums().cloned().unwrap_or_default();
+
+        // Each file is replaced atomically, so modules never see a partly written one
+        set_writable(&path, true).await?;
+        self.mount(&base_path).await?;
+        let current = self.checksums().cloned().unwrap_or_default();
+        let removed: Vec<_> = previous
+            .iter()
+            .filter(|(file, _)| current.get(file).is_none())
+            .map(|(file, _)| path.join(file))
+            .collect();
+        if !removed.is_empty() {
+            set_writable(&path, true).await?;
+            for file in removed {
+                if let Err(e) = tokio::fs::remove_file(&file).await {
+                    warn!(error = %e, path = %file.display(), "Unable to remove volume file");
+                }
+            }
+            set_writable(&path, false).await?;
+        }
+        match ownership {
+            Some(ownership) => self.apply_ownership(&ownership).await,
+            None => Ok(()),
         }
     }
 
@@ -101,10 +220,58 @@ impl VolumeRef {
             VolumeRef::PersistentVolumeClaim(pv) => pv.unmount().await,
             // Doesn't need any unmounting steps
             VolumeRef::HostPath(_) => Ok(()),
+            VolumeRef::Flex(flex) => flex.unmount().await,
+            VolumeRef::Projected(projected) => projected.unmount().await,
+            VolumeRef::DownwardApi(downward) => downward.unmount().await,
+            VolumeRef::EmptyDir(empty) => empty.unmount().await,
         }
     }
 }
 
+/// Update the mounted volumes of the pod whose files come from the object, after it changed.
+/// Failures are logged, as the pod can keep running with the files it has.
+pub async fn update_volumes(
+    volumes: &mut HashMap<String, VolumeRef>,
+    pod: &Pod,
+    object: &RelatedObject,
+) {
+    let ownership = match VolumeOwnership::for_pod(pod) {
+        Ok(ownership) => ownership,
+        Err(e) => {
+            warn!(error = %e, "Unable to update volumes");
+            return;
+        }
+    };
+    for name in crate::pod::volumes_referring_to(pod, object) {
+        if let Some(volume) = volumes.get_mut(&name) {
+            match volume.update(ownership).await {
+                Ok(()) => info!(volume_name = %name, object = %object.name, "Updated volume"),
+                Err(e) => warn!(error = %e, volume_name = %name, "Unable to update volume"),
+            }
+        }
+    }
+}
+
+/// Lets the Kubelet write to the directory of a volume it made read-only when mounting it, or
+/// makes it read-only again. Only the owner's write permission is changed.
+async fn set_writable(path: &Path, writable: bool) -> anyhow::Result<()> {
+    let mut perms = tokio::fs::metadata(path).await?.permissions();
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = perms.mode();
+        perms.set_mode(if writable {
+            mode | 0o200
+        } else {
+            mode & !0o222
+        });
+    }
+    #[cfg(target_family = "windows")]
+    perms.set_readonly(!writable);
+    tokio::fs::set_permissions(path, perms).await?;
+    Ok(())
+}
+
 fn mount_setting_for(key: &str, items_to_mount: &Option<Vec<KeyToPath>>) -> ItemMount {
     match items_to_mount {
         None => ItemMount::MountAt(key.to_string()),
@@ -133,10 +300,12 @@ impl From<Option<String>> for ItemMount {
 
 async fn to_volume_ref(
     vol: &KubeVolume,
-    namespace: &str,
+    pod: &Pod,
     client: &kube::Client,
     plugin_registry: Option<Arc<PluginRegistry>>,
+    volume_plugins_dir: Option<&Path>,
 ) -> anyhow::Result<VolumeRef> {
+    let namespace = pod.namespace();
     if vol.config_map.is_some() {
         Ok(VolumeRef::ConfigMap(ConfigMapVolume::new(
             vol,
@@ -155,9 +324,39 @@ async fn to_volume_ref(
         ))
     } else if vol.host_path.is_some() {
         Ok(VolumeRef::HostPath(hostpath::HostPathVolume::new(vol)?))
+    } else if vol.flex_volume.is_some() {
+        let plugins_dir = volume_plugins_dir.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Flex volumes are not supported: no volume plugins directory is configured"
+            )
+        })?;
+        Ok(VolumeRef::Flex(FlexVolume::new(
+            vol,
+            pod,
+            client.clone(),
+            plugins_dir,
+        )?))
+    } else if vol.projected.is_some() {
+        Ok(VolumeRef::Projected(ProjectedVolume::new(
+            vol,
+            pod,
+            client.clone(),
+        )?))
+    } else if vol.downward_api.is_some() {
+        Ok(VolumeRef::DownwardApi(DownwardApiVolume::new(
+            vol,
+            pod,
+            client.clone(),
+        )?))
+    } else if vol.empty_dir.is_some() {
+        Ok(VolumeRef::EmptyDir(EmptyDirVolume::new(
+            vol,
+            pod,
+            client.clone(),
+        )?))
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, and HostPath"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, FlexVolume, Projected, DownwardAPI, and EmptyDir"
         ))
     }
 }
@@ -0,0 +1,173 @@
+//! Applies the `fsGroup` of a pod's security context to the files of its volumes.
+//!
+//! As on other nodes, the files and directories of the volumes whose contents the Kubelet
+//! manages itself are given the `fsGroup` as their group, read (and for emptyDir volumes,
+//! write) permission for that group, and the setgid bit on directories so that files modules
+//! create later are owned by the group too. Modules run as the user of the Kubelet, which
+//! stays the owner of the files, so a module sees the same ownership and permissions that a
+//! container running as the pod's user and `fsGroup` would. Windows has no POSIX groups, so
+//! the `fsGroup` is ignored there.
+use std::convert::TryFrom;
+use std::path::Path;
+
+use tracing::warn;
+
+use crate::pod::Pod;
+
+/// The ownership a pod asks for its volumes with the `fsGroup` of its security context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VolumeOwnership {
+    fs_group: u32,
+}
+
+impl VolumeOwnership {
+    /// The ownership of the pod's volumes, or `None` if the pod doesn't set an `fsGroup`.
+    pub fn for_pod(pod: &Pod) -> anyhow::Result<Option<Self>> {
+        match pod.fs_group() {
+            None => Ok(None),
+            Some(fs_group) => u32::try_from(fs_group)
+                .map(|fs_group| Some(VolumeOwnership { fs_group }))
+                .map_err(|_| anyhow::anyhow!("Invalid fsGroup {}", fs_group)),
+        }
+    }
+
+    /// The group that owns the volumes.
+    pub fn fs_group(&self) -> u32 {
+        self.fs_group
+    }
+
+    /// Give the files and directories under `path` the group and permissions of the
+    /// `fsGroup`. Read-only volumes only get read permission for the group. Failing to change
+    /// the group, as happens when the Kubelet doesn't run as root and isn't in the group, is
+    /// logged rather than returned, as modules can still use the files as their owner.
+    pub async fn apply(&self, path: &Path, read_only: bool) -> anyhow::Result<()> {
+        if let Err(e) = change_group(path, self.fs_group).await {
+            warn!(
+                error = %e,
+                path = %path.display(),
+                fs_group = self.fs_group,
+                "Unable to change the group of volume files to the fsGroup of the pod"
+            );
+        }
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || add_permissions(&path, read_only)).await?
+    }
+}
+
+/// The permissions given to the `fsGroup`, in addition to those a file already has.
+#[cfg(target_family = "unix")]
+fn permission_mask(is_dir: bool, read_only: bool) -> u32 {
+    let mask = if read_only { 0o440 } else { 0o660 };
+    if is_dir {
+        // Search permission, and setgid so that new files belong to the group
+        mask | 0o110 | 0o2000
+    } else {
+        mask
+    }
+}
+
+#[cfg(target_family = "unix")]
+async fn change_group(path: &Path, fs_group: u32) -> anyhow::Result<()> {
+    // Symbolic links are changed themselves rather than the files they point to, which could
+    // be outside the volume
+    let output = tokio::process::Command::new("chgrp")
+        .arg("-hR")
+        .arg(fs_group.to_string())
+        .arg(path)
+        .output()
+        .await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn add_permissions(path: &Path, read_only: bool) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    // The permissions of symbolic links are those of the files they point to
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode() | permission_mask(metadata.is_dir(), read_only);
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            add_permissions(&entry?.path(), read_only)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+async fn change_group(_path: &Path, _fs_group: u32) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn add_permissions(_path: &Path, _read_only: bool) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{Pod as KubePod, PodSecurityContext, PodSpec};
+
+    fn pod(fs_group: Option<i64>) -> Pod {
+        Pod::from(KubePod {
+            spec: Some(PodSpec {
+                security_context: Some(PodSecurityContext {
+                    fs_group,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_ownership_for_pod() {
+        assert_eq!(None, VolumeOwnership::for_pod(&pod(None)).unwrap());
+        assert_eq!(
+            Some(2000),
+            VolumeOwnership::for_pod(&pod(Some(2000)))
+                .unwrap()
+                .map(|ownership| ownership.fs_group())
+        );
+        assert!(VolumeOwnership::for_pod(&pod(Some(-1))).is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_apply_adds_group_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        std::fs::set_permissions(&nested, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let file = nested.join("key");
+        std::fs::write(&file, b"secret").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        // The test can always change files to the group they already have
+        let fs_group = std::fs::metadata(dir.path()).unwrap().gid();
+        VolumeOwnership { fs_group }
+            .apply(dir.path(), true)
+            .await
+            .unwrap();
+
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(0o2750, mode(&nested));
+        assert_eq!(0o640, mode(&file));
+        assert_eq!(fs_group, std::fs::metadata(&file).unwrap().gid());
+    }
+}
@@ -0,0 +1,220 @@
+//! Maps the paths of volumes between the host and the guest.
+//!
+//! Pod specs give host paths (e.g. of `hostPath` volumes) in whatever form the user wrote them,
+//! and guest paths (mount paths, sub paths and working directories) as Unix paths. On Windows
+//! nodes host paths may use either separator, lower case drive letters or UNC shares, and are
+//! compared without regard to case, while guest paths must keep `/` as their separator however
+//! the node joins paths.
+use std::path::{Component, Path, PathBuf};
+
+/// The path conventions of a host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostPathStyle {
+    /// `/` separated, case-sensitive paths.
+    Unix,
+    /// `\` or `/` separated, case-insensitive paths with drive letters or UNC shares.
+    Windows,
+}
+
+impl HostPathStyle {
+    /// The conventions of the host this is running on.
+    pub fn native() -> Self {
+        if cfg!(target_family = "windows") {
+            Self::Windows
+        } else {
+            Self::Unix
+        }
+    }
+
+    /// Normalizes a host path given in a pod spec. Windows paths get `\` separators, an upper
+    /// case drive letter and no repeated or trailing separators, so that `c:/data/` becomes
+    /// `C:\data` and `//server/share` becomes `\\server\share`. Verbatim paths (`\\?\`) are
+    /// left alone, as are Unix paths.
+    pub fn normalize(&self, path: &str) -> String {
+        match self {
+            Self::Unix => path.to_owned(),
+            Self::Windows => normalize_windows(path),
+        }
+    }
+
+    /// Whether two host paths refer to the same file, which on Windows ignores case and the
+    /// form the path was written in.
+    pub fn same_path(&self, a: &str, b: &str) -> bool {
+        match self {
+            Self::Unix => Path::new(a) == Path::new(b),
+            Self::Windows => {
+                normalize_windows(a).to_lowercase() == normalize_windows(b).to_lowercase()
+            }
+        }
+    }
+}
+
+fn normalize_windows(path: &str) -> String {
+    if path.starts_with(r"\\?\") {
+        return path.to_owned();
+    }
+    let path = path.replace('/', "\\");
+    let (prefix, rest) = if let Some(unc) = path.strip_prefix(r"\\") {
+        (r"\\".to_owned(), unc)
+    } else if is_drive(&path) {
+        let drive = path[..1].to_ascii_uppercase();
+        // Host paths have to be absolute, so `C:data` is taken to mean `C:\data`
+        (format!(r"{}:\", drive), &path[2..])
+    } else if let Some(rooted) = path.strip_prefix('\\') {
+        (r"\".to_owned(), rooted)
+    } else {
+        (String::new(), path.as_str())
+    };
+    let components: Vec<&str> = rest.split('\\').filter(|c| !c.is_empty()).collect();
+    format!("{}{}", prefix, components.join("\\"))
+}
+
+fn is_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+/// Normalizes a host path given in a pod spec for the host this is running on. See
+/// [`HostPathStyle::normalize`].
+pub fn host_path(path: &str) -> PathBuf {
+    PathBuf::from(HostPathStyle::native().normalize(path))
+}
+
+/// Whether two host paths refer to the same file on the host this is running on. See
+/// [`HostPathStyle::same_path`].
+pub fn same_host_path(a: &Path, b: &Path) -> bool {
+    HostPathStyle::native().same_path(&a.to_string_lossy(), &b.to_string_lossy())
+}
+
+/// The absolute, `/` separated path in the guest of a volume mounted at `mount_path`, or of the
+/// `sub_path` in it if there is one. Backslashes are taken as separators and a drive letter is
+/// dropped, so a mount path written for a Windows container, such as `C:\data`, becomes `/data`.
+pub fn guest_path(mount_path: &str, sub_path: Option<&str>) -> PathBuf {
+    let mount_path = mount_path.replace('\\', "/");
+    let mount_path = if is_drive(&mount_path) {
+        &mount_path[2..]
+    } else {
+        mount_path.as_str()
+    };
+    let components: Vec<&str> = mount_path
+        .split('/')
+        .chain(
+            sub_path
+                .into_iter()
+                .flat_map(|sub| sub.split(&['/', '\\'][..])),
+        )
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    PathBuf::from(format!("/{}", components.join("/")))
+}
+
+/// The path on the host of `relative`, a path relative to the guest path that `host_dir` is
+/// mounted at. The components of the guest path are pushed one at a time, so the result uses the
+/// host's separators. `..` components are rejected, as they could leave the mounted directory.
+pub fn host_join(host_dir: &Path, relative: &Path) -> anyhow::Result<PathBuf> {
+    let mut path = host_dir.to_owned();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => (),
+            _ => anyhow::bail!("{} is not a path within the volume", relative.display()),
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_windows_drive_paths() {
+        let windows = HostPathStyle::Windows;
+        assert_eq!(r"C:\data", windows.normalize("c:/data/"));
+        assert_eq!(r"C:\data\logs", windows.normalize(r"C:\data\\logs"));
+        assert_eq!(r"D:\", windows.normalize("d:"));
+        assert_eq!(r"D:\data", windows.normalize("d:data"));
+        assert_eq!(r"\data", windows.normalize("/data"));
+    }
+
+    #[test]
+    fn test_normalize_windows_unc_paths() {
+        let windows = HostPathStyle::Windows;
+        assert_eq!(
+            r"\\server\share\data",
+            windows.normalize("//server/share/data/")
+        );
+        assert_eq!(r"\\server\share", windows.normalize(r"\\server\share"));
+        assert_eq!(
+            r"\\?\C:\data/literal",
+            windows.normalize(r"\\?\C:\data/literal")
+        );
+    }
+
+    #[test]
+    fn test_same_path() {
+        let windows = HostPathStyle::Windows;
+        assert!(windows.same_path(r"C:\Data", "c:/data/"));
+        assert!(windows.same_path(r"\\Server\Share", "//server/share"));
+        assert!(!windows.same_path(r"C:\data", r"D:\data"));
+
+        let unix = HostPathStyle::Unix;
+        assert!(unix.same_path("/data/", "/data"));
+        assert!(!unix.same_path("/Data", "/data"));
+    }
+
+    #[test]
+    fn test_normalize_unix_paths() {
+        assert_eq!("/data/logs", HostPathStyle::Unix.normalize("/data/logs"));
+    }
+
+    #[test]
+    fn test_guest_path() {
+        assert_eq!(PathBuf::from("/data"), guest_path("/data/", None));
+        assert_eq!(
+            PathBuf::from("/data/app/config"),
+            guest_path("/data", Some("app/config"))
+        );
+        assert_eq!(
+            PathBuf::from("/data/app"),
+            guest_path(r"C:\data", Some(r"app"))
+        );
+        assert_eq!(PathBuf::from("/"), guest_path("/", None));
+    }
+
+    #[test]
+    fn test_host_join() {
+        let joined = host_join(Path::new("host"), Path::new("sub/dir")).unwrap();
+        assert_eq!(Path::new("host").join("sub").join("dir"), joined);
+        assert!(host_join(Path::new("host"), Path::new("../escape")).is_err());
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn test_native_windows_host_path() {
+        use std::path::Prefix;
+
+        let path = host_path("c:/data/logs");
+        match path.components().next() {
+            Some(Component::Prefix(prefix)) => {
+                assert_eq!(Prefix::Disk(b'C'), prefix.kind())
+            }
+            other => panic!("expected a drive prefix, got {:?}", other),
+        }
+        assert!(path.is_absolute());
+        assert!(same_host_path(&path, Path::new(r"C:\DATA\LOGS")));
+
+        let share = host_path("//server/share/data");
+        match share.components().next() {
+            Some(Component::Prefix(prefix)) => assert!(matches!(prefix.kind(), Prefix::UNC(_, _))),
+            other => panic!("expected a UNC prefix, got {:?}", other),
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    #[test]
+    fn test_native_windows_host_join() {
+        let joined = host_join(Path::new(r"C:\data"), Path::new("a/b")).unwrap();
+        assert_eq!(PathBuf::from(r"C:\data\a\b"), joined);
+    }
+}
@@ -19,7 +19,6 @@ use k8s_openapi::api::core::v1::{
     PersistentVolumeClaimVolumeSource, SecretReference, TypedLocalObjectReference,
     Volume as KubeVolume,
 };
-use k8s_openapi::api::storage::v1::StorageClass;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 use tempfile::Builder;
 use thiserror::Error;
@@ -169,8 +168,8 @@ impl PvcVolume {
         })?;
 
         let spec = get_pvc_spec(source, &client, namespace).await?;
-        let csi_client = get_csi_client(&client, &spec, plugin_registry).await?;
         let csi_pv_source = get_csi(&client, source, &spec).await?;
+        let csi_client = get_csi_client(&csi_pv_source, plugin_registry).await?;
 
         Ok(PvcVolume {
             name: vol.name.clone(),
@@ -468,23 +467,26 @@ async fn unpublish_volume(
     Ok(())
 }
 
+/// Connects to the node service of the CSI driver the PersistentVolume names, which must have
+/// registered with the node. The driver is taken from the volume rather than from its
+/// StorageClass, so statically provisioned volumes without a class work too.
 async fn get_csi_client(
-    client: &kube::Client,
-    spec: &PersistentVolumeClaimSpec,
+    csi: &CSIPersistentVolumeSource,
     plugin_registry: Arc<PluginRegistry>,
 ) -> anyhow::Result<NodeClient<tonic::transport::Channel>> {
-    let storage_class_client: Api<StorageClass> = Api::all(client.clone());
-    // NOTE(thomastaylor312): Storage class names are not required (see comment in validate
-    // function), so we can just try to find one with an empty string
-    let def = String::default();
-    let storage_class = storage_class_client
-        .get(spec.storage_class_name.as_ref().unwrap_or(&def))
-        .await?;
-    let endpoint = plugin_registry
-        .get_endpoint(&storage_class.provisioner)
+    let driver = plugin_registry
+        .csi_driver(&csi.driver)
         .await
-        .ok_or_else(|| anyhow::anyhow!("could not get CSI plugin endpoint"))?;
-    let chan = grpc_sock::client::socket_channel(endpoint).await?;
+        .ok_or_else(|| {
+            anyhow::anyhow!("CSI driver {} is not registered on this node", csi.driver)
+        })?;
+    if !driver.healthy {
+        warn!(
+            "CSI driver {} did not answer its last probe, trying it anyway",
+            driver.name
+        );
+    }
+    let chan = grpc_sock::client::socket_channel(driver.endpoint).await?;
     Ok(NodeClient::new(chan))
 }
 
@@ -0,0 +1,370 @@
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::authentication::v1::{BoundObjectReference, TokenRequest, TokenRequestSpec};
+use k8s_openapi::api::core::v1::{
+    ConfigMap, ConfigMapProjection, SecretProjection, ServiceAccountTokenProjection,
+    Volume as KubeVolume, VolumeProjection,
+};
+use k8s_openapi::ByteString;
+use kube::error::ErrorResponse;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::*;
+
+/// How long service account tokens are valid for if the projection doesn't say.
+const DEFAULT_TOKEN_EXPIRATION_SECONDS: i64 = 3600;
+/// How long to wait before trying to refresh a service account token again after failing to.
+const TOKEN_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A type that can manage a projected volume with mounting and unmounting support. A projected
+/// volume combines the items of ConfigMaps, Secrets, downward API fields and service account
+/// tokens in one directory, each at its own path.
+///
+/// Service account tokens are requested for the pod's service account, bound to the pod, and
+/// replaced with a new token once 80% of their lifetime has passed, for as long as the volume
+/// is mounted.
+pub struct ProjectedVolume {
+    vol_name: String,
+    sources: Vec<VolumeProjection>,
+    config_maps: kube::Api<ConfigMap>,
+    secrets: kube::Api<Secret>,
+    pod: Pod,
+    token_source: TokenSource,
+    mounted_path: Option<PathBuf>,
+    checksums: FileChecksums,
+    token_refreshers: Vec<JoinHandle<()>>,
+}
+
+impl ProjectedVolume {
+    /// Creates a new projected volume from a Kubernetes volume object. Passing a non-projected
+    /// volume type will result in an error
+    pub fn new(vol: &KubeVolume, pod: &Pod, client: kube::Client) -> anyhow::Result<Self> {
+        let source = vol.projected.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called a projected volume constructor with a non-projected volume")
+        })?;
+        Ok(ProjectedVolume {
+            vol_name: vol.name.clone(),
+            sources: source.sources.clone().unwrap_or_default(),
+            config_maps: Api::namespaced(client.clone(), pod.namespace()),
+            secrets: Api::namespaced(client.clone(), pod.namespace()),
+            pod: pod.clone(),
+            token_source: TokenSource {
+                client,
+                namespace: pod.namespace().to_owned(),
+                service_account: pod.service_account_name().unwrap_or("default").to_owned(),
+                pod_name: pod.name().to_owned(),
+                pod_uid: pod.pod_uid().to_owned(),
+            },
+            mounted_path: None,
+            checksums: FileChecksums::default(),
+            token_refreshers: Vec::new(),
+        })
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Returns the checksums of the files written when the volume was mounted
+    pub fn checksums(&self) -> &FileChecksums {
+        &self.checksums
+    }
+
+    /// Mounts the projected volume in the given directory. The actual path will be
+    /// $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        // Mounting again, to update the volume, replaces the tokens being refreshed
+        for refresher in self.token_refreshers.drain(..) {
+            refresher.abort();
+        }
+        // Gather every item first, so that nothing is written if any source is missing
+        let mut files = Vec::new();
+        let mut tokens = Vec::new();
+        for source in &self.sources {
+            if let Some(cm) = &source.config_map {
+                files.extend(self.config_map_files(cm).await?);
+            }
+            if let Some(sec) = &source.secret {
+                files.extend(self.secret_files(sec).await?);
+            }
+            if let Some(downward) = &source.downward_api {
+                let items = downward.items.as_deref().unwrap_or_default();
+                let client = &self.token_source.client;
+                files.extend(
+                    super::downwardapi::downward_api_files(&self.pod, items, client).await?,
+                );
+            }
+            if let Some(projection) = &source.service_account_token {
+                let token = self.token_source.request(projection).await?;
+                files.push((PathBuf::from(&projection.path), token.token.into_bytes()));
+                tokens.push((projection.clone(), token.issued, token.expires));
+            }
+        }
+
+        let path = base_path.as_ref().join(&self.vol_name);
+        tokio::fs::create_dir_all(&path).await?;
+        self.checksums = files::write_files(&path, files).await?;
+
+        // Set projected directory to read-only.
+        let mut perms = tokio::fs::metadata(&path).await?.permissions();
+        perms.set_readonly(true);
+        tokio::fs::set_permissions(&path, perms).await?;
+
+        for (projection, issued, expires) in tokens {
+            let token_path = path.join(&projection.path);
+            let refresh = self
+                .token_source
+                .clone()
+                .refresh(projection, token_path, issued, expires);
+            self.token_refreshers.push(tokio::spawn(refresh));
+        }
+        self.mounted_path = Some(path);
+
+        Ok(())
+    }
+
+    /// Unmounts the directory, which removes all files and stops refreshing its service account
+    /// tokens. Calling `unmount` on a directory that hasn't been mounted will log a warning, but
+    /// otherwise not error
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        for refresher in self.token_refreshers.drain(..) {
+            refresher.abort();
+        }
+        match self.mounted_path.take() {
+            Some(p) => {
+                //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
+                #[cfg(target_family = "windows")]
+                tokio::task::spawn_blocking(|| remove_dir_all::remove_dir_all(p)).await??;
+
+                #[cfg(target_family = "unix")]
+                tokio::fs::remove_dir_all(p).await?;
+            }
+            None => {
+                warn!("Attempted to unmount projected directory that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+
+    async fn config_map_files(
+        &self,
+        projection: &ConfigMapProjection,
+    ) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let name = projection
+            .name
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("no ConfigMap name was given"))?;
+        let config_map = match self.config_maps.get(name).await {
+            Ok(config_map) => config_map,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                if projection.optional.unwrap_or(false) =>
+            {
+                debug!(name, "Optional ConfigMap of projected volume not found");
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let binary_data = config_map
+            .binary_data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, ByteString(data))| (key, data));
+        let data = config_map
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, data)| (key, data.into_bytes()));
+        Ok(items_to_mount(binary_data.chain(data), &projection.items))
+    }
+
+    async fn secret_files(
+        &self,
+        projection: &SecretProjection,
+    ) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let name = projection
+            .name
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Secret projection does not have a name"))?;
+        let secret = match self.secrets.get(name).await {
+            Ok(secret) => secret,
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                if projection.optional.unwrap_or(false) =>
+            {
+                debug!(name, "Optional Secret of projected volume not found");
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let data = secret
+            .data
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, ByteString(data))| (key, data));
+        Ok(items_to_mount(data, &projection.items))
+    }
+}
+
+impl Drop for ProjectedVolume {
+    fn drop(&mut self) {
+        for refresher in &self.token_refreshers {
+            refresher.abort();
+        }
+    }
+}
+
+/// The paths to write the given keys and values at, according to the items to mount.
+fn items_to_mount(
+    data: impl Iterator<Item = (String, Vec<u8>)>,
+    items: &Option<Vec<KeyToPath>>,
+) -> Vec<(PathBuf, Vec<u8>)> {
+    data.filter_map(|(key, data)| match mount_setting_for(&key, items) {
+        ItemMount::MountAt(mount_path) => Some((PathBuf::from(mount_path), data)),
+        ItemMount::DoNotMount => None,
+    })
+    .collect()
+}
+
+/// A service account token issued for a pod.
+struct IssuedToken {
+    token: String,
+    issued: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+/// Requests service account tokens bound to a pod.
+#[derive(Clone)]
+struct TokenSource {
+    client: kube::Client,
+    namespace: String,
+    service_account: String,
+    pod_name: String,
+    pod_uid: String,
+}
+
+impl TokenSource {
+    async fn request(
+        &self,
+        projection: &ServiceAccountTokenProjection,
+    ) -> anyhow::Result<IssuedToken> {
+        let body = TokenRequest {
+            metadata: Default::default(),
+            spec: TokenRequestSpec {
+                // The API server's own audiences are used if none are given
+                audiences: projection.audience.iter().cloned().collect(),
+                bound_object_ref: Some(BoundObjectReference {
+                    api_version: Some("v1".to_owned()),
+                    kind: Some("Pod".to_owned()),
+                    name: Some(self.pod_name.clone()),
+                    uid: Some(self.pod_uid.clone()),
+                }),
+                expiration_seconds: Some(
+                    projection
+                        .expiration_seconds
+                        .unwrap_or(DEFAULT_TOKEN_EXPIRATION_SECONDS),
+                ),
+            },
+            status: None,
+        };
+        let request = http::Request::post(format!(
+            "/api/v1/namespaces/{}/serviceaccounts/{}/token",
+            self.namespace, self.service_account
+        ))
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body)?)?;
+        let issued = Utc::now();
+        let response: TokenRequest = self.client.request(request).await?;
+        let status = response.status.ok_or_else(|| {
+            anyhow::anyhow!(
+                "no token was issued for service account {}",
+                self.service_account
+            )
+        })?;
+        Ok(IssuedToken {
+            token: status.token,
+            issued,
+            expires: status.expiration_timestamp.0,
+        })
+    }
+
+    /// Replace the token at `path` with a new one whenever 80% of the lifetime of the current
+    /// one has passed.
+    async fn refresh(
+        self,
+        projection: ServiceAccountTokenProjection,
+        path: PathBuf,
+        mut issued: DateTime<Utc>,
+        mut expires: DateTime<Utc>,
+    ) {
+        loop {
+            tokio::time::sleep(refresh_delay(issued, expires, Utc::now())).await;
+            let token = loop {
+                match self.request(&projection).await {
+                    Ok(token) => break token,
+                    Err(e) => {
+                        warn!(error = %e, path = %path.display(), "Unable to refresh service account token");
+                        tokio::time::sleep(TOKEN_RETRY_INTERVAL).await;
+                    }
+                }
+            };
+            if let Err(e) = tokio::fs::write(&path, token.token.as_bytes()).await {
+                warn!(error = %e, path = %path.display(), "Unable to write refreshed service account token");
+            }
+            issued = token.issued;
+            expires = token.expires;
+        }
+    }
+}
+
+/// How long to wait from `now` until a token issued at `issued` that expires at `expires` is
+/// refreshed.
+fn refresh_delay(issued: DateTime<Utc>, expires: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    let refresh_at = issued + (expires - issued) * 4 / 5;
+    (refresh_at - now).to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_refresh_delay() {
+        let issued = Utc.timestamp(1000, 0);
+        let expires = Utc.timestamp(4600, 0);
+        assert_eq!(
+            Duration::from_secs(2880),
+            refresh_delay(issued, expires, issued)
+        );
+        assert_eq!(
+            Duration::from_secs(880),
+            refresh_delay(issued, expires, Utc.timestamp(3000, 0))
+        );
+        assert_eq!(
+            Duration::from_secs(0),
+            refresh_delay(issued, expires, expires)
+        );
+    }
+
+    #[test]
+    fn test_items_to_mount() {
+        let data = vec![
+            ("a".to_owned(), b"1".to_vec()),
+            ("b".to_owned(), b"2".to_vec()),
+        ];
+        let items = Some(vec![KeyToPath {
+            key: "b".to_owned(),
+            path: "dir/b.txt".to_owned(),
+            mode: None,
+        }]);
+        assert_eq!(
+            vec![(PathBuf::from("dir/b.txt"), b"2".to_vec())],
+            items_to_mount(data.clone().into_iter(), &items)
+        );
+        assert_eq!(2, items_to_mount(data.into_iter(), &None).len());
+    }
+}
@@ -13,6 +13,7 @@ pub struct SecretVolume {
     client: kube::Api<Secret>,
     items: Option<Vec<KeyToPath>>,
     mounted_path: Option<PathBuf>,
+    checksums: FileChecksums,
 }
 
 impl SecretVolume {
@@ -31,6 +32,7 @@ impl SecretVolume {
             client: Api::namespaced(client, namespace),
             items: sec_source.items.clone(),
             mounted_path: None,
+            checksums: FileChecksums::default(),
         })
     }
 
@@ -40,28 +42,29 @@ impl SecretVolume {
         self.mounted_path.as_deref()
     }
 
+    /// Returns the checksums of the files written when the volume was mounted
+    pub fn checksums(&self) -> &FileChecksums {
+        &self.checksums
+    }
+
     /// Mounts the Secret volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
         let secret = self.client.get(&self.sec_name).await?;
         let path = base_path.as_ref().join(&self.vol_name);
         tokio::fs::create_dir_all(&path).await?;
-        let data = secret.data.unwrap_or_default();
-        // We could probably just move the data out of the option, but I don't know what the correct
-        // behavior is from k8s point of view if something tries to mount a volume again
-        let data = data
+        let files = secret
+            .data
+            .unwrap_or_default()
             .into_iter()
             .filter_map(
                 |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
+                    ItemMount::MountAt(mount_path) => Some((PathBuf::from(mount_path), data)),
                     ItemMount::DoNotMount => None,
                 },
             )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
-        futures::future::join_all(data)
-            .await
-            .into_iter()
-            .collect::<tokio::io::Result<_>>()?;
+            .collect();
+        self.checksums = files::write_files(&path, files).await?;
         // Set secret directory to read-only.
         let mut perms = tokio::fs::metadata(&path).await?.permissions();
         perms.set_readonly(true);
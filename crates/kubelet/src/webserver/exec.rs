@@ -0,0 +1,352 @@
+//! Streams commands run with `kubectl exec`, and containers attached to with `kubectl attach`,
+//! over a WebSocket, using the channel protocols of the Kubernetes remote command API: each binary
+//! message starts with the number of the stream it belongs to. The base64 variants of the
+//! protocols, which browsers use as they can't always send binary messages, send text messages
+//! instead that start with the number of the stream as an ASCII digit, followed by the data
+//! encoded as base64.
+
+use futures::{SinkExt, StreamExt};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Status, StatusCause, StatusDetails};
+use tracing::{debug, error};
+use warp::ws::{Message, WebSocket};
+
+use crate::exec::{Options, Output};
+use crate::provider::{NotImplementedError, Provider};
+
+const STDIN: u8 = 0;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const ERROR: u8 = 3;
+const RESIZE: u8 = 4;
+
+/// A version of the channel protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Protocol {
+    /// `channel.k8s.io`, which reports errors as plain text.
+    V1,
+    /// `v4.channel.k8s.io`, which reports the outcome of the command as a `Status`.
+    V4,
+    /// `base64.channel.k8s.io`, the base64 variant of `channel.k8s.io`.
+    Base64V1,
+    /// `v4.base64.channel.k8s.io`, the base64 variant of `v4.channel.k8s.io`.
+    Base64V4,
+}
+
+impl Protocol {
+    /// Pick the protocol to use from the `Sec-WebSocket-Protocol` header of the request, or
+    /// `None` if none of the requested ones is supported. Binary protocols are preferred over
+    /// base64 ones, and newer versions over older ones.
+    pub(super) fn negotiate(requested: Option<&str>) -> Option<Protocol> {
+        let requested: Vec<&str> = match requested {
+            Some(header) => header.split(',').map(str::trim).collect(),
+            // Clients that don't ask for a protocol get the original one
+            None => return Some(Protocol::V1),
+        };
+        [
+            Protocol::V4,
+            Protocol::V1,
+            Protocol::Base64V4,
+            Protocol::Base64V1,
+        ]
+        .iter()
+        .copied()
+        .find(|protocol| requested.contains(&protocol.name()))
+    }
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Protocol::V1 => "channel.k8s.io",
+            Protocol::V4 => "v4.channel.k8s.io",
+            Protocol::Base64V1 => "base64.channel.k8s.io",
+            Protocol::Base64V4 => "v4.base64.channel.k8s.io",
+        }
+    }
+
+    /// Whether this is a version 4 protocol, which reports the outcome of commands as a
+    /// `Status` and starts each port forward stream with its port.
+    pub(super) fn is_v4(self) -> bool {
+        matches!(self, Protocol::V4 | Protocol::Base64V4)
+    }
+
+    pub(super) fn is_base64(self) -> bool {
+        matches!(self, Protocol::Base64V1 | Protocol::Base64V4)
+    }
+
+    /// A message with the data on the given stream.
+    pub(super) fn frame(self, stream: u8, data: &[u8]) -> Message {
+        if self.is_base64() {
+            let mut frame = String::with_capacity(data.len() * 4 / 3 + 4);
+            frame.push((b'0' + stream) as char);
+            base64::encode_config_buf(data, base64::STANDARD, &mut frame);
+            Message::text(frame)
+        } else {
+            let mut frame = Vec::with_capacity(data.len() + 1);
+            frame.push(stream);
+            frame.extend_from_slice(data);
+            Message::binary(frame)
+        }
+    }
+
+    /// The stream and data of a message from the client, or `None` if it is not a data message
+    /// of the protocol.
+    pub(super) fn unframe(self, message: &Message) -> Option<(u8, Vec<u8>)> {
+        if self.is_base64() {
+            let frame = message.to_str().ok()?.as_bytes();
+            let stream = frame.first()?.checked_sub(b'0')?;
+            Some((stream, base64::decode(&frame[1..]).ok()?))
+        } else if message.is_binary() {
+            let (stream, data) = message.as_bytes().split_first()?;
+            Some((*stream, data.to_vec()))
+        } else {
+            None
+        }
+    }
+}
+
+/// What a remote command request streams.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Kind {
+    /// A new command run in the container.
+    Exec,
+    /// The standard streams of the container's own process.
+    Attach,
+}
+
+impl Kind {
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Kind::Exec => "exec",
+            Kind::Attach => "attach",
+        }
+    }
+}
+
+/// Run the command, or attach to the container, and stream it over the WebSocket until it exits.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn serve<T: Provider>(
+    provider: std::sync::Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    kind: Kind,
+    opts: Options,
+    protocol: Protocol,
+    socket: WebSocket,
+) {
+    let (sender, receiver, mut session) = crate::exec::channel(opts);
+    let run = async move {
+        match kind {
+            Kind::Exec => {
+                provider
+                    .exec(namespace, pod, container, sender, receiver)
+                    .await
+            }
+            // The container's process keeps running, so there is no exit code to report
+            Kind::Attach => provider
+                .attach(namespace, pod, container, sender, receiver)
+                .await
+                .map(|()| 0),
+        }
+    };
+    tokio::pin!(run);
+    let (mut socket_tx, mut socket_rx) = socket.split();
+
+    let result = loop {
+        tokio::select! {
+            result = &mut run => break result,
+            Some(output) = session.output.recv() => {
+                if socket_tx.send(output_message(output, protocol)).await.is_err() {
+                    debug!("Client disconnected during {}", kind.name());
+                    return;
+                }
+            }
+            message = socket_rx.next() => match message {
+                Some(Ok(message)) if message.is_close() => session.input = None,
+                Some(Ok(message)) => match protocol.unframe(&message) {
+                    Some((STDIN, data)) => {
+                        if let Some(input) = &session.input {
+                            let _ = input.send(data);
+                        }
+                    }
+                    // WASI has no terminals, so there is nothing to resize
+                    Some((RESIZE, _)) => (),
+                    Some(_) => debug!("Ignoring message on unknown {} stream", kind.name()),
+                    // Such as pings
+                    None => (),
+                },
+                Some(Err(e)) => {
+                    debug!(error = %e, "Client disconnected during {}", kind.name());
+                    return;
+                }
+                None => {
+                    debug!("Client disconnected during {}", kind.name());
+                    return;
+                }
+            },
+        }
+    };
+
+    // Send whatever output is left now that the command has exited
+    while let Some(output) = session.output.recv().await {
+        if socket_tx
+            .send(output_message(output, protocol))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    if let Err(e) = &result {
+        error!(error = %e, "Error during {}", kind.name());
+    }
+    if let Some(message) = result_message(result, kind, protocol, T::ARCH) {
+        let _ = socket_tx.send(message).await;
+    }
+    let _ = socket_tx.close().await;
+}
+
+fn output_message(output: Output, protocol: Protocol) -> Message {
+    match output {
+        Output::Stdout(data) => protocol.frame(STDOUT, &data),
+        Output::Stderr(data) => protocol.frame(STDERR, &data),
+    }
+}
+
+/// The message reporting how the command ended, if the protocol reports it.
+fn result_message(
+    result: anyhow::Result<i32>,
+    kind: Kind,
+    protocol: Protocol,
+    arch: &str,
+) -> Option<Message> {
+    let status = match result {
+        Ok(0) => Status {
+            status: Some("Success".to_owned()),
+            ..Default::default()
+        },
+        Ok(code) => Status {
+            status: Some("Failure".to_owned()),
+            reason: Some("NonZeroExitCode".to_owned()),
+            message: Some(format!(
+                "command terminated with non-zero exit code: {}",
+                code
+            )),
+            details: Some(StatusDetails {
+                causes: Some(vec![StatusCause {
+                    reason: Some("ExitCode".to_owned()),
+                    message: Some(code.to_string()),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        Err(e) => Status {
+            status: Some("Failure".to_owned()),
+            reason: Some("InternalError".to_owned()),
+            message: Some(if e.is::<NotImplementedError>() {
+                format!("{} not supported by provider {}", kind.name(), arch)
+            } else {
+                e.to_string()
+            }),
+            ..Default::default()
+        },
+    };
+    let body = if protocol.is_v4() {
+        serde_json::to_vec(&status).unwrap_or_default()
+    } else if status.status.as_deref() == Some("Success") {
+        // The original protocol only reports errors, as text
+        return None;
+    } else {
+        status.message.unwrap_or_default().into_bytes()
+    };
+    Some(protocol.frame(ERROR, &body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(Protocol::negotiate(None), Some(Protocol::V1));
+        assert_eq!(
+            Protocol::negotiate(Some("v4.channel.k8s.io, channel.k8s.io")),
+            Some(Protocol::V4)
+        );
+        assert_eq!(
+            Protocol::negotiate(Some("channel.k8s.io")),
+            Some(Protocol::V1)
+        );
+        assert_eq!(
+            Protocol::negotiate(Some("v4.base64.channel.k8s.io, base64.channel.k8s.io")),
+            Some(Protocol::Base64V4)
+        );
+        assert_eq!(
+            Protocol::negotiate(Some("base64.channel.k8s.io")),
+            Some(Protocol::Base64V1)
+        );
+        assert_eq!(Protocol::negotiate(Some("v5.channel.k8s.io")), None);
+    }
+
+    #[test]
+    fn test_base64_frames() {
+        let message = Protocol::Base64V4.frame(STDOUT, b"hello");
+        assert_eq!(Ok("1aGVsbG8="), message.to_str());
+        assert_eq!(
+            Some((STDOUT, b"hello".to_vec())),
+            Protocol::Base64V4.unframe(&message)
+        );
+        assert_eq!(
+            Some((STDIN, b"ls\n".to_vec())),
+            Protocol::Base64V1.unframe(&Message::text("0bHMK"))
+        );
+        assert_eq!(None, Protocol::Base64V1.unframe(&Message::text("0!!")));
+        assert_eq!(
+            None,
+            Protocol::Base64V1.unframe(&Message::binary(vec![0, 1]))
+        );
+        assert_eq!(None, Protocol::V4.unframe(&Message::text("0bHMK")));
+    }
+
+    #[test]
+    fn test_result_message() {
+        assert!(result_message(Ok(0), Kind::Exec, Protocol::V1, "test").is_none());
+        let message = result_message(Ok(2), Kind::Exec, Protocol::V4, "test").unwrap();
+        let data = message.as_bytes();
+        assert_eq!(data[0], ERROR);
+        let status: serde_json::Value = serde_json::from_slice(&data[1..]).unwrap();
+        assert_eq!("Failure", status["status"]);
+        assert_eq!("NonZeroExitCode", status["reason"]);
+        assert_eq!("2", status["details"]["causes"][0]["message"]);
+
+        let message = result_message(
+            Err(NotImplementedError.into()),
+            Kind::Exec,
+            Protocol::V1,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(
+            message.as_bytes(),
+            b"\x03exec not supported by provider test"
+        );
+        let message = result_message(
+            Err(NotImplementedError.into()),
+            Kind::Attach,
+            Protocol::V1,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(
+            message.as_bytes(),
+            b"\x03attach not supported by provider test"
+        );
+    }
+
+    #[test]
+    fn test_output_message() {
+        let message = output_message(Output::Stderr(b"oops".to_vec()), Protocol::V4);
+        assert_eq!(message.as_bytes(), b"\x02oops");
+    }
+}
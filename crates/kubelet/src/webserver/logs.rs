@@ -0,0 +1,105 @@
+//! Streams container logs over a WebSocket, for clients such as web dashboards that can't read a
+//! streamed HTTP response, using the protocols of the Kubernetes streaming API: each chunk of the
+//! log is sent as a binary message, or as a text message encoded as base64.
+
+use futures::{SinkExt, StreamExt};
+use hyper::Body;
+use tracing::debug;
+use warp::ws::{Message, WebSocket};
+
+/// A protocol for streaming logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Protocol {
+    /// `binary.k8s.io`, which sends the log as it is.
+    Binary,
+    /// `base64.binary.k8s.io`, which sends the log encoded as base64.
+    Base64,
+}
+
+impl Protocol {
+    /// Pick the protocol to use from the `Sec-WebSocket-Protocol` header of the request, or
+    /// `None` if none of the requested ones is supported.
+    pub(super) fn negotiate(requested: Option<&str>) -> Option<Protocol> {
+        let requested: Vec<&str> = match requested {
+            Some(header) => header.split(',').map(str::trim).collect(),
+            None => return Some(Protocol::Binary),
+        };
+        [Protocol::Binary, Protocol::Base64]
+            .iter()
+            .copied()
+            .find(|protocol| requested.contains(&protocol.name()))
+    }
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Protocol::Binary => "binary.k8s.io",
+            Protocol::Base64 => "base64.binary.k8s.io",
+        }
+    }
+
+    fn message(self, data: &[u8]) -> Message {
+        match self {
+            Protocol::Binary => Message::binary(data),
+            Protocol::Base64 => Message::text(base64::encode(data)),
+        }
+    }
+}
+
+/// Send the log over the WebSocket until it ends or the client disconnects.
+pub(super) async fn serve(mut log: Body, protocol: Protocol, socket: WebSocket) {
+    let (mut socket_tx, mut socket_rx) = socket.split();
+    loop {
+        tokio::select! {
+            chunk = log.next() => match chunk {
+                Some(Ok(chunk)) => {
+                    if socket_tx.send(protocol.message(&chunk)).await.is_err() {
+                        debug!("Client disconnected from logs");
+                        return;
+                    }
+                }
+                Some(Err(e)) => {
+                    debug!(error = %e, "Error reading logs");
+                    break;
+                }
+                None => break,
+            },
+            // Clients only send control messages, and close the WebSocket once they are done
+            message = socket_rx.next() => match message {
+                Some(Ok(message)) if !message.is_close() => (),
+                _ => {
+                    debug!("Client disconnected from logs");
+                    return;
+                }
+            },
+        }
+    }
+    let _ = socket_tx.close().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(Protocol::negotiate(None), Some(Protocol::Binary));
+        assert_eq!(
+            Protocol::negotiate(Some("base64.binary.k8s.io, binary.k8s.io")),
+            Some(Protocol::Binary)
+        );
+        assert_eq!(
+            Protocol::negotiate(Some("base64.binary.k8s.io")),
+            Some(Protocol::Base64)
+        );
+        assert_eq!(Protocol::negotiate(Some("channel.k8s.io")), None);
+    }
+
+    #[test]
+    fn test_log_messages() {
+        assert_eq!(
+            b"line\n" as &[u8],
+            Protocol::Binary.message(b"line\n").as_bytes()
+        );
+        assert_eq!(Ok("bGluZQo="), Protocol::Base64.message(b"line\n").to_str());
+    }
+}
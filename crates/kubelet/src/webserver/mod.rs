@@ -1,26 +1,43 @@
 //! Server is an HTTP(S) server for answering Kubelet callbacks.
 //!
-//! Logs and exec calls are the main things that a server should handle.
+//! Logs, exec and attach calls are the main things that a server should handle.
 
+use crate::attestation::Attestation;
 use crate::config::ServerConfig;
 use crate::log::{Options, Sender};
+use crate::metrics::MetricsRegistry;
 use crate::provider::{NotImplementedError, Provider};
+use anyhow::Context;
 use http::status::StatusCode;
 use http::Response;
 use hyper::Body;
+use k8s_openapi::api::core::v1::{Pod as KubePod, PodList};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
 use std::convert::Infallible;
+use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
 use tracing::{debug, error, instrument};
-use warp::Filter;
+use warp::{Filter, Reply};
+
+mod exec;
+mod logs;
+mod pods;
+mod port_forward;
 
 const PING: &str = "this is the Krustlet HTTP server";
+/// The largest pod manifest accepted for a preview, 1MiB, like the API server's limit on
+/// objects.
+const MAX_PREVIEW_MANIFEST_SIZE: u64 = 1024 * 1024;
 
 /// Start the Krustlet HTTP(S) server
 ///
 /// This is a primitive implementation of an HTTP provider for the internal API.
 pub(crate) async fn start<T: Provider>(
     provider: Arc<T>,
+    node_name: String,
     config: &ServerConfig,
+    metrics: MetricsRegistry,
 ) -> anyhow::Result<()> {
     let health = warp::get().and(warp::path("healthz")).map(|| PING);
     let ping = warp::get().and(warp::path::end()).map(|| PING);
@@ -34,16 +51,150 @@ pub(crate) async fn start<T: Provider>(
             get_container_logs(provider, namespace, pod, container, opts)
         });
 
+    let logs_ws_provider = provider.clone();
+    let logs_ws = warp::get()
+        .and(warp::path!("containerLogs" / String / String / String))
+        .and(warp::query::<Options>())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .and_then(move |namespace, pod, container, opts, protocols, ws| {
+            let provider = logs_ws_provider.clone();
+            upgrade_container_logs(provider, namespace, pod, container, opts, protocols, ws)
+        });
+
     let exec_provider = provider.clone();
-    let exec = warp::post()
+    let exec = warp::get()
+        .and(warp::path!("exec" / String / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace, pod, container, query: String, protocols: Option<String>, ws| {
+                let provider = exec_provider.clone();
+                upgrade_exec(
+                    provider,
+                    namespace,
+                    pod,
+                    container,
+                    exec::Kind::Exec,
+                    query,
+                    protocols,
+                    ws,
+                )
+            },
+        );
+    let exec_without_upgrade = warp::get()
+        .or(warp::post())
+        .unify()
         .and(warp::path!("exec" / String / String / String))
-        .and_then(move |namespace, pod, container| {
-            let provider = exec_provider.clone();
-            post_exec(provider, namespace, pod, container)
+        .map(|_, _, _| without_upgrade(exec::Kind::Exec));
+
+    let attach_provider = provider.clone();
+    let attach = warp::get()
+        .and(warp::path!("attach" / String / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace, pod, container, query: String, protocols: Option<String>, ws| {
+                let provider = attach_provider.clone();
+                upgrade_exec(
+                    provider,
+                    namespace,
+                    pod,
+                    container,
+                    exec::Kind::Attach,
+                    query,
+                    protocols,
+                    ws,
+                )
+            },
+        );
+    let attach_without_upgrade = warp::get()
+        .or(warp::post())
+        .unify()
+        .and(warp::path!("attach" / String / String / String))
+        .map(|_, _, _| without_upgrade(exec::Kind::Attach));
+
+    let port_forward_provider = provider.clone();
+    let port_forward = warp::get()
+        .and(warp::path!("portForward" / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |namespace, pod, query: String, protocols: Option<String>, ws| {
+                let provider = port_forward_provider.clone();
+                upgrade_port_forward(provider, namespace, pod, query, protocols, ws)
+            },
+        );
+    let port_forward_without_upgrade = warp::get()
+        .or(warp::post())
+        .unify()
+        .and(warp::path!("portForward" / String / String))
+        .and_then(post_port_forward);
+
+    let port_mappings_provider = provider.clone();
+    let port_mappings = warp::get()
+        .and(warp::path!("portMappings"))
+        .and_then(move || {
+            let provider = port_mappings_provider.clone();
+            get_port_mappings(provider)
+        });
+
+    let attestation_provider = provider.clone();
+    let attestation_config = Arc::new(config.clone());
+    let attestation = warp::get()
+        .and(warp::path!("attestation"))
+        .and(warp::query::<AttestationOptions>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |opts, authorization| {
+            let provider = attestation_provider.clone();
+            let node_name = node_name.clone();
+            let config = attestation_config.clone();
+            get_attestation(provider, node_name, config, opts, authorization)
+        });
+
+    let preview_provider = provider.clone();
+    let preview_config = Arc::new(config.clone());
+    let preview = warp::post()
+        .and(warp::path!("podPreview"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(MAX_PREVIEW_MANIFEST_SIZE))
+        .and(warp::body::json())
+        .and_then(move |authorization, pod| {
+            let provider = preview_provider.clone();
+            let config = preview_config.clone();
+            post_pod_preview(provider, config, authorization, pod)
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .map(move || get_metrics(&metrics));
+
+    let routes = ping
+        .or(health)
+        .or(logs_ws)
+        .or(logs)
+        .or(exec)
+        .or(exec_without_upgrade)
+        .or(attach)
+        .or(attach_without_upgrade)
+        .or(port_forward)
+        .or(port_forward_without_upgrade)
+        .or(port_mappings)
+        .or(attestation)
+        .or(preview)
+        .or(metrics)
+        .recover(handle_rejection);
 
+    // Warp's TLS server panics when it can't listen or read its certificate, so both are
+    // checked first to report them as errors
+    std::net::TcpListener::bind((config.addr, config.port))
+        .with_context(|| format!("unable to listen on {}:{}", config.addr, config.port))?;
+    for path in &[&config.cert_file, &config.private_key_file] {
+        std::fs::File::open(path).with_context(|| format!("unable to read {}", path.display()))?;
+    }
     warp::serve(routes)
         .tls()
         .cert_path(&config.cert_file)
@@ -53,6 +204,36 @@ pub(crate) async fn start<T: Provider>(
     Ok(())
 }
 
+/// Start the read-only Krustlet HTTP server
+///
+/// This serves a limited set of endpoints over plain HTTP without authentication, for
+/// monitoring setups that expect the read-only port of the Kubernetes kubelet. It never gives
+/// access to logs or exec.
+pub(crate) async fn start_read_only(
+    client: kube::Client,
+    node_name: String,
+    addr: IpAddr,
+    port: u16,
+    metrics: MetricsRegistry,
+) -> anyhow::Result<()> {
+    let health = warp::get().and(warp::path("healthz")).map(|| PING);
+    let cache = Arc::new(pods::PodCache::start(client, &node_name));
+    let pods = warp::get()
+        .and(warp::path!("pods"))
+        .map(move || get_pods(&cache));
+    let metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .map(move || get_metrics(&metrics));
+
+    let routes = health.or(pods).or(metrics).recover(handle_rejection);
+
+    let (_, server) = warp::serve(routes)
+        .try_bind_ephemeral((addr, port))
+        .with_context(|| format!("unable to listen on {}:{}", addr, port))?;
+    server.await;
+    Ok(())
+}
+
 /// Get the logs from the running container.
 ///
 /// Implements the kubelet path /containerLogs/{namespace}/{pod}/{container}
@@ -70,40 +251,541 @@ async fn get_container_logs<T: Provider>(
 
     match provider.logs(namespace, pod, container, log_sender).await {
         Ok(()) => Ok(Response::new(log_body)),
+        Err(e) => Ok(logs_error::<T>(e)),
+    }
+}
+
+/// Stream the logs from the running container over a WebSocket.
+///
+/// Implements the kubelet path /containerLogs/{namespace}/{pod}/{container} for WebSocket
+/// upgrades
+#[instrument(level = "info", skip(provider, ws))]
+async fn upgrade_container_logs<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    opts: Options,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+) -> Result<Response<Body>, Infallible> {
+    debug!("Got container log request over WebSocket");
+    let protocol = match logs::Protocol::negotiate(protocols.as_deref()) {
+        Some(protocol) => protocol,
+        None => {
+            return Ok(return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "none of the requested log protocols is supported, use {} or {}",
+                    logs::Protocol::Binary.name(),
+                    logs::Protocol::Base64.name()
+                ),
+            ))
+        }
+    };
+    let (sender, log_body) = Body::channel();
+    let log_sender = Sender::new(sender, opts);
+
+    // Errors are answered before upgrading so that clients get the same status codes as
+    // without WebSockets
+    if let Err(e) = provider.logs(namespace, pod, container, log_sender).await {
+        return Ok(logs_error::<T>(e));
+    }
+    let mut response = ws
+        .on_upgrade(move |socket| logs::serve(log_body, protocol, socket))
+        .into_response();
+    if protocols.is_some() {
+        response.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            http::HeaderValue::from_static(protocol.name()),
+        );
+    }
+    Ok(response)
+}
+
+fn logs_error<T: Provider>(e: anyhow::Error) -> Response<Body> {
+    error!(error = %e, "Error fetching logs");
+    if e.is::<NotImplementedError>() {
+        return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("logs not supported by provider {}", T::ARCH),
+        )
+    } else {
+        return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        )
+    }
+}
+
+/// List the container ports mapped to node ports.
+///
+/// Implements the kubelet path /portMappings
+#[instrument(level = "info", skip(provider))]
+async fn get_port_mappings<T: Provider>(provider: Arc<T>) -> Result<Response<Body>, Infallible> {
+    match provider.port_mappings().await {
+        Ok(mappings) => Ok(return_json(&mappings)),
+        Err(e) if e.is::<NotImplementedError>() => Ok(return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("port mapping not enabled in provider {}", T::ARCH),
+        )),
         Err(e) => {
-            error!(error = %e, "Error fetching logs");
-            if e.is::<NotImplementedError>() {
-                Ok(return_with_code(
-                    StatusCode::NOT_IMPLEMENTED,
-                    "Logs not implemented in provider.".to_owned(),
-                ))
-            } else {
-                Ok(return_with_code(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Server error: {}", e),
-                ))
-            }
+            error!(error = %e, "Error listing port mappings");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
         }
     }
 }
 
-/// Run a pod exec command and get the output
+/// The options of a request for an attestation.
+#[derive(Debug, serde::Deserialize)]
+struct AttestationOptions {
+    /// Whether to sign the attestation with the node's private key.
+    #[serde(default)]
+    signed: bool,
+}
+
+/// Attest to the modules running on the node, optionally signed with the node's private key.
+/// Requests must carry the token in the configured attestation token file as a bearer token,
+/// and attestations are not served at all without one.
 ///
-/// Implements the kubelet path /exec/{namespace}/{pod}/{container}
-async fn post_exec<T: Provider>(
-    _provider: Arc<T>,
-    _namespace: String,
-    _pod: String,
-    _container: String,
+/// Implements the Krustlet path /attestation
+#[instrument(level = "info", skip(provider, config, authorization))]
+async fn get_attestation<T: Provider>(
+    provider: Arc<T>,
+    node_name: String,
+    config: Arc<ServerConfig>,
+    opts: AttestationOptions,
+    authorization: Option<String>,
 ) -> Result<Response<Body>, Infallible> {
+    debug!("Got attestation request");
+    if let Err(response) = check_bearer_token(
+        config.attestation_token_file.as_deref(),
+        authorization.as_deref(),
+        "attestations",
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let pods = match provider.running_modules().await {
+        Ok(pods) => pods,
+        Err(e) if e.is::<NotImplementedError>() => {
+            return Ok(return_with_code(
+                StatusCode::NOT_IMPLEMENTED,
+                format!("attestation not supported by provider {}", T::ARCH),
+            ))
+        }
+        Err(e) => {
+            error!(error = %e, "Error listing running modules");
+            return Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ));
+        }
+    };
+    let attestation = Attestation::new(&node_name, pods);
+    if !opts.signed {
+        return Ok(return_json(&attestation));
+    }
+    let signed = async {
+        let private_key = tokio::fs::read_to_string(&config.private_key_file).await?;
+        attestation.sign(&private_key)
+    };
+    match signed.await {
+        Ok(signed) => Ok(return_json(&signed)),
+        Err(e) => {
+            error!(error = %e, "Error signing attestation");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// Check that a request carries the bearer token in `token_file`, answering it with an error
+/// if it doesn't. The endpoint, which serves `what`, is disabled without a token file.
+async fn check_bearer_token(
+    token_file: Option<&Path>,
+    authorization: Option<&str>,
+    what: &str,
+) -> Result<(), Response<Body>> {
+    let token_file = match token_file {
+        Some(token_file) => token_file,
+        None => {
+            return Err(return_with_code(
+                StatusCode::FORBIDDEN,
+                format!("{} are not enabled on this node", what),
+            ))
+        }
+    };
+    // The token is read for every request, so that it can be rotated without a restart
+    let token = match tokio::fs::read_to_string(token_file).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!(error = %e, token_file = %token_file.display(), "Unable to read token file");
+            return Err(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Server error: unable to read token".to_owned(),
+            ));
+        }
+    };
+    if !bearer_token_matches(authorization, &token) {
+        return Err(return_with_code(
+            StatusCode::UNAUTHORIZED,
+            format!("a valid bearer token is required for {}", what),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the `Authorization` header carries the expected bearer token. The tokens are
+/// compared in constant time, and an empty token never matches.
+fn bearer_token_matches(authorization: Option<&str>, token: &str) -> bool {
+    let token = token.trim();
+    match authorization.and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(given) if !token.is_empty() => {
+            ring::constant_time::verify_slices_are_equal(given.trim().as_bytes(), token.as_bytes())
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// The answer to a pod preview.
+#[derive(serde::Serialize)]
+struct PodPreview {
+    /// Whether the pod would start on this node.
+    allowed: bool,
+    /// Why the pod would be rejected.
+    reasons: Vec<String>,
+}
+
+/// Preview whether a pod would start on this node, without starting anything. The request
+/// body is the pod manifest, and pods without a namespace are previewed in `default`.
+/// Requests must carry the token in the configured pod preview token file as a bearer token,
+/// and previews are not served at all without one. Pods with modules from the node's file
+/// system are refused, as previewing them would tell whether paths exist on the node.
+///
+/// Implements the Krustlet path /podPreview
+#[instrument(level = "info", skip(provider, config, authorization, pod))]
+async fn post_pod_preview<T: Provider>(
+    provider: Arc<T>,
+    config: Arc<ServerConfig>,
+    authorization: Option<String>,
+    mut pod: KubePod,
+) -> Result<Response<Body>, Infallible> {
+    debug!("Got pod preview request");
+    if let Err(response) = check_bearer_token(
+        config.pod_preview_token_file.as_deref(),
+        authorization.as_deref(),
+        "pod previews",
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    pod.metadata
+        .namespace
+        .get_or_insert_with(|| "default".to_owned());
+    let pod = crate::pod::Pod::from(pod);
+    if let Some(container) = pod
+        .all_containers()
+        .iter()
+        .find(|container| matches!(container.image(), Ok(Some(image)) if image.registry() == "fs"))
+    {
+        return Ok(return_with_code(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "container {} has a module from the node's file system, which can't be previewed",
+                container.name()
+            ),
+        ));
+    }
+    match provider.preview_pod(&pod).await {
+        Ok(reasons) => Ok(return_json(&PodPreview {
+            allowed: reasons.is_empty(),
+            reasons,
+        })),
+        Err(e) if e.is::<NotImplementedError>() => Ok(return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("pod preview not supported by provider {}", T::ARCH),
+        )),
+        Err(e) => {
+            error!(error = %e, "Error previewing pod");
+            Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    }
+}
+
+/// List the pods bound to this node, as a `PodList`.
+///
+/// Implements the kubelet path /pods
+fn get_pods(cache: &pods::PodCache) -> Response<Body> {
+    match cache.pods() {
+        Some(items) => return_json(&PodList {
+            items,
+            ..Default::default()
+        }),
+        None => return_with_code(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "pods have not been listed yet".to_owned(),
+        ),
+    }
+}
+
+/// Get the Kubelet metrics in the Prometheus text format.
+///
+/// Implements the kubelet path /metrics
+fn get_metrics(metrics: &MetricsRegistry) -> Response<Body> {
+    let mut response = Response::new(metrics.render().into());
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+/// Run a command in a container, or attach to it, streaming its input and output over a
+/// WebSocket.
+///
+/// Implements the kubelet paths /exec/{namespace}/{pod}/{container} and
+/// /attach/{namespace}/{pod}/{container}
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "info", skip(provider, ws))]
+fn upgrade_exec<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    kind: exec::Kind,
+    query: String,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+) -> Response<Body> {
+    debug!("Got {} request", kind.name());
+    let protocol = match exec::Protocol::negotiate(protocols.as_deref()) {
+        Some(protocol) => protocol,
+        None => {
+            return return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "none of the requested {} protocols is supported, use {} or {}",
+                    kind.name(),
+                    exec::Protocol::V4.name(),
+                    exec::Protocol::V1.name()
+                ),
+            )
+        }
+    };
+    let opts = crate::exec::Options::from_query(&query);
+    let mut response = ws
+        .on_upgrade(move |socket| {
+            exec::serve(
+                provider, namespace, pod, container, kind, opts, protocol, socket,
+            )
+        })
+        .into_response();
+    if protocols.is_some() {
+        response.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            http::HeaderValue::from_static(protocol.name()),
+        );
+    }
+    response
+}
+
+/// Answer exec and attach requests that are not WebSocket upgrades, such as the SPDY ones of
+/// older clients.
+fn without_upgrade(kind: exec::Kind) -> Response<Body> {
+    return_with_code(
+        StatusCode::BAD_REQUEST,
+        format!("{} is only supported over WebSockets", kind.name()),
+    )
+}
+
+/// Forward ports of a pod over a WebSocket.
+///
+/// Implements the kubelet path /portForward/{namespace}/{pod}
+#[instrument(level = "info", skip(provider, ws))]
+fn upgrade_port_forward<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    query: String,
+    protocols: Option<String>,
+    ws: warp::ws::Ws,
+) -> Response<Body> {
+    debug!("Got port forward request");
+    let protocol = match exec::Protocol::negotiate(protocols.as_deref()) {
+        Some(protocol) => protocol,
+        None => {
+            return return_with_code(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "none of the requested port forward protocols is supported, use {} or {}",
+                    exec::Protocol::V4.name(),
+                    exec::Protocol::V1.name()
+                ),
+            )
+        }
+    };
+    let ports = match port_forward::ports_from_query(&query, protocol) {
+        Ok(ports) => ports,
+        Err(e) => return return_with_code(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let mut response = ws
+        .on_upgrade(move |socket| {
+            port_forward::serve(provider, namespace, pod, ports, protocol, socket)
+        })
+        .into_response();
+    if protocols.is_some() {
+        response.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            http::HeaderValue::from_static(protocol.name()),
+        );
+    }
+    response
+}
+
+/// Answer port forward requests that are not WebSocket upgrades, such as the SPDY ones the API
+/// server proxies `kubectl port-forward` with. SPDY is not supported, so only clients that
+/// connect with WebSockets can forward ports.
+async fn post_port_forward(_namespace: String, _pod: String) -> Result<Response<Body>, Infallible> {
     Ok(return_with_code(
-        StatusCode::NOT_IMPLEMENTED,
-        "Exec not implemented.".to_string(),
+        StatusCode::BAD_REQUEST,
+        "port forwarding is only supported over WebSockets".to_owned(),
     ))
 }
 
-fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
-    let mut response = Response::new(body.into());
+/// Answer requests that no route accepts, such as unsupported endpoints, with a `Status`.
+async fn handle_rejection(rejection: warp::Rejection) -> Result<Response<Body>, Infallible> {
+    let (code, message) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "endpoint not supported by Krustlet")
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else if rejection.find::<warp::reject::InvalidQuery>().is_some() {
+        (StatusCode::BAD_REQUEST, "invalid query parameters")
+    } else if rejection
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
+        (StatusCode::BAD_REQUEST, "invalid request body")
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+    } else if rejection.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "request body length required")
+    } else if rejection
+        .find::<warp::reject::UnsupportedMediaType>()
+        .is_some()
+    {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "request body must be application/json",
+        )
+    } else {
+        error!(?rejection, "Unhandled request rejection");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "unable to handle request",
+        )
+    };
+    Ok(return_with_code(code, message.to_owned()))
+}
+
+fn return_json<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(body.into());
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(e) => return_with_code(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Server error: {}", e),
+        ),
+    }
+}
+
+/// Build an error response whose body is a Kubernetes `Status`, so that clients like kubectl
+/// can show the message instead of a generic failure.
+fn return_with_code(code: StatusCode, message: String) -> Response<Body> {
+    let status = Status {
+        code: Some(code.as_u16() as i32),
+        message: Some(message),
+        reason: Some(status_reason(code).to_owned()),
+        status: Some("Failure".to_owned()),
+        ..Default::default()
+    };
+    let mut response = Response::new(serde_json::to_vec(&status).unwrap_or_default().into());
     *response.status_mut() = code;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
     response
 }
+
+/// The `reason` of a `Status` with the given code, as the API server would report it.
+fn status_reason(code: StatusCode) -> &'static str {
+    match code {
+        StatusCode::BAD_REQUEST => "BadRequest",
+        StatusCode::UNAUTHORIZED => "Unauthorized",
+        StatusCode::FORBIDDEN => "Forbidden",
+        StatusCode::NOT_FOUND => "NotFound",
+        StatusCode::METHOD_NOT_ALLOWED => "MethodNotAllowed",
+        StatusCode::PAYLOAD_TOO_LARGE => "RequestEntityTooLarge",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "UnsupportedMediaType",
+        // Kubernetes has no reasons for these codes, so use their names like the ones above
+        StatusCode::LENGTH_REQUIRED => "LengthRequired",
+        // Kubernetes has no reason for this code, so use its name like the ones above
+        StatusCode::NOT_IMPLEMENTED => "NotImplemented",
+        StatusCode::SERVICE_UNAVAILABLE => "ServiceUnavailable",
+        _ => "InternalError",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_status() {
+        let response = return_with_code(
+            StatusCode::NOT_IMPLEMENTED,
+            "exec not supported by provider wasm32-wasi".to_owned(),
+        );
+        assert_eq!(StatusCode::NOT_IMPLEMENTED, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("Status", status["kind"]);
+        assert_eq!("v1", status["apiVersion"]);
+        assert_eq!("Failure", status["status"]);
+        assert_eq!("NotImplemented", status["reason"]);
+        assert_eq!(501, status["code"]);
+        assert_eq!(
+            "exec not supported by provider wasm32-wasi",
+            status["message"]
+        );
+    }
+
+    #[test]
+    fn test_bearer_token_matches() {
+        assert!(bearer_token_matches(Some("Bearer s3cret"), "s3cret\n"));
+        assert!(!bearer_token_matches(Some("Bearer s3cre"), "s3cret"));
+        assert!(!bearer_token_matches(Some("Basic s3cret"), "s3cret"));
+        assert!(!bearer_token_matches(None, "s3cret"));
+        // An empty token file doesn't let requests without a token through
+        assert!(!bearer_token_matches(Some("Bearer "), "\n"));
+    }
+}
@@ -0,0 +1,149 @@
+//! The pods bound to this node, as the read-only server serves them. They are kept up to date
+//! with a watch, so that requests for them are answered without listing pods from the API
+//! server each time.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams};
+use kube_runtime::watcher::{watcher, Event};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// How long to wait before watching pods again after the watch failed.
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The pods by namespace and name, or `None` until they have been listed.
+type Pods = Arc<RwLock<Option<BTreeMap<(String, String), KubePod>>>>;
+
+/// The pods bound to this node, which a background watch keeps up to date until this is
+/// dropped.
+pub(super) struct PodCache {
+    pods: Pods,
+    task: JoinHandle<()>,
+}
+
+impl PodCache {
+    /// Start watching the pods bound to the node.
+    pub(super) fn start(client: kube::Client, node_name: &str) -> Self {
+        let pods = Pods::default();
+        let api: Api<KubePod> = Api::all(client);
+        let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+        let task = tokio::spawn(watch_pods(api, params, pods.clone()));
+        PodCache { pods, task }
+    }
+
+    /// The pods bound to the node, sorted by namespace and name, or `None` if they haven't been
+    /// listed yet.
+    pub(super) fn pods(&self) -> Option<Vec<KubePod>> {
+        let pods = self.pods.read().ok()?;
+        pods.as_ref().map(|pods| pods.values().cloned().collect())
+    }
+}
+
+impl Drop for PodCache {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn watch_pods(api: Api<KubePod>, params: ListParams, pods: Pods) {
+    let mut events = watcher(api, params).boxed();
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(event) => {
+                if let Ok(mut pods) = pods.write() {
+                    apply(&mut pods, event);
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "Error watching pods for the read-only server");
+                tokio::time::sleep(WATCH_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+fn apply(pods: &mut Option<BTreeMap<(String, String), KubePod>>, event: Event<KubePod>) {
+    match event {
+        Event::Restarted(listed) => {
+            *pods = Some(listed.into_iter().map(|pod| (key(&pod), pod)).collect());
+        }
+        Event::Applied(pod) => {
+            if let Some(pods) = pods {
+                pods.insert(key(&pod), pod);
+            }
+        }
+        Event::Deleted(pod) => {
+            if let Some(pods) = pods {
+                pods.remove(&key(&pod));
+            }
+        }
+    }
+}
+
+fn key(pod: &KubePod) -> (String, String) {
+    (
+        pod.metadata.namespace.clone().unwrap_or_default(),
+        pod.metadata.name.clone().unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn names(pods: &Option<BTreeMap<(String, String), KubePod>>) -> Vec<String> {
+        pods.as_ref()
+            .unwrap()
+            .values()
+            .map(|pod| pod.metadata.name.clone().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let web: KubePod = serde_json::from_value(json!({
+            "metadata": { "namespace": "default", "name": "web" },
+            "status": { "phase": "Running" }
+        }))
+        .unwrap();
+        let db: KubePod = serde_json::from_value(json!({
+            "metadata": { "namespace": "apps", "name": "db" }
+        }))
+        .unwrap();
+        let job: KubePod = serde_json::from_value(json!({
+            "metadata": { "namespace": "default", "name": "job" }
+        }))
+        .unwrap();
+
+        let mut pods = None;
+        // Changes before the pods are listed are left for the list
+        apply(&mut pods, Event::Applied(job.clone()));
+        assert!(pods.is_none());
+
+        apply(&mut pods, Event::Restarted(vec![web, db.clone()]));
+        assert_eq!(names(&pods), vec!["db", "web"]);
+
+        let failed: KubePod = serde_json::from_value(json!({
+            "metadata": { "namespace": "default", "name": "web" },
+            "status": { "phase": "Failed" }
+        }))
+        .unwrap();
+        apply(&mut pods, Event::Applied(failed));
+        apply(&mut pods, Event::Applied(job));
+        assert_eq!(names(&pods), vec!["db", "job", "web"]);
+        let web = &pods.as_ref().unwrap()[&("default".to_owned(), "web".to_owned())];
+        assert_eq!(
+            web.status.as_ref().unwrap().phase.as_deref(),
+            Some("Failed")
+        );
+
+        apply(&mut pods, Event::Deleted(db));
+        assert_eq!(names(&pods), vec!["job", "web"]);
+    }
+}
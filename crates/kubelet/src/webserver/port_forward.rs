@@ -0,0 +1,226 @@
+//! Tunnels `kubectl port-forward` connections over a WebSocket, using the channel protocols of the
+//! Kubernetes streaming API: each forwarded port gets a data stream and an error stream, whose
+//! numbers start each message.
+
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use warp::ws::{Message, WebSocket};
+
+use super::exec::Protocol;
+use crate::provider::{NotImplementedError, Provider};
+
+/// How much data can be buffered in each direction of a forwarded connection.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// How many of the client's messages for a port are held while the provider doesn't read them.
+const INPUT_MESSAGES: usize = 16;
+
+/// Forward the given ports of the pod over the WebSocket until the client disconnects.
+pub(super) async fn serve<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    ports: Vec<u16>,
+    protocol: Protocol,
+    socket: WebSocket,
+) {
+    let (mut socket_tx, mut socket_rx) = socket.split();
+    let (frames_tx, mut frames_rx) = mpsc::channel::<Message>(16);
+    let mut inputs: Vec<mpsc::Sender<Vec<u8>>> = Vec::new();
+    let mut tasks = Vec::new();
+
+    for (index, port) in ports.into_iter().enumerate() {
+        let data_stream = (index * 2) as u8;
+        let error_stream = data_stream + 1;
+        if protocol.is_v4() {
+            // Newer clients expect every stream to start with the port it belongs to
+            for stream in &[data_stream, error_stream] {
+                let frame = protocol.frame(*stream, &port.to_le_bytes());
+                if socket_tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let (local, remote) = tokio::io::duplex(STREAM_BUFFER);
+        let (mut reader, mut writer) = tokio::io::split(local);
+
+        // Write what the client sends in a task of its own, so that a provider that is slow to
+        // read its connection doesn't stop the output from being forwarded
+        let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(INPUT_MESSAGES);
+        inputs.push(input_tx);
+        tasks.push(tokio::spawn(async move {
+            while let Some(data) = input_rx.recv().await {
+                if writer.write_all(&data).await.is_err() {
+                    // The provider closed the connection
+                    return;
+                }
+            }
+        }));
+
+        let provider = provider.clone();
+        let (namespace, pod) = (namespace.clone(), pod.clone());
+        let frames = frames_tx.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Err(e) = provider.port_forward(namespace, pod, port, remote).await {
+                error!(error = %e, port, "Error forwarding port");
+                let message = if e.is::<NotImplementedError>() {
+                    format!("port forwarding not supported by provider {}", T::ARCH)
+                } else {
+                    format!("error forwarding port {}: {}", port, e)
+                };
+                let _ = frames
+                    .send(protocol.frame(error_stream, message.as_bytes()))
+                    .await;
+            }
+        }));
+
+        let frames = frames_tx.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut buf = vec![0; STREAM_BUFFER];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => {
+                        if frames
+                            .send(protocol.frame(data_stream, &buf[..n]))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            }
+        }));
+    }
+    drop(frames_tx);
+
+    'forward: loop {
+        tokio::select! {
+            Some(frame) = frames_rx.recv() => {
+                if socket_tx.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            message = socket_rx.next() => match message {
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(message)) => {
+                    let (stream, data) = match protocol.unframe(&message) {
+                        Some((stream, data)) => (stream as usize, data),
+                        // Such as pings
+                        None => continue,
+                    };
+                    // Clients only send data, on the even numbered streams
+                    let input = match inputs.get(stream / 2) {
+                        Some(input) if stream % 2 == 0 => input,
+                        _ => {
+                            debug!(stream, "Ignoring message on unknown port forward stream");
+                            continue;
+                        }
+                    };
+                    // Once the port holds as many messages as it can, the client's input is
+                    // held back until the provider reads, while its output keeps being sent.
+                    // Sending fails once the provider closed the connection.
+                    let send = input.send(data);
+                    tokio::pin!(send);
+                    loop {
+                        tokio::select! {
+                            _ = &mut send => break,
+                            Some(frame) = frames_rx.recv() => {
+                                if socket_tx.send(frame).await.is_err() {
+                                    break 'forward;
+                                }
+                            }
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    debug!(error = %e, "Client disconnected during port forward");
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+    let _ = socket_tx.close().await;
+}
+
+/// The most ports that can be forwarded in one request with the protocol. Each port takes two
+/// streams, and stream numbers have to fit in the byte, or ASCII digit for base64 protocols,
+/// that starts each message.
+fn max_ports(protocol: Protocol) -> usize {
+    let streams = if protocol.is_base64() {
+        usize::from(u8::MAX - b'0') + 1
+    } else {
+        usize::from(u8::MAX) + 1
+    };
+    streams / 2
+}
+
+/// Parse the ports to forward from the query string of a port forward request, in which `port`
+/// is repeated for each port.
+pub(super) fn ports_from_query(query: &str, protocol: Protocol) -> anyhow::Result<Vec<u16>> {
+    let ports = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == "port")
+        .map(|(_, value)| {
+            value
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .ok_or_else(|| anyhow::anyhow!("invalid port {}", value))
+        })
+        .collect::<anyhow::Result<Vec<u16>>>()?;
+    if ports.is_empty() {
+        anyhow::bail!("at least one port must be given");
+    }
+    if ports.len() > max_ports(protocol) {
+        anyhow::bail!(
+            "at most {} ports can be forwarded at once",
+            max_ports(protocol)
+        );
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ports_from_query() {
+        assert_eq!(
+            ports_from_query("port=8080&port=9090", Protocol::V4).unwrap(),
+            vec![8080, 9090]
+        );
+        assert!(ports_from_query("", Protocol::V4).is_err());
+        assert!(ports_from_query("port=0", Protocol::V4).is_err());
+        assert!(ports_from_query("port=http", Protocol::V4).is_err());
+    }
+
+    #[test]
+    fn test_too_many_ports_are_rejected() {
+        let query = |count: u16| {
+            (1..=count)
+                .map(|port| format!("port={}", port))
+                .collect::<Vec<_>>()
+                .join("&")
+        };
+        // The last error stream is 255
+        assert_eq!(
+            ports_from_query(&query(128), Protocol::V4).unwrap().len(),
+            128
+        );
+        assert!(ports_from_query(&query(129), Protocol::V4).is_err());
+        // The last error stream is sent as the character 48 + 207
+        assert!(ports_from_query(&query(104), Protocol::Base64V4).is_ok());
+        assert!(ports_from_query(&query(105), Protocol::Base64V4).is_err());
+    }
+}
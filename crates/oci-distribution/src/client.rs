@@ -3,32 +3,48 @@
 //! *Note*: This client is very feature poor. We hope to expand this to be a complete
 //! OCI distribution client in the future.
 
+use crate::compression::{
+    decompressed_media_type, Compression, Decompressor, DEFAULT_MAX_DECOMPRESSED_LAYER_SIZE,
+};
 use crate::errors::*;
 use crate::manifest::{
-    OciDescriptor, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
-    IMAGE_MANIFEST_MEDIA_TYPE,
+    ImageIndexEntry, OciDescriptor, OciImageIndex, OciManifest, Platform, Versioned,
+    IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
 };
 use crate::secrets::RegistryAuth;
 use crate::secrets::*;
 use crate::Reference;
 
 use anyhow::Context;
+use bytes::Bytes;
 use futures_util::future;
-use futures_util::stream::StreamExt;
 use hyperx::header::Header;
 use reqwest::header::HeaderMap;
 use serde::Deserialize;
 use sha2::Digest;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tracing::{debug, warn};
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
 
+/// The number of times manifest and blob requests are retried, unless the
+/// client configuration sets it.
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// The delay before the first retry of a request, unless the client
+/// configuration sets it.
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest delay between retries of a request.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
 /// The data for an image or module.
 #[derive(Clone)]
 pub struct ImageData {
-    /// The layers of the image or module.
+    /// The layers of the image or module, in the order of its manifest.
     pub layers: Vec<ImageLayer>,
     /// The digest of the image or module.
     pub digest: Option<String>,
@@ -99,13 +115,20 @@ impl ImageLayer {
 /// a read-only bearer token. From there, pulling images can be done with
 /// the `pull_*` functions.
 ///
+/// The `RegistryAuth` given to `auth()` is sent to the registry's token server
+/// to get a token for a private repository. HTTP Basic credentials are sent as
+/// they are, and identity tokens are exchanged for an access token. Registries
+/// that challenge for HTTP Basic authentication instead of handing out tokens
+/// are sent the credentials on every request.
+///
 /// For true anonymous access, you can skip `auth()`. This is not recommended
 /// unless you are sure that the remote registry does not require Oauth2.
 #[derive(Default)]
 pub struct Client {
     config: ClientConfig,
-    tokens: HashMap<String, RegistryToken>,
+    tokens: HashMap<String, RegistryAuthorization>,
     client: reqwest::Client,
+    insecure_client: Option<reqwest::Client>,
 }
 
 /// A source that can provide a `ClientConfig`.
@@ -120,35 +143,63 @@ impl TryFrom<ClientConfig> for Client {
     type Error = anyhow::Error;
 
     fn try_from(config: ClientConfig) -> Result<Self, Self::Error> {
-        let mut client_builder = reqwest::Client::builder()
-            .danger_accept_invalid_certs(config.accept_invalid_certificates);
-
-        client_builder = match () {
-            #[cfg(feature = "native-tls")]
-            () => client_builder.danger_accept_invalid_hostnames(config.accept_invalid_hostnames),
-            #[cfg(not(feature = "native-tls"))]
-            () => {
-                warn!("Cannot change value of `accept_invalid_hostnames`: missing 'native-tls' feature");
-                client_builder
-            }
+        let client = build_http_client(
+            &config,
+            config.accept_invalid_certificates,
+            config.accept_invalid_hostnames,
+        )?;
+        // Registries that don't verify certificates get a client of their own, so that the
+        // certificates of all the others are still verified
+        let insecure_client = if config.accept_invalid_certificates_from.is_empty() {
+            None
+        } else {
+            Some(build_http_client(&config, true, true)?)
         };
 
-        for c in &config.extra_root_certificates {
-            let cert = match c.encoding {
-                CertificateEncoding::Der => reqwest::Certificate::from_der(c.data.as_slice())?,
-                CertificateEncoding::Pem => reqwest::Certificate::from_pem(c.data.as_slice())?,
-            };
-            client_builder = client_builder.add_root_certificate(cert);
-        }
-
         Ok(Self {
             config,
             tokens: HashMap::new(),
-            client: client_builder.build()?,
+            client,
+            insecure_client,
         })
     }
 }
 
+fn build_http_client(
+    config: &ClientConfig,
+    accept_invalid_certificates: bool,
+    accept_invalid_hostnames: bool,
+) -> anyhow::Result<reqwest::Client> {
+    let mut client_builder =
+        reqwest::Client::builder().danger_accept_invalid_certs(accept_invalid_certificates);
+
+    client_builder = match () {
+        #[cfg(feature = "native-tls")]
+        () => client_builder.danger_accept_invalid_hostnames(accept_invalid_hostnames),
+        #[cfg(not(feature = "native-tls"))]
+        () => {
+            if accept_invalid_hostnames {
+                warn!("Cannot change value of `accept_invalid_hostnames`: missing 'native-tls' feature");
+            }
+            client_builder
+        }
+    };
+
+    for c in &config.extra_root_certificates {
+        let cert = match c.encoding {
+            CertificateEncoding::Der => reqwest::Certificate::from_der(c.data.as_slice())?,
+            CertificateEncoding::Pem => reqwest::Certificate::from_pem(c.data.as_slice())?,
+        };
+        client_builder = client_builder.add_root_certificate(cert);
+    }
+
+    if let Some(identity) = &config.client_identity {
+        client_builder = client_builder.identity(identity.to_reqwest_identity()?);
+    }
+
+    Ok(client_builder.build()?)
+}
+
 impl Client {
     /// Create a new client with the supplied config
     pub fn new(config: ClientConfig) -> Self {
@@ -159,6 +210,7 @@ impl Client {
                 config,
                 tokens: HashMap::new(),
                 client: reqwest::Client::new(),
+                insecure_client: None,
             }
         })
     }
@@ -186,9 +238,10 @@ impl Client {
 
         let (manifest, digest) = self._pull_manifest(image).await?;
 
-        self.validate_layers(&manifest, accepted_media_types)
+        self.validate_layers(&manifest, &accepted_media_types)
             .await?;
 
+        let accepted_media_types = &accepted_media_types;
         let layers = manifest.layers.into_iter().map(|layer| {
             // This avoids moving `self` which is &mut Self
             // into the async block. We only want to capture
@@ -197,8 +250,16 @@ impl Client {
             async move {
                 let mut out: Vec<u8> = Vec::new();
                 debug!("Pulling image layer");
-                this.pull_layer(image, &layer.digest, &mut out).await?;
-                Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
+                // Layers are only decompressed if the caller doesn't accept them compressed
+                if accepted_media_types.contains(&layer.media_type.as_str()) {
+                    this.pull_layer(image, &layer.digest, &mut out).await?;
+                    Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
+                } else {
+                    this.pull_layer_decompressed(image, &layer, &mut out)
+                        .await?;
+                    let media_type = decompressed_media_type(&layer.media_type).to_owned();
+                    Ok(ImageLayer::new(out, media_type))
+                }
             }
         });
 
@@ -282,19 +343,38 @@ impl Client {
             self.config.protocol.scheme_for(&self.get_registry(image)),
             self.get_registry(&image)
         );
-        let res = self.client.get(&url).send().await?;
+        let res = self.client_for(image).get(&url).send().await?;
         let dist_hdr = match res.headers().get(reqwest::header::WWW_AUTHENTICATE) {
             Some(h) => h,
             None => return Ok(()),
         };
 
         let auth = WwwAuthenticate::parse_header(&dist_hdr.as_bytes().into())?;
-        // If challenge_opt is not set it means that no challenge was present, even though the header
-        // was present. Since we do not handle basic auth, it could be the case that the upstream service
-        // is in compatibility mode with a Docker v1 registry.
+        // If challenge_opt is not set it means that no bearer challenge was present, even though
+        // the header was present. The registry then wants HTTP Basic authentication, as it could
+        // be in compatibility mode with a Docker v1 registry, so the credentials are sent along
+        // with every request.
         let challenge_opt = match auth.get::<BearerChallenge>() {
             Some(co) => co,
-            None => return Ok(()),
+            None => {
+                match authentication {
+                    RegistryAuth::Basic(username, password) => {
+                        debug!("Using basic authentication for image '{:?}'", image);
+                        self.tokens.insert(
+                            self.get_registry(image),
+                            RegistryAuthorization::Basic(username.clone(), password.clone()),
+                        );
+                    }
+                    RegistryAuth::IdentityToken(_) => {
+                        debug!(
+                            "No token server to exchange the identity token with for image '{:?}'",
+                            image
+                        );
+                    }
+                    RegistryAuth::Anonymous => (),
+                }
+                return Ok(());
+            }
         };
 
         // Allow for either push or pull authentication
@@ -312,17 +392,36 @@ impl Client {
             query.push(("service", s))
         }
 
-        // TODO: At some point in the future, we should support sending a secret to the
-        // server for auth. This particular workflow is for read-only public auth.
         debug!("Making authentication call to {}", realm);
 
-        let auth_res = self
-            .client
-            .get(realm)
-            .query(&query)
-            .apply_authentication(authentication)
-            .send()
-            .await?;
+        let auth_res = match authentication {
+            // Identity tokens are refresh tokens, which are exchanged for an access token
+            // following OAuth2 instead of being sent as credentials.
+            RegistryAuth::IdentityToken(identity_token) => {
+                let mut form = vec![
+                    ("grant_type", "refresh_token"),
+                    ("client_id", OAUTH_CLIENT_ID),
+                    ("refresh_token", identity_token.as_str()),
+                    ("scope", scope.as_str()),
+                ];
+                if let Some(s) = service {
+                    form.push(("service", s.as_str()))
+                }
+                self.client_for(image)
+                    .post(realm)
+                    .form(&form)
+                    .send()
+                    .await?
+            }
+            _ => {
+                self.client_for(image)
+                    .get(realm)
+                    .query(&query)
+                    .apply_authentication(authentication)
+                    .send()
+                    .await?
+            }
+        };
 
         match auth_res.status() {
             reqwest::StatusCode::OK => {
@@ -331,7 +430,10 @@ impl Client {
                 let token: RegistryToken = serde_json::from_str(&text)
                     .context("Failed to decode registry token from auth request")?;
                 debug!("Succesfully authorized for image '{:?}'", image);
-                self.tokens.insert(self.get_registry(image), token);
+                self.tokens.insert(
+                    self.get_registry(image),
+                    RegistryAuthorization::Bearer(token),
+                );
                 Ok(())
             }
             _ => {
@@ -357,9 +459,13 @@ impl Client {
 
         let url = self.to_v2_manifest_url(image);
         debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
+        let res = self
+            .send_with_retry(|| {
+                self.client_for(image)
+                    .get(&url)
+                    .headers(self.auth_headers(image))
+            })
+            .await?;
 
         // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
         // Obviously, HTTP servers are going to send other codes. This tries to catch the
@@ -372,7 +478,11 @@ impl Client {
                 // FIXME: This should not have to wrap the error.
                 Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
             }
-            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
+            s if s.is_server_error() => Err(ServerError {
+                url: url.to_string(),
+                status: s.as_u16(),
+            }
+            .into()),
             s => Err(anyhow::anyhow!(
                 "An unexpected error occured: code={}, message='{}'",
                 s,
@@ -384,14 +494,18 @@ impl Client {
     async fn validate_layers(
         &self,
         manifest: &OciManifest,
-        accepted_media_types: Vec<&str>,
+        accepted_media_types: &[&str],
     ) -> anyhow::Result<()> {
         if manifest.layers.is_empty() {
             return Err(anyhow::anyhow!("no layers to pull"));
         }
 
         for layer in &manifest.layers {
-            if !accepted_media_types.iter().any(|i| i.eq(&layer.media_type)) {
+            let decompressed = decompressed_media_type(&layer.media_type);
+            if !accepted_media_types
+                .iter()
+                .any(|i| i.eq(&layer.media_type) || i.eq(&decompressed))
+            {
                 return Err(anyhow::anyhow!(
                     "incompatible layer media type: {}",
                     layer.media_type
@@ -425,12 +539,67 @@ impl Client {
     ///
     /// If the connection has already gone through authentication, this will
     /// use the bearer token. Otherwise, this will attempt an anonymous pull.
+    ///
+    /// If the reference points at an image index (or a Docker manifest list),
+    /// the manifest for the configured platform is pulled instead. The digest
+    /// returned is still the one of the index, so that it matches the one
+    /// returned by `fetch_manifest_digest` for the same reference.
     async fn _pull_manifest(&self, image: &Reference) -> anyhow::Result<(OciManifest, String)> {
+        let (text, digest) = self.fetch_manifest_text(image).await?;
+        let versioned = self.validate_image_manifest(&text).await?;
+
+        let text = match versioned.media_type.as_deref() {
+            Some(OCI_IMAGE_INDEX_MEDIA_TYPE) | Some(IMAGE_MANIFEST_LIST_MEDIA_TYPE) => {
+                debug!("Parsing response as OciImageIndex: {}", text);
+                let index: OciImageIndex = serde_json::from_str(&text).with_context(|| {
+                    format!(
+                        "Failed to parse response from pulling manifest for '{:?}' as an OciImageIndex",
+                        image
+                    )
+                })?;
+                let entry = select_manifest(&index, image, &self.platform())?;
+                let (text, _) = self
+                    .fetch_manifest_text(&image.clone_with_digest(entry.digest.clone()))
+                    .await?;
+                let versioned = self.validate_image_manifest(&text).await?;
+                if !versioned
+                    .media_type
+                    .as_deref()
+                    .map(is_image_manifest_media_type)
+                    .unwrap_or(true)
+                {
+                    return Err(anyhow::anyhow!(
+                        "image index entry {} is not an image manifest",
+                        entry.digest
+                    ));
+                }
+                text
+            }
+            _ => text,
+        };
+
+        debug!("Parsing response as OciManifest: {}", text);
+        let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
+                image
+            )
+        })?;
+        Ok((manifest, digest))
+    }
+
+    /// Fetch the raw manifest for the given reference, returning it along with
+    /// its digest.
+    async fn fetch_manifest_text(&self, image: &Reference) -> anyhow::Result<(String, String)> {
         let url = self.to_v2_manifest_url(image);
         debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
+        let res = self
+            .send_with_retry(|| {
+                self.client_for(image)
+                    .get(&url)
+                    .headers(self.auth_headers(image))
+            })
+            .await?;
 
         // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
         // Obviously, HTTP servers are going to send other codes. This tries to catch the
@@ -439,17 +608,7 @@ impl Client {
             reqwest::StatusCode::OK => {
                 let digest = digest_header_value(&res)?;
                 let text = res.text().await?;
-
-                self.validate_image_manifest(&text).await?;
-
-                debug!("Parsing response as OciManifest: {}", text);
-                let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
-                    format!(
-                        "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
-                        image
-                    )
-                })?;
-                Ok((manifest, digest))
+                Ok((text, digest))
             }
             s if s.is_client_error() => {
                 // According to the OCI spec, we should see an error in the message body.
@@ -457,7 +616,11 @@ impl Client {
                 // FIXME: This should not have to wrap the error.
                 Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
             }
-            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
+            s if s.is_server_error() => Err(ServerError {
+                url: url.to_string(),
+                status: s.as_u16(),
+            }
+            .into()),
             s => Err(anyhow::anyhow!(
                 "An unexpected error occured: code={}, message='{}'",
                 s,
@@ -466,7 +629,7 @@ impl Client {
         }
     }
 
-    async fn validate_image_manifest(&self, text: &str) -> anyhow::Result<()> {
+    async fn validate_image_manifest(&self, text: &str) -> anyhow::Result<Versioned> {
         debug!("validating manifest: {}", text);
         let versioned: Versioned = serde_json::from_str(&text)
             .with_context(|| "Failed to parse manifest as a Versioned object")?;
@@ -476,14 +639,57 @@ impl Client {
                 versioned.schema_version
             ));
         }
-        if let Some(media_type) = versioned.media_type {
-            // TODO: support manifest lists?
-            if media_type != IMAGE_MANIFEST_MEDIA_TYPE {
+        if let Some(media_type) = versioned.media_type.as_deref() {
+            if !is_image_manifest_media_type(media_type)
+                && media_type != OCI_IMAGE_INDEX_MEDIA_TYPE
+                && media_type != IMAGE_MANIFEST_LIST_MEDIA_TYPE
+            {
                 return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
             }
         }
 
-        Ok(())
+        Ok(versioned)
+    }
+
+    /// Fetch the digest of the manifest for the given platform, if the image
+    /// is an image index (or a Docker manifest list). Returns `None` for images
+    /// that are a single manifest, which are the same on every platform.
+    ///
+    /// This lets the manifest for a platform other than the configured one be
+    /// pulled, by pulling the image by the returned digest.
+    pub async fn fetch_platform_manifest_digest(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        platform: &Platform,
+    ) -> anyhow::Result<Option<String>> {
+        if !self.tokens.contains_key(image.registry()) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let (text, _) = self.fetch_manifest_text(image).await?;
+        let versioned = self.validate_image_manifest(&text).await?;
+        match versioned.media_type.as_deref() {
+            Some(OCI_IMAGE_INDEX_MEDIA_TYPE) | Some(IMAGE_MANIFEST_LIST_MEDIA_TYPE) => {
+                let index: OciImageIndex = serde_json::from_str(&text).with_context(|| {
+                    format!(
+                        "Failed to parse response from pulling manifest for '{:?}' as an OciImageIndex",
+                        image
+                    )
+                })?;
+                let entry = select_manifest(&index, image, platform)?;
+                Ok(Some(entry.digest.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The platform to select from image indexes.
+    fn platform(&self) -> Platform {
+        self.config
+            .platform
+            .clone()
+            .unwrap_or_else(Platform::current)
     }
 
     /// Pull a manifest and its config from the remote OCI Distribution service.
@@ -527,28 +733,136 @@ impl Client {
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
     /// used for that.)
-    async fn pull_layer<T: AsyncWrite + Unpin>(
+    ///
+    /// The downloaded blob is checked against the digest, and a
+    /// [`DigestMismatchError`] is returned if it doesn't match. Blobs are
+    /// written to `out` as they are downloaded, so whatever was written
+    /// must be discarded if an error is returned. A download that fails
+    /// partway is resumed where it stopped, so nothing is written twice.
+    ///
+    /// The client must already have been authenticated against the
+    /// registry, e.g. by pulling the manifest of the image first.
+    pub async fn pull_layer<T: AsyncWrite + Unpin>(
         &self,
         image: &Reference,
         digest: &str,
         mut out: T,
     ) -> anyhow::Result<()> {
-        let url = self.to_v2_blob_url(&self.get_registry(image), image.repository(), digest);
-        let mut stream = self
-            .client
-            .get(&url)
-            .headers(self.auth_headers(image))
-            .send()
-            .await?
-            .bytes_stream();
+        let mut verifier = DigestVerifier::new(digest)?;
+        let mut download = BlobDownload::new(self, image, digest);
+
+        while let Some(bytes) = download.chunk().await? {
+            verifier.update(&bytes);
+            out.write_all(&bytes).await?;
+        }
+        verifier.verify()?;
+
+        Ok(())
+    }
 
-        while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+    /// Pull a single layer from an OCI registry, decompressing it as it is
+    /// downloaded according to its media type.
+    ///
+    /// The downloaded blob is checked against the digest and size of the
+    /// layer, and the layer may not decompress to more than the maximum
+    /// decompressed layer size of the client configuration. If any of these
+    /// checks fail, an error is returned and whatever was written to `out`
+    /// must be discarded.
+    ///
+    /// The client must already have been authenticated against the
+    /// registry, e.g. by pulling the manifest of the image first.
+    pub async fn pull_layer_decompressed<T: AsyncWrite + Unpin>(
+        &self,
+        image: &Reference,
+        layer: &OciDescriptor,
+        mut out: T,
+    ) -> anyhow::Result<()> {
+        let mut verifier = DigestVerifier::new(&layer.digest)?;
+        let mut decompressor = Decompressor::new(
+            Compression::from_media_type(&layer.media_type),
+            self.max_decompressed_layer_size(),
+        )?;
+        let mut download = BlobDownload::new(self, image, &layer.digest);
+
+        let mut size = 0;
+        while let Some(bytes) = download.chunk().await? {
+            size += bytes.len() as i64;
+            if size > layer.size {
+                return Err(anyhow::anyhow!(
+                    "layer {} is larger than its size of {} bytes",
+                    layer.digest,
+                    layer.size
+                ));
+            }
+            verifier.update(&bytes);
+            out.write_all(&decompressor.update(&bytes)?).await?;
         }
+        verifier.verify()?;
+        out.write_all(&decompressor.finish()?).await?;
 
         Ok(())
     }
 
+    /// Decompress a layer pulled with [`Client::pull_layer`] according to its
+    /// media type, failing if it decompresses to more than the maximum
+    /// decompressed layer size of the client configuration. The data is
+    /// returned as it is if the layer is not compressed.
+    pub fn decompress_layer(&self, layer: &OciDescriptor, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        crate::compression::decompress(&layer.media_type, data, self.max_decompressed_layer_size())
+    }
+
+    fn max_decompressed_layer_size(&self) -> u64 {
+        self.config
+            .max_decompressed_layer_size
+            .unwrap_or(DEFAULT_MAX_DECOMPRESSED_LAYER_SIZE)
+    }
+
+    /// The delay before retrying a request that has already been retried
+    /// `retries` times, or `None` if it has been retried as often as the
+    /// client configuration allows. The delay doubles with every retry.
+    fn retry_delay(&self, retries: u32) -> Option<Duration> {
+        if retries >= self.config.max_retries.unwrap_or(DEFAULT_MAX_RETRIES) {
+            return None;
+        }
+        let backoff = self.config.retry_backoff.unwrap_or(DEFAULT_RETRY_BACKOFF);
+        let delay = backoff
+            .checked_mul(2u32.saturating_pow(retries))
+            .unwrap_or(MAX_RETRY_BACKOFF);
+        Some(delay.min(MAX_RETRY_BACKOFF))
+    }
+
+    /// Send the request made by `request`, making it again while the registry
+    /// can't be reached or answers with an error that may be temporary, until
+    /// the retries run out. The response to the last try is returned whatever
+    /// its status, so that callers report errors as they would without
+    /// retries.
+    async fn send_with_retry(
+        &self,
+        request: impl Fn() -> reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut retries = 0;
+        loop {
+            let delay = match self.retry_delay(retries) {
+                Some(delay) => delay,
+                None => return Ok(request().send().await?),
+            };
+            match request().send().await {
+                Ok(response) if is_transient_status(response.status()) => warn!(
+                    "Request to {} failed with {}, retrying in {:?}",
+                    response.url(),
+                    response.status(),
+                    delay
+                ),
+                Err(e) if is_transient_error(&e) => {
+                    warn!("Request failed, retrying in {:?}: {}", delay, e)
+                }
+                result => return Ok(result?),
+            }
+            tokio::time::sleep(delay).await;
+            retries += 1;
+        }
+    }
+
     /// Begins a session to push an image to registry
     ///
     /// Returns URL with session UUID
@@ -557,7 +871,12 @@ impl Client {
         let mut headers = self.auth_headers(image);
         headers.insert("Content-Length", "0".parse().unwrap());
 
-        let res = self.client.post(url).headers(headers).send().await?;
+        let res = self
+            .client_for(image)
+            .post(url)
+            .headers(headers)
+            .send()
+            .await?;
 
         // OCI spec requires the status code be 202 Accepted to successfully begin the push process
         self.extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
@@ -577,7 +896,12 @@ impl Client {
         let mut close_headers = self.auth_headers(image);
         close_headers.insert("Content-Length", "0".parse().unwrap());
 
-        let res = self.client.put(&url).headers(close_headers).send().await?;
+        let res = self
+            .client_for(image)
+            .put(&url)
+            .headers(close_headers)
+            .send()
+            .await?;
         self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
             .await
     }
@@ -608,7 +932,7 @@ impl Client {
         headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
 
         let res = self
-            .client
+            .client_for(image)
             .patch(location)
             .headers(headers)
             .body(layer)
@@ -659,7 +983,7 @@ impl Client {
         );
 
         let res = self
-            .client
+            .client_for(image)
             .put(&url)
             .headers(headers)
             .body(serde_json::to_string(manifest)?)
@@ -796,19 +1120,39 @@ impl Client {
 
     /// Generate the headers necessary for authentication.
     ///
-    /// If the struct has Some(bearer), this will insert the bearer token in an
-    /// Authorization header. It will also set the Accept header, which must
-    /// be set on all OCI Registry request.
+    /// If the client has authorized for the registry, this will insert the bearer
+    /// token or basic credentials in an Authorization header. It will also set the
+    /// Accept header, which must be set on all OCI Registry request.
     fn auth_headers(&self, image: &Reference) -> HeaderMap {
         let mut headers = HeaderMap::new();
-        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json".parse().unwrap());
+        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json,application/vnd.oci.image.index.v1+json".parse().unwrap());
 
-        if let Some(token) = self.tokens.get(&self.get_registry(&image)) {
-            headers.insert("Authorization", token.bearer_token().parse().unwrap());
+        if let Some(authorization) = self.tokens.get(&self.get_registry(&image)) {
+            let mut value: reqwest::header::HeaderValue =
+                authorization.header_value().parse().unwrap();
+            value.set_sensitive(true);
+            headers.insert("Authorization", value);
         }
         headers
     }
 
+    /// Get the HTTP client for the registry of a given `Reference`, which doesn't
+    /// verify certificates if the registry is one the configuration accepts
+    /// invalid certificates from.
+    fn client_for(&self, image: &Reference) -> &reqwest::Client {
+        match &self.insecure_client {
+            Some(insecure_client)
+                if self
+                    .config
+                    .accept_invalid_certificates_from
+                    .contains(&image.registry().to_owned()) =>
+            {
+                insecure_client
+            }
+            _ => &self.client,
+        }
+    }
+
     /// Get the registry address of a given `Reference`.
     ///
     /// Some registries, such as docker.io, uses a different address for the actual
@@ -822,6 +1166,144 @@ impl Client {
     }
 }
 
+/// A blob being downloaded from a registry. When the connection fails partway,
+/// the rest of the blob is requested with a range request, so that large
+/// layers pulled over unreliable links don't start over.
+struct BlobDownload<'a> {
+    client: &'a Client,
+    image: &'a Reference,
+    url: String,
+    response: Option<reqwest::Response>,
+    /// The number of bytes of the blob received so far
+    received: u64,
+    /// The number of bytes to drop from the start of the response, when the
+    /// registry sent the whole blob again rather than the range asked for
+    skip: u64,
+    retries: u32,
+}
+
+impl<'a> BlobDownload<'a> {
+    fn new(client: &'a Client, image: &'a Reference, digest: &str) -> Self {
+        BlobDownload {
+            client,
+            image,
+            url: client.to_v2_blob_url(&client.get_registry(image), image.repository(), digest),
+            response: None,
+            received: 0,
+            skip: 0,
+            retries: 0,
+        }
+    }
+
+    /// The next chunk of the blob, or `None` once all of it has been received.
+    async fn chunk(&mut self) -> anyhow::Result<Option<Bytes>> {
+        loop {
+            let mut response = match self.response.take() {
+                Some(response) => response,
+                None => self.request().await?,
+            };
+            match response.chunk().await {
+                Ok(Some(mut bytes)) => {
+                    self.response = Some(response);
+                    let skipped = self.skip.min(bytes.len() as u64);
+                    self.skip -= skipped;
+                    let bytes = bytes.split_off(skipped as usize);
+                    if !bytes.is_empty() {
+                        self.received += bytes.len() as u64;
+                        return Ok(Some(bytes));
+                    }
+                }
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    let delay = match self.client.retry_delay(self.retries) {
+                        Some(delay) => delay,
+                        None => return Err(e.into()),
+                    };
+                    warn!(
+                        "Download from {} failed after {} bytes, resuming in {:?}: {}",
+                        self.url, self.received, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    self.retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Request the blob from where the download stopped.
+    async fn request(&mut self) -> anyhow::Result<reqwest::Response> {
+        let received = self.received;
+        let response = self
+            .client
+            .send_with_retry(|| {
+                let request = self
+                    .client
+                    .client_for(self.image)
+                    .get(&self.url)
+                    .headers(self.client.auth_headers(self.image));
+                if received > 0 {
+                    request.header(reqwest::header::RANGE, format!("bytes={}-", received))
+                } else {
+                    request
+                }
+            })
+            .await?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => self.skip = received,
+            reqwest::StatusCode::PARTIAL_CONTENT
+                if content_range_start(response.headers()) == Some(received) =>
+            {
+                self.skip = 0
+            }
+            s if s.is_server_error() => {
+                return Err(ServerError {
+                    url: self.url.clone(),
+                    status: s.as_u16(),
+                }
+                .into())
+            }
+            s => {
+                return Err(anyhow::anyhow!(
+                    "Failed to pull blob from {}: code={}, message='{}'",
+                    self.url,
+                    s,
+                    response.text().await?
+                ))
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Whether a request that got this status may succeed if it is made again.
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// Whether a request that failed with this error may succeed if it is made
+/// again, as it failed to reach the registry or to get its response.
+fn is_transient_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request() || error.is_body()
+}
+
+/// The first byte of the blob in a partial response, from its `Content-Range`
+/// header (e.g. `bytes 1024-4095/4096`).
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
 /// The encoding of the certificate
 #[derive(Debug, Clone)]
 pub enum CertificateEncoding {
@@ -841,6 +1323,58 @@ pub struct Certificate {
     pub data: Vec<u8>,
 }
 
+/// The encoding of a client identity
+#[derive(Debug, Clone)]
+pub enum IdentityEncoding {
+    /// A DER encoded PKCS #12 archive, which needs the `native-tls` feature
+    Pkcs12,
+    /// A PEM encoded certificate chain and private key, which needs the
+    /// `rustls-tls` feature
+    Pem,
+}
+
+/// A client certificate and its private key, which the client presents to
+/// registries that authenticate clients with TLS
+#[derive(Clone)]
+pub struct ClientIdentity {
+    /// Which encoding is used by the identity
+    pub encoding: IdentityEncoding,
+
+    /// Actual identity
+    pub data: Vec<u8>,
+
+    /// The password a PKCS #12 archive is encrypted with
+    pub password: Option<String>,
+}
+
+impl ClientIdentity {
+    fn to_reqwest_identity(&self) -> anyhow::Result<reqwest::Identity> {
+        match self.encoding {
+            #[cfg(feature = "native-tls")]
+            IdentityEncoding::Pkcs12 => Ok(reqwest::Identity::from_pkcs12_der(
+                &self.data,
+                self.password.as_deref().unwrap_or_default(),
+            )?),
+            #[cfg(feature = "rustls-tls")]
+            IdentityEncoding::Pem => Ok(reqwest::Identity::from_pem(&self.data)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(anyhow::anyhow!(
+                "{:?} client identities are not supported by the TLS implementation of the client",
+                self.encoding
+            )),
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Leave out the private key and its password
+        f.debug_struct("ClientIdentity")
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
 /// A client configuration
 #[derive(Debug, Clone, Default)]
 pub struct ClientConfig {
@@ -853,9 +1387,39 @@ pub struct ClientConfig {
     /// Accept invalid certificates. Defaults to false
     pub accept_invalid_certificates: bool,
 
+    /// Registries whose certificates and hostnames are accepted even if they
+    /// are invalid, such as self-hosted registries with self-signed
+    /// certificates. The certificates of all other registries are verified,
+    /// unless `accept_invalid_certificates` is set. Defaults to none
+    pub accept_invalid_certificates_from: Vec<String>,
+
     /// A list of extra root certificate to trust. This can be used to connect
     /// to servers using self-signed certificates
     pub extra_root_certificates: Vec<Certificate>,
+
+    /// A client certificate and private key to present to registries that
+    /// authenticate clients with TLS. Defaults to none
+    pub client_identity: Option<ClientIdentity>,
+
+    /// The platform whose manifest is pulled when a reference points at an
+    /// image index or manifest list. Defaults to the platform the client is
+    /// running on.
+    pub platform: Option<Platform>,
+
+    /// The largest size in bytes a compressed layer may decompress to, which
+    /// protects the client from layers that decompress to far more than they
+    /// take to download. Defaults to 1GiB.
+    pub max_decompressed_layer_size: Option<u64>,
+
+    /// How many times a manifest or blob request is retried when the registry
+    /// can't be reached, the connection fails partway or the registry answers
+    /// with an error that may be temporary. Blob downloads that fail partway
+    /// are resumed where they stopped. Defaults to 3, and 0 turns retries off.
+    pub max_retries: Option<u32>,
+
+    /// The delay before the first retry of a request, which doubles with
+    /// every retry after it, up to 30 seconds. Defaults to 1 second.
+    pub retry_backoff: Option<Duration>,
 }
 
 /// The protocol that the client should use to connect
@@ -891,6 +1455,29 @@ impl ClientProtocol {
     }
 }
 
+/// The `client_id` sent to token servers when exchanging identity tokens.
+const OAUTH_CLIENT_ID: &str = "oci-distribution";
+
+/// How requests to a registry are authorized once the client has authenticated.
+enum RegistryAuthorization {
+    /// A token from the registry's token server
+    Bearer(RegistryToken),
+    /// HTTP Basic credentials, for registries that don't hand out tokens
+    Basic(String, String),
+}
+
+impl RegistryAuthorization {
+    fn header_value(&self) -> String {
+        match self {
+            RegistryAuthorization::Bearer(token) => token.bearer_token(),
+            RegistryAuthorization::Basic(username, password) => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+        }
+    }
+}
+
 /// A token granted during the OAuth2-like workflow for OCI registries.
 #[derive(Deserialize)]
 #[serde(untagged)]
@@ -951,6 +1538,38 @@ impl Challenge for BearerChallenge {
     }
 }
 
+fn is_image_manifest_media_type(media_type: &str) -> bool {
+    media_type == IMAGE_MANIFEST_MEDIA_TYPE || media_type == OCI_IMAGE_MEDIA_TYPE
+}
+
+/// The entry of the manifest for the platform in the image index, or an error
+/// listing the platforms the index has manifests for.
+fn select_manifest<'a>(
+    index: &'a OciImageIndex,
+    image: &Reference,
+    platform: &Platform,
+) -> anyhow::Result<&'a ImageIndexEntry> {
+    let entry = index.select(platform).ok_or_else(|| {
+        anyhow::anyhow!(
+            "no manifest for platform {} in image index for '{:?}', available platforms: {}",
+            platform,
+            image,
+            index
+                .manifests
+                .iter()
+                .filter_map(|entry| entry.platform.as_ref())
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })?;
+    debug!(
+        "Selected manifest {} for platform {} from image index",
+        entry.digest, platform
+    );
+    Ok(entry)
+}
+
 fn digest_header_value(response: &reqwest::Response) -> anyhow::Result<String> {
     let headers = response.headers();
     let digest_header = headers.get("Docker-Content-Digest");
@@ -963,6 +1582,54 @@ fn digest_header_value(response: &reqwest::Response) -> anyhow::Result<String> {
     }
 }
 
+/// Computes the digest of a blob as it is downloaded, to check it against the
+/// digest it was pulled by.
+struct DigestVerifier {
+    digest: String,
+    hasher: DigestHasher,
+}
+
+enum DigestHasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl DigestVerifier {
+    fn new(digest: &str) -> anyhow::Result<Self> {
+        let hasher = match digest.split(':').next() {
+            Some("sha256") => DigestHasher::Sha256(sha2::Sha256::new()),
+            Some("sha512") => DigestHasher::Sha512(sha2::Sha512::new()),
+            _ => return Err(anyhow::anyhow!("unsupported digest algorithm: {}", digest)),
+        };
+        Ok(DigestVerifier {
+            digest: digest.to_owned(),
+            hasher,
+        })
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            DigestHasher::Sha256(hasher) => hasher.update(data),
+            DigestHasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn verify(self) -> anyhow::Result<()> {
+        let computed = match self.hasher {
+            DigestHasher::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            DigestHasher::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        };
+        if computed != self.digest {
+            return Err(DigestMismatchError {
+                digest: self.digest,
+                computed,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
 /// Computes the SHA256 digest of a byte vector
 fn sha256_digest(bytes: &[u8]) -> String {
     format!("sha256:{:x}", sha2::Sha256::digest(bytes))
@@ -1029,6 +1696,30 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_digest_verifier() {
+        let digest = "sha256:039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81";
+        let mut verifier = DigestVerifier::new(digest).expect("supported digest");
+        verifier.update(&[1, 2]);
+        verifier.update(&[3]);
+        verifier.verify().expect("blob matches its digest");
+
+        let mut verifier = DigestVerifier::new(digest).expect("supported digest");
+        verifier.update(&[1, 2]);
+        let error = verifier
+            .verify()
+            .expect_err("blob doesn't match its digest");
+        assert_eq!(
+            Some(&DigestMismatchError {
+                digest: digest.to_owned(),
+                computed: sha256_digest(&[1, 2]),
+            }),
+            error.downcast_ref::<DigestMismatchError>()
+        );
+
+        assert!(DigestVerifier::new("md5:1234").is_err());
+    }
+
     #[test]
     fn manifest_url_generation_respects_http_protocol() {
         let c = Client::new(ClientConfig {
@@ -1240,6 +1931,83 @@ mod test {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_client_for_insecure_registries() {
+        let c = Client::try_from(ClientConfig {
+            accept_invalid_certificates_from: vec!["local:5000".to_owned()],
+            ..Default::default()
+        })
+        .expect("client is created");
+        let insecure_client = c
+            .insecure_client
+            .as_ref()
+            .expect("insecure client is built");
+
+        let local = Reference::try_from("local:5000/hello-wasm:v1").unwrap();
+        assert!(std::ptr::eq(insecure_client, c.client_for(&local)));
+        let remote = Reference::try_from(HELLO_IMAGE_TAG).unwrap();
+        assert!(std::ptr::eq(&c.client, c.client_for(&remote)));
+
+        assert!(Client::try_from(ClientConfig::default())
+            .unwrap()
+            .insecure_client
+            .is_none());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let c = Client::default();
+        assert_eq!(Some(Duration::from_secs(1)), c.retry_delay(0));
+        assert_eq!(Some(Duration::from_secs(4)), c.retry_delay(2));
+        assert_eq!(None, c.retry_delay(3));
+
+        let c = Client::new(ClientConfig {
+            max_retries: Some(40),
+            retry_backoff: Some(Duration::from_secs(10)),
+            ..Default::default()
+        });
+        assert_eq!(Some(Duration::from_secs(20)), c.retry_delay(1));
+        assert_eq!(Some(MAX_RETRY_BACKOFF), c.retry_delay(2));
+        assert_eq!(Some(MAX_RETRY_BACKOFF), c.retry_delay(39));
+        assert_eq!(None, c.retry_delay(40));
+
+        let c = Client::new(ClientConfig {
+            max_retries: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(None, c.retry_delay(0));
+    }
+
+    #[test]
+    fn test_content_range_start() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, content_range_start(&headers));
+        headers.insert(
+            reqwest::header::CONTENT_RANGE,
+            "bytes 1024-4095/4096".parse().unwrap(),
+        );
+        assert_eq!(Some(1024), content_range_start(&headers));
+        headers.insert(
+            reqwest::header::CONTENT_RANGE,
+            "bytes */4096".parse().unwrap(),
+        );
+        assert_eq!(None, content_range_start(&headers));
+    }
+
+    #[test]
+    fn test_registry_authorization_header_value() {
+        let token: RegistryToken = serde_json::from_str(r#"{"access_token": "abc"}"#).unwrap();
+        assert_eq!(
+            "Bearer abc",
+            RegistryAuthorization::Bearer(token).header_value()
+        );
+        assert_eq!(
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==",
+            RegistryAuthorization::Basic("Aladdin".to_owned(), "open sesame".to_owned())
+                .header_value()
+        );
+    }
+
     #[tokio::test]
     async fn test_auth() {
         for &image in TEST_IMAGES {
@@ -1253,10 +2021,10 @@ mod test {
             .await
             .expect("result from auth request");
 
-            let tok = c
-                .tokens
-                .get(reference.registry())
-                .expect("token is available");
+            let tok = match c.tokens.get(reference.registry()) {
+                Some(RegistryAuthorization::Bearer(tok)) => tok,
+                _ => panic!("token is available"),
+            };
             // We test that the token is longer than a minimal hash.
             assert!(tok.token().len() > 64);
         }
@@ -1664,15 +2432,47 @@ mod test {
     #[tokio::test]
     async fn test_pull_docker_io() {
         let reference = Reference::try_from(DOCKER_IO_IMAGE).expect("failed to parse reference");
-        let mut c = Client::default();
-        let err = c
+        let mut c = Client::new(ClientConfig {
+            platform: Some(Platform::new("linux", "amd64")),
+            ..Default::default()
+        });
+        // This is a manifest list, so this pulls the linux/amd64 manifest from it
+        let (manifest, _digest) = c
             .pull_manifest(&reference, &RegistryAuth::Anonymous)
             .await
-            .unwrap_err();
-        // we don't support manifest list so pulling failed but this error means it did downloaded it
+            .expect("failed to pull manifest");
         assert_eq!(
-            format!("{}", err),
-            "unsupported media type: application/vnd.docker.distribution.manifest.list.v2+json"
+            Some(IMAGE_MANIFEST_MEDIA_TYPE.to_owned()),
+            manifest.media_type
         );
+
+        // The manifest for another platform can be pulled by its digest
+        let digest = c
+            .fetch_platform_manifest_digest(
+                &reference,
+                &RegistryAuth::Anonymous,
+                &Platform::new("linux", "arm64"),
+            )
+            .await
+            .expect("failed to fetch platform manifest digest")
+            .expect("image is a manifest list");
+        let (_, pulled_digest) = c
+            .pull_manifest(
+                &reference.clone_with_digest(digest.clone()),
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .expect("failed to pull platform manifest");
+        assert_eq!(digest, pulled_digest);
+
+        let mut c = Client::new(ClientConfig {
+            platform: Some(Platform::new("wasi", "wasm")),
+            ..Default::default()
+        });
+        let err = c
+            .pull_manifest(&reference, &RegistryAuth::Anonymous)
+            .await
+            .unwrap_err();
+        assert!(format!("{}", err).starts_with("no manifest for platform wasi/wasm"));
     }
 }
@@ -0,0 +1,218 @@
+//! Decompression of image layers according to their media type
+
+use std::io::Write;
+
+/// The largest size a layer may decompress to when the client configuration doesn't set one,
+/// 1GiB.
+pub const DEFAULT_MAX_DECOMPRESSED_LAYER_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How the content of a layer is compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// The content is stored as it is.
+    None,
+    /// The content is gzip compressed, as in `application/vnd.oci.image.layer.v1.tar+gzip`.
+    Gzip,
+    /// The content is zstd compressed, as in `application/vnd.oci.image.layer.v1.tar+zstd`.
+    Zstd,
+}
+
+impl Compression {
+    /// The compression of layers of the given media type.
+    pub fn from_media_type(media_type: &str) -> Self {
+        if media_type.ends_with("+gzip") || media_type.ends_with(".tar.gzip") {
+            Compression::Gzip
+        } else if media_type.ends_with("+zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// The media type of the content of layers of the given media type once decompressed, e.g.
+/// `application/vnd.oci.image.layer.v1.tar` for `application/vnd.oci.image.layer.v1.tar+gzip`.
+pub fn decompressed_media_type(media_type: &str) -> &str {
+    match Compression::from_media_type(media_type) {
+        Compression::None => media_type,
+        Compression::Gzip => media_type
+            .strip_suffix("+gzip")
+            .or_else(|| media_type.strip_suffix(".gzip"))
+            .unwrap_or(media_type),
+        Compression::Zstd => media_type.strip_suffix("+zstd").unwrap_or(media_type),
+    }
+}
+
+/// Decompress a whole layer of the given media type, failing if its content is larger than
+/// `limit` bytes.
+pub fn decompress(media_type: &str, data: &[u8], limit: u64) -> anyhow::Result<Vec<u8>> {
+    let mut decompressor = Decompressor::new(Compression::from_media_type(media_type), limit)?;
+    let mut out = decompressor.update(data)?;
+    out.extend(decompressor.finish()?);
+    Ok(out)
+}
+
+/// A streaming decoder for the content of a layer.
+///
+/// Compressed data is fed to it as it is downloaded, and the content decompressed so far is
+/// taken out after each chunk, so that a layer never has to be held in memory twice. The
+/// content may not grow past the limit the decompressor is created with, which stops layers
+/// that decompress to far more than they take to download.
+pub struct Decompressor {
+    decoder: Decoder,
+}
+
+enum Decoder {
+    Plain(LimitedBuffer),
+    Gzip(flate2::write::GzDecoder<LimitedBuffer>),
+    Zstd(zstd::stream::write::Decoder<'static, LimitedBuffer>),
+}
+
+impl Decompressor {
+    /// Create a decompressor for content compressed with `compression`, that fails once the
+    /// content is larger than `limit` bytes.
+    pub fn new(compression: Compression, limit: u64) -> anyhow::Result<Self> {
+        let buffer = LimitedBuffer {
+            data: Vec::new(),
+            remaining: limit,
+        };
+        let decoder = match compression {
+            Compression::None => Decoder::Plain(buffer),
+            Compression::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(buffer)),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(buffer)?),
+        };
+        Ok(Decompressor { decoder })
+    }
+
+    /// Decompress the next chunk of data, returning the content decompressed so far.
+    pub fn update(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let buffer = match &mut self.decoder {
+            Decoder::Plain(buffer) => {
+                buffer.write_all(data)?;
+                buffer
+            }
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(&mut buffer.data))
+    }
+
+    /// Finish decompressing, returning the rest of the content.
+    pub fn finish(self) -> anyhow::Result<Vec<u8>> {
+        let buffer = match self.decoder {
+            Decoder::Plain(buffer) => buffer,
+            Decoder::Gzip(decoder) => decoder.finish()?,
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            }
+        };
+        Ok(buffer.data)
+    }
+}
+
+/// The output of a decoder, which refuses to take more than the remaining number of bytes.
+struct LimitedBuffer {
+    data: Vec<u8>,
+    remaining: u64,
+}
+
+impl Write for LimitedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "layer is larger than the maximum decompressed layer size",
+            ));
+        }
+        self.remaining -= buf.len() as u64;
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_compression_from_media_type() {
+        assert_eq!(
+            Compression::Gzip,
+            Compression::from_media_type(crate::manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::Gzip,
+            Compression::from_media_type(crate::manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::Zstd,
+            Compression::from_media_type(crate::manifest::IMAGE_LAYER_ZSTD_MEDIA_TYPE)
+        );
+        assert_eq!(
+            Compression::None,
+            Compression::from_media_type(crate::manifest::WASM_LAYER_MEDIA_TYPE)
+        );
+        assert_eq!(
+            crate::manifest::WASM_LAYER_MEDIA_TYPE,
+            decompressed_media_type("application/vnd.wasm.content.layer.v1+wasm+zstd")
+        );
+        assert_eq!(
+            "application/vnd.docker.image.rootfs.diff.tar",
+            decompressed_media_type(crate::manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_decompress_in_chunks() {
+        let content = b"hello world ".repeat(100);
+        let compressed = zstd::stream::encode_all(&content[..], 0).unwrap();
+        let mut decompressor = Decompressor::new(Compression::Zstd, 10_000).unwrap();
+        let mut out = Vec::new();
+        for chunk in compressed.chunks(7) {
+            out.extend(decompressor.update(chunk).unwrap());
+        }
+        out.extend(decompressor.finish().unwrap());
+        assert_eq!(content, out);
+
+        let compressed = gzip(&content);
+        assert_eq!(
+            content,
+            decompress(
+                crate::manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                &compressed,
+                10_000
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decompress_stops_at_limit() {
+        let compressed = gzip(&vec![0; 1024 * 1024]);
+        assert!(compressed.len() < 10_000);
+        let e = decompress(
+            crate::manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+            &compressed,
+            10_000,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("maximum decompressed layer size"));
+    }
+}
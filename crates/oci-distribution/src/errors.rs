@@ -27,6 +27,48 @@ impl std::fmt::Display for OciError {
     }
 }
 
+/// A blob pulled from a registry did not match the digest it was pulled by, so it must not be
+/// used.
+///
+/// Pulls fail with this error, wrapped in an `anyhow::Error` that it can be downcast from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestMismatchError {
+    /// The digest the blob was pulled by
+    pub digest: String,
+    /// The digest computed from the blob that was downloaded
+    pub computed: String,
+}
+
+impl std::error::Error for DigestMismatchError {}
+impl std::fmt::Display for DigestMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "blob {} does not match its digest, got {}",
+            self.digest, self.computed
+        )
+    }
+}
+
+/// A registry answered with a server error (a 5xx status), so it may be down or overloaded.
+///
+/// Requests fail with this error, wrapped in an `anyhow::Error` that it can be downcast from, once
+/// the retries for the request have run out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerError {
+    /// The URL of the request
+    pub url: String,
+    /// The status the registry answered with
+    pub status: u16,
+}
+
+impl std::error::Error for ServerError {}
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server error at {}", self.url)
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub(crate) struct OciEnvelope {
     pub(crate) errors: Vec<OciError>,
@@ -2,6 +2,7 @@
 #![deny(missing_docs)]
 
 pub mod client;
+pub mod compression;
 pub mod errors;
 pub mod manifest;
 mod reference;
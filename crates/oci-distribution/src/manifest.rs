@@ -7,6 +7,13 @@ pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+w
 pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
 pub const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The mediatype for an OCI image manifest.
+pub const OCI_IMAGE_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The mediatype that Docker uses for a manifest list.
+pub const IMAGE_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+/// The mediatype for an OCI image index.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
 /// The mediatype for an image config (manifest).
 pub const IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
 /// The mediatype that Docker uses for image configs.
@@ -15,6 +22,8 @@ pub const IMAGE_DOCKER_CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.contain
 pub const IMAGE_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
 /// The mediatype for a layer that is gzipped.
 pub const IMAGE_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+/// The mediatype for a layer that is zstd compressed.
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
 /// The mediatype that Docker uses for a layer that is gzipped.
 pub const IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
     "application/vnd.docker.image.rootfs.diff.tar.gzip";
@@ -144,6 +153,161 @@ impl Default for OciDescriptor {
     }
 }
 
+/// The OCI image index points at the manifests of an image for several platforms.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md
+///
+/// Docker manifest lists use the same layout.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciImageIndex {
+    /// This is a schema version.
+    ///
+    /// The only version allowed by the specification is `2`.
+    pub schema_version: u8,
+
+    /// This is an optional media type describing this index.
+    pub media_type: Option<String>,
+
+    /// The manifests for the platforms this image is available for.
+    pub manifests: Vec<ImageIndexEntry>,
+
+    /// The annotations for this index
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// A manifest referenced by an [`OciImageIndex`].
+///
+/// This is an [`OciDescriptor`] with an additional, optional platform.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndexEntry {
+    /// The media type of the referenced manifest.
+    pub media_type: String,
+    /// The digest of the referenced manifest.
+    pub digest: String,
+    /// The size, in bytes, of the referenced manifest.
+    pub size: i64,
+    /// The platform the referenced manifest is built for.
+    ///
+    /// The specification says this SHOULD be set for image manifests, but it
+    /// is optional.
+    pub platform: Option<Platform>,
+    /// This OPTIONAL property contains arbitrary metadata for this entry.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The platform an image manifest is built for.
+///
+/// This is defined in the OCI Image Specification:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md#image-index-property-descriptions
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Platform {
+    /// The CPU architecture, using the values of Go's `GOARCH` (e.g. `amd64`,
+    /// `arm64` or `wasm`).
+    pub architecture: String,
+    /// The operating system, using the values of Go's `GOOS` (e.g. `linux`
+    /// or `wasi`).
+    pub os: String,
+    /// The version of the operating system.
+    #[serde(rename = "os.version")]
+    pub os_version: Option<String>,
+    /// The required features of the operating system.
+    #[serde(rename = "os.features")]
+    pub os_features: Option<Vec<String>>,
+    /// The variant of the CPU (e.g. `v7` for ARMv7).
+    pub variant: Option<String>,
+    /// The required features of the CPU.
+    pub features: Option<Vec<String>>,
+}
+
+impl Platform {
+    /// Create a platform with the given operating system and architecture.
+    pub fn new(os: &str, architecture: &str) -> Self {
+        Platform {
+            architecture: architecture.to_owned(),
+            os: os.to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: None,
+            features: None,
+        }
+    }
+
+    /// Parses a platform in the `os/architecture[/variant]` form used by
+    /// `docker --platform`.
+    pub fn parse(platform: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = platform.split('/').collect();
+        match parts.as_slice() {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => {
+                Ok(Platform::new(os, architecture))
+            }
+            [os, architecture, variant]
+                if !os.is_empty() && !architecture.is_empty() && !variant.is_empty() =>
+            {
+                Ok(Platform {
+                    variant: Some((*variant).to_owned()),
+                    ..Platform::new(os, architecture)
+                })
+            }
+            _ => Err(anyhow::anyhow!(
+                "invalid platform '{}', expected os/architecture[/variant]",
+                platform
+            )),
+        }
+    }
+
+    /// The platform of the machine this code is running on.
+    pub fn current() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            // Rust names both the big and little endian variants `powerpc64`
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "powerpc64" => "ppc64",
+            "wasm32" => "wasm",
+            arch => arch,
+        };
+        Platform::new(std::env::consts::OS, architecture)
+    }
+
+    /// Returns whether an image built for `other` can be run on this platform.
+    ///
+    /// The operating system and architecture must be equal. If this platform
+    /// has a variant, it must be equal as well.
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+impl OciImageIndex {
+    /// Select the entry of the manifest for the given platform, if the image
+    /// is available for it.
+    pub fn select(&self, platform: &Platform) -> Option<&ImageIndexEntry> {
+        self.manifests.iter().find(|entry| {
+            entry
+                .platform
+                .as_ref()
+                .map(|p| platform.matches(p))
+                .unwrap_or(false)
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -198,4 +362,81 @@ mod test {
                 .len()
         );
     }
+
+    const TEST_INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 7143,
+                "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                "platform": {
+                    "architecture": "amd64",
+                    "os": "linux"
+                }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 7682,
+                "digest": "sha256:5b0bcabd1ed22e9fb1310cf6c2dec7cdef19f0ad69efa1f392e94a4333501270",
+                "platform": {
+                    "architecture": "arm",
+                    "os": "linux",
+                    "variant": "v7"
+                }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1024,
+                "digest": "sha256:9b2bd4a1f8e4c6a0b4a5b7fd3c8c0d1ef5d2fa6a8a4c28e0f6b2f7a3b1c1d0e9",
+                "platform": {
+                    "architecture": "wasm",
+                    "os": "wasi"
+                }
+            }
+        ]
+    }
+    "#;
+
+    #[test]
+    fn test_index_select() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        assert_eq!(3, index.manifests.len());
+
+        let wasi = index
+            .select(&Platform::new("wasi", "wasm"))
+            .expect("wasi manifest");
+        assert_eq!(
+            "sha256:9b2bd4a1f8e4c6a0b4a5b7fd3c8c0d1ef5d2fa6a8a4c28e0f6b2f7a3b1c1d0e9",
+            wasi.digest
+        );
+
+        let armv7 = index
+            .select(&Platform::parse("linux/arm/v7").expect("parsed platform"))
+            .expect("arm manifest");
+        assert_eq!(
+            Some("v7".to_owned()),
+            armv7.platform.as_ref().unwrap().variant
+        );
+
+        assert!(index
+            .select(&Platform::parse("linux/arm/v6").expect("parsed platform"))
+            .is_none());
+        assert!(index.select(&Platform::new("windows", "amd64")).is_none());
+    }
+
+    #[test]
+    fn test_platform_parse() {
+        assert_eq!(
+            Platform::new("wasi", "wasm"),
+            Platform::parse("wasi/wasm").expect("parsed platform")
+        );
+        assert_eq!(
+            "linux/arm/v7",
+            Platform::parse("linux/arm/v7").unwrap().to_string()
+        );
+        assert!(Platform::parse("linux").is_err());
+        assert!(Platform::parse("linux//v7").is_err());
+    }
 }
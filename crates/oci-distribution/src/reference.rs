@@ -96,6 +96,17 @@ impl Reference {
         self.digest.as_deref()
     }
 
+    /// clone_with_digest returns a reference to the object with the given
+    /// digest in the same repository.
+    pub fn clone_with_digest(&self, digest: String) -> Reference {
+        Reference {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: None,
+            digest: Some(digest),
+        }
+    }
+
     /// full_name returns the full repository name and path.
     fn full_name(&self) -> String {
         if self.registry() == "" {
@@ -1,11 +1,15 @@
 //! Types for working with registry access secrets
 
 /// A method for authenticating to a registry
+#[derive(Clone)]
 pub enum RegistryAuth {
     /// Access the registry anonymously
     Anonymous,
     /// Access the registry using HTTP Basic authentication
     Basic(String, String),
+    /// Access the registry using an identity token, the refresh token of the registry's token
+    /// server that `docker login` stores as the `identitytoken` of a registry
+    IdentityToken(String),
 }
 
 /// Desired operation for registry authentication
@@ -25,6 +29,8 @@ impl Authenticable for reqwest::RequestBuilder {
         match auth {
             RegistryAuth::Anonymous => self,
             RegistryAuth::Basic(username, password) => self.basic_auth(username, Some(password)),
+            // Identity tokens are exchanged for access tokens in the body of the request
+            RegistryAuth::IdentityToken(_) => self,
         }
     }
 }
@@ -0,0 +1,94 @@
+//! Runs a diagnostics module in place of the commands of `kubectl exec`.
+//!
+//! WebAssembly modules have no shell, so there is nothing to run the commands of `kubectl exec`
+//! in. Instead, the provider can be configured with a diagnostics module, such as one that can
+//! list directories and print files, which is run with the volumes, environment and working
+//! directory of the container. The command and its arguments are passed to the module as its
+//! arguments, so a module that handles several commands can tell them apart by the first one.
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+
+use kubelet::exec;
+use kubelet::handle::StopHandler;
+use kubelet::log::LogIndex;
+use kubelet::secret::Redactor;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::profile::RuntimeProfile;
+use crate::wasi_runtime::WasiRuntime;
+
+/// The exit code of a diagnostics module that failed. The code the module exited with isn't
+/// known, so every failure is reported with this one.
+const FAILED_EXIT_CODE: i32 = 1;
+
+/// How to run the diagnostics module for a container.
+#[derive(Clone)]
+pub(crate) struct Diagnostics {
+    pub(crate) module_path: PathBuf,
+    pub(crate) name: String,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) dirs: HashMap<PathBuf, Option<PathBuf>>,
+    pub(crate) working_dir: Option<(PathBuf, PathBuf)>,
+    pub(crate) redactor: Redactor,
+    pub(crate) profile: RuntimeProfile,
+    pub(crate) log_dir: PathBuf,
+}
+
+impl Diagnostics {
+    /// Run the diagnostics module with the command of `sender` as its arguments, streaming its
+    /// output to `sender` and its input from `receiver` until it exits. The module is stopped if
+    /// the client disconnects first.
+    pub(crate) async fn run(
+        self,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> anyhow::Result<i32> {
+        let module_data = tokio::fs::read(&self.module_path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Unable to read diagnostics module {}: {}",
+                self.module_path.display(),
+                e
+            )
+        })?;
+        // The status of the module is reported through the exit code instead, but the channel
+        // has to stay open for the module to send it
+        let (status_tx, _status_rx) = mpsc::channel(8);
+        let runtime = WasiRuntime::new(
+            self.name,
+            module_data,
+            self.env,
+            sender.command().to_vec(),
+            self.dirs,
+            self.log_dir,
+            status_tx,
+        )
+        .await?
+        .with_redactor(self.redactor)
+        .with_profile(self.profile);
+        let runtime = match self.working_dir {
+            Some((host_path, guest_path)) => runtime.with_working_dir(host_path, guest_path),
+            None => runtime,
+        };
+        let runtime = if sender.stdin() {
+            runtime.with_stdin(true)
+        } else {
+            runtime
+        };
+
+        let mut running = runtime.start_runtime(LogIndex::default()).await?;
+        // The module starts before the client is streamed its output, so stream all of it
+        let streamed = running.stream(SeekFrom::Start(0), sender, receiver).await;
+        // Only does anything if the client disconnected before the module finished
+        running.stop().await?;
+        streamed?;
+        match running.wait().await {
+            Ok(()) => Ok(0),
+            Err(e) => {
+                debug!(error = %e, "Diagnostics module failed");
+                Ok(FAILED_EXIT_CODE)
+            }
+        }
+    }
+}
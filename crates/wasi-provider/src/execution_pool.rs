@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use kubelet::container::Container;
+use kubelet::resources::ResourceRequirements;
+use tokio::sync::Semaphore;
+
+/// The CPU a thread provides, in millicores
+const MILLICORES_PER_THREAD: u32 = 1000;
+/// The smallest share a container takes, so that containers requesting little or no CPU can't
+/// start an unbounded number of modules between them
+const MIN_SHARE_MILLICORES: u32 = 100;
+
+/// The threads that run modules, shared out between containers by their CPU requests.
+///
+/// Every module runs on its own thread until it exits, so on a node packed with pods the
+/// modules can easily outnumber the cores. A container holds a share of the pool for as long as
+/// its module runs, and waits to start until its share is free.
+#[derive(Clone, Debug)]
+pub(crate) struct ExecutionPool {
+    millicores: Arc<Semaphore>,
+    capacity: u32,
+}
+
+impl ExecutionPool {
+    /// Create a pool of the given number of threads
+    pub(crate) fn new(threads: u16) -> Self {
+        let capacity = u32::from(threads.max(1)) * MILLICORES_PER_THREAD;
+        ExecutionPool {
+            millicores: Arc::new(Semaphore::new(capacity as usize)),
+            capacity,
+        }
+    }
+
+    /// The number of threads in the pool
+    pub(crate) fn threads(&self) -> u32 {
+        self.capacity / MILLICORES_PER_THREAD
+    }
+
+    /// The share of the pool the container takes, in millicores. This is its CPU request, or
+    /// its CPU limit if it has no request, but at least [`MIN_SHARE_MILLICORES`] and at most
+    /// the whole pool so that every container can run eventually.
+    pub(crate) fn share_of(&self, container: &Container) -> u32 {
+        let cpu = ResourceRequirements::for_container(container)
+            .ok()
+            .and_then(|requirements| requirements.cpu_request())
+            .unwrap_or(0.0);
+        let millicores = (cpu * f64::from(MILLICORES_PER_THREAD)).ceil() as u32;
+        millicores.max(MIN_SHARE_MILLICORES).min(self.capacity)
+    }
+
+    /// Wait until the given share of the pool is free and take it. The share is given back when
+    /// the returned value is dropped.
+    pub(crate) async fn acquire(&self, millicores: u32) -> anyhow::Result<ExecutionShare> {
+        let millicores = millicores.min(self.capacity);
+        self.millicores.acquire_many(millicores).await?.forget();
+        Ok(ExecutionShare {
+            pool: self.millicores.clone(),
+            millicores,
+        })
+    }
+
+    /// Take the given share of the pool if it is free, without waiting for it.
+    pub(crate) fn try_acquire(&self, millicores: u32) -> Option<ExecutionShare> {
+        let millicores = millicores.min(self.capacity);
+        self.millicores.try_acquire_many(millicores).ok()?.forget();
+        Some(ExecutionShare {
+            pool: self.millicores.clone(),
+            millicores,
+        })
+    }
+}
+
+/// A share of an [`ExecutionPool`], held while a module runs.
+#[derive(Debug)]
+pub(crate) struct ExecutionShare {
+    pool: Arc<Semaphore>,
+    millicores: u32,
+}
+
+impl Drop for ExecutionShare {
+    fn drop(&mut self) {
+        self.pool.add_permits(self.millicores as usize);
+    }
+}
@@ -6,9 +6,9 @@
 //! # Example
 //! ```rust,no_run
 //! use kubelet::{Kubelet, config::Config};
-//! use kubelet::resources::DeviceManager;
+//! use kubelet::node::NodeInfo;
+//! use kubelet::provider::{Provider, ProviderClients};
 //! use kubelet::store::oci::FileStore;
-//! use std::convert::TryFrom;
 //! use std::sync::Arc;
 //! use wasi_provider::WasiProvider;
 //!
@@ -20,11 +20,11 @@
 //!
 //!     // Load a kubernetes configuration
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
-//!     let plugin_registry = Arc::new(Default::default());
-//!     let device_plugin_manager = Arc::new(DeviceManager::new_with_default_path(kube::Client::try_from(kubeconfig.clone()).unwrap(), &kubelet_config.node_name));
+//!     let clients = ProviderClients::new(&kubelet_config, kubeconfig.clone(), store).unwrap();
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasiProvider::new(store, &kubelet_config, kubeconfig.clone(), plugin_registry, device_plugin_manager).await.unwrap();
+//!     let node_info = NodeInfo::new(&kubelet_config, WasiProvider::ARCH);
+//!     let provider = WasiProvider::initialize(&kubelet_config, node_info, clients).await.unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
@@ -35,6 +35,11 @@
 
 #![deny(missing_docs)]
 
+mod diagnostics;
+mod execution_pool;
+mod node_status;
+mod profile;
+mod scheduler;
 mod wasi_runtime;
 
 use std::collections::HashMap;
@@ -43,19 +48,31 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use kubelet::node::Builder;
+use execution_pool::ExecutionPool;
+use kubelet::attestation::{ModuleRegistry, PodModules};
+use kubelet::backoff::CrashLoopPolicy;
+use kubelet::config::Config;
+use kubelet::log::LogSink;
+use kubelet::node::{Builder, NodeInfo, NodeStatus};
 use kubelet::plugin_watcher::PluginRegistry;
 use kubelet::pod::state::prelude::SharedState;
-use kubelet::pod::{Handle, Pod, PodKey};
+use kubelet::pod::{
+    get_same_pod, parse_port_range, Handle, HostsFile, Pod, PodControl, PodKey, PodKubeconfig,
+    PodSpecLimits, PortMapper, PortMapping, RelatedObjects,
+};
 use kubelet::provider::{
-    DevicePluginSupport, PluginSupport, Provider, ProviderError, VolumeSupport,
+    DevicePluginSupport, NotImplementedError, PluginSupport, Provider, ProviderClients,
+    ProviderError, VolumeSupport,
 };
-use kubelet::resources::DeviceManager;
+use kubelet::resources::{DeviceManager, ResourceAdmission};
+use kubelet::runtime_class::RuntimeClass;
 use kubelet::state::common::registered::Registered;
+use kubelet::state::common::startup::StartupLimiter;
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
 use kubelet::store::Store;
 use kubelet::volume::VolumeRef;
+use scheduler::FuelScheduler;
 use tokio::sync::RwLock;
 use wasi_runtime::Runtime;
 
@@ -83,9 +100,29 @@ pub struct ProviderState {
     store: Arc<dyn Store + Sync + Send>,
     log_path: PathBuf,
     client: kube::Client,
+    /// The URL of the API server, for the kubeconfigs generated for pods
+    api_server_url: String,
     volume_path: PathBuf,
+    volume_plugins_dir: Option<PathBuf>,
     plugin_registry: Arc<PluginRegistry>,
     device_plugin_manager: Arc<DeviceManager>,
+    log_sink: Option<Arc<dyn LogSink>>,
+    /// Where pods may forward their output to instead of `log_sink`
+    log_forward_destinations: Vec<String>,
+    pod_control: PodControl,
+    related_objects: RelatedObjects,
+    port_mapper: Option<PortMapper>,
+    pod_spec_limits: PodSpecLimits,
+    crash_loop_policy: CrashLoopPolicy,
+    execution_pool: Option<ExecutionPool>,
+    resource_admission: Option<Arc<ResourceAdmission>>,
+    node_info: Arc<NodeInfo>,
+    diagnostics_module: Option<PathBuf>,
+    oci_layout_root: Option<PathBuf>,
+    failure_output_lines: usize,
+    startup_limiter: Option<StartupLimiter>,
+    module_registry: ModuleRegistry,
+    scheduler: Option<FuelScheduler>,
 }
 
 #[async_trait]
@@ -98,19 +135,63 @@ impl GenericProviderState for ProviderState {
     }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
-        let mut handle_writer = self.handles.write().await;
-        if let Some(handle) = handle_writer.get_mut(&key) {
-            handle.stop().await
-        } else {
-            Ok(())
+        // Don't hold the lock while stopping, so other pods can start and stop meanwhile
+        let handle = get_same_pod(&*self.handles.read().await, &key).cloned();
+        match handle {
+            Some(handle) => handle.stop().await,
+            None => Ok(()),
+        }
+    }
+    async fn wait(&self, pod: &Pod) -> anyhow::Result<()> {
+        let key = PodKey::from(pod);
+        let handle = get_same_pod(&*self.handles.read().await, &key).cloned();
+        match handle {
+            Some(handle) => handle.wait().await,
+            None => Ok(()),
         }
     }
+    async fn kill(&self, pod: &Pod) -> anyhow::Result<()> {
+        let key = PodKey::from(pod);
+        let handle = get_same_pod(&*self.handles.read().await, &key).cloned();
+        match handle {
+            Some(handle) => handle.kill().await,
+            None => Ok(()),
+        }
+    }
+    fn pod_spec_limits(&self) -> PodSpecLimits {
+        self.pod_spec_limits
+    }
+    fn crash_loop_policy(&self) -> CrashLoopPolicy {
+        self.crash_loop_policy.clone()
+    }
+    fn node_info(&self) -> Option<&NodeInfo> {
+        Some(&self.node_info)
+    }
+    fn oci_layout_root(&self) -> Option<&Path> {
+        self.oci_layout_root.as_deref()
+    }
+    fn resource_admission(&self) -> Option<&ResourceAdmission> {
+        self.resource_admission.as_deref()
+    }
+    fn startup_limiter(&self) -> Option<&StartupLimiter> {
+        self.startup_limiter.as_ref()
+    }
+    fn module_registry(&self) -> Option<&ModuleRegistry> {
+        Some(&self.module_registry)
+    }
+    fn api_server_url(&self) -> Option<&str> {
+        Some(&self.api_server_url)
+    }
 }
 
 impl VolumeSupport for ProviderState {
     fn volume_path(&self) -> Option<&Path> {
         Some(self.volume_path.as_ref())
     }
+
+    fn volume_plugins_dir(&self) -> Option<&Path> {
+        self.volume_plugins_dir.as_deref()
+    }
 }
 
 impl PluginSupport for ProviderState {
@@ -126,30 +207,17 @@ impl DevicePluginSupport for ProviderState {
 }
 
 impl WasiProvider {
-    /// Create a new wasi provider from a module store and a kubelet config
-    pub async fn new(
-        store: Arc<dyn Store + Sync + Send>,
-        config: &kubelet::config::Config,
-        kubeconfig: kube::Config,
-        plugin_registry: Arc<PluginRegistry>,
-        device_plugin_manager: Arc<DeviceManager>,
-    ) -> anyhow::Result<Self> {
-        let log_path = config.data_dir.join(LOG_DIR_NAME);
-        let volume_path = config.data_dir.join(VOLUME_DIR);
-        tokio::fs::create_dir_all(&log_path).await?;
-        tokio::fs::create_dir_all(&volume_path).await?;
-        let client = kube::Client::try_from(kubeconfig)?;
-        Ok(Self {
-            shared: ProviderState {
-                handles: Default::default(),
-                store,
-                log_path,
-                volume_path,
-                client,
-                plugin_registry,
-                device_plugin_manager,
-            },
-        })
+    /// Returns a handle for restarting, failing or annotating the pods run by this provider
+    pub fn pod_control(&self) -> PodControl {
+        self.shared.pod_control.clone()
+    }
+
+    /// Forward the output of all containers to the given sink in addition to the local log
+    /// files, replacing any sink configured with `log_forward_url`. Pods can still override
+    /// this with the [`kubelet::log::LOG_FORWARD_ANNOTATION`] annotation.
+    pub fn with_log_sink(mut self, sink: Arc<dyn LogSink>) -> Self {
+        self.shared.log_sink = Some(sink);
+        self
     }
 }
 
@@ -157,6 +225,14 @@ struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, VolumeRef>,
     env_vars: HashMap<String, HashMap<String, String>>,
+    /// The pod's generated hosts file, whose directory is mounted at
+    /// `kubelet::pod::HOSTS_FILE_DIR` in each container
+    hosts_file: Option<HostsFile>,
+    /// The kubeconfig generated for the pod if it asked for one, whose directory is mounted
+    /// where the pod asked for it in each container
+    kubeconfig: Option<PodKubeconfig>,
+    /// How the pod's modules are run, from its RuntimeClass
+    profile: profile::RuntimeProfile,
 }
 
 #[async_trait::async_trait]
@@ -168,6 +244,78 @@ impl Provider for WasiProvider {
 
     const ARCH: &'static str = TARGET_WASM32_WASI;
 
+    async fn initialize(
+        config: &Config,
+        node_info: NodeInfo,
+        clients: ProviderClients,
+    ) -> anyhow::Result<Self> {
+        let log_path = config.data_dir.join(LOG_DIR_NAME);
+        let volume_path = config.data_dir.join(VOLUME_DIR);
+        tokio::fs::create_dir_all(&log_path).await?;
+        tokio::fs::create_dir_all(&volume_path).await?;
+        let api_server_url = clients.kube_config.cluster_url.to_string();
+        let client = kube::Client::try_from(clients.kube_config)?;
+        let log_sink = match &config.log_forward_url {
+            Some(url) => Some(kubelet::log::sink_from_url(url).await?),
+            None => None,
+        };
+        // Modules share the node's network, so container ports have to be mapped to node ports
+        let port_mapper = match &config.port_mapping_range {
+            Some(range) => Some(PortMapper::new(parse_port_range(range)?)),
+            None => None,
+        };
+        let execution_pool = config.execution_threads.map(ExecutionPool::new);
+        // Modules take turns on as many threads as they may use between them
+        let scheduler = match config.fuel_quantum {
+            Some(quantum) => {
+                let threads = execution_pool
+                    .as_ref()
+                    .map_or_else(num_cpus::get, |pool| pool.threads() as usize);
+                Some(FuelScheduler::new(threads, quantum)?)
+            }
+            None => None,
+        };
+        // Pods are admitted by what the node reports it can allocate
+        let resource_admission = if config.check_allocatable {
+            let status = node_status::host_status(execution_pool.as_ref()).await;
+            Some(Arc::new(ResourceAdmission::from_node_status(&status)))
+        } else {
+            None
+        };
+        Ok(Self {
+            shared: ProviderState {
+                handles: Default::default(),
+                store: clients.store,
+                log_path,
+                volume_path,
+                volume_plugins_dir: config.volume_plugins_dir.clone(),
+                plugin_registry: clients.plugin_registry,
+                device_plugin_manager: clients.device_plugin_manager,
+                log_sink,
+                log_forward_destinations: config
+                    .log_forward_destinations
+                    .clone()
+                    .unwrap_or_default(),
+                pod_control: PodControl::new(client.clone()),
+                related_objects: RelatedObjects::new(client.clone()),
+                port_mapper,
+                pod_spec_limits: config.pod_spec_limits(),
+                crash_loop_policy: config.crash_loop_policy(),
+                execution_pool,
+                resource_admission,
+                node_info: Arc::new(node_info),
+                diagnostics_module: config.diagnostics_module.clone(),
+                oci_layout_root: config.oci_layout_root.clone(),
+                failure_output_lines: usize::from(config.failure_output_lines),
+                startup_limiter: StartupLimiter::from_config(&config),
+                module_registry: Default::default(),
+                scheduler,
+                client,
+                api_server_url,
+            },
+        })
+    }
+
     fn provider_state(&self) -> SharedState<ProviderState> {
         Arc::new(RwLock::new(self.shared.clone()))
     }
@@ -179,8 +327,18 @@ impl Provider for WasiProvider {
         Ok(())
     }
 
+    async fn node_status(&self) -> anyhow::Result<NodeStatus> {
+        Ok(node_status::host_status(self.shared.execution_pool.as_ref()).await)
+    }
+
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
-        Ok(PodState::new(pod))
+        // Pods with invalid overrides are rejected once they are registered
+        let crash_loop_policy = self
+            .shared
+            .crash_loop_policy
+            .for_pod(pod)
+            .unwrap_or_else(|_| self.shared.crash_loop_policy.clone());
+        Ok(PodState::new(pod, crash_loop_policy))
     }
 
     async fn logs(
@@ -190,15 +348,76 @@ impl Provider for WasiProvider {
         container_name: String,
         sender: kubelet::log::Sender,
     ) -> anyhow::Result<()> {
-        let mut handles = self.shared.handles.write().await;
-        let handle = handles
-            .get_mut(&PodKey::new(&namespace, &pod_name))
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
             .ok_or_else(|| ProviderError::PodNotFound {
                 pod_name: pod_name.clone(),
             })?;
         handle.output(&container_name, sender).await
     }
 
+    async fn attach(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        sender: kubelet::exec::Sender,
+        receiver: kubelet::exec::Receiver,
+    ) -> anyhow::Result<()> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.attach(&container_name, sender, receiver).await
+    }
+
+    async fn exec(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        sender: kubelet::exec::Sender,
+        receiver: kubelet::exec::Receiver,
+    ) -> anyhow::Result<i32> {
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or_else(|| ProviderError::PodNotFound {
+                pod_name: pod_name.clone(),
+            })?;
+        handle.exec(&container_name, sender, receiver).await
+    }
+
+    async fn preview_pod(&self, pod: &Pod) -> anyhow::Result<Vec<String>> {
+        Ok(kubelet::state::common::preview_pod::<Self>(&self.provider_state(), pod).await)
+    }
+
+    async fn port_mappings(&self) -> anyhow::Result<Vec<PortMapping>> {
+        match &self.shared.port_mapper {
+            Some(port_mapper) => Ok(port_mapper.mappings().await),
+            None => Err(NotImplementedError.into()),
+        }
+    }
+
+    async fn running_modules(&self) -> anyhow::Result<Vec<PodModules>> {
+        Ok(self.shared.module_registry.pods().await)
+    }
+
     // Evict all pods upon shutdown
     async fn shutdown(&self, node_name: &str) -> anyhow::Result<()> {
         node::drain(&self.shared.client, &node_name).await?;
@@ -209,7 +428,7 @@ impl Provider for WasiProvider {
 impl GenericProvider for WasiProvider {
     type ProviderState = ProviderState;
     type PodState = PodState;
-    type RunState = crate::states::pod::initializing::Initializing;
+    type RunState = crate::states::pod::starting::Starting;
 
     fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
         Ok(())
@@ -225,4 +444,8 @@ impl GenericProvider for WasiProvider {
         }
         Ok(())
     }
+
+    fn validate_runtime_class(runtime_class: &RuntimeClass) -> anyhow::Result<()> {
+        profile::RuntimeProfile::from_runtime_class(runtime_class).map(|_| ())
+    }
 }
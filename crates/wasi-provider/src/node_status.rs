@@ -0,0 +1,70 @@
+//! The resources and conditions of the host, reported as those of the node.
+
+use kubelet::node::{ConditionState, ConditionStatus, NodeStatus};
+use tracing::debug;
+
+use crate::execution_pool::ExecutionPool;
+
+/// Where Linux reports the memory of the host.
+const MEMINFO_PATH: &str = "/proc/meminfo";
+/// The available memory in KiB below which the node reports `MemoryPressure`. This is the
+/// default memory eviction threshold of the Kubernetes Kubelet.
+const MEMORY_PRESSURE_THRESHOLD_KIB: u64 = 100 * 1024;
+
+/// The status of the node from the CPUs and memory of the host.
+///
+/// Modules run on threads of the Krustlet process, so the CPUs allocatable to pods are limited
+/// to the threads of the execution pool, if there is one. Memory and `MemoryPressure` are only
+/// reported where the host's memory can be read, i.e. on Linux.
+pub(crate) async fn host_status(execution_pool: Option<&ExecutionPool>) -> NodeStatus {
+    let mut status = NodeStatus::default();
+
+    let cpus = num_cpus::get() as u32;
+    status.add_capacity("cpu", &cpus.to_string());
+    if let Some(pool) = execution_pool {
+        status.add_allocatable("cpu", &pool.threads().min(cpus).to_string());
+    }
+
+    match tokio::fs::read_to_string(MEMINFO_PATH).await {
+        Ok(meminfo) => {
+            if let Some((total, available)) = parse_meminfo(&meminfo) {
+                status.add_capacity("memory", &format!("{}Ki", total));
+                status.add_condition("MemoryPressure", memory_pressure(available));
+            }
+        }
+        Err(e) => debug!(error = %e, "Unable to read host memory"),
+    }
+    status
+}
+
+fn memory_pressure(available_kib: u64) -> ConditionState {
+    if available_kib < MEMORY_PRESSURE_THRESHOLD_KIB {
+        ConditionState::new(
+            ConditionStatus::True,
+            "KubeletHasInsufficientMemory",
+            &format!("only {}Ki of memory is available", available_kib),
+        )
+    } else {
+        ConditionState::new(
+            ConditionStatus::False,
+            "KubeletHasSufficientMemory",
+            "kubelet has sufficient memory available",
+        )
+    }
+}
+
+/// The total and available memory in KiB from the contents of `/proc/meminfo`.
+fn parse_meminfo(meminfo: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            let value = line.strip_prefix(name)?.strip_prefix(':')?;
+            value
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+    };
+    Some((field("MemTotal")?, field("MemAvailable")?))
+}
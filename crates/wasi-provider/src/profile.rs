@@ -0,0 +1,48 @@
+//! Execution profiles selected by the RuntimeClass of a pod.
+use kubelet::runtime_class::RuntimeClass;
+
+/// The handler of the RuntimeClasses that the provider runs pods of.
+pub(crate) const WASMTIME_HANDLER: &str = "wasmtime";
+
+/// How wasmtime runs the modules of a pod. Pods without a RuntimeClass get the default profile,
+/// with no fuel limit and no compilation cache.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RuntimeProfile {
+    /// The fuel a module gets, roughly the number of WebAssembly instructions it may run before
+    /// it is stopped. Set by the `fuel` parameter.
+    pub(crate) fuel: Option<u64>,
+    /// Whether compiled modules are cached on disk, using wasmtime's default cache
+    /// configuration. Set by the `cache` parameter.
+    pub(crate) cache: bool,
+}
+
+impl RuntimeProfile {
+    /// The profile for pods with the RuntimeClass, failing if the provider doesn't handle it or
+    /// its parameters are invalid.
+    pub(crate) fn from_runtime_class(runtime_class: &RuntimeClass) -> anyhow::Result<Self> {
+        if runtime_class.handler != WASMTIME_HANDLER {
+            return Err(anyhow::anyhow!(
+                "unknown handler, the only handler is {}",
+                WASMTIME_HANDLER
+            ));
+        }
+        let mut profile = RuntimeProfile::default();
+        for (key, value) in &runtime_class.parameters {
+            match key.as_str() {
+                "fuel" => {
+                    profile.fuel =
+                        Some(value.parse().map_err(|e| {
+                            anyhow::anyhow!("invalid fuel parameter {}: {}", value, e)
+                        })?)
+                }
+                "cache" => {
+                    profile.cache = value
+                        .parse()
+                        .map_err(|e| anyhow::anyhow!("invalid cache parameter {}: {}", value, e))?
+                }
+                other => return Err(anyhow::anyhow!("unknown parameter {}", other)),
+            }
+        }
+        Ok(profile)
+    }
+}
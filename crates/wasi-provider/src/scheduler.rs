@@ -0,0 +1,54 @@
+use std::future::Future;
+
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/// Runs modules cooperatively on a fixed number of threads, switching between them whenever one
+/// has used up a quantum of fuel.
+///
+/// Without it, every module runs on a blocking thread of its own until it exits, so on a small
+/// edge CPU a single CPU-bound module can hold a core for as long as it likes and the modules
+/// of other pods wait for the OS to get around to them. Modules run by the scheduler only ever
+/// run for a quantum at a time before the others get a turn.
+///
+/// Modules only yield between their own instructions: one blocked in a host call, such as a
+/// long sleep, holds its thread until the call returns. Clones share the same threads.
+#[derive(Clone, Debug)]
+pub(crate) struct FuelScheduler {
+    handle: Handle,
+    quantum: u64,
+}
+
+impl FuelScheduler {
+    /// Start `threads` threads that switch between modules every `quantum` units of fuel
+    pub(crate) fn new(threads: usize, quantum: u32) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads.max(1))
+            .thread_name("wasmtime-scheduler")
+            .build()?;
+        let handle = runtime.handle().clone();
+        // The workers run for as long as the runtime exists, which has to be for the life of the
+        // process: a runtime can't be dropped from within the provider's own runtime
+        std::thread::Builder::new()
+            .name("wasmtime-scheduler".into())
+            .spawn(move || runtime.block_on(futures::future::pending::<()>()))?;
+        Ok(FuelScheduler {
+            handle,
+            quantum: u64::from(quantum.max(1)),
+        })
+    }
+
+    /// The units of fuel a module runs for before it yields to other modules
+    pub(crate) fn quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    /// Run a module on the scheduler's threads
+    pub(crate) fn spawn<F>(&self, run: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(run)
+    }
+}
@@ -2,9 +2,14 @@ use super::terminated::Terminated;
 use super::ContainerState;
 use crate::ProviderState;
 use kubelet::container::state::prelude::*;
+use kubelet::container::Prober;
+use kubelet::pod::{get_same_pod, PodKey};
 use tokio::sync::mpsc::Receiver;
 use tracing::{debug, instrument, warn};
 
+/// The host the probes of containers are run against.
+const PROBE_HOST: &str = "127.0.0.1";
+
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
 #[transition_to(Terminated)]
@@ -20,21 +25,42 @@ impl Running {
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Running {
-    #[instrument(level = "info", skip(self, _shared_state, _state, _container))]
+    #[instrument(level = "info", skip(self, shared_state, state, container))]
     async fn next(
         mut self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
-        _container: Manifest<Container>,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
     ) -> Transition<ContainerState> {
+        // Modules share the node's network and listen on their declared ports, which mapped
+        // node ports only forward to
+        let prober = Prober::new(&container.latest(), PROBE_HOST);
+        let probe_failed = prober.failed();
+        tokio::pin!(probe_failed);
+        let mut probe_failure = None;
+
         debug!("Awaiting container status updates");
-        while let Some(status) = self.rx.recv().await {
-            debug!(?status, "Got status update from WASI Runtime");
-            if let Status::Terminated {
-                failed, message, ..
-            } = status
-            {
-                return Transition::next(self, Terminated::new(message, failed));
+        loop {
+            tokio::select! {
+                status = self.rx.recv() => match status {
+                    Some(Status::Terminated { failed, message, .. }) => {
+                        // A container stopped after failing a probe is reported as failing it
+                        let (message, failed) = match probe_failure {
+                            Some(reason) => (reason, true),
+                            None => (message, failed),
+                        };
+                        return Transition::next(self, Terminated::new(message, failed));
+                    }
+                    Some(status) => debug!(?status, "Got status update from WASI Runtime"),
+                    None => break,
+                },
+                reason = &mut probe_failed, if probe_failure.is_none() && !prober.is_empty() => {
+                    warn!(%reason, "Stopping container after it failed a probe");
+                    if let Err(e) = stop_container(&shared_state, state).await {
+                        warn!(error = %e, "Unable to stop container that failed a probe");
+                    }
+                    probe_failure = Some(reason);
+                }
             }
         }
         warn!("WASI Runtime channel hung up");
@@ -52,3 +78,16 @@ impl State<ContainerState> for Running {
         Ok(Status::running())
     }
 }
+
+/// Stops the container, leaving the pod's other containers running.
+async fn stop_container(
+    shared_state: &SharedState<ProviderState>,
+    state: &ContainerState,
+) -> anyhow::Result<()> {
+    let handles = shared_state.read().await.handles.clone();
+    let pod_handle = get_same_pod(&*handles.read().await, &PodKey::from(&state.pod)).cloned();
+    match pod_handle {
+        Some(pod_handle) => pod_handle.stop_container(&state.container_key).await,
+        None => Err(anyhow::anyhow!("pod has no handle")),
+    }
+}
@@ -3,12 +3,15 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use tokio::sync::mpsc;
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
 
 use kubelet::container::state::prelude::*;
-use kubelet::pod::{Handle as PodHandle, PodKey};
-use kubelet::state::common::GenericProviderState;
-use kubelet::volume::VolumeRef;
+use kubelet::log::{LogSink, LogSource, LOG_FORWARD_ANNOTATION};
+use kubelet::pod::{get_same_pod, Handle as PodHandle, Pod, PodKey};
+use kubelet::resources::ResourceRequirements;
+use kubelet::secret::Redactor;
+use kubelet::state::common::{finish_starting_pod, GenericProviderState};
+use kubelet::volume::{guest_path, host_join, same_host_path, VolumeRef};
 
 use crate::wasi_runtime::WasiRuntime;
 use crate::ProviderState;
@@ -21,33 +24,88 @@ fn volume_path_map(
     container: &Container,
     volumes: &HashMap<String, VolumeRef>,
 ) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
-    if let Some(volume_mounts) = container.volume_mounts().as_ref() {
-        volume_mounts
-            .iter()
-            .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-                // Check the volume exists first
-                let vol = volumes.get(&vm.name).ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "no volume with the name of {} found for container {}",
-                        vm.name,
-                        container.name()
-                    )
-                })?;
-                let host_path = vol.get_path().map(|p| p.to_owned()).ok_or_else(|| {
-                    anyhow::anyhow!("Volume {} has not been mounted yet", vm.name)
-                })?;
-                let mut guest_path = PathBuf::from(&vm.mount_path);
-                if let Some(sub_path) = &vm.sub_path {
-                    guest_path.push(sub_path);
-                }
-                // We can safely assume that this should be valid UTF-8 because it would have
-                // been validated by the k8s API
-                Ok((host_path, Some(guest_path)))
-            })
-            .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
-    } else {
-        Ok(HashMap::default())
+    let mut dirs: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+    for vm in container.volume_mounts().iter().flatten() {
+        // Check the volume exists first
+        let vol = volumes.get(&vm.name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "no volume with the name of {} found for container {}",
+                vm.name,
+                container.name()
+            )
+        })?;
+        let host_path = vol
+            .get_path()
+            .map(|p| p.to_owned())
+            .ok_or_else(|| anyhow::anyhow!("Volume {} has not been mounted yet", vm.name))?;
+        // Guest paths are always Unix paths, even on Windows nodes
+        let guest_path = guest_path(&vm.mount_path, vm.sub_path.as_deref());
+        // A directory can only be preopened once. On Windows the same directory can be written
+        // in different ways, so look for it the way the host does.
+        if let Some(existing) = dirs
+            .keys()
+            .find(|existing| same_host_path(existing, &host_path))
+            .cloned()
+        {
+            warn!(
+                volume_name = %vm.name,
+                host_path = %host_path.display(),
+                "Directory is mounted more than once, only the last mount is used"
+            );
+            dirs.remove(&existing);
+        }
+        dirs.insert(host_path, Some(guest_path));
     }
+    Ok(dirs)
+}
+
+/// Finds where the container's working directory is on the host. The working directory has to be
+/// in one of the container's volume mounts, as those are the only directories a module can see.
+fn working_dir_path(
+    container: &Container,
+    volumes: &HashMap<PathBuf, Option<PathBuf>>,
+) -> anyhow::Result<Option<(PathBuf, PathBuf)>> {
+    let guest_path = match container.working_dir() {
+        Some(dir) if !dir.is_empty() => guest_path(dir, None),
+        _ => return Ok(None),
+    };
+    // Use the most specific mount containing the working directory
+    let (host_dir, relative) = volumes
+        .iter()
+        .filter_map(|(host, guest)| {
+            let guest = guest.as_ref().unwrap_or(host);
+            let relative = guest_path.strip_prefix(guest).ok()?;
+            Some((guest.components().count(), host, relative))
+        })
+        .max_by_key(|(depth, _, _)| *depth)
+        .map(|(_, host, relative)| (host, relative))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "working directory {} is not in a volume mount of container {}",
+                guest_path.display(),
+                container.name()
+            )
+        })?;
+    let host_path = host_join(host_dir, relative)?;
+    Ok(Some((host_path, guest_path)))
+}
+
+/// The message for a container that failed to start, with the secret values in the error
+/// replaced.
+fn failure_message(
+    redactor: &Redactor,
+    pod: &Pod,
+    container: &Container,
+    failure: &str,
+    e: &anyhow::Error,
+) -> String {
+    redactor.redact(&format!(
+        "Pod {} container {} {}: {:?}",
+        pod.name(),
+        container.name(),
+        failure,
+        e
+    ))
 }
 
 /// The container is starting.
@@ -74,14 +132,50 @@ impl State<ContainerState> for Waiting {
 
         info!("Starting container for pod");
 
-        let (client, log_path) = {
+        let (
+            client,
+            log_path,
+            node_log_sink,
+            log_forward_destinations,
+            limits,
+            execution_pool,
+            diagnostics_module,
+            failure_output_lines,
+            scheduler,
+        ) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state.log_path.clone(),
+                provider_state.log_sink.clone(),
+                provider_state.log_forward_destinations.clone(),
+                provider_state.pod_spec_limits(),
+                provider_state.execution_pool.clone(),
+                provider_state.diagnostics_module.clone(),
+                provider_state.failure_output_lines,
+                provider_state.scheduler.clone(),
+            )
+        };
+        let log_sink: Option<Arc<dyn LogSink>> = match container
+            .annotation_text(&state.pod, LOG_FORWARD_ANNOTATION)
+        {
+            Some(url) => {
+                match kubelet::log::pod_sink_from_url(url, &log_forward_destinations).await {
+                    Ok(sink) => Some(sink),
+                    Err(e) => {
+                        // Don't fail the container just because its logs can't be shipped
+                        warn!(error = %e, "Unusable log forwarding annotation, using node default");
+                        node_log_sink
+                    }
+                }
+            }
+            None => node_log_sink,
         };
 
-        let (module_data, container_volumes, container_envs) = {
+        let (module_data, container_volumes, container_envs, profile) = {
             let mut run_context = state.run_context.write().await;
-            let module_data = match run_context.modules.remove(container.name()) {
+            // Kept in the run context for when the container is restarted
+            let module_data = match run_context.modules.get(container.name()).cloned() {
                 Some(data) => data,
                 None => {
                     return Transition::next(
@@ -97,7 +191,7 @@ impl State<ContainerState> for Waiting {
                     );
                 }
             };
-            let container_volumes = match volume_path_map(&container, &run_context.volumes) {
+            let mut container_volumes = match volume_path_map(&container, &run_context.volumes) {
                 Ok(volumes) => volumes,
                 Err(e) => {
                     return Transition::next(
@@ -114,19 +208,73 @@ impl State<ContainerState> for Waiting {
                     )
                 }
             };
+            if let Some(hosts_file) = run_context.hosts_file.as_ref() {
+                if kubelet::pod::uses_pod_hosts_file(&container) {
+                    container_volumes
+                        .entry(hosts_file.dir().to_owned())
+                        .or_insert_with(|| Some(PathBuf::from(kubelet::pod::HOSTS_FILE_DIR)));
+                }
+            }
+            if let Some(kubeconfig) = run_context.kubeconfig.as_ref() {
+                container_volumes
+                    .entry(kubeconfig.dir().to_owned())
+                    .or_insert_with(|| Some(kubeconfig.guest_dir().to_owned()));
+            }
             (
                 module_data,
                 container_volumes,
                 run_context
                     .env_vars
-                    .remove(container.name())
+                    .get(container.name())
+                    .cloned()
                     .unwrap_or_default(),
+                run_context.profile.clone(),
             )
         };
 
-        let mut env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
+        let mut env = HashMap::new();
+        env.insert(
+            kubelet::pod::DNS_SEARCH_ENV_VAR.to_owned(),
+            kubelet::pod::dns_search_domains(&state.pod).join(" "),
+        );
+        // Anything the user set explicitly takes precedence
+        let (container_spec_envs, redactor) =
+            kubelet::provider::env_vars_with_redactor(&container, &state.pod, &client).await;
+        env.extend(container_spec_envs);
         env.extend(container_envs);
+        // Fail with a clear message here rather than somewhere inside the runtime
+        if let Err(e) = limits.check_env(&env) {
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} failed to start: {}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            );
+        }
         let args = container.args().clone().unwrap_or_default();
+        let working_dir = match working_dir_path(&container, &container_volumes) {
+            Ok(working_dir) => working_dir,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to set working directory: {}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        };
 
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
@@ -149,22 +297,111 @@ impl State<ContainerState> for Waiting {
         )
         .await
         {
-            Ok(runtime) => runtime,
+            Ok(runtime) => match log_sink {
+                Some(sink) => runtime.with_log_sink(
+                    sink,
+                    LogSource {
+                        namespace: state.pod.namespace().to_owned(),
+                        pod: state.pod.name().to_owned(),
+                        container: container.name().to_owned(),
+                    },
+                ),
+                None => runtime,
+            },
             Err(e) => {
                 return Transition::next(
                     self,
                     Terminated::new(
-                        format!(
-                            "Pod {} container {} failed to construct runtime: {:?}",
-                            state.pod.name(),
-                            container.name(),
-                            e
+                        failure_message(
+                            &redactor,
+                            &state.pod,
+                            &container,
+                            "failed to construct runtime",
+                            &e,
                         ),
                         true,
                     ),
                 )
             }
         };
+        // Anyone who can read the pod can read its status but not necessarily its logs, so output
+        // is only copied into the status of containers that ask for it
+        let failure_output_lines = match container.termination_message_policy().map(String::as_str)
+        {
+            Some("FallbackToLogsOnError") => failure_output_lines,
+            _ => 0,
+        };
+        let runtime = runtime
+            .with_redactor(redactor.clone())
+            .with_profile(profile)
+            .with_failure_output_lines(failure_output_lines)
+            .with_fuel_metrics(state.pod.namespace(), state.pod.name());
+        let runtime = match scheduler {
+            Some(scheduler) => runtime.with_scheduler(scheduler),
+            None => runtime,
+        };
+        let runtime = match working_dir {
+            Some((host_path, guest_path)) => runtime.with_working_dir(host_path, guest_path),
+            None => runtime,
+        };
+        let runtime = match diagnostics_module {
+            Some(module_path) => runtime.with_diagnostics_module(module_path),
+            None => runtime,
+        };
+        let runtime = if container.stdin().unwrap_or(false) {
+            runtime.with_stdin(container.stdin_once().unwrap_or(false))
+        } else {
+            runtime
+        };
+        // The API server checks that quantities are valid, so there is no memory limit to apply
+        // if they can't be parsed
+        let memory_limit = ResourceRequirements::for_container(&container)
+            .ok()
+            .and_then(|requirements| requirements.memory_limit());
+        let runtime = match memory_limit {
+            Some(bytes) => runtime.with_memory_limit(bytes),
+            None => runtime,
+        };
+        #[cfg(feature = "failure-injection")]
+        let runtime = match kubelet::failure_injection::FailureInjection::from_pod(&state.pod)
+            .and_then(|injection| injection.crash_after())
+        {
+            Some(crash_after) => runtime.with_crash_after(crash_after),
+            None => runtime,
+        };
+        let runtime = match execution_pool {
+            Some(pool) => {
+                let share = pool.share_of(&container);
+                let acquired = match pool.try_acquire(share) {
+                    Some(share) => Ok(share),
+                    None => {
+                        // Modules can run for as long as they like, so the pod gives up its
+                        // place among the starting pods rather than hold it while it waits
+                        finish_starting_pod(&shared, &state.pod).await;
+                        debug!(millicores = share, "Waiting for share of execution pool");
+                        pool.acquire(share).await
+                    }
+                };
+                match acquired {
+                    Ok(share) => runtime.with_execution_share(share),
+                    Err(e) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Pod {} container {} failed to get a share of the execution pool: {:?}",
+                                    state.pod.name(),
+                                    container.name(),
+                                    e
+                                ),
+                                true,
+                            ),
+                        )
+                    }
+                }
+            }
+            None => runtime,
+        };
         debug!("Starting container on thread");
         let container_handle = match runtime.start().await {
             Ok(handle) => handle,
@@ -172,12 +409,7 @@ impl State<ContainerState> for Waiting {
                 return Transition::next(
                     self,
                     Terminated::new(
-                        format!(
-                            "Pod {} container {} failed to start: {:?}",
-                            state.pod.name(),
-                            container.name(),
-                            e
-                        ),
+                        failure_message(&redactor, &state.pod, &container, "failed to start", &e),
                         true,
                     ),
                 )
@@ -185,16 +417,21 @@ impl State<ContainerState> for Waiting {
         };
         debug!("WASI Runtime started for container");
         let pod_key = PodKey::from(&state.pod);
-        {
-            let provider_state = shared.write().await;
-            let mut handles_writer = provider_state.handles.write().await;
-            let pod_handle = handles_writer
+        let handles = shared.read().await.handles.clone();
+        let pod_handle = {
+            let mut handles_writer = handles.write().await;
+            // Don't add containers to the handle of a deleted pod with the same name
+            if get_same_pod(&handles_writer, &pod_key).is_none() {
+                handles_writer.remove(&pod_key);
+            }
+            handles_writer
                 .entry(pod_key)
-                .or_insert_with(|| Arc::new(PodHandle::new(HashMap::new(), state.pod.clone())));
-            pod_handle
-                .insert_container_handle(state.container_key.clone(), container_handle)
-                .await;
-        }
+                .or_insert_with(|| Arc::new(PodHandle::new(HashMap::new(), state.pod.clone())))
+                .clone()
+        };
+        pod_handle
+            .insert_container_handle(state.container_key.clone(), container_handle)
+            .await;
         Transition::next(self, Running::new(rx))
     }
 
@@ -206,3 +443,92 @@ impl State<ContainerState> for Waiting {
         Ok(Status::waiting("Module is starting."))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::path::Path;
+
+    fn container(working_dir: Option<&str>) -> Container {
+        let pod: kubelet::pod::Pod = serde_json::from_value(json!({
+            "metadata": { "name": "pod" },
+            "spec": { "containers": [{ "name": "app", "workingDir": working_dir }] }
+        }))
+        .unwrap();
+        pod.containers().remove(0)
+    }
+
+    fn volumes() -> HashMap<PathBuf, Option<PathBuf>> {
+        let mut volumes = HashMap::new();
+        volumes.insert(PathBuf::from("/host/data"), Some(PathBuf::from("/data")));
+        volumes.insert(
+            PathBuf::from("/host/logs"),
+            Some(PathBuf::from("/data/logs")),
+        );
+        volumes
+    }
+
+    #[test]
+    fn test_working_dir_absolute() {
+        let (host, guest) = working_dir_path(&container(Some("/data/app")), &volumes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(Path::new("/host/data").join("app"), host);
+        assert_eq!(Path::new("/data/app"), guest);
+
+        // The most specific mount wins
+        let (host, _) = working_dir_path(&container(Some("/data/logs/today")), &volumes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(Path::new("/host/logs").join("today"), host);
+    }
+
+    #[test]
+    fn test_working_dir_relative() {
+        // Modules have no image working directory to be relative to, so it is from the root
+        let (host, guest) = working_dir_path(&container(Some("data/./app")), &volumes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(Path::new("/host/data").join("app"), host);
+        assert_eq!(Path::new("/data/app"), guest);
+    }
+
+    #[test]
+    fn test_working_dir_parent() {
+        let e = working_dir_path(&container(Some("/data/../etc")), &volumes()).unwrap_err();
+        assert!(e.to_string().contains("is not a path within the volume"));
+    }
+
+    #[test]
+    fn test_working_dir_missing() {
+        assert!(working_dir_path(&container(None), &volumes())
+            .unwrap()
+            .is_none());
+        assert!(working_dir_path(&container(Some("")), &volumes())
+            .unwrap()
+            .is_none());
+
+        let e = working_dir_path(&container(Some("/tmp")), &volumes()).unwrap_err();
+        assert_eq!(
+            "working directory /tmp is not in a volume mount of container app",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_failure_message_redacts_secrets() {
+        let mut redactor = Redactor::default();
+        redactor.add_secret("TOKEN", "hunter2");
+        let pod: Pod = serde_json::from_value(json!({
+            "metadata": { "name": "pod" },
+            "spec": { "containers": [{ "name": "app" }] }
+        }))
+        .unwrap();
+        let e = anyhow::anyhow!("invalid token hunter2").context("unable to load module");
+        let message = failure_message(&redactor, &pod, &container(None), "failed to start", &e);
+        assert!(message.starts_with("Pod pod container app failed to start: "));
+        assert!(message.contains("invalid token [redacted]"));
+        assert!(!message.contains("hunter2"));
+    }
+}
@@ -1,32 +1,46 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use krator::{ObjectState, SharedState};
+use krator::{Manifest, ObjectState, SharedState};
 use kubelet::backoff::BackoffStrategy;
-use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::backoff::{CrashLoopPolicy, CrashLoopTracker, ExponentialBackoffStrategy};
+use kubelet::container::state::{run_to_completion, run_with_restarts};
+use kubelet::container::{Container, ContainerKey};
 use kubelet::pod::Pod;
-use kubelet::pod::PodKey;
 use kubelet::pod::Status;
-use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
+use kubelet::pod::{remove_same_pod, HostsFile, PodKey, PodKubeconfig};
+use kubelet::runtime_class::RuntimeClass;
+use kubelet::state::common::{
+    BackoffSequence, GenericPodState, GenericProviderState, ThresholdTrigger,
+};
+use kubelet::task_group::TaskGroup;
 use tokio::sync::RwLock;
 use tracing::error;
 
+use crate::profile::RuntimeProfile;
+use crate::states::container::waiting::Waiting;
+use crate::states::container::ContainerState;
 use crate::ModuleRunContext;
 use crate::ProviderState;
 
 pub(crate) mod completed;
-pub(crate) mod initializing;
 pub(crate) mod running;
 pub(crate) mod starting;
 
+/// How long the tasks running a pod's containers are given to finish once the pod is gone,
+/// before they are aborted.
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// State that is shared between pod state handlers.
 pub struct PodState {
     key: PodKey,
     run_context: SharedState<ModuleRunContext>,
-    errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
-    pub(crate) crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    crash_loop_backoff_strategy: CrashLoopTracker,
+    /// The tasks running the pod's containers.
+    tasks: TaskGroup,
 }
 
 #[async_trait]
@@ -35,6 +49,7 @@ impl ObjectState for PodState {
     type Status = Status;
     type SharedState = ProviderState;
     async fn async_drop(self, provider_state: &mut Self::SharedState) {
+        self.tasks.shutdown(TASK_SHUTDOWN_TIMEOUT).await;
         {
             {
                 let mut context = self.run_context.write().await;
@@ -45,27 +60,50 @@ impl ObjectState for PodState {
                     }
                 });
                 futures::future::join_all(unmounts).await;
+                if let Some(hosts_file) = context.hosts_file.take() {
+                    if let Err(e) = hosts_file.remove().await {
+                        error!(error = %e, "Unable to clean up generated hosts file");
+                    }
+                }
+                if let Some(kubeconfig) = context.kubeconfig.take() {
+                    if let Err(e) = kubeconfig.remove().await {
+                        error!(error = %e, "Unable to clean up generated kubeconfig");
+                    }
+                }
+            }
+            let handle = remove_same_pod(&mut *provider_state.handles.write().await, &self.key);
+            if let Some(handle) = handle {
+                handle.close_logs().await;
+            }
+            if let Some(port_mapper) = &provider_state.port_mapper {
+                port_mapper.unmap_pod(&self.key).await;
             }
-            let mut handles = provider_state.handles.write().await;
-            handles.remove(&self.key);
+            // Running deregisters when it moves on, but not when the pod is deleted while it runs
+            provider_state.pod_control.deregister(&self.key).await;
+            provider_state.related_objects.unsubscribe(&self.key).await;
+            provider_state.module_registry.remove(&self.key).await;
+            kubelet::metrics::pod_metrics().remove_pod(&self.key.namespace(), &self.key.name());
         }
     }
 }
 
 impl PodState {
-    pub fn new(pod: &Pod) -> Self {
+    pub fn new(pod: &Pod, crash_loop_policy: CrashLoopPolicy) -> Self {
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
             env_vars: Default::default(),
+            hosts_file: None,
+            kubeconfig: None,
+            profile: Default::default(),
         };
         let key = PodKey::from(pod);
         PodState {
-            key,
+            key: key.clone(),
             run_context: Arc::new(RwLock::new(run_context)),
-            errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
-            crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            crash_loop_backoff_strategy: CrashLoopTracker::new(crash_loop_policy),
+            tasks: TaskGroup::new(format!("pod {}", key)),
         }
     }
 }
@@ -84,24 +122,105 @@ impl GenericPodState for PodState {
         let mut run_context = self.run_context.write().await;
         run_context.volumes = volumes;
     }
-    async fn backoff(&mut self, sequence: BackoffSequence) {
-        let backoff_strategy = match sequence {
-            BackoffSequence::ImagePull => &mut self.image_pull_backoff_strategy,
-            BackoffSequence::CrashLoop => &mut self.crash_loop_backoff_strategy,
+    async fn set_hosts_file(&mut self, hosts_file: HostsFile) {
+        let mut run_context = self.run_context.write().await;
+        run_context.hosts_file = Some(hosts_file);
+    }
+    async fn set_kubeconfig(&mut self, kubeconfig: PodKubeconfig) {
+        let mut run_context = self.run_context.write().await;
+        run_context.kubeconfig = Some(kubeconfig);
+    }
+    async fn set_runtime_class(&mut self, runtime_class: Option<RuntimeClass>) {
+        // The RuntimeClass was validated when the pod was registered
+        let profile = runtime_class
+            .and_then(|rc| RuntimeProfile::from_runtime_class(&rc).ok())
+            .unwrap_or_default();
+        let mut run_context = self.run_context.write().await;
+        run_context.profile = profile;
+    }
+    async fn run_init_container(
+        &mut self,
+        provider_state: SharedState<ProviderState>,
+        pod: Manifest<Pod>,
+        container: &Container,
+    ) -> anyhow::Result<()> {
+        let client = provider_state.read().await.client();
+        let container_key = ContainerKey::Init(container.name().to_string());
+        let new_run = {
+            let pod = pod.latest();
+            let container_key = container_key.clone();
+            let run_context = Arc::clone(&self.run_context);
+            move || {
+                let container_state = ContainerState::new(
+                    pod.clone(),
+                    container_key.clone(),
+                    Arc::clone(&run_context),
+                );
+                (Waiting, container_state)
+            }
         };
-        backoff_strategy.wait().await;
+        // Init containers run one at a time in the pod's state machine, so they are stopped by
+        // dropping this future rather than through the channel
+        let (_stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+        run_with_restarts(
+            &client,
+            new_run,
+            provider_state,
+            pod,
+            container_key,
+            stop_rx,
+        )
+        .await
+    }
+    async fn start_ephemeral_container(
+        &mut self,
+        provider_state: SharedState<ProviderState>,
+        pod: Manifest<Pod>,
+        container: &Container,
+        module: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let client = provider_state.read().await.client();
+        self.run_context
+            .write()
+            .await
+            .modules
+            .insert(container.name().to_owned(), module);
+        let container_key = ContainerKey::Ephemeral(container.name().to_string());
+        let container_state = ContainerState::new(
+            pod.latest(),
+            container_key.clone(),
+            Arc::clone(&self.run_context),
+        );
+        self.tasks.spawn(
+            format!("ephemeral container {}", container_key),
+            async move {
+                run_to_completion(
+                    &client,
+                    Waiting,
+                    provider_state,
+                    container_state,
+                    pod,
+                    container_key,
+                )
+                .await
+            },
+        );
+        Ok(())
+    }
+    async fn next_backoff(&mut self, sequence: BackoffSequence) -> Duration {
+        match sequence {
+            BackoffSequence::ImagePull => self.image_pull_backoff_strategy.next_duration(),
+            BackoffSequence::CrashLoop => self.crash_loop_backoff_strategy.next_duration(),
+        }
     }
     async fn reset_backoff(&mut self, sequence: BackoffSequence) {
-        let backoff_strategy = match sequence {
-            BackoffSequence::ImagePull => &mut self.image_pull_backoff_strategy,
-            BackoffSequence::CrashLoop => &mut self.crash_loop_backoff_strategy,
-        };
-        backoff_strategy.reset();
+        match sequence {
+            BackoffSequence::ImagePull => self.image_pull_backoff_strategy.reset(),
+            BackoffSequence::CrashLoop => self.crash_loop_backoff_strategy.reset(),
+        }
     }
     async fn record_error(&mut self) -> ThresholdTrigger {
-        self.errors += 1;
-        if self.errors > 3 {
-            self.errors = 0;
+        if self.crash_loop_backoff_strategy.record_error() {
             ThresholdTrigger::Triggered
         } else {
             ThresholdTrigger::Untriggered
@@ -1,5 +1,6 @@
 use crate::{PodState, ProviderState};
 use kubelet::pod::state::prelude::*;
+use kubelet::state::common::GenericProviderState;
 
 /// Pod was deleted.
 #[derive(Default, Debug)]
@@ -9,10 +10,14 @@ pub struct Completed;
 impl State<PodState> for Completed {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<ProviderState>,
+        provider_state: SharedState<ProviderState>,
         _pod_state: &mut PodState,
-        _pod: Manifest<Pod>,
+        pod: Manifest<Pod>,
     ) -> Transition<PodState> {
+        // Finished pods no longer use the resources they requested
+        if let Some(resource_admission) = provider_state.read().await.resource_admission() {
+            resource_admission.release(&pod.latest());
+        }
         Transition::Complete(Ok(()))
     }
 
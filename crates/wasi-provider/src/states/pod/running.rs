@@ -1,23 +1,49 @@
+use futures::StreamExt;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+use tracing::info;
 
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{PodCommand, PodKey, RelatedObjectUpdate};
+use kubelet::state::common::ephemeral::EphemeralContainers;
 use kubelet::state::common::error::Error;
-use kubelet::state::common::GenericProviderState;
+use kubelet::state::common::registered::Registered;
+use kubelet::state::common::{finish_starting_pod, GenericProviderState};
 
 use super::completed::Completed;
 use crate::fail_fatal;
 use crate::{PodState, ProviderState};
 
-/// The Kubelet is running the Pod.
+/// The Kubelet is running the Pod. Containers are restarted according to the pod's restart
+/// policy, so the pod completes once all of its containers have exited without being restarted.
+/// Ephemeral containers that are added to the pod while it runs are started alongside its
+/// containers, but the pod completes without waiting for them. The pod's ConfigMap, Secret and
+/// projected volumes are updated when the objects their files come from change.
 #[derive(Debug, TransitionTo)]
-#[transition_to(Completed, Error<crate::WasiProvider>)]
+#[transition_to(Completed, Error<crate::WasiProvider>, Registered<crate::WasiProvider>)]
 pub struct Running {
     rx: Receiver<anyhow::Result<()>>,
+    /// Stops the containers from being restarted. Dropped, and so set, when the pod leaves this
+    /// state.
+    stop_tx: watch::Sender<bool>,
+    ephemeral_containers: EphemeralContainers,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<anyhow::Result<()>>) -> Self {
-        Running { rx }
+    pub fn new(rx: Receiver<anyhow::Result<()>>, stop_tx: watch::Sender<bool>) -> Self {
+        Running {
+            rx,
+            stop_tx,
+            ephemeral_containers: EphemeralContainers::new(),
+        }
+    }
+
+    /// Stop the pod's containers without restarting them.
+    async fn stop(&self, provider_state: &SharedState<ProviderState>, pod: &Pod) {
+        // There are no receivers left if every container has already exited
+        self.stop_tx.send(true).ok();
+        let provider = provider_state.read().await.clone();
+        provider.stop(pod).await.ok();
     }
 }
 
@@ -26,32 +52,74 @@ impl State<PodState> for Running {
     async fn next(
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         pod: Manifest<Pod>,
     ) -> Transition<PodState> {
+        let mut pod_updates = pod.clone();
         let pod = pod.latest();
+        finish_starting_pod(&provider_state, &pod).await;
 
         let mut completed = 0;
         let total_containers = pod.containers().len();
 
-        while let Some(result) = self.rx.recv().await {
-            match result {
-                Ok(()) => {
-                    completed += 1;
-                    if completed == total_containers {
-                        return Transition::next(self, Completed);
+        let key = PodKey::from(&pod);
+        let pod_control = provider_state.read().await.pod_control.clone();
+        let mut commands = pod_control.register(key.clone()).await;
+        let related_objects = provider_state.read().await.related_objects.clone();
+        let mut related_updates = related_objects.subscribe(&pod).await;
+        self.ephemeral_containers
+            .start_new::<crate::WasiProvider>(provider_state.clone(), pod_state, &pod_updates)
+            .await;
+
+        loop {
+            tokio::select! {
+                result = self.rx.recv() => match result {
+                    Some(Ok(())) => {
+                        completed += 1;
+                        if completed == total_containers {
+                            pod_control.deregister(&key).await;
+                            return Transition::next(self, Completed);
+                        }
+                    }
+                    Some(Err(e)) => {
+                        pod_control.deregister(&key).await;
+                        // Stop remaining containers;
+                        self.stop(&provider_state, &pod).await;
+                        fail_fatal!(e);
+                    }
+                    None => break,
+                },
+                Some(_) = pod_updates.next() => {
+                    self.ephemeral_containers
+                        .start_new::<crate::WasiProvider>(provider_state.clone(), pod_state, &pod_updates)
+                        .await;
+                }
+                Some(update) = related_updates.recv() => {
+                    // As on other nodes, the files of a deleted object are left in place
+                    if let RelatedObjectUpdate::Applied(object) = update {
+                        let mut run_context = pod_state.run_context.write().await;
+                        kubelet::volume::update_volumes(&mut run_context.volumes, &pod, &object)
+                            .await;
                     }
                 }
-                Err(e) => {
-                    // Stop remaining containers;
-                    {
-                        let provider = provider_state.write().await;
-                        provider.stop(&pod).await.ok();
+                Some(command) = commands.recv() => {
+                    pod_control.deregister(&key).await;
+                    self.stop(&provider_state, &pod).await;
+                    match command {
+                        PodCommand::Restart => {
+                            info!(pod_name = pod.name(), "Restarting pod at provider request");
+                            let next = Registered::<crate::WasiProvider>::default();
+                            return Transition::next(self, next);
+                        }
+                        PodCommand::Fail(reason) => {
+                            let e = anyhow::anyhow!(reason);
+                            fail_fatal!(e);
+                        }
                     }
-                    fail_fatal!(e);
                 }
             }
         }
+        pod_control.deregister(&key).await;
         Transition::next(
             self,
             Error::new(format!(
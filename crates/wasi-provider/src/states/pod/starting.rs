@@ -2,9 +2,11 @@ use std::sync::Arc;
 
 use tracing::{info, instrument};
 
-use kubelet::container::state::run_to_completion;
+use kubelet::container::state::run_with_restarts;
 use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
+use kubelet::reason::Failure;
+use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
 
 use crate::states::container::waiting::Waiting;
@@ -14,9 +16,9 @@ use crate::{PodState, ProviderState};
 use super::running::Running;
 
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Running)]
+#[transition_to(Running, Error<crate::WasiProvider>)]
 /// The Kubelet is starting the Pod containers
-pub(crate) struct Starting;
+pub struct Starting;
 
 #[async_trait::async_trait]
 impl State<PodState> for Starting {
@@ -36,40 +38,76 @@ impl State<PodState> for Starting {
 
         tracing::Span::current().record("pod_name", &pod.name());
 
+        let port_mapper = provider_state.read().await.port_mapper.clone();
+        if let Some(port_mapper) = port_mapper {
+            match port_mapper.map_pod(&pod).await {
+                Ok(mappings) => {
+                    for mapping in mappings {
+                        info!(
+                            container = %mapping.container_name,
+                            container_port = mapping.container_port,
+                            node_port = mapping.node_port,
+                            "Mapped container port to node port"
+                        );
+                    }
+                }
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Error::with_failure(
+                            Failure::ContainerConfig,
+                            format!("Unable to map container ports: {}", e),
+                        ),
+                    )
+                }
+            }
+        }
         info!("Starting containers for pod");
         let containers = pod.containers();
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
+        let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
         for container in containers {
-            let initial_state = Waiting;
             let container_key = ContainerKey::App(container.name().to_string());
-            let container_state = ContainerState::new(
-                pod.clone(),
-                container_key.clone(),
-                Arc::clone(&pod_state.run_context),
-            );
+            let new_run = {
+                let pod = pod.clone();
+                let container_key = container_key.clone();
+                let run_context = Arc::clone(&pod_state.run_context);
+                move || {
+                    let container_state = ContainerState::new(
+                        pod.clone(),
+                        container_key.clone(),
+                        Arc::clone(&run_context),
+                    );
+                    (Waiting, container_state)
+                }
+            };
             let task_provider = Arc::clone(&provider_state);
             let task_tx = tx.clone();
             let task_pod = pod_rx.clone();
-            tokio::task::spawn(async move {
+            let task_stop_rx = stop_rx.clone();
+            let task_name = format!("container {}", container_key);
+            pod_state.tasks.spawn(task_name, async move {
                 let client = {
                     let provider_state = task_provider.read().await;
                     provider_state.client()
                 };
 
-                let result = run_to_completion(
+                let result = run_with_restarts(
                     &client,
-                    initial_state,
+                    new_run,
                     task_provider,
-                    container_state,
                     task_pod,
                     container_key,
+                    task_stop_rx,
                 )
                 .await;
-                task_tx.send(result).await
+                // The pod only stops waiting for the result once it is no longer running
+                task_tx.send(result).await.ok();
+                Ok(())
             });
         }
         info!("All containers started for pod");
-        Transition::next(self, Running::new(rx))
+        Transition::next(self, Running::new(rx, stop_tx))
     }
 
     async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
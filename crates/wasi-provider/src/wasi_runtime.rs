@@ -1,36 +1,295 @@
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::io::{Cursor, Read, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tracing::{debug, error, info, instrument, trace, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info, instrument, trace, warn, Instrument};
 
+use futures::future::BoxFuture;
 use tempfile::NamedTempFile;
-use tokio::sync::mpsc::Sender;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use wasi_cap_std_sync::WasiCtxBuilder;
+use wasi_common::pipe::ReadPipe;
 use wasmtime::{InterruptHandle, Linker};
 
 use kubelet::container::Handle as ContainerHandle;
-use kubelet::container::Status;
-use kubelet::handle::StopHandler;
+use kubelet::container::{Status, OOM_KILLED};
+use kubelet::exec;
+use kubelet::handle::{AttachHandler, ExecHandler, StopHandler};
+use kubelet::log::{LogIndex, LogSink, LogSource};
+use kubelet::secret::Redactor;
+use kubelet::task_group::TaskGroup;
+
+use crate::diagnostics::Diagnostics;
+use crate::execution_pool::ExecutionShare;
+use crate::profile::RuntimeProfile;
+use crate::scheduler::FuelScheduler;
+
+/// The preamble shared by all WebAssembly binaries
+const WASM_MAGIC: &[u8] = b"\0asm";
+/// The version field of a core WebAssembly module. Components (as produced by toolchains
+/// targeting WASI preview2) use a different version and a non-zero layer field instead
+const CORE_MODULE_VERSION: &[u8] = &[0x01, 0x00, 0x00, 0x00];
+
+/// Returns true if the binary is a WebAssembly component rather than a core module
+fn is_component(module_data: &[u8]) -> bool {
+    module_data.len() >= 8
+        && &module_data[0..4] == WASM_MAGIC
+        && &module_data[4..8] != CORE_MODULE_VERSION
+        && module_data[6..8] != [0x00, 0x00]
+}
+
+/// The size of a WebAssembly memory page
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// The number of whole pages that fit in a memory limit, which memories are limited to. Limits
+/// under a page still allow one, as most modules can't start without any memory.
+fn memory_pages(bytes: u64) -> u32 {
+    (bytes / WASM_PAGE_SIZE).clamp(1, u64::from(u32::MAX)) as u32
+}
+
+/// The most instances a module's store may hold. Only the module itself is instantiated, as
+/// modules aren't linked to other modules
+const MAX_INSTANCES: usize = 1;
+/// The most tables a module may have
+const MAX_TABLES: usize = 10;
+/// The most memories a module may have
+const MAX_MEMORIES: usize = 1;
+/// The most elements each table of a module may grow to
+const MAX_TABLE_ELEMENTS: u32 = 100_000;
+
+/// The state of the store a module runs in
+struct ModuleState {
+    wasi: wasi_common::WasiCtx,
+    limiter: ModuleLimiter,
+}
+
+/// Keeps a module within the resources of its container, recording whether it ran out of memory
+/// so that it can be reported as killed for it.
+struct ModuleLimiter {
+    /// The most pages each linear memory of the module may grow to
+    memory_pages: Option<u32>,
+    /// Set once the module is refused memory beyond its limit
+    memory_exceeded: bool,
+}
+
+impl wasmtime::ResourceLimiter for ModuleLimiter {
+    fn memory_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        let allowed = self.memory_pages.map_or(true, |limit| desired <= limit);
+        if !allowed {
+            warn!(desired, limit = ?self.memory_pages, "Module exceeded its memory limit");
+            self.memory_exceeded = true;
+        }
+        allowed
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        desired <= MAX_TABLE_ELEMENTS
+    }
+
+    fn instances(&self) -> usize {
+        MAX_INSTANCES
+    }
+
+    fn tables(&self) -> usize {
+        MAX_TABLES
+    }
+
+    fn memories(&self) -> usize {
+        MAX_MEMORIES
+    }
+}
+
+/// How often attached clients are sent new output of the module
+const ATTACH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// The most output of the module sent to attached clients at once
+const ATTACH_BUFFER: usize = 8 * 1024;
+/// How long a killed module is waited for before it is left to stop on its own
+const KILL_WAIT: Duration = Duration::from_secs(1);
+/// How long the tasks indexing and forwarding the output of a module are given to finish once it
+/// has exited, before they are aborted
+const TASK_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The sending end of the standard input of a module. It is taken out when the module is stopped
+/// or, for containers with `stdinOnce`, when the first attached client closes its input, so
+/// that the module reads the end of its input.
+type StdinSender = Arc<Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>>;
 
 pub struct Runtime {
-    handle: JoinHandle<anyhow::Result<()>>,
-    interrupt_handle: InterruptHandle,
+    /// The task running the module, until it has been waited for to the end
+    handle: Option<JoinHandle<anyhow::Result<()>>>,
+    interrupt_handle: Arc<InterruptHandle>,
+    /// Set once the module has been asked to stop, so that its interruption isn't reported as a
+    /// failure
+    stopped: Arc<AtomicBool>,
+    output: Arc<NamedTempFile>,
+    stdin: StdinSender,
+    stdin_once: bool,
+    done: watch::Receiver<()>,
+    /// Runs the commands of `kubectl exec`, if a diagnostics module is configured
+    diagnostics: Option<Diagnostics>,
+    /// The tasks indexing and forwarding the output of the module
+    tasks: TaskGroup,
 }
 
 #[async_trait::async_trait]
 impl StopHandler for Runtime {
     async fn stop(&mut self) -> anyhow::Result<()> {
+        // A module waiting for input can't be interrupted until it gets some
+        self.stdin.lock().unwrap().take();
+        self.stopped.store(true, Ordering::SeqCst);
         self.interrupt_handle.interrupt();
         Ok(())
     }
 
+    async fn kill(&mut self) -> anyhow::Result<()> {
+        self.stop().await?;
+        // An interrupted module stops as soon as it runs any of its own code again, which a
+        // module blocked in a host call, such as a long sleep, only does once the call returns.
+        // The thread running it can't be stopped before then, so it is left to finish on its own
+        // rather than holding up the termination of the pod
+        let mut done = self.done.clone();
+        if tokio::time::timeout(KILL_WAIT, done.changed())
+            .await
+            .is_err()
+        {
+            warn!("Module is blocked in a host call, leaving it to stop once the call returns");
+            self.handle = None;
+        }
+        Ok(())
+    }
+
     async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
+        // A finished task can't be waited for again, as it is when a pod that was killed after
+        // its grace period ran out is waited for again
+        if let Some(handle) = self.handle.as_mut() {
+            let result = handle.await;
+            self.handle = None;
+            // The output is indexed and forwarded to the end before the module counts as exited
+            self.tasks.shutdown(TASK_SHUTDOWN_TIMEOUT).await;
+            result??;
+        }
         Ok(())
     }
 }
 
+impl Runtime {
+    /// Stream the output of the module from the given position to `sender`, and the input from
+    /// `receiver` to the module, until the module has finished running or the client has
+    /// disconnected.
+    pub(crate) fn stream(
+        &self,
+        from: SeekFrom,
+        mut sender: exec::Sender,
+        mut receiver: exec::Receiver,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        let temp = self.output.clone();
+        let stdin = self.stdin.clone();
+        let stdin_once = self.stdin_once;
+        let mut done = self.done.clone();
+        Box::pin(async move {
+            let output = tokio::task::spawn_blocking(move || temp.reopen()).await??;
+            let mut output = tokio::fs::File::from_std(output);
+            // Stdout and stderr share the file, so all of the output is sent as stdout
+            output.seek(from).await?;
+            let mut buf = vec![0; ATTACH_BUFFER];
+            let mut input_open = sender.stdin();
+            let mut finished = false;
+            loop {
+                loop {
+                    let n = output.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    if sender.send_stdout(&buf[..n]).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                if finished {
+                    return Ok(());
+                }
+                tokio::select! {
+                    // The sender is dropped once the module has finished running
+                    _ = done.changed() => finished = true,
+                    input = receiver.recv(), if input_open => match input {
+                        Some(data) => {
+                            if let Some(stdin) = &*stdin.lock().unwrap() {
+                                let _ = stdin.send(data);
+                            }
+                        }
+                        None => {
+                            input_open = false;
+                            if stdin_once {
+                                stdin.lock().unwrap().take();
+                            }
+                        }
+                    },
+                    _ = tokio::time::sleep(ATTACH_POLL_INTERVAL) => (),
+                }
+            }
+        })
+    }
+}
+
+impl AttachHandler for Runtime {
+    fn attach(
+        &self,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        // Clients only get what the module writes from now on
+        self.stream(SeekFrom::End(0), sender, receiver)
+    }
+}
+
+impl ExecHandler for Runtime {
+    fn exec(
+        &self,
+        sender: exec::Sender,
+        receiver: exec::Receiver,
+    ) -> BoxFuture<'static, anyhow::Result<i32>> {
+        match self.diagnostics.clone() {
+            Some(diagnostics) => Box::pin(diagnostics.run(sender, receiver)),
+            None => Box::pin(async {
+                Err(anyhow::anyhow!(
+                    "WebAssembly modules have no shell to run commands in, and no diagnostics module is configured"
+                ))
+            }),
+        }
+    }
+}
+
+/// Feeds the input of attached clients to the standard input of a module. Reads block until a
+/// client sends input, as they would for a process reading from a terminal.
+struct StdinReader {
+    input: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Cursor<Vec<u8>>,
+}
+
+impl Read for StdinReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.pending.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            // Modules run by the scheduler read from within its runtime, which has to hand the
+            // thread's other modules over to another thread before it can block
+            match tokio::task::block_in_place(|| self.input.blocking_recv()) {
+                Some(data) => self.pending = Cursor::new(data),
+                // The end of the input
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
 /// WasiRuntime provides a WASI compatible runtime. A runtime should be used for
 /// each "instance" of a process and can be passed to a thread pool for running
 pub struct WasiRuntime {
@@ -42,6 +301,34 @@ pub struct WasiRuntime {
     output: Arc<NamedTempFile>,
     /// A channel to send status updates on the runtime
     status_sender: Sender<Status>,
+    /// An optional sink that output is forwarded to in addition to the tempfile
+    log_sink: Option<(Arc<dyn LogSink>, LogSource)>,
+    /// The host and guest paths of the working directory of the module, if it has one
+    working_dir: Option<(PathBuf, PathBuf)>,
+    /// Whether the module reads the input of attached clients, and whether only the first
+    /// client's input
+    stdin: Option<bool>,
+    /// The share of the execution pool the module holds while it runs
+    execution_share: Option<Arc<ExecutionShare>>,
+    /// Keeps the values of environment variables from Secrets out of logs and errors
+    redactor: Redactor,
+    /// How wasmtime runs the module, from the RuntimeClass of its pod
+    profile: RuntimeProfile,
+    /// The most memory in bytes each linear memory of the module may grow to, from the memory
+    /// limit of its container
+    memory_limit: Option<u64>,
+    /// The module run in place of the commands of `kubectl exec`
+    diagnostics_module: Option<PathBuf>,
+    /// The number of lines from the end of the output added to the status message if the module
+    /// fails
+    failure_output_lines: usize,
+    /// Runs the module cooperatively with other modules, instead of on a thread of its own
+    scheduler: Option<FuelScheduler>,
+    /// The namespace and name of the pod the fuel consumed by the module is recorded under
+    fuel_metrics: Option<(String, String)>,
+    /// Interrupt the module after this long to simulate a crash
+    #[cfg(feature = "failure-injection")]
+    crash_after: Option<std::time::Duration>,
 }
 
 struct Data {
@@ -60,6 +347,7 @@ struct Data {
 /// Holds our tempfile handle.
 pub struct HandleFactory {
     temp: Arc<NamedTempFile>,
+    index: LogIndex,
 }
 
 impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
@@ -67,6 +355,14 @@ impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
     fn new_handle(&self) -> tokio::fs::File {
         tokio::fs::File::from_std(self.temp.reopen().unwrap())
     }
+
+    fn log_index(&self) -> Option<LogIndex> {
+        Some(self.index.clone())
+    }
+
+    fn log_path(&self) -> Option<PathBuf> {
+        Some(self.temp.path().to_owned())
+    }
 }
 
 impl WasiRuntime {
@@ -111,10 +407,114 @@ impl WasiRuntime {
             }),
             output: Arc::new(temp),
             status_sender,
+            log_sink: None,
+            working_dir: None,
+            stdin: None,
+            execution_share: None,
+            redactor: Redactor::default(),
+            profile: RuntimeProfile::default(),
+            memory_limit: None,
+            diagnostics_module: None,
+            failure_output_lines: 0,
+            scheduler: None,
+            fuel_metrics: None,
+            #[cfg(feature = "failure-injection")]
+            crash_after: None,
         })
     }
 
+    /// Run the module in the given working directory, so that it resolves relative paths against
+    /// it. `host_path` is where the directory the module sees as `guest_path` is on the host.
+    pub fn with_working_dir(mut self, host_path: PathBuf, guest_path: PathBuf) -> Self {
+        self.working_dir = Some((host_path, guest_path));
+        self
+    }
+
+    /// Give the module the input of attached clients as its standard input. With `once`, the
+    /// input ends when the first attached client closes it.
+    pub fn with_stdin(mut self, once: bool) -> Self {
+        self.stdin = Some(once);
+        self
+    }
+
+    /// Hold the given share of the execution pool until the module has finished running
+    pub(crate) fn with_execution_share(mut self, share: ExecutionShare) -> Self {
+        self.execution_share = Some(Arc::new(share));
+        self
+    }
+
+    /// Keep the secret values known to `redactor` out of what is logged and the errors returned
+    /// about the module
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Run the module with the given profile rather than the default one
+    pub(crate) fn with_profile(mut self, profile: RuntimeProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Stop the module's memories from growing beyond the given number of bytes. Growing past
+    /// it fails as though the host were out of memory.
+    pub fn with_memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Run the given module in place of the commands of `kubectl exec`, with the same volumes,
+    /// environment and working directory as this module
+    pub(crate) fn with_diagnostics_module(mut self, module_path: PathBuf) -> Self {
+        self.diagnostics_module = Some(module_path);
+        self
+    }
+
+    /// Add the given number of lines from the end of the module's output to the status message
+    /// if it fails, with the secret values known to its redactor replaced
+    pub fn with_failure_output_lines(mut self, lines: usize) -> Self {
+        self.failure_output_lines = lines;
+        self
+    }
+
+    /// Run the module on the given scheduler, yielding to other modules each time it has used
+    /// a quantum of fuel, rather than on a blocking thread of its own
+    pub(crate) fn with_scheduler(mut self, scheduler: FuelScheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Record the fuel the module consumes under the given pod, if its fuel is metered
+    pub fn with_fuel_metrics(mut self, namespace: &str, pod: &str) -> Self {
+        self.fuel_metrics = Some((namespace.to_owned(), pod.to_owned()));
+        self
+    }
+
+    /// Interrupt the module after the given duration to simulate a crash
+    #[cfg(feature = "failure-injection")]
+    pub fn with_crash_after(mut self, crash_after: std::time::Duration) -> Self {
+        self.crash_after = Some(crash_after);
+        self
+    }
+
+    /// Forward the output of the module to the given sink in addition to the log file
+    pub fn with_log_sink(mut self, sink: Arc<dyn LogSink>, source: LogSource) -> Self {
+        self.log_sink = Some((sink, source));
+        self
+    }
+
     pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+        let index = LogIndex::default();
+        let runtime = self.start_runtime(index.clone()).await?;
+        let log_handle_factory = HandleFactory {
+            temp: self.output.clone(),
+            index,
+        };
+        Ok(ContainerHandle::new(runtime, log_handle_factory))
+    }
+
+    /// Start running the module, indexing its output into `index`
+    pub(crate) async fn start_runtime(&self, index: LogIndex) -> anyhow::Result<Runtime> {
         let temp = self.output.clone();
         // Because a reopen is blocking, run in a blocking task to get new
         // handles to the tempfile
@@ -123,41 +523,137 @@ impl WasiRuntime {
         })
         .await??;
 
+        // The sender is dropped once the module has finished running, which tells the log
+        // forwarder to send any remaining output and stop, and the log indexer to stop
+        let (done_tx, done_rx) = watch::channel(());
+        let attach_done = done_rx.clone();
+        let mut indexer_done = done_rx.clone();
+        let tasks = TaskGroup::new(format!("container {}", self.name));
+        let path = self.output.path().to_owned();
+        tasks.spawn("log indexer", async move {
+            let stop = async move {
+                let _ = indexer_done.changed().await;
+            };
+            kubelet::log::index(path, index, stop).await;
+            Ok(())
+        });
+        if let Some((sink, source)) = self.log_sink.clone() {
+            let mut done_rx = done_rx;
+            let temp = self.output.clone();
+            let output_read =
+                tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+                    Ok(temp.reopen()?)
+                })
+                .await??;
+            let forwarded = kubelet::log::forward(
+                tokio::fs::File::from_std(output_read),
+                source,
+                sink,
+                async move {
+                    let _ = done_rx.changed().await;
+                },
+            );
+            tasks.spawn("log forwarder", async move {
+                forwarded.await;
+                Ok(())
+            });
+        }
+
+        let (stdin_tx, stdin_rx) = match self.stdin {
+            Some(_) => {
+                let (tx, rx) = mpsc::unbounded_channel();
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
         let (interrupt_handle, handle) = self
-            .spawn_wasmtime(tokio::fs::File::from_std(output_write))
+            .spawn_wasmtime(
+                tokio::fs::File::from_std(output_write),
+                stdin_rx,
+                done_tx,
+                stopped.clone(),
+            )
             .await?;
+        let interrupt_handle = Arc::new(interrupt_handle);
 
-        let log_handle_factory = HandleFactory {
-            temp: self.output.clone(),
-        };
+        #[cfg(feature = "failure-injection")]
+        {
+            if let Some(crash_after) = self.crash_after {
+                let crash_handle = interrupt_handle.clone();
+                let mut crash_done = attach_done.clone();
+                tasks.spawn("crash injector", async move {
+                    tokio::select! {
+                        _ = tokio::time::sleep(crash_after) => {
+                            warn!("Injecting container crash");
+                            crash_handle.interrupt();
+                        }
+                        _ = crash_done.changed() => (),
+                    }
+                    Ok(())
+                });
+            }
+        }
 
-        Ok(ContainerHandle::new(
-            Runtime {
-                handle,
-                interrupt_handle,
-            },
-            log_handle_factory,
-        ))
+        let diagnostics = self
+            .diagnostics_module
+            .clone()
+            .map(|module_path| Diagnostics {
+                module_path,
+                name: format!("{}:exec", self.name),
+                env: self.data.env.clone(),
+                dirs: self.data.dirs.clone(),
+                working_dir: self.working_dir.clone(),
+                redactor: self.redactor.clone(),
+                profile: self.profile.clone(),
+                log_dir: self
+                    .output
+                    .path()
+                    .parent()
+                    .map(Path::to_owned)
+                    .unwrap_or_else(std::env::temp_dir),
+            });
+
+        Ok(Runtime {
+            handle: Some(handle),
+            interrupt_handle,
+            stopped,
+            output: self.output.clone(),
+            stdin: Arc::new(Mutex::new(stdin_tx)),
+            stdin_once: self.stdin.unwrap_or(false),
+            done: attach_done,
+            diagnostics,
+            tasks,
+        })
     }
 
     // Spawns a running wasmtime instance with the given context and status
     // channel.
-    #[instrument(level = "info", skip(self, output_write), fields(name = %self.name))]
+    #[instrument(level = "info", skip(self, output_write, stdin, done, stopped), fields(name = %self.name))]
     async fn spawn_wasmtime(
         &self,
         output_write: tokio::fs::File,
+        stdin: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+        done: watch::Sender<()>,
+        stopped: Arc<AtomicBool>,
     ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
         let status_sender = self.status_sender.clone();
 
         // Log this info here so it isn't on _every_ log line
-        trace!(env = ?data.env, args = ?data.args, dirs = ?data.dirs, "Starting setup of wasmtime module");
-        let env: Vec<(String, String)> = data
+        trace!(env = ?self.redactor.redact_env(&data.env), args = ?self.redactor.redact_args(&data.args), dirs = ?data.dirs, "Starting setup of wasmtime module");
+        let mut env: Vec<(String, String)> = data
             .env
             .iter()
             .map(|(k, v)| (k.to_string(), v.to_string()))
             .collect();
+        // Modules can't ask for their working directory, so tell them the way a shell would
+        if let Some((_, guest_dir)) = &self.working_dir {
+            if !data.env.contains_key("PWD") {
+                env.push(("PWD".to_owned(), guest_dir.to_string_lossy().into_owned()));
+            }
+        }
         let stdout = wasi_cap_std_sync::file::File::from_cap_std(unsafe {
             cap_std::fs::File::from_std(output_write.try_clone().await?.into_std().await)
         });
@@ -172,6 +668,12 @@ impl WasiRuntime {
             .envs(&env)?
             .stdout(Box::new(stdout))
             .stderr(Box::new(stderr));
+        if let Some(input) = stdin {
+            builder = builder.stdin(Box::new(ReadPipe::new(StdinReader {
+                input,
+                pending: Cursor::new(Vec::new()),
+            })));
+        }
 
         // Add preopen dirs.
         for (key, value) in data.dirs.iter() {
@@ -186,16 +688,78 @@ impl WasiRuntime {
             builder = builder.preopened_dir(preopen_dir, guest_dir)?;
         }
 
+        // WASI has no current directory. Modules built with wasi-libc (which includes Rust's
+        // standard library) resolve relative paths against a directory preopened as `.`
+        if let Some((host_dir, guest_dir)) = &self.working_dir {
+            debug!(
+                hostpath = %host_dir.display(),
+                guestpath = %guest_dir.display(),
+                "mounting working directory in module"
+            );
+            let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(host_dir) }?;
+            builder = builder.preopened_dir(preopen_dir, ".")?;
+        }
+
         let ctx = builder.build();
 
         let mut config = wasmtime::Config::new();
         config.interruptable(true);
+        if self.profile.fuel.is_some() || self.scheduler.is_some() {
+            config.consume_fuel(true);
+        }
+        if self.scheduler.is_some() {
+            config.async_support(true);
+        }
+        if self.profile.cache {
+            config.cache_config_load_default()?;
+        }
         let engine = wasmtime::Engine::new(&config)?;
-        let mut store = wasmtime::Store::new(&engine, ctx);
+        let mut store = wasmtime::Store::new(
+            &engine,
+            ModuleState {
+                wasi: ctx,
+                limiter: ModuleLimiter {
+                    memory_pages: self.memory_limit.map(memory_pages),
+                    memory_exceeded: false,
+                },
+            },
+        );
+        store.limiter(|state| &mut state.limiter);
+        match (&self.scheduler, self.profile.fuel) {
+            // The fuel of the profile, if any, is handed out a quantum at a time, with the module
+            // yielding to the others in between
+            (Some(scheduler), fuel) => {
+                let quantum = scheduler.quantum();
+                let (initial, quanta) = fuel_quanta(fuel, quantum);
+                store.add_fuel(initial)?;
+                store.out_of_fuel_async_yield(
+                    quanta.try_into().unwrap_or_else(|_| u32::MAX.into()),
+                    quantum,
+                );
+            }
+            (None, Some(fuel)) => store.add_fuel(fuel)?,
+            (None, None) => {}
+        }
         let interrupt = store.interrupt_handle()?;
 
         let mut linker = Linker::new(&engine);
 
+        if is_component(&data.module_data) {
+            // NOTE: Running components needs wasmtime's component model support and the WASI
+            // preview2 host implementation, neither of which exist in the wasmtime version we
+            // currently use. Fail with a clear message instead of a confusing parse error
+            let message = "module is a WebAssembly component (WASI preview2), which is not supported yet; build the module for wasm32-wasi (preview1) instead";
+            error!("{}", message);
+            status_sender
+                .send(Status::Terminated {
+                    failed: true,
+                    message: message.into(),
+                    timestamp: chrono::Utc::now(),
+                })
+                .await?;
+            return Err(anyhow::anyhow!(message));
+        }
+
         let module = match wasmtime::Module::new(&engine, &data.module_data) {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
@@ -215,8 +779,13 @@ impl WasiRuntime {
             }
         };
 
-        wasmtime_wasi::add_to_linker(&mut linker, |cx| cx)?;
-        let instance = match linker.instantiate(&mut store, &module) {
+        wasmtime_wasi::add_to_linker(&mut linker, |state: &mut ModuleState| &mut state.wasi)?;
+        let instantiated = if self.scheduler.is_some() {
+            linker.instantiate_async(&mut store, &module).await
+        } else {
+            linker.instantiate(&mut store, &module)
+        };
+        let instance = match instantiated {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
             Ok(i) => i,
@@ -226,7 +795,12 @@ impl WasiRuntime {
                 status_sender
                     .send(Status::Terminated {
                         failed: true,
-                        message: message.into(),
+                        // A module whose initial memory is over its limit can't even start
+                        message: if store.data().limiter.memory_exceeded {
+                            OOM_KILLED.into()
+                        } else {
+                            message.into()
+                        },
                         timestamp: chrono::Utc::now(),
                     })
                     .await?;
@@ -248,9 +822,8 @@ impl WasiRuntime {
             .get_export(&mut store, "_start")
             .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
 
-        // NOTE(thomastaylor312): In the future (pun intended) we might be able to use something
-        // like `func.call(...).await`. We should check every once and a while when upgraing
-        // wasmtime
+        // Only modules run by the scheduler can be called with `func.call_async(...).await`, as
+        // it needs an async store, so the rest still run on a blocking thread of their own
         let func = match export {
             wasmtime::Extern::Func(f) => f,
             _ => {
@@ -270,45 +843,170 @@ impl WasiRuntime {
         };
 
         let name = self.name.clone();
-        let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let span = tracing::info_span!("wasmtime_module_run", %name);
-            let _enter = span.enter();
-
-            match func.call(&mut store, &[]) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(_) => {}
-                Err(e) => {
-                    let message = "unable to run module";
-                    error!(error = %e, "{}", message);
-                    send(
-                        &status_sender,
-                        &name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                    );
+        let execution_share = self.execution_share.clone();
+        let redactor = self.redactor.clone();
+        let output = self.output.clone();
+        let failure_output_lines = self.failure_output_lines;
+        let fuel_metrics = self.fuel_metrics.clone();
+        let handle = match self.scheduler.clone() {
+            Some(scheduler) => {
+                let quantum = scheduler.quantum();
+                let span = tracing::info_span!("wasmtime_module_run", %name);
+                scheduler.spawn(
+                    async move {
+                        // Dropped when the module run finishes, whatever the outcome
+                        let _done = done;
+                        let _execution_share = execution_share;
 
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
+                        // The module yields each time it has used up a quantum, so the fuel of
+                        // long running modules is recorded as they go rather than when they end
+                        let mut recorded = 0;
+                        let result = {
+                            let mut run = Box::pin(func.call_async(&mut store, &[]));
+                            futures::future::poll_fn(|cx| {
+                                let poll = run.as_mut().poll(cx);
+                                if poll.is_pending() {
+                                    record_fuel(&fuel_metrics, quantum);
+                                    recorded += quantum;
+                                }
+                                poll
+                            })
+                            .await
+                        };
+                        let consumed = store.fuel_consumed().unwrap_or_default();
+                        record_fuel(&fuel_metrics, consumed.saturating_sub(recorded));
+
+                        let (status, result) = run_outcome(
+                            result.map(drop),
+                            &stopped,
+                            store.data().limiter.memory_exceeded,
+                            &redactor,
+                            &output,
+                            failure_output_lines,
+                        );
+                        if let Err(e) = status_sender.send(status).await {
+                            warn!(error = %e, "error sending wasi status");
+                        }
+                        result
+                    }
+                    .instrument(span),
+                )
+            }
+            None => {
+                let queued = kubelet::metrics::runtime_metrics().blocking_task_queued();
+                tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+                    let _running = queued.start();
+                    // Dropped when the module run finishes, whatever the outcome
+                    let _done = done;
+                    let _execution_share = execution_share;
+                    let span = tracing::info_span!("wasmtime_module_run", %name);
+                    let _enter = span.enter();
 
+                    let result = func.call(&mut store, &[]);
+                    if let Some(consumed) = store.fuel_consumed() {
+                        record_fuel(&fuel_metrics, consumed);
+                    }
+
+                    let (status, result) = run_outcome(
+                        result.map(drop),
+                        &stopped,
+                        store.data().limiter.memory_exceeded,
+                        &redactor,
+                        &output,
+                        failure_output_lines,
+                    );
+                    send(&status_sender, &name, status);
+                    result
+                })
+            }
+        };
+        // Wait for the interrupt to be sent back to us
+        Ok((interrupt, handle))
+    }
+}
+
+/// The status a module run ended with, and the result of the task that ran it
+fn run_outcome(
+    result: anyhow::Result<()>,
+    stopped: &AtomicBool,
+    memory_exceeded: bool,
+    redactor: &Redactor,
+    output: &NamedTempFile,
+    failure_output_lines: usize,
+) -> (Status, anyhow::Result<()>) {
+    match result {
+        Ok(()) => {
             info!("module run complete");
-            send(
-                &status_sender,
-                &name,
+            (
                 Status::Terminated {
                     failed: false,
                     message: "Module run completed".into(),
                     timestamp: chrono::Utc::now(),
                 },
-            );
-            Ok(())
-        });
-        // Wait for the interrupt to be sent back to us
-        Ok((interrupt, handle))
+                Ok(()),
+            )
+        }
+        // The module was interrupted because it was asked to stop
+        Err(_) if stopped.load(Ordering::SeqCst) => {
+            info!("module stopped");
+            (
+                Status::Terminated {
+                    failed: false,
+                    message: "Module stopped".into(),
+                    timestamp: chrono::Utc::now(),
+                },
+                Ok(()),
+            )
+        }
+        Err(e) => {
+            let message = "unable to run module";
+            let e = redactor.redact(&e.to_string());
+            error!(error = %e, "{}", message);
+            // The output is written straight to the file, so it is all there by now
+            let tail = kubelet::log::tail_lines(output.path(), failure_output_lines)
+                .unwrap_or_else(|e| {
+                    warn!(error = %e, "Unable to read output of failed module");
+                    Vec::new()
+                });
+            let tail: Vec<String> = tail.iter().map(|line| redactor.redact(line)).collect();
+            // Modules usually trap when they can't allocate, so a module refused memory is
+            // reported as killed for running out of it, whatever the trap
+            let status_message = if memory_exceeded {
+                OOM_KILLED.to_owned()
+            } else {
+                kubelet::log::message_with_output(message, &tail)
+            };
+            (
+                Status::Terminated {
+                    failed: true,
+                    message: status_message,
+                    timestamp: chrono::Utc::now(),
+                },
+                Err(anyhow::anyhow!("{}: {}", message, e)),
+            )
+        }
+    }
+}
+
+/// Split the fuel a module may use into the fuel it starts with and the number of quanta it is
+/// given each time it runs out, so that it uses exactly its fuel. Modules without a limit are
+/// given as many quanta as they can be.
+fn fuel_quanta(fuel: Option<u64>, quantum: u64) -> (u64, u64) {
+    match fuel {
+        Some(fuel) => {
+            let quanta = fuel.saturating_sub(1) / quantum;
+            (fuel - quanta * quantum, quanta)
+        }
+        None => (quantum, u64::MAX),
+    }
+}
+
+/// Record fuel consumed by the module under its pod, if it has one
+fn record_fuel(fuel_metrics: &Option<(String, String)>, fuel: u64) {
+    if let Some((namespace, pod)) = fuel_metrics {
+        if fuel > 0 {
+            kubelet::metrics::pod_metrics().record_fuel_consumed(namespace, pod, fuel);
+        }
     }
 }
 
@@ -319,3 +1017,99 @@ fn send(sender: &Sender<Status>, name: &str, status: Status) {
         Ok(_) => debug!("send completed"),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Copies its standard input to its standard output until the input ends
+    const ECHO: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_read"
+            (func $fd_read (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")
+            (block $end
+              (loop $copy
+                ;; Read up to 64 bytes into 64, with the count read at 8
+                (i32.store (i32.const 0) (i32.const 64))
+                (i32.store (i32.const 4) (i32.const 64))
+                (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (br_if $end (i32.eqz (i32.load (i32.const 8))))
+                ;; Write what was read
+                (i32.store (i32.const 16) (i32.const 64))
+                (i32.store (i32.const 20) (i32.load (i32.const 8)))
+                (drop (call $fd_write (i32.const 1) (i32.const 16) (i32.const 1) (i32.const 24)))
+                (br $copy)))))
+    "#;
+
+    #[tokio::test]
+    async fn test_stdin_under_scheduler() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let (status_tx, _status_rx) = mpsc::channel(16);
+        let runtime = WasiRuntime::new(
+            "echo".to_owned(),
+            wat::parse_str(ECHO).unwrap(),
+            HashMap::new(),
+            Vec::new(),
+            HashMap::new(),
+            log_dir.path().to_owned(),
+            status_tx,
+        )
+        .await
+        .unwrap()
+        .with_stdin(false)
+        .with_scheduler(FuelScheduler::new(1, 10_000).unwrap());
+        let mut module = runtime.start_runtime(LogIndex::default()).await.unwrap();
+
+        let send = |data: &[u8]| {
+            let stdin = module.stdin.lock().unwrap();
+            stdin.as_ref().unwrap().send(data.to_vec()).unwrap();
+        };
+        send(b"hello ");
+        // Give the module time to wait for more input
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send(b"world");
+        module.stdin.lock().unwrap().take();
+
+        tokio::time::timeout(Duration::from_secs(30), module.wait())
+            .await
+            .expect("module should exit once its input ends")
+            .unwrap();
+        assert_eq!(
+            "hello world",
+            std::fs::read_to_string(module.output.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_run_outcome_redacts_secrets() {
+        use std::io::Write;
+
+        let mut redactor = Redactor::default();
+        redactor.add_secret("TOKEN", "hunter2");
+        let mut output = NamedTempFile::new().unwrap();
+        writeln!(output, "rejected token hunter2").unwrap();
+        let (status, result) = run_outcome(
+            Err(anyhow::anyhow!("invalid token hunter2")),
+            &AtomicBool::new(false),
+            false,
+            &redactor,
+            &output,
+            10,
+        );
+        match status {
+            Status::Terminated { message, .. } => assert_eq!(
+                "unable to run module; last line of output:\nrejected token [redacted]",
+                message
+            ),
+            status => panic!("unexpected status {:?}", status),
+        }
+        assert_eq!(
+            "unable to run module: invalid token [redacted]",
+            result.unwrap_err().to_string()
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use kubelet::config::Config;
-use kubelet::plugin_watcher::PluginRegistry;
-use kubelet::resources::DeviceManager;
+use kubelet::node::NodeInfo;
+use kubelet::provider::{Provider, ProviderClients};
 use kubelet::store::composite::ComposableStore;
-use kubelet::store::oci::FileStore;
+use kubelet::store::oci::{platform_for_arch, FailoverClient, FileStore};
 use kubelet::Kubelet;
+use oci_distribution::client::ClientConfigSource;
 use std::convert::TryFrom;
 use std::sync::Arc;
 use wasi_provider::WasiProvider;
@@ -22,36 +23,35 @@ async fn main() -> anyhow::Result<()> {
 
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
-    let store = make_store(&config);
-    let plugin_registry = Arc::new(PluginRegistry::new(&config.plugins_dir));
-    let device_plugin_manager = Arc::new(DeviceManager::new(
-        &config.device_plugins_dir,
-        kube::Client::try_from(kubeconfig.clone())?,
-        &config.node_name,
-    ));
-
-    let provider = WasiProvider::new(
-        store,
-        &config,
-        kubeconfig.clone(),
-        plugin_registry,
-        device_plugin_manager,
-    )
-    .await?;
+    let store = make_store(&config)?;
+    let clients = ProviderClients::new(&config, kubeconfig.clone(), store)?;
+    let node_info = NodeInfo::new(&config, WasiProvider::ARCH);
+
+    let provider = WasiProvider::initialize(&config, node_info, clients).await?;
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
-    let client = oci_distribution::Client::from_source(config);
+fn make_store(config: &Config) -> anyhow::Result<Arc<dyn kubelet::store::Store + Send + Sync>> {
+    let mut client_config = config.client_config();
+    client_config.platform = platform_for_arch(WasiProvider::ARCH);
+    let client = FailoverClient::new(
+        oci_distribution::Client::try_from(client_config)?,
+        config.registry_failover.clone().unwrap_or_default(),
+    )?;
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
+    let mut file_store = FileStore::new(client, &store_path);
+    tokio::spawn(file_store.collect_garbage_periodically());
+    if let Some(size) = config.module_cache_size {
+        file_store = file_store.with_module_cache(size as usize * 1024 * 1024);
+    }
+    let file_store = Arc::new(file_store);
 
     if config.allow_local_modules {
-        file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
+        Ok(file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {})))
     } else {
-        file_store
+        Ok(file_store)
     }
 }
 
@@ -79,14 +79,14 @@ pub async fn pod_exited_with_failure(pods: &Api<Pod>, pod_name: &str) -> anyhow:
     Ok(())
 }
 
-pub async fn pod_reason_contains(
+pub async fn pod_message_contains(
     pods: &Api<Pod>,
     pod_name: &str,
     expected_message: &str,
 ) -> anyhow::Result<()> {
     let pod = pods.get(pod_name).await?;
 
-    let message = (|| pod.status?.reason)().expect("Could not get pod message.");
+    let message = (|| pod.status?.message)().expect("Could not get pod message.");
     assert!(
         message.contains(expected_message),
         "Expected pod message containing {} but got {}",